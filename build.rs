@@ -0,0 +1,9 @@
+//! With the sbi feature, OpenSBI occupies the start of RAM,
+//! so tell src/ld/kernel.ld to link the kernel above it.
+
+fn main() {
+    println!("cargo:rerun-if-changed=build.rs");
+    if std::env::var_os("CARGO_FEATURE_SBI").is_some() {
+        println!("cargo:rustc-link-arg=--defsym=SBI_BOOT=1");
+    }
+}
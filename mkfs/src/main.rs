@@ -0,0 +1,301 @@
+//! Build an xv6 file system image
+//!
+//! usage: mkfs fs.img files...
+//!
+//! Files are put in the root directory under their base name.
+//! The layout matches the kernel's fs module:
+//! [ boot block | sb block | log | inode blocks | free bit map | data blocks ]
+//! The super block, the log header and each inode block end with a CRC-32 of the rest,
+//! see the kernel's fs/crc32.rs.
+
+use std::convert::TryInto;
+use std::env;
+use std::fs::{self, File};
+use std::io::{self, Write};
+use std::path::Path;
+use std::process;
+
+const BSIZE: usize = 1024;
+const FSSIZE: usize = 1000; // size of file system in blocks
+const NINODES: usize = 200;
+const LOGSIZE: usize = 30;
+
+const NDIRECT: usize = 12;
+const NINDIRECT: usize = BSIZE / 4;
+const MAXFILE: usize = NDIRECT + NINDIRECT;
+const DIRSIZ: usize = 14;
+
+const FSMAGIC: u32 = 0x10203040;
+const ROOTINO: u32 = 1;
+
+const T_DIR: u16 = 1;
+const T_FILE: u16 = 2;
+
+const DINODE_SIZE: usize = 64;
+/// the last slot of an inode block holds its checksum
+const IPB: usize = BSIZE / DINODE_SIZE - 1;
+/// bytes under the checksums of the super block and the log header
+const SB_SUMMED: usize = 8 * 4;
+const LOG_SUMMED: usize = (1 + LOGSIZE) * 4;
+const DIRENT_SIZE: usize = 2 + DIRSIZ;
+
+/// On-disk inode, same as the kernel's DInode
+#[derive(Default, Clone)]
+struct DInode {
+    itype: u16,
+    major: u16,
+    minor: u16,
+    nlink: u16,
+    size: u32,
+    addrs: [u32; NDIRECT + 1],
+}
+
+impl DInode {
+    fn decode(b: &[u8]) -> Self {
+        let u16_at = |i: usize| u16::from_le_bytes(b[i..i + 2].try_into().unwrap());
+        let u32_at = |i: usize| u32::from_le_bytes(b[i..i + 4].try_into().unwrap());
+        let mut addrs = [0; NDIRECT + 1];
+        for (i, a) in addrs.iter_mut().enumerate() {
+            *a = u32_at(12 + i * 4);
+        }
+        Self {
+            itype: u16_at(0),
+            major: u16_at(2),
+            minor: u16_at(4),
+            nlink: u16_at(6),
+            size: u32_at(8),
+            addrs,
+        }
+    }
+
+    fn encode(&self, b: &mut [u8]) {
+        b[0..2].copy_from_slice(&self.itype.to_le_bytes());
+        b[2..4].copy_from_slice(&self.major.to_le_bytes());
+        b[4..6].copy_from_slice(&self.minor.to_le_bytes());
+        b[6..8].copy_from_slice(&self.nlink.to_le_bytes());
+        b[8..12].copy_from_slice(&self.size.to_le_bytes());
+        for (i, a) in self.addrs.iter().enumerate() {
+            b[12 + i * 4..16 + i * 4].copy_from_slice(&a.to_le_bytes());
+        }
+    }
+}
+
+struct Fs {
+    img: Vec<u8>,
+    logstart: usize,
+    inodestart: usize,
+    bmapstart: usize,
+    freeinode: u32,
+    freeblock: u32,
+}
+
+impl Fs {
+    fn new() -> Self {
+        let nbitmap = FSSIZE / (BSIZE * 8) + 1;
+        let ninodeblocks = NINODES / IPB + 1;
+        let nlog = LOGSIZE;
+        // 1 fs block = 1 disk sector
+        let nmeta = 2 + nlog + ninodeblocks + nbitmap;
+        let nblocks = FSSIZE - nmeta;
+
+        let logstart = 2;
+        let inodestart = 2 + nlog;
+        let bmapstart = 2 + nlog + ninodeblocks;
+
+        let mut fs = Self {
+            img: vec![0; FSSIZE * BSIZE],
+            logstart,
+            inodestart,
+            bmapstart,
+            freeinode: 1,
+            freeblock: nmeta as u32,
+        };
+
+        // super block
+        let sb = [
+            FSMAGIC,
+            FSSIZE as u32,
+            nblocks as u32,
+            NINODES as u32,
+            nlog as u32,
+            logstart as u32,
+            inodestart as u32,
+            bmapstart as u32,
+        ];
+        let block = fs.block_mut(1);
+        for (i, word) in sb.iter().enumerate() {
+            block[i * 4..i * 4 + 4].copy_from_slice(&word.to_le_bytes());
+        }
+
+        println!(
+            "nmeta {} (boot, super, log blocks {} inode blocks {}, bitmap blocks {}) blocks {} total {}",
+            nmeta, nlog, ninodeblocks, nbitmap, nblocks, FSSIZE
+        );
+        fs
+    }
+
+    fn block_mut(&mut self, bno: usize) -> &mut [u8] {
+        &mut self.img[bno * BSIZE..(bno + 1) * BSIZE]
+    }
+
+    fn inode_offset(&self, inum: u32) -> usize {
+        let inum = inum as usize;
+        (self.inodestart + inum / IPB) * BSIZE + (inum % IPB) * DINODE_SIZE
+    }
+
+    fn rinode(&self, inum: u32) -> DInode {
+        let off = self.inode_offset(inum);
+        DInode::decode(&self.img[off..off + DINODE_SIZE])
+    }
+
+    fn winode(&mut self, inum: u32, din: &DInode) {
+        let off = self.inode_offset(inum);
+        din.encode(&mut self.img[off..off + DINODE_SIZE]);
+    }
+
+    fn ialloc(&mut self, itype: u16) -> u32 {
+        let inum = self.freeinode;
+        assert!((inum as usize) < NINODES, "mkfs: out of inodes");
+        self.freeinode += 1;
+        let din = DInode {
+            itype,
+            nlink: 1,
+            ..Default::default()
+        };
+        self.winode(inum, &din);
+        inum
+    }
+
+    fn alloc_block(&mut self) -> u32 {
+        let bno = self.freeblock;
+        assert!((bno as usize) < FSSIZE, "mkfs: out of blocks");
+        self.freeblock += 1;
+        bno
+    }
+
+    /// Append data to the end of inode inum
+    fn iappend(&mut self, inum: u32, mut data: &[u8]) {
+        let mut din = self.rinode(inum);
+        let mut off = din.size as usize;
+        while !data.is_empty() {
+            let fbn = off / BSIZE;
+            assert!(fbn < MAXFILE, "mkfs: file too large");
+            let bno = if fbn < NDIRECT {
+                if din.addrs[fbn] == 0 {
+                    din.addrs[fbn] = self.alloc_block();
+                }
+                din.addrs[fbn]
+            } else {
+                if din.addrs[NDIRECT] == 0 {
+                    din.addrs[NDIRECT] = self.alloc_block();
+                }
+                let ind = din.addrs[NDIRECT] as usize * BSIZE + (fbn - NDIRECT) * 4;
+                let mut bno = u32::from_le_bytes(self.img[ind..ind + 4].try_into().unwrap());
+                if bno == 0 {
+                    bno = self.alloc_block();
+                    self.img[ind..ind + 4].copy_from_slice(&bno.to_le_bytes());
+                }
+                bno
+            };
+            let start = off % BSIZE;
+            let n = std::cmp::min(BSIZE - start, data.len());
+            let block = self.block_mut(bno as usize);
+            block[start..start + n].copy_from_slice(&data[..n]);
+            off += n;
+            data = &data[n..];
+        }
+        din.size = off as u32;
+        self.winode(inum, &din);
+    }
+
+    fn add_dirent(&mut self, dir: u32, inum: u32, name: &str) {
+        let mut de = [0; DIRENT_SIZE];
+        de[0..2].copy_from_slice(&(inum as u16).to_le_bytes());
+        de[2..2 + name.len()].copy_from_slice(name.as_bytes());
+        self.iappend(dir, &de);
+    }
+
+    /// Store the checksums of the metadata blocks, once they are filled
+    fn seal(&mut self) {
+        let mut blocks = vec![(1, SB_SUMMED), (self.logstart, LOG_SUMMED)];
+        blocks.extend((self.inodestart..self.bmapstart).map(|bno| (bno, IPB * DINODE_SIZE)));
+        for (bno, len) in blocks {
+            let block = self.block_mut(bno);
+            let sum = crc32(&block[..len]);
+            block[len..len + 4].copy_from_slice(&sum.to_le_bytes());
+        }
+    }
+
+    /// Mark the blocks already handed out as used in the bitmap
+    fn balloc(&mut self) {
+        let used = self.freeblock as usize;
+        println!("balloc: first {} blocks have been allocated", used);
+        assert!(used < BSIZE * 8);
+        let bmapstart = self.bmapstart;
+        let bitmap = self.block_mut(bmapstart);
+        for i in 0..used {
+            bitmap[i / 8] |= 1 << (i % 8);
+        }
+    }
+}
+
+/// The IEEE CRC-32, bit by bit, mkfs only sums a few blocks
+fn crc32(data: &[u8]) -> u32 {
+    let mut c = !0u32;
+    for b in data {
+        c ^= *b as u32;
+        for _ in 0..8 {
+            c = if c & 1 != 0 { 0xedb88320 ^ (c >> 1) } else { c >> 1 };
+        }
+    }
+    !c
+}
+
+fn main() {
+    let args: Vec<String> = env::args().collect();
+    if args.len() < 2 {
+        eprintln!("Usage: mkfs fs.img files...");
+        process::exit(1);
+    }
+
+    if let Err(err) = run(&args[1], &args[2..]) {
+        eprintln!("mkfs: {}", err);
+        process::exit(1);
+    }
+}
+
+fn run(img: &str, files: &[String]) -> io::Result<()> {
+    let mut fs = Fs::new();
+
+    let rootino = fs.ialloc(T_DIR);
+    assert_eq!(rootino, ROOTINO);
+    fs.add_dirent(rootino, rootino, ".");
+    fs.add_dirent(rootino, rootino, "..");
+
+    for file in files {
+        let name = Path::new(file)
+            .file_name()
+            .and_then(|name| name.to_str())
+            .ok_or_else(|| io::Error::new(io::ErrorKind::InvalidInput, file.clone()))?;
+        if name.len() > DIRSIZ {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                format!("{}: name longer than {} bytes", name, DIRSIZ),
+            ));
+        }
+        let data = fs::read(file)?;
+        let inum = fs.ialloc(T_FILE);
+        fs.add_dirent(rootino, inum, name);
+        fs.iappend(inum, &data);
+    }
+
+    // fix size of root inode dir
+    let mut din = fs.rinode(rootino);
+    din.size = ((din.size as usize / BSIZE + 1) * BSIZE) as u32;
+    fs.winode(rootino, &din);
+
+    fs.balloc();
+    fs.seal();
+
+    File::create(img)?.write_all(&fs.img)
+}
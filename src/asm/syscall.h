@@ -1,2 +1,69 @@
 // System call numbers
+#define SYS_fork    1
+#define SYS_exit    2
+#define SYS_wait    3
+#define SYS_pipe    4
+#define SYS_read    5
+#define SYS_kill    6
 #define SYS_exec    7
+#define SYS_fstat   8
+#define SYS_chdir   9
+#define SYS_dup    10
+#define SYS_getpid 11
+#define SYS_sbrk   12
+#define SYS_sleep  13
+#define SYS_uptime 14
+#define SYS_open   15
+#define SYS_write  16
+#define SYS_mknod  17
+#define SYS_unlink 18
+#define SYS_link   19
+#define SYS_mkdir  20
+#define SYS_close  21
+#define SYS_dmesg  22
+#define SYS_kfault 23
+#define SYS_prof   24
+#define SYS_ftrace 25
+#define SYS_schedtrace 26
+#define SYS_hvguest 27
+#define SYS_setitimer 28
+#define SYS_sigreturn 29
+#define SYS_getuid 30
+#define SYS_geteuid 31
+#define SYS_setuid 32
+#define SYS_getgid 33
+#define SYS_getegid 34
+#define SYS_setgid 35
+#define SYS_chroot 36
+#define SYS_ptrace 37
+#define SYS_proclog 38
+#define SYS_madvise 39
+#define SYS_getrandom 40
+#define SYS_hartctl 41
+#define SYS_suspend 42
+#define SYS_sched_setscheduler 43
+#define SYS_sched_yield 44
+#define SYS_cpustat 45
+#define SYS_sched_setquantum 46
+#define SYS_mmap 47
+#define SYS_munmap 48
+#define SYS_socket 49
+#define SYS_bind   50
+#define SYS_listen 51
+#define SYS_connect 52
+#define SYS_accept 53
+#define SYS_mq_open 54
+#define SYS_mq_send 55
+#define SYS_mq_receive 56
+#define SYS_mq_unlink 57
+#define SYS_eventfd 58
+#define SYS_timerfd_create 59
+#define SYS_timerfd_settime 60
+#define SYS_msync 61
+#define SYS_fcntl 62
+#define SYS_getrlimit 63
+#define SYS_setrlimit 64
+#define SYS_ioctl 65
+#define SYS_copy_file_range 66
+#define SYS_checkpoint 67
+#define SYS_restore 68
@@ -0,0 +1,85 @@
+//! Kernel command line, from the device tree's /chosen bootargs,
+//! e.g., qemu -append "quiet nosmp init=/sh"
+//!
+//! Options are separated by spaces, each a bare flag or key=value,
+//! the last one wins if a key is given twice. Looked up by:
+//! - loglevel=N, with 0 kernel messages only go to the message ring, see printf.rs,
+//!   there are no levels yet, any other N prints them all
+//! - quiet, the same as loglevel=0
+//! - root=N, the device of the root file system, instead of ROOTDEV
+//! - nosmp, only the boot hart runs, see rmain.rs
+//! - init=PATH, the first user program, instead of /init
+//! - proclog, log process events from boot, see proclog.rs
+//! - nopreempt, a tick in kernel mode does not yield, only at safe points, see process/cpu.rs
+//! - coredump, a user process killed by a fault dumps its core, see process/coredump.rs
+//! - crashdump=ram, a crash dump also holds all of RAM, with the crashdump feature, see crashdump.rs
+//! - fsck, check the root file system at mount, fsck=repair to also fix it, see fs/fsck.rs
+//!
+//! Unknown ones are kept too, and the whole line is printed at boot.
+
+use core::str;
+
+use crate::consts::CMDLINE_MAX;
+
+/// Copied out of the device tree before paging hides it,
+/// only written by the boot hart in dtb::init,
+/// before the other harts get past rust_main's Once.
+static mut LINE: [u8; CMDLINE_MAX] = [0; CMDLINE_MAX];
+static mut LEN: usize = 0;
+
+pub unsafe fn init(args: &[u8]) {
+    let mut n = args.len();
+    if n > CMDLINE_MAX {
+        println!("cmdline: longer than {} bytes, truncated", CMDLINE_MAX);
+        n = CMDLINE_MAX;
+    }
+    LINE[..n].copy_from_slice(&args[..n]);
+    // keep it a str, e.g., if the cut went through a character
+    LEN = match str::from_utf8(&LINE[..n]) {
+        Ok(_) => n,
+        Err(err) => err.valid_up_to(),
+    };
+}
+
+/// The whole command line, empty if there was none
+pub fn line() -> &'static str {
+    unsafe { str::from_utf8_unchecked(&LINE[..LEN]) }
+}
+
+/// The value of option key, "" for a bare flag, None if it is not given
+pub fn get(key: &str) -> Option<&'static str> {
+    lookup(line(), key)
+}
+
+pub fn has(key: &str) -> bool {
+    get(key).is_some()
+}
+
+fn lookup<'a>(line: &'a str, key: &str) -> Option<&'a str> {
+    line.split_ascii_whitespace()
+        .map(|option| option.split_once('=').unwrap_or((option, "")))
+        .filter(|&(k, _)| k == key)
+        .map(|(_, value)| value)
+        .next_back()
+}
+
+#[cfg(feature = "unit_test")]
+pub mod tests {
+    use super::*;
+
+    pub fn options() {
+        let line = " loglevel=3 nosmp  init=/bin/sh root=2 loglevel=0 x=a=b\t";
+        assert_eq!(lookup(line, "nosmp"), Some(""));
+        assert_eq!(lookup(line, "init"), Some("/bin/sh"));
+        assert_eq!(lookup(line, "root"), Some("2"));
+        // the last one wins
+        assert_eq!(lookup(line, "loglevel"), Some("0"));
+        // only the first = splits
+        assert_eq!(lookup(line, "x"), Some("a=b"));
+        // whole keys only
+        assert_eq!(lookup(line, "nosm"), None);
+        assert_eq!(lookup(line, "smp"), None);
+        assert_eq!(lookup("", "init"), None);
+    }
+    crate::kernel_test!(options);
+}
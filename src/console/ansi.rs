@@ -0,0 +1,129 @@
+//! ANSI escape sequences for the serial console
+//!
+//! Most terminals attached to qemu's serial port understand them.
+//! The helpers here print nothing when escape sequences are turned off,
+//! so that logs captured to a file stay readable.
+//! User programs write their own escape sequences through the console,
+//! which are passed through as is.
+
+use core::fmt;
+use core::sync::atomic::{AtomicBool, Ordering};
+
+static ENABLED: AtomicBool = AtomicBool::new(true);
+
+/// Turn on/off escape sequences emitted by the kernel
+pub fn set_enabled(on: bool) {
+    ENABLED.store(on, Ordering::Relaxed);
+}
+
+#[inline]
+pub fn is_enabled() -> bool {
+    ENABLED.load(Ordering::Relaxed)
+}
+
+#[derive(Copy, Clone)]
+pub enum Color {
+    Black = 30,
+    Red = 31,
+    Green = 32,
+    Yellow = 33,
+    Blue = 34,
+    Magenta = 35,
+    Cyan = 36,
+    White = 37,
+}
+
+/// An escape sequence to be used with print!,
+/// e.g., `println!("{}warning{}", fg(Color::Yellow), reset())`
+#[derive(Copy, Clone)]
+pub enum Esc {
+    Fg(Color),
+    Bold,
+    Reset,
+    ClearScreen,
+    ClearLine,
+    // 1-based row and column, like the terminal
+    MoveTo(usize, usize),
+    HideCursor,
+    ShowCursor,
+}
+
+impl fmt::Display for Esc {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        if !is_enabled() {
+            return Ok(());
+        }
+        match *self {
+            Esc::Fg(color) => write!(f, "\x1b[{}m", color as u8),
+            Esc::Bold => f.write_str("\x1b[1m"),
+            Esc::Reset => f.write_str("\x1b[0m"),
+            Esc::ClearScreen => f.write_str("\x1b[2J\x1b[H"),
+            Esc::ClearLine => f.write_str("\x1b[2K\r"),
+            Esc::MoveTo(row, col) => write!(f, "\x1b[{};{}H", row, col),
+            Esc::HideCursor => f.write_str("\x1b[?25l"),
+            Esc::ShowCursor => f.write_str("\x1b[?25h"),
+        }
+    }
+}
+
+#[inline]
+pub fn fg(color: Color) -> Esc {
+    Esc::Fg(color)
+}
+
+#[inline]
+pub fn reset() -> Esc {
+    Esc::Reset
+}
+
+/// Log levels used by the info!/warn!/error! macros
+#[derive(Copy, Clone)]
+pub enum Level {
+    Info,
+    Warn,
+    Error,
+}
+
+impl Level {
+    pub fn tag(&self) -> &'static str {
+        match self {
+            Level::Info => "info",
+            Level::Warn => "warn",
+            Level::Error => "error",
+        }
+    }
+
+    pub fn color(&self) -> Color {
+        match self {
+            Level::Info => Color::Green,
+            Level::Warn => Color::Yellow,
+            Level::Error => Color::Red,
+        }
+    }
+}
+
+/// Print a message tagged and colored with its log level
+#[macro_export]
+macro_rules! log {
+    ($level:expr, $($arg:tt)*) => {{
+        let level: $crate::console::ansi::Level = $level;
+        $crate::print!("{}[{}]{} ", $crate::console::ansi::fg(level.color()),
+            level.tag(), $crate::console::ansi::reset());
+        $crate::println!($($arg)*);
+    }};
+}
+
+#[macro_export]
+macro_rules! info {
+    ($($arg:tt)*) => {$crate::log!($crate::console::ansi::Level::Info, $($arg)*)};
+}
+
+#[macro_export]
+macro_rules! warn {
+    ($($arg:tt)*) => {$crate::log!($crate::console::ansi::Level::Warn, $($arg)*)};
+}
+
+#[macro_export]
+macro_rules! error {
+    ($($arg:tt)*) => {$crate::log!($crate::console::ansi::Level::Error, $($arg)*)};
+}
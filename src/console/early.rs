@@ -0,0 +1,57 @@
+//! Early boot console
+//!
+//! A polling uart writer that does not depend on any cpu/proc structure,
+//! nor on the spinlock's push_off/pop_off,
+//! so it is usable from the very first instructions of start().
+//! print!/println! go here until consoleinit() is done.
+//! With the sbi feature, it uses the firmware's console instead of the uart.
+
+use core::fmt;
+use core::sync::atomic::{AtomicBool, Ordering};
+
+#[cfg(not(feature = "sbi"))]
+use super::uart;
+
+/// A bare test-and-set lock,
+/// because the harts run start() at the same time.
+static LOCK: AtomicBool = AtomicBool::new(false);
+
+struct Early;
+
+impl fmt::Write for Early {
+    fn write_str(&mut self, s: &str) -> fmt::Result {
+        for byte in s.bytes() {
+            #[cfg(not(feature = "sbi"))]
+            uart::uartputc_sync(byte);
+            // the firmware's console is already set up,
+            // also on boards whose uart is not where UART0 says
+            #[cfg(feature = "sbi")]
+            crate::sbi::console_putchar(byte);
+        }
+        Ok(())
+    }
+}
+
+pub fn _print(args: fmt::Arguments) {
+    use fmt::Write;
+
+    while LOCK.compare_exchange(false, true, Ordering::Acquire, Ordering::Relaxed).is_err() {}
+    Early.write_fmt(args).expect("early _print: error");
+    LOCK.store(false, Ordering::Release);
+}
+
+#[macro_export]
+macro_rules! early_print {
+    ($($arg:tt)*) => {
+        $crate::console::early::_print(format_args!($($arg)*));
+    };
+}
+
+#[macro_export]
+macro_rules! early_println {
+    () => {$crate::early_print!("\n")};
+    ($fmt:expr) => {$crate::early_print!(concat!($fmt, "\n"))};
+    ($fmt:expr, $($arg:tt)*) => {
+        $crate::early_print!(concat!($fmt, "\n"), $($arg)*)
+    };
+}
@@ -0,0 +1,313 @@
+//! Text console on the virtio gpu framebuffer
+//!
+//! Renders console output with the 8x8 font,
+//! and understands the few escape sequences the kernel and sh emit,
+//! i.e., SGR colors (m), cursor position (H), erase display (J) and erase line (K).
+//!
+//! The state has no lock of its own,
+//! it is only written through console sinks, which printf serializes.
+
+use crate::driver::virtio_gpu;
+
+use super::font::{self, HEIGHT, WIDTH};
+use super::{register_sink, Sink};
+
+/// ansi colors 0-7, then their bright version
+const PALETTE: [u32; 16] = [
+    0x000000, 0xaa0000, 0x00aa00, 0xaa5500, 0x0000aa, 0xaa00aa, 0x00aaaa, 0xaaaaaa,
+    0x555555, 0xff5555, 0x55ff55, 0xffff55, 0x5555ff, 0xff55ff, 0x55ffff, 0xffffff,
+];
+const DEFAULT_FG: usize = 7;
+const DEFAULT_BG: usize = 0;
+
+const TAB: usize = 8;
+const MAX_PARAMS: usize = 4;
+
+#[derive(Copy, Clone, PartialEq)]
+enum State {
+    Normal,
+    Esc,
+    Csi,
+}
+
+struct FbCon {
+    cols: usize,
+    rows: usize,
+    col: usize,
+    row: usize,
+    fg: usize,
+    bg: usize,
+    bold: bool,
+    state: State,
+    params: [usize; MAX_PARAMS],
+    nparams: usize,
+    // text rows to be flushed, [dirty_lo, dirty_hi)
+    dirty_lo: usize,
+    dirty_hi: usize,
+}
+
+static mut FBCON: FbCon = FbCon::new();
+
+struct FbSink;
+
+impl Sink for FbSink {
+    fn name(&self) -> &'static str {
+        "fb"
+    }
+
+    fn putc(&self, c: u8) {
+        unsafe { FBCON.putc(c) }
+    }
+
+    fn flush(&self) {
+        unsafe { FBCON.flush() }
+    }
+}
+
+static FB_SINK: FbSink = FbSink;
+
+/// Register the framebuffer console as a console sink,
+/// if the gpu is present.
+/// Must be called only during boot on the boot hart.
+pub unsafe fn init() {
+    if !virtio_gpu::is_present() {
+        return;
+    }
+    let (width, height) = virtio_gpu::resolution();
+    FBCON.cols = width / WIDTH;
+    FBCON.rows = height / HEIGHT;
+    FBCON.clear(0, FBCON.rows);
+    FBCON.flush();
+    if let Err(err) = register_sink(&FB_SINK) {
+        println!("fbcon: {}", err);
+    }
+}
+
+impl FbCon {
+    const fn new() -> Self {
+        Self {
+            cols: 0,
+            rows: 0,
+            col: 0,
+            row: 0,
+            fg: DEFAULT_FG,
+            bg: DEFAULT_BG,
+            bold: false,
+            state: State::Normal,
+            params: [0; MAX_PARAMS],
+            nparams: 0,
+            dirty_lo: 0,
+            dirty_hi: 0,
+        }
+    }
+
+    fn putc(&mut self, c: u8) {
+        if self.rows == 0 {
+            return;
+        }
+
+        match self.state {
+            State::Normal => self.normal(c),
+            State::Esc => {
+                if c == b'[' {
+                    self.state = State::Csi;
+                    self.params = [0; MAX_PARAMS];
+                    self.nparams = 0;
+                } else {
+                    self.state = State::Normal;
+                }
+            }
+            State::Csi => self.csi(c),
+        }
+    }
+
+    fn normal(&mut self, c: u8) {
+        match c {
+            0x1b => self.state = State::Esc,
+            b'\n' => {
+                self.col = 0;
+                self.newline();
+            }
+            b'\r' => self.col = 0,
+            // backspace
+            8 => {
+                if self.col > 0 {
+                    self.col -= 1;
+                }
+            }
+            b'\t' => {
+                self.col = (self.col / TAB + 1) * TAB;
+                if self.col >= self.cols {
+                    self.col = 0;
+                    self.newline();
+                }
+            }
+            _ => {
+                if self.col >= self.cols {
+                    self.col = 0;
+                    self.newline();
+                }
+                self.draw(c);
+                self.col += 1;
+            }
+        }
+    }
+
+    fn csi(&mut self, c: u8) {
+        match c {
+            b'0'..=b'9' => {
+                if self.nparams == 0 {
+                    self.nparams = 1;
+                }
+                let p = &mut self.params[self.nparams - 1];
+                *p = *p * 10 + (c - b'0') as usize;
+                return;
+            }
+            b';' => {
+                if self.nparams == 0 {
+                    self.nparams = 1;
+                }
+                if self.nparams < MAX_PARAMS {
+                    self.nparams += 1;
+                }
+                return;
+            }
+            // private modes, e.g., cursor visibility, are ignored
+            b'?' => return,
+            b'm' => self.sgr(),
+            b'H' | b'f' => {
+                let row = if self.params[0] > 0 { self.params[0] - 1 } else { 0 };
+                let col = if self.params[1] > 0 { self.params[1] - 1 } else { 0 };
+                self.row = if row < self.rows { row } else { self.rows - 1 };
+                self.col = if col < self.cols { col } else { self.cols - 1 };
+            }
+            b'J' => match self.params[0] {
+                0 => {
+                    self.clear_line(self.col);
+                    self.clear(self.row + 1, self.rows);
+                }
+                _ => self.clear(0, self.rows),
+            },
+            b'K' => match self.params[0] {
+                0 => self.clear_line(self.col),
+                _ => self.clear_line(0),
+            },
+            _ => {}
+        }
+        self.state = State::Normal;
+    }
+
+    /// select graphic rendition, only colors and bold
+    fn sgr(&mut self) {
+        let n = if self.nparams == 0 { 1 } else { self.nparams };
+        for i in 0..n {
+            match self.params[i] {
+                0 => {
+                    self.fg = DEFAULT_FG;
+                    self.bg = DEFAULT_BG;
+                    self.bold = false;
+                }
+                1 => self.bold = true,
+                22 => self.bold = false,
+                p @ 30..=37 => self.fg = p - 30,
+                39 => self.fg = DEFAULT_FG,
+                p @ 40..=47 => self.bg = p - 40,
+                49 => self.bg = DEFAULT_BG,
+                _ => {}
+            }
+        }
+    }
+
+    fn newline(&mut self) {
+        if self.row + 1 < self.rows {
+            self.row += 1;
+        } else {
+            self.scroll();
+        }
+    }
+
+    /// Move everything up by one text row
+    fn scroll(&mut self) {
+        let fb = unsafe { virtio_gpu::framebuffer() };
+        let line = virtio_gpu::resolution().0 * HEIGHT;
+        let used = self.rows * line;
+        fb.copy_within(line..used, 0);
+        self.clear(self.rows - 1, self.rows);
+        self.mark(0, self.rows);
+    }
+
+    fn draw(&mut self, c: u8) {
+        let fb = unsafe { virtio_gpu::framebuffer() };
+        let stride = virtio_gpu::resolution().0;
+        let fg = PALETTE[if self.bold { self.fg + 8 } else { self.fg }];
+        let bg = PALETTE[self.bg];
+        let glyph = font::glyph(c);
+        let x0 = self.col * WIDTH;
+        let y0 = self.row * HEIGHT;
+        for (dy, bits) in glyph.iter().enumerate() {
+            let base = (y0 + dy) * stride + x0;
+            for dx in 0..WIDTH {
+                fb[base + dx] = if bits & (1 << dx) != 0 { fg } else { bg };
+            }
+        }
+        self.mark(self.row, self.row + 1);
+    }
+
+    /// Clear text rows [lo, hi) with the background color
+    fn clear(&mut self, lo: usize, hi: usize) {
+        if lo >= hi {
+            return;
+        }
+        let fb = unsafe { virtio_gpu::framebuffer() };
+        let line = virtio_gpu::resolution().0 * HEIGHT;
+        let bg = PALETTE[self.bg];
+        for px in fb[lo * line..hi * line].iter_mut() {
+            *px = bg;
+        }
+        self.mark(lo, hi);
+    }
+
+    /// Clear current text row from column col to the end
+    fn clear_line(&mut self, col: usize) {
+        let fb = unsafe { virtio_gpu::framebuffer() };
+        let stride = virtio_gpu::resolution().0;
+        let bg = PALETTE[self.bg];
+        for dy in 0..HEIGHT {
+            let base = (self.row * HEIGHT + dy) * stride;
+            for px in fb[base + col * WIDTH..base + self.cols * WIDTH].iter_mut() {
+                *px = bg;
+            }
+        }
+        self.mark(self.row, self.row + 1);
+    }
+
+    fn mark(&mut self, lo: usize, hi: usize) {
+        if self.dirty_lo == self.dirty_hi {
+            self.dirty_lo = lo;
+            self.dirty_hi = hi;
+        } else {
+            if lo < self.dirty_lo {
+                self.dirty_lo = lo;
+            }
+            if hi > self.dirty_hi {
+                self.dirty_hi = hi;
+            }
+        }
+    }
+
+    /// Send the modified rows to the display
+    fn flush(&mut self) {
+        if self.dirty_lo == self.dirty_hi {
+            return;
+        }
+        let width = virtio_gpu::resolution().0;
+        virtio_gpu::flush(
+            0,
+            self.dirty_lo * HEIGHT,
+            width,
+            (self.dirty_hi - self.dirty_lo) * HEIGHT,
+        );
+        self.dirty_lo = 0;
+        self.dirty_hi = 0;
+    }
+}
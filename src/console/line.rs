@@ -0,0 +1,163 @@
+//! The line discipline of a console's input, see vt.rs
+//!
+//! In canonical mode, as xv6's console, what is typed is edited on the current line,
+//! backspace or delete erases the last character, Ctrl-U the whole line,
+//! and readers only get it once it ends, with a newline or a Ctrl-D, or fills the buffer.
+//! A Ctrl-D starting a line reads as the end of the file.
+//! Otherwise, with ICANON off, see termios.rs, each character is for readers as it comes.
+//! What is kept is echoed, unless ECHO is off.
+
+use crate::consts::CONSOLE_BUF;
+
+pub const CTRL_D: u8 = 0x04;
+pub const CTRL_H: u8 = 0x08;
+pub const CTRL_P: u8 = 0x10;
+pub const CTRL_U: u8 = 0x15;
+pub const DEL: u8 = 0x7f;
+
+/// Erases the character before the cursor
+const BACKSPACE: &[u8] = b"\x08 \x08";
+
+/// Input of a console, wrapping indices into buf
+pub struct Line {
+    buf: [u8; CONSOLE_BUF],
+    r: usize, // Read index
+    w: usize, // Write index, readers get what is before it
+    e: usize, // Edit index
+}
+
+impl Line {
+    pub const fn new() -> Self {
+        Self { buf: [0; CONSOLE_BUF], r: 0, w: 0, e: 0 }
+    }
+
+    /// Take c as typed, echoing through echo, return whether readers have more to get
+    pub fn edit(&mut self, c: u8, canonical: bool, echo: &mut dyn FnMut(&[u8])) -> bool {
+        if !canonical {
+            if self.e - self.r < CONSOLE_BUF {
+                self.put(c);
+                echo(&[c]);
+                self.w = self.e;
+                return true;
+            }
+            return false;
+        }
+        match c {
+            CTRL_U => {
+                while self.e != self.w && self.buf[(self.e - 1) % CONSOLE_BUF] != b'\n' {
+                    self.e -= 1;
+                    echo(BACKSPACE);
+                }
+                false
+            }
+            CTRL_H | DEL => {
+                if self.e != self.w {
+                    self.e -= 1;
+                    echo(BACKSPACE);
+                }
+                false
+            }
+            0 => false,
+            _ if self.e - self.r == CONSOLE_BUF => false,
+            _ => {
+                let c = if c == b'\r' { b'\n' } else { c };
+                self.put(c);
+                if c != CTRL_D {
+                    echo(&[c]);
+                }
+                if c == b'\n' || c == CTRL_D || self.e - self.r == CONSOLE_BUF {
+                    self.w = self.e;
+                    true
+                } else {
+                    false
+                }
+            }
+        }
+    }
+
+    fn put(&mut self, c: u8) {
+        self.buf[self.e % CONSOLE_BUF] = c;
+        self.e += 1;
+    }
+
+    /// Whether readers have something to get
+    pub fn readable(&self) -> bool {
+        self.r != self.w
+    }
+
+    /// Take what readers have into dst, up to the end of a line,
+    /// a Ctrl-D is not copied, and one after what was copied is left for the next read to get 0
+    pub fn read(&mut self, dst: &mut [u8]) -> usize {
+        let mut n = 0;
+        while n < dst.len() && self.r != self.w {
+            let c = self.buf[self.r % CONSOLE_BUF];
+            if c == CTRL_D {
+                if n == 0 {
+                    self.r += 1;
+                }
+                break;
+            }
+            self.r += 1;
+            dst[n] = c;
+            n += 1;
+            if c == b'\n' {
+                break;
+            }
+        }
+        n
+    }
+}
+
+#[cfg(feature = "unit_test")]
+pub mod tests {
+    use super::*;
+
+    fn type_in(line: &mut Line, s: &[u8], canonical: bool, echoed: &mut [u8; 32]) -> (bool, usize) {
+        let mut n = 0;
+        let mut ready = false;
+        for c in s.iter() {
+            ready = line.edit(*c, canonical, &mut |bytes: &[u8]| {
+                echoed[n..n + bytes.len()].copy_from_slice(bytes);
+                n += bytes.len();
+            });
+        }
+        (ready, n)
+    }
+
+    /// Editing keys change the line until it ends, Ctrl-D is the end of the file
+    pub fn line_editing() {
+        let mut line = Line::new();
+        let mut echoed = [0u8; 32];
+        let mut buf = [0u8; 16];
+
+        assert_eq!(type_in(&mut line, b"lx\x7fs", true, &mut echoed), (false, 6));
+        assert_eq!(&echoed[..6], b"lx\x08 \x08s");
+        assert!(!line.readable());
+        assert_eq!(line.read(&mut buf), 0);
+        assert_eq!(type_in(&mut line, b"\r", true, &mut echoed), (true, 1));
+        assert_eq!(&echoed[..1], b"\n");
+        assert_eq!(line.read(&mut buf), 3);
+        assert_eq!(&buf[..3], b"ls\n");
+
+        type_in(&mut line, b"abc\x15", true, &mut echoed);
+        assert!(!line.readable());
+        type_in(&mut line, b"d\x04", true, &mut echoed);
+        assert_eq!(line.read(&mut buf), 1);
+        assert_eq!(buf[0], b'd');
+        assert!(line.readable());
+        assert_eq!(line.read(&mut buf), 0);
+        assert!(!line.readable());
+
+        assert_eq!(type_in(&mut line, b"\x7f", false, &mut echoed), (true, 1));
+        assert_eq!(line.read(&mut buf), 1);
+        assert_eq!(buf[0], DEL);
+
+        for _ in 0..CONSOLE_BUF - 1 {
+            line.edit(b'y', true, &mut |_: &[u8]| {});
+        }
+        assert!(!line.readable());
+        assert!(line.edit(b'y', true, &mut |_: &[u8]| {}));
+        assert!(!line.edit(b'z', true, &mut |_: &[u8]| {}));
+    }
+    crate::kernel_test!(line_editing);
+}
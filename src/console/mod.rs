@@ -1,38 +1,243 @@
-use crate::consts::CONSOLE_BUF as INPUT_BUF;
+use core::sync::atomic::{AtomicBool, Ordering};
+
+use crate::consts::{NSINK, UART_TX_BUF};
+use crate::monitor;
+use crate::printf;
+use crate::process::PROC_MANAGER;
 use crate::spinlock::SpinLock;
 
+pub mod ansi;
+pub mod early;
+pub mod fbcon;
+mod font;
+pub mod line;
+pub mod termios;
+pub mod vt;
+#[cfg(not(feature = "fu740"))]
 mod uart;
+#[cfg(feature = "fu740")]
+mod sifive_uart;
+#[cfg(feature = "fu740")]
+use sifive_uart as uart;
+
+/// Set once consoleinit() is done,
+/// before that print! goes to the early console.
+static READY: AtomicBool = AtomicBool::new(false);
+
+/// A console output device
+///
+/// Every byte of console output is sent to all enabled sinks,
+/// e.g., the uart and the framebuffer console.
+pub trait Sink: Sync {
+    fn name(&self) -> &'static str;
+    fn putc(&self, c: u8);
+    /// Called after a whole line has been written
+    fn flush(&self) {}
+}
+
+struct SinkSlot {
+    sink: Option<&'static dyn Sink>,
+    enabled: AtomicBool,
+}
+
+impl SinkSlot {
+    const fn new() -> Self {
+        Self {
+            sink: None,
+            enabled: AtomicBool::new(false),
+        }
+    }
+}
+
+/// Registered sinks, only modified during boot on the boot hart,
+/// afterwards only the enabled flags change.
+static mut SINKS: [SinkSlot; NSINK] = [const { SinkSlot::new() }; NSINK];
+
+/// Output on its way to the uart, oldest at r,
+/// sent as the transmitter takes it, see uartstart
+struct Tx {
+    buf: [u8; UART_TX_BUF],
+    r: usize,
+    n: usize,
+}
 
-struct Cons {
-    buf: [u8; INPUT_BUF],
-    r: usize, // Read index
-    w: usize, // Write index
-    e: usize, // Edit index
-}
-
-static CONS: SpinLock<Cons> = SpinLock::new(
-    Cons {
-        buf: [0; INPUT_BUF],
-        r: 0,
-        w: 0,
-        e: 0,
-    },
-    "cons",
-);
-
-// fn consputbs() {
-//     // b'\b' not supported in rust
-//     const BACKSPACE: u8 = 8;
-//     uart::uartputc(BACKSPACE);
-//     uart::uartputc(b' ');
-//     uart::uartputc(BACKSPACE);
-// }
+static TX: SpinLock<Tx> = SpinLock::new(Tx { buf: [0; UART_TX_BUF], r: 0, n: 0 }, "uart tx");
+
+/// Send what the transmitter takes now, and have its interrupt come for the rest
+fn uartstart(tx: &mut Tx) {
+    while tx.n > 0 && uart::tx_ready() {
+        uart::tx_put(tx.buf[tx.r]);
+        tx.r = (tx.r + 1) % UART_TX_BUF;
+        tx.n -= 1;
+    }
+    uart::tx_intr(tx.n > 0);
+}
+
+/// Wait for the transmitter until all of the output is sent
+fn uartdrain(tx: &mut Tx) {
+    while tx.n > 0 {
+        uartstart(tx);
+    }
+}
+
+struct UartSink;
+
+impl Sink for UartSink {
+    fn name(&self) -> &'static str {
+        "uart"
+    }
+
+    /// Buffer c, a panic sends it right away and leaves what is buffered.
+    /// With the buffer full it waits for the uart itself,
+    /// not sleeping, whoever prints may hold a spinlock.
+    fn putc(&self, c: u8) {
+        if printf::panicked() {
+            uart::uartputc_sync(c);
+            return;
+        }
+        let mut tx = TX.lock();
+        while tx.n == UART_TX_BUF {
+            uartstart(&mut tx);
+        }
+        let w = (tx.r + tx.n) % UART_TX_BUF;
+        tx.buf[w] = c;
+        tx.n += 1;
+        uartstart(&mut tx);
+        drop(tx);
+    }
+}
+
+static UART_SINK: UartSink = UartSink;
+
+/// Add an output sink, it is enabled right away.
+/// Must be called only during boot on the boot hart.
+pub unsafe fn register_sink(sink: &'static dyn Sink) -> Result<(), &'static str> {
+    for slot in SINKS.iter_mut() {
+        if slot.sink.is_none() {
+            slot.sink = Some(sink);
+            slot.enabled.store(true, Ordering::SeqCst);
+            return Ok(());
+        }
+    }
+    Err("console: too many sinks")
+}
+
+/// Enable or disable the sink with the given name.
+/// Return false if there is no such sink.
+pub fn set_sink_enabled(name: &str, on: bool) -> bool {
+    for slot in unsafe { SINKS.iter() } {
+        match slot.sink {
+            Some(sink) if sink.name() == name => {
+                slot.enabled.store(on, Ordering::SeqCst);
+                return true;
+            }
+            _ => {}
+        }
+    }
+    false
+}
 
 pub fn consputc(c: u8) {
-    uart::uartputc(c);
+    for slot in unsafe { SINKS.iter() } {
+        if let Some(sink) = slot.sink {
+            if slot.enabled.load(Ordering::Relaxed) {
+                sink.putc(c);
+            }
+        }
+    }
+}
+
+/// Let sinks present what has been written so far
+pub fn consflush() {
+    for slot in unsafe { SINKS.iter() } {
+        if let Some(sink) = slot.sink {
+            if slot.enabled.load(Ordering::Relaxed) {
+                sink.flush();
+            }
+        }
+    }
+}
+
+/// Serial line settings of the uart
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub struct UartConfig {
+    pub baud: u32,
+    /// bits in a word, 5-8
+    pub bits: u8,
+    pub two_stop: bool,
+    /// RTS/CTS flow control
+    pub flow: bool,
+}
+
+/// Settings of the uart after boot, 8 bits, one stop bit, no flow control
+pub const UART_DEFAULT: UartConfig = UartConfig {
+    baud: uart::DEFAULT_BAUD,
+    bits: 8,
+    two_stop: false,
+    flow: false,
+};
+
+/// Change the uart's settings, see termios.rs for the ioctl,
+/// once the output buffered is sent with the old ones
+pub fn uart_configure(config: &UartConfig) -> Result<(), &'static str> {
+    let mut tx = TX.lock();
+    uartdrain(&mut tx);
+    let configured = uart::uartconfig(config);
+    drop(tx);
+    configured
+}
+
+/// Poll the uart for an input character, e.g., for the kernel monitor,
+/// sending the output buffered meanwhile, the monitor runs with interrupts off
+pub fn uartgetc() -> Option<u8> {
+    uartstart(&mut TX.lock());
+    uart::uartgetc()
+}
+
+/// The uart interrupt, drain its input, and send more of the output.
+/// Ctrl-T enters the kernel monitor, see monitor.rs, on the kernel's log console,
+/// Ctrl-P dumps the process table into the kernel's log, like the monitor's ps,
+/// the rest goes to the console shown, see vt.rs.
+pub fn uartintr() {
+    while let Some(c) = uart::uartgetc() {
+        if c == monitor::MAGIC {
+            let _ = vt::switch(0);
+            monitor::enter();
+        } else if c == line::CTRL_P {
+            unsafe { PROC_MANAGER.dump() };
+        } else {
+            vt::input(c);
+        }
+    }
+    uartstart(&mut TX.lock());
+}
+
+/// Read from the console device with minor number minor, its vt, see fs/file.rs,
+/// failing if nonblock while no line is in
+pub fn consoleread(minor: u16, dst: &mut [u8], nonblock: bool) -> Result<usize, &'static str> {
+    vt::read_wait(minor as usize, dst, nonblock)
+}
+
+/// Write to the console device with minor number minor, console 0 is not for processes
+pub fn consolewrite(minor: u16, src: &[u8]) -> Result<usize, &'static str> {
+    vt::write(minor as usize, src)?;
+    Ok(src.len())
+}
+
+/// Carry out ioctl request req on the console device with minor number minor,
+/// every vt shares the attributes, see termios.rs
+pub fn consoleioctl(_minor: u16, req: usize, arg: &mut [u8]) -> Result<(), &'static str> {
+    termios::ioctl(req, arg)
 }
 
 // must be called only once in rmain.rs:rust_main
 pub unsafe fn consoleinit() {
     uart::uartinit();
+    register_sink(&UART_SINK).unwrap();
+    READY.store(true, Ordering::SeqCst);
+}
+
+#[inline]
+pub fn is_ready() -> bool {
+    READY.load(Ordering::Relaxed)
 }
@@ -0,0 +1,102 @@
+//! SiFive UART, on the FU740 instead of the ns16550 in uart.rs,
+//! refer to the FU740-C000 manual, chapter 18.
+//! The firmware has set the baud rate already, its divisor is kept.
+//! As in uart.rs, output past the early console goes through the transmit interrupt,
+//! which comes while the transmit fifo is empty, so tx_intr() turns it off once all is sent.
+
+use core::ptr;
+use core::convert::Into;
+
+use crate::consts::UART0;
+
+use super::UartConfig;
+
+const TXDATA: usize = 0x00;
+const RXDATA: usize = 0x04;
+const TXCTRL: usize = 0x08;
+const RXCTRL: usize = 0x0c;
+const IE: usize = 0x10;
+const IP: usize = 0x14;
+const DIV: usize = 0x18;
+
+/// in txdata, the transmit fifo is full
+const TX_FULL: u32 = 1 << 31;
+/// in rxdata, the receive fifo is empty
+const RX_EMPTY: u32 = 1 << 31;
+/// in txctrl and rxctrl
+const ENABLE: u32 = 1 << 0;
+/// in txctrl, two stop bits
+const NSTOP: u32 = 1 << 1;
+/// in txctrl, a watermark of 1, the fifo is below it once empty
+const TXCNT_1: u32 = 1 << 16;
+/// in ie, the transmit fifo is below its watermark
+const IE_TXWM: u32 = 1 << 0;
+/// in ie, the receive fifo is above its watermark, i.e., not empty
+const IE_RXWM: u32 = 1 << 1;
+
+#[inline]
+fn read(reg: usize) -> u32 {
+    unsafe { ptr::read_volatile((Into::<usize>::into(UART0) + reg) as *const u32) }
+}
+
+#[inline]
+fn write(reg: usize, value: u32) {
+    unsafe { ptr::write_volatile((Into::<usize>::into(UART0) + reg) as *mut u32, value) }
+}
+
+pub fn uartinit() {
+    // disable interrupts.
+    write(IE, 0);
+
+    // enable the transmitter, one stop bit, with a watermark of 1,
+    // and the receiver, with a watermark of 0.
+    write(TXCTRL, ENABLE | TXCNT_1);
+    write(RXCTRL, ENABLE);
+
+    // enable receive interrupts, transmit ones are enabled once there is output.
+    write(IE, IE_RXWM);
+}
+
+/// The baud rate the firmware sets, the divisor it leaves is for a clock only it knows
+pub const DEFAULT_BAUD: u32 = 115200;
+
+/// Set the number of stop bits, it has only 8-bit words and no flow control,
+/// and keeps the baud rate of the firmware.
+pub fn uartconfig(config: &UartConfig) -> Result<(), &'static str> {
+    if config.baud != DEFAULT_BAUD || config.bits != 8 || config.flow {
+        return Err("uart: only the stop bits can be changed");
+    }
+    let txctrl = read(TXCTRL);
+    write(TXCTRL, if config.two_stop { txctrl | NSTOP } else { txctrl & !NSTOP });
+    Ok(())
+}
+
+/// Send c, waiting for room in the fifo, not using interrupts
+pub fn uartputc_sync(c: u8) {
+    while !tx_ready() {}
+    tx_put(c);
+}
+
+/// Whether the transmit fifo can take another byte
+pub fn tx_ready() -> bool {
+    read(TXDATA) & TX_FULL == 0
+}
+
+/// Send c, the fifo is to have room
+pub fn tx_put(c: u8) {
+    write(TXDATA, c as u32);
+}
+
+/// Turn the transmit interrupt on or off
+pub fn tx_intr(on: bool) {
+    write(IE, if on { IE_RXWM | IE_TXWM } else { IE_RXWM });
+}
+
+/// Read one input character, if one has arrived
+pub fn uartgetc() -> Option<u8> {
+    // reading pops the fifo, so rxdata is read once
+    match read(RXDATA) {
+        data if data & RX_EMPTY != 0 => None,
+        data => Some(data as u8),
+    }
+}
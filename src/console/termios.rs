@@ -0,0 +1,238 @@
+//! Terminal attributes and window size of the console, like termios
+//!
+//! The console keeps one set of attributes, with the layout and request numbers of Linux,
+//! so that ported programs can query and change them:
+//! - TCGETS and TCSETS, a Termios with ECHO and ICANON, and VMIN and VTIME
+//!   for reads that are not canonical, and the baud rate, CSIZE, CSTOPB and CRTSCTS
+//!   of cflag setting up the uart
+//! - TIOCGWINSZ and TIOCSWINSZ, a Winsize, which the console does not know by itself,
+//!   it is 24x80 until set, e.g., by a program asking the terminal
+//!
+//! The ioctl syscall on a console's file lands in ioctl() here, see consoleioctl().
+//! The line discipline honors ECHO and ICANON, see line.rs.
+//! LTODO - VMIN and VTIME, a read that is not canonical returns with the first character.
+
+use core::mem;
+use core::ptr;
+
+use crate::spinlock::SpinLock;
+
+use super::{uart_configure, UartConfig, UART_DEFAULT};
+
+/// ioctl requests
+pub const TCGETS: usize = 0x5401;
+pub const TCSETS: usize = 0x5402;
+pub const TIOCGWINSZ: usize = 0x5413;
+pub const TIOCSWINSZ: usize = 0x5414;
+
+/// Termios lflag
+pub const ICANON: u32 = 0o2;
+pub const ECHO: u32 = 0o10;
+
+/// Termios cflag
+pub const CBAUD: u32 = 0o10017;
+pub const CSIZE: u32 = 0o60;
+pub const CS5: u32 = 0o0;
+pub const CS6: u32 = 0o20;
+pub const CS7: u32 = 0o40;
+pub const CS8: u32 = 0o60;
+pub const CSTOPB: u32 = 0o100;
+pub const CRTSCTS: u32 = 0o20000000000;
+
+/// The CBAUD codes of the baud rates
+const BAUDS: [(u32, u32); 11] = [
+    (0o11, 1200), (0o13, 2400), (0o14, 4800), (0o15, 9600), (0o16, 19200), (0o17, 38400),
+    (0o10001, 57600), (0o10002, 115200), (0o10003, 230400), (0o10004, 460800), (0o10007, 921600),
+];
+
+/// Indices into Termios cc
+pub const VTIME: usize = 5;
+pub const VMIN: usize = 6;
+pub const NCCS: usize = 19;
+
+/// Linux's struct termios, only lflag, cflag and the VMIN and VTIME of cc are looked at
+#[repr(C)]
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub struct Termios {
+    pub iflag: u32,
+    pub oflag: u32,
+    pub cflag: u32,
+    pub lflag: u32,
+    pub line: u8,
+    pub cc: [u8; NCCS],
+}
+
+#[repr(C)]
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub struct Winsize {
+    pub row: u16,
+    pub col: u16,
+    pub xpixel: u16,
+    pub ypixel: u16,
+}
+
+struct Tty {
+    termios: Termios,
+    winsize: Winsize,
+}
+
+const DEFAULT: Tty = Tty {
+    termios: Termios {
+        iflag: 0,
+        oflag: 0,
+        cflag: cflag(&UART_DEFAULT),
+        lflag: ICANON | ECHO,
+        line: 0,
+        cc: {
+            let mut cc = [0; NCCS];
+            cc[VMIN] = 1;
+            cc
+        },
+    },
+    winsize: Winsize { row: 24, col: 80, xpixel: 0, ypixel: 0 },
+};
+
+static TTY: SpinLock<Tty> = SpinLock::new(DEFAULT, "tty");
+
+pub fn termios() -> Termios {
+    TTY.lock().termios
+}
+
+/// Whether input is echoed and taken a line at a time
+pub fn echo() -> bool {
+    TTY.lock().termios.lflag & ECHO != 0
+}
+
+pub fn canonical() -> bool {
+    TTY.lock().termios.lflag & ICANON != 0
+}
+
+pub fn winsize() -> Winsize {
+    TTY.lock().winsize
+}
+
+const fn cflag(config: &UartConfig) -> u32 {
+    let mut cflag = CS5 + (config.bits as u32 - 5) * CS6;
+    if config.two_stop {
+        cflag |= CSTOPB;
+    }
+    if config.flow {
+        cflag |= CRTSCTS;
+    }
+    let mut i = 0;
+    while i < BAUDS.len() {
+        if BAUDS[i].1 == config.baud {
+            cflag |= BAUDS[i].0;
+        }
+        i += 1;
+    }
+    cflag
+}
+
+/// The uart settings of cflag
+fn uart_config(cflag: u32) -> Result<UartConfig, &'static str> {
+    let baud = BAUDS.iter().find(|(code, _)| *code == cflag & CBAUD).ok_or("termios: unknown baud rate")?.1;
+    Ok(UartConfig {
+        baud,
+        bits: 5 + ((cflag & CSIZE) / CS6) as u8,
+        two_stop: cflag & CSTOPB != 0,
+        flow: cflag & CRTSCTS != 0,
+    })
+}
+
+/// Read a T out of arg, which must be just as big
+fn get<T>(arg: &[u8]) -> Result<T, &'static str> {
+    if arg.len() != mem::size_of::<T>() {
+        return Err("ioctl: bad argument size");
+    }
+    Ok(unsafe { ptr::read_unaligned(arg.as_ptr() as *const T) })
+}
+
+fn put<T>(arg: &mut [u8], value: T) -> Result<(), &'static str> {
+    if arg.len() != mem::size_of::<T>() {
+        return Err("ioctl: bad argument size");
+    }
+    unsafe { ptr::write_unaligned(arg.as_mut_ptr() as *mut T, value); }
+    Ok(())
+}
+
+/// The size of the argument of ioctl request req
+pub fn ioctl_size(req: usize) -> Result<usize, &'static str> {
+    match req {
+        TCGETS | TCSETS => Ok(mem::size_of::<Termios>()),
+        TIOCGWINSZ | TIOCSWINSZ => Ok(mem::size_of::<Winsize>()),
+        _ => Err("ioctl: not a console request"),
+    }
+}
+
+/// Carry out ioctl request req on the console,
+/// arg holds the Termios or Winsize to set, or gets the one asked for.
+pub fn ioctl(req: usize, arg: &mut [u8]) -> Result<(), &'static str> {
+    match req {
+        TCGETS => put(arg, termios()),
+        TCSETS => {
+            let termios: Termios = get(arg)?;
+            let config = uart_config(termios.cflag)?;
+            if config != uart_config(self::termios().cflag)? {
+                uart_configure(&config)?;
+            }
+            TTY.lock().termios = termios;
+            Ok(())
+        }
+        TIOCGWINSZ => put(arg, winsize()),
+        TIOCSWINSZ => {
+            let winsize: Winsize = get(arg)?;
+            TTY.lock().winsize = winsize;
+            Ok(())
+        }
+        _ => Err("ioctl: not a console request"),
+    }
+}
+
+#[cfg(feature = "unit_test")]
+pub mod tests {
+    use super::*;
+
+    /// Raw mode and a window size go in and come back out
+    pub fn attributes() {
+        let saved = termios();
+        let mut arg = [0u8; mem::size_of::<Termios>()];
+        ioctl(TCGETS, &mut arg).unwrap();
+        let mut raw: Termios = get(&arg).unwrap();
+        assert_eq!(raw, saved);
+        raw.lflag &= !(ICANON | ECHO);
+        raw.cc[VMIN] = 0;
+        raw.cc[VTIME] = 10;
+        put(&mut arg, raw).unwrap();
+        ioctl(TCSETS, &mut arg).unwrap();
+        assert!(!echo() && !canonical());
+        assert_eq!(termios().cc[VTIME], 10);
+        assert!(ioctl(TCSETS, &mut arg[1..]).is_err());
+
+        // the uart is not reprogrammed here, only its settings are looked at
+        assert_eq!(uart_config(saved.cflag), Ok(UART_DEFAULT));
+        let config = UartConfig { baud: 9600, bits: 7, two_stop: true, flow: true };
+        assert_eq!(cflag(&config), 0o15 | CS7 | CSTOPB | CRTSCTS);
+        assert_eq!(uart_config(cflag(&config)), Ok(config));
+        raw.cflag = (raw.cflag & !CBAUD) | 0o10017;
+        put(&mut arg, raw).unwrap();
+        assert!(ioctl(TCSETS, &mut arg).is_err());
+        assert!(!echo());
+
+        let saved_winsize = winsize();
+        let mut size = [0u8; mem::size_of::<Winsize>()];
+        put(&mut size, Winsize { row: 50, col: 132, xpixel: 0, ypixel: 0 }).unwrap();
+        ioctl(TIOCSWINSZ, &mut size).unwrap();
+        let mut back = [0u8; mem::size_of::<Winsize>()];
+        ioctl(TIOCGWINSZ, &mut back).unwrap();
+        assert_eq!(back, size);
+        assert!(ioctl(0x1234, &mut back).is_err());
+        assert_eq!(ioctl_size(TIOCGWINSZ), Ok(back.len()));
+        assert_eq!(ioctl_size(TCSETS), Ok(arg.len()));
+        assert!(ioctl_size(0x1234).is_err());
+
+        TTY.lock().termios = saved;
+        TTY.lock().winsize = saved_winsize;
+    }
+    crate::kernel_test!(attributes);
+}
@@ -1,8 +1,17 @@
+//! ns16550a uart, qemu's virt board has one
+//!
+//! uartputc_sync() waits for the transmitter, for the early console and a panic,
+//! the rest of the output is buffered in console/mod.rs and sent from the transmit interrupt,
+//! tx_intr() turns it on while there is something to send.
+
 use core::ptr;
 use core::convert::Into;
+use core::sync::atomic::{AtomicBool, Ordering};
 
 use crate::consts::UART0;
 
+use super::UartConfig;
+
 macro_rules! Reg {
     ($reg: expr) => {
         Into::<usize>::into(UART0) + $reg
@@ -29,7 +38,36 @@ const IER: usize = 1;
 const FCR: usize = 2;
 const ISR: usize = 2;
 const LCR: usize = 3;
+const MCR: usize = 4;
 const LSR: usize = 5;
+const MSR: usize = 6;
+// with LCR_DLAB set, the divisor latch is in place of RHR and IER
+const DLL: usize = 0;
+const DLM: usize = 1;
+
+const IER_RX_ENABLE: u8 = 1 << 0;
+const IER_TX_ENABLE: u8 = 1 << 1;
+const LCR_DLAB: u8 = 1 << 7;
+const LCR_STOP2: u8 = 1 << 2;
+const MCR_DTR: u8 = 1 << 0;
+const MCR_RTS: u8 = 1 << 1;
+/// automatic RTS/CTS flow control, of the 16750 and most 16550 clones
+const MCR_AFE: u8 = 1 << 5;
+const LSR_RX_READY: u8 = 1 << 0;
+/// THR can take another byte
+const LSR_TX_IDLE: u8 = 1 << 5;
+const LSR_TEMT: u8 = 1 << 6;
+const MSR_CTS: u8 = 1 << 4;
+
+/// The uart's clock, the baud rate is it divided by 16 times the divisor
+const UART_CLOCK: u32 = 1_843_200;
+
+/// The baud rate uartinit sets
+pub const DEFAULT_BAUD: u32 = 38400;
+
+/// RTS/CTS flow control is on, the transmitter waits for CTS too,
+/// in case the uart has no MCR_AFE
+static FLOW: AtomicBool = AtomicBool::new(false);
 
 pub fn uartinit() {
     // disable interrupts.
@@ -51,11 +89,69 @@ pub fn uartinit() {
     // reset and enable FIFOs.
     WriteReg!(FCR, 0x07);
 
-    // enable receive interrupts.
-    WriteReg!(IER, 0x01);
+    // enable receive interrupts, transmit ones are enabled once there is output.
+    WriteReg!(IER, IER_RX_ENABLE);
+}
+
+/// Set the baud rate, word length, stop bits and flow control,
+/// once what is being sent is out.
+pub fn uartconfig(config: &UartConfig) -> Result<(), &'static str> {
+    if config.bits < 5 || config.bits > 8 {
+        return Err("uart: word length not in 5-8");
+    }
+    let divisor = match config.baud.checked_mul(16) {
+        Some(d) if d != 0 && UART_CLOCK.is_multiple_of(d) => UART_CLOCK / d,
+        _ => 0,
+    };
+    if divisor == 0 || divisor > 0xffff {
+        return Err("uart: baud rate not reachable");
+    }
+
+    while ReadReg!(LSR) & LSR_TEMT == 0 {}
+    let mut lcr = config.bits - 5;
+    if config.two_stop {
+        lcr |= LCR_STOP2;
+    }
+    WriteReg!(LCR, LCR_DLAB);
+    WriteReg!(DLL, divisor as u8);
+    WriteReg!(DLM, (divisor >> 8) as u8);
+    WriteReg!(LCR, lcr);
+    let mut mcr = MCR_DTR | MCR_RTS;
+    if config.flow {
+        mcr |= MCR_AFE;
+    }
+    WriteReg!(MCR, mcr);
+    FLOW.store(config.flow, Ordering::Relaxed);
+    Ok(())
+}
+
+/// Send c, waiting for the transmitter, not using interrupts
+pub fn uartputc_sync(c: u8) {
+    while !tx_ready() {}
+    tx_put(c);
+}
+
+/// Whether the transmitter can take another byte
+pub fn tx_ready() -> bool {
+    ReadReg!(LSR) & LSR_TX_IDLE != 0
+        && (!FLOW.load(Ordering::Relaxed) || ReadReg!(MSR) & MSR_CTS != 0)
 }
 
-pub fn uartputc(c: u8) {
-    while (ReadReg!(LSR) & (1 << 5)) == 0 {}
+/// Send c, the transmitter is to be ready
+pub fn tx_put(c: u8) {
     WriteReg!(THR, c);
 }
+
+/// Turn the transmit interrupt on or off, it comes when THR can take a byte again
+pub fn tx_intr(on: bool) {
+    WriteReg!(IER, if on { IER_RX_ENABLE | IER_TX_ENABLE } else { IER_RX_ENABLE });
+}
+
+/// Read one input character, if one has arrived
+pub fn uartgetc() -> Option<u8> {
+    if ReadReg!(LSR) & LSR_RX_READY != 0 {
+        Some(ReadReg!(RHR))
+    } else {
+        None
+    }
+}
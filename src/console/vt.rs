@@ -0,0 +1,290 @@
+//! Virtual consoles over the console's sinks
+//!
+//! There are NVT of them, one shown at a time, Ctrl-A then its number switches,
+//! Ctrl-A twice sends a Ctrl-A itself.
+//! Console 0 is the kernel's log, what print! writes only shows while it is shown,
+//! switching back to it writes out what was logged meanwhile, from the message ring.
+//! The others are for processes, each has its own input, fed by the uart interrupt
+//! while it is shown, through the line discipline, see line.rs,
+//! a scrollback of what was written to it, and echoed, redrawn when it is shown again,
+//! and a foreground process group.
+//! Entering the kernel monitor, and a panic, show console 0.
+//!
+//! A console's device file, see consoleread and consolewrite, reads its input and writes here.
+
+use core::sync::atomic::{AtomicUsize, Ordering};
+
+use crate::consts::{NVT, VT_SCROLLBACK};
+use crate::printf;
+use crate::process::{my_proc, PROC_MANAGER};
+use crate::spinlock::SpinLock;
+
+use super::line::Line;
+use super::{ansi, consflush, consputc, termios};
+
+/// Ctrl-A
+pub const VT_PREFIX: u8 = 0x01;
+
+/// Kept outside VTS, so that print! can look at it without a lock
+static SHOWN: AtomicUsize = AtomicUsize::new(0);
+
+/// Bytes in the order they came, the oldest go when it is full
+struct Ring<const N: usize> {
+    buf: [u8; N],
+    r: usize,
+    n: usize,
+}
+
+impl<const N: usize> Ring<N> {
+    const fn new() -> Self {
+        Self { buf: [0; N], r: 0, n: 0 }
+    }
+
+    fn push(&mut self, c: u8) {
+        if self.n == N {
+            self.r = (self.r + 1) % N;
+            self.n -= 1;
+        }
+        self.buf[(self.r + self.n) % N] = c;
+        self.n += 1;
+    }
+
+    fn get(&self, i: usize) -> u8 {
+        self.buf[(self.r + i) % N]
+    }
+}
+
+struct Vt {
+    input: Line,
+    scrollback: Ring<VT_SCROLLBACK>,
+    /// the foreground process group, 0 for none
+    pgrp: usize,
+}
+
+struct Vts {
+    vts: [Vt; NVT],
+    /// the last byte was VT_PREFIX
+    prefix: bool,
+    /// the message ring's sequence number when console 0 was left
+    log_seq: usize,
+}
+
+static VTS: SpinLock<Vts> = SpinLock::new(Vts {
+    vts: [const { Vt { input: Line::new(), scrollback: Ring::new(), pgrp: 0 } }; NVT],
+    prefix: false,
+    log_seq: 0,
+}, "vt");
+
+/// The console shown
+pub fn shown() -> usize {
+    SHOWN.load(Ordering::Relaxed)
+}
+
+/// Whether print! is to reach the sinks, a panic always does
+pub fn log_shown() -> bool {
+    shown() == 0 || printf::panicked()
+}
+
+/// Show console vt, redrawing it from its scrollback
+pub fn switch(vt: usize) -> Result<(), &'static str> {
+    if vt >= NVT {
+        return Err("vt: no such console");
+    }
+    // print! takes the message ring's lock, but never this one
+    let mut vts = VTS.lock();
+    let old = shown();
+    if vt == old {
+        return Ok(());
+    }
+    if old == 0 {
+        // print! stops showing before the screen is cleared
+        SHOWN.store(vt, Ordering::Relaxed);
+        vts.log_seq = printf::kmsg_seq();
+    }
+    if ansi::is_enabled() {
+        for c in b"\x1b[2J\x1b[H".iter() {
+            consputc(*c);
+        }
+    }
+    if vt != 0 {
+        SHOWN.store(vt, Ordering::Relaxed);
+        let scrollback = &vts.vts[vt].scrollback;
+        for i in 0..scrollback.n {
+            consputc(scrollback.get(i));
+        }
+        consflush();
+        return Ok(());
+    }
+
+    let mut seq = vts.log_seq;
+    let mut buf = [0u8; 64];
+    loop {
+        let (n, next) = printf::kmsg_read(seq, &mut buf);
+        if n == 0 {
+            break;
+        }
+        for c in buf[..n].iter() {
+            consputc(*c);
+        }
+        seq = next;
+    }
+    SHOWN.store(0, Ordering::Relaxed);
+    consflush();
+    Ok(())
+}
+
+/// A byte from the uart, for the console shown, unless it is a switch,
+/// echoed on it but on console 0
+pub fn input(c: u8) {
+    // the termios lock is never taken under VTS's
+    let (canonical, echo) = (termios::canonical(), termios::echo());
+    let mut vts = VTS.lock();
+    if vts.prefix {
+        vts.prefix = false;
+        match c {
+            VT_PREFIX => {}
+            b'0'..=b'9' => {
+                drop(vts);
+                let _ = switch((c - b'0') as usize);
+                return;
+            }
+            _ => return,
+        }
+    } else if c == VT_PREFIX {
+        vts.prefix = true;
+        return;
+    }
+    let vt = shown();
+    let Vt { input, scrollback, .. } = &mut vts.vts[vt];
+    let ready = input.edit(c, canonical, &mut |bytes: &[u8]| {
+        if echo && vt != 0 {
+            put(scrollback, true, bytes);
+        }
+    });
+    if ready {
+        unsafe { PROC_MANAGER.wakeup(input_chan(vt)) };
+    }
+}
+
+/// What a reader of console vt sleeps on until something is typed
+fn input_chan(vt: usize) -> usize {
+    &VTS as *const _ as usize + vt
+}
+
+/// Take what was typed on console vt, a line at most, see Line::read
+pub fn read(vt: usize, dst: &mut [u8]) -> Result<usize, &'static str> {
+    let mut vts = VTS.lock();
+    let vt = vts.vts.get_mut(vt).ok_or("vt: no such console")?;
+    Ok(vt.input.read(dst))
+}
+
+/// Take what was typed on console vt, sleeping until a line, or a character
+/// if the console is not canonical, is in, fail if the process is killed meanwhile,
+/// or at once if nonblock
+pub fn read_wait(vt: usize, dst: &mut [u8], nonblock: bool) -> Result<usize, &'static str> {
+    if vt >= NVT {
+        return Err("vt: no such console");
+    }
+    let p = unsafe { my_proc() };
+    let mut vts = VTS.lock();
+    while !vts.vts[vt].input.readable() {
+        if nonblock {
+            return Err("would block");
+        }
+        if p.killed {
+            return Err("killed");
+        }
+        vts = p.sleep(input_chan(vt), vts);
+    }
+    Ok(vts.vts[vt].input.read(dst))
+}
+
+/// Write src on console vt, it shows if vt is shown
+pub fn write(vt: usize, src: &[u8]) -> Result<(), &'static str> {
+    if vt == 0 {
+        return Err("vt: console 0 is the kernel's log");
+    }
+    let mut vts = VTS.lock();
+    let scrollback = &mut vts.vts.get_mut(vt).ok_or("vt: no such console")?.scrollback;
+    put(scrollback, vt == shown(), src);
+    Ok(())
+}
+
+/// Keep src in a console's scrollback, and write it out if the console is shown
+fn put(scrollback: &mut Ring<VT_SCROLLBACK>, shown: bool, src: &[u8]) {
+    for c in src.iter() {
+        scrollback.push(*c);
+    }
+    if shown {
+        for c in src.iter() {
+            consputc(*c);
+        }
+        consflush();
+    }
+}
+
+/// The foreground process group of console vt, which its Ctrl-C is for
+pub fn pgrp(vt: usize) -> Result<usize, &'static str> {
+    VTS.lock().vts.get(vt).map(|vt| vt.pgrp).ok_or("vt: no such console")
+}
+
+pub fn set_pgrp(vt: usize, pgrp: usize) -> Result<(), &'static str> {
+    let mut vts = VTS.lock();
+    vts.vts.get_mut(vt).ok_or("vt: no such console")?.pgrp = pgrp;
+    Ok(())
+}
+
+#[cfg(feature = "unit_test")]
+pub mod tests {
+    use super::*;
+
+    /// Input goes to the console shown, output of one not shown only to its scrollback,
+    /// nothing here switches, not to clear the screen
+    pub fn consoles() {
+        let vt = shown();
+        let mut buf = [0u8; 8];
+        while read(vt, &mut buf).unwrap() != 0 {}
+        for c in b"ab".iter() {
+            input(*c);
+        }
+        input(VT_PREFIX);
+        input(VT_PREFIX);
+        input(VT_PREFIX);
+        input(b'x');
+        assert_eq!(read(vt, &mut buf), Ok(0));
+        input(b'\r');
+        assert_eq!(read(vt, &mut buf), Ok(4));
+        assert_eq!(&buf[..4], b"ab\x01\n");
+        assert_eq!(read(vt, &mut buf), Ok(0));
+        assert!(read(NVT, &mut buf).is_err());
+
+        let other = (vt + 1) % NVT;
+        let other = if other == 0 { 1 } else { other };
+        write(other, b"hidden").unwrap();
+        {
+            let vts = VTS.lock();
+            let scrollback = &vts.vts[other].scrollback;
+            assert!((0..6).all(|i| scrollback.get(scrollback.n - 6 + i) == b"hidden"[i]));
+        }
+        assert!(write(0, b"log").is_err());
+        assert!(switch(NVT).is_err());
+
+        set_pgrp(other, 7).unwrap();
+        assert_eq!(pgrp(other), Ok(7));
+        set_pgrp(other, 0).unwrap();
+    }
+    crate::kernel_test!(consoles);
+
+    /// A full ring drops its oldest bytes
+    pub fn ring() {
+        let mut ring = Ring::<4>::new();
+        for c in b"abcdef".iter() {
+            ring.push(*c);
+        }
+        assert_eq!(ring.n, 4);
+        assert!((0..4).all(|i| ring.get(i) == b"cdef"[i]));
+        ring.push(b'g');
+        assert!((0..4).all(|i| ring.get(i) == b"defg"[i]));
+    }
+    crate::kernel_test!(ring);
+}
@@ -0,0 +1,74 @@
+//! The SiFive FU740, e.g., on the HiFive Unmatched,
+//! refer to the FU740-C000 manual, chapter 5, for its memory map.
+//! qemu -machine sifive_u models its older sibling, the FU540,
+//! with the same addresses but other interrupt numbers.
+//!
+//! 00100000 -- test device, only on qemu
+//! 02000000 -- CLINT
+//! 02010000 -- L2 cache controller
+//! 0C000000 -- PLIC
+//! 10010000 -- uart0
+//! 10011000 -- uart1
+//! 80000000 -- DDR, the firmware first, then the kernel at 80200000
+//!
+//! Hart 0 is a monitor core without supervisor mode,
+//! the firmware leaves it out of the device tree's harts.
+
+use super::super::{ConstAddr, PGSIZE};
+
+/// qemu's sifive_u has the same sifive_test device as virt,
+/// only touched to exit qemu, with the qemu_exit feature.
+pub const VIRT_TEST: ConstAddr = ConstAddr(0x100000);
+pub const VIRT_TEST_MAP_SIZE: usize = PGSIZE;
+
+/// local interrupt controller, which contains the timer.
+pub const CLINT: ConstAddr = ConstAddr(0x2000000);
+pub const CLINT_MAP_SIZE: usize = 0x10000;
+pub const CLINT_MSIP: ConstAddr = CLINT;
+pub const CLINT_MTIMECMP: ConstAddr = CLINT.const_add(0x4000);
+pub const CLINT_MTIME: ConstAddr = CLINT.const_add(0xbff8);
+
+/// the L2 cache controller, see driver/l2cache.rs
+pub const L2_CACHE: ConstAddr = ConstAddr(0x2010000);
+pub const L2_CACHE_MAP_SIZE: usize = PGSIZE;
+
+/// SiFive UARTs, not ns16550 ones, see console/sifive_uart.rs.
+/// qemu's sifive_u raises 4 and 5 for them instead.
+pub const UART0: ConstAddr = ConstAddr(0x10010000);
+pub const UART0_MAP_SIZE: usize = PGSIZE;
+pub const UART0_IRQ: usize = 39;
+
+pub const UART1: ConstAddr = ConstAddr(0x10011000);
+pub const UART1_MAP_SIZE: usize = PGSIZE;
+
+/// there are no virtio devices, HAS_VIRTIO keeps the drivers off them,
+/// these only let the drivers build
+pub const VIRTIO0: ConstAddr = ConstAddr(0x10001000);
+pub const VIRTIO0_MAP_SIZE: usize = PGSIZE;
+pub const VIRTIO0_IRQ: usize = 0;
+
+pub const VIRTIO1: ConstAddr = ConstAddr(0x10002000);
+pub const VIRTIO1_MAP_SIZE: usize = PGSIZE;
+pub const VIRTIO1_IRQ: usize = 0;
+
+pub const VIRTIO2: ConstAddr = ConstAddr(0x10003000);
+pub const VIRTIO2_MAP_SIZE: usize = PGSIZE;
+pub const VIRTIO2_IRQ: usize = 0;
+
+pub const VIRTIO3: ConstAddr = ConstAddr(0x10004000);
+pub const VIRTIO3_MAP_SIZE: usize = PGSIZE;
+pub const VIRTIO3_IRQ: usize = 0;
+
+pub const VIRTIO4: ConstAddr = ConstAddr(0x10005000);
+pub const VIRTIO4_MAP_SIZE: usize = PGSIZE;
+pub const VIRTIO4_IRQ: usize = 0;
+
+/// programmable interrupt controller.
+pub const PLIC: ConstAddr = ConstAddr(0x0c000000);
+pub const PLIC_MAP_SIZE: usize = 0x400000;
+
+/// the PLIC context of hart 0's supervisor mode, if it had one,
+/// hart 0 only has a machine one, the others a machine and a supervisor one
+pub const PLIC_SCONTEXT: usize = 0;
+
+pub const HAS_VIRTIO: bool = false;
@@ -0,0 +1,14 @@
+//! Where the devices are, per board
+//!
+//! qemu's virt machine by default,
+//! the SiFive FU740 with the fu740 feature.
+
+#[cfg(not(feature = "fu740"))]
+mod virt;
+#[cfg(not(feature = "fu740"))]
+pub use virt::*;
+
+#[cfg(feature = "fu740")]
+mod fu740;
+#[cfg(feature = "fu740")]
+pub use fu740::*;
@@ -0,0 +1,71 @@
+//! qemu -machine virt is set up like this,
+//! based on qemu's hw/riscv/virt.c:
+//!
+//! 00001000 -- boot ROM, provided by qemu
+//! 00100000 -- test device, used to exit qemu
+//! 02000000 -- CLINT
+//! 0C000000 -- PLIC
+//! 10000000 -- uart0
+//! 10001000 -- virtio disk
+//! 80000000 -- boot ROM jumps here in machine mode
+//!             -kernel loads the kernel here
+//! unused RAM after 80000000.
+
+use super::super::{ConstAddr, PGSIZE};
+
+/// qemu's sifive_test device, writing to it can exit qemu.
+pub const VIRT_TEST: ConstAddr = ConstAddr(0x100000);
+pub const VIRT_TEST_MAP_SIZE: usize = PGSIZE;
+
+/// local interrupt controller, which contains the timer.
+pub const CLINT: ConstAddr = ConstAddr(0x2000000);
+pub const CLINT_MAP_SIZE: usize = 0x10000;
+pub const CLINT_MSIP: ConstAddr = CLINT;
+pub const CLINT_MTIMECMP: ConstAddr = CLINT.const_add(0x4000);
+pub const CLINT_MTIME: ConstAddr = CLINT.const_add(0xbff8);
+
+/// qemu puts UART registers here in physical memory.
+pub const UART0: ConstAddr = ConstAddr(0x10000000);
+pub const UART0_MAP_SIZE: usize = PGSIZE;
+pub const UART0_IRQ: usize = 10;
+
+/// a second uart for the gdb stub, see gdbstub.rs,
+/// qemu's virt machine only has UART0, boards may have more
+pub const UART1: ConstAddr = ConstAddr(0x10010000);
+pub const UART1_MAP_SIZE: usize = PGSIZE;
+
+/// virtio mmio interface
+pub const VIRTIO0: ConstAddr = ConstAddr(0x10001000);
+pub const VIRTIO0_MAP_SIZE: usize = PGSIZE;
+pub const VIRTIO0_IRQ: usize = 1;
+
+/// second virtio mmio slot, for the gpu
+pub const VIRTIO1: ConstAddr = ConstAddr(0x10002000);
+pub const VIRTIO1_MAP_SIZE: usize = PGSIZE;
+pub const VIRTIO1_IRQ: usize = 2;
+
+/// third virtio mmio slot, for the 9p host directory
+pub const VIRTIO2: ConstAddr = ConstAddr(0x10003000);
+pub const VIRTIO2_MAP_SIZE: usize = PGSIZE;
+pub const VIRTIO2_IRQ: usize = 3;
+
+/// fourth virtio mmio slot, for the memory balloon
+pub const VIRTIO3: ConstAddr = ConstAddr(0x10004000);
+pub const VIRTIO3_MAP_SIZE: usize = PGSIZE;
+pub const VIRTIO3_IRQ: usize = 4;
+
+/// fifth virtio mmio slot, for the entropy source
+pub const VIRTIO4: ConstAddr = ConstAddr(0x10005000);
+pub const VIRTIO4_MAP_SIZE: usize = PGSIZE;
+pub const VIRTIO4_IRQ: usize = 5;
+
+/// qemu puts programmable interrupt controller here.
+pub const PLIC: ConstAddr = ConstAddr(0x0c000000);
+pub const PLIC_MAP_SIZE: usize = 0x400000;
+
+/// the PLIC context of hart 0's supervisor mode,
+/// each hart has a machine and a supervisor one, in that order
+pub const PLIC_SCONTEXT: usize = 1;
+
+/// the virtio disk and gpu, see driver/
+pub const HAS_VIRTIO: bool = true;
@@ -1,10 +1,12 @@
 use core::ops::{Add, Sub};
 use core::convert::From;
 
+pub use board::*;
 pub use memlayout::*;
 pub use param::*;
 pub use riscv::*;
 
+mod board;
 mod memlayout;
 mod param;
 mod riscv;
@@ -23,6 +25,11 @@ impl ConstAddr {
     pub const fn const_sub(&self, suber: usize) -> Self {
         Self(self.0 - suber)
     }
+
+    /// due to E0015's const restriction
+    pub const fn const_usize(&self) -> usize {
+        self.0
+    }
 }
 
 impl Add for ConstAddr {
@@ -4,12 +4,79 @@ pub const NCPU: usize = 8;
 /// Maximum number of processes
 pub const NPROC: usize = 64;
 
-/// This is actual number of harts.
-/// Same value is passed to qemu with -smp option
+/// Number of harts assumed without a device tree,
+/// otherwise qemu's -smp is found at boot, see dtb.rs.
 pub const NSMP: usize = 3;
 
 pub const CONSOLE_BUF: usize = 128;
 
+/// bytes of output waiting for the uart's transmit interrupt, see console/mod.rs
+pub const UART_TX_BUF: usize = 64;
+
+/// virtual consoles, and the bytes of output each keeps to redraw it, see console/vt.rs
+pub const NVT: usize = 4;
+pub const VT_SCROLLBACK: usize = 4096;
+
+/// maximum number of console output sinks
+pub const NSINK: usize = 4;
+
+/// maximum number of gdb software breakpoints
+pub const GDB_NBREAK: usize = 16;
+
+/// timer-tick samples kept per hart by the profiler
+pub const NPROF: usize = 1024;
+
+/// distinct (pc, pid) entries the profiler's dump aggregates into
+pub const NPROF_SITE: usize = 128;
+
+/// function-entry records kept per hart by ftrace
+pub const NFTRACE: usize = 256;
+
+/// scheduler events kept per hart by schedtrace
+pub const NSCHEDTRACE: usize = 512;
+
+/// size of the kernel message ring
+pub const KMSG_BUF: usize = 16 * 1024;
+
+/// kernel timers, see timer.rs, the wheel must fit in a page for its tests
+pub const NTIMER: usize = 64;
+
+/// open files per process, open files in the system, and device major numbers, see fs/file.rs
+pub const NOFILE: usize = 16;
+pub const NFILE: usize = 100;
+pub const NDEV: usize = 10;
+
+/// file mappings per process, see process/vma.rs
+pub const NVMA: usize = 16;
+
+/// local stream sockets, and the bytes buffered towards each, see socket.rs
+pub const NSOCKET: usize = 16;
+pub const SOCKBUF: usize = 512;
+
+/// message queues, their descriptors, messages per queue,
+/// and the longest message and name, see mqueue.rs
+pub const NMQUEUE: usize = 8;
+pub const NMQOPEN: usize = 32;
+pub const MQ_MAXMSG: usize = 8;
+pub const MQ_MSGSIZE: usize = 64;
+pub const MQ_NAME: usize = 32;
+
+/// event counters, see eventfd.rs
+pub const NEVENTFD: usize = 16;
+
+/// timer descriptors, each also takes an event counter, see timerfd.rs
+pub const NTIMERFD: usize = 8;
+
+/// most pages the host can take back, and pages the balloon leaves free, see virtio_balloon.rs
+pub const BALLOON_MAX: usize = 8192;
+pub const BALLOON_RESERVE: usize = 256;
+
+/// ticks between reseeds of the random generator, see random.rs
+pub const RESEED_TICKS: usize = 600;
+
+/// longest kernel command line kept, see cmdline.rs
+pub const CMDLINE_MAX: usize = 256;
+
 /// memory design
 pub const PGSIZE: usize = 4096;
 pub const PGSHIFT: usize = 12;
@@ -1,6 +1,6 @@
 use super::*;
 
-/// RV64 Sv39 Scheme
+// RV64 Sv39 Scheme
 
 /// lower flag bits length
 pub const SV39FLAGLEN: usize = 10;
@@ -0,0 +1,99 @@
+//! Per-hart cpu usage, the time each hart spent in user mode, the kernel, idle, and interrupts
+//!
+//! Each hart keeps what it is doing and since when, by the CLINT mtime,
+//! and charges the time to it whenever that changes, at the trap boundaries:
+//! entering the kernel from user space and returning, see trap.rs,
+//! taking a device or timer interrupt in either, and sleeping in idle() or park(), see process/cpu.rs.
+//! The scheduler and kernel threads count as the kernel.
+//! Read with the cpustat syscall, e.g., by top, which takes the difference of two readings.
+
+use crate::consts::NCPU;
+use crate::register::clint;
+use crate::spinlock::{pop_off, push_off};
+use crate::process::cpu_id;
+
+pub const CPU_USER: usize = 0;
+pub const CPU_KERNEL: usize = 1;
+pub const CPU_IDLE: usize = 2;
+pub const CPU_IRQ: usize = 3;
+const NSTATE: usize = 4;
+
+/// One hart's times, in mtime units, as the cpustat syscall gives them
+#[repr(C)]
+#[derive(Clone, Copy)]
+pub struct CpuStat {
+    pub hart: u64,
+    pub times: [u64; NSTATE],
+}
+
+struct Account {
+    times: [u64; NSTATE],
+    state: usize,
+    since: u64,
+}
+
+impl Account {
+    const fn new() -> Self {
+        Self {
+            times: [0; NSTATE],
+            state: CPU_KERNEL,
+            since: 0,
+        }
+    }
+
+    /// Charge the time up to now to the current state, then switch to state,
+    /// return the one it was in
+    fn switch(&mut self, state: usize, now: u64) -> usize {
+        // the first switch only starts the clock
+        if self.since != 0 {
+            self.times[self.state] += now.saturating_sub(self.since);
+        }
+        self.since = now;
+        let old = self.state;
+        self.state = state;
+        old
+    }
+}
+
+/// only changed by their own hart, with interrupts off,
+/// read by any for cpustat without a lock, a reading may be slightly off
+static mut ACCOUNTS: [Account; NCPU] = [const { Account::new() }; NCPU];
+
+/// Switch this hart to state, return the one it was in, to switch back to
+pub fn enter(state: usize) -> usize {
+    push_off();
+    let now = unsafe { clint::read_mtime() };
+    let old = unsafe { ACCOUNTS[cpu_id()].switch(state, now) };
+    pop_off();
+    old
+}
+
+/// The times of hart, its current state charged up to now
+pub fn read(hart: usize) -> CpuStat {
+    let now = unsafe { clint::read_mtime() };
+    let account = unsafe { &ACCOUNTS[hart] };
+    let mut times = account.times;
+    if account.since != 0 {
+        times[account.state] += now.saturating_sub(account.since);
+    }
+    CpuStat { hart: hart as u64, times }
+}
+
+#[cfg(feature = "unit_test")]
+pub mod tests {
+    use super::*;
+
+    /// An account of its own, charged at each switch
+    pub fn accounting() {
+        let mut a = Account::new();
+        assert_eq!(a.switch(CPU_USER, 100), CPU_KERNEL);
+        assert_eq!(a.times, [0; NSTATE]);
+        assert_eq!(a.switch(CPU_IRQ, 130), CPU_USER);
+        assert_eq!(a.switch(CPU_USER, 135), CPU_IRQ);
+        assert_eq!(a.switch(CPU_KERNEL, 150), CPU_USER);
+        assert_eq!(a.switch(CPU_IDLE, 160), CPU_KERNEL);
+        assert_eq!(a.switch(CPU_IDLE, 200), CPU_IDLE);
+        assert_eq!(a.times, [45, 10, 40, 5]);
+    }
+    crate::kernel_test!(accounting);
+}
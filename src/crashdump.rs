@@ -0,0 +1,344 @@
+//! Crash dumps to disk on panic, with the crashdump feature
+//!
+//! The disk blocks past the root file system are reserved for the dump,
+//! make CRASHDUMP=N grows fs.img by N MiB for them.
+//! On panic, once the other harts are stopped, the panicking hart writes, see dump(),
+//! each part from a block boundary on, polling the disk with interrupts off:
+//! - the kernel message ring, oldest first,
+//! - a ProcRecord per used process slot,
+//! - a HartRegs per hart, saved by each as the panic IPI froze it, see freeze() in printf.rs,
+//!   the panicking one saves its own,
+//! - all of RAM, from KERNBASE to PHYSTOP, with crashdump=ram on the command line,
+//! - and last a DumpHeader in the first reserved block, saying where each part is,
+//!   so that a dump cut short by a failed write has no header.
+//!
+//! Then the machine reboots,
+//! and the next boot reports the dump, which stays until the next panic overwrites it.
+//! Read it on the host from the disk image, e.g.,
+//! dd if=fs.img bs=1024 skip=BLOCK, BLOCK being the one the report names.
+
+use core::fmt;
+use core::mem;
+use core::slice;
+use core::sync::atomic::{AtomicUsize, Ordering};
+use core::panic::PanicInfo;
+
+use crate::cmdline;
+use crate::consts::{HAS_VIRTIO, KERNBASE, KMSG_BUF, NCPU, NPROC, PHYSTOP};
+use crate::driver::{qemu, virtio};
+use crate::dtb;
+use crate::fs::{self, BSIZE};
+use crate::printf;
+use crate::process::{cpu_id, my_pid, PROC_MANAGER};
+use crate::register::{clint, scause, sepc, sstatus, stval};
+use crate::trap;
+
+pub const DUMP_MAGIC: [u8; 8] = *b"xv6dump\0";
+
+/// Where a part of the dump is, len in bytes
+#[repr(C)]
+#[derive(Clone, Copy, Default)]
+pub struct Section {
+    pub block: u64,
+    pub len: u64,
+}
+
+/// The first reserved block, written last
+#[repr(C)]
+pub struct DumpHeader {
+    pub magic: [u8; 8],
+    pub hart: u64,
+    pub ticks: u64,
+    pub kmsg: Section,
+    pub procs: Section,
+    pub harts: Section,
+    pub ram: Section,
+    /// the panic message, cut to fit, NUL-padded
+    pub message: [u8; 256],
+}
+
+#[repr(C)]
+#[derive(Default)]
+pub struct ProcRecord {
+    pub pid: u64,
+    pub ppid: u64,
+    pub killed: u64,
+    /// as ps shows it, NUL-padded
+    pub state: [u8; 8],
+    pub name: [u8; 16],
+}
+
+/// A hart's registers where it stopped, in its trap handler or park()
+#[repr(C)]
+#[derive(Clone, Copy)]
+pub struct HartRegs {
+    /// 0 if the hart did not save them in time, e.g., it is not running
+    pub saved: u64,
+    /// the pid of its process, 0 if none
+    pub pid: u64,
+    pub sepc: u64,
+    pub scause: u64,
+    pub stval: u64,
+    pub sstatus: u64,
+    /// the return addresses of its kernel stack, innermost first, 0 past the end
+    pub backtrace: [u64; 8],
+}
+
+impl HartRegs {
+    const fn new() -> Self {
+        Self { saved: 0, pid: 0, sepc: 0, scause: 0, stval: 0, sstatus: 0, backtrace: [0; 8] }
+    }
+}
+
+const _: () = assert!(mem::size_of::<DumpHeader>() <= BSIZE);
+
+/// each only written by its own hart, once it is frozen
+static mut HARTS: [HartRegs; NCPU] = [const { HartRegs::new() }; NCPU];
+/// harts that have saved theirs in HARTS
+static SAVED: AtomicUsize = AtomicUsize::new(0);
+
+/// mtime units the panicking hart waits for the others to save their registers
+const SAVE_TIMEOUT: u64 = 1_000_000;
+/// bytes of RAM per write
+const RAM_CHUNK: usize = 64 * 1024;
+
+/// Save this hart's registers, with interrupts off
+pub fn save_hart() {
+    let id = unsafe { cpu_id() };
+    let mut ras = [0usize; 8];
+    printf::return_addrs(&mut ras);
+    let regs = HartRegs {
+        saved: 1,
+        pid: unsafe { my_pid() }.unwrap_or(0) as u64,
+        sepc: sepc::read() as u64,
+        scause: scause::read() as u64,
+        stval: stval::read() as u64,
+        sstatus: sstatus::read() as u64,
+        backtrace: ras.map(|ra| ra as u64),
+    };
+    unsafe { HARTS[id] = regs };
+    SAVED.fetch_or(1 << id, Ordering::SeqCst);
+}
+
+fn as_bytes<T>(value: &T) -> &[u8] {
+    unsafe { slice::from_raw_parts(value as *const T as *const u8, mem::size_of::<T>()) }
+}
+
+/// Formats into a byte array, cutting off what does not fit
+struct Message<'a> {
+    buf: &'a mut [u8],
+    len: usize,
+}
+
+impl fmt::Write for Message<'_> {
+    fn write_str(&mut self, s: &str) -> fmt::Result {
+        let n = s.len().min(self.buf.len() - self.len);
+        self.buf[self.len..self.len + n].copy_from_slice(&s.as_bytes()[..n]);
+        self.len += n;
+        Ok(())
+    }
+}
+
+/// Writes the dump a block at a time through BLOCK, from block to end
+struct Writer {
+    block: u64,
+    end: u64,
+    fill: usize,
+}
+
+/// not on the stack, which may be nearly full at a panic
+static mut BLOCK: [u8; BSIZE] = [0; BSIZE];
+
+impl Writer {
+    fn put(&mut self, mut bytes: &[u8]) -> Result<(), &'static str> {
+        while !bytes.is_empty() {
+            let n = bytes.len().min(BSIZE - self.fill);
+            unsafe { BLOCK[self.fill..self.fill + n].copy_from_slice(&bytes[..n]) };
+            self.fill += n;
+            bytes = &bytes[n..];
+            if self.fill == BSIZE {
+                self.flush()?;
+            }
+        }
+        Ok(())
+    }
+
+    /// Write out the block being filled, zero-padded
+    fn flush(&mut self) -> Result<(), &'static str> {
+        if self.fill == 0 {
+            return Ok(());
+        }
+        unsafe { BLOCK[self.fill..].fill(0) };
+        self.write(unsafe { BLOCK.as_ptr() }, BSIZE)?;
+        self.fill = 0;
+        Ok(())
+    }
+
+    /// Write len bytes at data, a multiple of BSIZE, from the next block on
+    fn write(&mut self, data: *const u8, len: usize) -> Result<(), &'static str> {
+        let nblocks = (len / BSIZE) as u64;
+        if self.block + nblocks > self.end {
+            return Err("the disk is full");
+        }
+        unsafe { virtio::disk_write_polled(self.block, data, len)? };
+        self.block += nblocks;
+        Ok(())
+    }
+
+    /// Run f to write a part of the dump, from a block boundary on
+    fn section(&mut self, f: impl FnOnce(&mut Self) -> Result<(), &'static str>)
+        -> Result<Section, &'static str>
+    {
+        let block = self.block;
+        f(self)?;
+        let len = (self.block - block) * BSIZE as u64 + self.fill as u64;
+        self.flush()?;
+        Ok(Section { block, len })
+    }
+}
+
+fn write_kmsg(w: &mut Writer) -> Result<(), &'static str> {
+    let mut chunk = [0u8; 256];
+    let mut seq = 0;
+    let mut left = KMSG_BUF;
+    while left > 0 {
+        let (n, next) = unsafe { printf::kmsg_read_frozen(seq, &mut chunk) };
+        if n == 0 {
+            break;
+        }
+        w.put(&chunk[..n])?;
+        seq = next;
+        left = left.saturating_sub(n);
+    }
+    Ok(())
+}
+
+fn write_procs(w: &mut Writer) -> Result<(), &'static str> {
+    // a handful at a time, the whole table is too big for the stack
+    let mut records: [ProcRecord; 4] = Default::default();
+    let mut skip = 0;
+    while skip < NPROC {
+        let n = unsafe { PROC_MANAGER.crash_records_from(skip, &mut records) };
+        for record in records[..n].iter() {
+            w.put(as_bytes(record))?;
+        }
+        if n < records.len() {
+            break;
+        }
+        skip += n;
+    }
+    Ok(())
+}
+
+fn write_harts(w: &mut Writer) -> Result<(), &'static str> {
+    for regs in unsafe { HARTS.iter() } {
+        w.put(as_bytes(regs))?;
+    }
+    Ok(())
+}
+
+fn write_ram(w: &mut Writer) -> Result<(), &'static str> {
+    let mut pa = usize::from(KERNBASE);
+    while pa < usize::from(PHYSTOP) {
+        w.write(pa as *const u8, RAM_CHUNK)?;
+        pa += RAM_CHUNK;
+    }
+    Ok(())
+}
+
+fn write_dump(info: &PanicInfo, id: usize, start: u64, end: u64) -> Result<u64, &'static str> {
+    // the header's block is written last
+    let mut w = Writer { block: start + 1, end, fill: 0 };
+    let kmsg = w.section(write_kmsg)?;
+    let procs = w.section(write_procs)?;
+    let harts = w.section(write_harts)?;
+    let ram = if cmdline::get("crashdump") == Some("ram") {
+        w.section(write_ram)?
+    } else {
+        Section::default()
+    };
+    let used = w.block - start;
+
+    let mut header = DumpHeader {
+        magic: DUMP_MAGIC,
+        hart: id as u64,
+        ticks: trap::ticks() as u64,
+        kmsg,
+        procs,
+        harts,
+        ram,
+        message: [0; 256],
+    };
+    let _ = fmt::write(&mut Message { buf: &mut header.message, len: 0 }, format_args!("{}", info));
+    w.block = start;
+    w.put(as_bytes(&header))?;
+    w.flush()?;
+    Ok(used)
+}
+
+/// Write the dump of a panic on this hart, the other harts having been sent the IPI
+pub fn dump(info: &PanicInfo) {
+    let id = unsafe { cpu_id() };
+    let start = fs::fs_blocks() as u64;
+    if !HAS_VIRTIO || start == 0 {
+        crate::println!("crash dump: no disk to write it to");
+        return;
+    }
+    let end = unsafe { virtio::disk_blocks() };
+    if end <= start + 1 {
+        crate::println!("crash dump: no blocks reserved past the file system");
+        return;
+    }
+
+    sstatus::intr_off();
+    save_hart();
+    let others = dtb::harts() & !(1 << id);
+    let deadline = unsafe { clint::read_mtime() } + SAVE_TIMEOUT;
+    while SAVED.load(Ordering::SeqCst) & others != others && unsafe { clint::read_mtime() } < deadline {
+        core::hint::spin_loop();
+    }
+
+    match write_dump(info, id, start, end) {
+        Ok(used) => crate::println!("crash dump: {} blocks from block {}", used, start),
+        Err(err) => crate::println!("crash dump: {}", err),
+    }
+}
+
+/// Reboot after the dump
+pub fn reboot() -> ! {
+    crate::println!("rebooting");
+    #[cfg(feature = "sbi")]
+    crate::sbi::system_reset();
+    qemu::reset();
+}
+
+/// Report the dump of an earlier panic, once the root file system is up
+pub fn check() {
+    let start = fs::fs_blocks();
+    if unsafe { virtio::disk_blocks() } <= start as u64 + 1 {
+        return;
+    }
+    fs::with_block(fs::root_dev(), start, |data| {
+        let header = unsafe { (data.as_ptr() as *const DumpHeader).read_unaligned() };
+        if header.magic != DUMP_MAGIC {
+            return;
+        }
+        let len = header.message.iter().position(|c| *c == 0).unwrap_or(header.message.len());
+        crate::println!("crash dump at block {}: panic on hart {} at tick {}: {}",
+            start, header.hart, header.ticks, core::str::from_utf8(&header.message[..len]).unwrap_or("?"));
+    });
+}
+
+#[cfg(feature = "unit_test")]
+pub mod tests {
+    use super::*;
+
+    pub fn message_cut() {
+        let mut buf = [0u8; 8];
+        let mut m = Message { buf: &mut buf, len: 0 };
+        let _ = fmt::write(&mut m, format_args!("panic {} at {}", 42, "here"));
+        assert_eq!(m.len, 8);
+        assert_eq!(&buf, b"panic 42");
+    }
+    crate::kernel_test!(message_cut);
+}
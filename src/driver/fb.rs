@@ -0,0 +1,191 @@
+//! The virtio gpu's framebuffer for user programs, like Linux's /dev/fb
+//!
+//! map() puts the framebuffer's pages into a process's address space,
+//! pixels being 0x00RRGGBB, a row after the other, stride bytes apart,
+//! and ioctl() tells its size, or presents a rectangle of it.
+//! While a process has it mapped, the framebuffer console is turned off,
+//! so that text does not scribble over the picture.
+//!
+//! It is the device file of major number FB, see fs/file.rs, made by init at /fb,
+//! whose ioctl lands in ioctl(), and mmap in map(), see Proc::mmap,
+//! unmapped by munmap, exec or exit. It is not read nor written, only mapped.
+
+use core::convert::TryFrom;
+use core::mem;
+use core::ptr;
+use core::sync::atomic::{AtomicBool, Ordering};
+
+use crate::console;
+use crate::consts::PGSIZE;
+use crate::mm::{PageTable, PhysAddr, PteFlag, VirtAddr};
+
+use super::virtio_gpu;
+
+/// ioctl requests, the first as Linux's
+pub const FBIOGET_INFO: usize = 0x4600;
+pub const FBIO_FLUSH: usize = 0x4680;
+
+#[repr(C)]
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub struct FbInfo {
+    pub width: u32,
+    pub height: u32,
+    /// bytes from a row to the next
+    pub stride: u32,
+    pub bits_per_pixel: u32,
+}
+
+/// A rectangle to present, in pixels
+#[repr(C)]
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub struct FbRect {
+    pub x: u32,
+    pub y: u32,
+    pub width: u32,
+    pub height: u32,
+}
+
+/// It is mapped by a process, only one at a time
+static MAPPED: AtomicBool = AtomicBool::new(false);
+
+fn info() -> Result<FbInfo, &'static str> {
+    if !virtio_gpu::is_present() {
+        return Err("fb: no gpu");
+    }
+    let (width, height) = virtio_gpu::resolution();
+    Ok(FbInfo {
+        width: width as u32,
+        height: height as u32,
+        stride: (width * mem::size_of::<u32>()) as u32,
+        bits_per_pixel: 32,
+    })
+}
+
+/// Bytes mapped, the framebuffer rounded up to whole pages
+pub fn len() -> Result<usize, &'static str> {
+    let info = info()?;
+    let len = info.stride as usize * info.height as usize;
+    Ok(len.div_ceil(PGSIZE) * PGSIZE)
+}
+
+/// Map the framebuffer into pagetable at page-aligned va, readable and writable by the user,
+/// return how many bytes that is.
+pub fn map(pagetable: &mut PageTable, va: usize) -> Result<usize, &'static str> {
+    let len = len()?;
+    if !va.is_multiple_of(PGSIZE) {
+        return Err("fb: va not aligned");
+    }
+    if MAPPED.swap(true, Ordering::AcqRel) {
+        return Err("fb: mapped by another process");
+    }
+    let pa = PhysAddr::try_from(virtio_gpu::framebuffer_addr()).unwrap();
+    let result = VirtAddr::try_from(va).and_then(|va| {
+        pagetable.map_pages(va, len, pa, PteFlag::R | PteFlag::W | PteFlag::U)
+    });
+    if let Err(str) = result {
+        MAPPED.store(false, Ordering::Release);
+        return Err(str);
+    }
+    console::set_sink_enabled("fb", false);
+    Ok(len)
+}
+
+/// Undo map(), the pages stay the gpu's, and the console is back on the screen
+pub fn unmap(pagetable: &mut PageTable, va: usize) -> Result<(), &'static str> {
+    let len = len()?;
+    if !MAPPED.load(Ordering::Acquire) {
+        return Err("fb: not mapped");
+    }
+    pagetable.unmap_pages(VirtAddr::try_from(va)?, len / PGSIZE, false)?;
+    MAPPED.store(false, Ordering::Release);
+    console::set_sink_enabled("fb", true);
+    Ok(())
+}
+
+/// The framebuffer device is not read, see map()
+pub fn fbread(_minor: u16, _dst: &mut [u8], _nonblock: bool) -> Result<usize, &'static str> {
+    Err("fb: not read, mmap it")
+}
+
+pub fn fbwrite(_minor: u16, _src: &[u8]) -> Result<usize, &'static str> {
+    Err("fb: not written, mmap it")
+}
+
+/// The size of the argument of ioctl request req
+pub fn ioctl_size(req: usize) -> Result<usize, &'static str> {
+    match req {
+        FBIOGET_INFO => Ok(mem::size_of::<FbInfo>()),
+        FBIO_FLUSH => Ok(mem::size_of::<FbRect>()),
+        _ => Err("fb: unknown request"),
+    }
+}
+
+/// ioctl() on the device file, its minor number does not matter
+pub fn fbioctl(_minor: u16, req: usize, arg: &mut [u8]) -> Result<(), &'static str> {
+    ioctl(req, arg)
+}
+
+/// Carry out ioctl request req, arg gets an FbInfo, or holds the FbRect to present
+pub fn ioctl(req: usize, arg: &mut [u8]) -> Result<(), &'static str> {
+    match req {
+        FBIOGET_INFO => {
+            if arg.len() != mem::size_of::<FbInfo>() {
+                return Err("fb: bad argument size");
+            }
+            let info = info()?;
+            unsafe { ptr::write_unaligned(arg.as_mut_ptr() as *mut FbInfo, info); }
+            Ok(())
+        }
+        FBIO_FLUSH => {
+            if arg.len() != mem::size_of::<FbRect>() {
+                return Err("fb: bad argument size");
+            }
+            let r = unsafe { ptr::read_unaligned(arg.as_ptr() as *const FbRect) };
+            let info = info()?;
+            if r.x.checked_add(r.width).is_none_or(|x| x > info.width)
+                || r.y.checked_add(r.height).is_none_or(|y| y > info.height)
+            {
+                return Err("fb: rectangle out of the screen");
+            }
+            virtio_gpu::flush(r.x as usize, r.y as usize, r.width as usize, r.height as usize);
+            Ok(())
+        }
+        _ => Err("fb: unknown request"),
+    }
+}
+
+#[cfg(feature = "unit_test")]
+pub mod tests {
+    use super::*;
+    use crate::mm::Addr;
+
+    /// The framebuffer's pages in a page table, if there is a gpu
+    pub fn mapping() {
+        let mut arg = [0u8; mem::size_of::<FbInfo>()];
+        if !virtio_gpu::is_present() {
+            assert!(ioctl(FBIOGET_INFO, &mut arg).is_err());
+            assert!(len().is_err());
+            return;
+        }
+        ioctl(FBIOGET_INFO, &mut arg).unwrap();
+        let info = unsafe { ptr::read_unaligned(arg.as_ptr() as *const FbInfo) };
+        assert_eq!(info.stride, info.width * 4);
+        assert!(ioctl(FBIOGET_INFO, &mut arg[1..]).is_err());
+        assert_eq!(ioctl_size(FBIOGET_INFO), Ok(arg.len()));
+        let mut rect = [0u8; mem::size_of::<FbRect>()];
+        let off = FbRect { x: 1, y: 0, width: info.width, height: 1 };
+        unsafe { ptr::write_unaligned(rect.as_mut_ptr() as *mut FbRect, off); }
+        assert!(ioctl(FBIO_FLUSH, &mut rect).is_err());
+
+        let mut pagetable = PageTable::uvm_create();
+        let va = 0x10_0000;
+        let len = map(&mut pagetable, va).unwrap();
+        assert!(map(&mut pagetable, va + len).is_err());
+        let pte = pagetable.walk(VirtAddr::try_from(va + len - PGSIZE).unwrap()).unwrap();
+        assert_eq!(pte.as_phys_addr().as_usize(), virtio_gpu::framebuffer_addr() + len - PGSIZE);
+        unmap(&mut pagetable, va).unwrap();
+        assert!(unmap(&mut pagetable, va).is_err());
+        pagetable.free_walk();
+    }
+    crate::kernel_test!(mapping);
+}
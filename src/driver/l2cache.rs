@@ -0,0 +1,40 @@
+//! The FU740's L2 cache controller, refer to its manual, chapter 13.
+//!
+//! Out of reset only one of its ways caches, the rest is scratchpad memory.
+//! The firmware usually enables them all already, init() makes sure,
+//! the kernel does not use the scratchpad.
+
+use core::ptr;
+
+use crate::consts::L2_CACHE;
+use crate::dtb;
+
+const CONFIG: usize = 0x000;
+const WAY_ENABLE: usize = 0x008;
+
+#[inline]
+fn read(offset: usize) -> u32 {
+    unsafe { ptr::read_volatile((L2_CACHE.const_usize() + offset) as *const u32) }
+}
+
+#[inline]
+fn write(offset: usize, value: u32) {
+    unsafe { ptr::write_volatile((L2_CACHE.const_usize() + offset) as *mut u32, value) }
+}
+
+/// Enable all the ways, if the device tree has the controller,
+/// qemu's sifive_u does not.
+pub fn init() {
+    if !dtb::has_l2_cache() {
+        println!("l2cache: not in the device tree");
+        return;
+    }
+    // config has the number of ways in bits 8-15,
+    // way enable the index of the last way enabled, it can only grow
+    let ways = (read(CONFIG) >> 8) & 0xff;
+    let enabled = read(WAY_ENABLE) + 1;
+    if ways > enabled {
+        write(WAY_ENABLE, ways - 1);
+    }
+    println!("l2cache: {} ways, {} enabled before", ways, enabled);
+}
@@ -1 +1,9 @@
-pub mod virtio;
\ No newline at end of file
+pub mod fb;
+#[cfg(feature = "fu740")]
+pub mod l2cache;
+pub mod qemu;
+pub mod virtio;
+pub mod virtio_9p;
+pub mod virtio_balloon;
+pub mod virtio_gpu;
+pub mod virtio_rng;
\ No newline at end of file
@@ -0,0 +1,39 @@
+//! qemu virt machine's sifive_test device,
+//! which can power off qemu with a given exit status, or reset the machine
+
+use core::ptr;
+
+use crate::consts::VIRT_TEST;
+use crate::register::wfi;
+
+const FINISHER_FAIL: u32 = 0x3333;
+const FINISHER_PASS: u32 = 0x5555;
+const FINISHER_RESET: u32 = 0x7777;
+
+/// Exit qemu, status 0 means success.
+/// For a non-zero status, qemu itself exits with (status << 1) | 1.
+pub fn exit(status: u16) -> ! {
+    let value = if status == 0 {
+        FINISHER_PASS
+    } else {
+        FINISHER_FAIL | ((status as u32) << 16)
+    };
+    unsafe {
+        ptr::write_volatile(Into::<usize>::into(VIRT_TEST) as *mut u32, value);
+    }
+
+    // not running on qemu or the write has not taken effect yet
+    loop {
+        wfi();
+    }
+}
+
+/// Reset the machine, booting the kernel again
+pub fn reset() -> ! {
+    unsafe {
+        ptr::write_volatile(Into::<usize>::into(VIRT_TEST) as *mut u32, FINISHER_RESET);
+    }
+    loop {
+        wfi();
+    }
+}
@@ -7,9 +7,9 @@ use core::sync::atomic::{fence, Ordering};
 use core::{mem, ptr};
 
 use crate::consts::{PGSHIFT, PGSIZE, VIRTIO0};
-use crate::fs::{Buf, BSIZE};
-use crate::mm::{kvm_pa, VirtAddr};
+use crate::fs::BSIZE;
 use crate::process::my_proc;
+use crate::register::clint;
 use crate::spinlock::SpinLock;
 
 static mut DISK: Disk = Disk::new();
@@ -45,6 +45,8 @@ pub unsafe fn disk_init() {
     features &= !(1u32 << VIRTIO_RING_F_EVENT_IDX);
     features &= !(1u32 << VIRTIO_RING_F_INDIRECT_DESC);
     write(VIRTIO_MMIO_DRIVER_FEATURES, features);
+    // without it the device writes through, and flushing is not needed
+    DISK.has_flush = features & (1u32 << VIRTIO_BLK_F_FLUSH) != 0;
 
     // step 5
     // set FEATURES_OK bit to tell the device feature negotiation is complete
@@ -73,61 +75,93 @@ pub unsafe fn disk_init() {
     write(VIRTIO_MMIO_QUEUE_PFN, u32::try_from(page_num).unwrap());
 
     // debug
-    println!("virtio disk init: done");
+    println!("virtio disk init: done, {}", if DISK.has_flush { "write cache" } else { "write through" });
     // TODO - plic.rs and trap.rs arrange for interrupts from VIRTIO0_IRQ
 }
 
-pub unsafe fn disk_rw(b: &Buf, writing: bool) {
-    let sector: u64 = (b.blockno as u64) * (BSIZE as u64 / 512);
+/// Read or write block blockno from or to data, sleeping until the request completes,
+/// an error if the device failed it.
+pub unsafe fn disk_rw(blockno: u32, data: &mut [u8; BSIZE], writing: bool) -> Result<(), &'static str> {
+    let sector: u64 = (blockno as u64) * (BSIZE as u64 / 512);
+    let typed = if writing { VIRTIO_BLK_T_OUT } else { VIRTIO_BLK_T_IN };
+    request(typed, sector, Some((data.as_ptr(), BSIZE)))
+}
+
+/// Read one sector into data, sleeping until it is done,
+/// for what is not a file system block, e.g., a partition table
+pub unsafe fn read_sector(sector: u64, data: &mut [u8; SECTOR]) -> Result<(), &'static str> {
+    check_sector(sector)?;
+    request(VIRTIO_BLK_T_IN, sector, Some((data.as_ptr(), SECTOR)))
+}
+
+/// Write data to one sector, sleeping until it is done
+pub unsafe fn write_sector(sector: u64, data: &[u8; SECTOR]) -> Result<(), &'static str> {
+    check_sector(sector)?;
+    request(VIRTIO_BLK_T_OUT, sector, Some((data.as_ptr(), SECTOR)))
+}
 
-    let mut _lock = DISK.lock.lock();
+/// Ask the device to make the writes it completed so far durable, a write barrier,
+/// and sleep until it has. Nothing to do for a write-through device.
+pub unsafe fn disk_flush() -> Result<(), &'static str> {
+    if !DISK.has_flush {
+        return Ok(());
+    }
+    request(VIRTIO_BLK_T_FLUSH, 0, None)
+}
 
-    // allocate three descriptors
+unsafe fn check_sector(sector: u64) -> Result<(), &'static str> {
+    if sector >= disk_blocks() * (BSIZE as u64 / 512) {
+        return Err("sector out of range");
+    }
+    Ok(())
+}
+
+/// Queue a request of type typed, with the len bytes at data if any,
+/// and sleep until its own completion.
+/// Several callers may have requests in flight at once,
+/// each holding one descriptor chain until disk_intr wakes it.
+unsafe fn request(typed: u32, sector: u64, data: Option<(*const u8, usize)>) -> Result<(), &'static str> {
+    let mut guard = DISK.lock.lock();
+
+    // allocate three descriptors, two with no data,
+    // sleeping until in-flight requests release some if the ring is full
     let mut idx: [usize; 3] = [0; 3];
-    loop {
-        match alloc3_desc(&mut idx) {
-            Ok(_) => break,
-            Err(_) => {}
-        }
-        // no sleep
-        println!("virtio disk waiting desc..");
+    let n = if data.is_some() { 3 } else { 2 };
+    while alloc_descs(&mut idx[..n]).is_err() {
+        guard = my_proc().sleep(&DISK.free as *const _ as usize, guard);
     }
 
-    // format the three descriptors.
+    // format the descriptors.
     // qemu's virtio-blk.c reads them.
-    let buf0 = VirtioBlkOutHdr {
-        typed: if writing {
-            VIRTIO_BLK_T_OUT
-        } else {
-            VIRTIO_BLK_T_IN
-        },
-        reserved: 0,
-        sector: sector,
-    };
-
-    // buf0 is on a kernel stack, which is not direct mapped,
-    // thus the call to kvmpa().
-    let buf0_addr = &buf0 as *const _ as usize;
-    DISK.desc[idx[0]].addr = kvm_pa(VirtAddr::try_from(buf0_addr).unwrap());
+    // the header lives in DISK, next to the chain head,
+    // so it stays valid while other requests are queued behind it.
+    let buf0 = &mut DISK.ops[idx[0]];
+    buf0.typed = typed;
+    buf0.reserved = 0;
+    buf0.sector = sector;
+
+    DISK.desc[idx[0]].addr = buf0 as *const _ as u64;
     DISK.desc[idx[0]].len = mem::size_of::<VirtioBlkOutHdr>() as u32;
     DISK.desc[idx[0]].flags = VRING_DESC_F_NEXT;
     DISK.desc[idx[0]].next = idx[1] as u16;
 
-    DISK.desc[idx[1]].addr = b.data.as_ptr() as u64;
-    DISK.desc[idx[1]].len = BSIZE as u32;
-    DISK.desc[idx[1]].flags = if writing { 0 } else { VRING_DESC_F_WRITE };
-    DISK.desc[idx[1]].flags |= VRING_DESC_F_NEXT;
-    DISK.desc[idx[1]].next = idx[2] as u16;
+    if let Some((addr, len)) = data {
+        DISK.desc[idx[1]].addr = addr as u64;
+        DISK.desc[idx[1]].len = len as u32;
+        DISK.desc[idx[1]].flags = if typed == VIRTIO_BLK_T_IN { VRING_DESC_F_WRITE } else { 0 };
+        DISK.desc[idx[1]].flags |= VRING_DESC_F_NEXT;
+        DISK.desc[idx[1]].next = idx[2] as u16;
+    }
 
-    DISK.info[idx[0]].status = 0;
-    DISK.desc[idx[2]].addr = &DISK.info[idx[0]].status as *const _ as u64;
-    DISK.desc[idx[2]].len = 1;
-    DISK.desc[idx[2]].flags = VRING_DESC_F_WRITE;
-    DISK.desc[idx[2]].next = 0;
+    let last = idx[n - 1];
+    DISK.info[idx[0]].status = 0xff; // device writes 0 on success
+    DISK.desc[last].addr = &DISK.info[idx[0]].status as *const _ as u64;
+    DISK.desc[last].len = 1;
+    DISK.desc[last].flags = VRING_DESC_F_WRITE;
+    DISK.desc[last].next = 0;
 
-    // record struct buf for virtio_disk_intr().
-    b.disk.set(true);
-    DISK.info[idx[0]].b = Some(mem::transmute::<*const _, *mut _>(b as *const Buf));
+    // for virtio_disk_intr() to find the request done.
+    DISK.info[idx[0]].busy = true;
 
     // avail[0] is flags
     // avail[1] tells the device how far to look in avail[2...].
@@ -135,20 +169,86 @@ pub unsafe fn disk_rw(b: &Buf, writing: bool) {
     // we only tell device the first index in our chain of descriptors.
     DISK.avail[2 + (DISK.avail[1] as usize % NUM)] = idx[0] as u16;
     fence(Ordering::SeqCst);
-    DISK.avail[1] = DISK.avail[1] + 1;
+    DISK.avail[1] = DISK.avail[1].wrapping_add(1);
+    fence(Ordering::SeqCst);
 
     write(VIRTIO_MMIO_QUEUE_NOTIFY, 0); // queue 0
 
-    // wait for virtio_disk_intr() to say request has finished.
-    while b.disk.get() {
-        println!("disk_rw goint to sleep!"); // debug
-        _lock = my_proc().sleep(b as *const _ as usize, &DISK.lock, _lock);
+    // wait for virtio_disk_intr() to say this request has finished,
+    // other requests may complete and be woken in the meantime.
+    let chan = &DISK.info[idx[0]] as *const _ as usize;
+    while DISK.info[idx[0]].busy {
+        guard = my_proc().sleep(chan, guard);
     }
 
-    DISK.info[idx[0]].b = None;
+    let status = DISK.info[idx[0]].status;
     free_chain(idx[0]);
 
-    drop(_lock);
+    drop(guard);
+    match status {
+        0 => Ok(()),
+        VIRTIO_BLK_S_UNSUPP => Err("disk: unsupported request"),
+        _ => Err("disk: I/O error"),
+    }
+}
+
+/// The disk's size in blocks, from its configuration space
+pub unsafe fn disk_blocks() -> u64 {
+    let sectors = read(VIRTIO_MMIO_CONFIG) as u64 | (read(VIRTIO_MMIO_CONFIG + 4) as u64) << 32;
+    sectors / (BSIZE as u64 / 512)
+}
+
+/// Write len bytes at data, a multiple of the sector size, to the disk from block blockno on,
+/// polling for the completion instead of sleeping, for the crash dump, see crashdump.rs.
+/// The other harts are frozen and interrupts are off, so the lock is not taken,
+/// a frozen hart may be holding it, and requests it had in flight are left alone.
+pub unsafe fn disk_write_polled(blockno: u64, data: *const u8, len: usize) -> Result<(), &'static str> {
+    let mut idx: [usize; 3] = [0; 3];
+    alloc_descs(&mut idx).map_err(|()| "no free descriptors")?;
+
+    let buf0 = &mut DISK.ops[idx[0]];
+    buf0.typed = VIRTIO_BLK_T_OUT;
+    buf0.reserved = 0;
+    buf0.sector = blockno * (BSIZE as u64 / 512);
+
+    DISK.desc[idx[0]].addr = buf0 as *const _ as u64;
+    DISK.desc[idx[0]].len = mem::size_of::<VirtioBlkOutHdr>() as u32;
+    DISK.desc[idx[0]].flags = VRING_DESC_F_NEXT;
+    DISK.desc[idx[0]].next = idx[1] as u16;
+
+    DISK.desc[idx[1]].addr = data as u64;
+    DISK.desc[idx[1]].len = len as u32;
+    DISK.desc[idx[1]].flags = VRING_DESC_F_NEXT;
+    DISK.desc[idx[1]].next = idx[2] as u16;
+
+    DISK.info[idx[0]].status = 0xff;
+    DISK.desc[idx[2]].addr = &DISK.info[idx[0]].status as *const _ as u64;
+    DISK.desc[idx[2]].len = 1;
+    DISK.desc[idx[2]].flags = VRING_DESC_F_WRITE;
+    DISK.desc[idx[2]].next = 0;
+
+    DISK.avail[2 + (DISK.avail[1] as usize % NUM)] = idx[0] as u16;
+    fence(Ordering::SeqCst);
+    DISK.avail[1] = DISK.avail[1].wrapping_add(1);
+    fence(Ordering::SeqCst);
+
+    write(VIRTIO_MMIO_QUEUE_NOTIFY, 0);
+
+    // the device writes the status last, there is no interrupt to wait for
+    let deadline = clint::read_mtime() + POLL_TIMEOUT;
+    let mut status = 0xff;
+    while status == 0xff && clint::read_mtime() < deadline {
+        status = ptr::read_volatile(&DISK.info[idx[0]].status);
+    }
+    // not free_chain(), nobody is to be woken up
+    for i in idx.iter() {
+        free_desc(*i);
+    }
+    match status {
+        0 => Ok(()),
+        0xff => Err("timed out"),
+        _ => Err("write failed"),
+    }
 }
 
 // find a free descriptor, mark it non-free, return its index.
@@ -165,15 +265,16 @@ fn alloc_desc() -> Option<usize> {
     None
 }
 
-fn alloc3_desc(idx: &mut [usize]) -> Result<(), ()> {
-    for i in 0..3 {
+// allocate a descriptor for each of idx, none if there are not enough.
+fn alloc_descs(idx: &mut [usize]) -> Result<(), ()> {
+    for i in 0..idx.len() {
         match alloc_desc() {
             Some(ui) => {
                 idx[i] = ui;
             }
             None => {
-                for j in 0..i {
-                    free_desc(j);
+                for &ui in &idx[..i] {
+                    free_desc(ui);
                 }
                 return Err(());
             }
@@ -194,10 +295,10 @@ fn free_desc(i: usize) {
         DISK.desc[i].addr = 0;
         DISK.free[i] = true;
     }
-    // no wakeup
 }
 
-// free a chain of descriptors.
+// free a chain of descriptors,
+// and wake up requests waiting for a free chain.
 fn free_chain(mut i: usize) {
     loop {
         free_desc(i);
@@ -207,28 +308,32 @@ fn free_chain(mut i: usize) {
             break;
         }
     }
+    unsafe {
+        crate::process::PROC_MANAGER.wakeup(&DISK.free as *const _ as usize);
+    }
 }
 
+/// Handle a disk interrupt.
+/// The device may have completed several requests since the last one,
+/// so walk the used ring and wake each finished request's owner.
 pub fn disk_intr() {
     unsafe {
         let _lock = DISK.lock.lock();
 
-        while DISK.used_idx % NUM != DISK.used.id as usize % NUM {
-            let id = DISK.used.elems[DISK.used_idx].id as usize;
+        // tell the device we've seen this interrupt,
+        // a completion arriving after this raises a new one.
+        write(VIRTIO_MMIO_INTERRUPT_ACK, read(VIRTIO_MMIO_INTERRUPT_STATUS) & 0x3);
+        fence(Ordering::SeqCst);
 
-            if DISK.info[id].status != 0 {
-                panic!("virtio_disk_intr status");
-            }
+        while DISK.used_idx != ptr::read_volatile(&DISK.used.id) as usize % NUM {
+            let id = DISK.used.elems[DISK.used_idx].id as usize;
 
-            match DISK.info[id].b {
-                Some(bp) => {
-                    (*bp).disk.set(false);
-                    crate::process::PROC_MANAGER.wakeup(bp as usize);
-                }
-                None => {
-                    panic!("disk_intr: disk's info buf is none");
-                }
+            // a failed one is woken all the same, to find its status
+            if !DISK.info[id].busy {
+                panic!("disk_intr: no request in flight");
             }
+            DISK.info[id].busy = false;
+            crate::process::PROC_MANAGER.wakeup(&DISK.info[id] as *const _ as usize);
 
             DISK.used_idx = (DISK.used_idx + 1) % NUM;
         }
@@ -239,33 +344,35 @@ pub fn disk_intr() {
 
 // virtio mmio control registers' offset
 // from qemu's virtio_mmio.h
-const VIRTIO_MMIO_MAGIC_VALUE: usize = 0x000;
-const VIRTIO_MMIO_VERSION: usize = 0x004; // 1 is legacy
-const VIRTIO_MMIO_DEVICE_ID: usize = 0x008; // 1: net, 2: disk
-const VIRTIO_MMIO_VENDOR_ID: usize = 0x00c;
-const VIRTIO_MMIO_DEVICE_FEATURES: usize = 0x010;
-const VIRTIO_MMIO_DRIVER_FEATURES: usize = 0x020;
-const VIRTIO_MMIO_GUEST_PAGE_SIZE: usize = 0x028; // page size for PFN, write-only
-const VIRTIO_MMIO_QUEUE_SEL: usize = 0x030; // select queue, write-only
-const VIRTIO_MMIO_QUEUE_NUM_MAX: usize = 0x034; // max size of current queue, read-only
-const VIRTIO_MMIO_QUEUE_NUM: usize = 0x038; // size of current queue, write-only
-const VIRTIO_MMIO_QUEUE_ALIGN: usize = 0x03c; // used ring alignment, write-only
-const VIRTIO_MMIO_QUEUE_PFN: usize = 0x040; // physical page number for queue, read/write
-const VIRTIO_MMIO_QUEUE_READY: usize = 0x044; // ready bit
-const VIRTIO_MMIO_QUEUE_NOTIFY: usize = 0x050; // write-only
-const VIRTIO_MMIO_INTERRUPT_STATUS: usize = 0x060; // read-only
-const VIRTIO_MMIO_INTERRUPT_ACK: usize = 0x064; // write-only
-const VIRTIO_MMIO_STATUS: usize = 0x070;
+pub const VIRTIO_MMIO_MAGIC_VALUE: usize = 0x000;
+pub const VIRTIO_MMIO_VERSION: usize = 0x004; // 1 is legacy
+pub const VIRTIO_MMIO_DEVICE_ID: usize = 0x008; // 1: net, 2: disk
+pub const VIRTIO_MMIO_VENDOR_ID: usize = 0x00c;
+pub const VIRTIO_MMIO_DEVICE_FEATURES: usize = 0x010;
+pub const VIRTIO_MMIO_DRIVER_FEATURES: usize = 0x020;
+pub const VIRTIO_MMIO_GUEST_PAGE_SIZE: usize = 0x028; // page size for PFN, write-only
+pub const VIRTIO_MMIO_QUEUE_SEL: usize = 0x030; // select queue, write-only
+pub const VIRTIO_MMIO_QUEUE_NUM_MAX: usize = 0x034; // max size of current queue, read-only
+pub const VIRTIO_MMIO_QUEUE_NUM: usize = 0x038; // size of current queue, write-only
+pub const VIRTIO_MMIO_QUEUE_ALIGN: usize = 0x03c; // used ring alignment, write-only
+pub const VIRTIO_MMIO_QUEUE_PFN: usize = 0x040; // physical page number for queue, read/write
+pub const VIRTIO_MMIO_QUEUE_READY: usize = 0x044; // ready bit
+pub const VIRTIO_MMIO_QUEUE_NOTIFY: usize = 0x050; // write-only
+pub const VIRTIO_MMIO_INTERRUPT_STATUS: usize = 0x060; // read-only
+pub const VIRTIO_MMIO_INTERRUPT_ACK: usize = 0x064; // write-only
+pub const VIRTIO_MMIO_STATUS: usize = 0x070;
+pub const VIRTIO_MMIO_CONFIG: usize = 0x100; // device specific configuration space
 
 // virtio status register bits
 // from qemu's virtio_config.h
-const VIRTIO_CONFIG_S_ACKNOWLEDGE: u32 = 1;
-const VIRTIO_CONFIG_S_DRIVER: u32 = 2;
-const VIRTIO_CONFIG_S_DRIVER_OK: u32 = 4;
-const VIRTIO_CONFIG_S_FEATURES_OK: u32 = 8;
+pub const VIRTIO_CONFIG_S_ACKNOWLEDGE: u32 = 1;
+pub const VIRTIO_CONFIG_S_DRIVER: u32 = 2;
+pub const VIRTIO_CONFIG_S_DRIVER_OK: u32 = 4;
+pub const VIRTIO_CONFIG_S_FEATURES_OK: u32 = 8;
 
 // device feature bits
 const VIRTIO_BLK_F_RO: u8 = 5;
+const VIRTIO_BLK_F_FLUSH: u8 = 9;
 const VIRTIO_BLK_F_SCSI: u8 = 7;
 const VIRTIO_BLK_F_CONFIG_WCE: u8 = 11;
 const VIRTIO_BLK_F_MQ: u8 = 12;
@@ -274,16 +381,27 @@ const VIRTIO_RING_F_INDIRECT_DESC: u8 = 28;
 const VIRTIO_RING_F_EVENT_IDX: u8 = 29;
 
 // VRingDesc flags
-const VRING_DESC_F_NEXT: u16 = 1; // chained with another descriptor
-const VRING_DESC_F_WRITE: u16 = 2; // device writes (vs read)
+pub const VRING_DESC_F_NEXT: u16 = 1; // chained with another descriptor
+pub const VRING_DESC_F_WRITE: u16 = 2; // device writes (vs read)
 
 // for disk ops
 const VIRTIO_BLK_T_IN: u32 = 0; // read the disk
 const VIRTIO_BLK_T_OUT: u32 = 1; // write the disk
+const VIRTIO_BLK_T_FLUSH: u32 = 4; // write out the device's cache
+
+// request status, the device writes
+const VIRTIO_BLK_S_UNSUPP: u8 = 2;
+
+/// bytes of a disk sector
+pub const SECTOR: usize = 512;
+
+/// mtime units disk_write_polled() waits, a second at qemu's 10 MHz
+const POLL_TIMEOUT: u64 = 10_000_000;
 
 // this many virtio descriptors
 // must be a power of 2
-const NUM: usize = 8;
+// each request takes three, so up to NUM / 3 can be in flight
+pub const NUM: usize = 32;
 
 #[inline]
 unsafe fn read(offset: usize) -> u32 {
@@ -307,33 +425,39 @@ struct Disk {
     free: [bool; NUM], // TODO - need to start another page?
     used_idx: usize,
     info: [Info; NUM],
+    // request headers, indexed by the first descriptor of each chain
+    ops: [VirtioBlkOutHdr; NUM],
+    // VIRTIO_BLK_F_FLUSH was negotiated
+    has_flush: bool,
     lock: SpinLock<()>,
 }
 
 impl Disk {
     const fn new() -> Self {
         Self {
-            desc: [VRingDesc::new(); NUM],
+            desc: [const { VRingDesc::new() }; NUM],
             avail: [0; (PGSIZE - NUM * mem::size_of::<VRingDesc>()) / mem::size_of::<u16>()],
             used: UsedArea::new(),
             free: [true; NUM],
             used_idx: 0,
-            info: [Info::new(); NUM],
+            info: [const { Info::new() }; NUM],
+            ops: [const { VirtioBlkOutHdr::new() }; NUM],
+            has_flush: false,
             lock: SpinLock::new((), "virtio_disk"),
         }
     }
 }
 
 #[repr(C)]
-struct VRingDesc {
-    addr: u64,
-    len: u32,
-    flags: u16,
-    next: u16,
+pub struct VRingDesc {
+    pub addr: u64,
+    pub len: u32,
+    pub flags: u16,
+    pub next: u16,
 }
 
 impl VRingDesc {
-    const fn new() -> Self {
+    pub const fn new() -> Self {
         Self {
             addr: 0,
             len: 0,
@@ -344,30 +468,30 @@ impl VRingDesc {
 }
 
 #[repr(C)]
-struct UsedArea {
-    flags: u16,
-    id: u16,
-    elems: [VRingUsedElem; NUM],
+pub struct UsedArea {
+    pub flags: u16,
+    pub id: u16,
+    pub elems: [VRingUsedElem; NUM],
 }
 
 impl UsedArea {
-    const fn new() -> Self {
+    pub const fn new() -> Self {
         Self {
             flags: 0,
             id: 0,
-            elems: [VRingUsedElem::new(); NUM],
+            elems: [const { VRingUsedElem::new() }; NUM],
         }
     }
 }
 
 #[repr(C)]
-struct VRingUsedElem {
-    id: u32,
-    len: u32,
+pub struct VRingUsedElem {
+    pub id: u32,
+    pub len: u32,
 }
 
 impl VRingUsedElem {
-    const fn new() -> Self {
+    pub const fn new() -> Self {
         Self { id: 0, len: 0 }
     }
 }
@@ -379,14 +503,60 @@ struct VirtioBlkOutHdr {
     sector: u64,
 }
 
+impl VirtioBlkOutHdr {
+    const fn new() -> Self {
+        Self {
+            typed: 0,
+            reserved: 0,
+            sector: 0,
+        }
+    }
+}
+
 #[repr(C)]
 struct Info {
-    b: Option<*mut Buf>,
     status: u8,
+    // the request with this chain head is in flight
+    busy: bool,
 }
 
 impl Info {
     const fn new() -> Self {
-        Self { b: None, status: 0 }
+        Self { status: 0, busy: false }
+    }
+}
+
+#[cfg(feature = "unit_test")]
+pub mod tests {
+    use super::*;
+
+    /// A full ring fails to allocate without leaking descriptors
+    pub fn desc_exhaustion() {
+        let _lock = unsafe { DISK.lock.lock() };
+        let mut chains = [[0usize; 3]; NUM / 3];
+        for c in chains.iter_mut() {
+            assert!(alloc_descs(c).is_ok());
+        }
+        let mut idx = [0usize; 3];
+        assert!(alloc_descs(&mut idx).is_err());
+        let nfree = unsafe { DISK.free.iter().filter(|f| **f).count() };
+        assert_eq!(nfree, NUM % 3);
+        for c in chains.iter() {
+            for &i in c.iter() {
+                free_desc(i);
+            }
+        }
+        assert!(unsafe { DISK.free.iter().all(|f| *f) });
+    }
+    crate::kernel_test!(desc_exhaustion);
+
+    /// A sector past the end of the disk is refused before it is queued
+    pub fn sector_range() {
+        let mut data = [0u8; SECTOR];
+        let end = unsafe { disk_blocks() } * (BSIZE / SECTOR) as u64;
+        assert_eq!(unsafe { read_sector(end, &mut data) }, Err("sector out of range"));
+        assert_eq!(unsafe { write_sector(u64::MAX, &data) }, Err("sector out of range"));
+        assert!(unsafe { DISK.free.iter().all(|f| *f) });
     }
+    crate::kernel_test!(sector_range);
 }
@@ -0,0 +1,639 @@
+//! driver for a virtio 9p transport, on the third virtio mmio slot,
+//! and a 9P2000.L client reading the host directory it exports
+//!
+//! make P9=dir gives qemu dir, with the mount tag "host".
+//! The root of the export is attached once at init, as ROOT_FID,
+//! walk() walks a new fid from a directory's to a name in it, getattr() gives its attributes,
+//! read_at() opens a file's fid the first time and reads it, readdir() a directory's entries,
+//! close() clunks the fid. The file system mounts the export at /host, see fs/host.rs,
+//! each of its inodes standing for a fid. Its files are only read.
+//! Like the gpu, the single request queue is polled instead of waiting for interrupts.
+
+use core::cmp::min;
+use core::convert::TryFrom;
+use core::sync::atomic::{fence, Ordering};
+use core::{mem, ptr, str};
+
+use crate::consts::{PGSHIFT, PGSIZE, VIRTIO2};
+use crate::spinlock::SpinLock;
+
+use super::virtio::{
+    UsedArea, VRingDesc, NUM, VIRTIO_CONFIG_S_ACKNOWLEDGE, VIRTIO_CONFIG_S_DRIVER,
+    VIRTIO_CONFIG_S_DRIVER_OK, VIRTIO_CONFIG_S_FEATURES_OK, VIRTIO_MMIO_DEVICE_FEATURES,
+    VIRTIO_MMIO_DEVICE_ID, VIRTIO_MMIO_DRIVER_FEATURES, VIRTIO_MMIO_GUEST_PAGE_SIZE,
+    VIRTIO_MMIO_MAGIC_VALUE, VIRTIO_MMIO_QUEUE_NOTIFY, VIRTIO_MMIO_QUEUE_NUM,
+    VIRTIO_MMIO_QUEUE_NUM_MAX, VIRTIO_MMIO_QUEUE_PFN, VIRTIO_MMIO_QUEUE_SEL, VIRTIO_MMIO_STATUS,
+    VIRTIO_MMIO_CONFIG, VIRTIO_MMIO_VERSION, VRING_DESC_F_NEXT, VRING_DESC_F_WRITE,
+};
+
+/// the largest message, of either direction
+pub const MSIZE: usize = 8192;
+
+/// the mount tag is in the device's config space
+const VIRTIO_9P_MOUNT_TAG: u32 = 1 << 0;
+const TAG_MAX: usize = 32;
+
+/// message types, a reply is its request's plus one
+const RLERROR: u8 = 7;
+const TLOPEN: u8 = 12;
+const TGETATTR: u8 = 24;
+const TREADDIR: u8 = 40;
+const TVERSION: u8 = 100;
+const TATTACH: u8 = 104;
+const TWALK: u8 = 110;
+const TREAD: u8 = 116;
+const TCLUNK: u8 = 120;
+
+const NOTAG: u16 = !0;
+const NOFID: u32 = !0;
+/// the most names in a Twalk
+const MAXWELEM: usize = 16;
+/// header of an Rread, before its data
+const IOHDRSZ: usize = 4 + 1 + 2 + 4;
+
+/// the root of the export, attached at init, never clunked
+pub const ROOT_FID: u32 = 0;
+/// the fids walk() gives, from FIRST_FID on
+const FIRST_FID: u32 = 1;
+const NFID: usize = 32;
+const O_RDONLY: u32 = 0;
+/// the attributes Tgetattr asks for, P9_GETATTR_BASIC
+const GETATTR_BASIC: u64 = 0x7ff;
+const S_IFMT: u32 = 0o170000;
+const S_IFDIR: u32 = 0o040000;
+/// a qid, its type, version and path
+const QIDSZ: usize = 1 + 4 + 8;
+
+/// What errno_str() makes of ENOENT, walk() gives None for it
+const NOENT: &str = "9p: no such file";
+
+static mut P9: P9 = P9::new();
+
+/// Builds a message, size, type and tag first, as little endian
+struct Writer<'a> {
+    buf: &'a mut [u8],
+    len: usize,
+    overflow: bool,
+}
+
+impl<'a> Writer<'a> {
+    fn new(buf: &'a mut [u8], typ: u8, tag: u16) -> Self {
+        let mut w = Self { buf, len: 4, overflow: false };
+        w.u8(typ);
+        w.u16(tag);
+        w
+    }
+
+    fn bytes(&mut self, bytes: &[u8]) {
+        if self.len + bytes.len() > self.buf.len() {
+            self.overflow = true;
+            return;
+        }
+        self.buf[self.len..self.len + bytes.len()].copy_from_slice(bytes);
+        self.len += bytes.len();
+    }
+
+    fn u8(&mut self, n: u8) {
+        self.bytes(&[n]);
+    }
+
+    fn u16(&mut self, n: u16) {
+        self.bytes(&n.to_le_bytes());
+    }
+
+    fn u32(&mut self, n: u32) {
+        self.bytes(&n.to_le_bytes());
+    }
+
+    fn u64(&mut self, n: u64) {
+        self.bytes(&n.to_le_bytes());
+    }
+
+    /// A string, its length in a u16 first
+    fn str(&mut self, s: &[u8]) {
+        match u16::try_from(s.len()) {
+            Ok(len) => {
+                self.u16(len);
+                self.bytes(s);
+            }
+            Err(_) => self.overflow = true,
+        }
+    }
+
+    /// Fill in the size, return it
+    fn finish(self) -> Result<usize, &'static str> {
+        if self.overflow {
+            return Err("9p: message too long");
+        }
+        self.buf[..4].copy_from_slice(&(self.len as u32).to_le_bytes());
+        Ok(self.len)
+    }
+}
+
+/// Takes a reply apart
+struct Reader<'a> {
+    buf: &'a [u8],
+    pos: usize,
+}
+
+impl<'a> Reader<'a> {
+    /// Check the header of reply buf to a request of type typ,
+    /// an Rlerror gives the host's errno.
+    fn new(buf: &'a [u8], typ: u8) -> Result<Self, &'static str> {
+        let mut r = Self { buf, pos: 0 };
+        let size = r.u32()? as usize;
+        if size < 7 || size > buf.len() {
+            return Err("9p: bad reply size");
+        }
+        r.buf = &buf[..size];
+        match r.u8()? {
+            t if t == typ + 1 => {}
+            RLERROR => {
+                r.u16()?;
+                return Err(errno_str(r.u32()?));
+            }
+            _ => return Err("9p: unexpected reply"),
+        }
+        r.u16()?;
+        Ok(r)
+    }
+
+    fn bytes(&mut self, n: usize) -> Result<&'a [u8], &'static str> {
+        if self.pos + n > self.buf.len() {
+            return Err("9p: reply too short");
+        }
+        self.pos += n;
+        Ok(&self.buf[self.pos - n..self.pos])
+    }
+
+    fn u8(&mut self) -> Result<u8, &'static str> {
+        Ok(self.bytes(1)?[0])
+    }
+
+    fn u16(&mut self) -> Result<u16, &'static str> {
+        let b = self.bytes(2)?;
+        Ok(u16::from_le_bytes([b[0], b[1]]))
+    }
+
+    fn u32(&mut self) -> Result<u32, &'static str> {
+        let b = self.bytes(4)?;
+        Ok(u32::from_le_bytes([b[0], b[1], b[2], b[3]]))
+    }
+
+    fn u64(&mut self) -> Result<u64, &'static str> {
+        let mut b = [0u8; 8];
+        b.copy_from_slice(self.bytes(8)?);
+        Ok(u64::from_le_bytes(b))
+    }
+
+    fn str(&mut self) -> Result<&'a [u8], &'static str> {
+        let len = self.u16()? as usize;
+        self.bytes(len)
+    }
+}
+
+fn errno_str(errno: u32) -> &'static str {
+    match errno {
+        2 => NOENT,
+        13 => "9p: permission denied",
+        20 => "9p: not a directory",
+        21 => "9p: is a directory",
+        _ => "9p: error from the host",
+    }
+}
+
+/// Probe and initialize the 9p transport, and attach the root of the export.
+/// Return false if there is none on this slot.
+pub unsafe fn init() -> bool {
+    if read(VIRTIO_MMIO_MAGIC_VALUE) != 0x74726976
+        || read(VIRTIO_MMIO_VERSION) != 1
+        || read(VIRTIO_MMIO_DEVICE_ID) != 9
+    {
+        return false;
+    }
+
+    let mut status: u32 = 0;
+    status |= VIRTIO_CONFIG_S_ACKNOWLEDGE;
+    write(VIRTIO_MMIO_STATUS, status);
+    status |= VIRTIO_CONFIG_S_DRIVER;
+    write(VIRTIO_MMIO_STATUS, status);
+
+    let features = read(VIRTIO_MMIO_DEVICE_FEATURES) & VIRTIO_9P_MOUNT_TAG;
+    write(VIRTIO_MMIO_DRIVER_FEATURES, features);
+    status |= VIRTIO_CONFIG_S_FEATURES_OK;
+    write(VIRTIO_MMIO_STATUS, status);
+    status |= VIRTIO_CONFIG_S_DRIVER_OK;
+    write(VIRTIO_MMIO_STATUS, status);
+
+    write(VIRTIO_MMIO_GUEST_PAGE_SIZE, PGSIZE as u32);
+
+    // request queue
+    write(VIRTIO_MMIO_QUEUE_SEL, 0);
+    let max = read(VIRTIO_MMIO_QUEUE_NUM_MAX);
+    if max < NUM as u32 {
+        println!("virtio 9p: request queue too short");
+        return false;
+    }
+    write(VIRTIO_MMIO_QUEUE_NUM, NUM as u32);
+    let page_num: usize = (&P9 as *const _ as usize) >> PGSHIFT;
+    write(VIRTIO_MMIO_QUEUE_PFN, u32::try_from(page_num).unwrap());
+
+    if features & VIRTIO_9P_MOUNT_TAG != 0 {
+        let src = (Into::<usize>::into(VIRTIO2) + VIRTIO_MMIO_CONFIG) as *const u8;
+        let len = min(ptr::read_volatile(src as *const u16) as usize, TAG_MAX);
+        for i in 0..len {
+            P9.tag[i] = ptr::read_volatile(src.add(2 + i));
+        }
+        P9.tag_len = len;
+    }
+
+    let guard = P9.lock.lock();
+    let result = version().and_then(|()| attach());
+    drop(guard);
+    if let Err(str) = result {
+        println!("virtio 9p: {}", str);
+        return false;
+    }
+
+    println!("virtio 9p init: tag {}, msize {}",
+        str::from_utf8(&P9.tag[..P9.tag_len]).unwrap_or("?"), P9.msize);
+    P9.present = true;
+    true
+}
+
+#[inline]
+pub fn is_present() -> bool {
+    unsafe { P9.present }
+}
+
+/// What getattr() tells of a file
+pub struct Attr {
+    pub dir: bool,
+    pub nlink: u64,
+    pub size: u64,
+}
+
+/// What a fid from FIRST_FID on is
+#[derive(Clone, Copy, PartialEq, Eq)]
+enum FidState {
+    Free,
+    /// walked to, not opened yet, it can be walked from
+    Walked,
+    /// opened for reading by read_at(), or by readdir() for its own
+    Open,
+}
+
+/// Walk a new fid from directory fid to name in it, return None if there is no such file
+pub fn walk(fid: u32, name: &[u8]) -> Result<Option<u32>, &'static str> {
+    if !is_present() {
+        return Err("9p: no host directory");
+    }
+    unsafe {
+        let guard = P9.lock.lock();
+        let result = alloc_fid().and_then(|i| {
+            let new = FIRST_FID + i as u32;
+            match walk_fid(fid, name, new) {
+                Ok(true) => {
+                    P9.fids[i] = FidState::Walked;
+                    Ok(Some(new))
+                }
+                Ok(false) => Ok(None),
+                Err(str) if str == NOENT => Ok(None),
+                Err(str) => Err(str),
+            }
+        });
+        drop(guard);
+        result
+    }
+}
+
+/// The attributes of fid
+pub fn getattr(fid: u32) -> Result<Attr, &'static str> {
+    unsafe {
+        let guard = P9.lock.lock();
+        let result = check_fid(fid).and_then(|_| {
+            let mut w = Writer::new(&mut P9.req, TGETATTR, 0);
+            w.u32(fid);
+            w.u64(GETATTR_BASIC);
+            let mut r = transact(w, TGETATTR)?;
+            r.u64()?; // valid
+            r.bytes(QIDSZ)?;
+            let mode = r.u32()?;
+            r.u32()?; // uid
+            r.u32()?; // gid
+            let nlink = r.u64()?;
+            r.u64()?; // rdev
+            let size = r.u64()?;
+            Ok(Attr { dir: mode & S_IFMT == S_IFDIR, nlink, size })
+        });
+        drop(guard);
+        result
+    }
+}
+
+/// Read file fid from offset into dst, opening it the first time,
+/// return how many bytes there were, short at its end
+pub fn read_at(fid: u32, offset: u64, dst: &mut [u8]) -> Result<usize, &'static str> {
+    unsafe {
+        let guard = P9.lock.lock();
+        let result = check_fid(fid).and_then(|i| {
+            let i = i.ok_or("9p: the root is a directory")?;
+            if P9.fids[i] == FidState::Walked {
+                lopen(fid)?;
+                P9.fids[i] = FidState::Open;
+            }
+            read_fid(fid, offset, dst)
+        });
+        drop(guard);
+        result
+    }
+}
+
+/// Call each with the names in directory fid, in the host's order, until it returns false.
+/// The directory is opened through a fid of its own, so that fid can still be walked from.
+pub fn readdir(fid: u32, mut each: impl FnMut(&[u8]) -> bool) -> Result<(), &'static str> {
+    unsafe {
+        let guard = P9.lock.lock();
+        let result = check_fid(fid).and_then(|_| alloc_fid()).and_then(|i| {
+            let dir = FIRST_FID + i as u32;
+            if !walk_fid(fid, b"", dir)? {
+                return Err(NOENT);
+            }
+            let result = lopen(dir).and_then(|()| {
+                let mut offset = 0;
+                loop {
+                    let mut w = Writer::new(&mut P9.req, TREADDIR, 0);
+                    w.u32(dir);
+                    w.u64(offset);
+                    w.u32((P9.msize - IOHDRSZ) as u32);
+                    let mut r = transact(w, TREADDIR)?;
+                    let count = r.u32()? as usize;
+                    let mut entries = Reader { buf: r.bytes(count)?, pos: 0 };
+                    if entries.buf.is_empty() {
+                        return Ok(());
+                    }
+                    while entries.pos < entries.buf.len() {
+                        entries.bytes(QIDSZ)?;
+                        offset = entries.u64()?;
+                        entries.u8()?; // type
+                        if !each(entries.str()?) {
+                            return Ok(());
+                        }
+                    }
+                }
+            });
+            clunk(dir).and(result)
+        });
+        drop(guard);
+        result
+    }
+}
+
+/// Clunk fid from walk(), it is free again even if the host fails it
+pub fn close(fid: u32) -> Result<(), &'static str> {
+    unsafe {
+        let guard = P9.lock.lock();
+        let result = check_fid(fid).and_then(|i| match i {
+            Some(i) => {
+                P9.fids[i] = FidState::Free;
+                clunk(fid)
+            }
+            None => Err("9p: the root is not clunked"),
+        });
+        drop(guard);
+        result
+    }
+}
+
+/// A free fid from FIRST_FID on, its index in P9.fids, with the lock held
+unsafe fn alloc_fid() -> Result<usize, &'static str> {
+    P9.fids.iter().position(|state| *state == FidState::Free).ok_or("9p: too many open files")
+}
+
+/// The index in P9.fids of fid, which must be ROOT_FID, None for it, or one in use,
+/// with the lock held
+unsafe fn check_fid(fid: u32) -> Result<Option<usize>, &'static str> {
+    match fid.checked_sub(FIRST_FID).map(|i| i as usize) {
+        _ if fid == ROOT_FID && P9.present => Ok(None),
+        Some(i) if i < NFID && P9.fids[i] != FidState::Free => Ok(Some(i)),
+        _ => Err("9p: bad fid"),
+    }
+}
+
+/// Negotiate the protocol and msize, with the lock held
+unsafe fn version() -> Result<(), &'static str> {
+    let mut w = Writer::new(&mut P9.req, TVERSION, NOTAG);
+    w.u32(MSIZE as u32);
+    w.str(b"9P2000.L");
+    let mut r = transact(w, TVERSION)?;
+    let msize = min(r.u32()? as usize, MSIZE);
+    if r.str()? != b"9P2000.L" {
+        return Err("9p: the host does not speak 9P2000.L");
+    }
+    if msize <= IOHDRSZ {
+        return Err("9p: msize too small");
+    }
+    P9.msize = msize;
+    Ok(())
+}
+
+unsafe fn attach() -> Result<(), &'static str> {
+    let mut w = Writer::new(&mut P9.req, TATTACH, 0);
+    w.u32(ROOT_FID);
+    w.u32(NOFID);
+    w.str(b"");
+    w.str(b"");
+    w.u32(0); // n_uname, as root
+    transact(w, TATTACH).map(|_| ())
+}
+
+/// Walk newfid from fid along the names of path, up to MAXWELEM of them,
+/// none clones fid, return false if the walk stopped short
+unsafe fn walk_fid(fid: u32, path: &[u8], newfid: u32) -> Result<bool, &'static str> {
+    let mut w = Writer::new(&mut P9.req, TWALK, 0);
+    w.u32(fid);
+    w.u32(newfid);
+    let nwname = twalk_names(&mut w, path)?;
+    let mut r = transact(w, TWALK)?;
+    // a walk stopping short leaves newfid unused
+    Ok(r.u16()? as usize == nwname)
+}
+
+/// Put the names of path into a Twalk, return how many
+fn twalk_names(w: &mut Writer, path: &[u8]) -> Result<usize, &'static str> {
+    let names = || path.split(|c| *c == b'/').filter(|name| !name.is_empty());
+    let nwname = names().count();
+    if nwname > MAXWELEM {
+        return Err("9p: path too deep");
+    }
+    w.u16(nwname as u16);
+    for name in names() {
+        w.str(name);
+    }
+    Ok(nwname)
+}
+
+unsafe fn lopen(fid: u32) -> Result<(), &'static str> {
+    let mut w = Writer::new(&mut P9.req, TLOPEN, 0);
+    w.u32(fid);
+    w.u32(O_RDONLY);
+    transact(w, TLOPEN).map(|_| ())
+}
+
+/// Read fid a message at a time until dst is full or the file ends
+unsafe fn read_fid(fid: u32, mut offset: u64, dst: &mut [u8]) -> Result<usize, &'static str> {
+    let mut done = 0;
+    while done < dst.len() {
+        let count = min(dst.len() - done, P9.msize - IOHDRSZ);
+        let mut w = Writer::new(&mut P9.req, TREAD, 0);
+        w.u32(fid);
+        w.u64(offset);
+        w.u32(count as u32);
+        let mut r = transact(w, TREAD)?;
+        let n = r.u32()? as usize;
+        if n > count {
+            return Err("9p: read more than asked for");
+        }
+        dst[done..done + n].copy_from_slice(r.bytes(n)?);
+        done += n;
+        offset += n as u64;
+        if n == 0 {
+            break;
+        }
+    }
+    Ok(done)
+}
+
+unsafe fn clunk(fid: u32) -> Result<(), &'static str> {
+    let mut w = Writer::new(&mut P9.req, TCLUNK, 0);
+    w.u32(fid);
+    transact(w, TCLUNK).map(|_| ())
+}
+
+/// Send the request w built in P9.req, and poll for its reply.
+/// The lock must be held, except during init.
+unsafe fn transact(w: Writer, typ: u8) -> Result<Reader<'static>, &'static str> {
+    let len = w.finish()?;
+
+    P9.desc[0].addr = P9.req.as_ptr() as u64;
+    P9.desc[0].len = len as u32;
+    P9.desc[0].flags = VRING_DESC_F_NEXT;
+    P9.desc[0].next = 1;
+
+    P9.desc[1].addr = P9.resp.as_ptr() as u64;
+    P9.desc[1].len = MSIZE as u32;
+    P9.desc[1].flags = VRING_DESC_F_WRITE;
+    P9.desc[1].next = 0;
+
+    P9.avail[2 + (P9.avail[1] as usize % NUM)] = 0;
+    fence(Ordering::SeqCst);
+    P9.avail[1] = P9.avail[1].wrapping_add(1);
+    fence(Ordering::SeqCst);
+
+    write(VIRTIO_MMIO_QUEUE_NOTIFY, 0);
+
+    // poll the used ring
+    while ptr::read_volatile(&P9.used.id) == P9.used_idx {}
+    P9.used_idx = P9.used_idx.wrapping_add(1);
+    fence(Ordering::SeqCst);
+
+    Reader::new(&P9.resp, typ)
+}
+
+#[inline]
+unsafe fn read(offset: usize) -> u32 {
+    let src = (Into::<usize>::into(VIRTIO2) + offset) as *const u32;
+    ptr::read_volatile(src)
+}
+
+#[inline]
+unsafe fn write(offset: usize, data: u32) {
+    let dst = (Into::<usize>::into(VIRTIO2) + offset) as *mut u32;
+    ptr::write_volatile(dst, data);
+}
+
+#[repr(C, align(4096))]
+struct P9 {
+    // a page
+    desc: [VRingDesc; NUM],
+    avail: [u16; (PGSIZE - NUM * mem::size_of::<VRingDesc>()) / mem::size_of::<u16>()],
+    // another page
+    used: UsedArea,
+    used_idx: u16,
+    req: [u8; MSIZE],
+    resp: [u8; MSIZE],
+    msize: usize,
+    tag: [u8; TAG_MAX],
+    tag_len: usize,
+    present: bool,
+    /// the fids from FIRST_FID on
+    fids: [FidState; NFID],
+    lock: SpinLock<()>,
+}
+
+impl P9 {
+    const fn new() -> Self {
+        Self {
+            desc: [const { VRingDesc::new() }; NUM],
+            avail: [0; (PGSIZE - NUM * mem::size_of::<VRingDesc>()) / mem::size_of::<u16>()],
+            used: UsedArea::new(),
+            used_idx: 0,
+            req: [0; MSIZE],
+            resp: [0; MSIZE],
+            msize: 0,
+            tag: [0; TAG_MAX],
+            tag_len: 0,
+            present: false,
+            fids: [FidState::Free; NFID],
+            lock: SpinLock::new((), "virtio_9p"),
+        }
+    }
+}
+
+#[cfg(feature = "unit_test")]
+pub mod tests {
+    use super::*;
+
+    /// Messages as the 9P2000.L manual lays them out
+    pub fn messages() {
+        let mut buf = [0u8; 64];
+        let mut w = Writer::new(&mut buf, TWALK, 3);
+        w.u32(ROOT_FID);
+        w.u32(FIRST_FID);
+        assert_eq!(twalk_names(&mut w, b"/usr//bin/"), Ok(2));
+        assert_eq!(w.finish(), Ok(27));
+        assert_eq!(&buf[..27], &[
+            27, 0, 0, 0, TWALK, 3, 0, 0, 0, 0, 0, 1, 0, 0, 0, 2, 0,
+            3, 0, b'u', b's', b'r', 3, 0, b'b', b'i', b'n',
+        ]);
+
+        let mut small = [0u8; 8];
+        let mut w = Writer::new(&mut small, TCLUNK, 0);
+        w.u32(FIRST_FID);
+        w.u32(0);
+        assert!(w.finish().is_err());
+
+        let rread = [13, 0, 0, 0, TREAD + 1, 0, 0, 2, 0, 0, 0, b'h', b'i', 0xff];
+        let mut r = Reader::new(&rread, TREAD).unwrap();
+        let n = r.u32().unwrap() as usize;
+        assert_eq!(r.bytes(n), Ok(&b"hi"[..]));
+        assert!(r.bytes(1).is_err());
+        assert!(Reader::new(&rread, TWALK).is_err());
+        assert!(Reader::new(&rread[..12], TREAD).is_err());
+
+        let rlerror = [11, 0, 0, 0, RLERROR, 0, 0, 2, 0, 0, 0];
+        assert_eq!(Reader::new(&rlerror, TREAD).err(), Some(NOENT));
+    }
+    crate::kernel_test!(messages);
+
+    /// Only fids in use go to the host, the root stays attached
+    pub fn fids() {
+        let present = unsafe { P9.present };
+        assert_eq!(unsafe { check_fid(ROOT_FID) }.is_ok(), present);
+        assert!(unsafe { check_fid(FIRST_FID) }.is_err());
+        assert!(unsafe { check_fid(FIRST_FID + NFID as u32) }.is_err());
+        assert!(close(FIRST_FID).is_err());
+        assert!(read_at(FIRST_FID, 0, &mut [0; 1]).is_err());
+    }
+    crate::kernel_test!(fids);
+}
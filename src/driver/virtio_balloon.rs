@@ -0,0 +1,396 @@
+//! driver for a virtio memory balloon, on the fourth virtio mmio slot
+//!
+//! The host sets how many pages it wants back, and raises a configuration change interrupt,
+//! the balloon then inflates, taking free pages with kalloc and telling the host their numbers,
+//! or deflates, telling the host it takes them again and giving them back to kfree.
+//! It leaves at least BALLOON_RESERVE pages free, and holds up to BALLOON_MAX.
+//! When kalloc runs out, it calls reclaim(), which deflates a request's worth of pages,
+//! if the host allows that with the deflate on OOM feature,
+//! only after that does the allocation fail.
+//! If the host asks for statistics, it is told the free and total pages of kalloc.
+//!
+//! make BALLOON=1 gives qemu one, set its size with "balloon <MB of guest memory>"
+//! in qemu's monitor.
+//! Requests to the inflate and deflate queues are polled, the stats queue's interrupts are not.
+
+use core::cmp::min;
+use core::convert::TryFrom;
+use core::sync::atomic::{fence, AtomicBool, Ordering};
+use core::{mem, ptr};
+
+use crate::consts::{BALLOON_MAX, BALLOON_RESERVE, PGSHIFT, PGSIZE, VIRTIO3};
+use crate::mm::{kalloc, kalloc_stats, kfree};
+use crate::spinlock::SpinLock;
+
+use super::virtio::{
+    UsedArea, VRingDesc, NUM, VIRTIO_CONFIG_S_ACKNOWLEDGE, VIRTIO_CONFIG_S_DRIVER,
+    VIRTIO_CONFIG_S_DRIVER_OK, VIRTIO_CONFIG_S_FEATURES_OK, VIRTIO_MMIO_CONFIG,
+    VIRTIO_MMIO_DEVICE_FEATURES, VIRTIO_MMIO_DEVICE_ID, VIRTIO_MMIO_DRIVER_FEATURES,
+    VIRTIO_MMIO_GUEST_PAGE_SIZE, VIRTIO_MMIO_INTERRUPT_ACK, VIRTIO_MMIO_INTERRUPT_STATUS,
+    VIRTIO_MMIO_MAGIC_VALUE, VIRTIO_MMIO_QUEUE_NOTIFY, VIRTIO_MMIO_QUEUE_NUM,
+    VIRTIO_MMIO_QUEUE_NUM_MAX, VIRTIO_MMIO_QUEUE_PFN, VIRTIO_MMIO_QUEUE_SEL, VIRTIO_MMIO_STATUS,
+    VIRTIO_MMIO_VERSION, VRING_DESC_F_WRITE,
+};
+
+/// feature bits
+const VIRTIO_BALLOON_F_STATS_VQ: u32 = 1 << 1;
+const VIRTIO_BALLOON_F_DEFLATE_ON_OOM: u32 = 1 << 2;
+
+/// configuration space, the pages the host wants, and the pages it has
+const CONFIG_NUM_PAGES: usize = VIRTIO_MMIO_CONFIG;
+const CONFIG_ACTUAL: usize = VIRTIO_MMIO_CONFIG + 4;
+
+/// interrupt status bits
+const INTERRUPT_USED: u32 = 1 << 0;
+const INTERRUPT_CONFIG: u32 = 1 << 1;
+
+/// the device does not interrupt when it uses a buffer of the queue
+const VRING_AVAIL_F_NO_INTERRUPT: u16 = 1;
+
+const INFLATEQ: u32 = 0;
+const DEFLATEQ: u32 = 1;
+const STATSQ: u32 = 2;
+
+/// page numbers in a request, as Linux's
+const PFNS_PER_REQ: usize = 256;
+
+/// statistics tags
+const S_MEMFREE: u16 = 4;
+const S_MEMTOT: u16 = 5;
+
+/// set while inflate() allocates, so that reclaim() does not take the lock again
+static INFLATING: AtomicBool = AtomicBool::new(false);
+
+static mut BALLOON: Balloon = Balloon::new();
+
+/// Probe and initialize the balloon, and inflate it to the host's size.
+/// Return false if there is none on this slot.
+pub unsafe fn init() -> bool {
+    if read(VIRTIO_MMIO_MAGIC_VALUE) != 0x74726976
+        || read(VIRTIO_MMIO_VERSION) != 1
+        || read(VIRTIO_MMIO_DEVICE_ID) != 5
+    {
+        return false;
+    }
+
+    let mut status: u32 = 0;
+    status |= VIRTIO_CONFIG_S_ACKNOWLEDGE;
+    write(VIRTIO_MMIO_STATUS, status);
+    status |= VIRTIO_CONFIG_S_DRIVER;
+    write(VIRTIO_MMIO_STATUS, status);
+
+    // the host need not be told before a deflated page is used again
+    let features = read(VIRTIO_MMIO_DEVICE_FEATURES)
+        & (VIRTIO_BALLOON_F_STATS_VQ | VIRTIO_BALLOON_F_DEFLATE_ON_OOM);
+    write(VIRTIO_MMIO_DRIVER_FEATURES, features);
+    status |= VIRTIO_CONFIG_S_FEATURES_OK;
+    write(VIRTIO_MMIO_STATUS, status);
+    status |= VIRTIO_CONFIG_S_DRIVER_OK;
+    write(VIRTIO_MMIO_STATUS, status);
+
+    write(VIRTIO_MMIO_GUEST_PAGE_SIZE, PGSIZE as u32);
+
+    let stats = features & VIRTIO_BALLOON_F_STATS_VQ != 0;
+    if !setup(INFLATEQ, &BALLOON.inflate)
+        || !setup(DEFLATEQ, &BALLOON.deflate)
+        || (stats && !setup(STATSQ, &BALLOON.stats))
+    {
+        println!("virtio balloon: queue too short");
+        return false;
+    }
+    BALLOON.inflate.avail[0] = VRING_AVAIL_F_NO_INTERRUPT;
+    BALLOON.deflate.avail[0] = VRING_AVAIL_F_NO_INTERRUPT;
+
+    let guard = BALLOON.lock.lock();
+    BALLOON.oom = features & VIRTIO_BALLOON_F_DEFLATE_ON_OOM != 0;
+    BALLOON.stats_vq = stats;
+    BALLOON.present = true;
+    // the device takes the first statistics at once, and asks for more by using them
+    if stats {
+        put_stats();
+    }
+    adjust();
+    drop(guard);
+
+    println!("virtio balloon init: {} pages{}",
+        BALLOON.count, if BALLOON.oom { ", deflated on OOM" } else { "" });
+    true
+}
+
+#[inline]
+pub fn is_present() -> bool {
+    unsafe { BALLOON.present }
+}
+
+/// Pages in the balloon, and how many of them reclaim() has deflated in all
+pub fn stats() -> (usize, usize) {
+    unsafe { (BALLOON.count, BALLOON.reclaimed) }
+}
+
+/// Called by kalloc when it has no free page.
+/// Deflate up to a request's worth of pages, if the host lets the guest,
+/// return how many pages kfree got.
+pub fn reclaim() -> usize {
+    if !is_present() || INFLATING.load(Ordering::Acquire) {
+        return 0;
+    }
+    unsafe {
+        if !BALLOON.oom {
+            return 0;
+        }
+        let guard = BALLOON.lock.lock();
+        let n = deflate(min(BALLOON.count, PFNS_PER_REQ));
+        BALLOON.reclaimed += n;
+        write(CONFIG_ACTUAL, BALLOON.count as u32);
+        drop(guard);
+        n
+    }
+}
+
+/// The host changed its size, or used the statistics
+pub fn intr() {
+    unsafe {
+        let status = read(VIRTIO_MMIO_INTERRUPT_STATUS);
+        write(VIRTIO_MMIO_INTERRUPT_ACK, status & (INTERRUPT_USED | INTERRUPT_CONFIG));
+        fence(Ordering::SeqCst);
+        if !is_present() {
+            return;
+        }
+
+        let guard = BALLOON.lock.lock();
+        if BALLOON.stats_vq && BALLOON.stats.take_used() {
+            put_stats();
+        }
+        if status & INTERRUPT_CONFIG != 0 {
+            adjust();
+        }
+        drop(guard);
+    }
+}
+
+#[derive(Debug, PartialEq, Eq)]
+enum Step {
+    Inflate(usize),
+    Deflate(usize),
+    Done,
+}
+
+/// The next request on the way from count pages towards target,
+/// with free pages in kalloc, of which BALLOON_RESERVE are kept.
+fn step(target: usize, count: usize, free: usize) -> Step {
+    let target = min(target, BALLOON_MAX);
+    if count > target {
+        Step::Deflate(min(count - target, PFNS_PER_REQ))
+    } else {
+        let n = min(min(target - count, free.saturating_sub(BALLOON_RESERVE)), PFNS_PER_REQ);
+        if n == 0 {
+            Step::Done
+        } else {
+            Step::Inflate(n)
+        }
+    }
+}
+
+/// Go towards the host's size, and tell it the size reached.
+/// The lock must be held.
+unsafe fn adjust() {
+    let target = read(CONFIG_NUM_PAGES) as usize;
+    loop {
+        let done = match step(target, BALLOON.count, kalloc_stats().0) {
+            Step::Inflate(n) => inflate(n),
+            Step::Deflate(n) => deflate(n),
+            Step::Done => 0,
+        };
+        if done == 0 {
+            break;
+        }
+    }
+    write(CONFIG_ACTUAL, BALLOON.count as u32);
+}
+
+/// Take up to n pages from kalloc and give them to the host, return how many.
+/// The lock must be held.
+unsafe fn inflate(n: usize) -> usize {
+    let start = BALLOON.count;
+    INFLATING.store(true, Ordering::Release);
+    let mut end = start;
+    while end < start + n {
+        match kalloc() {
+            Some(pa) => BALLOON.pfns[end] = u32::try_from(pa as usize >> PGSHIFT).unwrap(),
+            None => break,
+        }
+        end += 1;
+    }
+    INFLATING.store(false, Ordering::Release);
+    if end == start {
+        return 0;
+    }
+    let pfns = &BALLOON.pfns[start..end];
+    BALLOON.inflate.send(INFLATEQ, pfns.as_ptr() as usize, mem::size_of_val(pfns));
+    BALLOON.count = end;
+    end - start
+}
+
+/// Take n pages back from the host and give them to kfree.
+/// The lock must be held.
+unsafe fn deflate(n: usize) -> usize {
+    if n == 0 {
+        return 0;
+    }
+    let end = BALLOON.count;
+    let pfns = &BALLOON.pfns[end - n..end];
+    BALLOON.deflate.send(DEFLATEQ, pfns.as_ptr() as usize, mem::size_of_val(pfns));
+    for pfn in pfns.iter() {
+        kfree(((*pfn as usize) << PGSHIFT) as *mut u8);
+    }
+    BALLOON.count = end - n;
+    n
+}
+
+/// Give the device the statistics of now, for it to use when it wants the next.
+/// The lock must be held.
+unsafe fn put_stats() {
+    let (free, total) = kalloc_stats();
+    BALLOON.stat = [
+        Stat { tag: S_MEMFREE, val: (free * PGSIZE) as u64 },
+        Stat { tag: S_MEMTOT, val: (total * PGSIZE) as u64 },
+    ];
+    BALLOON.stats.push(BALLOON.stat.as_ptr() as usize, mem::size_of_val(&BALLOON.stat), false);
+    write(VIRTIO_MMIO_QUEUE_NOTIFY, STATSQ);
+}
+
+/// Tell the device where queue sel is
+unsafe fn setup(sel: u32, queue: &Queue) -> bool {
+    write(VIRTIO_MMIO_QUEUE_SEL, sel);
+    if read(VIRTIO_MMIO_QUEUE_NUM_MAX) < NUM as u32 {
+        return false;
+    }
+    write(VIRTIO_MMIO_QUEUE_NUM, NUM as u32);
+    let page_num: usize = (queue as *const _ as usize) >> PGSHIFT;
+    write(VIRTIO_MMIO_QUEUE_PFN, u32::try_from(page_num).unwrap());
+    true
+}
+
+#[inline]
+unsafe fn read(offset: usize) -> u32 {
+    let src = (Into::<usize>::into(VIRTIO3) + offset) as *const u32;
+    ptr::read_volatile(src)
+}
+
+#[inline]
+unsafe fn write(offset: usize, data: u32) {
+    let dst = (Into::<usize>::into(VIRTIO3) + offset) as *mut u32;
+    ptr::write_volatile(dst, data);
+}
+
+/// A queue with one buffer at a time in it
+#[repr(C, align(4096))]
+struct Queue {
+    // a page
+    desc: [VRingDesc; NUM],
+    avail: [u16; (PGSIZE - NUM * mem::size_of::<VRingDesc>()) / mem::size_of::<u16>()],
+    // another page
+    used: UsedArea,
+    used_idx: u16,
+}
+
+impl Queue {
+    const fn new() -> Self {
+        Self {
+            desc: [const { VRingDesc::new() }; NUM],
+            avail: [0; (PGSIZE - NUM * mem::size_of::<VRingDesc>()) / mem::size_of::<u16>()],
+            used: UsedArea::new(),
+            used_idx: 0,
+        }
+    }
+
+    /// Make the buffer of len bytes at addr available to the device
+    unsafe fn push(&mut self, addr: usize, len: usize, writable: bool) {
+        self.desc[0].addr = addr as u64;
+        self.desc[0].len = len as u32;
+        self.desc[0].flags = if writable { VRING_DESC_F_WRITE } else { 0 };
+        self.desc[0].next = 0;
+
+        self.avail[2 + (self.avail[1] as usize % NUM)] = 0;
+        fence(Ordering::SeqCst);
+        self.avail[1] = self.avail[1].wrapping_add(1);
+        fence(Ordering::SeqCst);
+    }
+
+    /// Whether the device used the buffer
+    unsafe fn take_used(&mut self) -> bool {
+        if ptr::read_volatile(&self.used.id) == self.used_idx {
+            return false;
+        }
+        self.used_idx = self.used_idx.wrapping_add(1);
+        fence(Ordering::SeqCst);
+        true
+    }
+
+    /// Push the buffer to queue sel, and poll until the device used it
+    unsafe fn send(&mut self, sel: u32, addr: usize, len: usize) {
+        self.push(addr, len, false);
+        write(VIRTIO_MMIO_QUEUE_NOTIFY, sel);
+        while !self.take_used() {}
+    }
+}
+
+#[repr(C, packed)]
+#[derive(Clone, Copy)]
+struct Stat {
+    tag: u16,
+    val: u64,
+}
+
+struct Balloon {
+    inflate: Queue,
+    deflate: Queue,
+    stats: Queue,
+    stat: [Stat; 2],
+    /// the page numbers of the pages the host has, count of them
+    pfns: [u32; BALLOON_MAX],
+    count: usize,
+    reclaimed: usize,
+    /// deflate on OOM, and the stats queue, were negotiated
+    oom: bool,
+    stats_vq: bool,
+    present: bool,
+    lock: SpinLock<()>,
+}
+
+impl Balloon {
+    const fn new() -> Self {
+        Self {
+            inflate: Queue::new(),
+            deflate: Queue::new(),
+            stats: Queue::new(),
+            stat: [Stat { tag: 0, val: 0 }; 2],
+            pfns: [0; BALLOON_MAX],
+            count: 0,
+            reclaimed: 0,
+            oom: false,
+            stats_vq: false,
+            present: false,
+            lock: SpinLock::new((), "virtio_balloon"),
+        }
+    }
+}
+
+#[cfg(feature = "unit_test")]
+pub mod tests {
+    use super::*;
+
+    /// Requests are at most PFNS_PER_REQ pages, and leave BALLOON_RESERVE free
+    pub fn steps() {
+        assert_eq!(step(0, 0, 1000), Step::Done);
+        assert_eq!(step(100, 0, 1000), Step::Inflate(100));
+        assert_eq!(step(1000, 0, 1000), Step::Inflate(PFNS_PER_REQ));
+        assert_eq!(step(1000, 600, BALLOON_RESERVE + 10), Step::Inflate(10));
+        assert_eq!(step(1000, 600, BALLOON_RESERVE), Step::Done);
+        assert_eq!(step(100, 150, 0), Step::Deflate(50));
+        assert_eq!(step(0, 1000, 0), Step::Deflate(PFNS_PER_REQ));
+        assert_eq!(step(usize::MAX, BALLOON_MAX, usize::MAX), Step::Done);
+        assert_eq!(mem::size_of::<Stat>(), 10);
+    }
+    crate::kernel_test!(steps);
+}
@@ -0,0 +1,400 @@
+//! driver for virtio gpu device, on the second virtio mmio slot
+//!
+//! Only the 2D part is used: a single resource backed by a static framebuffer,
+//! attached to scanout 0.
+//! The control queue is polled instead of waiting for interrupts,
+//! since commands are only issued by the console and are short.
+
+use core::convert::TryFrom;
+use core::sync::atomic::{fence, Ordering};
+use core::{mem, ptr};
+
+use crate::consts::{PGSHIFT, PGSIZE, VIRTIO1};
+use crate::spinlock::SpinLock;
+
+use super::virtio::{
+    UsedArea, VRingDesc, NUM, VIRTIO_CONFIG_S_ACKNOWLEDGE, VIRTIO_CONFIG_S_DRIVER,
+    VIRTIO_CONFIG_S_DRIVER_OK, VIRTIO_CONFIG_S_FEATURES_OK, VIRTIO_MMIO_DEVICE_FEATURES,
+    VIRTIO_MMIO_DEVICE_ID, VIRTIO_MMIO_DRIVER_FEATURES, VIRTIO_MMIO_GUEST_PAGE_SIZE,
+    VIRTIO_MMIO_MAGIC_VALUE, VIRTIO_MMIO_QUEUE_NOTIFY, VIRTIO_MMIO_QUEUE_NUM,
+    VIRTIO_MMIO_QUEUE_NUM_MAX, VIRTIO_MMIO_QUEUE_PFN, VIRTIO_MMIO_QUEUE_SEL, VIRTIO_MMIO_STATUS,
+    VIRTIO_MMIO_VERSION, VRING_DESC_F_NEXT, VRING_DESC_F_WRITE,
+};
+
+/// largest framebuffer supported, bigger displays only use the top-left part
+pub const FB_MAX_WIDTH: usize = 1280;
+pub const FB_MAX_HEIGHT: usize = 800;
+
+/// pixels are stored as 0x00RRGGBB, i.e., B8G8R8X8 in memory,
+/// in pages of their own, so that they can be mapped to user space, see fb.rs
+#[repr(C, align(4096))]
+struct Framebuffer([u32; FB_MAX_WIDTH * FB_MAX_HEIGHT]);
+
+static mut FB: Framebuffer = Framebuffer([0; FB_MAX_WIDTH * FB_MAX_HEIGHT]);
+
+static mut GPU: Gpu = Gpu::new();
+
+const RESOURCE_ID: u32 = 1;
+
+/// Probe and initialize the gpu.
+/// Return false if there is no gpu on this slot.
+pub unsafe fn init() -> bool {
+    assert_eq!((&GPU.desc as *const _ as usize) % PGSIZE, 0);
+    assert_eq!((&GPU.used as *const _ as usize) % PGSIZE, 0);
+
+    if read(VIRTIO_MMIO_MAGIC_VALUE) != 0x74726976
+        || read(VIRTIO_MMIO_VERSION) != 1
+        || read(VIRTIO_MMIO_DEVICE_ID) != 16
+    {
+        return false;
+    }
+
+    let mut status: u32 = 0;
+    status |= VIRTIO_CONFIG_S_ACKNOWLEDGE;
+    write(VIRTIO_MMIO_STATUS, status);
+    status |= VIRTIO_CONFIG_S_DRIVER;
+    write(VIRTIO_MMIO_STATUS, status);
+
+    // no 3D, no EDID
+    let _ = read(VIRTIO_MMIO_DEVICE_FEATURES);
+    write(VIRTIO_MMIO_DRIVER_FEATURES, 0);
+    status |= VIRTIO_CONFIG_S_FEATURES_OK;
+    write(VIRTIO_MMIO_STATUS, status);
+    status |= VIRTIO_CONFIG_S_DRIVER_OK;
+    write(VIRTIO_MMIO_STATUS, status);
+
+    write(VIRTIO_MMIO_GUEST_PAGE_SIZE, PGSIZE as u32);
+
+    // control queue
+    write(VIRTIO_MMIO_QUEUE_SEL, 0);
+    let max = read(VIRTIO_MMIO_QUEUE_NUM_MAX);
+    if max < NUM as u32 {
+        println!("virtio gpu: control queue too short");
+        return false;
+    }
+    write(VIRTIO_MMIO_QUEUE_NUM, NUM as u32);
+    let page_num: usize = (&GPU as *const _ as usize) >> PGSHIFT;
+    write(VIRTIO_MMIO_QUEUE_PFN, u32::try_from(page_num).unwrap());
+
+    let guard = GPU.lock.lock();
+
+    // query the size of the display, fall back to 640x480
+    let (width, height) = match display_info() {
+        Some((w, h)) if w > 0 && h > 0 => (w, h),
+        _ => (640, 480),
+    };
+    GPU.width = if width as usize > FB_MAX_WIDTH { FB_MAX_WIDTH as u32 } else { width };
+    GPU.height = if height as usize > FB_MAX_HEIGHT { FB_MAX_HEIGHT as u32 } else { height };
+
+    let ok = create_resource() && attach_backing() && set_scanout();
+    drop(guard);
+    if !ok {
+        println!("virtio gpu: failed to set up the framebuffer");
+        return false;
+    }
+
+    println!("virtio gpu init: {}x{}", GPU.width, GPU.height);
+    GPU.present = true;
+    true
+}
+
+#[inline]
+pub fn is_present() -> bool {
+    unsafe { GPU.present }
+}
+
+/// (width, height) of the framebuffer in pixels
+pub fn resolution() -> (usize, usize) {
+    unsafe { (GPU.width as usize, GPU.height as usize) }
+}
+
+/// The framebuffer, with a stride of resolution().0 pixels.
+/// Call flush() to present the modified part.
+pub unsafe fn framebuffer() -> &'static mut [u32] {
+    let (w, h) = resolution();
+    &mut FB.0[..w * h]
+}
+
+/// Physical address of the framebuffer, it is page aligned.
+pub fn framebuffer_addr() -> usize {
+    unsafe { FB.0.as_ptr() as usize }
+}
+
+/// Copy the given rectangle of the framebuffer to the host and show it
+pub fn flush(x: usize, y: usize, width: usize, height: usize) {
+    if !is_present() {
+        return;
+    }
+
+    let r = Rect {
+        x: x as u32,
+        y: y as u32,
+        width: width as u32,
+        height: height as u32,
+    };
+    unsafe {
+        let guard = GPU.lock.lock();
+        let offset = ((y * GPU.width as usize + x) * mem::size_of::<u32>()) as u64;
+        let transfer = TransferToHost2d {
+            hdr: CtrlHdr::new(CMD_TRANSFER_TO_HOST_2D),
+            r,
+            offset,
+            resource_id: RESOURCE_ID,
+            padding: 0,
+        };
+        command(&transfer);
+        let flush = ResourceFlush {
+            hdr: CtrlHdr::new(CMD_RESOURCE_FLUSH),
+            r,
+            resource_id: RESOURCE_ID,
+            padding: 0,
+        };
+        command(&flush);
+        drop(guard);
+    }
+}
+
+unsafe fn display_info() -> Option<(u32, u32)> {
+    let hdr = CtrlHdr::new(CMD_GET_DISPLAY_INFO);
+    if send(&hdr, mem::size_of::<RespDisplayInfo>()) != RESP_OK_DISPLAY_INFO {
+        return None;
+    }
+    let info = &*(GPU.resp.as_ptr() as *const RespDisplayInfo);
+    let mode = &info.pmodes[0];
+    Some((mode.r.width, mode.r.height))
+}
+
+unsafe fn create_resource() -> bool {
+    let create = ResourceCreate2d {
+        hdr: CtrlHdr::new(CMD_RESOURCE_CREATE_2D),
+        resource_id: RESOURCE_ID,
+        format: FORMAT_B8G8R8X8_UNORM,
+        width: GPU.width,
+        height: GPU.height,
+    };
+    command(&create)
+}
+
+unsafe fn attach_backing() -> bool {
+    // the framebuffer is a static array, thus physically contiguous
+    let attach = AttachBacking {
+        hdr: CtrlHdr::new(CMD_RESOURCE_ATTACH_BACKING),
+        resource_id: RESOURCE_ID,
+        nr_entries: 1,
+        entry: MemEntry {
+            addr: FB.0.as_ptr() as u64,
+            length: GPU.width * GPU.height * mem::size_of::<u32>() as u32,
+            padding: 0,
+        },
+    };
+    command(&attach)
+}
+
+unsafe fn set_scanout() -> bool {
+    let scanout = SetScanout {
+        hdr: CtrlHdr::new(CMD_SET_SCANOUT),
+        r: Rect {
+            x: 0,
+            y: 0,
+            width: GPU.width,
+            height: GPU.height,
+        },
+        scanout_id: 0,
+        resource_id: RESOURCE_ID,
+    };
+    command(&scanout)
+}
+
+/// Send a command only expecting an OK_NODATA response
+unsafe fn command<T>(req: &T) -> bool {
+    send(req, mem::size_of::<CtrlHdr>()) == RESP_OK_NODATA
+}
+
+/// Send a request in the control queue and poll for its response.
+/// Return the response type.
+/// The gpu's lock must be held, except during init.
+unsafe fn send<T>(req: &T, resp_len: usize) -> u32 {
+    let req_len = mem::size_of::<T>();
+    assert!(req_len <= GPU.req.len() * 8 && resp_len <= GPU.resp.len() * 8);
+
+    // copy the request into the static buffer,
+    // the request itself may be on a kernel stack, which is not direct mapped
+    ptr::copy_nonoverlapping(req as *const T as *const u8, GPU.req.as_mut_ptr() as *mut u8, req_len);
+
+    GPU.desc[0].addr = GPU.req.as_ptr() as u64;
+    GPU.desc[0].len = req_len as u32;
+    GPU.desc[0].flags = VRING_DESC_F_NEXT;
+    GPU.desc[0].next = 1;
+
+    GPU.desc[1].addr = GPU.resp.as_ptr() as u64;
+    GPU.desc[1].len = resp_len as u32;
+    GPU.desc[1].flags = VRING_DESC_F_WRITE;
+    GPU.desc[1].next = 0;
+
+    GPU.avail[2 + (GPU.avail[1] as usize % NUM)] = 0;
+    fence(Ordering::SeqCst);
+    GPU.avail[1] = GPU.avail[1].wrapping_add(1);
+    fence(Ordering::SeqCst);
+
+    write(VIRTIO_MMIO_QUEUE_NOTIFY, 0);
+
+    // poll the used ring
+    while ptr::read_volatile(&GPU.used.id) == GPU.used_idx {}
+    GPU.used_idx = GPU.used_idx.wrapping_add(1);
+    fence(Ordering::SeqCst);
+
+    (*(GPU.resp.as_ptr() as *const CtrlHdr)).typ
+}
+
+#[inline]
+unsafe fn read(offset: usize) -> u32 {
+    let src = (Into::<usize>::into(VIRTIO1) + offset) as *const u32;
+    ptr::read_volatile(src)
+}
+
+#[inline]
+unsafe fn write(offset: usize, data: u32) {
+    let dst = (Into::<usize>::into(VIRTIO1) + offset) as *mut u32;
+    ptr::write_volatile(dst, data);
+}
+
+#[repr(C, align(4096))]
+struct Gpu {
+    // a page
+    desc: [VRingDesc; NUM],
+    avail: [u16; (PGSIZE - NUM * mem::size_of::<VRingDesc>()) / mem::size_of::<u16>()],
+    // another page
+    used: UsedArea,
+    used_idx: u16,
+    // request and response buffers, in u64 for alignment
+    req: [u64; 64],
+    resp: [u64; 64],
+    width: u32,
+    height: u32,
+    present: bool,
+    lock: SpinLock<()>,
+}
+
+impl Gpu {
+    const fn new() -> Self {
+        Self {
+            desc: [const { VRingDesc::new() }; NUM],
+            avail: [0; (PGSIZE - NUM * mem::size_of::<VRingDesc>()) / mem::size_of::<u16>()],
+            used: UsedArea::new(),
+            used_idx: 0,
+            req: [0; 64],
+            resp: [0; 64],
+            width: 0,
+            height: 0,
+            present: false,
+            lock: SpinLock::new((), "virtio_gpu"),
+        }
+    }
+}
+
+// control commands and responses
+const CMD_GET_DISPLAY_INFO: u32 = 0x0100;
+const CMD_RESOURCE_CREATE_2D: u32 = 0x0101;
+const CMD_SET_SCANOUT: u32 = 0x0103;
+const CMD_RESOURCE_FLUSH: u32 = 0x0104;
+const CMD_TRANSFER_TO_HOST_2D: u32 = 0x0105;
+const CMD_RESOURCE_ATTACH_BACKING: u32 = 0x0106;
+const RESP_OK_NODATA: u32 = 0x1100;
+const RESP_OK_DISPLAY_INFO: u32 = 0x1101;
+
+const FORMAT_B8G8R8X8_UNORM: u32 = 2;
+
+const MAX_SCANOUTS: usize = 16;
+
+#[repr(C)]
+struct CtrlHdr {
+    typ: u32,
+    flags: u32,
+    fence_id: u64,
+    ctx_id: u32,
+    padding: u32,
+}
+
+impl CtrlHdr {
+    const fn new(typ: u32) -> Self {
+        Self {
+            typ,
+            flags: 0,
+            fence_id: 0,
+            ctx_id: 0,
+            padding: 0,
+        }
+    }
+}
+
+#[repr(C)]
+#[derive(Copy, Clone)]
+struct Rect {
+    x: u32,
+    y: u32,
+    width: u32,
+    height: u32,
+}
+
+#[repr(C)]
+struct DisplayOne {
+    r: Rect,
+    enabled: u32,
+    flags: u32,
+}
+
+#[repr(C)]
+struct RespDisplayInfo {
+    hdr: CtrlHdr,
+    pmodes: [DisplayOne; MAX_SCANOUTS],
+}
+
+#[repr(C)]
+struct ResourceCreate2d {
+    hdr: CtrlHdr,
+    resource_id: u32,
+    format: u32,
+    width: u32,
+    height: u32,
+}
+
+#[repr(C)]
+struct MemEntry {
+    addr: u64,
+    length: u32,
+    padding: u32,
+}
+
+/// attach_backing command followed by a single memory entry
+#[repr(C)]
+struct AttachBacking {
+    hdr: CtrlHdr,
+    resource_id: u32,
+    nr_entries: u32,
+    entry: MemEntry,
+}
+
+#[repr(C)]
+struct SetScanout {
+    hdr: CtrlHdr,
+    r: Rect,
+    scanout_id: u32,
+    resource_id: u32,
+}
+
+#[repr(C)]
+struct TransferToHost2d {
+    hdr: CtrlHdr,
+    r: Rect,
+    offset: u64,
+    resource_id: u32,
+    padding: u32,
+}
+
+#[repr(C)]
+struct ResourceFlush {
+    hdr: CtrlHdr,
+    r: Rect,
+    resource_id: u32,
+    padding: u32,
+}
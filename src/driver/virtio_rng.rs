@@ -0,0 +1,148 @@
+//! driver for a virtio entropy source, on the fifth virtio mmio slot
+//!
+//! The host fills a buffer with random bytes, which are mixed into the kernel's entropy pool,
+//! at init, seeding it, and whenever the pool reseeds, see random.rs.
+//! The single request queue is polled, its interrupt is not used.
+
+use core::cmp::min;
+use core::convert::TryFrom;
+use core::sync::atomic::{fence, Ordering};
+use core::{mem, ptr};
+
+use crate::consts::{PGSHIFT, PGSIZE, VIRTIO4};
+use crate::random;
+use crate::spinlock::SpinLock;
+
+use super::virtio::{
+    UsedArea, VRingDesc, NUM, VIRTIO_CONFIG_S_ACKNOWLEDGE, VIRTIO_CONFIG_S_DRIVER,
+    VIRTIO_CONFIG_S_DRIVER_OK, VIRTIO_CONFIG_S_FEATURES_OK, VIRTIO_MMIO_DEVICE_FEATURES,
+    VIRTIO_MMIO_DEVICE_ID, VIRTIO_MMIO_DRIVER_FEATURES, VIRTIO_MMIO_GUEST_PAGE_SIZE,
+    VIRTIO_MMIO_MAGIC_VALUE, VIRTIO_MMIO_QUEUE_NOTIFY, VIRTIO_MMIO_QUEUE_NUM,
+    VIRTIO_MMIO_QUEUE_NUM_MAX, VIRTIO_MMIO_QUEUE_PFN, VIRTIO_MMIO_QUEUE_SEL, VIRTIO_MMIO_STATUS,
+    VIRTIO_MMIO_VERSION, VRING_DESC_F_WRITE,
+};
+
+static mut RNG: Rng = Rng::new();
+
+/// Probe and initialize the entropy source, and seed the pool from it.
+/// Return false if there is none on this slot.
+pub unsafe fn init() -> bool {
+    if read(VIRTIO_MMIO_MAGIC_VALUE) != 0x74726976
+        || read(VIRTIO_MMIO_VERSION) != 1
+        || read(VIRTIO_MMIO_DEVICE_ID) != 4
+    {
+        return false;
+    }
+
+    let mut status: u32 = 0;
+    status |= VIRTIO_CONFIG_S_ACKNOWLEDGE;
+    write(VIRTIO_MMIO_STATUS, status);
+    status |= VIRTIO_CONFIG_S_DRIVER;
+    write(VIRTIO_MMIO_STATUS, status);
+
+    // there are no features
+    let _ = read(VIRTIO_MMIO_DEVICE_FEATURES);
+    write(VIRTIO_MMIO_DRIVER_FEATURES, 0);
+    status |= VIRTIO_CONFIG_S_FEATURES_OK;
+    write(VIRTIO_MMIO_STATUS, status);
+    status |= VIRTIO_CONFIG_S_DRIVER_OK;
+    write(VIRTIO_MMIO_STATUS, status);
+
+    write(VIRTIO_MMIO_GUEST_PAGE_SIZE, PGSIZE as u32);
+
+    // request queue
+    write(VIRTIO_MMIO_QUEUE_SEL, 0);
+    let max = read(VIRTIO_MMIO_QUEUE_NUM_MAX);
+    if max < NUM as u32 {
+        println!("virtio rng: request queue too short");
+        return false;
+    }
+    write(VIRTIO_MMIO_QUEUE_NUM, NUM as u32);
+    let page_num: usize = (&RNG as *const _ as usize) >> PGSHIFT;
+    write(VIRTIO_MMIO_QUEUE_PFN, u32::try_from(page_num).unwrap());
+
+    RNG.present = true;
+    let mut seed = [0u8; random::SEED_BYTES];
+    let n = fill(&mut seed);
+    random::add_entropy(&seed[..n], n * 8);
+    println!("virtio rng init: {} bytes", n);
+    true
+}
+
+#[inline]
+pub fn is_present() -> bool {
+    unsafe { RNG.present }
+}
+
+/// Fill dst with random bytes from the host, return how many it gave
+pub fn fill(dst: &mut [u8]) -> usize {
+    if !is_present() {
+        return 0;
+    }
+    unsafe {
+        let guard = RNG.lock.lock();
+        let len = min(dst.len(), RNG.buf.len());
+
+        RNG.desc[0].addr = RNG.buf.as_ptr() as u64;
+        RNG.desc[0].len = len as u32;
+        RNG.desc[0].flags = VRING_DESC_F_WRITE;
+        RNG.desc[0].next = 0;
+
+        RNG.avail[2 + (RNG.avail[1] as usize % NUM)] = 0;
+        fence(Ordering::SeqCst);
+        RNG.avail[1] = RNG.avail[1].wrapping_add(1);
+        fence(Ordering::SeqCst);
+
+        write(VIRTIO_MMIO_QUEUE_NOTIFY, 0);
+
+        // poll the used ring
+        while ptr::read_volatile(&RNG.used.id) == RNG.used_idx {}
+        let elem = &RNG.used.elems[RNG.used_idx as usize % NUM];
+        let n = min(ptr::read_volatile(&elem.len) as usize, len);
+        RNG.used_idx = RNG.used_idx.wrapping_add(1);
+        fence(Ordering::SeqCst);
+
+        dst[..n].copy_from_slice(&RNG.buf[..n]);
+        drop(guard);
+        n
+    }
+}
+
+#[inline]
+unsafe fn read(offset: usize) -> u32 {
+    let src = (Into::<usize>::into(VIRTIO4) + offset) as *const u32;
+    ptr::read_volatile(src)
+}
+
+#[inline]
+unsafe fn write(offset: usize, data: u32) {
+    let dst = (Into::<usize>::into(VIRTIO4) + offset) as *mut u32;
+    ptr::write_volatile(dst, data);
+}
+
+#[repr(C, align(4096))]
+struct Rng {
+    // a page
+    desc: [VRingDesc; NUM],
+    avail: [u16; (PGSIZE - NUM * mem::size_of::<VRingDesc>()) / mem::size_of::<u16>()],
+    // another page
+    used: UsedArea,
+    used_idx: u16,
+    buf: [u8; 64],
+    present: bool,
+    lock: SpinLock<()>,
+}
+
+impl Rng {
+    const fn new() -> Self {
+        Self {
+            desc: [const { VRingDesc::new() }; NUM],
+            avail: [0; (PGSIZE - NUM * mem::size_of::<VRingDesc>()) / mem::size_of::<u16>()],
+            used: UsedArea::new(),
+            used_idx: 0,
+            buf: [0; 64],
+            present: false,
+            lock: SpinLock::new((), "virtio_rng"),
+        }
+    }
+}
@@ -0,0 +1,537 @@
+//! Flattened device tree, as passed by qemu or the firmware in a1
+//!
+//! Only what the kernel needs is read out of it, once, by the boot hart,
+//! before paging is on, since the tree is usually above PHYSTOP.
+//! Refer to the devicetree specification, v0.3, chapter 5, for the format.
+//!
+//! All header fields and tokens are big-endian u32s,
+//! node names and property values are padded to 4 bytes.
+
+use core::slice;
+use core::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+
+use crate::cmdline;
+use crate::consts::{KERNBASE, NCPU, NSMP};
+
+const FDT_MAGIC: u32 = 0xd00dfeed;
+const FDT_BEGIN_NODE: u32 = 1;
+const FDT_END_NODE: u32 = 2;
+const FDT_PROP: u32 = 3;
+const FDT_NOP: u32 = 4;
+const FDT_END: u32 = 9;
+
+/// header size, in bytes, of version 17
+const HEADER_SIZE: usize = 40;
+/// sanity limit for totalsize, qemu's are a few KB
+const MAX_SIZE: usize = 1024 * 1024;
+
+#[inline]
+fn be32(bytes: &[u8], off: usize) -> Option<u32> {
+    let b = bytes.get(off..off + 4)?;
+    Some(u32::from_be_bytes([b[0], b[1], b[2], b[3]]))
+}
+
+#[inline]
+fn align4(n: usize) -> usize {
+    (n + 3) & !3
+}
+
+/// bytes up to the first nul
+fn cstr(bytes: &[u8]) -> &[u8] {
+    let len = bytes.iter().position(|&c| c == 0).unwrap_or(bytes.len());
+    &bytes[..len]
+}
+
+/// Size in bytes of the tree at physical address pa, from its header,
+/// e.g., for start.rs to let supervisor mode read it.
+pub unsafe fn total_size(pa: usize) -> Result<usize, &'static str> {
+    if pa == 0 || !pa.is_multiple_of(8) {
+        return Err("bad address");
+    }
+    let head = slice::from_raw_parts(pa as *const u8, 8);
+    if be32(head, 0) != Some(FDT_MAGIC) {
+        return Err("bad magic");
+    }
+    let total = be32(head, 4).unwrap() as usize;
+    if !(HEADER_SIZE..=MAX_SIZE).contains(&total) {
+        return Err("bad size");
+    }
+    Ok(total)
+}
+
+pub struct Fdt<'a> {
+    structs: &'a [u8],
+    strings: &'a [u8],
+}
+
+impl<'a> Fdt<'a> {
+    pub fn from_bytes(blob: &'a [u8]) -> Result<Self, &'static str> {
+        if be32(blob, 0) != Some(FDT_MAGIC) {
+            return Err("bad magic");
+        }
+        if blob.len() < HEADER_SIZE {
+            return Err("truncated header");
+        }
+        let field = |i: usize| be32(blob, 4 * i).unwrap() as usize;
+        let (total, off_struct, off_strings) = (field(1), field(2), field(3));
+        let (size_strings, size_struct) = (field(8), field(9));
+        if total > blob.len() {
+            return Err("truncated");
+        }
+        let structs = blob.get(off_struct..off_struct + size_struct).ok_or("bad struct block")?;
+        let strings = blob.get(off_strings..off_strings + size_strings).ok_or("bad strings block")?;
+        Ok(Self { structs, strings })
+    }
+
+    /// The tree at physical address pa, which must stay untouched for 'a
+    pub unsafe fn from_addr(pa: usize) -> Result<Self, &'static str> {
+        let total = total_size(pa)?;
+        Self::from_bytes(slice::from_raw_parts(pa as *const u8, total))
+    }
+
+    pub fn tokens(&self) -> Tokens<'a> {
+        Tokens { fdt: Fdt { structs: self.structs, strings: self.strings }, off: 0 }
+    }
+
+    /// Hart ids of the cpu nodes under /cpus that are not disabled,
+    /// bit i for hart i, ids from 64 on are dropped.
+    pub fn harts(&self) -> u64 {
+        let mut mask = 0u64;
+        let mut depth = 0;
+        let mut in_cpus = false;
+        // properties of the current /cpus/cpu@N node
+        let mut is_cpu = false;
+        let mut reg = None;
+        let mut disabled = false;
+        for token in self.tokens() {
+            match token {
+                Token::Begin(name) => {
+                    depth += 1;
+                    if depth == 2 {
+                        in_cpus = name == b"cpus";
+                    }
+                    if depth == 3 && in_cpus {
+                        is_cpu = false;
+                        reg = None;
+                        disabled = false;
+                    }
+                }
+                Token::Prop(name, value) if depth == 3 && in_cpus => match name {
+                    b"device_type" => is_cpu = cstr(value) == b"cpu",
+                    b"status" => disabled = cstr(value) == b"disabled",
+                    // #address-cells of /cpus is 1, or 2 on some boards
+                    b"reg" => reg = match value.len() {
+                        4 => be32(value, 0).map(|id| id as u64),
+                        8 => Some((be32(value, 0).unwrap() as u64) << 32 | be32(value, 4).unwrap() as u64),
+                        _ => None,
+                    },
+                    _ => {}
+                },
+                Token::Prop(..) => {}
+                Token::End => {
+                    if depth == 3 && in_cpus && is_cpu && !disabled {
+                        match reg {
+                            Some(id) if id < 64 => mask |= 1 << id,
+                            _ => {}
+                        }
+                    }
+                    depth -= 1;
+                }
+            }
+        }
+        mask
+    }
+
+    /// The riscv,isa string of the first cpu node, e.g., b"rv64imafdch_zicsr"
+    pub fn isa(&self) -> Option<&'a [u8]> {
+        let mut depth = 0;
+        let mut in_cpus = false;
+        for token in self.tokens() {
+            match token {
+                Token::Begin(name) => {
+                    depth += 1;
+                    if depth == 2 {
+                        in_cpus = name == b"cpus";
+                    }
+                }
+                Token::Prop(b"riscv,isa", value) if depth == 3 && in_cpus => return Some(cstr(value)),
+                Token::Prop(..) => {}
+                Token::End => depth -= 1,
+            }
+        }
+        None
+    }
+
+    /// Base and size of the first range of the first memory node,
+    /// sized by the root's #address-cells and #size-cells.
+    pub fn memory(&self) -> Option<(usize, usize)> {
+        let mut depth = 0;
+        // the spec's defaults
+        let (mut address_cells, mut size_cells) = (2, 1);
+        let mut is_memory = false;
+        let mut reg = None;
+        for token in self.tokens() {
+            match token {
+                Token::Begin(_) => {
+                    depth += 1;
+                    if depth == 2 {
+                        is_memory = false;
+                        reg = None;
+                    }
+                }
+                Token::Prop(name, value) if depth == 1 => match name {
+                    b"#address-cells" => address_cells = be32(value, 0)? as usize,
+                    b"#size-cells" => size_cells = be32(value, 0)? as usize,
+                    _ => {}
+                },
+                Token::Prop(name, value) if depth == 2 => match name {
+                    b"device_type" => is_memory = cstr(value) == b"memory",
+                    b"reg" => reg = Some(value),
+                    _ => {}
+                },
+                Token::Prop(..) => {}
+                Token::End => {
+                    if depth == 2 && is_memory {
+                        let reg = reg?;
+                        let base = cells(reg, 0, address_cells)?;
+                        let size = cells(reg, address_cells, size_cells)?;
+                        return Some((base as usize, size as usize));
+                    }
+                    depth -= 1;
+                }
+            }
+        }
+        None
+    }
+
+    /// The bootargs of /chosen, e.g., qemu's -append, without the nul
+    pub fn bootargs(&self) -> Option<&'a [u8]> {
+        let mut depth = 0;
+        let mut in_chosen = false;
+        for token in self.tokens() {
+            match token {
+                Token::Begin(name) => {
+                    depth += 1;
+                    if depth == 2 {
+                        in_chosen = name == b"chosen";
+                    }
+                }
+                Token::Prop(b"bootargs", value) if depth == 2 && in_chosen => return Some(cstr(value)),
+                Token::Prop(..) => {}
+                Token::End => depth -= 1,
+            }
+        }
+        None
+    }
+
+    /// Is there a node whose compatible list has compat, e.g., b"sifive,ccache0"?
+    pub fn has_compatible(&self, compat: &[u8]) -> bool {
+        self.tokens().any(|token| match token {
+            Token::Prop(b"compatible", value) => value.split(|&c| c == 0).any(|s| s == compat),
+            _ => false,
+        })
+    }
+}
+
+/// A number of n cells, 1 or 2, starting at cell i of value
+fn cells(value: &[u8], i: usize, n: usize) -> Option<u64> {
+    match n {
+        1 => be32(value, 4 * i).map(|x| x as u64),
+        2 => Some((be32(value, 4 * i)? as u64) << 32 | be32(value, 4 * i + 4)? as u64),
+        _ => None,
+    }
+}
+
+/// Single-letter extensions in an isa string, bit 0 for 'a', and so on,
+/// they follow rv32/rv64 up to the first multi-letter one.
+fn extensions(isa: &[u8]) -> usize {
+    let letters = match isa.get(..4) {
+        Some(b"rv32") | Some(b"rv64") => &isa[4..],
+        _ => return 0,
+    };
+    letters.iter()
+        .take_while(|&&c| c != b'_')
+        .filter(|c| c.is_ascii_lowercase())
+        .fold(0, |mask, &c| mask | 1 << (c - b'a'))
+}
+
+pub enum Token<'a> {
+    /// start of a node, with its name, e.g., b"cpu@0", the root's is empty
+    Begin(&'a [u8]),
+    End,
+    /// a property of the current node, with its name and value
+    Prop(&'a [u8], &'a [u8]),
+}
+
+/// Walk over the structure block, stops at FDT_END or at anything malformed
+pub struct Tokens<'a> {
+    fdt: Fdt<'a>,
+    off: usize,
+}
+
+impl<'a> Iterator for Tokens<'a> {
+    type Item = Token<'a>;
+
+    fn next(&mut self) -> Option<Token<'a>> {
+        let structs = self.fdt.structs;
+        loop {
+            let token = be32(structs, self.off)?;
+            self.off += 4;
+            match token {
+                FDT_BEGIN_NODE => {
+                    let name = cstr(structs.get(self.off..)?);
+                    self.off += align4(name.len() + 1);
+                    return Some(Token::Begin(name));
+                }
+                FDT_END_NODE => return Some(Token::End),
+                FDT_PROP => {
+                    let len = be32(structs, self.off)? as usize;
+                    let nameoff = be32(structs, self.off + 4)? as usize;
+                    let value = structs.get(self.off + 8..self.off + 8 + len)?;
+                    let name = cstr(self.fdt.strings.get(nameoff..)?);
+                    self.off += 8 + align4(len);
+                    return Some(Token::Prop(name, value));
+                }
+                FDT_NOP => continue,
+                // FDT_END, or garbage
+                _ => {
+                    self.off = structs.len();
+                    return None;
+                }
+            }
+        }
+    }
+}
+
+/// Harts that the kernel runs on, bit i for hart i
+static HARTS: AtomicUsize = AtomicUsize::new(0);
+
+/// Single-letter isa extensions of the first hart, see extensions()
+static EXTENSIONS: AtomicUsize = AtomicUsize::new(0);
+
+/// End of the RAM starting at KERNBASE, 0 if unknown
+static RAM_END: AtomicUsize = AtomicUsize::new(0);
+
+/// Is there a SiFive L2 cache controller? see driver/l2cache.rs
+static L2_CACHE: AtomicBool = AtomicBool::new(false);
+
+/// L2 cache controllers of the FU740 and the FU540
+const L2_CACHE_COMPATIBLE: [&[u8]; 2] = [b"sifive,fu740-c000-ccache", b"sifive,fu540-c000-ccache"];
+
+/// Read what the kernel needs from the tree at pa,
+/// called once by the boot hart before any other hart looks.
+/// Without a usable tree, assume the NSMP harts from param.rs.
+pub unsafe fn init(pa: usize) {
+    let found = match Fdt::from_addr(pa) {
+        Ok(fdt) => {
+            EXTENSIONS.store(fdt.isa().map_or(0, extensions), Ordering::SeqCst);
+            match fdt.memory() {
+                Some((base, size)) if base == KERNBASE.const_usize() => {
+                    RAM_END.store(base + size, Ordering::SeqCst);
+                }
+                Some((base, _)) => println!("dtb: RAM at {:#x}, not at KERNBASE", base),
+                None => println!("dtb: no memory node"),
+            }
+            cmdline::init(fdt.bootargs().unwrap_or(b""));
+            let l2 = L2_CACHE_COMPATIBLE.iter().any(|compat| fdt.has_compatible(compat));
+            L2_CACHE.store(l2, Ordering::SeqCst);
+            fdt.harts()
+        }
+        Err(err) => {
+            println!("dtb: {} at {:#x}, assume {} harts", err, pa, NSMP);
+            0
+        }
+    };
+    let mut harts = if found == 0 { (1 << NSMP) - 1 } else { found as usize };
+    if harts >> NCPU != 0 {
+        println!("dtb: harts {:#x} found, only the first NCPU={} are used", harts, NCPU);
+        harts &= (1 << NCPU) - 1;
+    }
+    HARTS.store(harts, Ordering::SeqCst);
+}
+
+/// Mask of the harts in use, bit i for hart i
+pub fn harts() -> usize {
+    HARTS.load(Ordering::SeqCst)
+}
+
+/// Use only the harts in mask from now on, e.g., for nosmp,
+/// called by the boot hart before the others look
+pub fn set_harts(mask: usize) {
+    HARTS.store(harts() & mask, Ordering::SeqCst);
+}
+
+/// Number of harts in use
+pub fn nharts() -> usize {
+    harts().count_ones() as usize
+}
+
+/// Does the device tree say the harts have single-letter extension c, e.g., b'h'?
+/// False without a device tree.
+pub fn has_extension(c: u8) -> bool {
+    c.is_ascii_lowercase() && EXTENSIONS.load(Ordering::SeqCst) & 1 << (c - b'a') != 0
+}
+
+/// End of RAM, if the device tree says where, e.g., below PHYSTOP with qemu -m 64M
+pub fn ram_end() -> Option<usize> {
+    match RAM_END.load(Ordering::SeqCst) {
+        0 => None,
+        end => Some(end),
+    }
+}
+
+pub fn has_l2_cache() -> bool {
+    L2_CACHE.load(Ordering::SeqCst)
+}
+
+#[cfg(feature = "unit_test")]
+pub mod tests {
+    use super::*;
+
+    struct Writer<'a> {
+        buf: &'a mut [u8],
+        n: usize,
+    }
+
+    impl Writer<'_> {
+        fn word(&mut self, w: u32) {
+            self.buf[self.n..self.n + 4].copy_from_slice(&w.to_be_bytes());
+            self.n += 4;
+        }
+
+        fn bytes(&mut self, b: &[u8]) {
+            self.buf[self.n..self.n + b.len()].copy_from_slice(b);
+            self.n += align4(b.len());
+        }
+
+        fn begin(&mut self, name: &[u8]) {
+            self.word(FDT_BEGIN_NODE);
+            self.bytes(name);
+        }
+
+        fn prop(&mut self, nameoff: u32, value: &[u8]) {
+            self.word(FDT_PROP);
+            self.word(value.len() as u32);
+            self.word(nameoff);
+            self.bytes(value);
+        }
+    }
+
+    /// Assemble a tree with /cpus/cpu@N nodes for the given ids,
+    /// the last one disabled, into buf, return its size.
+    fn build(buf: &mut [u8], ids: &[u32]) -> usize {
+        // device_type at 0, reg at 12, status at 16, riscv,isa at 23,
+        // compatible at 33, #address-cells at 44, #size-cells at 59, bootargs at 71
+        const STRINGS: &[u8] = b"device_type\0reg\0status\0riscv,isa\0\
+            compatible\0#address-cells\0#size-cells\0bootargs\0";
+        for b in buf.iter_mut() {
+            *b = 0;
+        }
+        let mut w = Writer { buf, n: HEADER_SIZE };
+        w.begin(b"\0");
+        w.prop(44, &2u32.to_be_bytes());
+        w.prop(59, &2u32.to_be_bytes());
+        w.begin(b"chosen\0");
+        w.prop(71, b"nosmp init=/sh\0");
+        w.word(FDT_END_NODE);
+        w.begin(b"cpus\0");
+        for (i, &id) in ids.iter().enumerate() {
+            w.begin(b"cpu@x\0");
+            w.prop(0, b"cpu\0");
+            w.word(FDT_NOP);
+            w.prop(12, &id.to_be_bytes());
+            w.prop(23, b"rv64imafdch_zicsr\0");
+            if i == ids.len() - 1 {
+                w.prop(16, b"disabled\0");
+            }
+            w.word(FDT_END_NODE);
+        }
+        w.word(FDT_END_NODE);
+        // a cpu-like node outside /cpus does not count
+        w.begin(b"soc\0");
+        w.begin(b"cpu@9\0");
+        w.prop(0, b"cpu\0");
+        w.prop(12, &9u32.to_be_bytes());
+        w.word(FDT_END_NODE);
+        w.begin(b"cache-controller@2010000\0");
+        w.prop(33, b"sifive,fu740-c000-ccache\0cache\0");
+        w.word(FDT_END_NODE);
+        w.word(FDT_END_NODE);
+        // 64MB at 0x80000000, in two cells each
+        w.begin(b"memory@80000000\0");
+        w.prop(0, b"memory\0");
+        w.prop(12, &[0, 0, 0, 0, 0x80, 0, 0, 0, 0, 0, 0, 0, 0x04, 0, 0, 0]);
+        w.word(FDT_END_NODE);
+        w.word(FDT_END_NODE);
+        w.word(FDT_END);
+
+        let size_struct = w.n - HEADER_SIZE;
+        let off_strings = w.n;
+        w.bytes(STRINGS);
+        let total = w.n;
+        let header = [FDT_MAGIC, total as u32, HEADER_SIZE as u32, off_strings as u32,
+            0, 17, 16, 0, STRINGS.len() as u32, size_struct as u32];
+        for (i, field) in header.iter().enumerate() {
+            w.buf[4 * i..4 * i + 4].copy_from_slice(&field.to_be_bytes());
+        }
+        total
+    }
+
+    pub fn parse_harts() {
+        let mut buf = [0u8; 1024];
+        let n = build(&mut buf, &[0, 1, 3, 2]);
+        let fdt = Fdt::from_bytes(&buf[..n]).unwrap();
+        // hart 2 is disabled, soc/cpu@9 is not under /cpus
+        assert_eq!(fdt.harts(), 0b1011);
+
+        let n = build(&mut buf, &[5, 6]);
+        assert_eq!(Fdt::from_bytes(&buf[..n]).unwrap().harts(), 1 << 5);
+
+        assert_eq!(fdt_isa(&buf[..n]), Some(&b"rv64imafdch_zicsr"[..]));
+
+        // truncated or corrupted trees are refused
+        assert!(Fdt::from_bytes(&buf[..n - 4]).is_err());
+        buf[0] = 0;
+        assert!(Fdt::from_bytes(&buf[..n]).is_err());
+    }
+
+    fn fdt_isa(blob: &[u8]) -> Option<&[u8]> {
+        Fdt::from_bytes(blob).unwrap().isa()
+    }
+
+    pub fn isa_extensions() {
+        let mask = extensions(b"rv64imafdch_zicsr_zifencei");
+        for c in b"imafdch".iter() {
+            assert!(mask & 1 << (c - b'a') != 0);
+        }
+        // s and z only show up in multi-letter names here
+        assert_eq!(mask.count_ones(), 7);
+        assert_eq!(extensions(b"rv64gc"), 1 << (b'g' - b'a') | 1 << (b'c' - b'a'));
+        assert_eq!(extensions(b"x86"), 0);
+    }
+
+    pub fn other_nodes() {
+        let mut buf = [0u8; 1024];
+        let n = build(&mut buf, &[0]);
+        let fdt = Fdt::from_bytes(&buf[..n]).unwrap();
+        assert_eq!(fdt.memory(), Some((0x80000000, 0x4000000)));
+        assert!(fdt.has_compatible(b"sifive,fu740-c000-ccache"));
+        assert!(fdt.has_compatible(b"cache"));
+        // whole strings only
+        assert!(!fdt.has_compatible(b"sifive"));
+        assert!(!fdt.has_compatible(b"sifive,fu540-c000-ccache"));
+        assert_eq!(fdt.bootargs(), Some(&b"nosmp init=/sh"[..]));
+    }
+
+    pub fn booted() {
+        // the hart running the tests is one of them
+        let id = unsafe { crate::process::cpu_id() };
+        assert!(harts() & (1 << id) != 0);
+        assert!(nharts() >= 1 && nharts() <= NCPU);
+    }
+
+    crate::kernel_test!(parse_harts);
+    crate::kernel_test!(isa_extensions);
+    crate::kernel_test!(other_nodes);
+    crate::kernel_test!(booted);
+}
@@ -0,0 +1,128 @@
+//! Event counters, like Linux's eventfd
+//!
+//! An event counter is a u64, add() adds to it, and read() waits until
+//! it is nonzero, then returns it and resets it to 0,
+//! so a burst of wakeups is taken in one go.
+//! add() never sleeps, so kernel threads and interrupt handlers
+//! can use it to wake a process waiting in read().
+//! With EFD_NONBLOCK, read() fails instead of waiting.
+//!
+//! A counter is referred to by its index in the table,
+//! behind a File from the eventfd syscall, see eventalloc() in fs/file.rs,
+//! a read of 8 bytes of it is read(), a write of 8 bytes add(), its last close closes it.
+
+use crate::consts::NEVENTFD;
+use crate::process::{my_proc, PROC_MANAGER};
+use crate::spinlock::SpinLock;
+
+pub const EFD_NONBLOCK: usize = 1 << 0;
+
+#[derive(Clone, Copy)]
+struct Event {
+    used: bool,
+    count: u64,
+    flags: usize,
+}
+
+static EVENTS: SpinLock<[Event; NEVENTFD]> =
+    SpinLock::new([Event { used: false, count: 0, flags: 0 }; NEVENTFD], "eventfd");
+
+/// Readers of counter id sleep here
+fn chan(events: &[Event; NEVENTFD], id: usize) -> usize {
+    &events[id] as *const Event as usize
+}
+
+fn check(events: &[Event; NEVENTFD], id: usize) -> Result<(), &'static str> {
+    match events.get(id) {
+        Some(event) if event.used => Ok(()),
+        _ => Err("no such event counter"),
+    }
+}
+
+/// Create a counter starting at initial
+pub fn eventfd(initial: u64, flags: usize) -> Result<usize, &'static str> {
+    let mut events = EVENTS.lock();
+    let id = events.iter().position(|event| !event.used).ok_or("out of event counters")?;
+    events[id] = Event { used: true, count: initial, flags };
+    Ok(id)
+}
+
+/// Wait until counter id is nonzero, return it and reset it
+pub fn read(id: usize) -> Result<u64, &'static str> {
+    let mut events = EVENTS.lock();
+    loop {
+        check(&events, id)?;
+        let event = &mut events[id];
+        if event.count != 0 {
+            let count = event.count;
+            event.count = 0;
+            return Ok(count);
+        }
+        if event.flags & EFD_NONBLOCK != 0 {
+            return Err("would block");
+        }
+        let p = unsafe { my_proc() };
+        if p.killed {
+            return Err("killed");
+        }
+        let chan = chan(&events, id);
+        events = p.sleep(chan, events);
+    }
+}
+
+/// Add n to counter id, waking its readers, never sleeps.
+/// Fail if it would overflow, u64::MAX is never reached, as in Linux.
+pub fn add(id: usize, n: u64) -> Result<(), &'static str> {
+    let mut events = EVENTS.lock();
+    check(&events, id)?;
+    let event = &mut events[id];
+    match event.count.checked_add(n) {
+        Some(count) if count != u64::MAX => event.count = count,
+        _ => return Err("counter overflow"),
+    }
+    if n != 0 {
+        unsafe { PROC_MANAGER.wakeup(chan(&events, id)); }
+    }
+    Ok(())
+}
+
+/// Whether read() would return at once
+pub fn readable(id: usize) -> bool {
+    let events = EVENTS.lock();
+    check(&events, id).is_ok() && events[id].count != 0
+}
+
+/// Free counter id, a reader waiting on it fails
+pub fn close(id: usize) -> Result<(), &'static str> {
+    let mut events = EVENTS.lock();
+    check(&events, id)?;
+    events[id].used = false;
+    unsafe { PROC_MANAGER.wakeup(chan(&events, id)); }
+    Ok(())
+}
+
+#[cfg(feature = "unit_test")]
+pub mod tests {
+    use super::*;
+
+    /// Adds are summed up and taken at once, without sleeping
+    pub fn counter() {
+        let id = eventfd(2, EFD_NONBLOCK).unwrap();
+        add(id, 3).unwrap();
+        add(id, 0).unwrap();
+        assert!(readable(id));
+        assert_eq!(read(id), Ok(5));
+        assert!(!readable(id));
+        assert!(read(id).is_err());
+
+        add(id, u64::MAX - 1).unwrap();
+        assert!(add(id, 1).is_err());
+        assert_eq!(read(id), Ok(u64::MAX - 1));
+
+        close(id).unwrap();
+        assert!(add(id, 1).is_err());
+        assert!(read(id).is_err());
+        assert!(!readable(id));
+    }
+    crate::kernel_test!(counter);
+}
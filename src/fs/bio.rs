@@ -1,16 +1,40 @@
 //! buffer cache layer
+//!
+//! A fixed pool of NBUF buffers, each caching one block. Which block a buffer holds,
+//! its reference count and its place in the LRU list are under the cache's spinlock,
+//! its contents are under a sleep lock of its own, held across the disk I/O.
+//! bread() returns the buffer locked in a BufGuard, dropping it releases the buffer,
+//! and a buffer no one refers to is recycled least recently used first.
+//!
+//! Writes go through to the disk, bwrite() returns once the device has taken the block,
+//! which may still sit in the host's cache. bflush() is the write barrier to make it durable,
+//! bwrite_fua() writes one block through, for a block that must not be reordered,
+//! e.g., the log's commit block, which is to be written between two flushes:
+//! the logged blocks stable before it, and it stable before the blocks are installed,
+//! see log.rs. The log pins the blocks it has yet to install, bpin(), so they stay cached.
+//!
+//! The metadata blocks, the super block, the log header and the inode blocks, carry a CRC-32,
+//! checked by bread() and recomputed by bwrite(), see verify() in fs/mod.rs.
+//! Once one does not match, the file system is read-only, and bwrite() fails.
 
+use core::mem::ManuallyDrop;
+use core::ops::{Deref, DerefMut, Drop};
+use core::ptr;
+
+use crate::sleeplock::SleepLockGuard;
 use crate::spinlock::SpinLock;
 use crate::driver::virtio;
 
 use super::NBUF;
-use super::Buf;
+use super::{Buf, BSIZE};
+use super::{read_only, seal, verify};
 
 static mut BCACHE: Bcache = Bcache::new();
 
 struct Bcache {
     lock: SpinLock<()>,
     bufs: [Buf; NBUF],
+    /// head of the LRU list, head.next is the most recently used
     head: Buf,
 }
 
@@ -18,7 +42,7 @@ impl Bcache {
     const fn new() -> Self {
         Self {
             lock: SpinLock::new((), "bcache"),
-            bufs: [Buf::new(); NBUF],
+            bufs: [const { Buf::new() }; NBUF],
             head: Buf::new(),
         }
     }
@@ -26,75 +50,187 @@ impl Bcache {
 
 pub unsafe fn binit() {
     // Create linked list of buffers
-    // BCACHE.head.prev = Some(&mut BCACHE.head as *mut Buf);
-    // BCACHE.head.next = Some(&mut BCACHE.head as *mut Buf);
-    // for b in BCACHE.bufs.iter_mut() {
-    //     b.next = BCACHE.head.next.clone();
-    //     b.prev = Some(&mut BCACHE.head as *mut Buf);
-    //     let ori_next = BCACHE.head.next.replace(b as *mut Buf).unwrap();
-    //     (*ori_next).prev = Some(b as *mut Buf);
-    // }
+    let head = ptr::addr_of_mut!(BCACHE.head);
+    (*head).prev = head;
+    (*head).next = head;
+    for b in BCACHE.bufs.iter_mut() {
+        push_front(head, b);
+    }
+}
+
+// put b right after head, the cache's lock held.
+unsafe fn push_front(head: *mut Buf, b: *mut Buf) {
+    (*b).next = (*head).next;
+    (*b).prev = head;
+    (*(*head).next).prev = b;
+    (*head).next = b;
+}
+
+// take b out of the list, the cache's lock held.
+unsafe fn unlink(b: *mut Buf) {
+    (*(*b).next).prev = (*b).prev;
+    (*(*b).prev).next = (*b).next;
 }
 
 /// Look through buffer cache for block on device dev.
-/// If not found, allocate a buffer.
-/// In either case, return locked buffer.
-/// LTODO - just loop the bufs to find empty(refcnt=0) buffer
+/// If not found, recycle the least recently used unreferenced buffer.
+/// In either case, return the buffer, not locked yet,
+/// its sleep lock is not to be taken holding the cache's spinlock.
 unsafe fn bget(dev: u32, blockno: u32) -> &'static Buf {
     let bcache = BCACHE.lock.lock();
+    let head = ptr::addr_of_mut!(BCACHE.head);
 
-    // find exist buffer first
-    for b in BCACHE.bufs.iter_mut() {
-        if b.refcnt > 0 && b.dev == dev && b.blockno == blockno {
-            b.refcnt += 1;
+    // Is the block already cached?
+    let mut b = (*head).next;
+    while b != head {
+        if (*b).dev == dev && (*b).blockno == blockno {
+            (*b).refcnt += 1;
             drop(bcache);
-            return b
+            return &*b;
         }
+        b = (*b).next;
     }
 
-    // find empty buffer then
-    for b in BCACHE.bufs.iter_mut() {
-        if b.refcnt == 0 {
-            b.refcnt += 1;
-            b.dev = dev;
-            b.blockno = blockno;
-            b.valid.set(false);
+    // Not cached, recycle the least recently used unused buffer.
+    let mut b = (*head).prev;
+    while b != head {
+        if (*b).refcnt == 0 {
+            (*b).dev = dev;
+            (*b).blockno = blockno;
+            (*b).valid.set(false);
+            (*b).refcnt = 1;
             drop(bcache);
-            return b
+            return &*b;
         }
+        b = (*b).prev;
     }
 
-    panic!("bget: could not find empty buffer")
+    panic!("bget: no buffers")
 }
 
+/// Release b, the caller having unlocked it,
+/// and move it to the head of the LRU list once no one refers to it
+unsafe fn brelse(b: &Buf) {
+    let bcache = BCACHE.lock.lock();
+    let b = b as *const Buf as *mut Buf;
+    (*b).refcnt -= 1;
+    if (*b).refcnt == 0 {
+        unlink(b);
+        push_front(ptr::addr_of_mut!(BCACHE.head), b);
+    }
+    drop(bcache);
+}
 
-pub fn bread(dev: u32, blockno: u32) -> &'static Buf {
+/// A locked buffer, its contents are the block's,
+/// dropping it unlocks and releases the buffer
+pub struct BufGuard {
+    buf: &'static Buf,
+    data: ManuallyDrop<SleepLockGuard<'static, [u8; BSIZE]>>,
+}
+
+impl BufGuard {
+    pub fn dev(&self) -> u32 {
+        self.buf.dev
+    }
+
+    pub fn blockno(&self) -> u32 {
+        self.buf.blockno
+    }
+}
+
+impl Deref for BufGuard {
+    type Target = [u8; BSIZE];
+    fn deref(&self) -> &[u8; BSIZE] {
+        &self.data
+    }
+}
+
+impl DerefMut for BufGuard {
+    fn deref_mut(&mut self) -> &mut [u8; BSIZE] {
+        &mut self.data
+    }
+}
+
+impl Drop for BufGuard {
+    /// Unlock the buffer first, then release it, as xv6's brelse
+    fn drop(&mut self) {
+        unsafe {
+            ManuallyDrop::drop(&mut self.data);
+            brelse(self.buf);
+        }
+    }
+}
+
+/// Keep b cached after it is released, until bunpin()
+pub fn bpin(b: &BufGuard) {
+    let bcache = unsafe {BCACHE.lock.lock()};
+    unsafe { (*(b.buf as *const Buf as *mut Buf)).refcnt += 1 };
+    drop(bcache);
+}
+
+pub fn bunpin(b: &BufGuard) {
+    let bcache = unsafe {BCACHE.lock.lock()};
+    unsafe { (*(b.buf as *const Buf as *mut Buf)).refcnt -= 1 };
+    drop(bcache);
+}
+
+/// Return a locked buffer with the contents of the indicated block.
+pub fn bread(dev: u32, blockno: u32) -> BufGuard {
+    ftrace!();
     let b = unsafe {bget(dev, blockno)};
+    let mut bp = BufGuard { buf: b, data: ManuallyDrop::new(b.data.lock()) };
     if !b.valid.get() {
-        unsafe {virtio::disk_rw(b, false)};
+        if let Err(err) = unsafe {virtio::disk_rw(blockno, &mut bp, false)} {
+            panic!("bread: block {}: {}", blockno, err);
+        }
         b.valid.set(true);
+        verify(dev, blockno, &bp);
     }
-    b
-}
-
-/// Release a ~locked~ buffer
-/// ~Move to the head of the MRU list~
-pub fn brelse(dev: u32, blockno: u32) {
-    let _lock = unsafe {BCACHE.lock.lock()};
-
-    // loop through the bcache bufs to
-    // find current buf to get its mut reference
-    let mut found: bool = false;
-    for b in unsafe {BCACHE.bufs.iter_mut()} {
-        if b.dev == dev && b.blockno == blockno {
-            found = true;
-            b.refcnt -= 1;
-            break;
-        }
+    bp
+}
+
+/// Write b's contents to the disk.
+pub fn bwrite(b: &mut BufGuard) -> Result<(), &'static str> {
+    ftrace!();
+    if read_only() {
+        return Err("read-only file system");
     }
-    
-    if !found {
-        panic!("brelse: not found buf(dev={}, blockno={})", dev, blockno);
+    let (dev, blockno) = (b.dev(), b.blockno());
+    seal(dev, blockno, b);
+    unsafe {virtio::disk_rw(blockno, b, true)}
+}
+
+/// Write b's contents to the disk and make them durable, forced unit access.
+/// virtio-blk has no FUA flag, so this is a write and a flush.
+pub fn bwrite_fua(b: &mut BufGuard) -> Result<(), &'static str> {
+    bwrite(b)?;
+    bflush()
+}
+
+/// Make every write completed so far durable, a write barrier.
+pub fn bflush() -> Result<(), &'static str> {
+    ftrace!();
+    unsafe {virtio::disk_flush()}
+}
+
+#[cfg(feature = "unit_test")]
+pub mod tests {
+    use super::*;
+
+    /// The LRU list holds every buffer once, linked both ways
+    pub fn lru_list() {
+        let bcache = unsafe { BCACHE.lock.lock() };
+        let head = unsafe { ptr::addr_of_mut!(BCACHE.head) };
+        let mut n = 0;
+        let mut b = unsafe { (*head).next };
+        while b != head {
+            assert_eq!(unsafe { (*(*b).next).prev }, b);
+            n += 1;
+            assert!(n <= NBUF);
+            b = unsafe { (*b).next };
+        }
+        assert_eq!(n, NBUF);
+        drop(bcache);
     }
-    drop(_lock);
+    crate::kernel_test!(lru_list);
 }
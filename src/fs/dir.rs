@@ -1,22 +1,403 @@
-use super::Inode;
-use super::{DIRSIZ, ROOTDEV, ROOTINO};
-use super::iget;
+//! Directories and path names
+//!
+//! A directory is a file of Dirents, an inum of 0 is a free one.
+//! namei() walks a path from the process's root or working directory,
+//! locking one directory at a time, nameiparent() stops at the last element's directory.
+//! create() makes a new file, directory or device at a path,
+//! link() another name for a file, unlink() takes a name off.
+//! Walking into /host leads into the host's directory mounted there, see host.rs,
+//! where directories are looked up on the host, and nothing is made or taken off.
 
-pub fn namei(path: &[u8]) -> &Inode {
+use core::mem;
+use core::ptr;
+
+use crate::process::{my_cwd, my_root};
+
+use super::host;
+use super::inode::{ialloc, idup, ilock, iput, InodeGuard};
+use super::log::{begin_op, Op};
+use super::{iget, Inode};
+use super::{root_dev, DIRSIZ, HOSTDEV, ROOTINO, T_DEVICE, T_DIR, T_FIFO, T_FILE};
+
+/// On-disk directory entry
+#[repr(C)]
+pub struct Dirent {
+    inum: u16,
+    name: [u8; DIRSIZ],
+}
+
+pub const DIRENT_SIZE: usize = mem::size_of::<Dirent>();
+
+impl Dirent {
+    /// The entry of name, cut to DIRSIZ
+    pub fn new(inum: u16, name: &[u8]) -> Self {
+        let mut de = Self { inum, name: [0; DIRSIZ] };
+        let len = name.len().min(DIRSIZ);
+        de.name[..len].copy_from_slice(&name[..len]);
+        de
+    }
+
+    fn from_bytes(bytes: &[u8; DIRENT_SIZE]) -> Self {
+        let mut name = [0u8; DIRSIZ];
+        name.copy_from_slice(&bytes[2..]);
+        Self { inum: u16::from_le_bytes([bytes[0], bytes[1]]), name }
+    }
+
+    pub fn to_bytes(&self) -> [u8; DIRENT_SIZE] {
+        let mut bytes = [0u8; DIRENT_SIZE];
+        bytes[..2].copy_from_slice(&self.inum.to_le_bytes());
+        bytes[2..].copy_from_slice(&self.name);
+        bytes
+    }
+}
+
+/// Whether name, cut to DIRSIZ, is the nul-padded entry name
+fn namecmp(name: &[u8], entry: &[u8; DIRSIZ]) -> bool {
+    let name = &name[..name.len().min(DIRSIZ)];
+    entry.starts_with(name) && entry[name.len()..].iter().all(|c| *c == 0)
+}
+
+/// Look for name in directory dp, return its inode, referenced but unlocked,
+/// and the entry's offset
+pub fn dirlookup(dp: &mut InodeGuard, name: &[u8]) -> Result<Option<(&'static Inode, u32)>, &'static str> {
+    if !dp.is_dir() {
+        return Err("dirlookup: not a directory");
+    }
+    if dp.dev() == HOSTDEV {
+        return host::lookup(dp, name).map(|ip| ip.map(|ip| (ip, 0)));
+    }
+    let mut bytes = [0u8; DIRENT_SIZE];
+    for off in (0..dp.size).step_by(DIRENT_SIZE) {
+        if dp.readi(off, &mut bytes)? != DIRENT_SIZE {
+            return Err("dirlookup: short read");
+        }
+        let de = Dirent::from_bytes(&bytes);
+        if de.inum != 0 && namecmp(name, &de.name) {
+            return Ok(Some((iget(dp.dev(), de.inum as u32), off)));
+        }
+    }
+    Ok(None)
+}
+
+/// Write a new entry (name, inum) into directory dp
+pub fn dirlink(op: &Op, dp: &mut InodeGuard, name: &[u8], inum: u32) -> Result<(), &'static str> {
+    if name.is_empty() || name.contains(&b'/') {
+        return Err("dirlink: bad name");
+    }
+    if dp.dev() == HOSTDEV {
+        return Err("dirlink: the host's directories are only read");
+    }
+    if let Some((ip, _)) = dirlookup(dp, name)? {
+        iput(op, ip);
+        return Err("dirlink: name exists");
+    }
+
+    // an empty entry, or one past the end
+    let mut bytes = [0u8; DIRENT_SIZE];
+    let mut off = 0;
+    while off < dp.size {
+        if dp.readi(off, &mut bytes)? != DIRENT_SIZE {
+            return Err("dirlink: short read");
+        }
+        if Dirent::from_bytes(&bytes).inum == 0 {
+            break;
+        }
+        off += DIRENT_SIZE as u32;
+    }
+
+    if dp.writei(op, off, &Dirent::new(inum as u16, name).to_bytes())? != DIRENT_SIZE {
+        return Err("dirlink: short write");
+    }
+    Ok(())
+}
+
+/// Split the next element off path, return it and the rest, both without leading slashes,
+/// None if there is no element left
+/// e.g., "a/bb/c" is ("a", "bb/c"), "///a//bb" is ("a", "bb"), "" and "/" are None
+fn skipelem(path: &[u8]) -> Option<(&[u8], &[u8])> {
+    let start = path.iter().position(|c| *c != b'/')?;
+    let path = &path[start..];
+    let end = path.iter().position(|c| *c == b'/').unwrap_or(path.len());
+    let (elem, rest) = path.split_at(end);
+    let rest = &rest[rest.iter().position(|c| *c != b'/').unwrap_or(rest.len())..];
+    Some((elem, rest))
+}
+
+/// The inode at path, referenced but unlocked
+pub fn namei(op: &Op, path: &[u8]) -> Result<&'static Inode, &'static str> {
+    ftrace!();
     let mut name: [u8; DIRSIZ] = [0; DIRSIZ];
-    namex(path, false, &mut name)
+    namex(op, path, false, &mut name)
 }
 
-// TODO
-fn namex(mut path: &[u8], nameparent: bool, name: &mut [u8]) -> &'static Inode {
-    if path[0] != b'/' {
-        panic!("namex: path={:?}, not start as root", path);
+/// The directory holding the last element of path, referenced but unlocked,
+/// the element copied into name
+pub fn nameiparent(op: &Op, path: &[u8], name: &mut [u8; DIRSIZ]) -> Result<&'static Inode, &'static str> {
+    namex(op, path, true, name)
+}
+
+/// Walk path, up to its nul if it has one, the Op for the iput of each directory on the way
+fn namex(op: &Op, path: &[u8], nameparent: bool, name: &mut [u8; DIRSIZ]) -> Result<&'static Inode, &'static str> {
+    let mut path = &path[..path.iter().position(|c| *c == 0).unwrap_or(path.len())];
+    if path.is_empty() {
+        return Err("namei: empty path");
+    }
+
+    // absolute paths start at the process's root, see sys_chroot,
+    // and ".." in it stays there
+    let root = my_root();
+    let mut ip = match (path[0], root, my_cwd()) {
+        (b'/', Some(root), _) => idup(root),
+        (b'/', None, _) | (_, _, None) => iget(root_dev(), ROOTINO),
+        (_, _, Some(cwd)) => idup(cwd),
+    };
+
+    while let Some((elem, rest)) = skipelem(path) {
+        path = rest;
+        name.fill(0);
+        let len = elem.len().min(DIRSIZ);
+        name[..len].copy_from_slice(&elem[..len]);
+
+        let mut dp = ilock(ip);
+        if !dp.is_dir() {
+            drop(dp);
+            iput(op, ip);
+            return Err("namei: not a directory");
+        }
+        if nameparent && path.is_empty() {
+            // stop one level early
+            return Ok(ip);
+        }
+        if elem == b".." && root.is_some_and(|root| ptr::eq(root, ip)) {
+            continue;
+        }
+        // ".." of the root of the host's directory is that of /host
+        if elem == b".." {
+            if let Some(covered) = host::covered(ip) {
+                drop(dp);
+                iput(op, ip);
+                ip = covered;
+                dp = ilock(ip);
+            }
+        }
+        let next = dirlookup(&mut dp, elem);
+        drop(dp);
+        iput(op, ip);
+        ip = match next? {
+            Some((next, _)) => host::cross(op, next),
+            None => return Err("namei: no such file or directory"),
+        };
     }
     if nameparent {
-        panic!("namex: nameparent not supported yet");
+        iput(op, ip);
+        return Err("nameiparent: no parent");
+    }
+    Ok(ip)
+}
+
+/// Make an inode of type itype at path, and return it locked,
+/// for T_FILE an existing file, device or named pipe is returned as it is
+pub fn create(op: &Op, path: &[u8], itype: u16, major: u16, minor: u16) -> Result<InodeGuard, &'static str> {
+    let mut name: [u8; DIRSIZ] = [0; DIRSIZ];
+    let dp = nameiparent(op, path, &mut name)?;
+    let name = &name[..name.iter().position(|c| *c == 0).unwrap_or(DIRSIZ)];
+    let mut dguard = ilock(dp);
+    if dguard.dev() == HOSTDEV {
+        drop(dguard);
+        iput(op, dp);
+        return Err("create: the host's directories are only read");
+    }
+
+    let found = dirlookup(&mut dguard, name);
+    if let Ok(Some((ip, _))) = found {
+        drop(dguard);
+        iput(op, dp);
+        let guard = ilock(ip);
+        if itype == T_FILE && matches!(guard.itype, T_FILE | T_DEVICE | T_FIFO) {
+            return Ok(guard);
+        }
+        drop(guard);
+        iput(op, ip);
+        return Err("create: file exists");
+    }
+    let allocated = found.and_then(|_| ialloc(op, dguard.dev(), itype));
+    let ip = match allocated {
+        Ok(ip) => ip,
+        Err(err) => {
+            drop(dguard);
+            iput(op, dp);
+            return Err(err);
+        }
+    };
+
+    let mut guard = ilock(ip);
+    guard.major = major;
+    guard.minor = minor;
+    guard.nlink = 1;
+    guard.iupdate(op);
+    let mut linked = Ok(());
+    if itype == T_DIR {
+        // "." and "..", not counting the link to itself, to keep from a cycle
+        let inum = guard.inum();
+        linked = dirlink(op, &mut guard, b".", inum)
+            .and_then(|()| dirlink(op, &mut guard, b"..", dp.inum));
+    }
+    linked = linked.and_then(|()| dirlink(op, &mut dguard, name, ip.inum));
+    if linked.is_ok() && itype == T_DIR {
+        // the new directory's ".."
+        dguard.nlink += 1;
+        dguard.iupdate(op);
+    }
+    drop(dguard);
+    iput(op, dp);
+    match linked {
+        Ok(()) => Ok(guard),
+        Err(err) => {
+            // freed by the iput, with no link to it
+            guard.nlink = 0;
+            guard.iupdate(op);
+            drop(guard);
+            iput(op, ip);
+            Err(err)
+        }
+    }
+}
+
+/// Make new another name of the file at old, on the same device, not of a directory
+pub fn link(old: &[u8], new: &[u8]) -> Result<(), &'static str> {
+    let op = begin_op();
+    let ip = namei(&op, old)?;
+    let mut guard = ilock(ip);
+    if guard.is_dir() {
+        drop(guard);
+        iput(&op, ip);
+        return Err("link: is a directory");
+    }
+    if guard.dev() == HOSTDEV {
+        drop(guard);
+        iput(&op, ip);
+        return Err("link: the host's files are only read");
+    }
+    // counted first, a crash in between leaves a link too many, not a dangling name
+    guard.nlink += 1;
+    guard.iupdate(&op);
+    let dev = guard.dev();
+    drop(guard);
+
+    let mut name: [u8; DIRSIZ] = [0; DIRSIZ];
+    let linked = nameiparent(&op, new, &mut name).and_then(|dp| {
+        let name = &name[..name.iter().position(|c| *c == 0).unwrap_or(DIRSIZ)];
+        let mut dguard = ilock(dp);
+        let linked = match dguard.dev() == dev {
+            true => dirlink(&op, &mut dguard, name, ip.inum),
+            false => Err("link: across devices"),
+        };
+        drop(dguard);
+        iput(&op, dp);
+        linked
+    });
+    if linked.is_err() {
+        let mut guard = ilock(ip);
+        guard.nlink -= 1;
+        guard.iupdate(&op);
     }
+    iput(&op, ip);
+    linked
+}
+
+/// Whether directory dp has no entries but "." and ".."
+fn is_dir_empty(dp: &mut InodeGuard) -> Result<bool, &'static str> {
+    let mut bytes = [0u8; DIRENT_SIZE];
+    for off in (2 * DIRENT_SIZE as u32..dp.size).step_by(DIRENT_SIZE) {
+        if dp.readi(off, &mut bytes)? != DIRENT_SIZE {
+            return Err("is_dir_empty: short read");
+        }
+        if Dirent::from_bytes(&bytes).inum != 0 {
+            return Ok(false);
+        }
+    }
+    Ok(true)
+}
+
+/// Take the name at path off its directory, the last link to a file not open frees it,
+/// a directory must be empty
+pub fn unlink(path: &[u8]) -> Result<(), &'static str> {
+    let op = begin_op();
+    let mut name: [u8; DIRSIZ] = [0; DIRSIZ];
+    let dp = nameiparent(&op, path, &mut name)?;
+    let name = &name[..name.iter().position(|c| *c == 0).unwrap_or(DIRSIZ)];
+    let mut dguard = ilock(dp);
+    let unlinked = unlink_at(&op, &mut dguard, name);
+    drop(dguard);
+    iput(&op, dp);
+    unlinked
+}
+
+/// Take name off directory dp, locked
+fn unlink_at(op: &Op, dp: &mut InodeGuard, name: &[u8]) -> Result<(), &'static str> {
+    if name == b"." || name == b".." {
+        return Err("unlink: . or ..");
+    }
+    if dp.dev() == HOSTDEV {
+        return Err("unlink: the host's directories are only read");
+    }
+    let (ip, off) = dirlookup(dp, name)?.ok_or("unlink: no such file or directory")?;
+    if host::is_mount_point(ip) {
+        iput(op, ip);
+        return Err("unlink: a mount point");
+    }
+    let mut guard = ilock(ip);
+    if guard.nlink < 1 {
+        panic!("unlink: nlink < 1");
+    }
+    let empty = match guard.is_dir() {
+        true => is_dir_empty(&mut guard),
+        false => Ok(true),
+    };
+    let erased = match empty {
+        Ok(true) => dp.writei(op, off, &[0; DIRENT_SIZE]).and_then(|n| match n {
+            DIRENT_SIZE => Ok(()),
+            _ => Err("unlink: short write"),
+        }),
+        Ok(false) => Err("unlink: directory not empty"),
+        Err(err) => Err(err),
+    };
+    if erased.is_ok() {
+        if guard.is_dir() {
+            // its ".."
+            dp.nlink -= 1;
+            dp.iupdate(op);
+        }
+        guard.nlink -= 1;
+        guard.iupdate(op);
+    }
+    drop(guard);
+    iput(op, ip);
+    erased
+}
 
-    let ip = iget(ROOTDEV, ROOTINO);
-    
-    ip
-}
\ No newline at end of file
+#[cfg(feature = "unit_test")]
+pub mod tests {
+    use super::*;
+
+    pub fn path_elems() {
+        assert_eq!(skipelem(b"a/bb/c"), Some((&b"a"[..], &b"bb/c"[..])));
+        assert_eq!(skipelem(b"///a//bb"), Some((&b"a"[..], &b"bb"[..])));
+        assert_eq!(skipelem(b"a/"), Some((&b"a"[..], &b""[..])));
+        assert_eq!(skipelem(b""), None);
+        assert_eq!(skipelem(b"/"), None);
+
+        let mut entry = [0u8; DIRSIZ];
+        entry[..4].copy_from_slice(b"init");
+        assert!(namecmp(b"init", &entry));
+        assert!(!namecmp(b"ini", &entry));
+        assert!(!namecmp(b"initx", &entry));
+        assert!(namecmp(b"abcdefghijklmnop", b"abcdefghijklmn"));
+        assert_eq!(DIRENT_SIZE, 16);
+        let bytes = Dirent::new(3, b"abcdefghijklmnop").to_bytes();
+        assert_eq!(bytes[..2], [3, 0]);
+        assert_eq!(&bytes[2..], b"abcdefghijklmn");
+        assert_eq!(Dirent::new(1, b"a").to_bytes()[3..], [0; DIRSIZ - 1]);
+    }
+    crate::kernel_test!(path_elems);
+}
@@ -0,0 +1,719 @@
+//! Open files, each one an entry of the file table, shared by the descriptors that refer to it
+//!
+//! A File is an inode read and written at its own offset, an end of a pipe, see pipe.rs,
+//! that of a named pipe along with its inode, a local socket, see socket.rs,
+//! an opening of a message queue, see mqueue.rs, an event counter, see eventfd.rs,
+//! a timer descriptor, see timerfd.rs, or a device, whose major number picks its functions
+//! in DEVSW, e.g., the console's, the minor number is theirs to interpret.
+//! A process's descriptors index its ofile array, see Proc::fdalloc.
+//! The last fileclose() of a File puts its inode, or closes its end of the pipe, or both.
+//! A File opened with O_NONBLOCK, or set so by fcntl, fails a read or write on a pipe, a socket
+//! or the console with "would block" where it would sleep, or returns what it wrote so far.
+
+use core::cell::Cell;
+use core::ptr;
+use core::sync::atomic::{AtomicBool, Ordering};
+
+use crate::console;
+use crate::driver::fb;
+use crate::eventfd;
+use crate::mm::{Box, PageAligned};
+use crate::mqueue;
+use crate::random;
+use crate::socket;
+use crate::timerfd;
+use crate::consts::{NDEV, NFILE};
+use crate::spinlock::SpinLock;
+
+use super::dir::{create, namei};
+use super::inode::{iget_used, ilock, iput, InodeGuard};
+use super::log::{begin_op, Op};
+use super::pipe::{fifoattach, fifowait, pipeclose, piperead, pipewrite, Pipe};
+use super::{Inode, BSIZE, HOSTDEV, MAXOPBLOCKS, T_DEVICE, T_DIR, T_FIFO, T_FILE};
+
+/// open() modes, as in user/src/fcntl.rs
+pub const O_RDONLY: usize = 0x000;
+pub const O_WRONLY: usize = 0x001;
+pub const O_RDWR: usize = 0x002;
+pub const O_CREATE: usize = 0x200;
+pub const O_TRUNC: usize = 0x400;
+pub const O_NONBLOCK: usize = 0x800;
+
+/// The console's major number
+pub const CONSOLE: u16 = 1;
+/// The framebuffer's, see driver/fb.rs
+pub const FB: u16 = 2;
+/// The random device's, see random.rs
+pub const RANDOM: u16 = 3;
+
+/// A device's functions, taking its minor number
+#[derive(Clone, Copy)]
+struct Devsw {
+    /// failing with "would block" rather than sleeping if its last argument is true
+    read: fn(u16, &mut [u8], bool) -> Result<usize, &'static str>,
+    write: fn(u16, &[u8]) -> Result<usize, &'static str>,
+    /// the size of the argument of an ioctl request, which ioctl then carries out on it
+    ioctl_size: fn(usize) -> Result<usize, &'static str>,
+    ioctl: fn(u16, usize, &mut [u8]) -> Result<(), &'static str>,
+}
+
+static DEVSW: [Option<Devsw>; NDEV] = {
+    let mut devsw = [None; NDEV];
+    devsw[CONSOLE as usize] = Some(Devsw {
+        read: console::consoleread,
+        write: console::consolewrite,
+        ioctl_size: console::termios::ioctl_size,
+        ioctl: console::consoleioctl,
+    });
+    devsw[FB as usize] = Some(Devsw {
+        read: fb::fbread,
+        write: fb::fbwrite,
+        ioctl_size: fb::ioctl_size,
+        ioctl: fb::fbioctl,
+    });
+    devsw[RANDOM as usize] = Some(Devsw {
+        read: random::randread,
+        write: random::randwrite,
+        ioctl_size: random::ioctl_size,
+        ioctl: random::randioctl,
+    });
+    devsw
+};
+
+/// What fstat() returns, as in user/src/stat.rs
+#[repr(C)]
+pub struct Stat {
+    pub dev: i32,
+    pub ino: u32,
+    pub typ: i16,
+    pub nlink: i16,
+    pub size: u64,
+}
+
+#[derive(Clone, Copy)]
+pub enum FileType {
+    None,
+    Pipe { pipe: &'static Pipe },
+    Inode { ip: &'static Inode },
+    Device { ip: &'static Inode, major: u16, minor: u16 },
+    /// the pipe is the one of the inode while it is open, see InodeData
+    Fifo { ip: &'static Inode, pipe: &'static Pipe },
+    /// by its index in the sockets' table, read and written by recv and send
+    Socket { id: usize },
+    /// by its descriptor in mqueue.rs, not read or written, only sent to and received from
+    Mqueue { mqd: usize },
+    /// by its index in eventfd.rs, read and written, i.e., added to, 8 bytes at a time
+    Eventfd { id: usize },
+    /// by its index in timerfd.rs, read 8 bytes at a time, set by timerfd_settime
+    Timerfd { id: usize },
+}
+
+pub struct File {
+    ftype: FileType,
+    refcnt: usize,
+    readable: bool,
+    writable: bool,
+    /// of an inode, changed with it locked
+    off: Cell<u32>,
+    /// O_NONBLOCK
+    nonblock: AtomicBool,
+}
+
+impl File {
+    const fn new() -> Self {
+        Self {
+            ftype: FileType::None,
+            refcnt: 0,
+            readable: false,
+            writable: false,
+            off: Cell::new(0),
+            nonblock: AtomicBool::new(false),
+        }
+    }
+
+    /// Whether it is read and written at an offset, a pipe or a device
+    /// returns what it has at once, writes what it can take
+    pub fn is_inode(&self) -> bool {
+        matches!(self.ftype, FileType::Inode { .. })
+    }
+
+    /// The offset of the inode it is, for a checkpoint, see process/coredump.rs
+    pub fn offset(&self) -> Option<u32> {
+        match self.ftype {
+            FileType::Inode { ip } => {
+                let guard = ilock(ip);
+                let off = self.off.get();
+                drop(guard);
+                Some(off)
+            }
+            _ => None,
+        }
+    }
+
+    /// Move the offset of the inode it is to off, nothing for other files, on a restore
+    pub fn set_offset(&self, off: u32) {
+        if let FileType::Inode { ip } = self.ftype {
+            let guard = ilock(ip);
+            self.off.set(off);
+            drop(guard);
+        }
+    }
+
+    /// The number of the inode or device of the root file system it is,
+    /// for a checkpoint to open it again, see reopen()
+    pub fn inum(&self) -> Option<u32> {
+        match self.ftype {
+            FileType::Inode { ip } | FileType::Device { ip, .. } if ip.dev != HOSTDEV => Some(ip.inum),
+            _ => None,
+        }
+    }
+
+    /// The major number of the device it is
+    pub fn major(&self) -> Option<u16> {
+        match self.ftype {
+            FileType::Device { major, .. } => Some(major),
+            _ => None,
+        }
+    }
+
+    pub fn readable(&self) -> bool {
+        self.readable
+    }
+
+    pub fn writable(&self) -> bool {
+        self.writable
+    }
+
+    pub fn nonblock(&self) -> bool {
+        self.nonblock.load(Ordering::Relaxed)
+    }
+
+    /// Set or clear O_NONBLOCK, for every descriptor of it, see sys_fcntl
+    pub fn set_nonblock(&self, on: bool) {
+        self.nonblock.store(on, Ordering::Relaxed);
+    }
+
+    /// The index of the socket it is, for the socket syscalls
+    pub fn socket(&self) -> Result<usize, &'static str> {
+        match self.ftype {
+            FileType::Socket { id } => Ok(id),
+            _ => Err("not a socket"),
+        }
+    }
+
+    /// The index of the timer descriptor it is, for timerfd_settime
+    pub fn timerfd(&self) -> Result<usize, &'static str> {
+        match self.ftype {
+            FileType::Timerfd { id } => Ok(id),
+            _ => Err("not a timer descriptor"),
+        }
+    }
+
+    /// The descriptor of the message queue it is, for the mq syscalls
+    pub fn mqueue(&self) -> Result<usize, &'static str> {
+        match self.ftype {
+            FileType::Mqueue { mqd } => Ok(mqd),
+            _ => Err("not a message queue"),
+        }
+    }
+}
+
+static mut FTABLE: Ftable = Ftable::new();
+
+struct Ftable {
+    lock: SpinLock<()>,
+    files: [File; NFILE],
+}
+
+impl Ftable {
+    const fn new() -> Self {
+        Self {
+            lock: SpinLock::new((), "ftable"),
+            files: [const { File::new() }; NFILE],
+        }
+    }
+}
+
+/// Take a free entry of the file table, with one reference
+pub fn filealloc(ftype: FileType, readable: bool, writable: bool) -> Result<&'static File, &'static str> {
+    let ftable = unsafe { FTABLE.lock.lock() };
+    for f in unsafe { FTABLE.files.iter_mut() } {
+        if f.refcnt == 0 {
+            f.refcnt = 1;
+            f.readable = readable;
+            f.writable = writable;
+            f.off.set(0);
+            f.nonblock.store(false, Ordering::Relaxed);
+            f.ftype = ftype;
+            drop(ftable);
+            return Ok(f);
+        }
+    }
+    drop(ftable);
+    Err("filealloc: file table full")
+}
+
+/// Count another reference to f, for a dup()ed descriptor
+pub fn filedup(f: &'static File) -> &'static File {
+    let ftable = unsafe { FTABLE.lock.lock() };
+    if f.refcnt < 1 {
+        panic!("filedup: not open");
+    }
+    unsafe { (*(f as *const File as *mut File)).refcnt += 1 };
+    drop(ftable);
+    f
+}
+
+/// Drop a reference to f, the last one closes it
+pub fn fileclose(f: &'static File) {
+    let ftable = unsafe { FTABLE.lock.lock() };
+    let fm = f as *const File as *mut File;
+    if f.refcnt < 1 {
+        panic!("fileclose: not open");
+    }
+    unsafe { (*fm).refcnt -= 1 };
+    if f.refcnt > 0 {
+        drop(ftable);
+        return;
+    }
+    let (ftype, readable, writable) = (f.ftype, f.readable, f.writable);
+    unsafe { (*fm).ftype = FileType::None };
+    drop(ftable);
+
+    // not holding the table's lock, iput may sleep for the disk
+    match ftype {
+        FileType::Pipe { pipe } => {
+            pipeclose(pipe, readable, writable);
+        }
+        FileType::Inode { ip } | FileType::Device { ip, .. } => iput(&begin_op(), ip),
+        FileType::Fifo { ip, pipe } => {
+            let op = begin_op();
+            // with the inode locked, the next open makes a new pipe
+            let mut guard = ilock(ip);
+            if pipeclose(pipe, readable, writable) {
+                guard.fifo = None;
+            }
+            drop(guard);
+            iput(&op, ip);
+        }
+        FileType::Socket { id } => {
+            if let Err(str) = socket::close(id) {
+                println!("fileclose: {}", str);
+            }
+        }
+        FileType::Mqueue { mqd } => {
+            if let Err(str) = mqueue::mq_close(mqd) {
+                println!("fileclose: {}", str);
+            }
+        }
+        FileType::Eventfd { id } => {
+            if let Err(str) = eventfd::close(id) {
+                println!("fileclose: {}", str);
+            }
+        }
+        FileType::Timerfd { id } => {
+            if let Err(str) = timerfd::close(id) {
+                println!("fileclose: {}", str);
+            }
+        }
+        FileType::None => {}
+    }
+}
+
+/// A File for socket id, readable and writable, the last fileclose() closes it
+pub fn sockalloc(id: usize) -> Result<&'static File, &'static str> {
+    filealloc(FileType::Socket { id }, true, true)
+}
+
+/// A File for message queue descriptor mqd, the last fileclose() closes it
+pub fn mqalloc(mqd: usize) -> Result<&'static File, &'static str> {
+    filealloc(FileType::Mqueue { mqd }, true, true)
+}
+
+/// A File for event counter id, the last fileclose() closes it
+pub fn eventalloc(id: usize) -> Result<&'static File, &'static str> {
+    filealloc(FileType::Eventfd { id }, true, true)
+}
+
+/// A File for timer descriptor id, read-only, the last fileclose() closes it
+pub fn timeralloc(id: usize) -> Result<&'static File, &'static str> {
+    filealloc(FileType::Timerfd { id }, true, false)
+}
+
+/// Open the file at path in mode omode, see O_*, creating it with O_CREATE,
+/// nonblocking with O_NONBLOCK.
+/// An end of a named pipe waits for the other end to be opened too,
+/// with O_NONBLOCK the read end does not, and the write end fails if there is no reader.
+pub fn fileopen(path: &[u8], omode: usize) -> Result<&'static File, &'static str> {
+    let readable = omode & O_WRONLY == 0;
+    let writable = omode & (O_WRONLY | O_RDWR) != 0;
+    let op = begin_op();
+    let mut guard = if omode & O_CREATE != 0 {
+        create(&op, path, T_FILE, 0, 0)?
+    } else {
+        let ip = namei(&op, path)?;
+        let guard = ilock(ip);
+        if guard.itype == T_DIR && writable {
+            drop(guard);
+            iput(&op, ip);
+            return Err("open: is a directory");
+        }
+        if guard.dev() == HOSTDEV && (writable || omode & O_TRUNC != 0) {
+            drop(guard);
+            iput(&op, ip);
+            return Err("open: the host's files are only read");
+        }
+        guard
+    };
+    let ip = guard.inode();
+    if guard.itype == T_FIFO {
+        return fifoopen(op, guard, readable, writable, omode & O_NONBLOCK != 0)
+            .inspect(|f| f.set_nonblock(omode & O_NONBLOCK != 0));
+    }
+
+    let ftype = match guard.itype {
+        T_DEVICE if device(guard.major).is_err() => {
+            drop(guard);
+            iput(&op, ip);
+            return Err("open: no such device");
+        }
+        T_DEVICE => FileType::Device { ip, major: guard.major, minor: guard.minor },
+        _ => FileType::Inode { ip },
+    };
+    if omode & O_TRUNC != 0 && guard.itype == T_FILE {
+        guard.itrunc(&op);
+    }
+    drop(guard);
+    let f = filealloc(ftype, readable, writable).inspect_err(|_| iput(&op, ip))?;
+    f.set_nonblock(omode & O_NONBLOCK != 0);
+    Ok(f)
+}
+
+/// Open the ends of the named pipe of guard, see fileopen(),
+/// waiting for the other end once the operation is over, unless nonblock
+fn fifoopen(op: Op, mut guard: InodeGuard, readable: bool, writable: bool, nonblock: bool)
+    -> Result<&'static File, &'static str>
+{
+    let ip = guard.inode();
+    let attached = fifoattach(&mut guard.fifo, readable, writable, nonblock).and_then(|(pipe, seen)| {
+        match filealloc(FileType::Fifo { ip, pipe }, readable, writable) {
+            Ok(f) => Ok((f, pipe, seen)),
+            Err(err) => {
+                if pipeclose(pipe, readable, writable) {
+                    guard.fifo = None;
+                }
+                Err(err)
+            }
+        }
+    });
+    drop(guard);
+    let (f, pipe, seen) = match attached {
+        Ok(attached) => attached,
+        Err(err) => {
+            iput(&op, ip);
+            return Err(err);
+        }
+    };
+    drop(op);
+    if nonblock {
+        return Ok(f);
+    }
+    // the File holds the inode's reference from here
+    fifowait(pipe, readable, writable, seen).map(|()| f).inspect_err(|_| fileclose(f))
+}
+
+/// Open inode inum of the root file system again, a file or a device, on a restore,
+/// the checkpoint knows it by its number, not by a path, see File::inum().
+pub fn reopen(inum: u32, readable: bool, writable: bool) -> Result<&'static File, &'static str> {
+    let op = begin_op();
+    let guard = iget_used(&op, inum)?;
+    let ip = guard.inode();
+    let ftype = match guard.itype {
+        T_FILE => FileType::Inode { ip },
+        T_DEVICE if device(guard.major).is_ok() => FileType::Device { ip, major: guard.major, minor: guard.minor },
+        _ => {
+            drop(guard);
+            iput(&op, ip);
+            return Err("reopen: not a file or a device any more");
+        }
+    };
+    drop(guard);
+    filealloc(ftype, readable, writable).inspect_err(|_| iput(&op, ip))
+}
+
+/// Make the device file (major, minor) at path
+pub fn mknod(path: &[u8], major: u16, minor: u16) -> Result<(), &'static str> {
+    let op = begin_op();
+    let guard = create(&op, path, T_DEVICE, major, minor)?;
+    let ip = guard.inode();
+    drop(guard);
+    iput(&op, ip);
+    Ok(())
+}
+
+/// Make a named pipe at path
+pub fn mkfifo(path: &[u8]) -> Result<(), &'static str> {
+    let op = begin_op();
+    let guard = create(&op, path, T_FIFO, 0, 0)?;
+    let ip = guard.inode();
+    drop(guard);
+    iput(&op, ip);
+    Ok(())
+}
+
+/// Make an empty directory at path
+pub fn mkdir(path: &[u8]) -> Result<(), &'static str> {
+    let op = begin_op();
+    let guard = create(&op, path, T_DIR, 0, 0)?;
+    let ip = guard.inode();
+    drop(guard);
+    iput(&op, ip);
+    Ok(())
+}
+
+/// Read from f into dst, at its offset, return the bytes read, 0 at the end of the file
+pub fn fileread(f: &File, dst: &mut [u8]) -> Result<usize, &'static str> {
+    if !f.readable {
+        return Err("read: not open for reading");
+    }
+    match f.ftype {
+        FileType::Inode { ip } => {
+            let mut guard = ilock(ip);
+            let n = guard.readi(f.off.get(), dst)?;
+            f.off.set(f.off.get() + n as u32);
+            drop(guard);
+            Ok(n)
+        }
+        FileType::Pipe { pipe } | FileType::Fifo { pipe, .. } => piperead(pipe, dst, f.nonblock()),
+        FileType::Socket { id } => socket::recv(id, dst, f.nonblock()),
+        FileType::Mqueue { .. } => Err("read: a message queue, see mq_receive"),
+        FileType::Eventfd { id } => {
+            let dst = dst.get_mut(..8).ok_or("read: an event counter is 8 bytes")?;
+            dst.copy_from_slice(&eventfd::read(id)?.to_ne_bytes());
+            Ok(8)
+        }
+        FileType::Timerfd { id } => {
+            let dst = dst.get_mut(..8).ok_or("read: a timer descriptor is 8 bytes")?;
+            dst.copy_from_slice(&timerfd::read(id)?.to_ne_bytes());
+            Ok(8)
+        }
+        FileType::Device { major, minor, .. } => (device(major)?.read)(minor, dst, f.nonblock()),
+        FileType::None => panic!("fileread: not open"),
+    }
+}
+
+/// Write src to f, at its offset, return the bytes written
+pub fn filewrite(f: &File, src: &[u8]) -> Result<usize, &'static str> {
+    if !f.writable {
+        return Err("write: not open for writing");
+    }
+    match f.ftype {
+        FileType::Inode { ip } => write_inode(ip, &f.off, src),
+        FileType::Pipe { pipe } | FileType::Fifo { pipe, .. } => pipewrite(pipe, src, f.nonblock()),
+        FileType::Socket { id } => socket::send(id, src, f.nonblock()),
+        FileType::Mqueue { .. } => Err("write: a message queue, see mq_send"),
+        FileType::Eventfd { id } => {
+            let mut n = [0u8; 8];
+            n.copy_from_slice(src.get(..8).ok_or("write: an event counter is 8 bytes")?);
+            eventfd::add(id, u64::from_ne_bytes(n)).map(|()| 8)
+        }
+        FileType::Timerfd { .. } => panic!("filewrite: a timer descriptor is read-only"),
+        FileType::Device { major, minor, .. } => (device(major)?.write)(minor, src),
+        FileType::None => panic!("filewrite: not open"),
+    }
+}
+
+/// Write src to ip at off, moving off along, in as many operations as the log needs
+fn write_inode(ip: &'static Inode, off: &Cell<u32>, src: &[u8]) -> Result<usize, &'static str> {
+    // a few blocks an operation, each with its bitmap block, as well as the inode,
+    // the indirect block, and two more for a write not aligned to blocks
+    let max = ((MAXOPBLOCKS - 1 - 1 - 2) / 2) * BSIZE;
+    let mut tot = 0;
+    while tot < src.len() {
+        let n = (src.len() - tot).min(max);
+        let op = begin_op();
+        let mut guard = ilock(ip);
+        let written = guard.writei(&op, off.get(), &src[tot..tot + n]);
+        if let Ok(m) = written {
+            off.set(off.get() + m as u32);
+        }
+        drop(guard);
+        drop(op);
+        match written {
+            Ok(m) if m == n => tot += m,
+            Ok(m) => return Ok(tot + m),
+            Err(err) if tot == 0 => return Err(err),
+            Err(_) => return Ok(tot),
+        }
+    }
+    Ok(tot)
+}
+
+/// Read from the inode of f at off into dst, leaving its offset as it is,
+/// for a page of a mapping of it, see process/vma.rs
+pub fn fileread_at(f: &File, off: u32, dst: &mut [u8]) -> Result<usize, &'static str> {
+    match f.ftype {
+        FileType::Inode { ip } => ilock(ip).readi(off, dst),
+        _ => Err("not an inode"),
+    }
+}
+
+/// Write src to the inode of f at off, leaving its offset as it is,
+/// but not past the end of the file, for a page of a shared mapping of it.
+/// Return the bytes written.
+pub fn filewrite_at(f: &File, off: u32, src: &[u8]) -> Result<usize, &'static str> {
+    let ip = match f.ftype {
+        FileType::Inode { ip } => ip,
+        _ => return Err("not an inode"),
+    };
+    let size = ilock(ip).size;
+    let n = (size.saturating_sub(off) as usize).min(src.len());
+    write_inode(ip, &Cell::new(off), &src[..n])
+}
+
+/// A block on its way from one file to another, see filecopy
+struct Block([u8; BSIZE]);
+
+impl PageAligned for Block {}
+
+/// Copy up to len bytes from the inode of fin at off_in to that of fout at off_out,
+/// moving either along, or the file's own offset where it is None.
+/// The data goes a block an operation through a kernel page with both inodes locked,
+/// the lower inum first, or the one lock when both are the same inode,
+/// whose ranges must not overlap then. Return the bytes copied, short at the end of fin.
+pub fn filecopy(
+    fin: &File,
+    off_in: Option<&mut u32>,
+    fout: &File,
+    off_out: Option<&mut u32>,
+    len: usize,
+) -> Result<usize, &'static str> {
+    if !fin.readable {
+        return Err("copy: not open for reading");
+    }
+    if !fout.writable {
+        return Err("copy: not open for writing");
+    }
+    let (ipin, ipout) = match (fin.ftype, fout.ftype) {
+        (FileType::Inode { ip: ipin }, FileType::Inode { ip: ipout }) => (ipin, ipout),
+        _ => return Err("copy: not an inode"),
+    };
+    let mut rd = off_in.as_deref().copied().unwrap_or(fin.off.get());
+    let mut wr = off_out.as_deref().copied().unwrap_or(fout.off.get());
+    let len = len.min((u32::MAX - rd.max(wr)) as usize);
+    let same = ptr::eq(ipin, ipout);
+    if same && (rd as usize) < wr as usize + len && (wr as usize) < rd as usize + len {
+        return Err("copy: overlapping ranges");
+    }
+
+    let mut buf = Box::<Block>::new().ok_or("copy: out of memory")?;
+    let mut tot = 0;
+    while tot < len {
+        // up to the end of the block being read, so that each operation
+        // writes at most the two blocks under it, see write_inode
+        let n = (len - tot).min(BSIZE - rd as usize % BSIZE);
+        let buf = &mut buf.0[..n];
+        let op = begin_op();
+        let copied = if same {
+            let mut guard = ilock(ipin);
+            let copied = guard.readi(rd, buf).and_then(|m| guard.writei(&op, wr, &buf[..m]));
+            drop(guard);
+            copied
+        } else {
+            let (mut gin, mut gout) = if (ipin.dev, ipin.inum) < (ipout.dev, ipout.inum) {
+                let gin = ilock(ipin);
+                (gin, ilock(ipout))
+            } else {
+                let gout = ilock(ipout);
+                (ilock(ipin), gout)
+            };
+            let copied = gin.readi(rd, buf).and_then(|m| gout.writei(&op, wr, &buf[..m]));
+            drop(gout);
+            drop(gin);
+            copied
+        };
+        drop(op);
+        match copied {
+            Ok(m) => {
+                rd += m as u32;
+                wr += m as u32;
+                tot += m;
+                if m < n {
+                    break;
+                }
+            }
+            Err(err) if tot == 0 => return Err(err),
+            Err(_) => break,
+        }
+    }
+
+    match off_in {
+        Some(off) => *off = rd,
+        None => fin.off.set(rd),
+    }
+    match off_out {
+        Some(off) => *off = wr,
+        None => fout.off.set(wr),
+    }
+    Ok(tot)
+}
+
+/// The inode's metadata
+pub fn filestat(f: &File) -> Result<Stat, &'static str> {
+    let ip = match f.ftype {
+        FileType::Inode { ip } | FileType::Device { ip, .. } | FileType::Fifo { ip, .. } => ip,
+        FileType::Pipe { .. } => return Err("fstat: a pipe has no inode"),
+        FileType::Socket { .. } => return Err("fstat: a socket has no inode"),
+        FileType::Mqueue { .. } => return Err("fstat: a message queue has no inode"),
+        FileType::Eventfd { .. } => return Err("fstat: an event counter has no inode"),
+        FileType::Timerfd { .. } => return Err("fstat: a timer descriptor has no inode"),
+        FileType::None => panic!("filestat: not open"),
+    };
+    let guard = ilock(ip);
+    Ok(Stat {
+        dev: guard.dev() as i32,
+        ino: guard.inum(),
+        typ: guard.itype as i16,
+        nlink: guard.nlink as i16,
+        size: guard.size as u64,
+    })
+}
+
+/// The size of the argument of ioctl request req on f, a device
+pub fn fileioctl_size(f: &File, req: usize) -> Result<usize, &'static str> {
+    match f.ftype {
+        FileType::Device { major, .. } => (device(major)?.ioctl_size)(req),
+        _ => Err("ioctl: not a device"),
+    }
+}
+
+/// Carry out ioctl request req on f, a device, arg holds its argument,
+/// of fileioctl_size(), and gets what the request returns
+pub fn fileioctl(f: &File, req: usize, arg: &mut [u8]) -> Result<(), &'static str> {
+    match f.ftype {
+        FileType::Device { major, minor, .. } => (device(major)?.ioctl)(minor, req, arg),
+        _ => Err("ioctl: not a device"),
+    }
+}
+
+fn device(major: u16) -> Result<Devsw, &'static str> {
+    DEVSW.get(major as usize).copied().flatten().ok_or("no such device")
+}
+
+#[cfg(feature = "unit_test")]
+pub mod tests {
+    use core::mem;
+
+    use super::*;
+
+    /// Stat and the modes are those of user space, the console has its functions
+    pub fn file_abi() {
+        assert_eq!(mem::size_of::<Stat>(), 24);
+        assert!(device(CONSOLE).is_ok());
+        assert!(device(RANDOM).is_ok());
+        assert!(device(0).is_err());
+        assert!(device(NDEV as u16).is_err());
+        assert_eq!(O_RDONLY | O_WRONLY | O_RDWR, 3);
+    }
+    crate::kernel_test!(file_abi);
+}
@@ -0,0 +1,409 @@
+//! File system consistency check, at mount with fsck or fsck=repair on the command line
+//!
+//! Run by init() before any process uses the file system, so nothing else touches the blocks.
+//! Four passes over the root file system:
+//! - the inodes, each allocated one has a known type, and its blocks,
+//!   direct, the indirect one and those in it, are data blocks claimed by no other inode,
+//! - the directories, "." and ".." come first, "." is the directory itself,
+//!   and every entry names an allocated inode,
+//! - the link counts, an inode's nlink is the number of entries naming it,
+//!   not counting "." and "..", as mkfs makes them, the root has 1 for being the root,
+//! - the bitmap, a block is marked used iff it is metadata or claimed by an inode.
+//!
+//! Every problem is printed. With repair, each is fixed by throwing away what is wrong:
+//! an inode of unknown type is freed, a bad or twice-claimed block address is zeroed,
+//! an entry naming a free inode is cleared, nlink is set to the count,
+//! an allocated inode no entry names is freed, as iput() frees one with no links left,
+//! and the bitmap is rewritten. A bad root, or a file system too big for the scratch page, is only reported.
+//! Nothing is repaired on a file system that is read-only, see verify() in mod.rs.
+//!
+//! Each repaired block is written in an operation of its own through the log, see log.rs,
+//! a crash leaves it either repaired or as it was, and the next fsck finds what is left.
+//!
+//! LTODO - there is no lost+found to keep the unnamed inodes in.
+
+use core::mem;
+use core::ptr;
+
+use crate::consts::PGSIZE;
+use crate::mm::{Box, PageAligned};
+
+use super::bio::{bread, BufGuard};
+use super::log::{begin_op, log_write};
+use super::dir::DIRENT_SIZE;
+use super::{u32_at, DInode, SB, BSIZE, IPB, NDIRECT, NINDIRECT, ROOTINO};
+use super::{T_DEVICE, T_DIR, T_FIFO, T_FILE};
+
+/// The largest file system the scratch page holds the state of
+const MAXBLOCKS: usize = 16 * 1024;
+const MAXINODES: usize = 512;
+
+/// What the passes learn, too big for the kernel stack
+#[repr(C, align(4096))]
+struct Scratch {
+    /// blocks claimed by an inode, a bit each
+    used: [u8; MAXBLOCKS / 8],
+    /// entries naming each inode
+    links: [u16; MAXINODES],
+    /// type of each inode, 0 for a free one
+    itype: [u16; MAXINODES],
+}
+
+impl PageAligned for Scratch {}
+
+const _: () = assert!(mem::size_of::<Scratch>() <= PGSIZE);
+
+/// Mark bit in map, return whether it was clear
+fn claim(map: &mut [u8], bit: usize) -> bool {
+    let clear = map[bit / 8] & (1 << (bit % 8)) == 0;
+    map[bit / 8] |= 1 << (bit % 8);
+    clear
+}
+
+fn is_set(map: &[u8], bit: usize) -> bool {
+    map[bit / 8] & (1 << (bit % 8)) != 0
+}
+
+/// The nlink inode inum should have, being named by refs entries
+fn expected_nlink(inum: u32, refs: u16) -> u16 {
+    if inum == ROOTINO { refs + 1 } else { refs }
+}
+
+/// The problems found and repaired
+#[derive(Default)]
+pub struct Report {
+    pub problems: usize,
+    pub repaired: usize,
+}
+
+struct Fsck {
+    dev: u32,
+    repair: bool,
+    /// first data block, past the bitmap
+    datastart: u32,
+    size: u32,
+    ninodes: u32,
+    scratch: Box<Scratch>,
+    report: Report,
+}
+
+impl Fsck {
+    fn problem(&mut self, fixable: bool) -> bool {
+        self.report.problems += 1;
+        let fix = fixable && self.repair;
+        if fix {
+            self.report.repaired += 1;
+        }
+        fix
+    }
+
+    /// Write b back after a repair, in an operation of its own
+    fn write(&self, b: BufGuard) {
+        let op = begin_op();
+        log_write(&op, &b);
+        // unlocked before the commit copies it to the log
+        drop(b);
+        drop(op);
+    }
+
+    fn rinode(&self, inum: u32) -> DInode {
+        let bp = bread(self.dev, super::iblock(inum));
+        let dip = unsafe {
+            ptr::read_unaligned((bp.as_ptr() as *const DInode).add(inum as usize % IPB))
+        };
+        drop(bp);
+        dip
+    }
+
+    fn winode(&mut self, inum: u32, dip: &DInode) {
+        let mut bp = bread(self.dev, super::iblock(inum));
+        unsafe {
+            ptr::write_unaligned((bp.as_mut_ptr() as *mut DInode).add(inum as usize % IPB), ptr::read(dip));
+        }
+        self.write(bp);
+    }
+
+    /// Claim block addr for inode inum, false if it may not have it
+    fn claim_block(&mut self, inum: u32, addr: u32) -> bool {
+        if addr < self.datastart || addr >= self.size {
+            println!("fsck: inode {} has block {} outside the data blocks", inum, addr);
+            false
+        } else if !claim(&mut self.scratch.used, addr as usize) {
+            println!("fsck: inode {} has block {} of another inode", inum, addr);
+            false
+        } else {
+            true
+        }
+    }
+
+    /// Pass 1, the inodes' types and blocks
+    fn check_inodes(&mut self) {
+        for inum in 1..self.ninodes {
+            let mut dip = self.rinode(inum);
+            self.scratch.itype[inum as usize] = dip.itype;
+            if dip.itype == 0 {
+                continue;
+            }
+            if ![T_DIR, T_FILE, T_DEVICE, T_FIFO].contains(&dip.itype) {
+                println!("fsck: inode {} has unknown type {}", inum, dip.itype);
+                if self.problem(true) {
+                    self.scratch.itype[inum as usize] = 0;
+                    self.winode(inum, &unsafe { mem::zeroed() });
+                }
+                continue;
+            }
+
+            let mut dirty = false;
+            for i in 0..=NDIRECT {
+                let addr = dip.addrs[i];
+                if addr != 0 && !self.claim_block(inum, addr) && self.problem(true) {
+                    dip.addrs[i] = 0;
+                    dirty = true;
+                }
+            }
+            if dip.addrs[NDIRECT] != 0 {
+                self.check_indirect(inum, dip.addrs[NDIRECT]);
+            }
+            if dirty {
+                self.winode(inum, &dip);
+            }
+        }
+    }
+
+    fn check_indirect(&mut self, inum: u32, addr: u32) {
+        let mut bp = bread(self.dev, addr);
+        let mut dirty = false;
+        for i in 0..NINDIRECT {
+            let addr = u32_at(&bp, i);
+            if addr != 0 && !self.claim_block(inum, addr) && self.problem(true) {
+                bp[i * 4..i * 4 + 4].copy_from_slice(&0u32.to_le_bytes());
+                dirty = true;
+            }
+        }
+        if dirty {
+            self.write(bp);
+        }
+    }
+
+    /// Block of the fbn-th block of an inode, 0 for a hole
+    fn bmap(&self, dip: &DInode, fbn: usize) -> u32 {
+        if fbn < NDIRECT {
+            return dip.addrs[fbn];
+        }
+        if dip.addrs[NDIRECT] == 0 {
+            return 0;
+        }
+        let bp = bread(self.dev, dip.addrs[NDIRECT]);
+        let addr = u32_at(&bp, fbn - NDIRECT);
+        drop(bp);
+        addr
+    }
+
+    /// Pass 2, the directories' entries, counting the links
+    fn check_dirs(&mut self) {
+        for inum in 1..self.ninodes {
+            if self.scratch.itype[inum as usize] != T_DIR {
+                continue;
+            }
+            let dip = self.rinode(inum);
+            if !(dip.size as usize).is_multiple_of(DIRENT_SIZE) {
+                println!("fsck: directory {} has size {}, not a number of entries", inum, dip.size);
+                self.problem(false);
+            }
+            let mut nth = 0;
+            let nblocks = (dip.size as usize).div_ceil(BSIZE);
+            for fbn in 0..nblocks.min(NDIRECT + NINDIRECT) {
+                let addr = self.bmap(&dip, fbn);
+                // a hole, or a bad address zeroed by pass 1
+                if addr == 0 {
+                    nth += BSIZE / DIRENT_SIZE;
+                    continue;
+                }
+                let mut bp = bread(self.dev, addr);
+                let len = (dip.size as usize - fbn * BSIZE).min(BSIZE);
+                let mut dirty = false;
+                for off in (0..len - len % DIRENT_SIZE).step_by(DIRENT_SIZE) {
+                    if self.check_entry(inum, nth, &mut bp[off..off + DIRENT_SIZE]) {
+                        dirty = true;
+                    }
+                    nth += 1;
+                }
+                if dirty {
+                    self.write(bp);
+                }
+            }
+            if nth < 2 {
+                println!("fsck: directory {} has no . and ..", inum);
+                self.problem(false);
+            }
+        }
+    }
+
+    /// Check the nth entry de of directory dir, return whether it was repaired
+    fn check_entry(&mut self, dir: u32, nth: usize, de: &mut [u8]) -> bool {
+        let inum = u16::from_le_bytes([de[0], de[1]]) as u32;
+        let name = &de[2..];
+        let want: &[u8] = match nth {
+            0 => b".",
+            1 => b"..",
+            _ => b"",
+        };
+        if !want.is_empty() {
+            let ok = name.starts_with(want) && name[want.len()..].iter().all(|c| *c == 0);
+            if !ok || (nth == 0 && inum != dir) || (dir == ROOTINO && inum != ROOTINO) {
+                println!("fsck: directory {} entry {} is not a right {}", dir, nth,
+                    if nth == 0 { "." } else { ".." });
+                self.problem(false);
+            }
+            return false;
+        }
+        if inum == 0 {
+            return false;
+        }
+        if inum >= self.ninodes || self.scratch.itype[inum as usize] == 0 {
+            println!("fsck: directory {} names free inode {}", dir, inum);
+            if self.problem(true) {
+                de.iter_mut().for_each(|c| *c = 0);
+                return true;
+            }
+            return false;
+        }
+        self.scratch.links[inum as usize] += 1;
+        false
+    }
+
+    /// Pass 3, the link counts, freeing the inodes no entry names
+    fn check_links(&mut self) {
+        for inum in 1..self.ninodes {
+            if self.scratch.itype[inum as usize] == 0 {
+                continue;
+            }
+            let mut dip = self.rinode(inum);
+            let want = expected_nlink(inum, self.scratch.links[inum as usize]);
+            if want == 0 {
+                println!("fsck: inode {} is named by no directory", inum);
+                if self.problem(true) {
+                    self.release_blocks(&dip);
+                    self.scratch.itype[inum as usize] = 0;
+                    self.winode(inum, &unsafe { mem::zeroed() });
+                }
+            } else if dip.nlink != want {
+                println!("fsck: inode {} has nlink {}, named {} times", inum, dip.nlink, want);
+                if self.problem(true) {
+                    dip.nlink = want;
+                    self.winode(inum, &dip);
+                }
+            }
+        }
+    }
+
+    /// Unclaim the blocks of a freed inode, pass 1 left it only ones of its own
+    fn release_blocks(&mut self, dip: &DInode) {
+        let unclaim = |map: &mut [u8], addr: u32| map[addr as usize / 8] &= !(1 << (addr % 8));
+        for addr in dip.addrs.iter().filter(|addr| **addr != 0) {
+            unclaim(&mut self.scratch.used, *addr);
+        }
+        if dip.addrs[NDIRECT] != 0 {
+            let bp = bread(self.dev, dip.addrs[NDIRECT]);
+            for i in 0..NINDIRECT {
+                let addr = u32_at(&bp, i);
+                if addr != 0 {
+                    unclaim(&mut self.scratch.used, addr);
+                }
+            }
+            drop(bp);
+        }
+    }
+
+    /// Pass 4, the bitmap against the claimed blocks
+    fn check_bitmap(&mut self) {
+        let bmapstart = unsafe { SB.bmapstart };
+        for bno in bmapstart..self.datastart {
+            let mut bp = bread(self.dev, bno);
+            let first = (bno - bmapstart) as usize * BSIZE * 8;
+            let mut dirty = false;
+            for bit in 0..BSIZE * 8 {
+                let b = first + bit;
+                if b >= self.size as usize {
+                    break;
+                }
+                let want = b < self.datastart as usize || is_set(&self.scratch.used, b);
+                if is_set(&bp[..], bit) != want {
+                    println!("fsck: block {} is marked {} in the bitmap", b, if want { "free" } else { "used" });
+                    if self.problem(true) {
+                        bp[bit / 8] ^= 1 << (bit % 8);
+                        dirty = true;
+                    }
+                }
+            }
+            if dirty {
+                self.write(bp);
+            }
+        }
+    }
+}
+
+/// Check the file system on dev, its super block read, repairing what is wrong if repair
+pub fn fsck(dev: u32, repair: bool) -> Report {
+    let sb = unsafe { &SB };
+    let mut report = Report::default();
+    let datastart = sb.bmapstart + sb.size / (BSIZE as u32 * 8) + 1;
+    if sb.size as usize > MAXBLOCKS || sb.ninodes as usize > MAXINODES {
+        println!("fsck: {} blocks and {} inodes are too many to check", sb.size, sb.ninodes);
+        report.problems += 1;
+        return report;
+    }
+    if sb.inodestart + sb.ninodes.div_ceil(IPB as u32) > sb.bmapstart || datastart > sb.size {
+        println!("fsck: the super block's layout does not fit in {} blocks", sb.size);
+        report.problems += 1;
+        return report;
+    }
+    let mut scratch = match Box::<Scratch>::new() {
+        Some(scratch) => scratch,
+        None => {
+            println!("fsck: no page to check in");
+            report.problems += 1;
+            return report;
+        }
+    };
+    unsafe { ptr::write_bytes(&mut *scratch as *mut Scratch, 0, 1) };
+
+    let mut fsck = Fsck {
+        dev,
+        repair: repair && !super::read_only(),
+        datastart,
+        size: sb.size,
+        ninodes: sb.ninodes,
+        scratch,
+        report,
+    };
+    if fsck.rinode(ROOTINO).itype != T_DIR {
+        println!("fsck: the root inode is not a directory");
+        fsck.report.problems += 1;
+        return fsck.report;
+    }
+    fsck.check_inodes();
+    fsck.check_dirs();
+    fsck.check_links();
+    fsck.check_bitmap();
+    fsck.report
+}
+
+#[cfg(feature = "unit_test")]
+pub mod tests {
+    use super::*;
+
+    pub fn fsck_rules() {
+        let mut map = [0u8; 4];
+        assert!(claim(&mut map, 9));
+        assert!(!claim(&mut map, 9));
+        assert!(is_set(&map, 9));
+        assert!(!is_set(&map, 8));
+        assert_eq!(map, [0, 2, 0, 0]);
+
+        assert_eq!(expected_nlink(ROOTINO, 0), 1);
+        assert_eq!(expected_nlink(ROOTINO + 1, 0), 0);
+        assert_eq!(expected_nlink(ROOTINO + 1, 2), 2);
+    }
+    crate::kernel_test!(fsck_rules);
+}
@@ -0,0 +1,133 @@
+//! The host's directory, exported over virtio 9p, mounted at /host, see driver/virtio_9p.rs
+//!
+//! Its files and directories are inodes of device HOSTDEV, each standing for a fid of its own,
+//! which is its inum, walked to from its directory's fid by lookup(), see dirlookup(),
+//! with the attributes the host gave then, and clunked by the last iput().
+//! namex() crosses from the directory /host of the root file system to the export's root,
+//! see cross(), and back from it by "..", see covered().
+//! readi() of a file reads it from the host, of a directory gives its entries as Dirents,
+//! out of a Treaddir, with inums of their own, names longer than DIRSIZ are cut.
+//! The export is only read, creating, linking, unlinking or writing in it fails.
+
+use core::ptr;
+
+use crate::driver::virtio_9p;
+
+use super::dir::{create, namei, Dirent, DIRENT_SIZE};
+use super::inode::{idup, iget, ilock, iput, InodeGuard};
+use super::log::{begin_op, Op};
+use super::{Inode, HOSTDEV, NDIRECT, T_DIR, T_FILE};
+
+/// Where the export is mounted
+pub const MOUNT: &[u8] = b"/host";
+
+/// The directory the export is mounted on and the root of the export,
+/// set once by mount(), while the first process is the only one
+static mut MOUNTED: Option<(&'static Inode, &'static Inode)> = None;
+
+/// Mount the export on MOUNT, making the directory if there is none
+pub fn mount() -> Result<(), &'static str> {
+    let op = begin_op();
+    let covered = match namei(&op, MOUNT) {
+        Ok(ip) => ip,
+        Err(_) => create(&op, MOUNT, T_DIR, 0, 0)?.inode(),
+    };
+    if !ilock(covered).is_dir() {
+        iput(&op, covered);
+        return Err("mount: /host is not a directory");
+    }
+    match inode(virtio_9p::ROOT_FID) {
+        Ok(root) => {
+            unsafe { MOUNTED = Some((covered, root)) };
+            Ok(())
+        }
+        Err(str) => {
+            iput(&op, covered);
+            Err(str)
+        }
+    }
+}
+
+/// ip, or the root of the export, referenced in its stead, if ip is the directory it is mounted on
+pub fn cross(op: &Op, ip: &'static Inode) -> &'static Inode {
+    match unsafe { MOUNTED } {
+        Some((covered, root)) if ptr::eq(covered, ip) => {
+            iput(op, ip);
+            idup(root)
+        }
+        _ => ip,
+    }
+}
+
+/// The directory the export is mounted on, referenced, if ip is the root of the export
+pub fn covered(ip: &'static Inode) -> Option<&'static Inode> {
+    match unsafe { MOUNTED } {
+        Some((covered, root)) if ptr::eq(root, ip) => Some(idup(covered)),
+        _ => None,
+    }
+}
+
+/// Whether ip is the directory the export is mounted on, which is not unlinked
+pub fn is_mount_point(ip: &'static Inode) -> bool {
+    matches!(unsafe { MOUNTED }, Some((covered, _)) if ptr::eq(covered, ip))
+}
+
+/// The inode of fid, referenced but unlocked, filled in with its attributes from the host
+fn inode(fid: u32) -> Result<&'static Inode, &'static str> {
+    let attr = virtio_9p::getattr(fid)?;
+    let ip = iget(HOSTDEV, fid);
+    let mut data = ip.data.lock();
+    data.itype = if attr.dir { T_DIR } else { T_FILE };
+    data.major = 0;
+    data.minor = 0;
+    // never 0, with which iput() would free it
+    data.nlink = attr.nlink.clamp(1, u16::MAX as u64) as u16;
+    data.size = attr.size.min(u32::MAX as u64) as u32;
+    data.addrs = [0; NDIRECT + 1];
+    data.fifo = None;
+    data.valid = true;
+    drop(data);
+    Ok(ip)
+}
+
+/// Look for name in host directory dp, walking a fid to it,
+/// return its inode, referenced but unlocked
+pub fn lookup(dp: &InodeGuard, name: &[u8]) -> Result<Option<&'static Inode>, &'static str> {
+    if name == b"." {
+        return Ok(Some(idup(dp.inode())));
+    }
+    let fid = match virtio_9p::walk(dp.inum(), name)? {
+        Some(fid) => fid,
+        None => return Ok(None),
+    };
+    inode(fid).map(Some).inspect_err(|_| release(fid))
+}
+
+/// Clunk the fid of a host inode no one refers to any more
+pub fn release(fid: u32) {
+    if let Err(str) = virtio_9p::close(fid) {
+        println!("host: {}", str);
+    }
+}
+
+/// Read host inode guard from off into dst, return the bytes read, short at the end,
+/// a file from the host, a directory as the Dirents of its entries
+pub fn readi(guard: &InodeGuard, off: u32, dst: &mut [u8]) -> Result<usize, &'static str> {
+    if !guard.is_dir() {
+        return virtio_9p::read_at(guard.inum(), off as u64, dst);
+    }
+    let (start, end) = (off as usize, off as usize + dst.len());
+    let (mut pos, mut n) = (0, 0);
+    virtio_9p::readdir(guard.inum(), |name| {
+        let inum = (pos / DIRENT_SIZE % u16::MAX as usize) as u16 + 1;
+        let bytes = Dirent::new(inum, name).to_bytes();
+        let (from, to) = (pos.max(start), (pos + DIRENT_SIZE).min(end));
+        if from < to {
+            dst[from - start..to - start].copy_from_slice(&bytes[from - pos..to - pos]);
+            n = to - start;
+        }
+        pos += DIRENT_SIZE;
+        pos < end
+    })?;
+    Ok(n)
+}
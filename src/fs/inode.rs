@@ -1,9 +1,23 @@
 //! Inode-relevant operations
+//!
+//! The inode cache holds the inodes in use, iget() finds or recycles one and counts the reference,
+//! iput() drops it, freeing the inode and its blocks with the last reference to an unlinked one.
+//! ilock() locks the contents, reading them from the disk the first time,
+//! and returns an InodeGuard, through which the file's blocks are mapped, read and written.
+//! Whatever changes the disk takes an Op, the writes go through the log, see log.rs.
+//! An inode of HOSTDEV stands for a fid of the host's directory instead, see host.rs.
 
+use core::ops::{Deref, DerefMut};
+use core::ptr;
+
+use crate::sleeplock::SleepLockGuard;
 use crate::spinlock::SpinLock;
 
-use super::Inode;
-use super::NINODE;
+use super::bio::bread;
+use super::host;
+use super::log::{log_write, Op};
+use super::{iblock, u32_at, DInode, Inode, InodeData, SB};
+use super::{BPB, BSIZE, HOSTDEV, IPB, MAXFILE, NDIRECT, NINDIRECT, NINODE, T_DIR};
 
 static mut ICACHE: Icache = Icache::new();
 
@@ -16,15 +30,69 @@ impl Icache {
     const fn new() -> Self {
         Self {
             lock: SpinLock::new((), "icache"),
-            inodes: [Inode::new(); NINODE],
+            inodes: [const { Inode::new() }; NINODE],
+        }
+    }
+}
+
+/// Allocate a zeroed disk block on dev
+fn balloc(op: &Op, dev: u32) -> Result<u32, &'static str> {
+    let (size, bmapstart) = unsafe { (SB.size, SB.bmapstart) };
+    for base in (0..size).step_by(BPB as usize) {
+        let mut bp = bread(dev, bmapstart + base / BPB);
+        let bits = BPB.min(size - base);
+        for bi in 0..bits {
+            let m = 1 << (bi % 8);
+            if bp[bi as usize / 8] & m == 0 {
+                bp[bi as usize / 8] |= m;
+                log_write(op, &bp);
+                drop(bp);
+                let mut zp = bread(dev, base + bi);
+                zp.fill(0);
+                log_write(op, &zp);
+                return Ok(base + bi);
+            }
+        }
+    }
+    Err("balloc: out of blocks")
+}
+
+/// Free disk block b of dev
+fn bfree(op: &Op, dev: u32, b: u32) {
+    let mut bp = bread(dev, b / BPB + unsafe { SB.bmapstart });
+    let bi = b % BPB;
+    let m = 1 << (bi % 8);
+    if bp[bi as usize / 8] & m == 0 {
+        panic!("bfree: block {} already free", b);
+    }
+    bp[bi as usize / 8] &= !m;
+    log_write(op, &bp);
+}
+
+/// Allocate an inode on dev, marked with type itype,
+/// return it unlocked but referenced.
+pub fn ialloc(op: &Op, dev: u32, itype: u16) -> Result<&'static Inode, &'static str> {
+    for inum in 1..unsafe { SB.ninodes } {
+        let mut bp = bread(dev, iblock(inum));
+        let slot = unsafe { (bp.as_mut_ptr() as *mut DInode).add(inum as usize % IPB) };
+        let mut dip = unsafe { ptr::read_unaligned(slot) };
+        if dip.itype == 0 {
+            // a free inode
+            dip = DInode { itype, major: 0, minor: 0, nlink: 0, size: 0, addrs: [0; NDIRECT + 1] };
+            unsafe { ptr::write_unaligned(slot, dip) };
+            log_write(op, &bp);
+            drop(bp);
+            return Ok(iget(dev, inum));
         }
     }
+    Err("ialloc: no inodes")
 }
 
 /// Find the inode with number inum on device dev
 /// and return the in-memory copy. Does not lock
 /// the inode and does not read it from disk.
 pub fn iget(dev: u32, inum: u32) -> &'static Inode {
+    ftrace!();
     let icache = unsafe {ICACHE.lock.lock()};
 
     // Is the inode we are looking for already cached?
@@ -40,30 +108,303 @@ pub fn iget(dev: u32, inum: u32) -> &'static Inode {
         }
     }
 
-    // Recycle an inode cacahe entry
-    if empty.is_none() {
-        panic!("iget: no enough space in inode cache");
-    }
-    let ip: &mut Inode = empty.take().unwrap();
+    // Recycle an inode cache entry,
+    // with no reference no one holds its sleep lock
+    let ip: &mut Inode = match empty {
+        Some(ip) => ip,
+        None => panic!("iget: no enough space in inode cache"),
+    };
     ip.dev = dev;
     ip.inum = inum;
     ip.iref = 1;
-    ip.valid = false;
+    ip.data.get_mut().valid = false;
+    drop(icache);
+    ip
+}
+
+/// Increment the reference count of ip, for ip = idup(ip1) idiom
+pub fn idup(ip: &'static Inode) -> &'static Inode {
+    let icache = unsafe {ICACHE.lock.lock()};
+    unsafe { (*(ip as *const Inode as *mut Inode)).iref += 1 };
     drop(icache);
     ip
 }
 
+/// Drop a reference to an in-memory inode.
+/// If that was the last reference and the inode has no links to it,
+/// free the inode and its content on disk, hence the Op.
+/// The last reference to a host inode clunks its fid.
+/// ip is not to be locked by the caller.
+pub fn iput(op: &Op, ip: &'static Inode) {
+    let icache = unsafe {ICACHE.lock.lock()};
+    let ipm = ip as *const Inode as *mut Inode;
+    if ip.iref == 1 && ip.dev == HOSTDEV {
+        // unreferenced first, so that iget() does not find it once its fid is walked to again
+        unsafe { (*ipm).iref -= 1 };
+        drop(icache);
+        host::release(ip.inum);
+        return;
+    }
+    if ip.iref == 1 {
+        // the only reference, no one else has it locked, this does not sleep
+        let mut guard = InodeGuard { ip, data: ip.data.lock() };
+        if guard.valid && guard.nlink == 0 {
+            drop(icache);
+            guard.itrunc(op);
+            guard.itype = 0;
+            guard.iupdate(op);
+            guard.valid = false;
+            drop(guard);
+            let icache = unsafe {ICACHE.lock.lock()};
+            unsafe { (*ipm).iref -= 1 };
+            drop(icache);
+            return;
+        }
+    }
+    unsafe { (*ipm).iref -= 1 };
+    drop(icache);
+}
+
 /// Lock the given inode.
 /// Reads the inode from disk if necessary.
-/// LTODO - do not lock the inode yet, only process zero exists
-pub fn ilock(ip: &mut Inode) {
+pub fn ilock(ip: &'static Inode) -> InodeGuard {
+    ftrace!();
     if ip.iref < 1 {
         panic!("ilock: iref smaller than 1");
     }
 
-    // acquire sleep lock
+    let mut guard = InodeGuard { ip, data: ip.data.lock() };
+    if !guard.valid {
+        iread(&mut guard);
+        if guard.itype == 0 {
+            panic!("ilock: inode {} has no type", ip.inum);
+        }
+    }
+    guard
+}
+
+/// Read the locked inode from disk
+fn iread(guard: &mut InodeGuard) {
+    // the inode block's checksum is checked by bread
+    let bp = bread(guard.ip.dev, iblock(guard.ip.inum));
+    let dip = unsafe {
+        ptr::read_unaligned((bp.as_ptr() as *const DInode).add(guard.ip.inum as usize % IPB))
+    };
+    drop(bp);
+    guard.itype = dip.itype;
+    guard.major = dip.major;
+    guard.minor = dip.minor;
+    guard.nlink = dip.nlink;
+    guard.size = dip.size;
+    guard.addrs = dip.addrs;
+    guard.valid = true;
+}
+
+/// Get inode inum of the root device and lock it, for one known only by its number,
+/// e.g., by a checkpoint, failing where ilock() would panic, if it is free.
+pub fn iget_used(op: &Op, inum: u32) -> Result<InodeGuard, &'static str> {
+    if inum == 0 || inum >= unsafe { SB.ninodes } {
+        return Err("iget: no such inode");
+    }
+    let ip = iget(super::root_dev(), inum);
+    let mut guard = InodeGuard { ip, data: ip.data.lock() };
+    if !guard.valid {
+        iread(&mut guard);
+    }
+    if guard.itype == 0 {
+        guard.valid = false;
+        drop(guard);
+        iput(op, ip);
+        return Err("iget: a free inode");
+    }
+    Ok(guard)
+}
+
+/// A locked inode, dropping it unlocks the inode, the reference stays
+pub struct InodeGuard {
+    ip: &'static Inode,
+    data: SleepLockGuard<'static, InodeData>,
+}
 
-    if !ip.valid {
-        // bp = bread(ip.dev, )
+impl Deref for InodeGuard {
+    type Target = InodeData;
+    fn deref(&self) -> &InodeData {
+        &self.data
+    }
+}
+
+impl DerefMut for InodeGuard {
+    fn deref_mut(&mut self) -> &mut InodeData {
+        &mut self.data
+    }
+}
+
+impl InodeGuard {
+    /// The inode, to keep a reference to it once unlocked
+    pub fn inode(&self) -> &'static Inode {
+        self.ip
+    }
+
+    pub fn dev(&self) -> u32 {
+        self.ip.dev
+    }
+
+    pub fn inum(&self) -> u32 {
+        self.ip.inum
+    }
+
+    pub fn is_dir(&self) -> bool {
+        self.itype == T_DIR
+    }
+
+    /// Copy the in-memory inode to the disk, after every change of it
+    pub fn iupdate(&self, op: &Op) {
+        if self.dev() == HOSTDEV {
+            panic!("iupdate: a host inode");
+        }
+        let mut bp = bread(self.dev(), iblock(self.inum()));
+        let dip = DInode {
+            itype: self.itype,
+            major: self.major,
+            minor: self.minor,
+            nlink: self.nlink,
+            size: self.size,
+            addrs: self.addrs,
+        };
+        unsafe {
+            ptr::write_unaligned((bp.as_mut_ptr() as *mut DInode).add(self.inum() as usize % IPB), dip);
+        }
+        log_write(op, &bp);
+    }
+
+    /// The disk block of the bn-th block of the file, allocating it with an op,
+    /// 0 for a hole without one
+    fn bmap(&mut self, op: Option<&Op>, bn: usize) -> Result<u32, &'static str> {
+        let dev = self.dev();
+        if bn < NDIRECT {
+            if self.addrs[bn] == 0 {
+                if let Some(op) = op {
+                    self.addrs[bn] = balloc(op, dev)?;
+                }
+            }
+            return Ok(self.addrs[bn]);
+        }
+        let bn = bn - NDIRECT;
+        if bn >= NINDIRECT {
+            return Err("bmap: out of range");
+        }
+
+        // the indirect block, allocating if necessary
+        if self.addrs[NDIRECT] == 0 {
+            match op {
+                Some(op) => self.addrs[NDIRECT] = balloc(op, dev)?,
+                None => return Ok(0),
+            }
+        }
+        let mut bp = bread(dev, self.addrs[NDIRECT]);
+        let mut addr = u32_at(&bp, bn);
+        if addr == 0 {
+            if let Some(op) = op {
+                addr = balloc(op, dev)?;
+                bp[bn * 4..bn * 4 + 4].copy_from_slice(&addr.to_le_bytes());
+                log_write(op, &bp);
+            }
+        }
+        Ok(addr)
+    }
+
+    /// Truncate the inode, discarding its contents
+    pub fn itrunc(&mut self, op: &Op) {
+        let dev = self.dev();
+        for i in 0..NDIRECT {
+            if self.addrs[i] != 0 {
+                bfree(op, dev, self.addrs[i]);
+                self.addrs[i] = 0;
+            }
+        }
+        if self.addrs[NDIRECT] != 0 {
+            let bp = bread(dev, self.addrs[NDIRECT]);
+            for j in 0..NINDIRECT {
+                let addr = u32_at(&bp, j);
+                if addr != 0 {
+                    bfree(op, dev, addr);
+                }
+            }
+            drop(bp);
+            bfree(op, dev, self.addrs[NDIRECT]);
+            self.addrs[NDIRECT] = 0;
+        }
+        self.size = 0;
+        self.iupdate(op);
+    }
+
+    /// Read data from the inode, from off on, return the bytes read,
+    /// short at the end of the file, a hole reads as zeros
+    pub fn readi(&mut self, off: u32, dst: &mut [u8]) -> Result<usize, &'static str> {
+        if self.dev() == HOSTDEV {
+            return host::readi(self, off, dst);
+        }
+        let size = self.size as usize;
+        let off = off as usize;
+        if off > size {
+            return Ok(0);
+        }
+        let n = dst.len().min(size - off);
+        let mut tot = 0;
+        while tot < n {
+            let pos = off + tot;
+            let m = (n - tot).min(BSIZE - pos % BSIZE);
+            let addr = self.bmap(None, pos / BSIZE)?;
+            let dst = &mut dst[tot..tot + m];
+            if addr == 0 {
+                dst.fill(0);
+            } else {
+                let bp = bread(self.dev(), addr);
+                dst.copy_from_slice(&bp[pos % BSIZE..pos % BSIZE + m]);
+            }
+            tot += m;
+        }
+        Ok(n)
+    }
+
+    /// Write src to the inode at off, growing it past its end,
+    /// return the bytes written, short if the disk is full
+    pub fn writei(&mut self, op: &Op, off: u32, src: &[u8]) -> Result<usize, &'static str> {
+        if self.dev() == HOSTDEV {
+            return Err("writei: the host's files are only read");
+        }
+        let off = off as usize;
+        if off > self.size as usize {
+            return Err("writei: past the end");
+        }
+        if off + src.len() > MAXFILE * BSIZE {
+            return Err("writei: file too large");
+        }
+        let mut tot = 0;
+        let mut failed = None;
+        while tot < src.len() {
+            let pos = off + tot;
+            let m = (src.len() - tot).min(BSIZE - pos % BSIZE);
+            let addr = match self.bmap(Some(op), pos / BSIZE) {
+                Ok(addr) => addr,
+                Err(err) => {
+                    failed = Some(err);
+                    break;
+                }
+            };
+            let mut bp = bread(self.dev(), addr);
+            bp[pos % BSIZE..pos % BSIZE + m].copy_from_slice(&src[tot..tot + m]);
+            log_write(op, &bp);
+            tot += m;
+        }
+        if off + tot > self.size as usize {
+            self.size = (off + tot) as u32;
+        }
+        // the size, or a block bmap allocated
+        self.iupdate(op);
+        match failed {
+            Some(err) if tot == 0 => Err(err),
+            _ => Ok(tot),
+        }
     }
 }
@@ -0,0 +1,244 @@
+//! Write-ahead log, for crash-safe file system operations
+//!
+//! Each operation, an Op from begin_op() until it is dropped, log_write()s the blocks it changes
+//! instead of bwrite()ing them. They stay pinned in the buffer cache,
+//! and once no operation is in flight, the last one to end commits them all together:
+//! - each block is copied to its slot in the log, and flushed,
+//! - the header, naming the blocks, is written through, this is the commit point,
+//! - each block is then installed at home, flushed, and the header cleared, written through.
+//!
+//! A crash before the commit point leaves the operations undone, the header still empty,
+//! a crash after it is one init() recovers from, installing the blocks again.
+//! A torn header fails its checksum, see verify() in fs/mod.rs, so it is never replayed.
+//!
+//! The kernel panics without unwinding, so an Op is never dropped half way:
+//! a panic in the middle of an operation leaves it uncommitted,
+//! and the panic message says how many were in flight, see in_flight().
+
+use core::ptr;
+use core::sync::atomic::{AtomicUsize, Ordering};
+
+use crate::process::{my_proc, PROC_MANAGER};
+use crate::spinlock::SpinLock;
+
+use super::bio::{bflush, bpin, bread, bunpin, bwrite, bwrite_fua, BufGuard};
+use super::{read_only, LogHeader, SuperBlock, LOGSIZE, MAXOPBLOCKS};
+
+// every operation in flight can fill its share of the log, see begin_op()
+const _: () = assert!(LOGSIZE >= MAXOPBLOCKS);
+
+struct Log {
+    dev: u32,
+    /// the header's block, the logged blocks follow it
+    start: u32,
+    size: u32,
+    /// in commit(), no operation may begin
+    committing: bool,
+    lh: LogHeader,
+}
+
+static LOG: SpinLock<Log> = SpinLock::new(
+    Log { dev: 0, start: 0, size: 0, committing: false, lh: LogHeader::new() },
+    "log",
+);
+
+/// What begin_op() sleeps on, for room in the log or the end of a commit
+fn chan() -> usize {
+    &LOG as *const SpinLock<Log> as usize
+}
+
+/// Operations between begin_op() and end_op(), changed under LOG's lock,
+/// read without it by the panic handler
+static OUTSTANDING: AtomicUsize = AtomicUsize::new(0);
+
+/// File system operations in flight, none of them committed
+pub fn in_flight() -> usize {
+    OUTSTANDING.load(Ordering::Relaxed)
+}
+
+/// Set the log up from the super block, and recover from a crash after a commit
+pub fn init(dev: u32, sb: &SuperBlock) {
+    let mut log = LOG.lock();
+    log.dev = dev;
+    log.start = sb.logstart;
+    log.size = sb.nlog;
+    drop(log);
+    recover(dev, sb.logstart);
+}
+
+fn read_head(dev: u32, start: u32) -> LogHeader {
+    let bp = bread(dev, start);
+    unsafe { ptr::read_unaligned(bp.as_ptr() as *const LogHeader) }
+}
+
+/// Write lh to the header block and make it durable, bwrite() recomputes its checksum
+fn write_head(dev: u32, start: u32, lh: &LogHeader) -> Result<(), &'static str> {
+    let mut bp = bread(dev, start);
+    unsafe { ptr::write_unaligned(bp.as_mut_ptr() as *mut LogHeader, *lh) };
+    bwrite_fua(&mut bp)
+}
+
+fn recover(dev: u32, start: u32) {
+    let lh = read_head(dev, start);
+    if lh.n == 0 {
+        return;
+    }
+    if read_only() || lh.n as usize > LOGSIZE {
+        println!("log: {} blocks committed but not installed, not recovering", lh.n);
+        return;
+    }
+    let recovered = install(dev, start, &lh, true)
+        .and_then(|()| write_head(dev, start, &LogHeader::new()));
+    match recovered {
+        Ok(()) => println!("log: recovered {} blocks", lh.n),
+        Err(err) => println!("log: recovery: {}", err),
+    }
+}
+
+/// A file system operation, from begin_op() until it is dropped,
+/// log_write() takes one to be called in the middle of one only
+pub struct Op {
+    _private: (),
+}
+
+/// Begin a file system operation, sleeping until the log has room for its blocks
+/// and no commit is going on
+pub fn begin_op() -> Op {
+    let mut log = LOG.lock();
+    loop {
+        let outstanding = OUTSTANDING.load(Ordering::Relaxed);
+        if log.committing || log.lh.n as usize + (outstanding + 1) * MAXOPBLOCKS > LOGSIZE {
+            log = unsafe { my_proc() }.sleep(chan(), log);
+        } else {
+            OUTSTANDING.store(outstanding + 1, Ordering::Relaxed);
+            break;
+        }
+    }
+    drop(log);
+    Op { _private: () }
+}
+
+impl Drop for Op {
+    /// End the operation, and commit if it was the last one in flight
+    fn drop(&mut self) {
+        let mut log = LOG.lock();
+        if log.committing {
+            panic!("end_op: committing");
+        }
+        let outstanding = OUTSTANDING.load(Ordering::Relaxed) - 1;
+        OUTSTANDING.store(outstanding, Ordering::Relaxed);
+        if outstanding > 0 {
+            // begin_op() may be waiting for room in the log
+            unsafe { PROC_MANAGER.wakeup(chan()) };
+            drop(log);
+            return;
+        }
+        log.committing = true;
+        let (dev, start, lh) = (log.dev, log.start, log.lh);
+        drop(log);
+
+        // not holding the lock, commit() sleeps for the disk,
+        // and no one touches the header while committing
+        if let Err(err) = commit(dev, start, &lh) {
+            println!("log: commit of {} blocks: {}", lh.n, err);
+        }
+
+        let mut log = LOG.lock();
+        log.lh.n = 0;
+        log.committing = false;
+        unsafe { PROC_MANAGER.wakeup(chan()) };
+        drop(log);
+    }
+}
+
+fn commit(dev: u32, start: u32, lh: &LogHeader) -> Result<(), &'static str> {
+    if lh.n == 0 {
+        return Ok(());
+    }
+    // the logged blocks stable before the header names them
+    let logged = write_log(dev, start, lh)
+        .and_then(|()| bflush())
+        .and_then(|()| write_head(dev, start, lh));
+    if let Err(err) = logged {
+        unpin_all(dev, lh);
+        return Err(err);
+    }
+    // committed, a failure from here on is recovered from at the next boot
+    install(dev, start, lh, false)?;
+    write_head(dev, start, &LogHeader::new())
+}
+
+/// Copy the logged blocks from the cache to the log
+fn write_log(dev: u32, start: u32, lh: &LogHeader) -> Result<(), &'static str> {
+    for (tail, blockno) in lh.block[..lh.n as usize].iter().enumerate() {
+        let mut to = bread(dev, start + tail as u32 + 1);
+        let from = bread(dev, *blockno);
+        to.copy_from_slice(&*from);
+        bwrite(&mut to)?;
+    }
+    Ok(())
+}
+
+/// Copy the committed blocks from the log to their home locations,
+/// they are the cached ones but when recovering, and unpin each, even after a failed write
+fn install(dev: u32, start: u32, lh: &LogHeader, recovering: bool) -> Result<(), &'static str> {
+    let mut installed = Ok(());
+    for (tail, blockno) in lh.block[..lh.n as usize].iter().enumerate() {
+        let mut dst = bread(dev, *blockno);
+        if recovering {
+            let lbuf = bread(dev, start + tail as u32 + 1);
+            dst.copy_from_slice(&*lbuf);
+        }
+        if installed.is_ok() {
+            installed = bwrite(&mut dst);
+        }
+        if !recovering {
+            bunpin(&dst);
+        }
+    }
+    installed?;
+    // installed before the header is cleared
+    bflush()
+}
+
+/// After a failed commit, let the cache recycle the logged blocks
+fn unpin_all(dev: u32, lh: &LogHeader) {
+    for blockno in lh.block[..lh.n as usize].iter() {
+        bunpin(&bread(dev, *blockno));
+    }
+}
+
+/// Record that b was changed by op, in place of bwrite(b),
+/// the block is written at the commit, and stays cached until then.
+/// A block changed again is absorbed, it takes one slot of the log.
+pub fn log_write(_op: &Op, b: &BufGuard) {
+    let mut log = LOG.lock();
+    let n = log.lh.n as usize;
+    if n >= LOGSIZE || n as u32 >= log.size - 1 {
+        panic!("log_write: too big a transaction");
+    }
+    if b.dev() != log.dev {
+        panic!("log_write: dev {} is not the log's", b.dev());
+    }
+    let i = log.lh.block[..n].iter().position(|blockno| *blockno == b.blockno()).unwrap_or(n);
+    if i == n {
+        log.lh.block[n] = b.blockno();
+        log.lh.n += 1;
+        bpin(b);
+    }
+    drop(log);
+}
+
+#[cfg(feature = "unit_test")]
+pub mod tests {
+    use core::mem;
+
+    use super::*;
+
+    /// The header fits in its block with its checksum last
+    pub fn log_layout() {
+        assert_eq!(mem::size_of::<LogHeader>(), (1 + LOGSIZE + 1) * 4);
+        assert_eq!(LogHeader::new().n, 0);
+    }
+    crate::kernel_test!(log_layout);
+}
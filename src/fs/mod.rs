@@ -1,24 +1,59 @@
 use core::cell::Cell;
-use core::option::Option;
 use core::ptr;
+use core::mem;
+use core::sync::atomic::{AtomicBool, AtomicU32, Ordering};
+
+use crate::cmdline;
+use crate::driver::virtio_9p;
+use crate::sleeplock::SleepLock;
 
 mod bio;
+mod crc32;
 mod dir;
+mod file;
+mod fsck;
+mod host;
 mod inode;
+mod log;
+mod pipe;
 
 pub use bio::binit;
+pub use dir::{link, namei, unlink};
+pub use file::{fileclose, filecopy, filedup, fileopen, fileread, fileread_at, filestat, filewrite, filewrite_at};
+pub use file::{fileioctl, fileioctl_size};
+pub use file::{eventalloc, mkdir, mkfifo, mknod, mqalloc, reopen, sockalloc, timeralloc, File, Stat};
+pub use file::{FB, O_CREATE, O_NONBLOCK, O_RDONLY, O_RDWR, O_TRUNC, O_WRONLY};
+pub use inode::{idup, ilock, iput};
+pub use log::{begin_op, in_flight};
+pub use pipe::pipealloc;
 
-use bio::{bread, brelse};
+use bio::bread;
+use pipe::Pipe;
 use inode::iget;
 
 // LTODO - just put all the consts relevant to fs to here tmp
 pub const BSIZE: usize = 1024;
 const NINODE: usize = 50;
 const NDIRECT: usize = 12;
+const NINDIRECT: usize = BSIZE / mem::size_of::<u32>();
+const MAXFILE: usize = NDIRECT + NINDIRECT;
 const DIRSIZ: usize = 14;
 const NBUF: usize = 30;
+const LOGSIZE: usize = 30;
+/// Blocks one operation writes at most, begin_op() makes room for them
+const MAXOPBLOCKS: usize = 10;
+
+/// Inode types, as in the on-disk itype and user/src/stat.rs
+pub const T_DIR: u16 = 1;
+pub const T_FILE: u16 = 2;
+pub const T_DEVICE: u16 = 3;
+/// A named pipe, mkfifo() in user space, its open ends share the pipe in InodeData,
+/// the inode itself never holds data, see fileopen()
+pub const T_FIFO: u16 = 4;
 
 pub const ROOTDEV: u32 = 1;
+/// The device of the host's directory, see host.rs, which no disk is
+pub const HOSTDEV: u32 = 9;
 const ROOTINO: u32 = 1;
 const FSMAGIC: u32 = 0x10203040;
 
@@ -33,20 +68,46 @@ struct DInode {
     addrs: [u32; NDIRECT + 1],
 }
 
-/// in-memory copy of an inode
+/// Inodes per block, the last slot of each inode block holds its checksum
+const IPB: usize = BSIZE / mem::size_of::<DInode>() - 1;
+
+/// Bitmap bits per block
+const BPB: u32 = (BSIZE * 8) as u32;
+
+/// The i-th block address in data, an indirect block
+fn u32_at(data: &[u8; BSIZE], i: usize) -> u32 {
+    let mut word = [0u8; 4];
+    word.copy_from_slice(&data[i * 4..i * 4 + 4]);
+    u32::from_le_bytes(word)
+}
+
+/// Block holding inode inum
+fn iblock(inum: u32) -> u32 {
+    inum / IPB as u32 + unsafe { SB.inodestart }
+}
+
+/// On-disk log header, in the first log block
 #[repr(C)]
+#[derive(Clone, Copy)]
+struct LogHeader {
+    n: u32,
+    block: [u32; LOGSIZE],
+    checksum: u32,
+}
+
+impl LogHeader {
+    const fn new() -> Self {
+        Self { n: 0, block: [0; LOGSIZE], checksum: 0 }
+    }
+}
+
+/// in-memory copy of an inode,
+/// dev, inum and iref under the inode cache's lock, the rest under its sleep lock
 pub struct Inode {
     dev: u32,
     inum: u32,
     iref: u32,
-    valid: bool,
-    // copy of disk inode
-    itype: Cell<u16>,
-    major: Cell<u16>,
-    minor: Cell<u16>,
-    nlink: Cell<u16>,
-    size: Cell<u32>,
-    addrs: [Cell<u32>; NDIRECT + 1],
+    data: SleepLock<InodeData>,
 }
 
 impl Inode {
@@ -55,51 +116,99 @@ impl Inode {
             dev: 0,
             inum: 0,
             iref: 0,
+            data: SleepLock::new(InodeData::new(), "inode"),
+        }
+    }
+}
+
+/// What ilock() reads from the disk inode
+pub struct InodeData {
+    /// read from the disk yet
+    valid: bool,
+    // copy of disk inode
+    itype: u16,
+    major: u16,
+    minor: u16,
+    nlink: u16,
+    size: u32,
+    addrs: [u32; NDIRECT + 1],
+    /// of a T_FIFO while it is open, not on the disk
+    fifo: Option<&'static Pipe>,
+}
+
+impl InodeData {
+    const fn new() -> Self {
+        Self {
             valid: false,
-            itype: Cell::new(0),
-            major: Cell::new(0),
-            minor: Cell::new(0),
-            nlink: Cell::new(0),
-            size: Cell::new(0),
-            addrs: [Cell::new(0); NDIRECT + 1],
+            itype: 0,
+            major: 0,
+            minor: 0,
+            nlink: 0,
+            size: 0,
+            addrs: [0; NDIRECT + 1],
+            fifo: None,
         }
     }
 }
 
-/// LTODO - may consider arc, rc, cell, unsafecell
+/// A buffer of the cache, see bio.rs,
+/// all but data under the cache's lock, data under its own sleep lock
 pub struct Buf {
+    /// data has been read from the disk
     valid: Cell<bool>,
-    pub disk: Cell<bool>,
     dev: u32,
-    pub blockno: u32,
+    blockno: u32,
     refcnt: usize,
-    prev: Option<*mut Buf>,
-    next: Option<*mut Buf>,
-    pub data: [u8; BSIZE],
+    // the LRU list
+    prev: *mut Buf,
+    next: *mut Buf,
+    data: SleepLock<[u8; BSIZE]>,
 }
 
 impl Buf {
     const fn new() -> Self {
         Self {
             valid: Cell::new(false),
-            disk: Cell::new(false),
             dev: 0,
             blockno: 0,
             refcnt: 0,
-            prev: None,
-            next: None,
-            data: [0; BSIZE],
+            prev: ptr::null_mut(),
+            next: ptr::null_mut(),
+            data: SleepLock::new([0; BSIZE], "buffer"),
         }
     }
 }
 
-/// Init fs
-pub fn init(dev: u32) {
+/// Device of the root file system, set before the first process gets its working directory
+static ROOT_DEV: AtomicU32 = AtomicU32::new(ROOTDEV);
+
+pub fn root_dev() -> u32 {
+    ROOT_DEV.load(Ordering::Relaxed)
+}
+
+pub fn set_root_dev(dev: u32) {
+    ROOT_DEV.store(dev, Ordering::Relaxed);
+}
+
+/// Init fs, with the root file system on root_dev()
+pub fn init() {
+    let dev = root_dev();
     read_super_block(dev);
     if unsafe { SB.magic } != FSMAGIC {
         panic!("fs::init: invalid file system");
     }
-    println!("read file system super block..done");
+    log::init(dev, unsafe { &SB });
+    println!("read file system super block..done{}", if read_only() { ", read-only" } else { "" });
+    if let Some(mode) = cmdline::get("fsck") {
+        let report = fsck::fsck(dev, mode == "repair");
+        println!("fsck: dev {}, {} problems, {} repaired", dev, report.problems, report.repaired);
+    }
+    if virtio_9p::is_present() {
+        match host::mount() {
+            Ok(()) => println!("host directory mounted at /host"),
+            Err(str) => println!("host directory not mounted: {}", str),
+        }
+    }
 }
 
 static mut SB: SuperBlock = SuperBlock::new();
@@ -115,6 +224,7 @@ struct SuperBlock {
     logstart: u32,   // Block number of first log block
     inodestart: u32, // Block number of first inode block
     bmapstart: u32,  // Block number of first free map block
+    checksum: u32,   // CRC-32 of the fields above
 }
 
 impl SuperBlock {
@@ -128,6 +238,7 @@ impl SuperBlock {
             logstart: 0,
             inodestart: 0,
             bmapstart: 0,
+            checksum: 0,
         }
     }
 }
@@ -137,10 +248,89 @@ fn read_super_block(dev: u32) {
     let bp = bread(dev, 1);
     unsafe {
         ptr::copy(
-            bp.data.as_ptr() as *mut SuperBlock,
+            bp.as_ptr() as *const SuperBlock,
             &mut SB as *mut SuperBlock,
             1,
         );
     }
-    brelse(bp.dev, bp.blockno);
+    drop(bp);
+}
+
+/// Size of the root file system in blocks, 0 until init() has read its super block,
+/// the crash dump goes past it, see crashdump.rs
+pub fn fs_blocks() -> u32 {
+    unsafe { SB.size }
+}
+
+/// Call f with block blockno of dev, through the buffer cache
+pub fn with_block<R>(dev: u32, blockno: u32, f: impl FnOnce(&[u8; BSIZE]) -> R) -> R {
+    let bp = bread(dev, blockno);
+    f(&bp)
+}
+
+/// A bad checksum was found, nothing is written from then on, see bio.rs
+static READ_ONLY: AtomicBool = AtomicBool::new(false);
+
+pub fn read_only() -> bool {
+    READ_ONLY.load(Ordering::Relaxed)
+}
+
+/// The bytes of a metadata block of the root file system under its checksum,
+/// which is stored right after them, None for other blocks.
+/// The super block is always block 1, the others are known once it is read.
+fn summed_len(dev: u32, blockno: u32) -> Option<usize> {
+    let sb = unsafe { &SB };
+    if dev != root_dev() {
+        None
+    } else if blockno == 1 {
+        Some(mem::size_of::<SuperBlock>() - 4)
+    } else if sb.nlog > 0 && blockno == sb.logstart {
+        Some(mem::size_of::<LogHeader>() - 4)
+    } else if blockno >= sb.inodestart && blockno < sb.bmapstart {
+        Some(IPB * mem::size_of::<DInode>())
+    } else {
+        None
+    }
+}
+
+fn stored_sum(data: &[u8; BSIZE], len: usize) -> u32 {
+    let mut sum = [0u8; 4];
+    sum.copy_from_slice(&data[len..len + 4]);
+    u32::from_le_bytes(sum)
+}
+
+/// Check the checksum of block blockno after reading it from the disk, if it is a metadata block,
+/// a mismatch makes the file system read-only
+fn verify(dev: u32, blockno: u32, data: &[u8; BSIZE]) {
+    if let Some(len) = summed_len(dev, blockno) {
+        if crc32::crc32(&data[..len]) != stored_sum(data, len) {
+            println!("fs: bad checksum in block {} of dev {}, read-only from now on", blockno, dev);
+            READ_ONLY.store(true, Ordering::Relaxed);
+        }
+    }
+}
+
+/// Recompute the checksum of block blockno before writing it to the disk, if it is a metadata block
+fn seal(dev: u32, blockno: u32, data: &mut [u8; BSIZE]) {
+    if let Some(len) = summed_len(dev, blockno) {
+        let sum = crc32::crc32(&data[..len]).to_le_bytes();
+        data[len..len + 4].copy_from_slice(&sum);
+    }
+}
+
+#[cfg(feature = "unit_test")]
+pub mod tests {
+    use super::*;
+
+    /// The super block's checksum goes right after its fields
+    pub fn metadata_sums() {
+        let mut data = [0u8; BSIZE];
+        data[..4].copy_from_slice(&FSMAGIC.to_le_bytes());
+        assert_eq!(summed_len(root_dev(), 1), Some(32));
+        seal(root_dev(), 1, &mut data);
+        assert_eq!(stored_sum(&data, 32), crc32::crc32(&data[..32]));
+        assert_eq!(summed_len(root_dev() + 1, 1), None);
+        assert_eq!(mem::size_of::<LogHeader>() - 4, (1 + LOGSIZE) * 4);
+    }
+    crate::kernel_test!(metadata_sums);
 }
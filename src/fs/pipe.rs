@@ -0,0 +1,298 @@
+//! Pipes, a ring of PIPESIZE bytes between a read end and a write end, each a File
+//!
+//! A writer sleeps while the ring is full, a reader while it is empty,
+//! each wakes the other after making progress.
+//! Closing the write end makes the reader see the end of the file once the ring is drained,
+//! closing the read end fails the writer, both wake the one asleep on the other end.
+//! A pipe lives in a page of its own, freed once both ends are closed.
+//! A named pipe, a T_FIFO inode, holds one while it is open, see fifoattach(),
+//! any number of read and write ends, an open() of one end waits for the other, see fifowait().
+
+use core::ptr;
+
+use crate::mm::{kfree, Box, PageAligned};
+use crate::process::{my_proc, PROC_MANAGER};
+use crate::spinlock::SpinLock;
+
+use super::file::{filealloc, fileclose, File, FileType};
+
+const PIPESIZE: usize = 512;
+
+struct PipeState {
+    data: [u8; PIPESIZE],
+    /// bytes read and written so far, wrapping, the ring holds nwrite - nread of them
+    nread: usize,
+    nwrite: usize,
+    /// open read ends and write ends, one of each from pipealloc()
+    readers: usize,
+    writers: usize,
+    /// read ends and write ends ever opened, for a FIFO's open() to wait on
+    ropened: usize,
+    wopened: usize,
+}
+
+pub struct Pipe {
+    state: SpinLock<PipeState>,
+}
+
+impl PageAligned for Pipe {}
+
+/// A reader sleeps on nread, a writer on nwrite
+fn chan(field: &usize) -> usize {
+    field as *const usize as usize
+}
+
+/// A new pipe with readers and writers open ends
+fn alloc(readers: usize, writers: usize) -> Result<&'static Pipe, &'static str> {
+    let pipe = Box::<Pipe>::new().ok_or("pipe: out of memory")?.into_raw();
+    unsafe {
+        ptr::write(pipe, Pipe {
+            state: SpinLock::new(PipeState {
+                data: [0; PIPESIZE],
+                nread: 0,
+                nwrite: 0,
+                readers,
+                writers,
+                ropened: readers,
+                wopened: writers,
+            }, "pipe"),
+        });
+    }
+    Ok(unsafe { &*pipe })
+}
+
+/// Make a pipe, and return its read end and its write end
+pub fn pipealloc() -> Result<(&'static File, &'static File), &'static str> {
+    let pipe = alloc(1, 1)?;
+
+    let rf = match filealloc(FileType::Pipe { pipe }, true, false) {
+        Ok(rf) => rf,
+        Err(err) => {
+            unsafe { kfree(pipe as *const Pipe as *mut u8) };
+            return Err(err);
+        }
+    };
+    match filealloc(FileType::Pipe { pipe }, false, true) {
+        Ok(wf) => Ok((rf, wf)),
+        Err(err) => {
+            // the write end that never was, then the read end frees the pipe
+            pipeclose(pipe, false, true);
+            fileclose(rf);
+            Err(err)
+        }
+    }
+}
+
+/// Close the ends of pipe a File has, a read end if readable, a write end if writable.
+/// Return whether that was the last one, and the pipe is freed.
+pub fn pipeclose(pipe: &'static Pipe, readable: bool, writable: bool) -> bool {
+    let mut state = pipe.state.lock();
+    if writable {
+        state.writers -= 1;
+        unsafe { PROC_MANAGER.wakeup(chan(&state.nread)) };
+    }
+    if readable {
+        state.readers -= 1;
+        unsafe { PROC_MANAGER.wakeup(chan(&state.nwrite)) };
+    }
+    let free = state.readers == 0 && state.writers == 0;
+    drop(state);
+    if free {
+        unsafe { kfree(pipe as *const Pipe as *mut u8) };
+    }
+    free
+}
+
+/// Open the ends of the pipe of a FIFO, its inode locked, fifo its field,
+/// the first open makes the pipe.
+/// Return it, and how many of the other end had been opened, for fifowait().
+/// With nonblock, a write end alone fails at once if there is no reader.
+pub fn fifoattach(fifo: &mut Option<&'static Pipe>, readable: bool, writable: bool, nonblock: bool)
+    -> Result<(&'static Pipe, usize), &'static str>
+{
+    if nonblock && writable && !readable && fifo.is_none_or(|pipe| pipe.state.lock().readers == 0) {
+        return Err("open: no reader of the named pipe");
+    }
+    let pipe = match *fifo {
+        Some(pipe) => pipe,
+        None => alloc(0, 0)?,
+    };
+    *fifo = Some(pipe);
+    let mut state = pipe.state.lock();
+    if readable {
+        state.readers += 1;
+        state.ropened += 1;
+        unsafe { PROC_MANAGER.wakeup(chan(&state.ropened)) };
+    }
+    if writable {
+        state.writers += 1;
+        state.wopened += 1;
+        unsafe { PROC_MANAGER.wakeup(chan(&state.wopened)) };
+    }
+    let seen = if readable { state.wopened } else { state.ropened };
+    drop(state);
+    Ok((pipe, seen))
+}
+
+/// Wait for an end of the pipe of a FIFO, opened by fifoattach(), to have the other end open,
+/// one opened after seen, also if it is closed again by now, none for both ends.
+/// Fail if the process is killed meanwhile.
+pub fn fifowait(pipe: &Pipe, readable: bool, writable: bool, seen: usize) -> Result<(), &'static str> {
+    if readable && writable {
+        return Ok(());
+    }
+    let mut state = pipe.state.lock();
+    loop {
+        let (open, opened) = match readable {
+            true => (state.writers, &state.wopened),
+            false => (state.readers, &state.ropened),
+        };
+        if open > 0 || *opened != seen {
+            return Ok(());
+        }
+        let p = unsafe { my_proc() };
+        if p.killed {
+            return Err("killed");
+        }
+        let opened = chan(opened);
+        state = p.sleep(opened, state);
+    }
+}
+
+/// Write all of src into pipe, sleeping while it is full, or only what fits if nonblock,
+/// fail if the read end is closed, or the process killed, or it is full and nonblock,
+/// before any of it is written
+pub fn pipewrite(pipe: &Pipe, src: &[u8], nonblock: bool) -> Result<usize, &'static str> {
+    let mut state = pipe.state.lock();
+    let mut i = 0;
+    while i < src.len() {
+        if state.readers == 0 {
+            drop(state);
+            return if i == 0 { Err("pipe: read end closed") } else { Ok(i) };
+        }
+        if state.nwrite == state.nread.wrapping_add(PIPESIZE) {
+            // full, the reader is to make room
+            if nonblock {
+                unsafe { PROC_MANAGER.wakeup(chan(&state.nread)) };
+                drop(state);
+                return if i == 0 { Err("would block") } else { Ok(i) };
+            }
+            let p = unsafe { my_proc() };
+            if p.killed {
+                drop(state);
+                return if i == 0 { Err("killed") } else { Ok(i) };
+            }
+            unsafe { PROC_MANAGER.wakeup(chan(&state.nread)) };
+            let nwrite = chan(&state.nwrite);
+            state = p.sleep(nwrite, state);
+        } else {
+            let w = state.nwrite % PIPESIZE;
+            state.data[w] = src[i];
+            state.nwrite = state.nwrite.wrapping_add(1);
+            i += 1;
+        }
+    }
+    unsafe { PROC_MANAGER.wakeup(chan(&state.nread)) };
+    drop(state);
+    Ok(i)
+}
+
+/// Read what pipe holds into dst, sleeping while it is empty, failing then if nonblock,
+/// 0 once it is empty and the write end closed
+pub fn piperead(pipe: &Pipe, dst: &mut [u8], nonblock: bool) -> Result<usize, &'static str> {
+    let mut state = pipe.state.lock();
+    while state.nread == state.nwrite && state.writers > 0 {
+        if nonblock {
+            return Err("would block");
+        }
+        let p = unsafe { my_proc() };
+        if p.killed {
+            return Err("killed");
+        }
+        let nread = chan(&state.nread);
+        state = p.sleep(nread, state);
+    }
+    let mut n = 0;
+    while n < dst.len() && state.nread != state.nwrite {
+        dst[n] = state.data[state.nread % PIPESIZE];
+        state.nread = state.nread.wrapping_add(1);
+        n += 1;
+    }
+    unsafe { PROC_MANAGER.wakeup(chan(&state.nwrite)) };
+    drop(state);
+    Ok(n)
+}
+
+#[cfg(feature = "unit_test")]
+pub mod tests {
+    use super::super::file::{fileread, filewrite};
+    use super::*;
+
+    /// What goes into the write end comes out of the read end, then the end of the file,
+    /// nothing here fills or empties the pipe to sleep on it
+    pub fn pipe_ends() {
+        let (rf, wf) = pipealloc().expect("pipe_ends: no pipe");
+        assert_eq!(filewrite(wf, b"hello"), Ok(5));
+        assert!(filewrite(rf, b"x").is_err());
+        let mut buf = [0u8; 8];
+        assert_eq!(fileread(rf, &mut buf[..3]), Ok(3));
+        assert_eq!(&buf[..3], b"hel");
+        fileclose(wf);
+        assert_eq!(fileread(rf, &mut buf), Ok(2));
+        assert_eq!(&buf[..2], b"lo");
+        assert_eq!(fileread(rf, &mut buf), Ok(0));
+        fileclose(rf);
+
+        let (rf, wf) = pipealloc().expect("pipe_ends: no pipe");
+        fileclose(rf);
+        assert!(filewrite(wf, b"x").is_err());
+        fileclose(wf);
+    }
+    crate::kernel_test!(pipe_ends);
+
+    /// Nonblocking ends fail where they would sleep, a full pipe takes part of a write
+    pub fn pipe_nonblock() {
+        let (rf, wf) = pipealloc().expect("pipe_nonblock: no pipe");
+        rf.set_nonblock(true);
+        wf.set_nonblock(true);
+        let mut buf = [0u8; PIPESIZE + 3];
+        assert_eq!(fileread(rf, &mut buf), Err("would block"));
+        assert_eq!(filewrite(wf, &[1; PIPESIZE + 3]), Ok(PIPESIZE));
+        assert_eq!(filewrite(wf, b"x"), Err("would block"));
+        assert_eq!(fileread(rf, &mut buf), Ok(PIPESIZE));
+        assert!(buf[..PIPESIZE].iter().all(|c| *c == 1));
+        fileclose(wf);
+        assert_eq!(fileread(rf, &mut buf), Ok(0));
+        fileclose(rf);
+    }
+    crate::kernel_test!(pipe_nonblock);
+
+    /// The ends of a named pipe's pipe come and go, the last close frees it,
+    /// an end waits for the other only until one has been opened,
+    /// a nonblocking write end needs a reader
+    pub fn fifo_ends() {
+        let mut fifo = None;
+        assert!(fifoattach(&mut fifo, false, true, true).is_err());
+        assert!(fifo.is_none());
+        let (pipe, seen) = fifoattach(&mut fifo, true, false, false).expect("fifo_ends: no pipe");
+        assert_eq!(seen, 0);
+        let (same, wseen) = fifoattach(&mut fifo, false, true, true).expect("fifo_ends: no pipe");
+        assert!(ptr::eq(pipe, same));
+        assert_eq!(wseen, 1);
+        assert_eq!(fifowait(pipe, true, false, seen), Ok(()));
+        assert_eq!(fifowait(pipe, false, true, wseen), Ok(()));
+        assert_eq!(pipewrite(pipe, b"fifo", false), Ok(4));
+
+        // a writer that came and went still lets a reader opened before it through
+        assert!(!pipeclose(pipe, false, true));
+        assert_eq!(fifowait(pipe, true, false, seen), Ok(()));
+        let mut buf = [0u8; 8];
+        assert_eq!(piperead(pipe, &mut buf, false), Ok(4));
+        assert_eq!(piperead(pipe, &mut buf, false), Ok(0));
+        let (_, seen) = fifoattach(&mut fifo, true, true, false).expect("fifo_ends: no pipe");
+        assert_eq!(fifowait(pipe, true, true, seen), Ok(()));
+        assert!(!pipeclose(pipe, true, true));
+        assert!(pipeclose(pipe, true, false));
+    }
+    crate::kernel_test!(fifo_ends);
+}
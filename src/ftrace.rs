@@ -0,0 +1,133 @@
+//! Function-entry tracing, recording only with the ftrace feature
+//!
+//! A function that calls `ftrace!()` at its entry records
+//! (sequence number, tick, function) into the ring of the current hart.
+//! The sequence number orders the records of all harts,
+//! the tick is too coarse for that.
+//! Recording starts disabled, and is switched at runtime by set_enabled(),
+//! e.g., through the ftrace syscall.
+//! dump() prints the rings merged in order, also done on panic.
+//!
+//! Do not trace functions called with the tick lock held, i.e., clock_intr,
+//! or the spinlock itself.
+
+/// Record the entry of the enclosing function,
+/// expands to nothing without the ftrace feature
+#[macro_export]
+macro_rules! ftrace {
+    () => {
+        #[cfg(feature = "ftrace")]
+        {
+            fn f() {}
+            $crate::ftrace::record($crate::ftrace::enclosing(f));
+        }
+    };
+}
+
+#[cfg(feature = "ftrace")]
+pub use imp::*;
+
+#[cfg(feature = "ftrace")]
+mod imp {
+    use core::any::type_name;
+    use core::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+
+    use crate::consts::{NCPU, NFTRACE};
+    use crate::process::cpu_id;
+    use crate::spinlock::{pop_off, push_off};
+    use crate::trap::ticks;
+
+    #[derive(Clone, Copy)]
+    struct Record {
+        seq: usize,
+        tick: usize,
+        func: &'static str,
+    }
+
+    struct Ring {
+        records: [Record; NFTRACE],
+        // total records ever written, the write index is n % NFTRACE
+        n: usize,
+    }
+
+    static mut RINGS: [Ring; NCPU] = [const { Ring {
+        records: [Record { seq: 0, tick: 0, func: "" }; NFTRACE],
+        n: 0,
+    } }; NCPU];
+
+    static ENABLED: AtomicBool = AtomicBool::new(false);
+    static SEQ: AtomicUsize = AtomicUsize::new(0);
+
+    /// Path of the function enclosing f, which is a fn item named f
+    pub fn enclosing<T>(_f: T) -> &'static str {
+        let name = type_name::<T>();
+        name.strip_suffix("::f").unwrap_or(name)
+    }
+
+    pub fn record(func: &'static str) {
+        if !ENABLED.load(Ordering::Relaxed) {
+            return;
+        }
+        let tick = ticks();
+        // an interrupt on this hart must not record in the middle
+        push_off();
+        let ring = unsafe { &mut RINGS[cpu_id()] };
+        ring.records[ring.n % NFTRACE] = Record {
+            seq: SEQ.fetch_add(1, Ordering::Relaxed),
+            tick,
+            func,
+        };
+        ring.n += 1;
+        pop_off();
+    }
+
+    /// Turn recording on or off, turning it on clears the rings.
+    pub fn set_enabled(on: bool) {
+        ENABLED.store(false, Ordering::SeqCst);
+        if on {
+            for ring in unsafe { RINGS.iter_mut() } {
+                ring.n = 0;
+            }
+            ENABLED.store(true, Ordering::SeqCst);
+        }
+    }
+
+    /// Print the records of all harts, oldest first.
+    /// Recording is stopped first so the rings hold still.
+    pub fn dump() {
+        let was = ENABLED.swap(false, Ordering::SeqCst);
+        let rings = unsafe { &RINGS };
+
+        // index of the next record to print in each ring
+        let mut next = [0usize; NCPU];
+        for (hart, ring) in rings.iter().enumerate() {
+            next[hart] = ring.n.saturating_sub(NFTRACE);
+        }
+
+        println!("ftrace: seq tick hart function");
+        loop {
+            let mut oldest: Option<(usize, Record)> = None;
+            for (hart, ring) in rings.iter().enumerate() {
+                if next[hart] < ring.n {
+                    let r = ring.records[next[hart] % NFTRACE];
+                    if oldest.is_none_or(|(_, o)| r.seq < o.seq) {
+                        oldest = Some((hart, r));
+                    }
+                }
+            }
+            match oldest {
+                Some((hart, r)) => {
+                    println!("{:>8} {:>6} {} {}", r.seq, r.tick, hart, r.func);
+                    next[hart] += 1;
+                }
+                None => break,
+            }
+        }
+
+        ENABLED.store(was, Ordering::SeqCst);
+    }
+
+    pub fn enabled() -> bool {
+        ENABLED.load(Ordering::Relaxed)
+    }
+}
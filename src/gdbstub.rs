@@ -0,0 +1,537 @@
+//! GDB remote serial protocol stub, only built with the gdbstub feature
+//!
+//! It talks to gdb over a second ns16550 UART at UART1, polled,
+//! so it also works when the trap path itself is what is being debugged,
+//! and on hardware without qemu's gdbstub.
+//! The stub runs inside kerneltrap, on an ebreak in supervisor mode:
+//! - registers are the ones kernelvec saved, see trap::KernelFrame
+//! - memory is accessed through the kernel page table,
+//!   writes go to the physical page with paging off for a moment,
+//!   so breakpoints can be patched into the read-only kernel text
+//! - software breakpoints replace instructions with (c.)ebreak
+//! - single step puts temporary breakpoints at every possible next pc
+//!
+//! Only the trapping hart stops, the others keep running.
+//! gdb cannot interrupt a running kernel with ctrl-c,
+//! put a breakpoint first, e.g., the one at boot, see init().
+
+use core::arch::asm;
+use core::ptr;
+
+use crate::consts::{GDB_NBREAK, UART1};
+use crate::insn;
+use crate::mm::kvm_translate;
+use crate::register::satp;
+use crate::spinlock::SpinLock;
+use crate::trap::KernelFrame;
+
+/// size of the packet buffers, enough for a 'G' packet
+const BUF: usize = 1024;
+
+const RHR: usize = 0;
+const THR: usize = 0;
+const IER: usize = 1;
+const FCR: usize = 2;
+const LCR: usize = 3;
+const LSR: usize = 5;
+
+const LSR_RX_READY: u8 = 1 << 0;
+const LSR_TX_IDLE: u8 = 1 << 5;
+
+/// gdb's register numbers, x0-x31 then pc
+const NREG: usize = 33;
+const REG_SP: usize = 2;
+const REG_TP: usize = 4;
+const REG_PC: usize = 32;
+
+#[derive(Clone, Copy)]
+struct Breakpoint {
+    addr: usize,
+    // original instruction bytes, len is 0 for an empty slot
+    orig: [u8; 4],
+    len: usize,
+}
+
+impl Breakpoint {
+    const fn empty() -> Self {
+        Self { addr: 0, orig: [0; 4], len: 0 }
+    }
+}
+
+struct Stub {
+    rx: [u8; BUF],
+    tx: [u8; BUF],
+    breaks: [Breakpoint; GDB_NBREAK],
+    // temporary breakpoints of a single step
+    steps: [Breakpoint; 2],
+}
+
+static STUB: SpinLock<Stub> = SpinLock::new(Stub {
+    rx: [0; BUF],
+    tx: [0; BUF],
+    breaks: [Breakpoint::empty(); GDB_NBREAK],
+    steps: [Breakpoint::empty(); 2],
+}, "gdbstub");
+
+#[inline]
+fn read_reg(reg: usize) -> u8 {
+    unsafe { ptr::read_volatile((UART1.const_usize() + reg) as *const u8) }
+}
+
+#[inline]
+fn write_reg(reg: usize, value: u8) {
+    unsafe { ptr::write_volatile((UART1.const_usize() + reg) as *mut u8, value) }
+}
+
+fn getc() -> u8 {
+    while read_reg(LSR) & LSR_RX_READY == 0 {}
+    read_reg(RHR)
+}
+
+fn putc(c: u8) {
+    while read_reg(LSR) & LSR_TX_IDLE == 0 {}
+    write_reg(THR, c);
+}
+
+/// Set up UART1 like uartinit, but without interrupts,
+/// then wait for gdb at a breakpoint.
+pub fn init() {
+    write_reg(IER, 0x00);
+    write_reg(LCR, 0x80);
+    write_reg(0, 0x03);
+    write_reg(1, 0x00);
+    write_reg(LCR, 0x03);
+    write_reg(FCR, 0x07);
+
+    println!("gdbstub: waiting for gdb on uart1");
+    breakpoint();
+}
+
+/// Stop here and hand control to gdb
+#[inline(always)]
+pub fn breakpoint() {
+    unsafe { asm!("ebreak", options(nostack)); }
+}
+
+const HEX: &[u8; 16] = b"0123456789abcdef";
+
+fn unhex(c: u8) -> Option<usize> {
+    match c {
+        b'0'..=b'9' => Some((c - b'0') as usize),
+        b'a'..=b'f' => Some((c - b'a' + 10) as usize),
+        b'A'..=b'F' => Some((c - b'A' + 10) as usize),
+        _ => None,
+    }
+}
+
+/// Parse a hex number up to the first non-hex byte,
+/// return it and the rest
+fn parse_hex(s: &[u8]) -> Option<(usize, &[u8])> {
+    let mut value: usize = 0;
+    let mut n = 0;
+    while n < s.len() {
+        match unhex(s[n]) {
+            Some(d) => value = (value << 4) | d,
+            None => break,
+        }
+        n += 1;
+    }
+    if n == 0 { None } else { Some((value, &s[n..])) }
+}
+
+/// "addr,len" followed by the rest
+fn parse_addr_len(s: &[u8]) -> Option<(usize, usize, &[u8])> {
+    let (addr, s) = parse_hex(s)?;
+    if s.first() != Some(&b',') {
+        return None
+    }
+    let (len, s) = parse_hex(&s[1..])?;
+    Some((addr, len, s))
+}
+
+/// Receive one packet into buf, acking it, return its length
+fn recv_packet(buf: &mut [u8; BUF]) -> usize {
+    loop {
+        while getc() != b'$' {}
+
+        let mut n = 0;
+        let mut sum: u8 = 0;
+        loop {
+            let c = getc();
+            if c == b'#' {
+                break;
+            }
+            if n < BUF {
+                buf[n] = c;
+                n += 1;
+            }
+            sum = sum.wrapping_add(c);
+        }
+        let hi = unhex(getc());
+        let lo = unhex(getc());
+        match (hi, lo) {
+            (Some(hi), Some(lo)) if ((hi << 4) | lo) as u8 == sum && n < BUF => {
+                putc(b'+');
+                return n
+            }
+            _ => putc(b'-'),
+        }
+    }
+}
+
+/// Send buf as a packet until gdb acks it
+fn send_packet(buf: &[u8]) {
+    let sum = buf.iter().fold(0u8, |sum, c| sum.wrapping_add(*c));
+    loop {
+        putc(b'$');
+        for c in buf {
+            putc(*c);
+        }
+        putc(b'#');
+        putc(HEX[(sum >> 4) as usize]);
+        putc(HEX[(sum & 0xf) as usize]);
+        if getc() == b'+' {
+            return
+        }
+    }
+}
+
+/// Writes hex into the tx buffer
+struct Reply<'a> {
+    buf: &'a mut [u8; BUF],
+    n: usize,
+}
+
+impl<'a> Reply<'a> {
+    fn push(&mut self, c: u8) {
+        if self.n < BUF {
+            self.buf[self.n] = c;
+            self.n += 1;
+        }
+    }
+
+    fn str(&mut self, s: &[u8]) {
+        for c in s {
+            self.push(*c);
+        }
+    }
+
+    fn byte(&mut self, b: u8) {
+        self.push(HEX[(b >> 4) as usize]);
+        self.push(HEX[(b & 0xf) as usize]);
+    }
+
+    /// gdb wants registers in target byte order
+    fn word(&mut self, w: usize) {
+        for b in w.to_le_bytes().iter() {
+            self.byte(*b);
+        }
+    }
+}
+
+/// Parse a little-endian 64-bit register value
+fn parse_word(s: &[u8]) -> Option<usize> {
+    if s.len() < 16 {
+        return None
+    }
+    let mut bytes = [0u8; 8];
+    for (i, b) in bytes.iter_mut().enumerate() {
+        *b = ((unhex(s[2 * i])? << 4) | unhex(s[2 * i + 1])?) as u8;
+    }
+    Some(usize::from_le_bytes(bytes))
+}
+
+/// Registers as gdb sees them
+struct Regs<'a> {
+    frame: &'a mut KernelFrame,
+    pc: usize,
+}
+
+impl<'a> Regs<'a> {
+    fn get(&self, n: usize) -> usize {
+        match n {
+            0 => 0,
+            // kernelvec saved sp after making room for the frame
+            REG_SP => self.frame.regs[REG_SP - 1] + KernelFrame::SIZE,
+            REG_PC => self.pc,
+            n => self.frame.regs[n - 1],
+        }
+    }
+
+    fn set(&mut self, n: usize, value: usize) {
+        match n {
+            0 => {}
+            REG_SP => self.frame.regs[REG_SP - 1] = value - KernelFrame::SIZE,
+            // kernelvec does not restore tp, in case we moved CPUs
+            REG_TP => {}
+            REG_PC => self.pc = value,
+            n => self.frame.regs[n - 1] = value,
+        }
+    }
+}
+
+/// Read kernel memory, None if some byte is not mapped
+fn read_mem(addr: usize, dst: &mut [u8]) -> Option<()> {
+    for (i, b) in dst.iter_mut().enumerate() {
+        let va = addr.checked_add(i)?;
+        kvm_translate(va)?;
+        *b = unsafe { ptr::read_volatile(va as *const u8) };
+    }
+    Some(())
+}
+
+/// Write kernel memory through its physical address,
+/// with paging off, so that read-only kernel text can be patched.
+/// Interrupts must be off, the kernel and the current stack are identity mapped.
+fn write_mem(addr: usize, src: &[u8]) -> Option<()> {
+    for i in 0..src.len() {
+        kvm_translate(addr.checked_add(i)?)?;
+    }
+    let saved = satp::read();
+    satp::write(0);
+    unsafe { asm!("sfence.vma zero, zero", options(nostack)); }
+    for (i, b) in src.iter().enumerate() {
+        let pa = kvm_translate(addr + i).unwrap();
+        unsafe { ptr::write_volatile(pa as *mut u8, *b); }
+    }
+    satp::write(saved);
+    unsafe {
+        asm!("sfence.vma zero, zero", options(nostack));
+        // other harts may still run the stale instructions until their next fence.i
+        asm!("fence.i", options(nostack));
+    }
+    Some(())
+}
+
+fn read_inst(pc: usize) -> Option<u32> {
+    let mut bytes = [0u8; 4];
+    read_mem(pc, &mut bytes[..2])?;
+    if insn::inst_len(bytes[0] as u32) == 4 {
+        read_mem(pc + 2, &mut bytes[2..])?;
+    }
+    Some(u32::from_le_bytes(bytes))
+}
+
+/// Patch an ebreak of len bytes at addr, return the original bytes
+fn insert(addr: usize, len: usize) -> Option<Breakpoint> {
+    let mut bp = Breakpoint { addr, orig: [0; 4], len };
+    read_mem(addr, &mut bp.orig[..len])?;
+    match len {
+        2 => write_mem(addr, &insn::C_EBREAK.to_le_bytes())?,
+        4 => write_mem(addr, &insn::EBREAK.to_le_bytes())?,
+        _ => return None,
+    }
+    Some(bp)
+}
+
+fn remove(bp: &mut Breakpoint) {
+    if bp.len != 0 {
+        let _ = write_mem(bp.addr, &bp.orig[..bp.len]);
+        bp.len = 0;
+    }
+}
+
+/// Put temporary breakpoints at the next pcs, false if it is not possible
+fn step(steps: &mut [Breakpoint; 2], regs: &Regs) -> bool {
+    let pc = regs.pc;
+    let inst = match read_inst(pc) {
+        Some(inst) => inst,
+        None => return false,
+    };
+    let mut placed = 0;
+    for next in insn::next_pcs(|n| regs.get(n), pc, inst).iter().flatten() {
+        if steps[..placed].iter().any(|bp| bp.addr == *next) {
+            continue;
+        }
+        let len = match read_inst(*next) {
+            Some(inst) => insn::inst_len(inst),
+            None => continue,
+        };
+        if let Some(bp) = insert(*next, len) {
+            steps[placed] = bp;
+            placed += 1;
+        }
+    }
+    placed > 0
+}
+
+/// Handle 'Z0'/'z0', software breakpoints
+fn set_breakpoint(breaks: &mut [Breakpoint; GDB_NBREAK], args: &[u8], on: bool) -> bool {
+    let (addr, kind, _) = match parse_addr_len(args) {
+        Some(parsed) => parsed,
+        None => return false,
+    };
+    if on {
+        if breaks.iter().any(|bp| bp.len != 0 && bp.addr == addr) {
+            return true
+        }
+        let slot = match breaks.iter().position(|bp| bp.len == 0) {
+            Some(slot) => slot,
+            None => return false,
+        };
+        match insert(addr, kind) {
+            Some(bp) => {
+                breaks[slot] = bp;
+                true
+            }
+            None => false,
+        }
+    } else {
+        for bp in breaks.iter_mut().filter(|bp| bp.len != 0 && bp.addr == addr) {
+            remove(bp);
+        }
+        true
+    }
+}
+
+/// Handle 'M', all bytes are checked before any is written
+fn write_packet_mem(args: &[u8]) -> Option<()> {
+    let (addr, len, rest) = parse_addr_len(args)?;
+    if rest.first() != Some(&b':') || rest.len() < 1 + 2 * len {
+        return None
+    }
+    let data = &rest[1..1 + 2 * len];
+    if !data.iter().all(|c| unhex(*c).is_some()) {
+        return None
+    }
+    for i in 0..len {
+        let b = ((unhex(data[2 * i])? << 4) | unhex(data[2 * i + 1])?) as u8;
+        write_mem(addr + i, &[b])?;
+    }
+    Some(())
+}
+
+/// Called by kerneltrap on an ebreak in supervisor mode.
+/// Talk to gdb until it resumes, return the pc to resume at.
+/// The packet buffers live in STUB, the kernel stack is only a page.
+pub fn trap(frame: &mut KernelFrame, pc: usize) -> usize {
+    let mut guard = STUB.lock();
+    let Stub { rx, tx, breaks, steps } = &mut *guard;
+
+    // end of a single step
+    for bp in steps.iter_mut() {
+        remove(bp);
+    }
+
+    // an ebreak compiled into the kernel, e.g., breakpoint(),
+    // is stepped over when gdb resumes without moving the pc
+    let known = breaks.iter().any(|bp| bp.len != 0 && bp.addr == pc);
+    let skip = match read_inst(pc) {
+        Some(inst) if !known => insn::inst_len(inst),
+        _ => 0,
+    };
+
+    let mut regs = Regs { frame, pc };
+    send_packet(b"S05");
+    loop {
+        let len = recv_packet(rx);
+        let packet = &rx[..len];
+        let args = if len > 0 { &packet[1..] } else { packet };
+        let mut reply = Reply { buf: tx, n: 0 };
+
+        match packet.first() {
+            Some(b'?') => reply.str(b"S05"),
+            Some(b'g') => {
+                for r in 0..NREG {
+                    reply.word(regs.get(r));
+                }
+            }
+            Some(b'G') => {
+                for r in 0..NREG {
+                    if let Some(value) = args.get(16 * r..).and_then(parse_word) {
+                        regs.set(r, value);
+                    }
+                }
+                reply.str(b"OK");
+            }
+            Some(b'p') => match parse_hex(args) {
+                Some((r, _)) if r < NREG => reply.word(regs.get(r)),
+                _ => reply.str(b"E01"),
+            },
+            Some(b'P') => match parse_hex(args) {
+                Some((r, rest)) if r < NREG && rest.first() == Some(&b'=') => match parse_word(&rest[1..]) {
+                    Some(value) => {
+                        regs.set(r, value);
+                        reply.str(b"OK");
+                    }
+                    None => reply.str(b"E01"),
+                },
+                _ => reply.str(b"E01"),
+            },
+            Some(b'm') => match parse_addr_len(args) {
+                Some((addr, len, _)) => {
+                    let len = len.min(BUF / 2 - 2);
+                    let mut ok = true;
+                    for i in 0..len {
+                        let mut b = [0u8; 1];
+                        if read_mem(addr.wrapping_add(i), &mut b).is_none() {
+                            ok = false;
+                            break;
+                        }
+                        reply.byte(b[0]);
+                    }
+                    if !ok {
+                        reply.n = 0;
+                        reply.str(b"E14");
+                    }
+                }
+                None => reply.str(b"E01"),
+            },
+            Some(b'M') => match write_packet_mem(args) {
+                Some(()) => reply.str(b"OK"),
+                None => reply.str(b"E14"),
+            },
+            Some(b'Z') | Some(b'z') if args.starts_with(b"0,") => {
+                match set_breakpoint(breaks, &args[2..], packet[0] == b'Z') {
+                    true => reply.str(b"OK"),
+                    false => reply.str(b"E01"),
+                }
+            }
+            Some(b'c') => {
+                if let Some((addr, _)) = parse_hex(args) {
+                    regs.pc = addr;
+                }
+                break;
+            }
+            Some(b's') => {
+                if let Some((addr, _)) = parse_hex(args) {
+                    regs.pc = addr;
+                }
+                if regs.pc == pc {
+                    regs.pc += skip;
+                }
+                if step(steps, &regs) {
+                    // the temporary breakpoints stop us again
+                    return regs.pc
+                }
+                reply.str(b"E01");
+            }
+            // detach, also removing all breakpoints
+            Some(b'D') => {
+                for bp in breaks.iter_mut() {
+                    remove(bp);
+                }
+                reply.str(b"OK");
+                send_packet(&reply.buf[..reply.n]);
+                break;
+            }
+            // kill, just let the kernel go on
+            Some(b'k') => break,
+            Some(b'q') if args.starts_with(b"Supported") => {
+                reply.str(b"PacketSize=3e8;swbreak+");
+            }
+            Some(b'q') if args.starts_with(b"Attached") => reply.str(b"1"),
+            Some(b'q') if args == b"C" => reply.str(b"QC0"),
+            Some(b'H') => reply.str(b"OK"),
+            // empty reply for anything unsupported
+            _ => {}
+        }
+        send_packet(&reply.buf[..reply.n]);
+    }
+
+    // resuming at the ebreak itself would trap again right away
+    if regs.pc == pc {
+        regs.pc += skip;
+    }
+    regs.pc
+}
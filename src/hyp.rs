@@ -0,0 +1,381 @@
+//! Hypervisor, experimental, only with the hypervisor feature
+//!
+//! Runs the tiny guest at guest_start in asm/hyp.S in virtual supervisor mode,
+//! on harts with the H extension, e.g., qemu -cpu rv64,h=true.
+//! Refer to the RISC-V privileged specification (v1.12 or later), chapter 8.
+//!
+//! - its memory is GUEST_PAGES kalloc'd pages at GUEST_BASE in guest physical space,
+//!   mapped by the Sv39x4 second-stage page table in hgatp,
+//! - its ecalls are SBI calls, for the console, the timer and shutting down,
+//! - its CSR accesses that trap, i.e., reading time, are emulated,
+//! - its timer interrupt is injected through hvip once its deadline has passed.
+//!
+//! The guest runs on the calling process's kernel stack, see sys_hvguest, with interrupts off.
+//! It leaves whenever the host takes an interrupt, which is then handled as usual,
+//! possibly running other processes on the hart in the meantime.
+//! There is one second-stage root, so one guest at a time.
+
+use core::convert::TryFrom;
+use core::ptr;
+use core::sync::atomic::{AtomicBool, Ordering};
+
+use crate::consts::{PGSHIFT, PGSIZE};
+use crate::dtb;
+use crate::mm::{kalloc, kfree, PageTable, PhysAddr, PteFlag, VirtAddr};
+use crate::register::{clint, scause, sepc, sstatus, stval, stvec};
+use crate::register::hyp::{self, hcounteren, hedeleg, hgatp, hideleg, hstatus, htimedelta, htval, hvip};
+use crate::register::hyp::{vsatp, vscause, vsepc, vsie, vsscratch, vsstatus, vstval, vstvec};
+use crate::string;
+
+/// where the guest's memory and its entry are, like qemu's virt machine
+const GUEST_BASE: usize = 0x80000000;
+const GUEST_PAGES: usize = 4;
+
+/// traps out of the guest, in scause
+const INTERRUPT: usize = 1 << 63;
+const ILLEGAL_INSTRUCTION: usize = 2;
+const VS_ECALL: usize = 10;
+const INST_GUEST_PAGE_FAULT: usize = 20;
+const LOAD_GUEST_PAGE_FAULT: usize = 21;
+const VIRTUAL_INSTRUCTION: usize = 22;
+const STORE_GUEST_PAGE_FAULT: usize = 23;
+
+/// SBI extensions served to the guest
+const EID_LEGACY_SET_TIMER: usize = 0x00;
+const EID_CONSOLE_PUTCHAR: usize = 0x01;
+const EID_LEGACY_SHUTDOWN: usize = 0x08;
+const EID_TIME: usize = 0x54494d45; // "TIME"
+const EID_SRST: usize = 0x53525354; // "SRST"
+const SBI_ERR_NOT_SUPPORTED: isize = -2;
+
+const CSR_TIME: usize = 0xc01;
+
+/// The Sv39x4 root is four normal ones, aligned to 16KB.
+/// Guest physical addresses are kept below MAXVA like virtual ones,
+/// so only the first quarter is used, as an ordinary PageTable.
+#[repr(C, align(16384))]
+struct Root([PageTable; 4]);
+
+static mut ROOT: Root = Root([const { PageTable::empty() }; 4]);
+static BUSY: AtomicBool = AtomicBool::new(false);
+
+/// The guest's x1-x31, at the offsets hyp.S uses,
+/// x[0] keeps the host's sp while the guest runs
+#[repr(C)]
+struct GuestRegs {
+    x: [usize; 32],
+}
+
+/// The guest's virtual supervisor CSRs,
+/// kept across exits, since the process may move to another hart
+struct VsState {
+    status: usize,
+    ie: usize,
+    tvec: usize,
+    scratch: usize,
+    epc: usize,
+    cause: usize,
+    tval: usize,
+    atp: usize,
+    /// interrupts injected in the guest
+    hvip: usize,
+}
+
+impl VsState {
+    const fn new() -> Self {
+        Self {
+            status: 0,
+            ie: 0,
+            tvec: 0,
+            scratch: 0,
+            epc: 0,
+            cause: 0,
+            tval: 0,
+            atp: 0,
+            hvip: 0,
+        }
+    }
+
+    fn save(&mut self) {
+        self.status = vsstatus::read();
+        self.ie = vsie::read();
+        self.tvec = vstvec::read();
+        self.scratch = vsscratch::read();
+        self.epc = vsepc::read();
+        self.cause = vscause::read();
+        self.tval = vstval::read();
+        self.atp = vsatp::read();
+    }
+
+    fn restore(&self) {
+        vsstatus::write(self.status);
+        vsie::write(self.ie);
+        vstvec::write(self.tvec);
+        vsscratch::write(self.scratch);
+        vsepc::write(self.epc);
+        vscause::write(self.cause);
+        vstval::write(self.tval);
+        vsatp::write(self.atp);
+        hvip::write(self.hvip);
+    }
+}
+
+struct Guest {
+    regs: GuestRegs,
+    pc: usize,
+    vs: VsState,
+    /// host physical address of each page of the guest's memory
+    pages: [usize; GUEST_PAGES],
+    /// time the guest asked for its next timer interrupt at
+    deadline: Option<u64>,
+}
+
+/// Run the built-in guest until it shuts down, and return the reason it gave.
+pub fn run() -> Result<usize, &'static str> {
+    if !dtb::has_extension(b'h') {
+        return Err("no hypervisor extension");
+    }
+    if BUSY.swap(true, Ordering::Acquire) {
+        return Err("a guest is running already");
+    }
+    let result = unsafe {
+        let root = &mut ROOT.0[0];
+        match Guest::new(root) {
+            Ok(mut guest) => {
+                let result = guest.run(root);
+                guest.free(root, GUEST_PAGES);
+                result
+            }
+            Err(str) => Err(str),
+        }
+    };
+    BUSY.store(false, Ordering::Release);
+    result
+}
+
+impl Guest {
+    /// Allocate and map the guest's memory, with its program copied to the start,
+    /// where it begins like under a firmware, with its hart id in a0 and no device tree in a1.
+    unsafe fn new(root: &mut PageTable) -> Result<Self, &'static str> {
+        extern "C" {
+            fn guest_start();
+            fn guest_end();
+        }
+
+        let mut guest = Guest {
+            regs: GuestRegs { x: [0; 32] },
+            pc: GUEST_BASE,
+            vs: VsState::new(),
+            pages: [0; GUEST_PAGES],
+            deadline: None,
+        };
+        for i in 0..GUEST_PAGES {
+            let page = match kalloc() {
+                Some(page) => page,
+                None => {
+                    guest.free(root, i);
+                    return Err("out of memory");
+                }
+            };
+            string::memset(page, 0, PGSIZE);
+            if let Err(str) = root.map_pages(
+                VirtAddr::try_from(GUEST_BASE + i * PGSIZE).unwrap(),
+                PGSIZE,
+                PhysAddr::try_from(page as usize).unwrap(),
+                // second-stage leaves must be user pages
+                PteFlag::R | PteFlag::W | PteFlag::X | PteFlag::U | PteFlag::A | PteFlag::D,
+            ) {
+                kfree(page);
+                guest.free(root, i);
+                return Err(str);
+            }
+            guest.pages[i] = page as usize;
+        }
+
+        let start = guest_start as *const () as usize;
+        let size = guest_end as *const () as usize - start;
+        assert!(size <= PGSIZE, "hyp: guest program too big");
+        string::memcpy(guest.pages[0] as *mut u8, start as *const u8, size);
+        guest.regs.x[2] = GUEST_BASE + GUEST_PAGES * PGSIZE;
+
+        Ok(guest)
+    }
+
+    /// Unmap and free the first npages pages of the guest's memory,
+    /// and the second-stage page-table pages
+    unsafe fn free(&mut self, root: &mut PageTable, npages: usize) {
+        root.unmap_pages(VirtAddr::try_from(GUEST_BASE).unwrap(), npages, true)
+            .expect("hyp: guest memory not mapped");
+        root.free_walk();
+    }
+
+    /// Enter the guest over and over, handling each trap out of it,
+    /// until it shuts down or does something it may not.
+    unsafe fn run(&mut self, root: &PageTable) -> Result<usize, &'static str> {
+        loop {
+            if let Some(deadline) = self.deadline {
+                if clint::read_mtime() >= deadline {
+                    self.vs.hvip |= hyp::VSTIP;
+                    self.deadline = None;
+                }
+            }
+
+            let (cause, tval, gpa) = self.enter(root);
+            match cause {
+                // the host has taken it already, right after leaving the guest
+                c if c & INTERRUPT != 0 => {}
+                VS_ECALL => {
+                    if let Some(reason) = self.sbi_call() {
+                        return Ok(reason);
+                    }
+                    self.pc += 4;
+                }
+                ILLEGAL_INSTRUCTION | VIRTUAL_INSTRUCTION => self.emulate()?,
+                INST_GUEST_PAGE_FAULT | LOAD_GUEST_PAGE_FAULT | STORE_GUEST_PAGE_FAULT => {
+                    println!("hyp: guest page fault at {:#x}, pc={:#x}", gpa | tval & 3, self.pc);
+                    return Err("guest page fault");
+                }
+                _ => {
+                    println!("hyp: guest trap, scause={:#x}, stval={:#x}, pc={:#x}", cause, tval, self.pc);
+                    return Err("unexpected guest trap");
+                }
+            }
+        }
+    }
+
+    /// Run the guest until it traps out,
+    /// return scause, stval and the guest physical address of a guest page fault.
+    unsafe fn enter(&mut self, root: &PageTable) -> (usize, usize, usize) {
+        extern "C" {
+            fn hyp_enter(regs: *mut GuestRegs);
+            fn hyp_exit();
+            fn kernelvec();
+        }
+
+        sstatus::intr_off();
+
+        // traps and interrupts of the guest's own stay with the host,
+        // except the virtual supervisor interrupts
+        hedeleg::write(0);
+        hideleg::write(hyp::VSSIP | hyp::VSTIP | hyp::VSEIP);
+        hcounteren::write(0);
+        htimedelta::write(0);
+        hgatp::write(hyp::HGATP_SV39X4 | (root as *const PageTable as usize) >> PGSHIFT);
+        hyp::hfence_gvma();
+        self.vs.restore();
+
+        // sret to the guest's supervisor mode
+        hstatus::write(hstatus::read() | hyp::HSTATUS_SPV);
+        sstatus::guest_ret_prepare();
+        sepc::write(self.pc);
+        stvec::write(hyp_exit as *const () as usize);
+
+        hyp_enter(&mut self.regs);
+
+        stvec::write(kernelvec as *const () as usize);
+        let exit = (scause::read(), stval::read(), htval::read() << 2);
+        self.pc = sepc::read();
+        self.vs.save();
+
+        // an interrupt that made the guest exit is taken here, by kernelvec
+        sstatus::intr_on();
+        exit
+    }
+
+    /// Serve the guest's SBI call, a7 is the extension and a6 the function.
+    /// Return the reason if the guest shuts down.
+    fn sbi_call(&mut self) -> Option<usize> {
+        let x = &mut self.regs.x;
+        let (error, value): (isize, usize) = match (x[17], x[16]) {
+            (EID_CONSOLE_PUTCHAR, _) => {
+                print!("{}", x[10] as u8 as char);
+                // legacy calls only return a0
+                x[10] = 0;
+                return None;
+            }
+            (EID_LEGACY_SET_TIMER, _) | (EID_TIME, 0) => {
+                self.deadline = Some(x[10] as u64);
+                self.vs.hvip &= !hyp::VSTIP;
+                if x[17] == EID_LEGACY_SET_TIMER {
+                    x[10] = 0;
+                    return None;
+                }
+                (0, 0)
+            }
+            (EID_LEGACY_SHUTDOWN, _) => return Some(0),
+            (EID_SRST, 0) => return Some(x[11]),
+            _ => (SBI_ERR_NOT_SUPPORTED, 0),
+        };
+        x[10] = error as usize;
+        x[11] = value;
+        None
+    }
+
+    /// Emulate the CSR read at pc that trapped, only time is readable.
+    fn emulate(&mut self) -> Result<(), &'static str> {
+        let inst = self.read_inst(self.pc).ok_or("guest pc out of its memory")?;
+        let value = match decode_csr_read(inst) {
+            Some((CSR_TIME, rd)) => Some((unsafe { clint::read_mtime() } as usize, rd)),
+            _ => None,
+        };
+        match value {
+            Some((value, rd)) => {
+                if rd != 0 {
+                    self.regs.x[rd] = value;
+                }
+                self.pc += 4;
+                Ok(())
+            }
+            None => {
+                println!("hyp: guest instruction {:#x} not emulated, pc={:#x}", inst, self.pc);
+                Err("guest instruction not emulated")
+            }
+        }
+    }
+
+    /// Read the instruction at guest physical address gpa, as two halves,
+    /// since with compressed instructions it may straddle a page
+    fn read_inst(&self, gpa: usize) -> Option<u32> {
+        let low = unsafe { ptr::read_unaligned(self.host_addr(gpa)? as *const u16) };
+        let high = unsafe { ptr::read_unaligned(self.host_addr(gpa + 2)? as *const u16) };
+        Some(low as u32 | (high as u32) << 16)
+    }
+
+    fn host_addr(&self, gpa: usize) -> Option<usize> {
+        let offset = gpa.checked_sub(GUEST_BASE)?;
+        let page = *self.pages.get(offset / PGSIZE)?;
+        Some(page + offset % PGSIZE)
+    }
+}
+
+/// Decode csrrs rd, csr, x0, i.e., csrr, the only CSR access emulated,
+/// return the csr and rd
+fn decode_csr_read(inst: u32) -> Option<(usize, usize)> {
+    let opcode = inst & 0x7f;
+    let funct3 = (inst >> 12) & 0x7;
+    let rs1 = (inst >> 15) & 0x1f;
+    if opcode == 0x73 && funct3 == 2 && rs1 == 0 {
+        Some(((inst >> 20) as usize, ((inst >> 7) & 0x1f) as usize))
+    } else {
+        None
+    }
+}
+
+#[cfg(feature = "unit_test")]
+pub mod tests {
+    use super::*;
+
+    pub fn csr_decode() {
+        // csrr a0, time
+        assert_eq!(decode_csr_read(0xc0102573), Some((CSR_TIME, 10)));
+        // csrr zero, time
+        assert_eq!(decode_csr_read(0xc0102073), Some((CSR_TIME, 0)));
+        // csrs sie, t0 writes too
+        assert_eq!(decode_csr_read(0x1042a073), None);
+        // csrw stvec, t0
+        assert_eq!(decode_csr_read(0x10529073), None);
+        // ecall
+        assert_eq!(decode_csr_read(0x00000073), None);
+    }
+    crate::kernel_test!(csr_decode);
+}
@@ -0,0 +1,94 @@
+//! RISC-V instruction decoding shared by the debuggers,
+//! the gdb stub in the kernel and ptrace in user space:
+//! a single step puts temporary breakpoints at every pc
+//! the instruction may continue at, there being no step flag in supervisor mode.
+
+pub const EBREAK: u32 = 0x00100073;
+pub const C_EBREAK: u16 = 0x9002;
+
+/// Length of the instruction starting with the 16-bit parcel inst
+pub fn inst_len(inst: u32) -> usize {
+    if inst & 0b11 == 0b11 { 4 } else { 2 }
+}
+
+/// Sign-extend the low bits of value
+fn sext(value: u32, bits: u32) -> usize {
+    (((value as i32) << (32 - bits)) >> (32 - bits)) as isize as usize
+}
+
+/// Every pc the instruction at pc may continue at,
+/// reg gives the value of register xN
+pub fn next_pcs(reg: impl Fn(usize) -> usize, pc: usize, inst: u32) -> [Option<usize>; 2] {
+    let bit = |n: u32| (inst >> n) & 1;
+    let bits = |hi: u32, lo: u32| (inst >> lo) & ((1 << (hi - lo + 1)) - 1);
+
+    if inst_len(inst) == 4 {
+        let fall = pc.wrapping_add(4);
+        match inst & 0x7f {
+            // jal
+            0x6f => {
+                let imm = (bit(31) << 20) | (bits(19, 12) << 12) | (bit(20) << 11) | (bits(30, 21) << 1);
+                [Some(pc.wrapping_add(sext(imm, 21))), None]
+            }
+            // jalr
+            0x67 => {
+                let rs1 = bits(19, 15) as usize;
+                let imm = sext(bits(31, 20), 12);
+                [Some(reg(rs1).wrapping_add(imm) & !1), None]
+            }
+            // branches
+            0x63 => {
+                let imm = (bit(31) << 12) | (bit(7) << 11) | (bits(30, 25) << 5) | (bits(11, 8) << 1);
+                [Some(fall), Some(pc.wrapping_add(sext(imm, 13)))]
+            }
+            _ => [Some(fall), None],
+        }
+    } else {
+        let fall = pc.wrapping_add(2);
+        match (inst & 0b11, bits(15, 13)) {
+            // c.j
+            (0b01, 0b101) => {
+                let imm = (bit(12) << 11) | (bit(8) << 10) | (bits(10, 9) << 8) | (bit(6) << 7)
+                    | (bit(7) << 6) | (bit(2) << 5) | (bit(11) << 4) | (bits(5, 3) << 1);
+                [Some(pc.wrapping_add(sext(imm, 12))), None]
+            }
+            // c.beqz, c.bnez
+            (0b01, 0b110) | (0b01, 0b111) => {
+                let imm = (bit(12) << 8) | (bits(6, 5) << 6) | (bit(2) << 5)
+                    | (bits(11, 10) << 3) | (bits(4, 3) << 1);
+                [Some(fall), Some(pc.wrapping_add(sext(imm, 9)))]
+            }
+            // c.jr, c.jalr
+            (0b10, 0b100) if bits(6, 2) == 0 && bits(11, 7) != 0 => {
+                [Some(reg(bits(11, 7) as usize)), None]
+            }
+            _ => [Some(fall), None],
+        }
+    }
+}
+
+#[cfg(feature = "unit_test")]
+pub mod tests {
+    use super::*;
+
+    /// One instruction of each kind next_pcs knows
+    pub fn step_targets() {
+        let pc = 0x1000;
+        let reg = |n: usize| n * 0x100;
+        // addi a0, a0, 1 and c.addi a0, 1 fall through
+        assert_eq!(next_pcs(reg, pc, 0x00150513), [Some(pc + 4), None]);
+        assert_eq!(next_pcs(reg, pc, 0x0505), [Some(pc + 2), None]);
+        // jal ra, -16
+        assert_eq!(next_pcs(reg, pc, 0xff1ff0ef), [Some(pc - 16), None]);
+        // jalr ra, 8(a1), and jr a1
+        assert_eq!(next_pcs(reg, pc, 0x008580e7), [Some(0xb08), None]);
+        assert_eq!(next_pcs(reg, pc, 0x8582), [Some(0xb00), None]);
+        // beq a0, a1, 32, and c.beqz a0, -4
+        assert_eq!(next_pcs(reg, pc, 0x02b50063), [Some(pc + 4), Some(pc + 32)]);
+        assert_eq!(next_pcs(reg, pc, 0xdd75), [Some(pc + 2), Some(pc - 4)]);
+        // c.j 6
+        assert_eq!(next_pcs(reg, pc, 0xa019), [Some(pc + 6), None]);
+        assert_eq!((inst_len(EBREAK), inst_len(C_EBREAK as u32)), (4, 2));
+    }
+    crate::kernel_test!(step_targets);
+}
@@ -0,0 +1,97 @@
+//! Kernel message ring buffer
+//!
+//! Every byte written by print!/println! is also recorded here,
+//! with each line prefixed by the tick count and the hart id,
+//! so early-boot messages that scrolled off the console can still be read,
+//! e.g., by the dmesg syscall.
+//!
+//! The ring itself has no lock, it lives inside printf's Pr,
+//! and is protected by the same spinlock that serializes console output.
+
+use core::cmp::min;
+use core::fmt;
+
+use crate::consts::KMSG_BUF;
+
+pub struct Kmsg {
+    buf: [u8; KMSG_BUF],
+    // total bytes ever written,
+    // the write index is w % KMSG_BUF
+    w: usize,
+    line_start: bool,
+    // prefix for the next line
+    ticks: usize,
+    hart: usize,
+}
+
+impl Kmsg {
+    pub const fn new() -> Self {
+        Self {
+            buf: [0; KMSG_BUF],
+            w: 0,
+            line_start: true,
+            ticks: 0,
+            hart: 0,
+        }
+    }
+
+    #[inline]
+    fn push(&mut self, c: u8) {
+        self.buf[self.w % KMSG_BUF] = c;
+        self.w += 1;
+    }
+
+    /// Set the tick count and hart id used to prefix following lines
+    pub fn stamp(&mut self, ticks: usize, hart: usize) {
+        self.ticks = ticks;
+        self.hart = hart;
+    }
+
+    /// Record a byte, prefixing it with "[ticks hartN] "
+    /// if it is the first byte of a line.
+    pub fn putc(&mut self, c: u8) {
+        if self.line_start {
+            self.line_start = false;
+            let (ticks, hart) = (self.ticks, self.hart);
+            let _ = fmt::write(&mut Raw(self), format_args!("[{:>8} hart{}] ", ticks, hart));
+        }
+        self.push(c);
+        if c == b'\n' {
+            self.line_start = true;
+        }
+    }
+
+    /// Sequence number of the next byte
+    pub fn seq(&self) -> usize {
+        self.w
+    }
+
+    /// Sequence number of the oldest byte still in the ring
+    fn first_seq(&self) -> usize {
+        self.w.saturating_sub(KMSG_BUF)
+    }
+
+    /// Copy bytes starting at sequence number seq into dst.
+    /// Bytes that have already been overwritten are skipped.
+    /// Return (bytes copied, sequence number to continue from).
+    pub fn read(&self, seq: usize, dst: &mut [u8]) -> (usize, usize) {
+        let seq = if seq < self.first_seq() { self.first_seq() } else { seq };
+        let n = min(dst.len(), self.w.saturating_sub(seq));
+        for (i, b) in dst[..n].iter_mut().enumerate() {
+            *b = self.buf[(seq + i) % KMSG_BUF];
+        }
+        (n, seq + n)
+    }
+}
+
+/// Write prefixes into the ring without recursing into line handling
+struct Raw<'a>(&'a mut Kmsg);
+
+impl<'a> fmt::Write for Raw<'a> {
+    fn write_str(&mut self, s: &str) -> fmt::Result {
+        for byte in s.bytes() {
+            self.0.push(byte);
+        }
+        Ok(())
+    }
+}
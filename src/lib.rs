@@ -1,52 +1,75 @@
 #![no_std]
-#![feature(llvm_asm)]
-#![feature(const_fn)]
-#![feature(const_in_array_repeat_expressions)]
-#![feature(global_asm)]
-#![feature(ptr_internals)]
 #![allow(dead_code)]
+// kernel globals are static mut, each guarded by its own lock or only touched by its hart
+#![allow(static_mut_refs)]
 
 #[macro_use]
 extern crate bitflags;
 
+use core::arch::global_asm;
+
+#[cfg(all(feature = "fu740", feature = "gdbstub"))]
+compile_error!("the gdb stub drives an ns16550 uart, the FU740 has SiFive ones");
+
+#[cfg(not(feature = "sbi"))]
 global_asm!(include_str!("asm/entry.S"));
+#[cfg(feature = "sbi")]
+global_asm!(include_str!("asm/entry_sbi.S"));
 global_asm!(include_str!("asm/kernelvec.S"));
 global_asm!(include_str!("asm/swtch.S"));
 global_asm!(include_str!("asm/trampoline.S"));
+#[cfg(feature = "hypervisor")]
+global_asm!(include_str!("asm/hyp.S"));
 
 #[macro_use]
 mod printf;
 
+#[macro_use]
+mod ftrace;
+
+#[cfg(feature = "unit_test")]
+#[macro_use]
+mod test;
+
+mod cmdline;
 mod console;
 mod consts;
+mod cpustat;
+#[cfg(feature = "crashdump")]
+mod crashdump;
+mod dtb;
+mod eventfd;
 mod fs;
+#[cfg(feature = "gdbstub")]
+mod gdbstub;
+#[cfg(feature = "hypervisor")]
+mod hyp;
+mod insn;
+mod kmsg;
 mod mm;
+mod monitor;
+mod mqueue;
+mod once;
+mod profile;
 mod process;
+mod proclog;
+mod random;
 mod register;
 mod rmain;
+#[cfg(feature = "sbi")]
+mod sbi;
+mod schedtrace;
+#[cfg(feature = "selftest")]
+mod selftest;
+mod sleeplock;
+mod socket;
 mod spinlock;
 mod start;
 mod string;
+mod suspend;
+mod timer;
+mod timerfd;
 mod trap;
+mod watch;
 mod driver;
 mod plic;
-
-#[cfg(feature = "unit_test")]
-fn test_main_entry() {
-    use proc::cpu_id;
-
-    let cpu_id = unsafe { cpu_id() };
-
-    // test cases only needed to be executed with a single hart/kernel-thread
-    if cpu_id == 0 {
-        spinlock::tests::smoke();
-    }
-
-    // test cases needed to be executed with multiple harts/kernel-threads
-    printf::tests::println_simo();
-    mm::kalloc::tests::alloc_simo();
-
-    if cpu_id == 0 {
-        println!("all tests pass.");
-    }
-}
@@ -1,5 +1,4 @@
 #![no_std]
 #![no_main]
 
-#[allow(unused_imports)]
-use xv6_riscv_rust;
+extern crate xv6_riscv_rust;
@@ -55,7 +55,7 @@ impl TryFrom<usize> for PhysAddr {
     type Error = &'static str;
 
     fn try_from(addr: usize) -> Result<Self, Self::Error> {
-        if addr % PGSIZE != 0 {
+        if !addr.is_multiple_of(PGSIZE) {
             return Err("PhysAddr addr not aligned");
         }
         if addr > PHYSTOP.into() {
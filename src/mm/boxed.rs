@@ -3,20 +3,18 @@
 
 use core::mem;
 use core::ops::{Deref, DerefMut};
-use core::ptr::Unique;
+use core::ptr::NonNull;
 
 use crate::mm::{kalloc, kfree};
 
 pub trait PageAligned {}
 
-pub struct Box<T>(Unique<T>);
+pub struct Box<T>(NonNull<T>);
 
 impl<T: PageAligned> Box<T> {
+    #[cfg_attr(debug_assertions, track_caller)]
     pub fn new() -> Option<Box<T>> {
-        match unsafe { kalloc() } {
-            Some(ptr) => Some(Self(Unique::new(ptr as *mut T).unwrap())),
-            None => None,
-        }
+        unsafe { kalloc() }.map(|ptr| Self(NonNull::new(ptr as *mut T).unwrap()))
     }
 
     pub fn into_raw(self) -> *mut T {
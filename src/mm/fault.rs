@@ -0,0 +1,111 @@
+//! Allocation fault injection, only built with the fault_inject feature
+//!
+//! kalloc asks should_fail() before taking a page,
+//! so the out-of-memory paths in fork, exec, pipe and the fs layer
+//! can be exercised on demand instead of only when memory is really full.
+//! It starts off, and is switched at runtime by set(),
+//! e.g., from a kernel test or through the kfault syscall.
+
+use core::sync::atomic::{AtomicUsize, Ordering};
+
+/// Fault injection off
+pub const FAULT_OFF: usize = 0;
+/// Fail every arg-th allocation
+pub const FAULT_NTH: usize = 1;
+/// Fail each allocation with a chance of arg percent
+pub const FAULT_RANDOM: usize = 2;
+
+static MODE: AtomicUsize = AtomicUsize::new(FAULT_OFF);
+static ARG: AtomicUsize = AtomicUsize::new(0);
+// allocations seen since the last set()
+static COUNT: AtomicUsize = AtomicUsize::new(0);
+static INJECTED: AtomicUsize = AtomicUsize::new(0);
+static SEED: AtomicUsize = AtomicUsize::new(0x2545f4914f6cdd1d);
+
+/// Switch the mode, resetting the counters.
+/// A zero arg turns injection off.
+/// Return the number of faults injected under the previous mode.
+pub fn set(mode: usize, arg: usize) -> Result<usize, &'static str> {
+    let mode = match mode {
+        FAULT_OFF | FAULT_NTH | FAULT_RANDOM if arg == 0 => FAULT_OFF,
+        FAULT_OFF | FAULT_NTH => mode,
+        FAULT_RANDOM if arg <= 100 => mode,
+        FAULT_RANDOM => return Err("fault::set: percent out of range"),
+        _ => return Err("fault::set: unknown mode"),
+    };
+    MODE.store(FAULT_OFF, Ordering::SeqCst);
+    ARG.store(arg, Ordering::SeqCst);
+    COUNT.store(0, Ordering::SeqCst);
+    let injected = INJECTED.swap(0, Ordering::SeqCst);
+    MODE.store(mode, Ordering::SeqCst);
+    Ok(injected)
+}
+
+/// Seed the random mode, so a failing run can be replayed
+pub fn seed(seed: usize) {
+    SEED.store(if seed == 0 { 1 } else { seed }, Ordering::SeqCst);
+}
+
+/// Number of faults injected since the last set()
+pub fn injected() -> usize {
+    INJECTED.load(Ordering::Relaxed)
+}
+
+/// xorshift64, racy updates from several harts are fine here
+fn random() -> usize {
+    let mut x = SEED.load(Ordering::Relaxed);
+    x ^= x << 13;
+    x ^= x >> 7;
+    x ^= x << 17;
+    SEED.store(x, Ordering::Relaxed);
+    x
+}
+
+/// Whether this allocation should pretend memory is exhausted
+pub fn should_fail() -> bool {
+    let fail = match MODE.load(Ordering::Relaxed) {
+        FAULT_NTH => {
+            let n = COUNT.fetch_add(1, Ordering::Relaxed) + 1;
+            n.is_multiple_of(ARG.load(Ordering::Relaxed))
+        }
+        FAULT_RANDOM => random() % 100 < ARG.load(Ordering::Relaxed),
+        _ => false,
+    };
+    if fail {
+        INJECTED.fetch_add(1, Ordering::Relaxed);
+    }
+    fail
+}
+
+#[cfg(feature = "unit_test")]
+pub mod tests {
+    use super::*;
+    use crate::mm::{kalloc, kfree};
+
+    /// Every third allocation fails, and turning it off restores kalloc
+    pub fn every_nth() {
+        set(FAULT_NTH, 3).unwrap();
+        let mut got = [None; 6];
+        for page in got.iter_mut() {
+            *page = unsafe { kalloc() };
+        }
+        assert_eq!(set(FAULT_OFF, 0), Ok(2));
+        for (i, page) in got.iter().enumerate() {
+            assert_eq!(page.is_none(), i % 3 == 2);
+            if let Some(pa) = page {
+                unsafe { kfree(*pa) };
+            }
+        }
+        let pa = unsafe { kalloc() }.expect("every_nth: kalloc still failing");
+        unsafe { kfree(pa) };
+    }
+    crate::kernel_test!(every_nth);
+
+    pub fn bad_args() {
+        assert!(set(FAULT_RANDOM, 101).is_err());
+        assert!(set(7, 1).is_err());
+        assert_eq!(set(FAULT_RANDOM, 0), Ok(0));
+        assert!(!should_fail());
+    }
+    crate::kernel_test!(bad_args);
+}
@@ -1,8 +1,11 @@
 use core::convert::TryFrom;
 use core::option::Option;
 use core::ptr::{self, NonNull};
+use core::sync::atomic::{AtomicUsize, Ordering};
 
-use crate::consts::{PGSIZE, PHYSTOP};
+use crate::consts::{HAS_VIRTIO, PGSIZE, PHYSTOP};
+use crate::driver::virtio_balloon;
+use crate::dtb;
 use crate::mm::{Addr, PhysAddr};
 use crate::spinlock::SpinLock;
 
@@ -33,17 +36,32 @@ type FrameList = Frame;
 
 static KMEM: SpinLock<FrameList> = SpinLock::new(FrameList { next: None }, "kmem");
 
+/// pages on the free list, and all of them after kinit, for the kernel monitor
+static FREE: AtomicUsize = AtomicUsize::new(0);
+static TOTAL: AtomicUsize = AtomicUsize::new(0);
+
+/// Free and total pages
+pub fn kalloc_stats() -> (usize, usize) {
+    (FREE.load(Ordering::Relaxed), TOTAL.load(Ordering::Relaxed))
+}
+
 // must only be called once by a single hart
 pub unsafe fn kinit() {
     extern "C" {
         fn end();
     }
-    let end = end as usize;
-    println!("kinit: end={:#x}", end);
+    let end = end as *const () as usize;
+    // up to PHYSTOP, unless the device tree says there is less RAM
+    let top = match dtb::ram_end() {
+        Some(ram_end) if ram_end < PHYSTOP.const_usize() => ram_end & !(PGSIZE - 1),
+        _ => PHYSTOP.const_usize(),
+    };
+    println!("kinit: end={:#x}, top={:#x}", end, top);
     free_range(
         PhysAddr::try_from((end + PGSIZE - 1) & !(PGSIZE - 1)).unwrap(),
-        PhysAddr::try_from(PHYSTOP).unwrap(),
+        PhysAddr::try_from(top).unwrap(),
     );
+    TOTAL.store(FREE.load(Ordering::Relaxed), Ordering::Relaxed);
     println!("kinit: done");
 }
 
@@ -61,49 +79,69 @@ unsafe fn free_range(mut start: PhysAddr, end: PhysAddr) {
 /// call to kalloc().  (The exception is when
 /// initializing the allocator; see kinit above.)
 pub unsafe fn kfree(ptr: *mut u8) {
+    #[cfg(debug_assertions)]
+    super::leak::untrack(ptr as usize);
+
     let mut frame: NonNull<Frame> = Frame::new(ptr);
     let mut kmem = KMEM.lock();
     frame.as_mut().set(kmem.take_next());
     kmem.set(Some(frame));
+    FREE.fetch_add(1, Ordering::Relaxed);
     drop(kmem);
 }
 
+/// In debug builds the page is tagged with the caller, see leak.rs.
+/// Out of pages, it takes some back from the host's balloon, if it may.
+#[cfg_attr(debug_assertions, track_caller)]
 pub unsafe fn kalloc() -> Option<*mut u8> {
-    let mut kmem = KMEM.lock();
-    let first_frame = kmem.take_next();
-    if let Some(mut first_frame_ptr) = first_frame {
-        kmem.set(first_frame_ptr.as_mut().take_next());
+    #[cfg(feature = "fault_inject")]
+    if super::fault::should_fail() {
+        return None;
     }
-    drop(kmem);
+
+    let first_frame = match take_frame() {
+        None if HAS_VIRTIO && virtio_balloon::reclaim() != 0 => take_frame(),
+        first_frame => first_frame,
+    };
 
     match first_frame {
-        Some(first_frame_ptr) => Some(first_frame_ptr.as_ptr() as *mut u8),
+        Some(first_frame_ptr) => {
+            #[cfg(debug_assertions)]
+            super::leak::track(first_frame_ptr.as_ptr() as usize, core::panic::Location::caller());
+            Some(first_frame_ptr.as_ptr() as *mut u8)
+        }
         None => None,
     }
 }
 
+fn take_frame() -> Option<NonNull<Frame>> {
+    let mut kmem = KMEM.lock();
+    let first_frame = kmem.take_next();
+    if let Some(mut first_frame_ptr) = first_frame {
+        kmem.set(unsafe { first_frame_ptr.as_mut() }.take_next());
+        FREE.fetch_sub(1, Ordering::Relaxed);
+    }
+    drop(kmem);
+    first_frame
+}
+
 #[cfg(feature = "unit_test")]
 pub mod tests {
     use super::*;
-    use crate::consts;
-    use crate::proc::cpu_id;
-    use crate::mm::pagetable::PageTable;
-    use core::sync::atomic::{AtomicU8, Ordering};
+    use crate::process::cpu_id;
 
+    /// All harts allocate at the same time
     pub fn alloc_simo() {
-        // use NSMP to synchronize testing pr's spinlock
-        static NSMP: AtomicU8 = AtomicU8::new(0);
-        NSMP.fetch_add(1, Ordering::Relaxed);
-        while NSMP.load(Ordering::Relaxed) != NSMP as u8 {}
-
         let id = unsafe { cpu_id() };
 
-        for _ in 0..10 {
-            let page_table = PageTable::new();
-            println!("hart {} alloc page table at {:#x}", id, page_table.addr());
+        let mut pages: [*mut u8; 10] = [ptr::null_mut(); 10];
+        for page in pages.iter_mut() {
+            *page = unsafe { kalloc() }.expect("alloc_simo: out of memory");
+            println!("hart {} alloc page at {:#x}", id, *page as usize);
+        }
+        for page in pages.iter() {
+            unsafe { kfree(*page) };
         }
-
-        NSMP.fetch_sub(1, Ordering::Relaxed);
-        while NSMP.load(Ordering::Relaxed) != 0 {}
     }
+    crate::kernel_test!(alloc_simo, smp);
 }
@@ -1,8 +1,10 @@
+use core::arch::asm;
 use core::convert::{TryFrom, Into};
 
 use crate::consts::{
-    CLINT, CLINT_MAP_SIZE, KERNBASE, PHYSTOP, PLIC, PLIC_MAP_SIZE, UART0, UART0_MAP_SIZE, VIRTIO0,
-    VIRTIO0_MAP_SIZE, TRAMPOLINE, PGSIZE
+    CLINT, CLINT_MAP_SIZE, HAS_VIRTIO, KERNBASE, PHYSTOP, PLIC, PLIC_MAP_SIZE, UART0, UART0_MAP_SIZE,
+    VIRTIO0, VIRTIO0_MAP_SIZE, VIRTIO1, VIRTIO1_MAP_SIZE, VIRTIO2, VIRTIO2_MAP_SIZE, VIRTIO3,
+    VIRTIO3_MAP_SIZE, VIRTIO4, VIRTIO4_MAP_SIZE, TRAMPOLINE, PGSIZE, VIRT_TEST, VIRT_TEST_MAP_SIZE
 };
 use crate::mm::{Addr, PageTable, PhysAddr, PteFlag, VirtAddr};
 use crate::register::satp;
@@ -11,10 +13,18 @@ static mut KERNEL_PAGE_TABLE: PageTable = PageTable::empty();
 
 pub unsafe fn kvm_init_hart() {
     satp::write(KERNEL_PAGE_TABLE.as_satp());
-    llvm_asm!("sfence.vma zero, zero"::::"volatile");
+    asm!("sfence.vma zero, zero", options(nostack));
 }
 
 pub unsafe fn kvm_init() {
+    // qemu test device, to exit qemu
+    kvm_map(
+        VirtAddr::from(VIRT_TEST),
+        PhysAddr::from(VIRT_TEST),
+        VIRT_TEST_MAP_SIZE,
+        PteFlag::R | PteFlag::W,
+    );
+
     // uart registers
     kvm_map(
         VirtAddr::from(UART0),
@@ -23,11 +33,63 @@ pub unsafe fn kvm_init() {
         PteFlag::R | PteFlag::W,
     );
 
-    // virtio mmio disk interface
+    // second uart, for gdb
+    #[cfg(feature = "gdbstub")]
+    kvm_map(
+        VirtAddr::from(crate::consts::UART1),
+        PhysAddr::from(crate::consts::UART1),
+        crate::consts::UART1_MAP_SIZE,
+        PteFlag::R | PteFlag::W,
+    );
+
+    if HAS_VIRTIO {
+        // virtio mmio disk interface
+        kvm_map(
+            VirtAddr::from(VIRTIO0),
+            PhysAddr::from(VIRTIO0),
+            VIRTIO0_MAP_SIZE,
+            PteFlag::R | PteFlag::W,
+        );
+
+        // virtio mmio gpu interface
+        kvm_map(
+            VirtAddr::from(VIRTIO1),
+            PhysAddr::from(VIRTIO1),
+            VIRTIO1_MAP_SIZE,
+            PteFlag::R | PteFlag::W,
+        );
+
+        // virtio mmio 9p interface
+        kvm_map(
+            VirtAddr::from(VIRTIO2),
+            PhysAddr::from(VIRTIO2),
+            VIRTIO2_MAP_SIZE,
+            PteFlag::R | PteFlag::W,
+        );
+
+        // virtio mmio balloon interface
+        kvm_map(
+            VirtAddr::from(VIRTIO3),
+            PhysAddr::from(VIRTIO3),
+            VIRTIO3_MAP_SIZE,
+            PteFlag::R | PteFlag::W,
+        );
+
+        // virtio mmio entropy source interface
+        kvm_map(
+            VirtAddr::from(VIRTIO4),
+            PhysAddr::from(VIRTIO4),
+            VIRTIO4_MAP_SIZE,
+            PteFlag::R | PteFlag::W,
+        );
+    }
+
+    // L2 cache controller
+    #[cfg(feature = "fu740")]
     kvm_map(
-        VirtAddr::from(VIRTIO0),
-        PhysAddr::from(VIRTIO0),
-        VIRTIO0_MAP_SIZE,
+        VirtAddr::from(crate::consts::L2_CACHE),
+        PhysAddr::from(crate::consts::L2_CACHE),
+        crate::consts::L2_CACHE_MAP_SIZE,
         PteFlag::R | PteFlag::W,
     );
 
@@ -52,7 +114,7 @@ pub unsafe fn kvm_init() {
     extern "C" {
         fn etext();
     }
-    let etext = etext as usize;
+    let etext = etext as *const () as usize;
 
     // map kernel text executable and read-only.
     kvm_map(
@@ -77,7 +139,7 @@ pub unsafe fn kvm_init() {
     }
     kvm_map(
         VirtAddr::from(TRAMPOLINE),
-        PhysAddr::try_from(trampoline as usize).unwrap(),
+        PhysAddr::try_from(trampoline as *const () as usize).unwrap(),
         PGSIZE,
         PteFlag::R | PteFlag::X
     );
@@ -96,21 +158,14 @@ pub unsafe fn kvm_map(va: VirtAddr, pa: PhysAddr, size: usize, perm: PteFlag) {
     }
 }
 
-/// translate a kernel virtual address to
-/// a physical address. only needed for
-/// addresses on the stack.
-/// va need not be page aligned.
-pub unsafe fn kvm_pa(va: VirtAddr) -> u64 {
-    let off: u64 = (va.as_usize() % PGSIZE) as u64;
-    match KERNEL_PAGE_TABLE.walk(va) {
-        Some(pte) => {
-            if !pte.is_valid() {
-                panic!("kvm_pa: va={:?} mapped pa not valid");
-            }
-            pte.as_phys_addr().as_usize() as u64 + off
-        }
-        None => {
-            panic!("kvm_pa: va={:?} no mapped pa", va);
-        }
+/// Translate a kernel virtual address, None if it is not mapped.
+/// Used by debuggers, e.g., the gdb stub.
+#[cfg(feature = "gdbstub")]
+pub fn kvm_translate(va: usize) -> Option<usize> {
+    let off = va % PGSIZE;
+    let va = VirtAddr::try_from(va).ok()?;
+    match unsafe { KERNEL_PAGE_TABLE.walk(va) } {
+        Some(pte) if pte.is_valid() => Some(pte.as_phys_addr().as_usize() + off),
+        _ => None,
     }
 }
@@ -0,0 +1,96 @@
+//! Page leak detector, only built in debug builds
+//!
+//! kalloc tags every page it hands out with the source location of its caller
+//! (through #[track_caller], also on Box::new),
+//! and with the current generation, see mark().
+//! kfree clears the tag, so the tags left are exactly the outstanding pages,
+//! which report() prints grouped by call site.
+//! The test harness marks before each test and fails it if pages remain.
+
+use core::panic::Location;
+use core::sync::atomic::{AtomicUsize, Ordering};
+
+use crate::consts::{KERNBASE, PGSIZE, PHYSTOP};
+use crate::spinlock::SpinLock;
+
+const NPAGE: usize = (PHYSTOP.const_usize() - KERNBASE.const_usize()) / PGSIZE;
+
+/// distinct call sites report() can group by,
+/// the rest are counted as others
+const NSITE: usize = 16;
+
+#[derive(Clone, Copy)]
+struct Tag {
+    site: Option<&'static Location<'static>>,
+    gen: usize,
+}
+
+static TAGS: SpinLock<[Tag; NPAGE]> = SpinLock::new([Tag { site: None, gen: 0 }; NPAGE], "leak");
+
+static GEN: AtomicUsize = AtomicUsize::new(0);
+
+#[inline]
+fn index(pa: usize) -> usize {
+    (pa - KERNBASE.const_usize()) / PGSIZE
+}
+
+/// Record that the page at pa was handed out to site
+pub fn track(pa: usize, site: &'static Location<'static>) {
+    let gen = GEN.load(Ordering::Relaxed);
+    TAGS.lock()[index(pa)] = Tag { site: Some(site), gen };
+}
+
+/// Record that the page at pa is free again
+pub fn untrack(pa: usize) {
+    TAGS.lock()[index(pa)].site = None;
+}
+
+/// Start a new generation, returning it.
+/// Pages allocated from now on count as leaked by report(gen) until freed.
+pub fn mark() -> usize {
+    GEN.fetch_add(1, Ordering::SeqCst) + 1
+}
+
+/// Number of pages allocated since generation gen and not yet freed
+pub fn outstanding(gen: usize) -> usize {
+    TAGS.lock().iter().filter(|tag| tag.site.is_some() && tag.gen >= gen).count()
+}
+
+/// Print the pages allocated since generation gen and not yet freed,
+/// grouped by call site. Return their number.
+pub fn report(gen: usize) -> usize {
+    let mut sites: [(Option<&'static Location<'static>>, usize); NSITE] = [(None, 0); NSITE];
+    let mut others = 0;
+    let mut total = 0;
+
+    let tags = TAGS.lock();
+    for tag in tags.iter().filter(|tag| tag.gen >= gen) {
+        let site = match tag.site {
+            Some(site) => site,
+            None => continue,
+        };
+        total += 1;
+        match sites.iter_mut().find(|(s, _)| s.is_none() || *s == Some(site)) {
+            Some(slot) => {
+                slot.0 = Some(site);
+                slot.1 += 1;
+            }
+            None => others += 1,
+        }
+    }
+    drop(tags);
+
+    if total == 0 {
+        return 0;
+    }
+    println!("leak: {} pages outstanding", total);
+    for (site, count) in sites.iter() {
+        if let Some(site) = site {
+            println!("  {:>6} {}:{}", count, site.file(), site.line());
+        }
+    }
+    if others > 0 {
+        println!("  {:>6} other call sites", others);
+    }
+    total
+}
@@ -1,11 +1,17 @@
 pub use addr::{Addr, PhysAddr, VirtAddr};
 pub use boxed::{Box, PageAligned};
-pub use kalloc::{kalloc, kfree, kinit};
-pub use kvm::{kvm_init, kvm_init_hart, kvm_map, kvm_pa};
+pub use kalloc::{kalloc, kalloc_stats, kfree, kinit};
+pub use kvm::{kvm_init, kvm_init_hart, kvm_map};
+#[cfg(feature = "gdbstub")]
+pub use kvm::kvm_translate;
 pub use pagetable::{PageTable, PteFlag};
 
 mod addr;
 mod boxed;
+#[cfg(feature = "fault_inject")]
+pub mod fault;
 mod kalloc;
 mod kvm;
+#[cfg(debug_assertions)]
+pub mod leak;
 mod pagetable;
@@ -1,10 +1,12 @@
+use core::cmp::min;
 use core::convert::TryFrom;
 use core::ptr;
 
-use crate::consts::{PGSIZE, PGSHIFT, SATP_SV39, SV39FLAGLEN, USERTEXT};
+use crate::consts::{PGSIZE, PGSHIFT, SATP_SV39, SV39FLAGLEN};
+use crate::process::cond_resched;
+use crate::string;
 
-use super::Box;
-use super::kalloc;
+use super::{kalloc, kfree, Box};
 use super::PageAligned;
 use super::{Addr, PhysAddr, VirtAddr};
 
@@ -42,6 +44,23 @@ impl PageTableEntry {
         (self.data & (PteFlag::U.bits())) > 0
     }
 
+    #[inline]
+    fn is_writable(&self) -> bool {
+        (self.data & (PteFlag::W.bits())) > 0
+    }
+
+    /// Written to since it was mapped, the hardware sets D at a store through it
+    #[inline]
+    pub fn is_dirty(&self) -> bool {
+        (self.data & (PteFlag::D.bits())) > 0
+    }
+
+    /// A leaf maps a page, otherwise it points to the next level
+    #[inline]
+    fn is_leaf(&self) -> bool {
+        (self.data & (PteFlag::R | PteFlag::W | PteFlag::X).bits()) > 0
+    }
+
     #[inline]
     fn as_page_table(&self) -> *mut PageTable {
         ((self.data >> SV39FLAGLEN) << PGSHIFT) as *mut PageTable
@@ -78,7 +97,7 @@ impl PageAligned for PageTable {}
 impl PageTable {
     pub const fn empty() -> Self {
         Self {
-            data: [PageTableEntry { data: 0 }; 512],
+            data: [const { PageTableEntry { data: 0 } }; 512],
         }
     }
 
@@ -99,6 +118,10 @@ impl PageTable {
     /// physical addresses starting at pa. va and size might not
     /// be page-aligned. Returns Ok(()) on success, Err(_) if walk() couldn't
     /// allocate a needed page-table page.
+    ///
+    /// The level-1 and level-0 tables are looked up, or allocated,
+    /// only when va crosses into a new one,
+    /// so consecutive pages cost one PTE write each instead of a full walk.
     pub fn map_pages(
         &mut self,
         mut va: VirtAddr,
@@ -110,56 +133,286 @@ impl PageTable {
         va.pg_round_down();
         last.pg_round_up();
 
+        let mut l1: *mut PageTable = ptr::null_mut();
+        let mut l0: *mut PageTable = ptr::null_mut();
         while va != last {
-            match self.walk_alloc(va) {
-                Some(pte) => {
-                    if pte.is_valid() {
-                        println!(
-                            "va: {:#x}, pa: {:#x}, pte: {:#x}",
-                            va.as_usize(),
-                            pa.as_usize(),
-                            pte.data
-                        );
-                        panic!("remap");
-                    }
-                    pte.write_perm(pa, perm);
-                    va.add_page();
-                    pa.add_page();
-                }
-                None => {
-                    return Err("PageTable.map_pages: \
-                    not enough memory for new page table")
-                }
+            if l1.is_null() || (va.page_num(1) == 0 && va.page_num(0) == 0) {
+                l1 = self.table_alloc(va.page_num(2))
+                    .ok_or("PageTable.map_pages: not enough memory for new page table")?;
+                l0 = ptr::null_mut();
+            }
+            if l0.is_null() || va.page_num(0) == 0 {
+                l0 = unsafe { (*l1).table_alloc(va.page_num(1)) }
+                    .ok_or("PageTable.map_pages: not enough memory for new page table")?;
+            }
+
+            let pte = unsafe { &mut (*l0).data[va.page_num(0)] };
+            if pte.is_valid() {
+                println!(
+                    "va: {:#x}, pa: {:#x}, pte: {:#x}",
+                    va.as_usize(),
+                    pa.as_usize(),
+                    pte.data
+                );
+                panic!("remap");
             }
+            pte.write_perm(pa, perm);
+            va.add_page();
+            pa.add_page();
         }
 
         Ok(())
     }
 
-    /// Return the bottom level of PTE that corresponds to the given va.
-    /// i.e. this PTE contains the pa that is mapped for the given va.
-    ///
-    /// if alloc is true then allocate new page table necessarily
-    /// but doesn't change anything.(lazy allocation)
-    fn walk_alloc(&mut self, va: VirtAddr) -> Option<&mut PageTableEntry> {
-        let mut page_table = self as *mut PageTable;
-        for level in (1..=2).rev() {
-            let pte = unsafe { &mut (*page_table).data[va.page_num(level)] };
+    /// Return the next-level table that entry index points to,
+    /// allocating a zeroed one if there is none yet.
+    fn table_alloc(&mut self, index: usize) -> Option<*mut PageTable> {
+        let pte = &mut self.data[index];
+        if pte.is_valid() {
+            return Some(pte.as_page_table());
+        }
+        let mut new_page_table = Box::<PageTable>::new()?;
+        new_page_table.clear();
+        let page_table = new_page_table.into_raw();
+        pte.write(PhysAddr::try_from(page_table as usize).unwrap());
+        Some(page_table)
+    }
 
-            if pte.is_valid() {
-                page_table = pte.as_page_table();
-            } else {
-                match Box::<PageTable>::new() {
-                    Some(mut new_page_table) => {
-                        new_page_table.clear();
-                        page_table = new_page_table.into_raw();
-                        pte.write(PhysAddr::try_from(page_table as usize).unwrap());
-                    }
-                    None => return None,
+    /// Return the next-level table that entry index points to, if any
+    fn table(&self, index: usize) -> Option<*mut PageTable> {
+        let pte = &self.data[index];
+        if pte.is_valid() && !pte.is_leaf() {
+            Some(pte.as_page_table())
+        } else {
+            None
+        }
+    }
+
+    /// Remove npages of mappings starting from va,
+    /// which must be page-aligned and mapped.
+    /// Optionally free the physical memory mapped.
+    /// Like map_pages, the level-0 table is only looked up again
+    /// when va crosses into the next one.
+    pub fn unmap_pages(&mut self, mut va: VirtAddr, npages: usize, free: bool)
+        -> Result<(), &'static str>
+    {
+        if !va.as_usize().is_multiple_of(PGSIZE) {
+            return Err("PageTable.unmap_pages: va not aligned")
+        }
+        let mut l0: *mut PageTable = ptr::null_mut();
+        for _ in 0..npages {
+            if l0.is_null() || va.page_num(0) == 0 {
+                l0 = self.table(va.page_num(2))
+                    .and_then(|l1| unsafe { (*l1).table(va.page_num(1)) })
+                    .ok_or("PageTable.unmap_pages: not mapped")?;
+            }
+            let pte = unsafe { &mut (*l0).data[va.page_num(0)] };
+            if !pte.is_valid() {
+                return Err("PageTable.unmap_pages: not mapped")
+            }
+            if !pte.is_leaf() {
+                return Err("PageTable.unmap_pages: not a leaf")
+            }
+            if free {
+                unsafe { kfree(pte.as_phys_addr().as_usize() as *mut u8); }
+            }
+            pte.write_zero();
+            va.add_page();
+        }
+        Ok(())
+    }
+
+    /// Free the page-table pages below this one, but not itself.
+    /// All leaf mappings must have been removed already.
+    pub fn free_walk(&mut self) {
+        for pte in self.data.iter_mut() {
+            if !pte.is_valid() {
+                continue;
+            }
+            if pte.is_leaf() {
+                panic!("PageTable.free_walk: leaf");
+            }
+            let child = pte.as_page_table();
+            unsafe {
+                (*child).free_walk();
+                kfree(child as *mut u8);
+            }
+            pte.write_zero();
+        }
+    }
+
+    /// Print the valid PTEs, indented by level, like xv6's vmprint
+    pub fn print(&self) {
+        println!("page table {:#x}", self as *const PageTable as usize);
+        self.print_level(2, 0);
+    }
+
+    fn print_level(&self, level: usize, va: usize) {
+        const INDENT: [&str; 3] = [" .. .. ..", " .. ..", " .."];
+        for (i, pte) in self.data.iter().enumerate() {
+            if !pte.is_valid() {
+                continue;
+            }
+            let va = va | i << (PGSHIFT + 9 * level);
+            let flag = |f: PteFlag, c: char| if pte.data & f.bits() != 0 { c } else { '-' };
+            println!("{}{}: va {:#x} pa {:#x} {}{}{}{}", INDENT[level], i, va,
+                pte.as_phys_addr().as_usize(),
+                flag(PteFlag::R, 'r'), flag(PteFlag::W, 'w'),
+                flag(PteFlag::X, 'x'), flag(PteFlag::U, 'u'));
+            if !pte.is_leaf() && level > 0 {
+                unsafe { (*pte.as_page_table()).print_level(level - 1, va); }
+            }
+        }
+    }
+
+    /// Call f with the va and permissions of every user page, by ascending va
+    pub fn for_each_user_page(&self, f: &mut impl FnMut(usize, PteFlag)) {
+        self.pages_level(2, 0, true, f);
+    }
+
+    /// Call f with the va of every page mapped without U, by ascending va,
+    /// the trampoline, the trapframe and the guard page below a user stack
+    pub fn for_each_kernel_page(&self, f: &mut impl FnMut(usize)) {
+        self.pages_level(2, 0, false, &mut |va, _| f(va));
+    }
+
+    fn pages_level(&self, level: usize, va: usize, user: bool, f: &mut impl FnMut(usize, PteFlag)) {
+        for (i, pte) in self.data.iter().enumerate() {
+            if !pte.is_valid() {
+                continue;
+            }
+            let va = va | i << (PGSHIFT + 9 * level);
+            if pte.is_leaf() {
+                if pte.is_user() == user {
+                    f(va, PteFlag::from_bits_truncate(pte.data) & (PteFlag::R | PteFlag::W | PteFlag::X));
                 }
+            } else if level > 0 {
+                unsafe { (*pte.as_page_table()).pages_level(level - 1, va, user, f); }
+            }
+        }
+    }
+
+    /// Remove and free the writable user pages among npages starting from va,
+    /// which must be page-aligned, skipping those not mapped.
+    /// Read-only pages are kept, there is no file to read them back from.
+    /// Return how many were dropped.
+    pub fn uvm_dontneed(&mut self, mut va: VirtAddr, npages: usize)
+        -> Result<usize, &'static str>
+    {
+        let mut dropped = 0;
+        for _ in 0..npages {
+            let anon = self.walk(va).is_some_and(|pte| {
+                pte.is_valid() && pte.is_leaf() && pte.is_user() && pte.is_writable()
+            });
+            if anon {
+                self.unmap_pages(va, 1, true)?;
+                dropped += 1;
+            }
+            va.add_page();
+        }
+        Ok(dropped)
+    }
+
+    /// Mark the page mapped at va dirty, after the kernel wrote to it through the direct map,
+    /// as a store of the user would have, if it is mapped
+    pub fn uvm_set_dirty(&mut self, va: VirtAddr) {
+        let pte = match self.walk(va) {
+            Some(pte) if pte.is_valid() => pte as *const PageTableEntry as *mut PageTableEntry,
+            _ => return,
+        };
+        unsafe { (*pte).data |= PteFlag::D.bits(); }
+    }
+
+    /// Mark the page mapped at va clean, once it is written back, if it is mapped.
+    /// A TLB entry still dirty goes at the next sfence.vma, on the way back to user space.
+    pub fn uvm_clear_dirty(&mut self, va: VirtAddr) {
+        let pte = match self.walk(va) {
+            Some(pte) if pte.is_valid() => pte as *const PageTableEntry as *mut PageTableEntry,
+            _ => return,
+        };
+        unsafe { (*pte).data &= !PteFlag::D.bits(); }
+    }
+
+    /// Free the pages mapped below sz, skipping the holes,
+    /// e.g., given back by madvise, then the page-table pages below this one.
+    /// Nothing else may be mapped.
+    pub fn uvm_free(&mut self, sz: usize) {
+        self.free_level(2, 0, sz);
+        self.free_walk();
+    }
+
+    fn free_level(&mut self, level: usize, va: usize, sz: usize) {
+        for (i, pte) in self.data.iter_mut().enumerate() {
+            let va = va | i << (PGSHIFT + 9 * level);
+            if !pte.is_valid() || va >= sz {
+                continue;
             }
+            if pte.is_leaf() {
+                unsafe { kfree(pte.as_phys_addr().as_usize() as *mut u8); }
+                pte.write_zero();
+            } else if level > 0 {
+                unsafe { (*pte.as_page_table()).free_level(level - 1, va, sz); }
+            }
+        }
+    }
+
+    /// Map a zeroed user page, readable and writable, at page-aligned va,
+    /// which must not be mapped.
+    pub fn uvm_zero_page(&mut self, va: VirtAddr) -> Result<(), &'static str> {
+        if self.walk(va).is_some_and(|pte| pte.is_valid()) {
+            return Err("PageTable.uvm_zero_page: already mapped")
         }
-        unsafe { Some(&mut (*page_table).data[va.page_num(0)]) }
+        let pa = unsafe { kalloc() }.ok_or("PageTable.uvm_zero_page: out of memory")?;
+        unsafe { ptr::write_bytes(pa, 0, PGSIZE); }
+        self.map_pages(va, PGSIZE, PhysAddr::try_from(pa as usize).unwrap(),
+            PteFlag::R | PteFlag::W | PteFlag::U)
+            .inspect_err(|_| unsafe { kfree(pa); })
+    }
+
+    /// Shrink the user memory from oldsz to newsz, freeing the pages above newsz,
+    /// skipping the holes, as uvm_free() does.
+    /// Return newsz.
+    pub fn uvm_dealloc(&mut self, oldsz: usize, newsz: usize) -> usize {
+        if newsz >= oldsz {
+            return oldsz;
+        }
+        let mut va = VirtAddr::try_from(newsz).unwrap();
+        va.pg_round_up();
+        while va.as_usize() < oldsz {
+            if self.walk(va).is_some_and(|pte| pte.is_valid()) {
+                self.unmap_pages(va, 1, true).unwrap();
+            }
+            va.add_page();
+        }
+        newsz
+    }
+
+    /// Copy the user pages mapped in [start, end), page-aligned, into new,
+    /// at the same addresses and with the same permissions, skipping the holes,
+    /// e.g., pages sbrk() grew the memory by but not touched yet.
+    /// The copies are not dirty.
+    /// On failure, those copied so far stay mapped in new, for its owner to free.
+    pub fn uvm_copy(&self, new: &mut PageTable, start: usize, end: usize) -> Result<(), &'static str> {
+        let mut va = VirtAddr::try_from(start)?;
+        while va.as_usize() < end {
+            let pte = match self.walk(va) {
+                Some(pte) if pte.is_valid() => pte,
+                _ => {
+                    va.add_page();
+                    continue;
+                }
+            };
+            let perm = PteFlag::from_bits_truncate(pte.data)
+                & (PteFlag::R | PteFlag::W | PteFlag::X | PteFlag::U);
+            let pa = unsafe { kalloc() }.ok_or("PageTable.uvm_copy: out of memory")?;
+            unsafe {
+                ptr::copy_nonoverlapping(pte.as_phys_addr().as_usize() as *const u8, pa, PGSIZE);
+            }
+            new.map_pages(va, PGSIZE, PhysAddr::try_from(pa as usize).unwrap(), perm)
+                .inspect_err(|_| unsafe { kfree(pa); })?;
+            va.add_page();
+        }
+        Ok(())
     }
 
     pub fn walk(&self, va: VirtAddr) -> Option<&PageTableEntry> {
@@ -190,41 +443,28 @@ impl PageTable {
         }
     }
 
-    /// Load the initcode and map it into the pagetable
-    /// Only used for the very first process
-    pub fn uvm_init(&mut self, code: &[u8]) {
-        if code.len() >= PGSIZE {
-            panic!("pagetable's uvm_init: initcode more than a page");
-        }
-        match unsafe{kalloc()} {
-            Some(mem) => {
-                unsafe {ptr::write_bytes(mem, 0, PGSIZE);}
-                self.map_pages(VirtAddr::from(USERTEXT), PGSIZE,
-                    PhysAddr::try_from(mem as usize).unwrap(),
-                    PteFlag::R | PteFlag::W | PteFlag::X | PteFlag::U)
-                    .expect("pagetable's uvm_init map_pages: ");
-                unsafe {ptr::copy_nonoverlapping(code.as_ptr(),
-                    mem, code.len());}
-            }
-            None => {
-                panic!("pagetable's uvm_init: not enough memory");
-            }
-        }
-    }
-
-    /// Return the mapped physical address(page aligned)
-    /// va need not be page aligned
-    fn walk_addr(&self, va: VirtAddr)
-        -> Result<PhysAddr, &'static str>
+    /// Translate user address va through the page table once,
+    /// return where it lies in the kernel's direct map,
+    /// and how many bytes are left in its page from there.
+    /// write also requires the page to be user writable,
+    /// since the kernel's own mapping of it is.
+    fn user_page(&self, va: usize, write: bool)
+        -> Result<(*mut u8, usize), &'static str>
     {
-        match self.walk(va) {
+        let mut base = VirtAddr::try_from(va)?;
+        base.pg_round_down();
+        let distance = va - base.as_usize();
+        match self.walk(base) {
             Some(pte) => {
                 if !pte.is_valid() {
                     Err("pte not valid")
                 } else if !pte.is_user() {
                     Err("pte not mapped for user")
+                } else if write && !pte.is_writable() {
+                    Err("pte not writable")
                 } else {
-                    Ok(pte.as_phys_addr())
+                    let pa = pte.as_phys_addr().as_usize() + distance;
+                    Ok((pa as *mut u8, PGSIZE - distance))
                 }
             }
             None => {
@@ -239,42 +479,257 @@ impl PageTable {
         -> Result<(), &'static str>
     {
         let mut i: usize = 0;
-        let mut va = VirtAddr::try_from(srcva).unwrap();
 
-        // iterate through the raw content page by page
+        // look for the nul a page at a time, then copy up to it at once
         while i < dst.len() {
-            let mut base = va;
-            base.pg_round_down();
-            let distance = (va - base).as_usize();
-            let mut pa_ptr = unsafe {
-                self.walk_addr(base)?
-                    .as_ptr()
-                    .offset(distance as isize)
-            };
-            let mut va_ptr = va.as_ptr();
-            base.add_page();
-            let va_end = base.as_ptr();
-            va = base;
-
-            // iterate througn each u8 in a page
-            let enough_space = (dst.len() - i) >= (PGSIZE - distance);
-            while !ptr::eq(va_ptr, va_end) {
-                if !enough_space && i >= dst.len() {
-                    return Err("copy_in_str: dst not enough space")
-                }
-
-                unsafe {
-                    dst[i] = *pa_ptr;
-                    if dst[i] == 0 {
-                        return Ok(())
-                    }
-                    i += 1;
-                    pa_ptr = pa_ptr.add(1);
-                    va_ptr = va_ptr.add(1);
-                }
+            let va = srcva.checked_add(i).ok_or("va out of range")?;
+            let (pa_ptr, left) = self.user_page(va, false)?;
+            let n = min(left, dst.len() - i);
+            let len = unsafe { string::strnlen(pa_ptr, n) };
+            if len < n {
+                unsafe { string::memcpy(dst.as_mut_ptr().add(i), pa_ptr, len + 1); }
+                return Ok(())
             }
+            unsafe { string::memcpy(dst.as_mut_ptr().add(i), pa_ptr, n); }
+            i += n;
         }
 
         Err("copy_in_str: dst not enough space")
     }
+
+    /// Copy from user space, starting at virtual address srcva,
+    /// to the kernel u8 slice.
+    /// Each user page is translated once and copied through the direct map
+    /// with the word-wise memcpy, with a chance to yield after each, see cond_resched().
+    pub fn copy_in(&self, srcva: usize, dst: &mut [u8])
+        -> Result<(), &'static str>
+    {
+        let mut i: usize = 0;
+        while i < dst.len() {
+            let va = srcva.checked_add(i).ok_or("va out of range")?;
+            let (pa_ptr, left) = self.user_page(va, false)?;
+            let n = min(left, dst.len() - i);
+            unsafe {
+                string::memcpy(dst.as_mut_ptr().add(i), pa_ptr, n);
+            }
+            i += n;
+            cond_resched();
+        }
+
+        Ok(())
+    }
+
+    /// Copy the kernel u8 slice to user space,
+    /// starting at virtual address dstva.
+    /// Each user page is translated once and copied through the direct map
+    /// with the word-wise memcpy, with a chance to yield after each.
+    pub fn copy_out(&self, dstva: usize, src: &[u8])
+        -> Result<(), &'static str>
+    {
+        let mut i: usize = 0;
+        while i < src.len() {
+            let va = dstva.checked_add(i).ok_or("va out of range")?;
+            let (pa_ptr, left) = self.user_page(va, true)?;
+            let n = min(left, src.len() - i);
+            unsafe {
+                string::memcpy(pa_ptr, src.as_ptr().add(i), n);
+            }
+            i += n;
+            cond_resched();
+        }
+
+        Ok(())
+    }
+
+    /// Copy the kernel u8 slice to user space like copy_out,
+    /// but into read-only pages too, for a debugger's breakpoints in the text.
+    pub fn copy_out_text(&self, dstva: usize, src: &[u8])
+        -> Result<(), &'static str>
+    {
+        let mut i: usize = 0;
+        while i < src.len() {
+            let va = dstva.checked_add(i).ok_or("va out of range")?;
+            let (pa_ptr, left) = self.user_page(va, false)?;
+            let n = min(left, src.len() - i);
+            unsafe {
+                string::memcpy(pa_ptr, src.as_ptr().add(i), n);
+            }
+            i += n;
+        }
+
+        Ok(())
+    }
+}
+
+#[cfg(feature = "unit_test")]
+pub mod tests {
+    use super::*;
+    use crate::mm::kalloc;
+
+    /// Copies that straddle two user pages, one of them read-only
+    pub fn user_copy() {
+        let mut pagetable = PageTable::uvm_create();
+        let va = 0x1000;
+        let rw = unsafe { kalloc() }.expect("user_copy: out of memory");
+        let ro = unsafe { kalloc() }.expect("user_copy: out of memory");
+        unsafe { ptr::write_bytes(ro, b'r', PGSIZE); }
+        pagetable.map_pages(VirtAddr::try_from(va).unwrap(), PGSIZE,
+            PhysAddr::try_from(rw as usize).unwrap(), PteFlag::R | PteFlag::W | PteFlag::U).unwrap();
+        pagetable.map_pages(VirtAddr::try_from(va + PGSIZE).unwrap(), PGSIZE,
+            PhysAddr::try_from(ro as usize).unwrap(), PteFlag::R | PteFlag::U).unwrap();
+
+        let src = [7u8; 64];
+        let mut dst = [0u8; 64];
+        let edge = va + PGSIZE - 13;
+        pagetable.copy_out(edge - 64, &src).unwrap();
+        pagetable.copy_in(edge - 64, &mut dst).unwrap();
+        assert!(dst == src);
+        assert!(pagetable.copy_out(edge, &src).is_err());
+        pagetable.copy_out_text(va + PGSIZE, &src[..4]).unwrap();
+        assert_eq!(unsafe { *ro.add(3) }, 7);
+        unsafe { ptr::write_bytes(ro, b'r', 4); }
+
+        // a string running from the first page into the second
+        unsafe { ptr::write_bytes(rw.add(PGSIZE - 13), b's', 13); *ro.add(3) = 0; }
+        pagetable.copy_in(edge, &mut dst[..16]).unwrap();
+        assert_eq!(&dst[..13], &[b's'; 13]);
+        pagetable.copy_in_str(edge, &mut dst).unwrap();
+        assert_eq!(&dst[..17], b"sssssssssssssrrr\0");
+        assert!(pagetable.copy_in_str(edge, &mut dst[..16]).is_err());
+        assert!(pagetable.copy_in(va + 2 * PGSIZE - 8, &mut dst).is_err());
+        assert!(pagetable.copy_in(usize::MAX - 8, &mut dst).is_err());
+        assert!(pagetable.copy_in_str(usize::MAX - 8, &mut dst).is_err());
+
+        pagetable.unmap_pages(VirtAddr::try_from(va).unwrap(), 2, true).unwrap();
+        pagetable.free_walk();
+    }
+    crate::kernel_test!(user_copy);
+
+    /// A range crossing a level-0 and a level-1 table boundary
+    pub fn map_range() {
+        let mut pagetable = PageTable::uvm_create();
+        // one page below the 1GB boundary to three above
+        let start = (1 << 30) - PGSIZE;
+        let npages = 4;
+        let pa = PhysAddr::try_from(0x8000_0000usize).unwrap();
+        pagetable.map_pages(VirtAddr::try_from(start).unwrap(), npages * PGSIZE, pa, PteFlag::R).unwrap();
+
+        for i in 0..npages {
+            let pte = pagetable.walk(VirtAddr::try_from(start + i * PGSIZE).unwrap())
+                .expect("map_range: no table");
+            assert!(pte.is_valid());
+            assert_eq!(pte.as_phys_addr().as_usize(), pa.as_usize() + i * PGSIZE);
+        }
+        assert!(pagetable.walk(VirtAddr::try_from(start + npages * PGSIZE).unwrap())
+            .is_none_or(|pte| !pte.is_valid()));
+
+        pagetable.unmap_pages(VirtAddr::try_from(start).unwrap(), npages, false).unwrap();
+        assert!(pagetable.unmap_pages(VirtAddr::try_from(start).unwrap(), 1, false).is_err());
+        pagetable.free_walk();
+    }
+    crate::kernel_test!(map_range);
+
+    /// The user pages and the table pages all go back, around a hole
+    pub fn uvm_free_all() {
+        let (free, _) = crate::mm::kalloc_stats();
+        let mut pagetable = PageTable::uvm_create();
+        let va = |i: usize| VirtAddr::try_from(i * PGSIZE).unwrap();
+        for i in [0, 1, 3].iter() {
+            pagetable.uvm_zero_page(va(*i)).unwrap();
+        }
+        pagetable.uvm_free(4 * PGSIZE);
+        assert!(pagetable.walk(va(0)).is_none());
+        drop(pagetable);
+        assert_eq!(crate::mm::kalloc_stats().0, free);
+    }
+    crate::kernel_test!(uvm_free_all);
+
+    /// Shrinking frees the pages above the new sz, around a hole, and keeps those below
+    pub fn uvm_shrink() {
+        let (free, _) = crate::mm::kalloc_stats();
+        let mut pagetable = PageTable::uvm_create();
+        let va = |i: usize| VirtAddr::try_from(i * PGSIZE).unwrap();
+        for i in [0, 1, 2, 4].iter() {
+            pagetable.uvm_zero_page(va(*i)).unwrap();
+        }
+
+        // va(3) was never touched
+        assert_eq!(pagetable.uvm_dealloc(5 * PGSIZE, PGSIZE + 8), PGSIZE + 8);
+        assert!(pagetable.walk(va(1)).is_some_and(|pte| pte.is_valid()));
+        for i in 2..5 {
+            assert!(pagetable.walk(va(i)).is_none_or(|pte| !pte.is_valid()));
+        }
+        assert_eq!(pagetable.uvm_dealloc(PGSIZE + 8, 2 * PGSIZE), PGSIZE + 8);
+
+        pagetable.uvm_free(PGSIZE + 8);
+        drop(pagetable);
+        assert_eq!(crate::mm::kalloc_stats().0, free);
+    }
+    crate::kernel_test!(uvm_shrink);
+
+    /// A copy has the same contents and permissions, around a hole, and is not dirty
+    pub fn uvm_copy_all() {
+        let (free, _) = crate::mm::kalloc_stats();
+        let mut pagetable = PageTable::uvm_create();
+        let va = |i: usize| VirtAddr::try_from(i * PGSIZE).unwrap();
+        let text = unsafe { kalloc() }.expect("uvm_copy_all: out of memory");
+        unsafe { ptr::write_bytes(text, 0x13, PGSIZE); }
+        pagetable.map_pages(va(0), PGSIZE, PhysAddr::try_from(text as usize).unwrap(),
+            PteFlag::R | PteFlag::X | PteFlag::U).unwrap();
+        pagetable.uvm_zero_page(va(2)).unwrap();
+        pagetable.copy_out(va(2).as_usize() + 8, &[9; 8]).unwrap();
+        pagetable.uvm_set_dirty(va(2));
+
+        // va(1) was never touched
+        let mut new = PageTable::uvm_create();
+        pagetable.uvm_copy(&mut new, 0, 3 * PGSIZE).unwrap();
+        let mut back = [0u8; 16];
+        new.copy_in(va(2).as_usize(), &mut back).unwrap();
+        assert_eq!(back[8..], [9; 8]);
+        new.copy_in(0, &mut back).unwrap();
+        assert_eq!(back, [0x13; 16]);
+        assert!(new.copy_out(0, &back).is_err());
+        assert!(new.walk(va(1)).is_none_or(|pte| !pte.is_valid()));
+        assert!(!new.walk(va(2)).unwrap().is_dirty());
+        new.copy_out(va(2).as_usize(), &[1; 8]).unwrap();
+        pagetable.copy_in(va(2).as_usize(), &mut back).unwrap();
+        assert_eq!(back[..8], [0; 8]);
+
+        new.uvm_free(3 * PGSIZE);
+        pagetable.uvm_free(3 * PGSIZE);
+        drop(new);
+        drop(pagetable);
+        assert_eq!(crate::mm::kalloc_stats().0, free);
+    }
+    crate::kernel_test!(uvm_copy_all);
+
+    /// Writable pages are dropped and come back as zero pages, text stays
+    pub fn dontneed() {
+        let mut pagetable = PageTable::uvm_create();
+        let va = |i: usize| VirtAddr::try_from(0x1000 + i * PGSIZE).unwrap();
+        let text = unsafe { kalloc() }.expect("dontneed: out of memory");
+        pagetable.map_pages(va(0), PGSIZE, PhysAddr::try_from(text as usize).unwrap(),
+            PteFlag::R | PteFlag::X | PteFlag::U).unwrap();
+        pagetable.uvm_zero_page(va(1)).unwrap();
+        pagetable.uvm_zero_page(va(3)).unwrap();
+        assert!(pagetable.uvm_zero_page(va(3)).is_err());
+        pagetable.copy_out(va(1).as_usize() + 8, &[9; 8]).unwrap();
+
+        // va(2) was never mapped
+        assert_eq!(pagetable.uvm_dontneed(va(0), 4), Ok(2));
+        assert!(pagetable.walk(va(0)).is_some_and(|pte| pte.is_valid()));
+        for i in 1..4 {
+            assert!(pagetable.walk(va(i)).is_none_or(|pte| !pte.is_valid()));
+        }
+        assert_eq!(pagetable.uvm_dontneed(va(0), 4), Ok(0));
+
+        pagetable.uvm_zero_page(va(1)).unwrap();
+        let mut back = [1u8; 16];
+        pagetable.copy_in(va(1).as_usize(), &mut back).unwrap();
+        assert_eq!(back, [0; 16]);
+
+        pagetable.unmap_pages(va(0), 2, true).unwrap();
+        pagetable.free_walk();
+    }
+    crate::kernel_test!(dontneed);
 }
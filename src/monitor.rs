@@ -0,0 +1,180 @@
+//! Kernel monitor, a small command interpreter on the console
+//!
+//! Entered with Ctrl-T from the uart interrupt, see console/mod.rs,
+//! or after a panic, when the kernel does not exit qemu.
+//! It polls the uart with interrupts off on its hart, the other harts go on,
+//! unless it was a panic, so that it can still look around.
+//! The dumps read the kernel's state without taking its locks,
+//! in case the monitor was entered after a panic with one of them held,
+//! what they print may be slightly off as other harts change things.
+//! Only kill takes the lock of each process.
+
+use core::str;
+
+use crate::console;
+use crate::consts::NCPU;
+use crate::driver::virtio_balloon;
+use crate::dtb;
+use crate::mm;
+use crate::printf;
+use crate::process::{self, cpu_id, PROC_MANAGER};
+use crate::spinlock;
+use crate::trap;
+use crate::watch;
+
+/// Ctrl-T
+pub const MAGIC: u8 = 0x14;
+
+const LINE: usize = 64;
+
+const HELP: &str = "\
+help            this
+ps              the process table
+pt PID          the page table of a process
+mem             free and total pages
+locks           spinlock acquisitions per hart, and how many of them spun
+traps           traps taken, by cause
+kill PID        set the killed flag of a process
+harts           which harts are online, going offline, or parked, and queue lengths
+offline HART    park a hart, it stops scheduling
+online HART     bring a parked hart back
+watch [ADDR]    show or set the kernel watchpoint, on stores to ADDR, in hex
+unwatch         clear the kernel watchpoint
+panic           panic on purpose
+c               leave the monitor";
+
+/// Run commands until c, from the uart interrupt.
+pub fn enter() {
+    println!();
+    println!("monitor on hart {}, help for commands", unsafe { cpu_id() });
+    run(false);
+    println!("leaving the monitor");
+}
+
+/// Run commands after a panic, never returns.
+pub fn enter_panicked() -> ! {
+    println!("monitor, help for commands");
+    run(true);
+    unreachable!()
+}
+
+fn run(panicked: bool) {
+    let mut line = [0u8; LINE];
+    loop {
+        print!("monitor> ");
+        printf::flush();
+        let n = read_line(&mut line);
+        let line = str::from_utf8(&line[..n]).unwrap_or("");
+        let mut words = line.split_ascii_whitespace();
+        let cmd = match words.next() {
+            Some(cmd) => cmd,
+            None => continue,
+        };
+        let word = words.next();
+        let arg = word.map(|arg| arg.parse::<usize>());
+        match (cmd, arg) {
+            ("help", None) => println!("{}", HELP),
+            ("ps", None) => unsafe { PROC_MANAGER.dump() },
+            ("pt", Some(Ok(pid))) => {
+                if !unsafe { PROC_MANAGER.dump_pagetable(pid) } {
+                    println!("no user process {}", pid);
+                }
+            }
+            ("mem", None) => {
+                let (free, total) = mm::kalloc_stats();
+                println!("{} of {} pages free", free, total);
+                if virtio_balloon::is_present() {
+                    let (pages, reclaimed) = virtio_balloon::stats();
+                    println!("{} pages in the balloon, {} reclaimed", pages, reclaimed);
+                }
+            }
+            ("locks", None) => {
+                for (hart, (acquired, contended)) in spinlock::lock_stats().iter().enumerate() {
+                    if *acquired != 0 {
+                        println!("hart {}: {} acquired, {} spun", hart, acquired, contended);
+                    }
+                }
+            }
+            ("traps", None) => {
+                for (cause, count) in trap::trap_counts().iter() {
+                    println!("{:<12} {}", cause, count);
+                }
+            }
+            ("kill", Some(Ok(pid))) => {
+                if let Err(err) = unsafe { PROC_MANAGER.kill(pid, 0, None) } {
+                    println!("kill {}: {}", pid, err);
+                }
+            }
+            ("harts", None) => {
+                let (offline, parked) = (process::offline(), process::parked());
+                for hart in (0..NCPU).filter(|hart| dtb::harts() & (1 << hart) != 0) {
+                    let state = match (offline & (1 << hart) != 0, parked & (1 << hart) != 0) {
+                        (_, true) => "parked",
+                        (true, false) => "going offline",
+                        (false, false) => "online",
+                    };
+                    println!("hart {}: {}, {} queued", hart, state, process::runq_len(hart));
+                }
+            }
+            ("offline", Some(Ok(hart))) | ("online", Some(Ok(hart))) => {
+                if let Err(str) = process::set_online(hart, cmd == "online") {
+                    println!("{}", str);
+                }
+            }
+            ("watch", None) => match watch::get() {
+                Some((addr, kind)) => println!("watching {:#x}, kind {}", addr, kind),
+                None => println!("no watchpoint, {} triggers a hart", watch::triggers()),
+            },
+            ("watch", Some(_)) => {
+                let addr = word.and_then(|word| usize::from_str_radix(word.trim_start_matches("0x"), 16).ok());
+                match addr.map(|addr| watch::set(addr, watch::WATCH_STORE)) {
+                    Some(Ok(())) => {}
+                    Some(Err(str)) => println!("{}", str),
+                    None => println!("bad address"),
+                }
+            }
+            ("unwatch", None) => {
+                if let Err(str) = watch::clear() {
+                    println!("{}", str);
+                }
+            }
+            ("panic", None) => panic!("monitor: panic on request"),
+            ("c", None) if panicked => println!("cannot go on after a panic"),
+            ("c", None) => return,
+            _ => println!("bad command, help for commands"),
+        }
+    }
+}
+
+/// Read a line into buf, return its length.
+/// The echo only goes to the console, not into the kernel message ring.
+fn read_line(buf: &mut [u8]) -> usize {
+    const BACKSPACE: u8 = 0x08;
+    const DELETE: u8 = 0x7f;
+
+    let mut n = 0;
+    loop {
+        let c = match console::uartgetc() {
+            Some(c) => c,
+            None => continue,
+        };
+        match c {
+            b'\r' | b'\n' => {
+                console::consputc(b'\n');
+                return n;
+            }
+            BACKSPACE | DELETE if n > 0 => {
+                n -= 1;
+                for c in [BACKSPACE, b' ', BACKSPACE] {
+                    console::consputc(c);
+                }
+            }
+            c if (c.is_ascii_graphic() || c == b' ') && n < buf.len() => {
+                buf[n] = c;
+                n += 1;
+                console::consputc(c);
+            }
+            _ => {}
+        }
+    }
+}
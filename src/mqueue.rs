@@ -0,0 +1,284 @@
+//! Message queues, like POSIX mq_open, mq_send and mq_receive
+//!
+//! A queue has a name, opening it by name again gives the same queue,
+//! and holds up to MQ_MAXMSG messages of up to MQ_MSGSIZE bytes each.
+//! mq_receive takes the message of the highest priority,
+//! the oldest one among those of the same priority.
+//! mq_send sleeps while the queue is full, and mq_receive while it is empty,
+//! unless the queue was opened with MQ_NONBLOCK, then they fail at once.
+//! A queue lives until it is unlinked and its last opener closed it.
+//!
+//! A queue is referred to by a descriptor, an index into the table of its openings,
+//! each behind a File, see mqalloc() in fs/file.rs, whose file descriptor the mq syscalls take,
+//! its last close closes the opening.
+
+use crate::consts::{MQ_MAXMSG, MQ_MSGSIZE, MQ_NAME, NMQUEUE, NMQOPEN};
+use crate::process::{my_proc, PROC_MANAGER};
+use crate::spinlock::{SpinLock, SpinLockGuard};
+
+/// mq_open flags
+pub const MQ_CREATE: usize = 1 << 0;
+pub const MQ_NONBLOCK: usize = 1 << 1;
+
+#[derive(Clone, Copy)]
+struct Msg {
+    prio: usize,
+    /// the order it was sent in, among all queues
+    seq: usize,
+    len: usize,
+    data: [u8; MQ_MSGSIZE],
+}
+
+struct Queue {
+    /// false for a free slot
+    used: bool,
+    refs: usize,
+    unlinked: bool,
+    name: [u8; MQ_NAME],
+    name_len: usize,
+    msgs: [Msg; MQ_MAXMSG],
+    n: usize,
+}
+
+impl Queue {
+    const fn new() -> Self {
+        Self {
+            used: false,
+            refs: 0,
+            unlinked: false,
+            name: [0; MQ_NAME],
+            name_len: 0,
+            msgs: [Msg { prio: 0, seq: 0, len: 0, data: [0; MQ_MSGSIZE] }; MQ_MAXMSG],
+            n: 0,
+        }
+    }
+
+    /// Whether it is the queue called name
+    fn named(&self, name: &[u8]) -> bool {
+        self.used && !self.unlinked && &self.name[..self.name_len] == name
+    }
+
+    /// Free it once it is unlinked and closed by all
+    fn put(&mut self) {
+        if self.unlinked && self.refs == 0 {
+            self.used = false;
+        }
+    }
+
+    /// The index of the next message to receive
+    fn next(&self) -> Option<usize> {
+        (0..self.n).max_by_key(|&i| (self.msgs[i].prio, usize::MAX - self.msgs[i].seq))
+    }
+}
+
+#[derive(Clone, Copy)]
+struct Open {
+    /// None for a free descriptor
+    queue: Option<usize>,
+    flags: usize,
+}
+
+struct Mqueues {
+    queues: [Queue; NMQUEUE],
+    opens: [Open; NMQOPEN],
+    seq: usize,
+}
+
+static MQUEUES: SpinLock<Mqueues> = SpinLock::new(Mqueues {
+    queues: [const { Queue::new() }; NMQUEUE],
+    opens: [Open { queue: None, flags: 0 }; NMQOPEN],
+    seq: 0,
+}, "mqueue");
+
+/// Senders and receivers of queue q sleep here,
+/// a change to it wakes them all, they are few
+fn chan(m: &Mqueues, q: usize) -> usize {
+    &m.queues[q] as *const Queue as usize
+}
+
+fn sleep<'a>(m: SpinLockGuard<'a, Mqueues>, q: usize)
+    -> Result<SpinLockGuard<'a, Mqueues>, &'static str>
+{
+    let p = unsafe { my_proc() };
+    if p.killed {
+        return Err("killed");
+    }
+    let chan = chan(&m, q);
+    Ok(p.sleep(chan, m))
+}
+
+/// The queue and flags of descriptor mqd
+fn lookup(m: &Mqueues, mqd: usize) -> Result<(usize, usize), &'static str> {
+    match m.opens.get(mqd) {
+        Some(Open { queue: Some(q), flags }) => Ok((*q, *flags)),
+        _ => Err("bad descriptor"),
+    }
+}
+
+/// Open the queue called name, creating it if flags has MQ_CREATE,
+/// return a descriptor for it.
+pub fn mq_open(name: &[u8], flags: usize) -> Result<usize, &'static str> {
+    let mut m = MQUEUES.lock();
+    if name.is_empty() || name.len() > MQ_NAME {
+        return Err("bad name");
+    }
+    let mqd = m.opens.iter().position(|open| open.queue.is_none()).ok_or("out of descriptors")?;
+    let q = match m.queues.iter().position(|q| q.named(name)) {
+        Some(q) => q,
+        None if flags & MQ_CREATE != 0 => {
+            let q = m.queues.iter().position(|q| !q.used).ok_or("out of queues")?;
+            let queue = &mut m.queues[q];
+            *queue = Queue::new();
+            queue.used = true;
+            queue.name[..name.len()].copy_from_slice(name);
+            queue.name_len = name.len();
+            q
+        }
+        None => return Err("no such queue"),
+    };
+    m.queues[q].refs += 1;
+    m.opens[mqd] = Open { queue: Some(q), flags };
+    Ok(mqd)
+}
+
+/// Send msg with priority prio
+pub fn mq_send(mqd: usize, msg: &[u8], prio: usize) -> Result<(), &'static str> {
+    let mut m = MQUEUES.lock();
+    let (q, flags) = lookup(&m, mqd)?;
+    if msg.len() > MQ_MSGSIZE {
+        return Err("message too long");
+    }
+    while m.queues[q].n == MQ_MAXMSG {
+        if flags & MQ_NONBLOCK != 0 {
+            return Err("queue full");
+        }
+        m = sleep(m, q)?;
+        lookup(&m, mqd)?;
+    }
+    m.seq += 1;
+    let seq = m.seq;
+    let queue = &mut m.queues[q];
+    let slot = &mut queue.msgs[queue.n];
+    slot.prio = prio;
+    slot.seq = seq;
+    slot.len = msg.len();
+    slot.data[..msg.len()].copy_from_slice(msg);
+    queue.n += 1;
+    unsafe { PROC_MANAGER.wakeup(chan(&m, q)); }
+    Ok(())
+}
+
+/// Receive the next message into buf, return its length and priority.
+/// buf must be able to hold any message, MQ_MSGSIZE bytes,
+/// a shorter one is refused before a message is taken off the queue.
+pub fn mq_receive(mqd: usize, buf: &mut [u8]) -> Result<(usize, usize), &'static str> {
+    let mut m = MQUEUES.lock();
+    let (q, flags) = lookup(&m, mqd)?;
+    if buf.len() < MQ_MSGSIZE {
+        return Err("buffer too small");
+    }
+    let i = loop {
+        if let Some(i) = m.queues[q].next() {
+            break i;
+        }
+        if flags & MQ_NONBLOCK != 0 {
+            return Err("queue empty");
+        }
+        m = sleep(m, q)?;
+        lookup(&m, mqd)?;
+    };
+    let queue = &mut m.queues[q];
+    let msg = queue.msgs[i];
+    buf[..msg.len].copy_from_slice(&msg.data[..msg.len]);
+    // the order of the others does not matter, next() looks at them all
+    queue.n -= 1;
+    queue.msgs[i] = queue.msgs[queue.n];
+    unsafe { PROC_MANAGER.wakeup(chan(&m, q)); }
+    Ok((msg.len, msg.prio))
+}
+
+/// Close descriptor mqd, the queue goes with its last opener if it was unlinked
+pub fn mq_close(mqd: usize) -> Result<(), &'static str> {
+    let mut m = MQUEUES.lock();
+    let (q, _) = lookup(&m, mqd)?;
+    m.opens[mqd].queue = None;
+    m.queues[q].refs -= 1;
+    m.queues[q].put();
+    // a process sleeping on it through this descriptor fails in lookup
+    unsafe { PROC_MANAGER.wakeup(chan(&m, q)); }
+    Ok(())
+}
+
+/// Remove the name of a queue, opening it again creates a new one,
+/// those who have it open can go on using it.
+pub fn mq_unlink(name: &[u8]) -> Result<(), &'static str> {
+    let mut m = MQUEUES.lock();
+    let q = m.queues.iter().position(|q| q.named(name)).ok_or("no such queue")?;
+    m.queues[q].unlinked = true;
+    m.queues[q].put();
+    Ok(())
+}
+
+#[cfg(feature = "unit_test")]
+pub mod tests {
+    use super::*;
+
+    /// Priorities, a full and an empty queue, and unlinking, without sleeping
+    pub fn priorities() {
+        assert!(mq_open(b"/prio", 0).is_err());
+        let tx = mq_open(b"/prio", MQ_CREATE | MQ_NONBLOCK).unwrap();
+        let rx = mq_open(b"/prio", MQ_NONBLOCK).unwrap();
+
+        let mut buf = [0u8; MQ_MSGSIZE];
+        assert!(mq_receive(rx, &mut buf).is_err());
+        assert!(mq_receive(rx, &mut buf[..1]).is_err());
+        assert!(mq_send(tx, &[0; MQ_MSGSIZE + 1], 0).is_err());
+
+        mq_send(tx, b"low", 1).unwrap();
+        mq_send(tx, b"high", 5).unwrap();
+        mq_send(tx, b"low2", 1).unwrap();
+        mq_send(tx, b"", 3).unwrap();
+        assert_eq!(mq_receive(rx, &mut buf), Ok((4, 5)));
+        assert_eq!(&buf[..4], b"high");
+        assert_eq!(mq_receive(rx, &mut buf), Ok((0, 3)));
+        assert_eq!(mq_receive(rx, &mut buf), Ok((3, 1)));
+        assert_eq!(&buf[..3], b"low");
+        assert_eq!(mq_receive(rx, &mut buf), Ok((4, 1)));
+        assert_eq!(&buf[..4], b"low2");
+
+        // a short buffer leaves the message for the next receive
+        mq_send(tx, b"kept", 2).unwrap();
+        assert!(mq_receive(rx, &mut buf[..MQ_MSGSIZE - 1]).is_err());
+        assert_eq!(mq_receive(rx, &mut buf), Ok((4, 2)));
+        assert_eq!(&buf[..4], b"kept");
+
+        for i in 0..MQ_MAXMSG {
+            mq_send(tx, &[i as u8], 0).unwrap();
+        }
+        assert!(mq_send(tx, b"full", 9).is_err());
+
+        // the unlinked queue stays for its openers, the name is free again
+        mq_unlink(b"/prio").unwrap();
+        assert!(mq_open(b"/prio", 0).is_err());
+        let fresh = mq_open(b"/prio", MQ_CREATE | MQ_NONBLOCK).unwrap();
+        assert!(mq_receive(fresh, &mut buf).is_err());
+        for i in 0..MQ_MAXMSG {
+            assert_eq!(mq_receive(rx, &mut buf), Ok((1, 0)));
+            assert_eq!(buf[0], i as u8);
+        }
+
+        mq_close(tx).unwrap();
+        mq_close(rx).unwrap();
+        assert!(mq_close(rx).is_err());
+
+        // a closed queue keeps its messages until it is unlinked
+        mq_send(fresh, b"kept", 0).unwrap();
+        mq_close(fresh).unwrap();
+        let again = mq_open(b"/prio", MQ_NONBLOCK).unwrap();
+        assert_eq!(mq_receive(again, &mut buf), Ok((4, 0)));
+        mq_unlink(b"/prio").unwrap();
+        mq_close(again).unwrap();
+        assert!(mq_open(b"/prio", 0).is_err());
+    }
+    crate::kernel_test!(priorities);
+}
@@ -0,0 +1,74 @@
+//! One-time initialization
+//!
+//! Callers that lose the race spin until the winner is done,
+//! like a spinlock, so it is usable before there is anything to sleep on,
+//! e.g., for the boot-time setup in rmain.rs.
+
+use core::sync::atomic::{AtomicUsize, Ordering};
+
+const INCOMPLETE: usize = 0;
+const RUNNING: usize = 1;
+const COMPLETE: usize = 2;
+
+pub struct Once {
+    state: AtomicUsize,
+}
+
+impl Once {
+    pub const fn new() -> Self {
+        Self {
+            state: AtomicUsize::new(INCOMPLETE),
+        }
+    }
+
+    /// Run f and return true on the first caller,
+    /// others wait until it has returned, then return false.
+    pub fn call_once<F: FnOnce()>(&self, f: F) -> bool {
+        if self.state
+            .compare_exchange(INCOMPLETE, RUNNING, Ordering::Acquire, Ordering::Acquire)
+            .is_ok()
+        {
+            f();
+            self.state.store(COMPLETE, Ordering::Release);
+            return true;
+        }
+        while self.state.load(Ordering::Acquire) != COMPLETE {
+            core::hint::spin_loop();
+        }
+        false
+    }
+
+    pub fn is_completed(&self) -> bool {
+        self.state.load(Ordering::Acquire) == COMPLETE
+    }
+}
+
+#[cfg(feature = "unit_test")]
+pub mod tests {
+    use super::*;
+
+    pub fn only_once() {
+        let once = Once::new();
+        let mut runs = 0;
+        assert!(!once.is_completed());
+        assert!(once.call_once(|| runs += 1));
+        assert!(!once.call_once(|| runs += 1));
+        assert_eq!(runs, 1);
+        assert!(once.is_completed());
+    }
+    crate::kernel_test!(only_once);
+
+    /// All harts race, exactly one runs it, and nobody returns before it is done
+    pub fn race() {
+        static ONCE: Once = Once::new();
+        static RUNS: AtomicUsize = AtomicUsize::new(0);
+        ONCE.call_once(|| {
+            for _ in 0..10000 {
+                core::hint::spin_loop();
+            }
+            RUNS.fetch_add(1, Ordering::SeqCst);
+        });
+        assert_eq!(RUNS.load(Ordering::SeqCst), 1);
+    }
+    crate::kernel_test!(race, smp);
+}
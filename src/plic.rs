@@ -2,21 +2,46 @@
 
 use core::ptr;
 
-use crate::consts::{PLIC, UART0_IRQ, VIRTIO0_IRQ};
+use crate::consts::{HAS_VIRTIO, PLIC, PLIC_SCONTEXT, UART0_IRQ, VIRTIO0_IRQ, VIRTIO3_IRQ};
 use crate::process::cpu_id;
 
 pub unsafe fn init() {
     // set desired IRQ priorities non-zero (otherwise disabled)
     write(UART0_IRQ*4, 1);
-    write(VIRTIO0_IRQ*4, 1);
+    if HAS_VIRTIO {
+        write(VIRTIO0_IRQ*4, 1);
+        write(VIRTIO3_IRQ*4, 1);
+    }
 }
 
 pub unsafe fn init_hart() {
     let hart: usize = cpu_id();
-    write(SENABLE+SENABLE_HART*hart, (1<<UART0_IRQ)|(1<<VIRTIO0_IRQ));
+    enable(hart, UART0_IRQ);
+    if HAS_VIRTIO {
+        enable(hart, VIRTIO0_IRQ);
+        enable(hart, VIRTIO3_IRQ);
+    }
     write(SPRIORITY+SPRIORITY_HART*hart, 0);
 }
 
+/// let irq interrupt hart's supervisor mode,
+/// 32 irqs per enable word, e.g., the FU740's uarts are in the second one
+pub fn enable(hart: usize, irq: usize) {
+    let offset = SENABLE + SENABLE_HART * hart + irq / 32 * 4;
+    write(offset, read(offset) | 1 << (irq % 32));
+}
+
+pub fn disable(hart: usize, irq: usize) {
+    let offset = SENABLE + SENABLE_HART * hart + irq / 32 * 4;
+    write(offset, read(offset) & !(1 << (irq % 32)));
+}
+
+/// keep device interrupts off hart's supervisor mode, or let them in again,
+/// the priorities are all 1, so a threshold of 1 masks them all
+pub fn set_masked(hart: usize, masked: bool) {
+    write(SPRIORITY + SPRIORITY_HART * hart, masked as u32);
+}
+
 /// ask the PLIC what interrupt we should serve
 pub fn claim() -> u32 {
     let hart: usize = unsafe {cpu_id()};
@@ -29,21 +54,22 @@ pub fn complete(irq: u32) {
     write(SCLAIM+SCLAIM_HART*hart, irq);
 }
 
-// qemu puts programmable interrupt controller here.
+// register offsets, a hart's supervisor context
+// is PLIC_SCONTEXT after its machine one, see consts/board.
 const PRIORITY: usize = 0x0;
 const PENDING: usize = 0x1000;
 
 const MENABLE: usize = 0x2000;
 const MENABLE_HART: usize = 0x100;
-const SENABLE: usize = 0x2080;
+const SENABLE: usize = MENABLE + 0x80 * PLIC_SCONTEXT;
 const SENABLE_HART: usize = 0x100;
 const MPRIORITY: usize = 0x200000;
 const MPRIORITY_HART: usize = 0x2000;
-const SPRIORITY: usize = 0x201000;
+const SPRIORITY: usize = MPRIORITY + 0x1000 * PLIC_SCONTEXT;
 const SPRIORITY_HART: usize = 0x2000;
 const MCLAIM: usize = 0x200004;
 const MCLAIM_HART: usize = 0x2000;
-const SCLAIM: usize = 0x201004;
+const SCLAIM: usize = MCLAIM + 0x1000 * PLIC_SCONTEXT;
 const SCLAIM_HART: usize = 0x2000;
 
 #[inline]
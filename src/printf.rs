@@ -1,5 +1,13 @@
-use crate::console;
-use crate::spinlock::SpinLock;
+use crate::console::{self, ansi::{self, Color, Esc}};
+use crate::consts::{NCPU, PGSIZE};
+use crate::dtb;
+use crate::kmsg::Kmsg;
+use crate::process::{cpu_id, my_pid, my_proc_info};
+#[cfg(not(feature = "sbi"))]
+use crate::register::clint;
+use crate::register::{fp, scause, sepc, sstatus, stval, wfi};
+use crate::spinlock::{push_off, pop_off, SpinLock};
+use crate::trap;
 use core::fmt;
 use core::panic;
 use core::sync::atomic::{AtomicBool, Ordering};
@@ -9,14 +17,19 @@ use core::sync::atomic::{AtomicBool, Ordering};
 /// Because we need another field(locking),
 /// to represent if we want to use the spinlock when printing.
 /// This trick can make `panic` print something to the console quicker.
+/// The kernel message ring is also kept here, protected by the same lock.
 struct Pr {
     locking: AtomicBool,
     lock: SpinLock<()>,
+    kmsg: Kmsg,
 }
 
 impl Pr {
-    fn print(&self, c: u8) {
-        console::consputc(c);
+    fn print(&mut self, c: u8) {
+        if shown() {
+            console::consputc(c);
+        }
+        self.kmsg.putc(c);
     }
 }
 
@@ -32,8 +45,109 @@ impl fmt::Write for Pr {
 static mut PR: Pr = Pr {
     locking: AtomicBool::new(true),
     lock: SpinLock::new((), "pr"),
+    kmsg: Kmsg::new(),
 };
 
+/// Per-hart line buffer
+///
+/// Formatted output is collected here until a newline (or a full buffer),
+/// and then written to the console as a whole under Pr's lock,
+/// so that lines printed by different harts do not interleave.
+struct LineBuf {
+    buf: [u8; LINE_BUF],
+    len: usize,
+}
+
+const LINE_BUF: usize = 256;
+
+impl LineBuf {
+    const fn new() -> Self {
+        Self {
+            buf: [0; LINE_BUF],
+            len: 0,
+        }
+    }
+
+    /// Write out the buffered bytes as a single line
+    unsafe fn flush(&mut self) {
+        if self.len == 0 {
+            return;
+        }
+
+        let guard = PR.lock.lock();
+        PR.kmsg.stamp(trap::ticks(), cpu_id());
+        if PREFIX.load(Ordering::Relaxed) && shown() {
+            write_prefix();
+        }
+        for i in 0..self.len {
+            PR.print(self.buf[i]);
+        }
+        console::consflush();
+        drop(guard);
+        self.len = 0;
+    }
+}
+
+impl fmt::Write for LineBuf {
+    fn write_str(&mut self, s: &str) -> fmt::Result {
+        for byte in s.bytes() {
+            self.buf[self.len] = byte;
+            self.len += 1;
+            if byte == b'\n' || self.len == LINE_BUF {
+                unsafe { self.flush(); }
+            }
+        }
+        Ok(())
+    }
+}
+
+/// Interrupts must be disabled when accessing current hart's line buffer.
+static mut LINES: [LineBuf; NCPU] = [const { LineBuf::new() }; NCPU];
+
+/// Whether to tag each console line with hart id and pid
+static PREFIX: AtomicBool = AtomicBool::new(false);
+
+/// Turn on/off the "[hartN pid M] " prefix of console lines.
+/// The kernel message ring always records the hart id.
+pub fn set_prefix(on: bool) {
+    PREFIX.store(on, Ordering::Relaxed);
+}
+
+/// Whether kernel messages only go to the message ring, not to the console
+static QUIET: AtomicBool = AtomicBool::new(false);
+
+/// Turn on/off printing kernel messages on the console,
+/// e.g., for loglevel=0 on the command line, see cmdline.rs.
+/// Panic messages are always printed.
+pub fn set_quiet(on: bool) {
+    QUIET.store(on, Ordering::Relaxed);
+}
+
+/// Whether kernel messages go to the console, not only to the message ring,
+/// it may be quiet, or show another virtual console, see console/vt.rs.
+fn shown() -> bool {
+    !QUIET.load(Ordering::Relaxed) && console::vt::log_shown()
+}
+
+/// Write the line prefix to the console only,
+/// not into the kernel message ring.
+unsafe fn write_prefix() {
+    struct Console;
+    impl fmt::Write for Console {
+        fn write_str(&mut self, s: &str) -> fmt::Result {
+            for byte in s.bytes() {
+                console::consputc(byte);
+            }
+            Ok(())
+        }
+    }
+
+    let _ = match my_pid() {
+        Some(pid) => fmt::write(&mut Console, format_args!("[hart{} pid {}] ", cpu_id(), pid)),
+        None => fmt::write(&mut Console, format_args!("[hart{}] ", cpu_id())),
+    };
+}
+
 /// used only in printf's print macro
 ///
 /// note: it needs to be pub because it is used in macro_rules,
@@ -41,21 +155,98 @@ static mut PR: Pr = Pr {
 pub fn _print(args: fmt::Arguments) {
     use fmt::Write;
 
+    if !console::is_ready() {
+        console::early::_print(args);
+        return;
+    }
+
     unsafe {
         if PR.locking.load(Ordering::Relaxed) {
-            let guard = PR.lock.lock();
-            PR.write_fmt(args).expect("_print: error");
-            drop(guard);
+            push_off();
+            LINES[cpu_id()].write_fmt(args).expect("_print: error");
+            pop_off();
         } else {
+            // do not touch the ticks lock, it might be held by the panicking hart
+            // also write out what is left in this hart's line buffer
+            let line = &mut LINES[cpu_id()];
+            PR.kmsg.stamp(0, cpu_id());
+            for i in 0..line.len {
+                PR.print(line.buf[i]);
+            }
+            line.len = 0;
             PR.write_fmt(args).expect("_print: error");
         }
     }
 }
 
+/// Write out current hart's incomplete line, e.g., a prompt without newline
+pub fn flush() {
+    unsafe {
+        if PR.locking.load(Ordering::Relaxed) {
+            push_off();
+            LINES[cpu_id()].flush();
+            pop_off();
+        }
+    }
+}
+
+/// Copy buffered kernel messages, starting at sequence number seq, into dst.
+/// Return (bytes copied, sequence number to continue from).
+/// Messages already overwritten in the ring are skipped.
+pub fn kmsg_read(seq: usize, dst: &mut [u8]) -> (usize, usize) {
+    unsafe {
+        let guard = PR.lock.lock();
+        let ret = PR.kmsg.read(seq, dst);
+        drop(guard);
+        ret
+    }
+}
+
+/// kmsg_read() without the lock, for the crash dump once the other harts are frozen,
+/// one of them may be holding it
+#[cfg(feature = "crashdump")]
+pub unsafe fn kmsg_read_frozen(seq: usize, dst: &mut [u8]) -> (usize, usize) {
+    PR.kmsg.read(seq, dst)
+}
+
+/// Sequence number of the next byte into the kernel message ring
+pub fn kmsg_seq() -> usize {
+    unsafe {
+        let guard = PR.lock.lock();
+        let seq = PR.kmsg.seq();
+        drop(guard);
+        seq
+    }
+}
+
+/// Record a line into the kernel message ring only, not on the console,
+/// e.g., process events, see proclog.rs.
+pub fn kmsg_log(args: fmt::Arguments) {
+    struct Ring;
+    impl fmt::Write for Ring {
+        fn write_str(&mut self, s: &str) -> fmt::Result {
+            for byte in s.bytes() {
+                unsafe { PR.kmsg.putc(byte); }
+            }
+            Ok(())
+        }
+    }
+
+    unsafe {
+        if !PR.locking.load(Ordering::Relaxed) {
+            return;
+        }
+        let guard = PR.lock.lock();
+        PR.kmsg.stamp(trap::ticks(), cpu_id());
+        let _ = fmt::write(&mut Ring, args);
+        drop(guard);
+    }
+}
+
 #[macro_export]
 macro_rules! print {
     ($($arg:tt)*) => {
-        $crate::printf::_print(format_args!($($arg)*));
+        $crate::printf::_print(format_args!($($arg)*))
     };
 }
 
@@ -68,13 +259,112 @@ macro_rules! println {
     };
 }
 
+/// Set by the first hart that panics.
+/// Other harts freeze once they notice it, see trap.rs.
+static PANICKED: AtomicBool = AtomicBool::new(false);
+
+#[inline]
+pub fn panicked() -> bool {
+    PANICKED.load(Ordering::Relaxed)
+}
+
+/// Spin here forever with interrupts off,
+/// saving this hart's registers for the crash dump first, see crashdump.rs
+pub fn freeze() -> ! {
+    sstatus::intr_off();
+    #[cfg(feature = "crashdump")]
+    crate::crashdump::save_hart();
+    loop {
+        wfi();
+    }
+}
+
+/// Fill ras with the return addresses of the kernel call stack,
+/// by walking the frame pointers, return how many there are.
+/// It stops at the end of the current stack page.
+#[inline(always)]
+pub fn return_addrs(ras: &mut [usize]) -> usize {
+    let mut frame = fp::read();
+    let top = (frame + PGSIZE - 1) & !(PGSIZE - 1);
+    for (n, slot) in ras.iter_mut().enumerate() {
+        // ra at fp-8, previous fp at fp-16
+        if !frame.is_multiple_of(8) || frame <= top - PGSIZE || frame > top {
+            return n;
+        }
+        *slot = unsafe { *((frame - 8) as *const usize) };
+        frame = unsafe { *((frame - 16) as *const usize) };
+    }
+    ras.len()
+}
+
+/// Print the return addresses of the kernel call stack
+pub fn backtrace() {
+    println!("backtrace:");
+    let mut ras = [0; 32];
+    let n = return_addrs(&mut ras);
+    for ra in ras[..n].iter() {
+        println!("  {:#x}", ra);
+    }
+}
+
+/// On panic, stop the other harts by IPI first,
+/// so that they do not garble the output,
+/// then dump the state of the panicking hart.
 #[panic_handler]
 fn panic(info: &panic::PanicInfo) -> ! {
+    if PANICKED.swap(true, Ordering::SeqCst) {
+        // another hart is already dumping, or we panicked while dumping
+        freeze();
+    }
+
     unsafe {
         PR.locking.store(false, Ordering::Relaxed);
+        QUIET.store(false, Ordering::Relaxed);
+
+        let id = cpu_id();
+        #[cfg(not(feature = "sbi"))]
+        for hart in (0..NCPU).filter(|hart| *hart != id && dtb::harts() & (1 << hart) != 0) {
+            clint::send_msip(hart);
+        }
+        #[cfg(feature = "sbi")]
+        crate::sbi::send_ipi(dtb::harts() & !(1 << id));
+
+        crate::println!();
+        #[cfg(feature = "unit_test")]
+        if let Some(name) = crate::test::current() {
+            crate::println!("{}test {} ... FAILED{}", ansi::fg(Color::Red), name, ansi::reset());
+        }
+        crate::println!("{}{}panic on hart {}: {}{}",
+            Esc::Bold, ansi::fg(Color::Red), id, info, ansi::reset());
+        match my_proc_info() {
+            Some((pid, name)) => crate::println!("current process: pid {} ({})", pid, name),
+            None => crate::println!("current process: none"),
+        }
+        if crate::fs::in_flight() > 0 {
+            crate::println!("{} file system operations in flight, not committed", crate::fs::in_flight());
+        }
     }
-    crate::println!("{}", info);
-    loop {}
+    crate::println!("sepc={:#x} scause={:#x} stval={:#x} sstatus={:#x}",
+        sepc::read(), scause::read(), stval::read(), sstatus::read());
+    backtrace();
+
+    #[cfg(feature = "ftrace")]
+    if crate::ftrace::enabled() {
+        crate::ftrace::dump();
+    }
+
+    #[cfg(feature = "crashdump")]
+    crate::crashdump::dump(info);
+
+    #[cfg(feature = "qemu_exit")]
+    crate::driver::qemu::exit(1);
+
+    // a dump is rather looked at after the reboot than in the monitor
+    #[cfg(all(feature = "crashdump", not(feature = "qemu_exit")))]
+    crate::crashdump::reboot();
+
+    #[cfg(not(any(feature = "qemu_exit", feature = "crashdump")))]
+    crate::monitor::enter_panicked();
 }
 
 #[no_mangle]
@@ -84,23 +374,15 @@ fn abort() -> ! {
 
 #[cfg(feature = "unit_test")]
 pub mod tests {
-    use crate::consts::NSMP;
-    use crate::proc::cpu_id;
-    use core::sync::atomic::{AtomicU8, Ordering};
+    use crate::process::cpu_id;
 
+    /// All harts print at the same time, lines must not interleave
     pub fn println_simo() {
         let cpu_id = unsafe { cpu_id() };
 
-        // use NSMP to synchronize testing pr's spinlock
-        static NSMP: AtomicU8 = AtomicU8::new(0);
-        NSMP.fetch_add(1, Ordering::Relaxed);
-        while NSMP.load(Ordering::Relaxed) != NSMP as u8 {}
-
         for i in 0..10 {
             println!("println_mul_hart{}: hart {}", i, cpu_id);
         }
-
-        NSMP.fetch_sub(1, Ordering::Relaxed);
-        while NSMP.load(Ordering::Relaxed) != 0 {}
     }
+    crate::kernel_test!(println_simo, smp);
 }
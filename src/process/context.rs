@@ -64,3 +64,17 @@ impl Context {
         self.sp = sp;
     }
 }
+
+/// Offsets of the fields swtch.S relies on, for the boot self-test
+#[cfg(feature = "selftest")]
+pub fn layout() -> [(&'static str, usize); 4] {
+    let c = Context::new();
+    let base = &c as *const Context as usize;
+    let off = |field: &usize| field as *const usize as usize - base;
+    [
+        ("ra", off(&c.ra)),
+        ("sp", off(&c.sp)),
+        ("s0", off(&c.s0)),
+        ("s11", off(&c.s11)),
+    ]
+}
@@ -0,0 +1,598 @@
+//! Core dumps of user processes killed by a fault, with the coredump boot option
+//!
+//! A core is saved as /core.PID, in the kernel's own layout, little endian:
+//! - a CoreHeader, with the cause of the fault and how many regions and mappings follow
+//! - the user registers, pc then x1-x31, as ptrace gives them
+//! - a CoreRegion per run of user pages with the same permissions,
+//!   the layout of the address space
+//! - a CoreVma per mapping of a file, see vma.rs, whose touched pages are among the regions
+//! - the bytes of each region, in the same order
+//!
+//! A checkpoint, saved by the checkpoint syscall, is a core with CKPT_MAGIC,
+//! the registers as the call returns them, followed by a CkptTail,
+//! with the size of the memory, its guard page and the descriptors.
+//! Restoring one starts it as a new child of the caller, see Checkpoint.
+//! Its files and devices are opened again by the numbers of their inodes, see fs::reopen(),
+//! with their offsets, the other descriptors, pipes, sockets and the like, cannot be,
+//! the child has those of the caller with the same numbers instead.
+//! A process with mappings of files is not saved, they are not restored.
+
+use core::convert::TryFrom;
+use core::mem;
+use core::ptr;
+use core::slice;
+
+use crate::cmdline;
+use crate::consts::{NOFILE, NVMA, PGSIZE, TRAPFRAME};
+use crate::fs::{self, File, O_CREATE, O_TRUNC, O_WRONLY};
+use crate::mm::{kalloc, kfree, Box, PageTable, PhysAddr, PteFlag, VirtAddr};
+
+use super::elf::{read_file, ElfReader};
+use super::proc::Proc;
+
+pub const CORE_MAGIC: [u8; 8] = *b"xv6core\0";
+pub const CKPT_MAGIC: [u8; 8] = *b"xv6ckpt\0";
+
+/// a0 among the user registers, x10
+const A0: usize = 10;
+
+/// Flags of a descriptor in a checkpoint,
+/// CKPT_INODE with the number of its inode, a file or a device, and its offset
+pub const CKPT_OPEN: usize = 1;
+pub const CKPT_INODE: usize = 2;
+pub const CKPT_CLOEXEC: usize = 4;
+pub const CKPT_NONBLOCK: usize = 8;
+pub const CKPT_READABLE: usize = 16;
+pub const CKPT_WRITABLE: usize = 32;
+
+#[repr(C)]
+#[derive(Default)]
+pub struct CoreHeader {
+    pub magic: [u8; 8],
+    pub pid: usize,
+    pub name: [u8; 16],
+    pub scause: usize,
+    pub sepc: usize,
+    pub stval: usize,
+    pub nregion: usize,
+    pub nvma: usize,
+}
+
+#[repr(C)]
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Default)]
+pub struct CoreRegion {
+    pub start: usize,
+    pub len: usize,
+    /// R, W and X as in a PTE
+    pub flags: usize,
+}
+
+/// A mapping of a file, at [start, end) from off, prot and flags as mmap() takes them
+#[repr(C)]
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Default)]
+pub struct CoreVma {
+    pub start: usize,
+    pub end: usize,
+    pub prot: usize,
+    pub flags: usize,
+    pub off: usize,
+}
+
+/// What follows the regions of a checkpoint
+#[repr(C)]
+#[derive(Clone, Copy, Default)]
+pub struct CkptTail {
+    pub sz: usize,
+    /// the page mapped without U below the stack, 0 for none
+    pub guard: usize,
+    /// RLIMIT_NOFILE, soft and hard
+    pub nofile: [usize; 2],
+    pub files: [CkptFile; NOFILE],
+}
+
+#[repr(C)]
+#[derive(Clone, Copy, Default)]
+pub struct CkptFile {
+    pub flags: usize,
+    pub inum: usize,
+    pub off: usize,
+}
+
+/// A checkpoint read back, for ProcManager::restore to start as a child, see Proc::restore_from
+pub struct Checkpoint {
+    /// the user memory, without the trampoline and trapframe, those are the child's
+    pagetable: Box<PageTable>,
+    sz: usize,
+    regs: [usize; 32],
+    name: [u8; 16],
+    nofile: [usize; 2],
+    /// the files and devices opened again
+    files: [Option<&'static File>; NOFILE],
+    /// open but not opened again, the child has the caller's
+    inherit: [bool; NOFILE],
+    cloexec: [bool; NOFILE],
+}
+
+/// Where a core goes, a chunk at a time
+type Sink<'a> = dyn FnMut(&[u8]) -> Result<(), &'static str> + 'a;
+
+fn as_bytes<T>(value: &T) -> &[u8] {
+    unsafe { slice::from_raw_parts(value as *const T as *const u8, mem::size_of::<T>()) }
+}
+
+/// Call f with every region of pagetable's user pages
+fn regions(pagetable: &PageTable, f: &mut impl FnMut(CoreRegion)) {
+    let mut run: Option<CoreRegion> = None;
+    pagetable.for_each_user_page(&mut |va, perm| {
+        match run.as_mut() {
+            Some(r) if r.start + r.len == va && r.flags == perm.bits() => r.len += PGSIZE,
+            _ => {
+                if let Some(r) = run.take() {
+                    f(r);
+                }
+                run = Some(CoreRegion { start: va, len: PGSIZE, flags: perm.bits() });
+            }
+        }
+    });
+    if let Some(r) = run {
+        f(r);
+    }
+}
+
+/// Write the core of the user memory in pagetable with its mappings vmas,
+/// header.nregion and header.nvma are filled in
+pub fn write_core(pagetable: &PageTable, mut header: CoreHeader, regs: &[usize; 32], vmas: &[CoreVma],
+    sink: &mut Sink) -> Result<(), &'static str>
+{
+    header.nregion = 0;
+    regions(pagetable, &mut |_| header.nregion += 1);
+    header.nvma = vmas.len();
+    sink(as_bytes(&header))?;
+    sink(as_bytes(regs))?;
+
+    let mut result = Ok(());
+    regions(pagetable, &mut |r| if result.is_ok() { result = sink(as_bytes(&r)) });
+    for vma in vmas {
+        result = result.and_then(|()| sink(as_bytes(vma)));
+    }
+    regions(pagetable, &mut |r| {
+        // the kernel stack is only a page, copy a bit at a time
+        let mut chunk = [0u8; 256];
+        let mut va = r.start;
+        while result.is_ok() && va < r.start + r.len {
+            result = pagetable.copy_in(va, &mut chunk).and_then(|()| sink(&chunk));
+            va += chunk.len();
+        }
+    });
+    result
+}
+
+/// "/core.PID" in buf, nul-terminated
+fn core_path(pid: usize, buf: &mut [u8; 32]) -> &[u8] {
+    let prefix = b"/core.";
+    buf[..prefix.len()].copy_from_slice(prefix);
+    let mut digits = [0u8; 20];
+    let (mut n, mut i) = (pid, digits.len());
+    loop {
+        i -= 1;
+        digits[i] = b'0' + (n % 10) as u8;
+        n /= 10;
+        if n == 0 {
+            break;
+        }
+    }
+    let len = prefix.len() + digits.len() - i;
+    buf[prefix.len()..len].copy_from_slice(&digits[i..]);
+    buf[len] = 0;
+    &buf[..len + 1]
+}
+
+/// The guard page below the stack, the lowest page below sz mapped without U, 0 for none
+fn guard_page(pagetable: &PageTable, sz: usize) -> usize {
+    let mut guard = 0;
+    pagetable.for_each_kernel_page(&mut |va| if guard == 0 && va < sz { guard = va });
+    guard
+}
+
+/// Map a new page at va with perm, as fill fills it, unless va is mapped already
+fn map_page(pagetable: &mut PageTable, va: usize, perm: PteFlag,
+    fill: impl FnOnce(&mut [u8]) -> Result<(), &'static str>) -> Result<(), &'static str>
+{
+    let page = VirtAddr::try_from(va)?;
+    if pagetable.walk(page).is_some_and(|pte| pte.is_valid()) {
+        return Err("restore: regions overlap");
+    }
+    let pa = unsafe { kalloc() }.ok_or("restore: out of memory")?;
+    let mapped = fill(unsafe { slice::from_raw_parts_mut(pa, PGSIZE) })
+        .and_then(|()| pagetable.map_pages(page, PGSIZE, PhysAddr::try_from(pa as usize).unwrap(), perm));
+    if mapped.is_err() {
+        unsafe { kfree(pa); }
+    }
+    mapped
+}
+
+/// Map the regions of the checkpoint read gives into pagetable, and its guard page,
+/// return its header, registers and tail
+fn build_checkpoint(pagetable: &mut PageTable, read: &mut ElfReader)
+    -> Result<(CoreHeader, [usize; 32], CkptTail), &'static str>
+{
+    let header: CoreHeader = read_file(read, 0)?;
+    if header.magic != CKPT_MAGIC {
+        return Err("restore: not a checkpoint");
+    }
+    if header.nvma != 0 {
+        return Err("restore: mappings of files are not restored");
+    }
+    let regs: [usize; 32] = read_file(read, mem::size_of::<CoreHeader>())?;
+    let table = mem::size_of::<CoreHeader>() + mem::size_of_val(&regs);
+    let region = |read: &mut ElfReader, i: usize| {
+        read_file::<CoreRegion>(read, table + i * mem::size_of::<CoreRegion>())
+    };
+    let data = header.nregion.checked_mul(mem::size_of::<CoreRegion>())
+        .and_then(|len| len.checked_add(table))
+        .ok_or("restore: too many regions")?;
+    let mut end = data;
+    for i in 0..header.nregion {
+        end = end.checked_add(region(read, i)?.len).ok_or("restore: regions too long")?;
+    }
+    let tail: CkptTail = read_file(read, end)?;
+    if tail.sz > TRAPFRAME.into() {
+        return Err("restore: memory too big");
+    }
+
+    let mut off = data;
+    for i in 0..header.nregion {
+        let r = region(read, i)?;
+        if !r.start.is_multiple_of(PGSIZE) || !r.len.is_multiple_of(PGSIZE) || r.start.saturating_add(r.len) > tail.sz {
+            return Err("restore: bad region");
+        }
+        let perm = PteFlag::from_bits_truncate(r.flags) & (PteFlag::R | PteFlag::W | PteFlag::X);
+        for va in (r.start..r.start + r.len).step_by(PGSIZE) {
+            map_page(pagetable, va, perm | PteFlag::U, |page| read(off, page))?;
+            off += PGSIZE;
+        }
+    }
+    if tail.guard != 0 {
+        if !tail.guard.is_multiple_of(PGSIZE) || tail.guard >= tail.sz {
+            return Err("restore: bad guard page");
+        }
+        map_page(pagetable, tail.guard, PteFlag::R | PteFlag::W, |page| {
+            page.fill(0);
+            Ok(())
+        })?;
+    }
+    Ok((header, regs, tail))
+}
+
+impl Proc {
+    /// Dump the core of the process, about to be killed by a fault,
+    /// if the kernel was booted with coredump.
+    pub fn core_dump(&self, scause: usize, sepc: usize, stval: usize) {
+        if !cmdline::has("coredump") {
+            return;
+        }
+        let pagetable = match self.pagetable.as_ref() {
+            Some(pagetable) => pagetable,
+            None => return,
+        };
+        let mut header = CoreHeader {
+            magic: CORE_MAGIC,
+            pid: self.pid,
+            name: [0; 16],
+            scause,
+            sepc,
+            stval,
+            nregion: 0,
+            nvma: 0,
+        };
+        let name = self.name().as_bytes();
+        header.name[..name.len()].copy_from_slice(name);
+        let regs = unsafe { (*self.tf).user_regs() };
+        let mut vmas = [CoreVma::default(); NVMA];
+        let nvma = self.core_vmas(&mut vmas);
+
+        let mut buf = [0u8; 32];
+        let path = core_path(self.pid, &mut buf);
+        let path_str = core::str::from_utf8(&path[..path.len() - 1]).unwrap();
+        let mut size = 0;
+        let result = fs::fileopen(path, O_WRONLY | O_CREATE | O_TRUNC).and_then(|f| {
+            let written = write_core(pagetable, header, &regs, &vmas[..nvma], &mut |bytes| {
+                match fs::filewrite(f, bytes)? {
+                    n if n == bytes.len() => {
+                        size += n;
+                        Ok(())
+                    }
+                    _ => Err("short write"),
+                }
+            });
+            fs::fileclose(f);
+            written
+        });
+        if let Err(str) = result {
+            println!("core dump of pid {} to {}: {}", self.pid, path_str, str);
+            return;
+        }
+        println!("core dump of pid {} ({}) saved to {}, {} bytes", self.pid, self.name(), path_str, size);
+        let flag = |flags: usize, f: PteFlag, c: char| if flags & f.bits() != 0 { c } else { '-' };
+        regions(pagetable, &mut |r| {
+            println!("  {:#x}-{:#x} {}{}{}", r.start, r.start + r.len,
+                flag(r.flags, PteFlag::R, 'r'), flag(r.flags, PteFlag::W, 'w'), flag(r.flags, PteFlag::X, 'x'));
+        });
+        for vma in &vmas[..nvma] {
+            println!("  {:#x}-{:#x} mapping, prot {:#x}, flags {:#x}, off {:#x}",
+                vma.start, vma.end, vma.prot, vma.flags, vma.off);
+        }
+    }
+
+    /// Save the process to a new file at path, with its registers as the call returns them,
+    /// but 1 in a0 for the one restored from it, see restore_from().
+    /// Mappings of files are not restored, a process with any is not saved.
+    pub fn checkpoint(&mut self, path: &[u8]) -> Result<(), &'static str> {
+        if self.vma.iter().any(Option::is_some) {
+            return Err("checkpoint: mappings of files are not saved");
+        }
+        let f = fs::fileopen(path, O_WRONLY | O_CREATE | O_TRUNC)?;
+        let written = self.write_checkpoint(f);
+        fs::fileclose(f);
+        written
+    }
+
+    fn write_checkpoint(&self, f: &File) -> Result<(), &'static str> {
+        let pagetable = self.pagetable.as_ref().ok_or("checkpoint: no user memory")?;
+        let mut regs = unsafe { (*self.tf).user_regs() };
+        regs[A0] = 1;
+        let mut header = CoreHeader { magic: CKPT_MAGIC, pid: self.pid, sepc: regs[0], ..CoreHeader::default() };
+        let name = self.name().as_bytes();
+        header.name[..name.len()].copy_from_slice(name);
+
+        let mut tail = CkptTail {
+            sz: self.sz(),
+            guard: guard_page(pagetable, self.sz()),
+            nofile: [self.nofile.0, self.nofile.1],
+            ..CkptTail::default()
+        };
+        for (fd, saved) in tail.files.iter_mut().enumerate() {
+            let f = match self.ofile[fd] {
+                Some(f) => f,
+                None => continue,
+            };
+            saved.flags = CKPT_OPEN;
+            if self.cloexec[fd] {
+                saved.flags |= CKPT_CLOEXEC;
+            }
+            if f.nonblock() {
+                saved.flags |= CKPT_NONBLOCK;
+            }
+            if f.readable() {
+                saved.flags |= CKPT_READABLE;
+            }
+            if f.writable() {
+                saved.flags |= CKPT_WRITABLE;
+            }
+            if let Some(inum) = f.inum() {
+                saved.flags |= CKPT_INODE;
+                saved.inum = inum as usize;
+                saved.off = f.offset().unwrap_or(0) as usize;
+            }
+        }
+
+        let mut sink = |bytes: &[u8]| match fs::filewrite(f, bytes)? {
+            n if n == bytes.len() => Ok(()),
+            _ => Err("checkpoint: short write"),
+        };
+        write_core(pagetable, header, &regs, &[], &mut sink)?;
+        sink(as_bytes(&tail))
+    }
+
+    /// Make this proc, fresh from alloc_proc(), a child of parent running the process saved in ckpt:
+    /// a copy of its memory, its registers, returning 1 in a0 from the checkpoint, its name,
+    /// the files and devices ckpt opened again, the descriptors of parent the others were,
+    /// and the directories, credentials and scheduling of parent, as fork gives them.
+    /// On failure the memory copied so far is left for free().
+    /// p->lock must be held.
+    pub fn restore_from(&mut self, parent: &Proc, ckpt: &mut Checkpoint) -> Result<(), &'static str> {
+        self.copy_image(&ckpt.pagetable, ckpt.sz)?;
+        unsafe {
+            ptr::copy_nonoverlapping(parent.tf, self.tf, 1);
+            (*self.tf).set_user_regs(&ckpt.regs);
+        }
+        self.set_name(&ckpt.name);
+        self.rt_prio = parent.rt_prio;
+        self.quantum = parent.quantum;
+        self.cred = parent.cred;
+        self.root = parent.root.map(fs::idup);
+        self.cwd = parent.cwd.map(fs::idup);
+        for fd in 0..NOFILE {
+            self.ofile[fd] = match ckpt.files[fd].take() {
+                Some(f) => Some(f),
+                None if ckpt.inherit[fd] => parent.ofile[fd].map(fs::filedup),
+                None => None,
+            };
+            self.cloexec[fd] = ckpt.cloexec[fd] && self.ofile[fd].is_some();
+        }
+        self.nofile = parent.nofile;
+        // the caller's limits stay if it may not raise them to the saved ones
+        let _ = self.set_nofile(ckpt.nofile[0], ckpt.nofile[1]);
+        Ok(())
+    }
+}
+
+impl Checkpoint {
+    /// Read the checkpoint at path, its memory into a page table of its own,
+    /// and open its files and devices again, at their offsets.
+    pub fn read(path: &[u8]) -> Result<Self, &'static str> {
+        let op = fs::begin_op();
+        let ip = fs::namei(&op, path)?;
+        let mut guard = fs::ilock(ip);
+        let mut read = |off: usize, buf: &mut [u8]| -> Result<(), &'static str> {
+            let off = u32::try_from(off).map_err(|_| "restore: offset out of range")?;
+            match guard.readi(off, buf)? {
+                n if n == buf.len() => Ok(()),
+                _ => Err("restore: short read"),
+            }
+        };
+        let mut pagetable = PageTable::uvm_create();
+        let built = build_checkpoint(&mut pagetable, &mut read);
+        drop(guard);
+        fs::iput(&op, ip);
+        drop(op);
+        let (header, regs, tail) = match built {
+            Ok(built) => built,
+            Err(str) => {
+                pagetable.uvm_free(TRAPFRAME.into());
+                return Err(str);
+            }
+        };
+
+        let mut ckpt = Checkpoint {
+            pagetable,
+            sz: tail.sz,
+            regs,
+            name: header.name,
+            nofile: tail.nofile,
+            files: [None; NOFILE],
+            inherit: [false; NOFILE],
+            cloexec: [false; NOFILE],
+        };
+        for (fd, saved) in tail.files.iter().enumerate() {
+            if saved.flags & CKPT_OPEN == 0 {
+                continue;
+            }
+            ckpt.cloexec[fd] = saved.flags & CKPT_CLOEXEC != 0;
+            if saved.flags & CKPT_INODE == 0 {
+                ckpt.inherit[fd] = true;
+                continue;
+            }
+            let inum = u32::try_from(saved.inum).map_err(|_| "restore: bad inode number")?;
+            let off = u32::try_from(saved.off).map_err(|_| "restore: offset out of range")?;
+            let f = fs::reopen(inum, saved.flags & CKPT_READABLE != 0, saved.flags & CKPT_WRITABLE != 0)?;
+            ckpt.files[fd] = Some(f);
+            f.set_nonblock(saved.flags & CKPT_NONBLOCK != 0);
+            f.set_offset(off);
+        }
+        Ok(ckpt)
+    }
+}
+
+impl Drop for Checkpoint {
+    /// Close the files restore_from() did not take, and free the memory it copied
+    fn drop(&mut self) {
+        for f in self.files.iter_mut().filter_map(Option::take) {
+            fs::fileclose(f);
+        }
+        self.pagetable.uvm_free(self.sz);
+    }
+}
+
+#[cfg(feature = "unit_test")]
+pub mod tests {
+    use core::ptr;
+
+    use super::*;
+
+    /// Two writable pages in a row, a gap, then a text page, and a mapping not touched yet
+    pub fn core_layout() {
+        let mut pagetable = PageTable::uvm_create();
+        let map = |pagetable: &mut PageTable, va: usize, perm: PteFlag, fill: u8| {
+            let pa = unsafe { kalloc() }.expect("core_layout: out of memory");
+            unsafe { ptr::write_bytes(pa, fill, PGSIZE); }
+            pagetable.map_pages(VirtAddr::try_from(va).unwrap(), PGSIZE,
+                PhysAddr::try_from(pa as usize).unwrap(), perm | PteFlag::U).unwrap();
+        };
+        map(&mut pagetable, 0x1000, PteFlag::R | PteFlag::X, b't');
+        map(&mut pagetable, 0x4000, PteFlag::R | PteFlag::W, b'd');
+        map(&mut pagetable, 0x5000, PteFlag::R | PteFlag::W, b'd');
+
+        let header = CoreHeader {
+            magic: CORE_MAGIC, pid: 7, name: [0; 16], scause: 13, sepc: 0x1004, stval: 0, nregion: 0, nvma: 0,
+        };
+        let regs = [0x1004; 32];
+        let vma = CoreVma { start: 0x8000, end: 0xa000, prot: 1, flags: 2, off: 0 };
+        // keep the start of the core, up to the first bytes of the text
+        let mut head = [0u8; 512];
+        let mut size = 0;
+        write_core(&pagetable, header, &regs, &[vma], &mut |bytes| {
+            if size < head.len() {
+                let n = bytes.len().min(head.len() - size);
+                head[size..size + n].copy_from_slice(&bytes[..n]);
+            }
+            size += bytes.len();
+            Ok(())
+        }).unwrap();
+
+        let header = unsafe { ptr::read_unaligned(head.as_ptr() as *const CoreHeader) };
+        assert_eq!((header.magic, header.pid, header.nregion, header.nvma), (CORE_MAGIC, 7, 2, 1));
+        let table = mem::size_of::<CoreHeader>() + mem::size_of_val(&regs);
+        let region = |i: usize| unsafe {
+            ptr::read_unaligned(head[table + i * mem::size_of::<CoreRegion>()..].as_ptr() as *const CoreRegion)
+        };
+        assert_eq!(region(0), CoreRegion { start: 0x1000, len: PGSIZE, flags: (PteFlag::R | PteFlag::X).bits() });
+        assert_eq!(region(1), CoreRegion { start: 0x4000, len: 2 * PGSIZE, flags: (PteFlag::R | PteFlag::W).bits() });
+        let vmas = table + 2 * mem::size_of::<CoreRegion>();
+        assert_eq!(unsafe { ptr::read_unaligned(head[vmas..].as_ptr() as *const CoreVma) }, vma);
+        let data = vmas + mem::size_of::<CoreVma>();
+        assert_eq!(&head[data..data + 8], &[b't'; 8]);
+        assert_eq!(size, data + 3 * PGSIZE);
+
+        pagetable.unmap_pages(VirtAddr::try_from(0x1000).unwrap(), 1, true).unwrap();
+        pagetable.unmap_pages(VirtAddr::try_from(0x4000).unwrap(), 2, true).unwrap();
+        pagetable.free_walk();
+    }
+    crate::kernel_test!(core_layout);
+
+    pub fn core_path_pid() {
+        let mut buf = [0u8; 32];
+        assert_eq!(core_path(0, &mut buf), b"/core.0\0");
+        assert_eq!(core_path(1207, &mut buf), b"/core.1207\0");
+    }
+    crate::kernel_test!(core_path_pid);
+
+    /// The guard page is the one below sz mapped without U, and a restore maps no page twice
+    pub fn checkpoint_guard() {
+        let mut pagetable = PageTable::uvm_create();
+        for (va, perm) in [(0x1000, PteFlag::R | PteFlag::U), (0x2000, PteFlag::R | PteFlag::W),
+            (0x3000, PteFlag::R | PteFlag::W | PteFlag::U)]
+        {
+            map_page(&mut pagetable, va, perm, |page| {
+                page.fill(0);
+                Ok(())
+            }).unwrap();
+        }
+        assert_eq!(guard_page(&pagetable, 0x4000), 0x2000);
+        assert_eq!(guard_page(&pagetable, 0x2000), 0);
+        assert_eq!(map_page(&mut pagetable, 0x3000, PteFlag::R | PteFlag::U, |_| Ok(())),
+            Err("restore: regions overlap"));
+        // a page whose fill fails is not mapped
+        assert!(map_page(&mut pagetable, 0x5000, PteFlag::R | PteFlag::U, |_| Err("no")).is_err());
+        assert!(pagetable.walk(VirtAddr::try_from(0x5000).unwrap()).is_none_or(|pte| !pte.is_valid()));
+
+        pagetable.unmap_pages(VirtAddr::try_from(0x1000).unwrap(), 3, true).unwrap();
+        pagetable.free_walk();
+    }
+    crate::kernel_test!(checkpoint_guard);
+
+    /// A checkpoint read back but not restored frees its memory, its guard page too
+    pub fn checkpoint_drop() {
+        let (free, _) = crate::mm::kalloc_stats();
+        let mut pagetable = PageTable::uvm_create();
+        for (va, perm) in [(0x1000, PteFlag::R | PteFlag::X | PteFlag::U), (0x2000, PteFlag::R | PteFlag::W)] {
+            map_page(&mut pagetable, va, perm, |page| {
+                page.fill(0);
+                Ok(())
+            }).unwrap();
+        }
+        let ckpt = Checkpoint {
+            pagetable,
+            sz: 0x3000,
+            regs: [0; 32],
+            name: [0; 16],
+            nofile: [NOFILE, NOFILE],
+            files: [None; NOFILE],
+            inherit: [false; NOFILE],
+            cloexec: [false; NOFILE],
+        };
+        drop(ckpt);
+        assert_eq!(crate::mm::kalloc_stats().0, free);
+    }
+    crate::kernel_test!(checkpoint_drop);
+}
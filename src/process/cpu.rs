@@ -1,9 +1,34 @@
-use crate::register::{tp, sie, sstatus};
+use core::cmp::{max, min};
+use core::ptr;
+use core::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+
+use crate::register::{clint, tp, sie, sstatus, wfi};
 use crate::consts::NCPU;
+use crate::cpustat;
+use crate::dtb;
+use crate::plic;
+use crate::printf;
+use crate::rmain::boot_hart;
+use crate::schedtrace;
+use crate::start::{timer_at, INTERVAL};
+use crate::timer;
+
+use super::{make_runnable, rt, runq, sleepq, Context, Proc, PROC_MANAGER, ProcState};
+
+static mut CPUS: [Cpu; NCPU] = [const { Cpu::new() }; NCPU];
+
+/// harts sleeping in idle(), a bit per hart
+static IDLE: AtomicUsize = AtomicUsize::new(0);
 
-use super::{Context, Proc, PROC_MANAGER, ProcState};
+/// ticks an idle hart sleeps at most, even with nothing to wake it
+const MAX_IDLE: u64 = 100;
 
-static mut CPUS: [Cpu; NCPU] = [Cpu::new(); NCPU];
+/// harts asked to go offline, and those parked in park(), a bit per hart
+static OFFLINE: AtomicUsize = AtomicUsize::new(0);
+static PARKED: AtomicUsize = AtomicUsize::new(0);
+
+/// whether a tick taken in kernel mode yields, off with the nopreempt boot option
+static PREEMPT_KERNEL: AtomicBool = AtomicBool::new(true);
 
 /// Must be called with interrupts disabled,
 /// to prevent race with process being moved
@@ -24,6 +49,36 @@ pub unsafe fn my_proc() -> &'static mut Proc {
     c.proc.as_mut().unwrap()
 }
 
+/// Pid and name of the process running on this hart, if any.
+/// Only meant for debugging output, p->lock is not acquired.
+/// Interrupts must be disabled.
+pub unsafe fn my_proc_info() -> Option<(usize, &'static str)> {
+    let c = my_cpu();
+    c.proc.as_ref().map(|p| (p.pid, p.name()))
+}
+
+/// Pid of the process running on this hart, if any.
+/// Only meant for debugging output, p->lock is not acquired.
+/// Interrupts must be disabled.
+pub unsafe fn my_pid() -> Option<usize> {
+    let c = my_cpu();
+    c.proc.as_ref().map(|p| p.pid)
+}
+
+/// The process running on this hart,
+/// null if the hart is scheduling or still booting.
+pub fn my_proc_ptr() -> *mut Proc {
+    crate::spinlock::push_off();
+    let p = unsafe {
+        match my_cpu().proc.as_mut() {
+            Some(p) => &mut **p as *mut Proc,
+            None => ptr::null_mut(),
+        }
+    };
+    crate::spinlock::pop_off();
+    p
+}
+
 /// Cpu contains current info about this hart
 ///
 /// no need to bind a spinlock to it,
@@ -34,8 +89,20 @@ pub struct Cpu<'a> {
     scheduler: Context,
     noff: u8,
     intena: bool,
+    // preempt_disable() depth, and a tick came in meanwhile, see preemptible()
+    preempt: u8,
+    need_resched: bool,
+    // spinlocks held by this hart, (address, name), for debug assertions
+    #[cfg(debug_assertions)]
+    held: [(usize, &'static str); NHELD],
+    #[cfg(debug_assertions)]
+    nheld: usize,
 }
 
+/// spinlocks a hart can hold at the same time in debug builds
+#[cfg(debug_assertions)]
+const NHELD: usize = 16;
+
 impl<'a> Cpu<'a> {
     const fn new() -> Self {
         Self {
@@ -43,6 +110,12 @@ impl<'a> Cpu<'a> {
             scheduler: Context::new(),
             noff: 0,
             intena: false,
+            preempt: 0,
+            need_resched: false,
+            #[cfg(debug_assertions)]
+            held: [(0, ""); NHELD],
+            #[cfg(debug_assertions)]
+            nheld: 0,
         }
     }
 
@@ -53,35 +126,120 @@ impl<'a> Cpu<'a> {
         }
 
         loop {
-            // ensure devices can interrupt
-            intr_on();
-
-            // use ProcManager to find a runnable process
-            match PROC_MANAGER.alloc_runnable() {
-                Some(p) => {
-                    p.state = ProcState::RUNNING;
-                    self.proc = Some(p);
-
-                    swtch(&mut self.scheduler as *mut Context,
-                        self.proc
-                            .as_mut()
-                            .unwrap()
-                            .get_context_mut()
-                            as *mut Context);
-                    
-                    let p = self.proc
-                        .take()
-                        .expect("context switch back with no process reference");
-                    p.lock.release_lock();
-                },
-                None => {},
+            if OFFLINE.load(Ordering::SeqCst) & (1 << cpu_id()) != 0 {
+                self.park();
+            } else if !self.schedule_once() {
+                self.idle();
             }
         }
     }
 
+    /// Sit in wfi, running nothing, until set_online() brings this hart back.
+    /// The process it was running went back to the boot hart's queue at its last tick,
+    /// with the rest of its queue, for the other harts to pick up.
+    /// No timer or device interrupt comes in meanwhile, only IPIs wake it up,
+    /// and a panic elsewhere freezes it as usual.
+    unsafe fn park(&mut self) {
+        sstatus::intr_off();
+        let id = cpu_id();
+        plic::set_masked(id, true);
+        timer_at(u64::MAX);
+        if id != boot_hart() {
+            runq::hand_over(id, boot_hart());
+            kick(boot_hart());
+        }
+        PARKED.fetch_or(1 << id, Ordering::SeqCst);
+        println!("hart {} parked", id);
+
+        let was = cpustat::enter(cpustat::CPU_IDLE);
+        while OFFLINE.load(Ordering::SeqCst) & (1 << id) != 0 {
+            wfi();
+            if printf::panicked() {
+                printf::freeze();
+            }
+        }
+        cpustat::enter(was);
+
+        PARKED.fetch_and(!(1 << id), Ordering::SeqCst);
+        // the IPI that woke it up is still pending, and taken as a tick once interrupts are on
+        timer_at(clint::read_mtime() + INTERVAL);
+        plic::set_masked(id, false);
+        println!("hart {} back online", id);
+    }
+
+    /// Nothing is runnable, sleep until the next timer or timed sleep is due, see timer.rs,
+    /// instead of waking up at every tick, or until a device or kick() interrupts.
+    /// Then the scheduler looks at its run queue, or steals from another.
+    /// Only the boot hart runs the timers, the others sleep until interrupted.
+    /// Back to a tick every INTERVAL after that.
+    unsafe fn idle(&mut self) {
+        sstatus::intr_off();
+        let id = cpu_id();
+        IDLE.fetch_or(1 << id, Ordering::SeqCst);
+
+        // a process may have become runnable before this hart was marked idle,
+        // later on, kick() interrupts it, and wfi returns right away
+        if !PROC_MANAGER.has_runnable() {
+            let now = clint::read_mtime();
+            let mut until = now + MAX_IDLE * INTERVAL;
+            if id == boot_hart() {
+                let next = timer::next_expiry().into_iter().chain(sleepq::next_deadline()).min();
+                if let Some(tick) = next {
+                    until = min(until, max(tick as u64 * INTERVAL, now));
+                }
+            }
+            timer_at(until);
+            let was = cpustat::enter(cpustat::CPU_IDLE);
+            wfi();
+            cpustat::enter(was);
+            timer_at(clint::read_mtime() + INTERVAL);
+        }
+
+        IDLE.fetch_and(!(1 << id), Ordering::SeqCst);
+    }
+
+    /// Run one runnable process, if there is any,
+    /// until it switches back to the scheduler.
+    /// Return whether a process was run.
+    /// Also used by kernel tests, which schedule until some condition holds.
+    pub unsafe fn schedule_once(&mut self) -> bool {
+        extern "C" {
+            fn swtch(old: *mut Context, new: *mut Context);
+        }
+
+        // ensure devices can interrupt
+        intr_on();
+
+        // use ProcManager to find a runnable process
+        match PROC_MANAGER.alloc_runnable(cpu_id()) {
+            Some(p) => {
+                p.state = ProcState::RUNNING;
+                p.last_run = rt::next_run();
+                p.slice = 0;
+                schedtrace::record(schedtrace::SCHED_RUN, 0, p.pid);
+                self.proc = Some(p);
+
+                swtch(&mut self.scheduler as *mut Context,
+                    self.proc
+                        .as_mut()
+                        .unwrap()
+                        .get_context_mut()
+                        as *mut Context);
+
+                let p = self.proc
+                    .take()
+                    .expect("context switch back with no process reference");
+                p.lock.release_lock();
+                true
+            },
+            None => false,
+        }
+    }
+
     /// Switch back to scheduler.
     /// see more in xv6-riscv
     pub unsafe fn sched(&mut self) {
+        ftrace!();
         extern "C" {
             fn swtch(old: *mut Context, new: *mut Context);
         }
@@ -96,24 +254,25 @@ impl<'a> Cpu<'a> {
             panic!("sched: locks")
         }
 
-        // not using match
-        // because that will move the mut reference out
-        if self.proc.is_none() {
-            panic!("sched: cpu {} have no proc reference", cpu_id());
-        } else {
-            let p = self.proc.as_mut().unwrap();
-            if !p.lock.holding() {
-                panic!("sched: not holding proc's lock");
-            }
-            if p.state == ProcState::RUNNING {
-                panic!("sched: current proc is still running");
-            }
+        if self.preempt != 0 {
+            panic!("sched: preempt_disable depth {}", self.preempt);
+        }
 
-            let intena = self.intena;
-            swtch(p.get_context_mut() as *mut Context,
-                &mut self.scheduler as *mut Context);
-            self.intena = intena;
+        let p = match self.proc.as_mut() {
+            Some(p) => p,
+            None => panic!("sched: cpu {} have no proc reference", cpu_id()),
+        };
+        if !p.lock.holding() {
+            panic!("sched: not holding proc's lock");
         }
+        if p.state == ProcState::RUNNING {
+            panic!("sched: current proc is still running");
+        }
+
+        let intena = self.intena;
+        swtch(p.get_context_mut() as *mut Context,
+            &mut self.scheduler as *mut Context);
+        self.intena = intena;
     }
 
     /// Give up the current runing process in this cpu
@@ -121,22 +280,55 @@ impl<'a> Cpu<'a> {
     /// The referenced process's state should be running
     /// Change the name to yielding, because `yield` is a key word
     pub fn yielding(&mut self) {
+        ftrace!();
         // not using match
         // because that will move the mut reference out
         // ignore none case in case the cpu is scheduling
         if self.proc.is_some() {
+            self.need_resched = false;
             // note: p is the copy of &'a mut Proc
             //      and self.proc may refer others in the middle
             let p = self.proc.as_mut().unwrap();
             unsafe {p.lock.acquire_lock();}
             assert_eq!(p.state, ProcState::RUNNING);
-            p.state = ProcState::RUNNABLE;
+            make_runnable(p, Some(unsafe { cpu_id() }));
+            schedtrace::record(schedtrace::SCHED_YIELD, p.pid, 0);
             unsafe {self.sched();}
             let p = self.proc.as_mut().unwrap();
             unsafe {p.lock.release_lock();}
         }
     }
 
+    /// Whether a tick taken in kernel mode may yield right away,
+    /// or only mark need_resched, for preempt_enable() or cond_resched() to yield at.
+    /// Interrupts being on before the tick already means no spinlock is held.
+    pub fn preemptible(&self) -> bool {
+        self.preempt == 0 && PREEMPT_KERNEL.load(Ordering::Relaxed)
+    }
+
+    /// Charge a tick to the running process's slice, and tell whether it takes the hart:
+    /// once the slice is used up, or a real-time process of a higher priority is queued on this hart,
+    /// see rt.rs
+    pub fn tick_yields(&mut self) -> bool {
+        let p = match self.proc.as_mut() {
+            Some(p) => p,
+            None => return true,
+        };
+        p.slice += 1;
+        if runq::has_above(unsafe { cpu_id() }, p.rt_prio) {
+            return true;
+        }
+        match rt::quantum(p.rt_prio, p.quantum) {
+            0 => false,
+            quantum => p.slice >= quantum,
+        }
+    }
+
+    /// A tick came in that did not yield
+    pub fn set_need_resched(&mut self) {
+        self.need_resched = true;
+    }
+
     /// Release the process's lock
     /// Only used in fork_ret or
     /// places not having current cpu's reference
@@ -147,10 +339,9 @@ impl<'a> Cpu<'a> {
     /// Prepare for the user trap return
     /// Return current proc's satp for assembly code to switch page table
     pub fn user_ret_prepare(&mut self) -> usize {
-        if self.proc.is_none() {
-            panic!("Cpu's user_ret_prepare: holding no process");
-        } else {
-            self.proc.as_mut().unwrap().user_ret_prepare()
+        match self.proc.as_mut() {
+            Some(p) => p.user_ret_prepare(),
+            None => panic!("Cpu's user_ret_prepare: holding no process"),
         }
     }
 
@@ -178,6 +369,147 @@ impl<'a> Cpu<'a> {
     }
 }
 
+fn send_ipi(hart: usize) {
+    #[cfg(not(feature = "sbi"))]
+    unsafe { clint::send_msip(hart); }
+    #[cfg(feature = "sbi")]
+    crate::sbi::send_ipi(1 << hart);
+}
+
+/// The hart to queue a process that became runnable on, see runq.rs:
+/// hart if given, otherwise an idle one, or else this one,
+/// never one going offline, that one's goes to the boot hart instead.
+/// Interrupts must be off.
+pub fn place(hart: Option<usize>) -> usize {
+    let offline = OFFLINE.load(Ordering::SeqCst);
+    let idle = IDLE.load(Ordering::SeqCst) & !offline;
+    let hart = match hart {
+        Some(hart) => hart,
+        None if idle != 0 => idle.trailing_zeros() as usize,
+        None => unsafe { cpu_id() },
+    };
+    if offline & (1 << hart) != 0 {
+        boot_hart()
+    } else {
+        hart
+    }
+}
+
+/// Interrupt hart if it is idle, after a process was queued on it,
+/// or else an idle hart to steal one, if more wait on hart than the next it runs
+pub fn kick(hart: usize) {
+    let idle = IDLE.load(Ordering::SeqCst);
+    if idle & (1 << hart) != 0 {
+        send_ipi(hart);
+    } else if idle != 0 && runq::len(hart) > 1 {
+        send_ipi(idle.trailing_zeros() as usize);
+    }
+}
+
+/// Take hart off scheduling, or bring it back, a bit at a time, see park().
+/// An offlined hart parks after the process it runs gives it up at its next tick,
+/// parked() tells when it did.
+/// The boot hart keeps the time, so it stays online.
+pub fn set_online(hart: usize, online: bool) -> Result<(), &'static str> {
+    if hart >= NCPU || dtb::harts() & (1 << hart) == 0 {
+        return Err("no such hart");
+    }
+    if hart == boot_hart() {
+        return Err("the boot hart cannot go offline");
+    }
+    if online {
+        OFFLINE.fetch_and(!(1 << hart), Ordering::SeqCst);
+    } else {
+        OFFLINE.fetch_or(1 << hart, Ordering::SeqCst);
+    }
+    // out of wfi, either idle or parked
+    send_ipi(hart);
+    Ok(())
+}
+
+/// Park every other hart, the boot hart too, e.g., to suspend, see suspend.rs,
+/// and wait until they are parked.
+/// Return the harts asked to go offline before, for unpark_others().
+/// Interrupts must be off, so that no process is scheduled on this hart meanwhile.
+pub fn park_others() -> usize {
+    let others = dtb::harts() & !(1 << unsafe { cpu_id() });
+    let saved = OFFLINE.fetch_or(others, Ordering::SeqCst);
+    for hart in (0..NCPU).filter(|hart| others & (1 << hart) != 0) {
+        send_ipi(hart);
+    }
+    while PARKED.load(Ordering::SeqCst) & others != others {
+        core::hint::spin_loop();
+    }
+    saved
+}
+
+/// Undo park_others(), the harts offline before stay parked
+pub fn unpark_others(saved: usize) {
+    let others = dtb::harts() & !(1 << unsafe { cpu_id() });
+    OFFLINE.store(saved, Ordering::SeqCst);
+    for hart in (0..NCPU).filter(|hart| others & !saved & (1 << hart) != 0) {
+        send_ipi(hart);
+    }
+}
+
+/// Harts asked to go offline, and those that are parked, a bit per hart
+pub fn offline() -> usize {
+    OFFLINE.load(Ordering::SeqCst)
+}
+
+pub fn parked() -> usize {
+    PARKED.load(Ordering::SeqCst)
+}
+
+/// Tell whether a tick taken in kernel mode yields, see rmain.rs and the nopreempt option
+pub fn set_preempt_kernel(on: bool) {
+    PREEMPT_KERNEL.store(on, Ordering::Relaxed);
+}
+
+/// Keep the current process on this hart until preempt_enable(),
+/// ticks taken meanwhile only mark it to yield at the end.
+/// Unlike push_off, interrupts stay on, and it nests.
+/// The process must not sleep before preempt_enable(), see sched().
+pub fn preempt_disable() {
+    crate::spinlock::push_off();
+    let c = unsafe { my_cpu() };
+    c.preempt = c.preempt.checked_add(1).expect("cpu: preempt_disable");
+    crate::spinlock::pop_off();
+}
+
+/// Undo preempt_disable(), and yield if a tick came in meanwhile
+pub fn preempt_enable() {
+    crate::spinlock::push_off();
+    let c = unsafe { my_cpu() };
+    c.preempt = c.preempt.checked_sub(1).expect("cpu: preempt_enable");
+    crate::spinlock::pop_off();
+    cond_resched();
+}
+
+/// A safe point in a long kernel path, e.g., a big copy:
+/// yield if a tick came in that could not, with preemption disabled,
+/// or booted with nopreempt.
+/// Nothing happens with interrupts off, e.g., holding a spinlock.
+pub fn cond_resched() {
+    if !sstatus::intr_get() {
+        return;
+    }
+    sstatus::intr_off();
+    let c = unsafe { my_cpu() };
+    if c.need_resched && c.preempt == 0 && c.noff == 0 {
+        c.yielding();
+    }
+    intr_on();
+}
+
+/// The preempt_disable() depth on this hart
+pub fn preempt_count() -> u8 {
+    crate::spinlock::push_off();
+    let n = unsafe { my_cpu() }.preempt;
+    crate::spinlock::pop_off();
+    n
+}
+
 /// Called in spinlock's push_off().
 /// Interrupts must be disabled due to its use of mut ref to CPUS.
 pub fn push_off(old: bool) {
@@ -207,9 +539,59 @@ pub fn pop_off() {
     }
 }
 
+/// Called by a spinlock after acquiring it, interrupts must be off.
+#[cfg(debug_assertions)]
+pub fn lock_acquired(addr: usize, name: &'static str) {
+    let c = unsafe { &mut CPUS[cpu_id()] };
+    if c.nheld == NHELD {
+        panic!("lock_acquired: {} spinlocks held, acquiring {}", NHELD, name);
+    }
+    c.held[c.nheld] = (addr, name);
+    c.nheld += 1;
+}
+
+/// Called by a spinlock before releasing it, interrupts must be off.
+/// Locks need not be released in the order they were acquired.
+#[cfg(debug_assertions)]
+pub fn lock_released(addr: usize) {
+    let c = unsafe { &mut CPUS[cpu_id()] };
+    let n = c.nheld;
+    match c.held[..n].iter().rposition(|(a, _)| *a == addr) {
+        Some(i) => {
+            c.held.copy_within(i + 1..n, i);
+            c.nheld -= 1;
+        }
+        None => panic!("lock_released: lock {:#x} not recorded", addr),
+    }
+}
+
+/// Panic if this hart holds any spinlock other than the one at allowed.
+/// Sleeping with another spinlock held can deadlock,
+/// e.g., when the waker needs it too.
+#[cfg(debug_assertions)]
+pub fn assert_only_holding(allowed: usize, what: &str) {
+    crate::spinlock::push_off();
+    let c = unsafe { &CPUS[cpu_id()] };
+    for (addr, name) in c.held[..c.nheld].iter() {
+        if *addr != allowed {
+            panic!("{}: holding spinlock {}", what, name);
+        }
+    }
+    crate::spinlock::pop_off();
+}
+
 /// enable device interrupts
 #[inline]
 pub fn intr_on() {
+    // interrupts must stay off as long as push_off is in effect,
+    // e.g., while holding a spinlock
+    #[cfg(debug_assertions)]
+    {
+        let c = unsafe { &CPUS[cpu_id()] };
+        if c.noff > 0 {
+            panic!("intr_on: noff={} on hart {}", c.noff, unsafe { cpu_id() });
+        }
+    }
     sie::intr_on();
     sstatus::intr_on();
 }
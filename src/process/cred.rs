@@ -0,0 +1,144 @@
+//! User and group credentials of a process
+//!
+//! Each of the user and group ids is kept three times, as in Unix:
+//! the real one, who started the process,
+//! the effective one, which permission checks go by,
+//! and the saved one, the effective id a setuid program was started with,
+//! so that it can drop its privileges and take them back.
+//! Effective user 0 is root, which may do anything.
+//!
+//! There are no permission bits in the file system yet,
+//! so check_root() only guards the privileged syscalls, see syscall.rs, and check_kill() kill,
+//! and every process starts as root, there being no login yet.
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct Cred {
+    pub uid: u32,
+    pub euid: u32,
+    pub suid: u32,
+    pub gid: u32,
+    pub egid: u32,
+    pub sgid: u32,
+}
+
+impl Cred {
+    pub const fn root() -> Self {
+        Self { uid: 0, euid: 0, suid: 0, gid: 0, egid: 0, sgid: 0 }
+    }
+
+    pub fn is_root(&self) -> bool {
+        self.euid == 0
+    }
+
+    /// Fail unless running as root
+    pub fn check_root(&self) -> Result<(), &'static str> {
+        match self.is_root() {
+            true => Ok(()),
+            false => Err("permission denied"),
+        }
+    }
+
+    /// Root sets all three user ids,
+    /// anyone else only the effective one, to the real or saved one.
+    pub fn set_uid(&mut self, uid: u32) -> Result<(), &'static str> {
+        if self.is_root() {
+            self.uid = uid;
+            self.suid = uid;
+        } else if uid != self.uid && uid != self.suid {
+            return Err("permission denied");
+        }
+        self.euid = uid;
+        Ok(())
+    }
+
+    /// The same as set_uid, for the group ids, root is still by the user id
+    pub fn set_gid(&mut self, gid: u32) -> Result<(), &'static str> {
+        if self.is_root() {
+            self.gid = gid;
+            self.sgid = gid;
+        } else if gid != self.gid && gid != self.sgid {
+            return Err("permission denied");
+        }
+        self.egid = gid;
+        Ok(())
+    }
+
+    /// Fail unless it may kill a process of target, being root,
+    /// or with its real or effective user id the real or saved one of target, as for signals in Unix
+    pub fn check_kill(&self, target: &Cred) -> Result<(), &'static str> {
+        let ours = [self.uid, self.euid];
+        if self.is_root() || ours.contains(&target.uid) || ours.contains(&target.suid) {
+            Ok(())
+        } else {
+            Err("permission denied")
+        }
+    }
+
+    /// Starting a program, with the owner of its file if it is setuid or setgid.
+    /// The effective ids become the saved ones either way.
+    pub fn exec(&mut self, owner: Option<u32>, group: Option<u32>) {
+        if let Some(uid) = owner {
+            self.euid = uid;
+        }
+        if let Some(gid) = group {
+            self.egid = gid;
+        }
+        self.suid = self.euid;
+        self.sgid = self.egid;
+    }
+}
+
+#[cfg(feature = "unit_test")]
+pub mod tests {
+    use super::*;
+
+    pub fn setuid_rules() {
+        // root drops to user 10 for good
+        let mut cred = Cred::root();
+        cred.set_uid(10).unwrap();
+        assert_eq!((cred.uid, cred.euid, cred.suid), (10, 10, 10));
+        assert!(cred.set_uid(0).is_err());
+        assert!(cred.check_root().is_err());
+
+        // user 10 runs a program owned by root with the setuid bit,
+        // being root, setting the uid sets all of them
+        cred.exec(Some(0), None);
+        assert_eq!((cred.uid, cred.euid, cred.suid), (10, 0, 0));
+        assert!(cred.check_root().is_ok());
+        cred.set_uid(10).unwrap();
+        assert_eq!((cred.uid, cred.euid, cred.suid), (10, 10, 10));
+
+        // anyone else switches between the real and the saved one
+        let mut cred = Cred { uid: 10, euid: 20, suid: 30, gid: 5, egid: 5, sgid: 7 };
+        cred.set_uid(30).unwrap();
+        assert_eq!((cred.uid, cred.euid, cred.suid), (10, 30, 30));
+        cred.set_uid(10).unwrap();
+        assert_eq!((cred.uid, cred.euid, cred.suid), (10, 10, 30));
+        assert!(cred.set_uid(20).is_err());
+
+        // and the same for groups
+        cred.set_gid(7).unwrap();
+        assert_eq!((cred.gid, cred.egid, cred.sgid), (5, 7, 7));
+        assert!(cred.set_gid(9).is_err());
+        cred.exec(None, Some(9));
+        assert_eq!((cred.gid, cred.egid, cred.sgid), (5, 9, 9));
+        assert_eq!((cred.euid, cred.suid), (10, 10));
+        cred.set_gid(5).unwrap();
+        assert_eq!(cred.egid, 5);
+    }
+    crate::kernel_test!(setuid_rules);
+
+    pub fn kill_rules() {
+        let user = Cred { uid: 10, euid: 20, suid: 30, gid: 5, egid: 5, sgid: 5 };
+        assert!(Cred::root().check_kill(&user).is_ok());
+        assert!(user.check_kill(&Cred::root()).is_err());
+        // its real or effective one, against the real or saved one of the target
+        let target = Cred { uid: 20, euid: 40, suid: 40, gid: 5, egid: 5, sgid: 5 };
+        assert!(user.check_kill(&target).is_ok());
+        let target = Cred { uid: 40, euid: 40, suid: 10, gid: 5, egid: 5, sgid: 5 };
+        assert!(user.check_kill(&target).is_ok());
+        let target = Cred { uid: 30, euid: 10, suid: 40, gid: 5, egid: 5, sgid: 5 };
+        assert!(user.check_kill(&target).is_err());
+    }
+    crate::kernel_test!(kill_rules);
+}
@@ -1,23 +1,712 @@
 //! ELF loader
+//!
+//! Executables are either linked at USERTEXT(ET_EXEC),
+//! or position-independent(ET_DYN), loaded at USERPIE
+//! with their R_RISCV_RELATIVE relocations applied by relocate.
+//! A script starting with a #! line is run by the interpreter it names, see exec.
+
+use core::cmp::min;
+use core::convert::TryFrom;
+use core::mem;
+use core::ptr;
+
+use crate::consts::{MAXARG, PGSIZE, TRAPFRAME, USERPIE, USERTEXT};
+use crate::fs;
+use crate::mm::{kalloc, kfree, PageTable, PhysAddr, PteFlag, VirtAddr};
 
 use super::Proc;
+use super::proc::{free_pagetable, Image};
+
+/// The longest #! line looked at, newline included, as Linux's BINPRM_BUF_SIZE
+const SHEBANG_MAX: usize = 128;
+
+/// Run the program at path, which is an elf executable,
+/// or a script whose first line is "#!interpreter [arg]".
+/// A script is run as "interpreter [arg] path argv[1..]",
+/// the interpreter must be an elf executable itself, as in the historic Unix.
+/// path and each argument in argv include the terminating nul.
+/// Return argc, which becomes a0 of the new program.
+pub fn exec(p: &mut Proc, path: &[u8], argv: &[&[u8]]) -> Result<usize, &'static str> {
+    let mut head = [0u8; SHEBANG_MAX];
+    let n = read_head(path, &mut head)?;
+    let (interp, arg) = match parse_shebang(&head[..n])? {
+        Some(line) => line,
+        None => return load(p, path, argv),
+    };
+
+    // the interpreter and its argument, nul-terminated
+    let mut line = [0u8; SHEBANG_MAX + 2];
+    line[..interp.len()].copy_from_slice(interp);
+    let interp_end = interp.len() + 1;
+    let mut arg_end = interp_end;
+    if let Some(arg) = arg {
+        line[interp_end..interp_end + arg.len()].copy_from_slice(arg);
+        arg_end = interp_end + arg.len() + 1;
+    }
+    let (interp, arg) = line[..arg_end].split_at(interp_end);
+    let path = &path[..path.iter().position(|c| *c == 0).ok_or("exec: path not nul-terminated")? + 1];
+
+    let mut nargv: [&[u8]; MAXARG] = [&[]; MAXARG];
+    let argc = script_argv(interp, arg, path, argv, &mut nargv)?;
+    load(p, interp, &nargv[..argc])
+}
+
+/// Read the first bytes of the file at path into buf, return how many.
+fn read_head(path: &[u8], buf: &mut [u8]) -> Result<usize, &'static str> {
+    let op = fs::begin_op();
+    let ip = fs::namei(&op, path)?;
+    let n = fs::ilock(ip).readi(0, buf);
+    fs::iput(&op, ip);
+    n
+}
+
+/// The interpreter of a #! line and its optional argument
+type Shebang<'a> = (&'a [u8], Option<&'a [u8]>);
+
+/// Parse the #! line at the start of head, return the interpreter
+/// and the optional argument, the rest of the line with the blanks around it trimmed,
+/// None if head does not start with #!.
+fn parse_shebang(head: &[u8]) -> Result<Option<Shebang<'_>>, &'static str> {
+    if !head.starts_with(b"#!") {
+        return Ok(None);
+    }
+    let len = head.iter().position(|c| *c == b'\n').ok_or("exec: #! line too long")?;
+    let blank = |c: &u8| *c == b' ' || *c == b'\t';
+    let line = &head[2..len];
+    let line = match line.iter().position(|c| !blank(c)) {
+        Some(start) => &line[start..],
+        None => return Err("exec: no interpreter after #!"),
+    };
+    let end = line.iter().position(blank).unwrap_or(line.len());
+    let (interp, rest) = line.split_at(end);
+    let arg = match rest.iter().position(|c| !blank(c)) {
+        Some(start) => {
+            let rest = &rest[start..];
+            Some(&rest[..rest.iter().rposition(|c| !blank(c)).unwrap() + 1])
+        }
+        None => None,
+    };
+    if interp.contains(&0) || arg.is_some_and(|arg| arg.contains(&0)) {
+        return Err("exec: nul in #! line");
+    }
+    Ok(Some((interp, arg)))
+}
+
+/// Fill nargv with the arguments of a script's interpreter,
+/// interp, arg if it is not empty, path, then argv without its argv[0],
+/// all nul-terminated, return how many.
+fn script_argv<'a>(
+    interp: &'a [u8],
+    arg: &'a [u8],
+    path: &'a [u8],
+    argv: &[&'a [u8]],
+    nargv: &mut [&'a [u8]; MAXARG],
+) -> Result<usize, &'static str> {
+    let mut argc = 0;
+    let rest = if argv.is_empty() { argv } else { &argv[1..] };
+    let args = [interp, arg, path];
+    for a in args.iter().filter(|a| !a.is_empty()).chain(rest.iter()) {
+        if argc == MAXARG {
+            return Err("exec: too many arguments");
+        }
+        nargv[argc] = a;
+        argc += 1;
+    }
+    Ok(argc)
+}
 
 /// Load an elf executable into the process's user space
 /// note: it can get the mut reference of a Proc,
 ///     because it will be valid until it calls exit itself
-/// TODO
-pub fn load(p: &mut Proc, path: &[u8]) -> Result<(), &'static str> {
-    // get relevant inode using path
+/// Each argument in argv includes its terminating nul.
+/// Return argc, which becomes a0 of the new program.
+pub fn load(p: &mut Proc, path: &[u8], argv: &[&[u8]]) -> Result<usize, &'static str> {
+    // LTODO - run a setuid or setgid file as its owner or group, see Cred::exec
+    let op = fs::begin_op();
+    let ip = fs::namei(&op, path)?;
+    let mut guard = fs::ilock(ip);
+    let mut read = |off: usize, buf: &mut [u8]| -> Result<(), &'static str> {
+        let off = u32::try_from(off).map_err(|_| "exec: offset out of range")?;
+        match guard.readi(off, buf)? {
+            n if n == buf.len() => Ok(()),
+            _ => Err("exec: short read"),
+        }
+    };
+    let loaded = load_image(p, &mut read, argv);
+    drop(guard);
+    fs::iput(&op, ip);
+    drop(op);
+    let (argc, old) = loaded?;
+    p.drop_image(old);
+    let name = path.rsplit(|c| *c == b'/').next().unwrap_or(path);
+    p.set_name(name);
+    Ok(argc)
+}
+
+/// Load the elf executable read gives into a fresh user page table,
+/// and switch the process to it, see Proc::exec_image,
+/// the old image is only switched from once the new one is complete.
+/// Return argc, and the old image for Proc::drop_image.
+pub fn load_image(p: &mut Proc, read: &mut ElfReader, argv: &[&[u8]])
+    -> Result<(usize, Option<Image>), &'static str>
+{
+    let mut pagetable = p.user_pagetable();
+    match build_image(&mut pagetable, read, argv) {
+        Ok((sz, entry, sp)) => {
+            let old = p.exec_image(pagetable, sz, entry, sp);
+            Ok((argv.len(), old))
+        }
+        Err(str) => {
+            free_pagetable(&mut pagetable, TRAPFRAME.into());
+            Err(str)
+        }
+    }
+}
+
+/// Load the segments into pagetable, then a guard page and the stack above them,
+/// return sz, the entry point and sp
+fn build_image(pagetable: &mut PageTable, read: &mut ElfReader, argv: &[&[u8]])
+    -> Result<(usize, usize, usize), &'static str>
+{
+    let elf: ElfHeader = read_file(read, 0)?;
+    let bias = elf.load_bias()?;
+    elf.check()?;
+
+    // the segments are mapped at bias + p_vaddr
+    let mut sz = bias;
+    let mut dynamic = None;
+    for i in 0..elf.phnum as usize {
+        let ph: ProgHeader = read_file(read, elf.phoff as usize + i * mem::size_of::<ProgHeader>())?;
+        match ph.ptype {
+            ELF_PROG_LOAD => sz = sz.max(load_segment(pagetable, &ph, bias, read)?),
+            ELF_PROG_DYNAMIC => dynamic = Some(ph.vaddr as usize),
+            _ => {}
+        }
+    }
+    if let (ET_DYN, Some(dynamic)) = (elf.etype, dynamic) {
+        relocate(pagetable, bias, dynamic, sz)?;
+    }
+
+    // a guard page the user cannot touch, so that a stack overflow faults,
+    // then the stack
+    let guard = sz.div_ceil(PGSIZE) * PGSIZE;
+    let stackbase = guard + PGSIZE;
+    sz = stackbase + PGSIZE;
+    if sz > TRAPFRAME.into() {
+        return Err("elf: no room for the stack");
+    }
+    let pa = unsafe { kalloc() }.ok_or("elf: out of memory")?;
+    if let Err(str) = pagetable.map_pages(VirtAddr::try_from(guard)?, PGSIZE,
+        PhysAddr::try_from(pa as usize).unwrap(), PteFlag::R | PteFlag::W)
+    {
+        unsafe { kfree(pa); }
+        return Err(str);
+    }
+    pagetable.uvm_zero_page(VirtAddr::try_from(stackbase)?)?;
+    let sp = push_args(pagetable, sz, stackbase, argv)?;
+
+    Ok((sz, bias + elf.entry as usize, sp))
+}
+
+/// Where an elf file is read from, n bytes at an offset into a buffer of n
+pub type ElfReader<'a> = dyn FnMut(usize, &mut [u8]) -> Result<(), &'static str> + 'a;
+
+/// Map the pages of a PT_LOAD segment at bias + vaddr, with the permissions of its flags,
+/// copy its filesz bytes from the file with read, the rest up to memsz, the bss, is zero.
+/// vaddr need not be page-aligned, but must be congruent to off modulo the page size,
+/// the part of its first page below vaddr and of its last page above the end is zero too.
+/// Segments are loaded one after the other, with gaps between them left unmapped,
+/// but two of them never share a page, linkers start each in a page of its own.
+/// Return the end of the segment, sz is the highest end among them.
+pub fn load_segment(pagetable: &mut PageTable, ph: &ProgHeader, bias: usize, read: &mut ElfReader)
+    -> Result<usize, &'static str>
+{
+    let (off, vaddr) = (ph.off as usize, ph.vaddr as usize);
+    let (filesz, memsz) = (ph.filesz as usize, ph.memsz as usize);
+    if memsz < filesz {
+        return Err("elf: segment's memsz smaller than its filesz");
+    }
+    if vaddr % PGSIZE != off % PGSIZE {
+        return Err("elf: segment's vaddr and offset not congruent");
+    }
+    let start = bias.checked_add(vaddr).ok_or("elf: segment out of range")?;
+    let end = start.checked_add(memsz).ok_or("elf: segment out of range")?;
+    if end > TRAPFRAME.into() {
+        return Err("elf: segment out of range");
+    }
+
+    let mut perm = PteFlag::U;
+    if ph.flags & ELF_PROG_FLAG_READ != 0 {
+        perm |= PteFlag::R;
+    }
+    if ph.flags & ELF_PROG_FLAG_WRITE != 0 {
+        perm |= PteFlag::W;
+    }
+    if ph.flags & ELF_PROG_FLAG_EXEC != 0 {
+        perm |= PteFlag::X;
+    }
+    let mut va = start - start % PGSIZE;
+    while va < end {
+        let page = VirtAddr::try_from(va)?;
+        if pagetable.walk(page).is_some_and(|pte| pte.is_valid()) {
+            return Err("elf: segments share a page");
+        }
+        let pa = unsafe { kalloc() }.ok_or("elf: out of memory")?;
+        unsafe { ptr::write_bytes(pa, 0, PGSIZE); }
+        if let Err(str) = pagetable.map_pages(page, PGSIZE, PhysAddr::try_from(pa as usize).unwrap(), perm) {
+            unsafe { kfree(pa); }
+            return Err(str);
+        }
+        va += PGSIZE;
+    }
+
+    // through a buffer on the kernel stack, into pages that may be read-only
+    let mut chunk = [0u8; 256];
+    let mut done = 0;
+    while done < filesz {
+        let n = min(chunk.len(), filesz - done);
+        read(off + done, &mut chunk[..n])?;
+        pagetable.copy_out_text(start + done, &chunk[..n])?;
+        done += n;
+    }
+    Ok(end)
+}
+
+/// Push the argument strings, and then the argv pointer array, onto the user stack,
+/// which spans [stackbase, sp).
+/// Each argument in argv includes its terminating nul.
+/// Return the new sp, which is also the user address of argv.
+///
+/// The stack then looks like, from high to low address:
+/// the strings, each 16-byte aligned,
+/// argv[0..argc] followed by a null pointer, at the returned sp.
+pub fn push_args(
+    pagetable: &PageTable,
+    mut sp: usize,
+    stackbase: usize,
+    argv: &[&[u8]],
+) -> Result<usize, &'static str> {
+    if argv.len() > MAXARG {
+        return Err("push_args: too many arguments");
+    }
+
+    let mut ustack: [usize; MAXARG + 1] = [0; MAXARG + 1];
+    for (i, arg) in argv.iter().enumerate() {
+        if arg.last() != Some(&0) {
+            return Err("push_args: argument not nul-terminated");
+        }
+        if sp < stackbase + arg.len() {
+            return Err("push_args: arguments too large");
+        }
+        sp -= arg.len();
+        sp -= sp % 16; // riscv sp must be 16-byte aligned
+        if sp < stackbase {
+            return Err("push_args: arguments too large");
+        }
+        pagetable.copy_out(sp, arg)?;
+        ustack[i] = sp;
+    }
+    ustack[argv.len()] = 0;
+
+    // push the array of argv[] pointers
+    let size = (argv.len() + 1) * mem::size_of::<usize>();
+    if sp < stackbase + size {
+        return Err("push_args: arguments too large");
+    }
+    sp -= size;
+    sp -= sp % 16;
+    if sp < stackbase {
+        return Err("push_args: arguments too large");
+    }
+    let bytes = unsafe {
+        core::slice::from_raw_parts(ustack.as_ptr() as *const u8, size)
+    };
+    pagetable.copy_out(sp, bytes)?;
+
+    Ok(sp)
+}
+
+const ELF_MAGIC: u32 = 0x464C457F; // "\x7FELF" in little endian
+
+// ElfHeader elf, the rest of e_ident after the magic
+const ELFCLASS64: u8 = 2;
+const ELFDATA2LSB: u8 = 1;
+
+// ElfHeader machine
+const EM_RISCV: u16 = 243;
+
+// ElfHeader etype
+const ET_EXEC: u16 = 2;
+const ET_DYN: u16 = 3;
 
-    // check elf header, create new empty pagetable for user
+// ProgHeader ptype
+pub const ELF_PROG_LOAD: u32 = 1;
+pub const ELF_PROG_DYNAMIC: u32 = 2;
 
-    // load each program section
+// ProgHeader flags
+pub const ELF_PROG_FLAG_EXEC: u32 = 1;
+pub const ELF_PROG_FLAG_WRITE: u32 = 2;
+pub const ELF_PROG_FLAG_READ: u32 = 4;
 
-    // allocate space for user stack
+/// File header
+#[repr(C)]
+#[derive(Default)]
+pub struct ElfHeader {
+    pub magic: u32,
+    pub elf: [u8; 12],
+    pub etype: u16,
+    pub machine: u16,
+    pub version: u32,
+    pub entry: u64,
+    pub phoff: u64,
+    pub shoff: u64,
+    pub flags: u32,
+    pub ehsize: u16,
+    pub phentsize: u16,
+    pub phnum: u16,
+    pub shentsize: u16,
+    pub shnum: u16,
+    pub shstrndx: u16,
+}
 
-    // prepare content in the stack
+/// Program section header
+#[repr(C)]
+#[derive(Default)]
+pub struct ProgHeader {
+    pub ptype: u32,
+    pub flags: u32,
+    pub off: u64,
+    pub vaddr: u64,
+    pub paddr: u64,
+    pub filesz: u64,
+    pub memsz: u64,
+    pub align: u64,
+}
 
-    // update the process's info
+impl ElfHeader {
+    /// Check the magic number, and return the load bias,
+    /// i.e., where address 0 of the executable goes.
+    pub fn load_bias(&self) -> Result<usize, &'static str> {
+        if self.magic != ELF_MAGIC {
+            return Err("elf: bad magic");
+        }
+        match self.etype {
+            ET_EXEC => Ok(USERTEXT.into()),
+            ET_DYN => Ok(USERPIE.into()),
+            _ => Err("elf: not an executable"),
+        }
+    }
 
+    /// Check that it is a little-endian RISC-V ELF64 one, with program headers as ours
+    pub fn check(&self) -> Result<(), &'static str> {
+        if self.elf[0] != ELFCLASS64 || self.elf[1] != ELFDATA2LSB || self.machine != EM_RISCV {
+            return Err("elf: not a 64-bit RISC-V executable");
+        }
+        if self.phentsize as usize != mem::size_of::<ProgHeader>() {
+            return Err("elf: bad program header size");
+        }
+        Ok(())
+    }
+}
+
+/// Read a plain old data struct from the file at off
+pub fn read_file<T: Default>(read: &mut ElfReader, off: usize) -> Result<T, &'static str> {
+    let mut t = T::default();
+    let dst = unsafe {
+        core::slice::from_raw_parts_mut(&mut t as *mut T as *mut u8, mem::size_of::<T>())
+    };
+    read(off, dst)?;
+    Ok(t)
+}
+
+// dynamic section tags
+const DT_NULL: u64 = 0;
+const DT_RELA: u64 = 7;
+const DT_RELASZ: u64 = 8;
+const DT_RELAENT: u64 = 9;
+
+// relocation types
+const R_RISCV_NONE: u64 = 0;
+const R_RISCV_RELATIVE: u64 = 3;
+
+#[repr(C)]
+#[derive(Default)]
+struct Dyn {
+    tag: u64,
+    val: u64,
+}
+
+#[repr(C)]
+#[derive(Default)]
+struct Rela {
+    offset: u64,
+    info: u64,
+    addend: i64,
+}
+
+/// Read a plain old data struct from user memory
+fn read_user<T: Default>(pagetable: &PageTable, va: usize) -> Result<T, &'static str> {
+    let mut t = T::default();
+    let dst = unsafe {
+        core::slice::from_raw_parts_mut(&mut t as *mut T as *mut u8, mem::size_of::<T>())
+    };
+    pagetable.copy_in(va, dst)?;
+    Ok(t)
+}
+
+/// Apply the relocations of a position-independent executable,
+/// whose segments are already loaded at bias in pagetable, up to sz.
+/// dynamic is the (unbiased) address of its PT_DYNAMIC segment.
+/// It is statically linked, so only relative relocations are expected.
+/// The dynamic section, the relocation table and every word relocated
+/// must lie inside the loaded image, and no address may overflow.
+pub fn relocate(pagetable: &PageTable, bias: usize, dynamic: usize, sz: usize) -> Result<(), &'static str> {
+    // the biased address of len bytes at the unbiased va, if inside the image
+    let image = |va: usize, len: usize| -> Result<usize, &'static str> {
+        bias.checked_add(va)
+            .filter(|&start| start.checked_add(len).is_some_and(|end| end <= sz))
+            .ok_or("relocate: address outside the image")
+    };
+
+    let (mut rela, mut relasz, mut relaent) = (0, 0, mem::size_of::<Rela>());
+    let mut off = dynamic;
+    loop {
+        let d: Dyn = read_user(pagetable, image(off, mem::size_of::<Dyn>())?)?;
+        match d.tag {
+            DT_NULL => break,
+            DT_RELA => rela = d.val as usize,
+            DT_RELASZ => relasz = d.val as usize,
+            DT_RELAENT => relaent = d.val as usize,
+            _ => {}
+        }
+        off += mem::size_of::<Dyn>();
+    }
+    if relaent != mem::size_of::<Rela>() {
+        return Err("relocate: bad relocation entry size");
+    }
+    let table = image(rela, relasz)?;
+
+    for i in 0..relasz / relaent {
+        let r: Rela = read_user(pagetable, table + i * relaent)?;
+        match r.info & 0xffffffff {
+            R_RISCV_NONE => {}
+            R_RISCV_RELATIVE => {
+                let value = (bias as i64).checked_add(r.addend)
+                    .ok_or("relocate: addend overflows")? as u64;
+                let va = image(r.offset as usize, mem::size_of::<u64>())?;
+                pagetable.copy_out(va, &value.to_le_bytes())?;
+            }
+            _ => return Err("relocate: unsupported relocation type"),
+        }
+    }
     Ok(())
-}
\ No newline at end of file
+}
+
+#[cfg(feature = "unit_test")]
+pub mod tests {
+    use super::*;
+
+    /// #! lines and the interpreter's arguments they give
+    pub fn shebang() {
+        assert_eq!(parse_shebang(b"\x7fELF"), Ok(None));
+        assert_eq!(parse_shebang(b""), Ok(None));
+        assert_eq!(parse_shebang(b"#!/sh\necho"), Ok(Some((&b"/sh"[..], None))));
+        assert_eq!(parse_shebang(b"#! /sh \t\n"), Ok(Some((&b"/sh"[..], None))));
+        assert_eq!(parse_shebang(b"#!/awk  -f  x \n"), Ok(Some((&b"/awk"[..], Some(&b"-f  x"[..])))));
+        assert!(parse_shebang(b"#!  \n").is_err());
+        assert!(parse_shebang(b"#!/sh").is_err());
+        assert!(parse_shebang(b"#!/s\0h\n").is_err());
+
+        let mut nargv: [&[u8]; MAXARG] = [&[]; MAXARG];
+        let argv: [&[u8]; 3] = [b"run\0", b"a\0", b"b\0"];
+        let argc = script_argv(b"/awk\0", b"-f\0", b"/run\0", &argv, &mut nargv).unwrap();
+        assert_eq!(&nargv[..argc], &[&b"/awk\0"[..], b"-f\0", b"/run\0", b"a\0", b"b\0"]);
+        let argc = script_argv(b"/sh\0", b"", b"/run\0", &[], &mut nargv).unwrap();
+        assert_eq!(&nargv[..argc], &[&b"/sh\0"[..], b"/run\0"]);
+        let many: [&[u8]; MAXARG] = [b"x\0"; MAXARG];
+        assert!(script_argv(b"/sh\0", b"", b"/run\0", &many, &mut nargv).is_err());
+    }
+    crate::kernel_test!(shebang);
+
+    /// Text, then data with a bss running over two more pages after a gap,
+    /// neither of them page-aligned
+    pub fn segments() {
+        let mut pagetable = PageTable::uvm_create();
+        let byte = |off: usize| (off % 251) as u8;
+        let mut read = |off: usize, buf: &mut [u8]| -> Result<(), &'static str> {
+            for (i, b) in buf.iter_mut().enumerate() {
+                *b = byte(off + i);
+            }
+            Ok(())
+        };
+        let segment = |flags: u32, off: u64, vaddr: u64, filesz: u64, memsz: u64| ProgHeader {
+            ptype: ELF_PROG_LOAD, flags, off, vaddr, paddr: vaddr, filesz, memsz, align: PGSIZE as u64,
+        };
+        let text = segment(ELF_PROG_FLAG_READ | ELF_PROG_FLAG_EXEC, 0x120, 0x120, 0x1800, 0x1800);
+        let data = segment(ELF_PROG_FLAG_READ | ELF_PROG_FLAG_WRITE, 0x1a40, 0x3a40, 0x100, 0x1e00);
+        let bias = 0x1000;
+        assert_eq!(load_segment(&mut pagetable, &text, bias, &mut read), Ok(0x2920));
+        assert_eq!(load_segment(&mut pagetable, &data, bias, &mut read), Ok(0x6840));
+
+        let mut buf = [0u8; 16];
+        pagetable.copy_in(0x1120, &mut buf).unwrap();
+        assert!((0..16).all(|i| buf[i] == byte(0x120 + i)));
+        pagetable.copy_in(0x1110, &mut buf).unwrap();
+        assert_eq!(buf, [0; 16]);
+        // the end of data, then its bss
+        pagetable.copy_in(0x4b38, &mut buf).unwrap();
+        assert!((0..8).all(|i| buf[i] == byte(0x1b38 + i)));
+        assert_eq!(buf[8..], [0; 8]);
+        pagetable.copy_in(0x6830, &mut buf).unwrap();
+        assert_eq!(buf, [0; 16]);
+
+        let mapped = |va: usize| pagetable.walk(VirtAddr::try_from(va).unwrap())
+            .is_some_and(|pte| pte.is_valid());
+        assert!(mapped(0x1000) && mapped(0x2000) && !mapped(0x3000));
+        assert!(mapped(0x4000) && mapped(0x6000) && !mapped(0x7000));
+        // text is not writable by the user
+        assert!(pagetable.copy_out(0x1120, &buf).is_err());
+        pagetable.copy_out(0x5000, &buf).unwrap();
+
+        let overlap = segment(ELF_PROG_FLAG_READ, 0x800, 0x1800, 0x10, 0x10);
+        assert!(load_segment(&mut pagetable, &overlap, bias, &mut read).is_err());
+        let bad = segment(ELF_PROG_FLAG_READ, 0x10, 0x8000, 0x20, 0x10);
+        assert!(load_segment(&mut pagetable, &bad, bias, &mut read).is_err());
+        let bad = segment(ELF_PROG_FLAG_READ, 0x10, 0x8020, 0x10, 0x10);
+        assert!(load_segment(&mut pagetable, &bad, bias, &mut read).is_err());
+
+        pagetable.unmap_pages(VirtAddr::try_from(0x1000).unwrap(), 2, true).unwrap();
+        pagetable.unmap_pages(VirtAddr::try_from(0x4000).unwrap(), 3, true).unwrap();
+        pagetable.free_walk();
+    }
+    crate::kernel_test!(segments);
+
+    /// A relative relocation is applied, and a table, an addend or an offset
+    /// that overflows or leaves the image is refused
+    pub fn relocations() {
+        let mut pagetable = PageTable::uvm_create();
+        let mut read = |_: usize, buf: &mut [u8]| -> Result<(), &'static str> {
+            buf.iter_mut().for_each(|b| *b = 0);
+            Ok(())
+        };
+        let data = ProgHeader {
+            ptype: ELF_PROG_LOAD, flags: ELF_PROG_FLAG_READ | ELF_PROG_FLAG_WRITE,
+            memsz: 0x100, align: PGSIZE as u64, ..Default::default()
+        };
+        let bias = 0x1000;
+        let sz = load_segment(&mut pagetable, &data, bias, &mut read).unwrap();
+        assert_eq!(sz, 0x1100);
+
+        let set = |rela: u64, offset: u64, addend: i64| {
+            let table = [(DT_RELA, rela), (DT_RELASZ, 24), (DT_NULL, 0)];
+            for (i, &(tag, val)) in table.iter().enumerate() {
+                pagetable.copy_out(bias + i * 16, &tag.to_le_bytes()).unwrap();
+                pagetable.copy_out(bias + i * 16 + 8, &val.to_le_bytes()).unwrap();
+            }
+            let entry = [offset, R_RISCV_RELATIVE, addend as u64];
+            for (i, word) in entry.iter().enumerate() {
+                pagetable.copy_out(bias + 0x40 + i * 8, &word.to_le_bytes()).unwrap();
+            }
+            relocate(&pagetable, bias, 0, sz)
+        };
+        assert_eq!(set(0x40, 0x80, 0x10), Ok(()));
+        assert_eq!(set(u64::MAX, 0x80, 0x10), Err("relocate: address outside the image"));
+        assert_eq!(set(0xf0, 0x80, 0x10), Err("relocate: address outside the image"));
+        assert_eq!(set(0x40, 0xfc, 0x10), Err("relocate: address outside the image"));
+        assert_eq!(set(0x40, u64::MAX, 0x10), Err("relocate: address outside the image"));
+        assert_eq!(set(0x40, 0x80, i64::MAX), Err("relocate: addend overflows"));
+        let mut word = [0u8; 8];
+        pagetable.copy_in(bias + 0x80, &mut word).unwrap();
+        assert_eq!(u64::from_le_bytes(word), 0x1010);
+
+        assert!(relocate(&pagetable, bias, 0xf8, sz).is_err());
+        pagetable.unmap_pages(VirtAddr::try_from(bias).unwrap(), 1, true).unwrap();
+        pagetable.free_walk();
+    }
+    crate::kernel_test!(relocations);
+
+    fn put<T>(file: &mut [u8], off: usize, value: &T) {
+        let bytes = unsafe { core::slice::from_raw_parts(value as *const T as *const u8, mem::size_of::<T>()) };
+        file[off..off + bytes.len()].copy_from_slice(bytes);
+    }
+
+    /// A static executable of nphdrs copies of one text segment with a bss
+    fn executable(nphdrs: usize) -> [u8; 0x100] {
+        let mut file = [0u8; 0x100];
+        let phoff = mem::size_of::<ElfHeader>();
+        let text = phoff + nphdrs * mem::size_of::<ProgHeader>();
+        let mut ident = [0u8; 12];
+        ident[0] = ELFCLASS64;
+        ident[1] = ELFDATA2LSB;
+        put(&mut file, 0, &ElfHeader {
+            magic: ELF_MAGIC, elf: ident, etype: ET_EXEC, machine: EM_RISCV, version: 1,
+            entry: text as u64, phoff: phoff as u64, ehsize: phoff as u16,
+            phentsize: mem::size_of::<ProgHeader>() as u16, phnum: nphdrs as u16,
+            ..Default::default()
+        });
+        for i in 0..nphdrs {
+            put(&mut file, phoff + i * mem::size_of::<ProgHeader>(), &ProgHeader {
+                ptype: ELF_PROG_LOAD, flags: ELF_PROG_FLAG_READ | ELF_PROG_FLAG_EXEC,
+                off: text as u64, vaddr: text as u64, paddr: text as u64,
+                filesz: 8, memsz: 0x100, align: PGSIZE as u64,
+            });
+        }
+        // nop, ebreak
+        file[text..text + 8].copy_from_slice(&[0x13, 0, 0, 0, 0x73, 0, 0x10, 0]);
+        file
+    }
+
+    /// The image and its stack are in place, a bad one leaves the old image,
+    /// and every page comes back once the process is freed
+    pub fn load_exec() {
+        let (free, _) = crate::mm::kalloc_stats();
+        let mut p = Proc::new();
+        p.set_tf(unsafe { kalloc() }.expect("load_exec: out of memory") as *mut super::super::TrapFrame);
+        p.proc_pagetable();
+
+        let file = executable(1);
+        let mut read = |off: usize, buf: &mut [u8]| -> Result<(), &'static str> {
+            buf.copy_from_slice(file.get(off..off + buf.len()).ok_or("past the end")?);
+            Ok(())
+        };
+        let argv: [&[u8]; 2] = [b"prog\0", b"x\0"];
+        let (argc, old) = load_image(&mut p, &mut read, &argv).expect("load_exec: not loaded");
+        assert_eq!(argc, 2);
+        p.drop_image(old);
+        let text = mem::size_of::<ElfHeader>() + mem::size_of::<ProgHeader>();
+        let tf = unsafe { &*p.tf };
+        let sp = tf.get_sp();
+        assert_eq!(tf.epc, text);
+        assert_eq!(tf.get_a1(), sp);
+        assert!(sp > 2 * PGSIZE && sp < 3 * PGSIZE && sp % 16 == 0);
+
+        let pagetable = p.pagetable.as_ref().unwrap();
+        let mut buf = [0u8; 16];
+        pagetable.copy_in(text, &mut buf).unwrap();
+        assert_eq!(buf[..8], file[text..text + 8]);
+        assert_eq!(buf[8..], [0; 8]);
+        // argv[1], then the null pointer
+        let mut word = [0u8; 8];
+        pagetable.copy_in(sp + 8, &mut word).unwrap();
+        pagetable.copy_in(usize::from_ne_bytes(word), &mut buf[..2]).unwrap();
+        assert_eq!(&buf[..2], b"x\0");
+        pagetable.copy_in(sp + 16, &mut word).unwrap();
+        assert_eq!(word, [0; 8]);
+        // the guard page
+        assert!(pagetable.copy_in(PGSIZE, &mut buf).is_err());
+
+        let satp = pagetable.as_satp();
+        let bad = executable(2);
+        let mut read = |off: usize, buf: &mut [u8]| -> Result<(), &'static str> {
+            buf.copy_from_slice(bad.get(off..off + buf.len()).ok_or("past the end")?);
+            Ok(())
+        };
+        assert!(load_image(&mut p, &mut read, &argv).is_err());
+        assert_eq!(p.pagetable.as_ref().unwrap().as_satp(), satp);
+        assert_eq!(unsafe { (*p.tf).epc }, text);
+
+        p.free();
+        assert_eq!(crate::mm::kalloc_stats().0, free);
+    }
+    crate::kernel_test!(load_exec);
+}
@@ -0,0 +1,175 @@
+//! Interval timers of a process, see sys_setitimer in syscall.rs
+//!
+//! ITIMER_REAL counts clock ticks, as a timer in the timing wheel, see timer.rs,
+//! ITIMER_VIRTUAL counts the ticks the process ran in user mode, its utime.
+//! There are no signals, so instead of SIGALRM or SIGVTALRM
+//! a timer calls the user handler given to setitimer, like xv6's sigalarm:
+//! on the way back to user space the registers are saved,
+//! the handler is entered with the timer in a0, on the interrupted stack,
+//! and it must end with sigreturn, which puts the registers back.
+//! A timer firing again before its handler ran is only delivered once,
+//! timers firing while a handler runs wait for its sigreturn.
+
+use core::mem;
+
+use crate::consts::NPROC;
+use crate::timer::{self, TimerId};
+
+use super::{Proc, TrapFrame, PROC_MANAGER};
+
+pub const ITIMER_REAL: usize = 0;
+pub const ITIMER_VIRTUAL: usize = 1;
+const NITIMER: usize = 2;
+
+pub struct ITimers {
+    handler: [usize; NITIMER],
+    /// ticks between firings after the first, 0 for a one-shot timer
+    interval: [usize; NITIMER],
+    /// the real timer's entry in the wheel, it re-adds itself for each interval
+    real: Option<TimerId>,
+    /// bumped whenever the real timer is set, so a stale one firing is ignored
+    real_seq: usize,
+    /// utime the virtual timer fires at, 0 while it is not armed
+    virt_expires: usize,
+    /// a bit per timer that fired, but whose handler has not been entered yet
+    pending: usize,
+    /// registers of the interrupted code while a handler runs
+    saved: Option<TrapFrame>,
+}
+
+impl ITimers {
+    pub const fn new() -> Self {
+        Self {
+            handler: [0; NITIMER],
+            interval: [0; NITIMER],
+            real: None,
+            real_seq: 0,
+            virt_expires: 0,
+            pending: 0,
+            saved: None,
+        }
+    }
+}
+
+/// The argument of the real timer's callback,
+/// the slot of the process in the table and its real_seq
+fn real_arg(p: &Proc) -> usize {
+    let table = unsafe { PROC_MANAGER.table.as_ptr() } as usize;
+    let index = (p as *const Proc as usize - table) / mem::size_of::<Proc>();
+    index + NPROC * p.itimers.real_seq
+}
+
+/// Called from the timing wheel on the boot hart, with interrupts off
+fn real_fire(arg: usize) {
+    let p = unsafe { &mut PROC_MANAGER.table[arg % NPROC] };
+    let _guard = p.lock.lock();
+    let t = &mut p.itimers;
+    if t.real_seq != arg / NPROC {
+        return;
+    }
+    t.pending |= 1 << ITIMER_REAL;
+    t.real = match t.interval[ITIMER_REAL] {
+        0 => None,
+        interval => timer::add_timer(interval, real_fire, arg).ok(),
+    };
+}
+
+impl Proc {
+    /// Arm timer which to fire in value ticks, and every interval ticks after that,
+    /// calling handler, or disarm it if value is 0.
+    pub fn set_itimer(&mut self, which: usize, value: usize, interval: usize, handler: usize)
+        -> Result<(), &'static str>
+    {
+        if which >= NITIMER {
+            return Err("setitimer: no such timer");
+        }
+        let guard = self.lock.lock();
+        let t = &mut self.itimers;
+        t.pending &= !(1 << which);
+        t.handler[which] = handler;
+        t.interval[which] = interval;
+        match which {
+            ITIMER_REAL => {
+                if let Some(id) = t.real.take() {
+                    timer::cancel(id);
+                }
+                t.real_seq += 1;
+                if value != 0 {
+                    let arg = real_arg(self);
+                    match timer::add_timer(value, real_fire, arg) {
+                        Ok(id) => self.itimers.real = Some(id),
+                        Err(str) => {
+                            drop(guard);
+                            return Err(str);
+                        }
+                    }
+                }
+            }
+            _ => t.virt_expires = if value == 0 { 0 } else { self.utime + value },
+        }
+        drop(guard);
+        Ok(())
+    }
+
+    /// Charge a clock tick to the process, interrupted in user mode,
+    /// called by the hart running it.
+    pub fn user_tick(&mut self) {
+        self.utime += 1;
+        let t = &mut self.itimers;
+        if t.virt_expires == 0 || self.utime < t.virt_expires {
+            return;
+        }
+        t.virt_expires = match t.interval[ITIMER_VIRTUAL] {
+            0 => 0,
+            interval => self.utime + interval,
+        };
+        let _guard = self.lock.lock();
+        self.itimers.pending |= 1 << ITIMER_VIRTUAL;
+    }
+
+    /// Enter the handler of a timer that fired, called on the way back to user space,
+    /// with interrupts off.
+    pub fn deliver_itimers(&mut self) {
+        if self.itimers.saved.is_some() {
+            return;
+        }
+        let guard = self.lock.lock();
+        let t = &mut self.itimers;
+        let which = match (0..NITIMER).find(|which| t.pending & (1 << which) != 0) {
+            Some(which) => which,
+            None => return,
+        };
+        t.pending &= !(1 << which);
+        drop(guard);
+
+        let tf = unsafe { &mut *self.tf };
+        self.itimers.saved = Some(*tf);
+        tf.epc = self.itimers.handler[which];
+        tf.set_a0(which);
+        // in case the interrupted code was between adjusting sp and aligning it
+        let sp = tf.get_sp();
+        tf.set_sp(sp & !0xf);
+    }
+
+    /// Leave a handler, back to the registers it interrupted,
+    /// return the interrupted a0, which the syscall puts back.
+    pub fn itimer_return(&mut self) -> Result<usize, &'static str> {
+        let saved = self.itimers.saved.take().ok_or("sigreturn: not in a handler")?;
+        let tf = unsafe { &mut *self.tf };
+        *tf = saved;
+        Ok(tf.get_a0())
+    }
+
+    /// Disarm all timers, when the process is freed.
+    /// p->lock must be held.
+    pub fn clear_itimers(&mut self) {
+        if let Some(id) = self.itimers.real.take() {
+            timer::cancel(id);
+        }
+        let seq = self.itimers.real_seq + 1;
+        self.itimers = ITimers::new();
+        // a real timer already firing must not hit the next process in the slot
+        self.itimers.real_seq = seq;
+        self.utime = 0;
+    }
+}
@@ -1,22 +1,42 @@
 use core::convert::TryFrom;
-use core::ptr;
+use core::{mem, ptr};
 
-use crate::consts::{NPROC, PGSIZE, TRAMPOLINE};
+use crate::cmdline;
+use crate::consts::{MAXPATH, NPROC, PGSIZE, TRAMPOLINE};
 use crate::mm::{kalloc, kvm_map, PhysAddr, PteFlag, VirtAddr};
 use crate::spinlock::SpinLock;
 use crate::trap::user_trap_ret;
-use crate::fs::{self, ROOTDEV};
+use crate::fs::{self, Inode, ROOTDEV};
+use crate::proclog;
+use crate::schedtrace;
 
-pub use cpu::{cpu_id, push_off, pop_off, my_cpu, my_proc};
+pub use cpu::{cpu_id, push_off, pop_off, my_cpu, my_proc, my_proc_ptr, my_pid, my_proc_info};
+pub use cpu::{offline, park_others, parked, set_online, unpark_others};
+pub use cpu::{cond_resched, set_preempt_kernel};
+pub use runq::len as runq_len;
+#[cfg(debug_assertions)]
+pub use cpu::{assert_only_holding, lock_acquired, lock_released};
 
 mod context;
+mod coredump;
+mod cred;
 mod proc;
 mod cpu;
 mod trapframe;
 mod syscall;
 mod elf;
+mod itimer;
+mod ptrace;
+mod rt;
+mod runq;
+mod sleepq;
+mod vma;
+#[cfg(feature = "selftest")]
+pub mod selftest;
 
 use context::Context;
+use coredump::Checkpoint;
+use cred::Cred;
 use proc::{Proc, ProcState};
 use trapframe::TrapFrame;
 
@@ -29,16 +49,21 @@ pub static mut PROC_MANAGER: ProcManager = ProcManager::new();
 
 pub struct ProcManager {
     table: [Proc; NPROC],
-    init_proc: usize,
+    init_proc: *mut Proc,
     pid: SpinLock<usize>,
+    // protects every p.parent,
+    // and keeps a parent's wakeup in exit from being lost,
+    // always acquired before any p.lock
+    wait_lock: SpinLock<()>,
 }
 
 impl ProcManager {
     const fn new() -> Self {
         Self {
-            table: [Proc::new(); NPROC],
-            init_proc: 0,
+            table: [const { Proc::new() }; NPROC],
+            init_proc: ptr::null_mut(),
             pid: SpinLock::new(0, "nextpid"),
+            wait_lock: SpinLock::new((), "wait_lock"),
         }
     }
 
@@ -62,9 +87,8 @@ impl ProcManager {
     /// Allocate pid
     /// It can be accessed simultaneously
     fn alloc_pid(&self) -> usize {
-        let ret_pid: usize;
         let mut pid = self.pid.lock();
-        ret_pid = *pid;
+        let ret_pid = *pid;
         *pid += 1;
         drop(pid);
         ret_pid
@@ -80,25 +104,22 @@ impl ProcManager {
         for i in 0..self.table.len() {
             let p = &mut self.table[i];
             unsafe {p.lock.acquire_lock();}
-            match p.state {
-                ProcState::UNUSED => {
-                    let pid = self.alloc_pid();
-                    let p = &mut self.table[i];
-                    p.pid = pid;
-                    match unsafe { kalloc() } {
-                        Some(ptr) => {
-                            p.set_tf(ptr as *mut TrapFrame);
-                        },
-                        None => {
-                            unsafe {p.lock.release_lock();}
-                            return None
-                        },
-                    }
-                    p.proc_pagetable();
-                    p.init_context();
-                    return Some(&mut self.table[i])
-                },
-                _ => {},
+            if p.state == ProcState::UNUSED {
+                let pid = self.alloc_pid();
+                let p = &mut self.table[i];
+                p.pid = pid;
+                match unsafe { kalloc() } {
+                    Some(ptr) => {
+                        p.set_tf(ptr as *mut TrapFrame);
+                    },
+                    None => {
+                        unsafe {p.lock.release_lock();}
+                        return None
+                    },
+                }
+                p.proc_pagetable();
+                p.init_context();
+                return Some(&mut self.table[i])
             }
             unsafe {p.lock.release_lock();}
         }
@@ -106,72 +127,664 @@ impl ProcManager {
         None
     }
 
-    /// Look in the process table for an RUNNABLE proc.
+    /// Take a RUNNABLE proc off hart's run queue, or steal one from another hart's,
+    /// and return it with p->lock held, see runq.rs.
     /// Typically used in each cpu's scheduler
-    fn alloc_runnable(&mut self) ->
+    fn alloc_runnable(&mut self, hart: usize) ->
         Option<&mut Proc>
     {
-        for i in 0..self.table.len() {
-            unsafe {self.table[i].lock.acquire_lock();}
-            match self.table[i].state {
-                ProcState::RUNNABLE => {
-                    return Some(&mut self.table[i])
-                },
-                _ => {},
+        while let Some(entry) = runq::pop(hart).or_else(|| runq::steal(hart)) {
+            let p = &mut self.table[entry.index];
+            unsafe {p.lock.acquire_lock();}
+            if p.state == ProcState::RUNNABLE {
+                return Some(&mut self.table[entry.index])
             }
-            unsafe {self.table[i].lock.release_lock();}
+            unsafe {p.lock.release_lock();}
         }
 
         None
     }
 
+    /// Set the real-time priority of process pid, 0 for SCHED_OTHER.
+    /// Return false if there is no such process.
+    pub fn set_rt_prio(&mut self, pid: usize, prio: u8) -> bool {
+        for p in self.table.iter_mut() {
+            let _guard = p.lock.lock();
+            if p.pid == pid && p.state != ProcState::UNUSED {
+                p.rt_prio = prio;
+                return true
+            }
+        }
+        false
+    }
+
+    /// Set the quantum of process pid, 0 for its class's default.
+    /// Return false if there is no such process.
+    pub fn set_quantum(&mut self, pid: usize, quantum: usize) -> bool {
+        for p in self.table.iter_mut() {
+            let _guard = p.lock.lock();
+            if p.pid == pid && p.state != ProcState::UNUSED {
+                p.quantum = quantum;
+                return true
+            }
+        }
+        false
+    }
+
+    /// Whether there is a RUNNABLE proc in any run queue, for an idle hart
+    fn has_runnable(&self) -> bool {
+        runq::any()
+    }
+
     /// Set up first process
     /// Only called once in rust_main(),
     /// which can guarantee the init proc's index at table is 0
     pub unsafe fn user_init(&mut self) {
         let p = self.alloc_proc().expect("user_init: all process should be unused");
+        // root= on the command line overrides the default,
+        // its root directory becomes the first working directory
+        let dev = match cmdline::get("root") {
+            Some(root) => root.parse().unwrap_or_else(|_| {
+                println!("root={} is not a device number, using {}", root, ROOTDEV);
+                ROOTDEV
+            }),
+            None => ROOTDEV,
+        };
+        fs::set_root_dev(dev);
         p.user_init();
+        make_runnable(p, None);
+        proclog::fork(p.pid, 0, p.name());
         p.lock.release_lock();
+        self.init_proc = &mut self.table[0];
     }
 
     /// Check if the given process is the init_proc 
     fn is_init_proc(&self, p: &Proc) -> bool {
-        ptr::eq(&self.table[0], p)
+        ptr::eq(self.init_proc, p)
+    }
+
+    /// Create a kernel thread running func(arg) in a free proc,
+    /// as a child of the current process, or as an orphan if there is none.
+    /// It has no trapframe or user page table,
+    /// and runs on its kernel stack in the kernel page table.
+    /// Return its pid.
+    pub fn spawn_kthread(&mut self, name: &[u8], func: fn(usize) -> i32, arg: usize) -> Option<usize> {
+        ftrace!();
+        let parent = my_proc_ptr();
+        let wait_guard = self.wait_lock.lock();
+        for i in 0..self.table.len() {
+            let p = &mut self.table[i];
+            unsafe {p.lock.acquire_lock();}
+            if p.state == ProcState::UNUSED {
+                let pid = self.alloc_pid();
+                let p = &mut self.table[i];
+                p.pid = pid;
+                p.parent = parent;
+                p.set_name(name);
+                p.kthread = Some((func as usize, arg));
+                p.init_kthread_context();
+                make_runnable(p, None);
+                proclog::fork(pid, p.ppid(), p.name());
+                unsafe {p.lock.release_lock();}
+                drop(wait_guard);
+                return Some(pid)
+            }
+            unsafe {p.lock.release_lock();}
+        }
+        drop(wait_guard);
+
+        None
+    }
+
+    /// Create a child of p, a copy of it, see Proc::fork_from,
+    /// which returns 0 from the fork.
+    /// Return its pid.
+    pub fn fork(&mut self, p: &mut Proc) -> Result<usize, &'static str> {
+        ftrace!();
+        self.new_child(p, |child, p| child.fork_from(p))
+    }
+
+    /// Start the process saved in ckpt as a child of p, see Proc::restore_from,
+    /// which returns 1 from the checkpoint.
+    /// Return its pid.
+    pub fn restore(&mut self, p: &mut Proc, ckpt: &mut Checkpoint) -> Result<usize, &'static str> {
+        ftrace!();
+        self.new_child(p, |child, p| child.restore_from(p, ckpt))
+    }
+
+    /// Create a child of p, set up by init, which must not sleep, and make it runnable.
+    /// Return its pid.
+    fn new_child(&mut self, p: &mut Proc, init: impl FnOnce(&mut Proc, &Proc) -> Result<(), &'static str>)
+        -> Result<usize, &'static str>
+    {
+        // the child is set up from the start, with wait_lock held through, as in spawn_kthread
+        unsafe {self.wait_lock.acquire_lock();}
+        let child = match self.alloc_proc() {
+            Some(child) => child,
+            None => {
+                unsafe {self.wait_lock.release_lock();}
+                return Err("no free process")
+            }
+        };
+        if let Err(str) = init(child, p) {
+            child.free();
+            unsafe {child.lock.release_lock();}
+            unsafe {self.wait_lock.release_lock();}
+            return Err(str)
+        }
+        let pid = child.pid;
+        child.parent = p;
+        make_runnable(child, None);
+        proclog::fork(pid, p.pid, child.name());
+        unsafe {child.lock.release_lock();}
+        unsafe {self.wait_lock.release_lock();}
+        Ok(pid)
+    }
+
+    /// Give the children of p to init,
+    /// or make them orphans if there is no init process, e.g., in unit tests.
+    /// wait_lock must be held.
+    fn reparent(&mut self, p: *mut Proc) {
+        let init = self.init_proc;
+        let mut given = false;
+        for pp in self.table.iter_mut() {
+            if pp.parent == p {
+                pp.parent = init;
+                given = true;
+            }
+        }
+        if given && !init.is_null() {
+            self.wakeup(init as usize);
+        }
+    }
+
+    /// Exit the current process, a user one or a kernel thread,
+    /// which stays a zombie until its parent calls wait(), or it is reaped as an orphan.
+    /// Its children go to init.
+    pub fn exit(&mut self, p: &mut Proc, status: i32) -> ! {
+        ftrace!();
+        unsafe {self.wait_lock.acquire_lock();}
+        self.reparent(p);
+
+        // the parent might be sleeping in wait()
+        if !p.parent.is_null() {
+            self.wakeup(p.parent as usize);
+        }
+
+        unsafe {p.lock.acquire_lock();}
+        p.xstate = status;
+        p.state = ProcState::ZOMBIE;
+        schedtrace::record(schedtrace::SCHED_EXIT, p.pid, 0);
+        proclog::exit(p.pid, p.ppid(), status);
+        unsafe {self.wait_lock.release_lock();}
+
+        unsafe {my_cpu().sched();}
+        panic!("exit: zombie exit");
+    }
+
+    /// Wait for a child of p to exit, free it,
+    /// and return its pid and exit status.
+    /// Return None if p has no children, or it has been killed.
+    pub fn wait(&mut self, p: &mut Proc) -> Option<(usize, i32)> {
+        ftrace!();
+        let me = p as *mut Proc;
+        let mut wait_guard = self.wait_lock.lock();
+        loop {
+            let mut have_kids = false;
+            for pp in self.table.iter_mut() {
+                if pp.parent != me {
+                    continue;
+                }
+                have_kids = true;
+                unsafe {pp.lock.acquire_lock();}
+                if pp.state == ProcState::ZOMBIE {
+                    let ret = (pp.pid, pp.xstate);
+                    pp.free();
+                    unsafe {pp.lock.release_lock();}
+                    return Some(ret)
+                }
+                unsafe {pp.lock.release_lock();}
+            }
+
+            if !have_kids || p.killed {
+                return None
+            }
+
+            wait_guard = p.sleep(me as usize, wait_guard);
+        }
+    }
+
+    /// Free the orphan with pid if it has exited, returning its exit status.
+    /// For contexts without a process to wait in, e.g., kernel tests.
+    pub fn reap_orphan(&mut self, pid: usize) -> Option<i32> {
+        let wait_guard = self.wait_lock.lock();
+        let mut ret = None;
+        for p in self.table.iter_mut() {
+            if p.pid != pid || !p.parent.is_null() {
+                continue;
+            }
+            unsafe {p.lock.acquire_lock();}
+            if p.state == ProcState::ZOMBIE {
+                ret = Some(p.xstate);
+                p.free();
+            }
+            unsafe {p.lock.release_lock();}
+        }
+        drop(wait_guard);
+        ret
+    }
+
+    /// Print the used process slots, for the kernel monitor.
+    /// No lock is taken, see monitor.rs.
+    pub fn dump(&self) {
+        println!("pid   state      killed parent rt  name");
+        for p in self.table.iter().filter(|p| p.state != ProcState::UNUSED) {
+            let state = match p.state {
+                ProcState::UNUSED => "unused",
+                ProcState::SLEEPING => "sleeping",
+                ProcState::RUNNABLE => "runnable",
+                ProcState::RUNNING => "running",
+                ProcState::ZOMBIE => "zombie",
+            };
+            println!("{:<5} {:<10} {:<6} {:<6} {:<3} {}",
+                p.pid, state, p.killed, p.ppid(), p.rt_prio, p.name());
+        }
+    }
+
+    /// Fill records with the used process slots after the first skip of them,
+    /// for the crash dump, return how many there are. No lock is taken, as in dump().
+    #[cfg(feature = "crashdump")]
+    pub fn crash_records_from(&self, skip: usize, records: &mut [crate::crashdump::ProcRecord]) -> usize {
+        let used = self.table.iter().filter(|p| p.state != ProcState::UNUSED).skip(skip);
+        let mut n = 0;
+        for (record, p) in records.iter_mut().zip(used) {
+            let state: &[u8] = match p.state {
+                ProcState::UNUSED => b"unused",
+                ProcState::SLEEPING => b"sleeping",
+                ProcState::RUNNABLE => b"runnable",
+                ProcState::RUNNING => b"running",
+                ProcState::ZOMBIE => b"zombie",
+            };
+            *record = crate::crashdump::ProcRecord::default();
+            record.pid = p.pid as u64;
+            record.ppid = p.ppid() as u64;
+            record.killed = p.killed as u64;
+            record.state[..state.len()].copy_from_slice(state);
+            let name = p.name().as_bytes();
+            record.name[..name.len()].copy_from_slice(name);
+            n += 1;
+        }
+        n
+    }
+
+    /// Print the page table of user process pid, for the kernel monitor.
+    /// Return false if there is none.
+    pub fn dump_pagetable(&self, pid: usize) -> bool {
+        let p = self.table.iter().find(|p| p.state != ProcState::UNUSED && p.pid == pid);
+        match p.and_then(|p| p.pagetable.as_ref()) {
+            Some(pagetable) => {
+                pagetable.print();
+                true
+            }
+            None => false,
+        }
+    }
+
+    /// Set the killed flag of process pid, which exits
+    /// once it traps into the kernel, or returns to user space, see try_abondon in cpu.rs.
+    /// A sleeping one is woken up to notice it.
+    /// by is the pid of the killer, for the event log, 0 for the kernel,
+    /// cred the killer's, to check, None for the kernel. init cannot be killed.
+    pub fn kill(&mut self, pid: usize, by: usize, cred: Option<&Cred>) -> Result<(), &'static str> {
+        let init = self.init_proc;
+        for p in self.table.iter_mut() {
+            unsafe {p.lock.acquire_lock();}
+            if p.pid == pid && p.state != ProcState::UNUSED {
+                let allowed = match cred {
+                    _ if ptr::eq(init, p) => Err("cannot kill init"),
+                    Some(cred) => cred.check_kill(&p.cred),
+                    None => Ok(()),
+                };
+                if allowed.is_ok() {
+                    p.killed = true;
+                    proclog::kill(pid, p.ppid(), by);
+                    if p.state == ProcState::SLEEPING {
+                        make_runnable(p, None);
+                    }
+                }
+                unsafe {p.lock.release_lock();}
+                return allowed;
+            }
+            unsafe {p.lock.release_lock();}
+        }
+        Err("no such process")
     }
 
     /// Wake up all processes sleeping on chan.
     /// Must be called without any p->lock.
     pub fn wakeup(&mut self, chan: usize) {
         for p in self.table.iter_mut() {
-            let _lock = p.lock.lock();
+            unsafe {p.lock.acquire_lock();}
             if p.state == ProcState::SLEEPING && p.chan == chan {
-                p.state = ProcState::RUNNABLE;
+                make_runnable(p, None);
+                // interrupts are off with p.lock held
+                let waker = unsafe { my_pid() }.unwrap_or(0);
+                schedtrace::record(schedtrace::SCHED_WAKEUP, waker, p.pid);
             }
-            drop(_lock);
+            unsafe {p.lock.release_lock();}
+        }
+    }
+}
+
+/// The slot of p in the process table
+fn index_of(p: &Proc) -> usize {
+    let table = unsafe { PROC_MANAGER.table.as_ptr() } as usize;
+    (p as *const Proc as usize - table) / mem::size_of::<Proc>()
+}
+
+/// What a timed sleeper at index sleeps on, see sleepq.rs
+fn sleep_chan(index: usize) -> usize {
+    &sleepq::SLEEPQ as *const _ as usize + index
+}
+
+/// Wake the timed sleepers due at tick now, see sleepq.rs.
+/// Called from the clock interrupt, without any p->lock.
+pub fn expire_sleepers(now: usize) {
+    let mut q = sleepq::SLEEPQ.lock();
+    while let Some(sleeper) = q.pop_due(now) {
+        let p = unsafe { &mut PROC_MANAGER.table[sleeper.index] };
+        unsafe {p.lock.acquire_lock();}
+        if p.state == ProcState::SLEEPING && p.chan == sleep_chan(sleeper.index) {
+            make_runnable(p, None);
         }
+        unsafe {p.lock.release_lock();}
     }
 }
 
+/// Mark p RUNNABLE, and queue it on hart if given, e.g., the one it gave up,
+/// otherwise on an idle hart or this one, see place() in cpu.rs and runq.rs.
+/// p->lock must be held.
+fn make_runnable(p: &mut Proc, hart: Option<usize>) {
+    p.state = ProcState::RUNNABLE;
+    let index = index_of(p);
+    let hart = cpu::place(hart);
+    runq::push(hart, runq::Entry { index, rt_prio: p.rt_prio, last_run: p.last_run });
+    cpu::kick(hart);
+}
+
 /// A fork child's very first scheduling by scheduler()
 /// will swtch to forkret.
 /// 
 /// Need to be handled carefully, because CPU use ra to jump here
 unsafe fn fork_ret() -> ! {
+    ftrace!();
     static mut FIRST: bool = true;
     
     // Still holding p->lock from scheduler
     my_cpu().release_proc();
     
     if FIRST {
-        // File system initialization
+        // File system initialization, on the root= device, see user_init,
+        // init= on the command line overrides the default
         FIRST = false;
-        fs::init(ROOTDEV);
+        fs::init();
+        #[cfg(feature = "crashdump")]
+        crate::crashdump::check();
+
+        // only the first process gets here,
+        // it runs /init built from user/src/bin/init.rs, or the init= one
+        let init = match cmdline::get("init") {
+            Some(init) if !init.is_empty() && init.len() < MAXPATH => init,
+            Some(init) => {
+                println!("init={} is not a usable path, using /init", init);
+                "/init"
+            }
+            None => "/init",
+        };
+        let mut path = [0u8; MAXPATH];
+        path[..init.len()].copy_from_slice(init.as_bytes());
+        let path = &path[..init.len() + 1];
+        let p = my_proc();
+        match elf::exec(p, path, &[path]) {
+            Ok(argc) => {
+                proclog::exec(p.pid, p.ppid(), p.name());
+                (*p.tf).set_a0(argc)
+            }
+            Err(err) => panic!("fork_ret: cannot load {}: {}", init, err),
+        }
     }
 
     user_trap_ret();
 }
 
+/// A kernel thread's very first scheduling will swtch here
+unsafe fn kthread_ret() -> ! {
+    // Still holding p->lock from scheduler
+    my_cpu().release_proc();
+
+    let p = my_proc();
+    let (func, arg) = p.kthread.expect("kthread_ret: not a kernel thread");
+    let func: fn(usize) -> i32 = core::mem::transmute(func);
+    let status = func(arg);
+    PROC_MANAGER.exit(p, status);
+}
+
+/// The root directory of the current process, see sys_chroot,
+/// None for the file system's root, also when no process runs on this hart.
+pub fn my_root() -> Option<&'static Inode> {
+    let p = my_proc_ptr();
+    if p.is_null() {
+        return None
+    }
+    unsafe { (*p).root }
+}
+
+/// The working directory of the current process,
+/// None for the file system's root, also when no process runs on this hart.
+pub fn my_cwd() -> Option<&'static Inode> {
+    let p = my_proc_ptr();
+    if p.is_null() {
+        return None
+    }
+    unsafe { (*p).cwd }
+}
+
 #[inline]
 fn kstack(pos: usize) -> usize {
     Into::<usize>::into(TRAMPOLINE) - (pos + 1) * 2 * PGSIZE
 }
+
+#[cfg(feature = "unit_test")]
+pub mod tests {
+    use core::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+
+    use crate::rmain::boot_hart;
+    use super::*;
+
+    /// Run the scheduler on this hart until done is set
+    fn schedule_until(done: &AtomicBool) {
+        while !done.load(Ordering::SeqCst) {
+            unsafe { my_cpu().schedule_once(); }
+        }
+    }
+
+    /// Hart 0 waits for the orphan pid to exit while scheduling,
+    /// then tells the other harts to stop.
+    fn reap_scheduling(pid: usize, done: &AtomicBool) -> i32 {
+        loop {
+            unsafe { my_cpu().schedule_once(); }
+            if let Some(status) = unsafe { PROC_MANAGER.reap_orphan(pid) } {
+                done.store(true, Ordering::SeqCst);
+                return status
+            }
+        }
+    }
+
+    /// Every slot is unused again, and kept its kernel stack
+    fn check_table(kstacks: &[usize; NPROC]) {
+        for (i, p) in unsafe { PROC_MANAGER.table.iter() }.enumerate() {
+            let _guard = p.lock.lock();
+            assert_eq!(p.state, ProcState::UNUSED, "proc slot {} leaked", i);
+            assert!(p.parent.is_null(), "proc slot {} still has a parent", i);
+            assert_eq!(p.kstack(), kstacks[i], "proc slot {} lost its kstack", i);
+        }
+    }
+
+    fn kstacks() -> [usize; NPROC] {
+        let mut kstacks = [0; NPROC];
+        for (i, p) in unsafe { PROC_MANAGER.table.iter() }.enumerate() {
+            kstacks[i] = p.kstack();
+        }
+        kstacks
+    }
+
+    const ROUNDS: usize = 100;
+    const BATCH: usize = NPROC / 2;
+
+    static STRESS_STARTED: AtomicBool = AtomicBool::new(false);
+    static STRESS_DONE: AtomicBool = AtomicBool::new(false);
+    static STRESS_EXITED: AtomicUsize = AtomicUsize::new(0);
+
+    fn stress_child(arg: usize) -> i32 {
+        STRESS_EXITED.fetch_add(1, Ordering::SeqCst);
+        arg as i32
+    }
+
+    /// Spawn batches of children and wait for all of them,
+    /// each exit status must come back with the right pid
+    fn stress_parent(_: usize) -> i32 {
+        let p = unsafe { my_proc() };
+        for round in 0..ROUNDS {
+            let mut spawned: [(usize, i32); BATCH] = [(0, 0); BATCH];
+            for (i, child) in spawned.iter_mut().enumerate() {
+                let status = (round * BATCH + i) as i32;
+                let pid = unsafe { PROC_MANAGER.spawn_kthread(b"stress", stress_child, status as usize) }
+                    .expect("stress_parent: out of procs");
+                *child = (pid, status);
+            }
+            for _ in 0..BATCH {
+                let (pid, status) = unsafe { PROC_MANAGER.wait(p) }.expect("stress_parent: lost a child");
+                let child = spawned.iter_mut().find(|c| c.0 == pid).expect("stress_parent: not my child");
+                assert_eq!(child.1, status, "stress_parent: wrong exit status");
+                child.0 = 0;
+            }
+            assert!(unsafe { PROC_MANAGER.wait(p) }.is_none(), "stress_parent: extra child");
+        }
+        0
+    }
+
+    /// Thousands of short-lived kernel threads scheduled across all harts
+    pub fn fork_exit_stress() {
+        if unsafe { cpu_id() } == boot_hart() {
+            let kstacks = kstacks();
+            let pid = unsafe { PROC_MANAGER.spawn_kthread(b"stress", stress_parent, 0) }
+                .expect("fork_exit_stress: no free proc");
+            STRESS_STARTED.store(true, Ordering::SeqCst);
+            assert_eq!(reap_scheduling(pid, &STRESS_DONE), 0);
+            assert_eq!(STRESS_EXITED.load(Ordering::SeqCst), ROUNDS * BATCH);
+            check_table(&kstacks);
+        } else {
+            while !STRESS_STARTED.load(Ordering::SeqCst) {
+                core::hint::spin_loop();
+            }
+            schedule_until(&STRESS_DONE);
+        }
+    }
+    crate::kernel_test!(fork_exit_stress, smp);
+
+    const NORPHAN: usize = 8;
+
+    static ORPHAN_STARTED: AtomicBool = AtomicBool::new(false);
+    static ORPHAN_RELEASE: AtomicBool = AtomicBool::new(false);
+    static ORPHAN_DONE: AtomicBool = AtomicBool::new(false);
+    static ORPHAN_PIDS: [AtomicUsize; NORPHAN] = [
+        AtomicUsize::new(0), AtomicUsize::new(0), AtomicUsize::new(0), AtomicUsize::new(0),
+        AtomicUsize::new(0), AtomicUsize::new(0), AtomicUsize::new(0), AtomicUsize::new(0),
+    ];
+
+    fn orphan(arg: usize) -> i32 {
+        while !ORPHAN_RELEASE.load(Ordering::SeqCst) {
+            core::hint::spin_loop();
+        }
+        arg as i32
+    }
+
+    /// Spawn children and exit without waiting for them
+    fn orphan_parent(_: usize) -> i32 {
+        for (i, pid) in ORPHAN_PIDS.iter().enumerate() {
+            let child = unsafe { PROC_MANAGER.spawn_kthread(b"orphan", orphan, i) }
+                .expect("orphan_parent: out of procs");
+            pid.store(child, Ordering::SeqCst);
+        }
+        NORPHAN as i32
+    }
+
+    /// Children outlive their parent, and are reparented,
+    /// here to nobody, since there is no init process in unit tests
+    pub fn reparent() {
+        if unsafe { cpu_id() } == boot_hart() {
+            let kstacks = kstacks();
+            let pid = unsafe { PROC_MANAGER.spawn_kthread(b"orphan", orphan_parent, 0) }
+                .expect("reparent: no free proc");
+            ORPHAN_STARTED.store(true, Ordering::SeqCst);
+            let parent_done = AtomicBool::new(false);
+            assert_eq!(reap_scheduling(pid, &parent_done), NORPHAN as i32);
+
+            for (i, pid) in ORPHAN_PIDS.iter().enumerate() {
+                let pid = pid.load(Ordering::SeqCst);
+                let _wait_guard = unsafe { PROC_MANAGER.wait_lock.lock() };
+                let p = unsafe { PROC_MANAGER.table.iter() }.find(|p| p.pid == pid)
+                    .expect("reparent: orphan gone before release");
+                assert!(p.parent.is_null(), "reparent: orphan {} still has its parent", i);
+            }
+
+            ORPHAN_RELEASE.store(true, Ordering::SeqCst);
+            for (i, pid) in ORPHAN_PIDS.iter().enumerate() {
+                let pid = pid.load(Ordering::SeqCst);
+                loop {
+                    unsafe { my_cpu().schedule_once(); }
+                    if let Some(status) = unsafe { PROC_MANAGER.reap_orphan(pid) } {
+                        assert_eq!(status, i as i32);
+                        break;
+                    }
+                }
+            }
+            ORPHAN_DONE.store(true, Ordering::SeqCst);
+            check_table(&kstacks);
+        } else {
+            while !ORPHAN_STARTED.load(Ordering::SeqCst) {
+                core::hint::spin_loop();
+            }
+            schedule_until(&ORPHAN_DONE);
+        }
+    }
+    crate::kernel_test!(reparent, smp);
+
+    /// Harts that cannot go offline, none of them is parked by this
+    pub fn hart_offline_checks() {
+        assert!(set_online(boot_hart(), false).is_err());
+        assert!(set_online(crate::consts::NCPU, false).is_err());
+        assert!(set_online(boot_hart(), true).is_err());
+        assert_eq!(offline() & (1 << boot_hart()), 0);
+        assert_eq!(parked(), 0);
+    }
+    crate::kernel_test!(hart_offline_checks);
+
+    /// preempt_disable() nests, and only the outermost preempt_enable() lets ticks yield again
+    pub fn preempt_nesting() {
+        use super::cpu::{preempt_count, preempt_disable, preempt_enable};
+
+        let depth = preempt_count();
+        preempt_disable();
+        preempt_disable();
+        assert_eq!(preempt_count(), depth + 2);
+        crate::spinlock::push_off();
+        assert!(!unsafe { my_cpu() }.preemptible());
+        crate::spinlock::pop_off();
+        preempt_enable();
+        assert_eq!(preempt_count(), depth + 1);
+        preempt_enable();
+        assert_eq!(preempt_count(), depth);
+    }
+    crate::kernel_test!(preempt_nesting);
+}
@@ -1,18 +1,33 @@
 use core::convert::TryFrom;
+use core::mem;
 use core::option::Option;
 use core::ptr;
 
-use crate::consts::{PGSIZE, TRAMPOLINE, TRAPFRAME};
-use crate::mm::{Box, PageTable, PhysAddr, PteFlag, VirtAddr};
+use crate::consts::{NOFILE, NVMA, PGSIZE, TRAMPOLINE, TRAPFRAME};
+use crate::fs::{self, File, Inode};
+use crate::mm::{kfree, Addr, Box, PageTable, PhysAddr, PteFlag, VirtAddr};
 use crate::register::{satp, sepc};
+use crate::schedtrace;
 use crate::spinlock::{SpinLock, SpinLockGuard};
-use crate::trap::user_trap;
+use crate::trap::{self, user_trap};
 
+use super::cred::Cred;
+use super::itimer::ITimers;
+use super::ptrace::{self, Trace};
+use super::sleepq::{Sleeper, SLEEPQ};
 use super::syscall::Syscall;
+use super::vma::Vma;
 use super::{cpu, PROC_MANAGER, my_cpu};
-use super::{cpu_id, fork_ret, Context, TrapFrame};
+use super::{cpu_id, fork_ret, kthread_ret, Context, TrapFrame};
+
+/// The user memory of a program replaced by exec, see Proc::exec_image
+pub struct Image {
+    pagetable: Box<PageTable>,
+    sz: usize,
+}
 
 #[derive(Eq, PartialEq, Debug)]
+#[allow(clippy::upper_case_acronyms)]
 pub enum ProcState {
     UNUSED,
     SLEEPING,
@@ -28,7 +43,17 @@ pub struct Proc {
     pub state: ProcState,
     pub chan: usize,
     pub killed: bool,
+    pub xstate: i32, // exit status to be returned to parent's wait
     pub pid: usize,
+    // real-time priority, 0 for SCHED_OTHER, and when it was last switched in, see rt.rs
+    pub rt_prio: u8,
+    pub last_run: usize,
+    // ticks of its slice, 0 for its class's default, and those it has run since switched in
+    pub quantum: usize,
+    pub slice: usize,
+
+    // PROC_MANAGER's wait_lock must be held when using this:
+    pub parent: *mut Proc, // null for orphans of no init process
 
     // lock need not be held, or
     // lock already be held
@@ -39,6 +64,29 @@ pub struct Proc {
     pub tf: *mut TrapFrame,
     context: Context,
     name: [u8; 16],
+    // entry, a fn(usize) -> i32, and argument of a kernel thread,
+    // not a fn pointer so that new() can stay const
+    pub kthread: Option<(usize, usize)>,
+    // clock ticks it ran in user mode
+    pub utime: usize,
+    // see itimer.rs, pending firings are protected by p->lock
+    pub itimers: ITimers,
+    // only changed by the process itself
+    pub cred: Cred,
+    // where absolute paths start, None for the file system's root
+    pub root: Option<&'static Inode>,
+    // where relative paths start, None for the file system's root
+    pub cwd: Option<&'static Inode>,
+    // open files, indexed by file descriptor, only changed by the process itself
+    pub ofile: [Option<&'static File>; NOFILE],
+    // close-on-exec flags of the descriptors, FD_CLOEXEC, see sys_fcntl
+    pub cloexec: [bool; NOFILE],
+    // RLIMIT_NOFILE, soft and hard, descriptors are below the soft one, see set_nofile()
+    pub nofile: (usize, usize),
+    // mappings of files above the heap, see vma.rs, only changed by the process itself
+    pub vma: [Option<Vma>; NVMA],
+    // see ptrace.rs, protected by its TRACE lock
+    pub trace: Trace,
 }
 
 impl Proc {
@@ -48,13 +96,52 @@ impl Proc {
             state: ProcState::UNUSED,
             chan: 0,
             killed: false,
+            xstate: 0,
             pid: 0,
+            rt_prio: 0,
+            last_run: 0,
+            quantum: 0,
+            slice: 0,
+            parent: ptr::null_mut(),
             kstack: 0,
             sz: 0,
             pagetable: None,
             tf: ptr::null_mut(),
             context: Context::new(),
             name: [0; 16],
+            kthread: None,
+            utime: 0,
+            itimers: ITimers::new(),
+            cred: Cred::root(),
+            root: None,
+            cwd: None,
+            ofile: [None; NOFILE],
+            cloexec: [false; NOFILE],
+            nofile: (NOFILE, NOFILE),
+            vma: [None; NVMA],
+            trace: Trace::new(),
+        }
+    }
+
+    /// Set the process name, truncated to fit
+    pub fn set_name(&mut self, name: &[u8]) {
+        let len = name.iter().position(|c| *c == 0).unwrap_or(name.len()).min(self.name.len() - 1);
+        self.name = [0; 16];
+        self.name[..len].copy_from_slice(&name[..len]);
+    }
+
+    /// Process name, up to the first nul byte
+    pub fn name(&self) -> &str {
+        let len = self.name.iter().position(|c| *c == 0).unwrap_or(self.name.len());
+        core::str::from_utf8(&self.name[..len]).unwrap_or("?")
+    }
+
+    /// Pid of the parent, 0 for an orphan,
+    /// read without wait_lock, only for logs and dumps
+    pub fn ppid(&self) -> usize {
+        match self.parent.is_null() {
+            true => 0,
+            false => unsafe { (*self.parent).pid },
         }
     }
 
@@ -62,9 +149,167 @@ impl Proc {
         self.kstack = kstack;
     }
 
+    pub fn kstack(&self) -> usize {
+        self.kstack
+    }
+
+    /// Size of the user memory, from address 0
+    pub fn sz(&self) -> usize {
+        self.sz
+    }
+
+    /// Copy from the process's memory at va into dst, see PageTable::copy_in
+    pub fn copy_in(&mut self, va: usize, dst: &mut [u8]) -> Result<(), &'static str> {
+        self.fault_in(va, dst.len())?;
+        self.pagetable.as_ref().unwrap().copy_in(va, dst)
+    }
+
+    /// Copy src into the process's memory at va, see PageTable::copy_out
+    pub fn copy_out(&mut self, va: usize, src: &[u8]) -> Result<(), &'static str> {
+        self.fault_in(va, src.len())?;
+        let copied = self.pagetable.as_ref().unwrap().copy_out(va, src);
+        self.mmap_written(va, src.len());
+        copied
+    }
+
+    /// Copy the nul-terminated string at va into dst, nul included,
+    /// see PageTable::copy_in_str
+    pub fn copy_in_str(&mut self, va: usize, dst: &mut [u8]) -> Result<(), &'static str> {
+        self.fault_in(va, dst.len())?;
+        self.pagetable.as_ref().unwrap().copy_in_str(va, dst)
+    }
+
+    /// Map the pages of [va, va+len), below sz or in a mapping, that the process has not touched yet,
+    /// as if it had faulted on each, for the kernel to copy in and out of them
+    fn fault_in(&mut self, va: usize, len: usize) -> Result<(), &'static str> {
+        if self.pagetable.is_none() {
+            return Err("no user memory");
+        }
+        let end = va.saturating_add(len);
+        let mut page = va & !(PGSIZE - 1);
+        while page < end {
+            if !self.zero_fault(page) {
+                self.mmap_fault(page, false);
+            }
+            page += PGSIZE;
+        }
+        Ok(())
+    }
+
+    /// Init the context of a kernel thread,
+    /// its return address is kthread_ret, which runs self.kthread
+    pub fn init_kthread_context(&mut self) {
+        self.context.clear();
+        self.context.set_ra(kthread_ret as *const () as usize);
+        self.context.set_sp(self.kstack + PGSIZE);
+    }
+
+    /// Free a zombie's trapframe, user memory and page table, and mark it unused.
+    /// Its kernel stack stays with the slot, see proc_init().
+    /// p->lock must be held.
+    pub fn free(&mut self) {
+        if let Some(mut pagetable) = self.pagetable.take() {
+            free_pagetable(&mut pagetable, self.sz);
+        }
+        if !self.tf.is_null() {
+            unsafe { kfree(self.tf as *mut u8); }
+            self.tf = ptr::null_mut();
+        }
+        self.sz = 0;
+        self.pid = 0;
+        self.parent = ptr::null_mut();
+        self.name = [0; 16];
+        self.chan = 0;
+        self.killed = false;
+        self.xstate = 0;
+        self.rt_prio = 0;
+        self.last_run = 0;
+        self.quantum = 0;
+        self.slice = 0;
+        self.kthread = None;
+        self.clear_itimers();
+        self.cred = Cred::root();
+        // all put and closed by exit()
+        self.root = None;
+        self.cwd = None;
+        self.ofile = [None; NOFILE];
+        self.cloexec = [false; NOFILE];
+        self.nofile = (NOFILE, NOFILE);
+        self.vma = [None; NVMA];
+        self.trace = Trace::new();
+        self.state = ProcState::UNUSED;
+    }
+
+    /// Make this proc, fresh from alloc_proc(), a copy of parent for fork:
+    /// its user memory and mappings of files, its trapframe, returning 0 in a0,
+    /// its open files with their close-on-exec flags and limit, directories and credentials.
+    /// Interval timers and tracing are not inherited.
+    /// On failure the memory copied so far is left for free().
+    /// p->lock must be held.
+    pub fn fork_from(&mut self, parent: &Proc) -> Result<(), &'static str> {
+        self.copy_image(parent.pagetable.as_ref().unwrap(), parent.sz)?;
+        self.fork_vma(parent)?;
+        unsafe {
+            ptr::copy_nonoverlapping(parent.tf, self.tf, 1);
+            (*self.tf).set_a0(0);
+        }
+        self.name = parent.name;
+        self.rt_prio = parent.rt_prio;
+        self.quantum = parent.quantum;
+        self.cred = parent.cred;
+        self.root = parent.root.map(fs::idup);
+        self.cwd = parent.cwd.map(fs::idup);
+        for (f, pf) in self.ofile.iter_mut().zip(parent.ofile.iter()) {
+            *f = pf.map(fs::filedup);
+        }
+        self.cloexec = parent.cloexec;
+        self.nofile = parent.nofile;
+        Ok(())
+    }
+
+    /// Copy the user memory below sz in pagetable into its own, empty so far, for fork and restore.
+    /// On failure the memory copied so far is left for free().
+    pub fn copy_image(&mut self, pagetable: &PageTable, sz: usize) -> Result<(), &'static str> {
+        self.sz = sz;
+        pagetable.uvm_copy(self.pagetable.as_mut().unwrap(), 0, sz)
+    }
+
+    /// Take the lowest free file descriptor for f
+    pub fn fdalloc(&mut self, f: &'static File) -> Result<usize, &'static str> {
+        self.fdalloc_from(f, 0)
+    }
+
+    /// Take the lowest free file descriptor for f not below lowest, for F_DUPFD,
+    /// under the soft RLIMIT_NOFILE, not closed on exec
+    pub fn fdalloc_from(&mut self, f: &'static File, lowest: usize) -> Result<usize, &'static str> {
+        let fd = (lowest..self.nofile.0).find(|fd| self.ofile[*fd].is_none()).ok_or("too many open files")?;
+        self.ofile[fd] = Some(f);
+        self.cloexec[fd] = false;
+        Ok(fd)
+    }
+
+    /// Set RLIMIT_NOFILE, the soft one at most the hard one, the hard one at most NOFILE,
+    /// only root raises it.
+    /// The descriptors open above the new soft one stay so.
+    pub fn set_nofile(&mut self, cur: usize, max: usize) -> Result<(), &'static str> {
+        if cur > max || max > NOFILE {
+            return Err("bad limit");
+        }
+        if max > self.nofile.1 {
+            self.cred.check_root()?;
+        }
+        self.nofile = (cur, max);
+        Ok(())
+    }
+
     /// Allocate a new user pagetable for itself
     /// and map trampoline code and trapframe
     pub fn proc_pagetable(&mut self) {
+        self.pagetable = Some(self.user_pagetable());
+    }
+
+    /// A new user pagetable with only the trampoline code and its trapframe mapped
+    pub fn user_pagetable(&self) -> Box<PageTable> {
         extern "C" {
             fn trampoline();
         }
@@ -74,7 +319,7 @@ impl Proc {
             .map_pages(
                 VirtAddr::from(TRAMPOLINE),
                 PGSIZE,
-                PhysAddr::try_from(trampoline as usize).unwrap(),
+                PhysAddr::try_from(trampoline as *const () as usize).unwrap(),
                 PteFlag::R | PteFlag::X,
             )
             .expect("user proc table mapping trampoline");
@@ -86,8 +331,52 @@ impl Proc {
                 PteFlag::R | PteFlag::W,
             )
             .expect("user proc table mapping trapframe");
+        pagetable
+    }
+
+    /// Switch to the user memory of a new program, sz bytes in pagetable,
+    /// it starts at entry, with sp, and argv at sp in a1.
+    /// Return the old memory, for drop_image().
+    pub fn exec_image(&mut self, pagetable: Box<PageTable>, sz: usize, entry: usize, sp: usize)
+        -> Option<Image>
+    {
+        let old = self.pagetable.replace(pagetable);
+        let old_sz = mem::replace(&mut self.sz, sz);
+        let tf = unsafe { &mut *self.tf };
+        tf.epc = entry;
+        tf.set_sp(sp);
+        tf.set_a1(sp);
+
+        // the interval timers' handlers are gone with the old image, the cpu time stays
+        unsafe { self.lock.acquire_lock(); }
+        let utime = self.utime;
+        self.clear_itimers();
+        self.utime = utime;
+        unsafe { self.lock.release_lock(); }
+        old.map(|pagetable| Image { pagetable, sz: old_sz })
+    }
 
-        self.pagetable = Some(pagetable);
+    /// Free the memory of the program before exec, and the mappings of files with it,
+    /// and close the descriptors marked FD_CLOEXEC.
+    /// Their write-back and last closes take inode locks and log operations of their own,
+    /// so exec calls it once it has let go of the executable's.
+    pub fn drop_image(&mut self, old: Option<Image>) {
+        for fd in 0..NOFILE {
+            if mem::take(&mut self.cloexec[fd]) {
+                if let Some(f) = self.ofile[fd].take() {
+                    fs::fileclose(f);
+                }
+            }
+        }
+        let Image { pagetable, sz } = match old {
+            Some(old) => old,
+            None => return,
+        };
+        // the mappings are in the old page table, the new one is only used back in user space
+        let new = self.pagetable.replace(pagetable);
+        self.munmap_all();
+        let mut pagetable = mem::replace(&mut self.pagetable, new).unwrap();
+        free_pagetable(&mut pagetable, sz);
     }
 
     pub fn set_tf(&mut self, tf: *mut TrapFrame) {
@@ -108,26 +397,19 @@ impl Proc {
         &mut self.context
     }
 
-    /// Called by ProcManager's user_init,
+    /// Called by ProcManager's user_init, which makes it runnable,
     /// Only be called once for the first user process
-    /// TODO - copy user code and sth else
+    /// Its user memory is left empty,
+    /// /init is loaded from the file system in fork_ret,
+    /// once the file system is ready.
     pub fn user_init(&mut self) {
-        // map initcode in user pagetable
-        self.pagetable.as_mut().unwrap().uvm_init(&INITCODE);
-        self.sz = PGSIZE;
-
-        // prepare return pc and stack pointer
-        let tf: &mut TrapFrame = unsafe { &mut *self.tf };
-        tf.epc = 0;
-        tf.set_sp(PGSIZE);
-
-        let init_name = b"initcode\0";
+        let init_name = b"init\0";
         unsafe {
             ptr::copy_nonoverlapping(init_name.as_ptr(), self.name.as_mut_ptr(), init_name.len());
         }
-        // TODO - p->cwd = namei("/");
-
-        self.state = ProcState::RUNNABLE;
+        // no disk I/O for the root itself, the file system is not up yet
+        let op = fs::begin_op();
+        self.cwd = Some(fs::namei(&op, b"/").expect("user_init: no root directory"));
     }
 
     // Prepare things before sret to user space
@@ -137,55 +419,218 @@ impl Proc {
         // current kernel stack's content is cleaned
         // after returning to the kernel space
         tf.kernel_sp = self.kstack + PGSIZE;
-        tf.kernel_trap = user_trap as usize;
+        tf.kernel_trap = user_trap as *const () as usize;
         tf.kernel_hartid = unsafe { cpu_id() };
 
+        // maybe enter an interval timer's handler instead
+        self.deliver_itimers();
+        let tf: &mut TrapFrame = unsafe { &mut *self.tf };
+        self.trace_fence();
+        self.trace_watch();
+
         // restore the user pc previously stored in sepc
         sepc::write(tf.epc);
 
         self.pagetable.as_ref().unwrap().as_satp()
     }
 
+    /// Map a zero page at va, if it is below sz but not mapped,
+    /// i.e., a page sbrk() grew the memory by and not touched yet,
+    /// or one given back by madvise(MADV_DONTNEED).
+    /// Return whether it was, then the faulting access is retried.
+    pub fn zero_fault(&mut self, va: usize) -> bool {
+        if va >= self.sz {
+            return false;
+        }
+        let mut va = VirtAddr::try_from(va).unwrap();
+        va.pg_round_down();
+        match self.pagetable.as_mut().unwrap().uvm_zero_page(va) {
+            Ok(()) => true,
+            Err(_) => false,
+        }
+    }
+
+    /// Give back the pages of [addr, addr+len) to the kernel,
+    /// addr is page-aligned, and the range below sz, which stays.
+    /// Return how many pages were freed.
+    pub fn dontneed(&mut self, addr: usize, len: usize) -> Result<usize, &'static str> {
+        if !addr.is_multiple_of(PGSIZE) {
+            return Err("addr not aligned");
+        }
+        match addr.checked_add(len) {
+            Some(end) if end <= self.sz => {}
+            _ => return Err("range beyond the process's memory"),
+        }
+        // stale TLB entries go at the sfence.vma on the way back to user space
+        let npages = len.div_ceil(PGSIZE);
+        self.pagetable.as_mut().unwrap().uvm_dontneed(VirtAddr::try_from(addr)?, npages)
+    }
+
+    /// Grow the user memory by n bytes, or shrink it if n is negative,
+    /// up to the lowest mapping of a file at most, or the trapframe's page.
+    /// The pages grown by are only mapped once touched, see zero_fault(),
+    /// those shrunk by are freed.
+    /// Return the old sz.
+    pub fn grow(&mut self, n: isize) -> Result<usize, &'static str> {
+        let oldsz = self.sz;
+        if n >= 0 {
+            let base = self.mmap_base();
+            self.sz = oldsz.checked_add(n as usize)
+                .filter(|newsz| *newsz <= base)
+                .ok_or("out of address space")?;
+        } else {
+            let newsz = oldsz.checked_sub(n.unsigned_abs()).ok_or("shrinking below 0")?;
+            // stale TLB entries go at the sfence.vma on the way back to user space
+            self.sz = self.pagetable.as_mut().unwrap().uvm_dealloc(oldsz, newsz);
+        }
+        Ok(oldsz)
+    }
+
     /// Exit the current process. No return.
-    /// LTODO - An exited process remains in the zombie state
-    ///     until its parent calls wait()
-    pub fn exit(&mut self, status: isize) {
-        if unsafe { PROC_MANAGER.is_init_proc(&self) } {
+    /// It remains a zombie until its parent calls wait(),
+    /// which frees its memory, see free().
+    pub fn exit(&mut self, status: isize) -> ! {
+        ftrace!();
+        if unsafe { PROC_MANAGER.is_init_proc(self) } {
             panic!("init_proc exiting");
         }
 
-        panic!("exit: TODO, status={}", status);
+        self.trace_exit();
+        self.munmap_all();
+        for f in self.ofile.iter_mut() {
+            if let Some(f) = f.take() {
+                fs::fileclose(f);
+            }
+        }
+        if self.cwd.is_some() || self.root.is_some() {
+            let op = fs::begin_op();
+            for ip in [self.cwd.take(), self.root.take()].iter().flatten() {
+                fs::iput(&op, ip);
+            }
+        }
+        unsafe { PROC_MANAGER.exit(self, status as i32) }
     }
 
     /// Handle system call as a process
     /// It may be interrrupted in the procedure of syscall
     /// Cpu's syscall jumps here
     pub fn syscall(&mut self) {
-        let a7 = {
-            let tf = unsafe { &mut *self.tf };
-            tf.admit_ecall();
-            tf.get_a7()
-        };
+        ftrace!();
+        unsafe { (*self.tf).admit_ecall(); }
+        // the tracer may change the syscall and its arguments
+        self.trace_syscall(ptrace::STOP_SYSCALL_ENTER);
+        let a7 = unsafe { (*self.tf).get_a7() };
         cpu::intr_on();
 
         let return_a0 = match a7 {
+            1 => self.sys_fork(),
+            2 => self.sys_exit(),
+            3 => self.sys_wait(),
+            4 => self.sys_pipe(),
             7 => self.sys_exec(),
+            5 => self.sys_read(),
+            6 => self.sys_kill(),
+            8 => self.sys_fstat(),
+            9 => self.sys_chdir(),
+            10 => self.sys_dup(),
+            11 => self.sys_getpid(),
+            12 => self.sys_sbrk(),
+            13 => self.sys_sleep(),
+            14 => self.sys_uptime(),
+            15 => self.sys_open(),
+            16 => self.sys_write(),
+            17 => self.sys_mknod(),
+            18 => self.sys_unlink(),
+            19 => self.sys_link(),
+            20 => self.sys_mkdir(),
+            21 => self.sys_close(),
+            22 => self.sys_dmesg(),
+            23 => self.sys_kfault(),
+            24 => self.sys_prof(),
+            25 => self.sys_ftrace(),
+            26 => self.sys_schedtrace(),
+            27 => self.sys_hvguest(),
+            28 => self.sys_setitimer(),
+            29 => self.sys_sigreturn(),
+            30 => self.sys_getuid(),
+            31 => self.sys_geteuid(),
+            32 => self.sys_setuid(),
+            33 => self.sys_getgid(),
+            34 => self.sys_getegid(),
+            35 => self.sys_setgid(),
+            36 => self.sys_chroot(),
+            37 => self.sys_ptrace(),
+            38 => self.sys_proclog(),
+            39 => self.sys_madvise(),
+            40 => self.sys_getrandom(),
+            41 => self.sys_hartctl(),
+            42 => self.sys_suspend(),
+            43 => self.sys_sched_setscheduler(),
+            44 => self.sys_sched_yield(),
+            45 => self.sys_cpustat(),
+            46 => self.sys_sched_setquantum(),
+            47 => self.sys_mmap(),
+            48 => self.sys_munmap(),
+            49 => self.sys_socket(),
+            50 => self.sys_bind(),
+            51 => self.sys_listen(),
+            52 => self.sys_connect(),
+            53 => self.sys_accept(),
+            54 => self.sys_mq_open(),
+            55 => self.sys_mq_send(),
+            56 => self.sys_mq_receive(),
+            57 => self.sys_mq_unlink(),
+            58 => self.sys_eventfd(),
+            59 => self.sys_timerfd_create(),
+            60 => self.sys_timerfd_settime(),
+            61 => self.sys_msync(),
+            62 => self.sys_fcntl(),
+            63 => self.sys_getrlimit(),
+            64 => self.sys_setrlimit(),
+            65 => self.sys_ioctl(),
+            66 => self.sys_copy_file_range(),
+            67 => self.sys_checkpoint(),
+            68 => self.sys_restore(),
             _ => {
-                panic!("unknown syscall");
+                println!("pid {} {}: unknown syscall {}", self.pid, self.name(), a7);
+                usize::MAX
             }
         };
         let tf = unsafe { &mut *self.tf };
         tf.set_a0(return_a0);
+        self.trace_syscall(ptrace::STOP_SYSCALL_EXIT);
     }
 
-    /// Atomically release lock and sleep on chan.
-    /// Reacquires lock when awakened.
-    pub fn sleep<'a, T>(
-        &mut self,
-        chan: usize,
-        lk: &'a SpinLock<T>,
-        guard: SpinLockGuard<'a, T>,
-    ) -> SpinLockGuard<'a, T> {
+    /// Sleep until tick deadline, or until killed, see sleepq.rs.
+    /// Return whether it slept through.
+    pub fn sleep_until(&mut self, deadline: usize) -> Result<(), &'static str> {
+        let index = super::index_of(self);
+        let mut q = SLEEPQ.lock();
+        if deadline > trap::ticks() {
+            q.push(Sleeper { deadline, index });
+        }
+        // also woken up by kill
+        while q.contains(index) {
+            if self.killed {
+                q.remove(index);
+                return Err("killed");
+            }
+            q = self.sleep(super::sleep_chan(index), q);
+        }
+        Ok(())
+    }
+
+    /// Atomically release the lock of guard and sleep on chan.
+    /// Reacquires the lock when awakened, and returns its new guard.
+    /// No wakeup is lost in between, a waker holds the same lock to change the condition,
+    /// and wakeup() takes p.lock, which is held from before the release until sched().
+    pub fn sleep<'a, T>(&mut self, chan: usize, guard: SpinLockGuard<'a, T>) -> SpinLockGuard<'a, T> {
+        ftrace!();
+        let lk = SpinLockGuard::spin_lock(&guard);
+        // lk is the only spinlock the caller may hold
+        #[cfg(debug_assertions)]
+        super::assert_only_holding(lk.addr(), "sleep");
+
         let same_lock: bool = ptr::eq(
             lk as *const _ as *const T,
             &self.lock as *const _ as *const T,
@@ -197,6 +642,7 @@ impl Proc {
 
             self.chan = chan;
             self.state = ProcState::SLEEPING;
+            schedtrace::record(schedtrace::SCHED_BLOCK, self.pid, 0);
             unsafe {
                 let c = my_cpu();
                 c.sched();
@@ -210,6 +656,7 @@ impl Proc {
         else {
             self.chan = chan;
             self.state = ProcState::SLEEPING;
+            schedtrace::record(schedtrace::SCHED_BLOCK, self.pid, 0);
             unsafe {
                 let c = my_cpu();
                 c.sched();
@@ -220,11 +667,12 @@ impl Proc {
     }
 }
 
-/// from xv6-riscv:
-/// first user program that calls exec("/init")
-static INITCODE: [u8; 51] = [
-    0x17, 0x05, 0x00, 0x00, 0x13, 0x05, 0x05, 0x02, 0x97, 0x05, 0x00, 0x00, 0x93, 0x85, 0x05, 0x02,
-    0x9d, 0x48, 0x73, 0x00, 0x00, 0x00, 0x89, 0x48, 0x73, 0x00, 0x00, 0x00, 0xef, 0xf0, 0xbf, 0xff,
-    0x2f, 0x69, 0x6e, 0x69, 0x74, 0x00, 0x00, 0x01, 0x20, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00,
-    0x00, 0x00, 0x00,
-];
+/// Free the user memory below sz of a process's page table, and the table's own pages,
+/// the trampoline and trapframe are only unmapped
+pub fn free_pagetable(pagetable: &mut PageTable, sz: usize) {
+    pagetable.unmap_pages(VirtAddr::from(TRAMPOLINE), 1, false)
+        .expect("free_pagetable: trampoline");
+    pagetable.unmap_pages(VirtAddr::from(TRAPFRAME), 1, false)
+        .expect("free_pagetable: trapframe");
+    pagetable.uvm_free(sz);
+}
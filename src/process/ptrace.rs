@@ -0,0 +1,391 @@
+//! Tracing of one process by another, see sys_ptrace in syscall.rs
+//!
+//! A tracer attaches to a process it may debug, being root or its user,
+//! which then stops at its next trap into the kernel.
+//! While it is stopped, the tracer reads and writes its memory, text included,
+//! and its registers, and resumes it with one of:
+//! - PTRACE_CONT, until it runs into an ebreak, e.g., a breakpoint of the tracer
+//! - PTRACE_SYSCALL, also stopping at the entry and the exit of each syscall
+//! - PTRACE_SINGLESTEP, stopping after one instruction,
+//!   there being no step flag for user mode, temporary breakpoints
+//!   are put at every pc it may continue at, see insn.rs
+//!
+//! PTRACE_WAIT sleeps until it stopped and returns why, one of the STOP_ reasons.
+//! PTRACE_WATCH sets a hardware watchpoint at addr, on the stores or loads of kind data,
+//! it stops with STOP_WATCH before the access, once, see watch.rs.
+//!
+//! A tracee stops in the kernel, sleeping in stop() until it is resumed.
+//! TRACE protects the tracing state of every process,
+//! and keeps the wakeups in between them from being lost,
+//! it is always acquired before any p.lock.
+
+use core::arch::asm;
+use core::mem;
+use core::ptr;
+use core::slice;
+
+use crate::insn;
+use crate::mm::PageTable;
+use crate::watch;
+use crate::spinlock::{SpinLock, SpinLockGuard};
+
+use super::{Proc, ProcState, PROC_MANAGER};
+
+pub const PTRACE_ATTACH: usize = 0;
+pub const PTRACE_DETACH: usize = 1;
+pub const PTRACE_PEEK: usize = 2;
+pub const PTRACE_POKE: usize = 3;
+pub const PTRACE_GETREGS: usize = 4;
+pub const PTRACE_SETREGS: usize = 5;
+pub const PTRACE_CONT: usize = 6;
+pub const PTRACE_SYSCALL: usize = 7;
+pub const PTRACE_SINGLESTEP: usize = 8;
+pub const PTRACE_WAIT: usize = 9;
+pub const PTRACE_WATCH: usize = 10;
+
+pub const STOP_ATTACH: usize = 1;
+pub const STOP_BREAKPOINT: usize = 2;
+pub const STOP_STEP: usize = 3;
+pub const STOP_SYSCALL_ENTER: usize = 4;
+pub const STOP_SYSCALL_EXIT: usize = 5;
+pub const STOP_WATCH: usize = 6;
+
+static TRACE: SpinLock<()> = SpinLock::new((), "trace");
+
+/// A temporary breakpoint of a single step
+#[derive(Clone, Copy)]
+struct Step {
+    addr: usize,
+    // original instruction bytes, len is 0 for an empty slot
+    orig: [u8; 4],
+    len: usize,
+}
+
+impl Step {
+    const fn empty() -> Self {
+        Self { addr: 0, orig: [0; 4], len: 0 }
+    }
+}
+
+pub struct Trace {
+    /// null while not traced
+    tracer: *mut Proc,
+    /// why it is stopped, 0 while it runs
+    stop: usize,
+    /// stop at the next trap, just attached
+    pending: bool,
+    /// stop at the entry and the exit of syscalls
+    syscalls: bool,
+    steps: [Step; 2],
+    /// its text was written, it stays set,
+    /// the process may later run on a hart whose cache has the old instructions
+    poked: bool,
+    /// the address of its watchpoint, 0 if none, and what it fires on
+    watch: usize,
+    watch_kind: usize,
+}
+
+impl Trace {
+    pub const fn new() -> Self {
+        Self {
+            tracer: ptr::null_mut(),
+            stop: 0,
+            pending: false,
+            syscalls: false,
+            steps: [Step::empty(); 2],
+            poked: false,
+            watch: 0,
+            watch_kind: 0,
+        }
+    }
+}
+
+/// The tracee sleeps here while stopped
+fn stop_chan(p: &Proc) -> usize {
+    &p.trace.stop as *const usize as usize
+}
+
+/// The tracer sleeps here in PTRACE_WAIT
+fn wait_chan(p: &Proc) -> usize {
+    &p.trace.tracer as *const *mut Proc as usize
+}
+
+/// The process with pid, holding its p.lock only to find it
+fn find(pid: usize) -> Option<&'static mut Proc> {
+    for p in unsafe { PROC_MANAGER.table.iter_mut() } {
+        let guard = p.lock.lock();
+        let found = p.pid == pid && p.state != ProcState::UNUSED;
+        drop(guard);
+        if found {
+            return Some(p);
+        }
+    }
+    None
+}
+
+fn as_bytes(regs: &[usize; 32]) -> &[u8] {
+    unsafe { slice::from_raw_parts(regs.as_ptr() as *const u8, mem::size_of_val(regs)) }
+}
+
+fn as_bytes_mut(regs: &mut [usize; 32]) -> &mut [u8] {
+    unsafe { slice::from_raw_parts_mut(regs.as_mut_ptr() as *mut u8, mem::size_of_val(regs)) }
+}
+
+/// The instruction at pc in user memory, only as many bytes as it has
+fn read_inst(pagetable: &PageTable, pc: usize) -> Result<u32, &'static str> {
+    let mut bytes = [0u8; 4];
+    pagetable.copy_in(pc, &mut bytes[..2])?;
+    if insn::inst_len(bytes[0] as u32) == 4 {
+        pagetable.copy_in(pc + 2, &mut bytes[2..])?;
+    }
+    Ok(u32::from_le_bytes(bytes))
+}
+
+impl Proc {
+    /// Handle request req of ptrace on the process with pid, see syscall.rs
+    pub fn ptrace(&mut self, req: usize, pid: usize, addr: usize, data: usize)
+        -> Result<usize, &'static str>
+    {
+        let me = self as *mut Proc;
+        let mut guard = TRACE.lock();
+        let mut p = find(pid).ok_or("no such process")?;
+
+        if req == PTRACE_ATTACH {
+            if ptr::eq(p, self) {
+                return Err("cannot trace itself");
+            }
+            if p.pagetable.is_none() {
+                return Err("not a user process");
+            }
+            if !p.trace.tracer.is_null() {
+                return Err("already traced");
+            }
+            if !self.cred.is_root() && (p.cred.uid != self.cred.euid || p.cred.euid != self.cred.euid) {
+                return Err("permission denied");
+            }
+            p.trace.tracer = me;
+            p.trace.pending = true;
+            return Ok(0);
+        }
+
+        if p.trace.tracer != me {
+            return Err("not traced by the caller");
+        }
+        if req == PTRACE_WAIT {
+            while p.trace.stop == 0 {
+                if self.killed || p.killed || p.trace.tracer != me {
+                    return Err("tracee died");
+                }
+                guard = self.sleep(wait_chan(p), guard);
+                // the tracee changes under TRACE while asleep, look it up again
+                p = find(pid).ok_or("tracee died")?;
+            }
+            return Ok(p.trace.stop);
+        }
+        if p.trace.stop == 0 {
+            return Err("tracee not stopped");
+        }
+
+        // a stopped tracee only goes on once resumed,
+        // so its memory and registers can be used while TRACE is held
+        let mine = self.pagetable.as_ref().unwrap();
+        let theirs = p.pagetable.as_ref().unwrap();
+        let tf = unsafe { &mut *p.tf };
+        match req {
+            PTRACE_PEEK => {
+                let mut word = [0u8; mem::size_of::<usize>()];
+                theirs.copy_in(addr, &mut word)?;
+                mine.copy_out(data, &word)?;
+            }
+            PTRACE_POKE => {
+                theirs.copy_out_text(addr, &data.to_ne_bytes())?;
+                p.trace.poked = true;
+            }
+            PTRACE_WATCH => {
+                if watch::triggers() == 0 {
+                    return Err("no hardware triggers");
+                }
+                if addr != 0 && (data == 0 || data & !(watch::WATCH_STORE | watch::WATCH_LOAD) != 0) {
+                    return Err("bad watchpoint");
+                }
+                p.trace.watch = addr;
+                p.trace.watch_kind = data;
+            }
+            PTRACE_GETREGS => mine.copy_out(data, as_bytes(&tf.user_regs()))?,
+            PTRACE_SETREGS => {
+                let mut regs = [0usize; 32];
+                mine.copy_in(data, as_bytes_mut(&mut regs))?;
+                tf.set_user_regs(&regs);
+            }
+            PTRACE_CONT | PTRACE_SYSCALL | PTRACE_SINGLESTEP | PTRACE_DETACH => {
+                if req == PTRACE_SINGLESTEP {
+                    p.place_steps()?;
+                }
+                if req == PTRACE_DETACH {
+                    p.trace.tracer = ptr::null_mut();
+                    p.trace.pending = false;
+                    p.trace.watch = 0;
+                }
+                p.trace.syscalls = req == PTRACE_SYSCALL;
+                p.trace.stop = 0;
+                unsafe { PROC_MANAGER.wakeup(stop_chan(p)); }
+            }
+            _ => return Err("bad request"),
+        }
+        drop(guard);
+        Ok(0)
+    }
+
+    /// Stop for the tracer, if traced, until it resumes the process.
+    /// TRACE must be held, as the only lock.
+    fn stop(&mut self, reason: usize, mut guard: SpinLockGuard<'static, ()>) {
+        if self.trace.tracer.is_null() {
+            return;
+        }
+        self.remove_steps();
+        self.trace.pending = false;
+        self.trace.stop = reason;
+        let tracer = wait_chan(self);
+        unsafe { PROC_MANAGER.wakeup(tracer); }
+        while self.trace.stop != 0 {
+            if self.killed {
+                // the tracer's PTRACE_WAIT gives up on it
+                self.trace.stop = 0;
+                unsafe { PROC_MANAGER.wakeup(tracer); }
+                break;
+            }
+            guard = self.sleep(stop_chan(self), guard);
+        }
+        drop(guard);
+    }
+
+    /// Stop just after being attached, called at the end of every user trap.
+    pub fn trace_trap(&mut self) {
+        // tracer is only read here, stop() checks it again under TRACE
+        if self.trace.tracer.is_null() {
+            return;
+        }
+        let guard = TRACE.lock();
+        if self.trace.pending {
+            self.stop(STOP_ATTACH, guard);
+        }
+    }
+
+    /// Stop at the entry or the exit of a syscall, if the tracer asked to.
+    pub fn trace_syscall(&mut self, reason: usize) {
+        if self.trace.tracer.is_null() {
+            return;
+        }
+        let guard = TRACE.lock();
+        if self.trace.syscalls {
+            self.stop(reason, guard);
+        }
+    }
+
+    /// Stop at an ebreak in user mode,
+    /// return false if not traced, for it to be killed instead.
+    pub fn trace_breakpoint(&mut self) -> bool {
+        let guard = TRACE.lock();
+        if self.trace.tracer.is_null() {
+            return false;
+        }
+        let epc = unsafe { (*self.tf).epc };
+        let stepped = self.trace.steps.iter().any(|step| step.len != 0 && step.addr == epc);
+        let watched = self.trace.watch != 0 && !stepped
+            && read_inst(self.pagetable.as_ref().unwrap(), epc).is_ok_and(watch::is_user_hit);
+        let reason = match (stepped, watched) {
+            (true, _) => STOP_STEP,
+            (false, true) => {
+                self.trace.watch = 0;
+                STOP_WATCH
+            }
+            (false, false) => STOP_BREAKPOINT,
+        };
+        self.stop(reason, guard);
+        true
+    }
+
+    /// Set this hart's user trigger to the watchpoint, if any,
+    /// called on the way back to user space.
+    pub fn trace_watch(&self) {
+        watch::switch_user(self.trace.watch, self.trace.watch_kind);
+    }
+
+    /// Detach from the tracer, waking it in PTRACE_WAIT, and detach the tracees,
+    /// which go on running, called by exit().
+    pub fn trace_exit(&mut self) {
+        let me = self as *mut Proc;
+        let guard = TRACE.lock();
+        if !self.trace.tracer.is_null() {
+            self.trace.tracer = ptr::null_mut();
+            unsafe { PROC_MANAGER.wakeup(wait_chan(self)); }
+        }
+        for p in unsafe { PROC_MANAGER.table.iter_mut() } {
+            if p.trace.tracer == me {
+                p.remove_steps();
+                p.trace.tracer = ptr::null_mut();
+                p.trace.pending = false;
+                p.trace.syscalls = false;
+                p.trace.watch = 0;
+                p.trace.stop = 0;
+                unsafe { PROC_MANAGER.wakeup(stop_chan(p)); }
+            }
+        }
+        drop(guard);
+    }
+
+    /// Make the instructions the tracer wrote visible to this hart,
+    /// called on the way back to user space.
+    pub fn trace_fence(&self) {
+        if self.trace.poked {
+            unsafe { asm!("fence.i", options(nostack)); }
+        }
+    }
+
+    /// Put temporary breakpoints at the next pcs, TRACE must be held
+    fn place_steps(&mut self) -> Result<(), &'static str> {
+        let pagetable = self.pagetable.as_ref().unwrap();
+        let read_inst = |pc: usize| read_inst(pagetable, pc);
+
+        // x0 is not saved, the pc is there instead
+        let regs = unsafe { (*self.tf).user_regs() };
+        let pc = regs[0];
+        let inst = read_inst(pc)?;
+        let reg = |n: usize| if n == 0 { 0 } else { regs[n] };
+        let mut placed = 0;
+        for next in insn::next_pcs(reg, pc, inst).iter().flatten() {
+            if self.trace.steps[..placed].iter().any(|step| step.addr == *next) {
+                continue;
+            }
+            let len = match read_inst(*next) {
+                Ok(inst) => insn::inst_len(inst),
+                Err(_) => continue,
+            };
+            let mut step = Step { addr: *next, orig: [0; 4], len };
+            step.orig[..len].copy_from_slice(&read_inst(*next)?.to_le_bytes()[..len]);
+            match len {
+                2 => pagetable.copy_out_text(*next, &insn::C_EBREAK.to_le_bytes())?,
+                _ => pagetable.copy_out_text(*next, &insn::EBREAK.to_le_bytes())?,
+            }
+            self.trace.steps[placed] = step;
+            placed += 1;
+        }
+        self.trace.poked = true;
+        match placed {
+            0 => Err("cannot step there"),
+            _ => Ok(()),
+        }
+    }
+
+    /// Put back the instructions under the temporary breakpoints
+    fn remove_steps(&mut self) {
+        let pagetable = match self.pagetable.as_ref() {
+            Some(pagetable) => pagetable,
+            None => return,
+        };
+        for step in self.trace.steps.iter_mut().filter(|step| step.len != 0) {
+            let _ = pagetable.copy_out_text(step.addr, &step.orig[..step.len]);
+            step.len = 0;
+        }
+    }
+}
@@ -0,0 +1,89 @@
+//! The real-time scheduling class, see sys_sched_setscheduler in syscall.rs
+//!
+//! A SCHED_FIFO process has a priority from 1 to RT_PRIO_MAX,
+//! and is always taken from a run queue before the SCHED_OTHER ones, see runq.rs,
+//! the highest priority first, and among equal ones the one that ran the longest ago,
+//! so sched_yield lets the others of its priority run.
+//! A tick does not take the hart away from it, see tick_yields() in cpu.rs,
+//! it runs until it sleeps, yields, exits, or a higher priority one is runnable.
+//! A real-time process woken up waits at most a tick for a hart running a lower one.
+//!
+//! Each process also has a quantum, the ticks it runs before a tick takes its hart,
+//! set with sched_setquantum, see syscall.rs, or else its class's default, see quantum():
+//! DEFAULT_QUANTUM for SCHED_OTHER, and none for SCHED_FIFO, which then shares its hart
+//! with the others of its priority, a slice each, like Linux's SCHED_RR.
+//!
+//! LTODO - throttling, a real-time process spinning keeps its hart forever,
+//!     the monitor on Ctrl-T still comes in, being an interrupt.
+
+use core::sync::atomic::{AtomicUsize, Ordering};
+
+/// Policies, as Linux's
+pub const SCHED_OTHER: usize = 0;
+pub const SCHED_FIFO: usize = 1;
+
+pub const RT_PRIO_MAX: usize = 99;
+
+/// Ticks of a SCHED_OTHER process's slice, unless it sets its own
+pub const DEFAULT_QUANTUM: usize = 1;
+pub const QUANTUM_MAX: usize = 100;
+
+/// Bumped for each process switched in, stamped on it as its last_run
+static RUN_SEQ: AtomicUsize = AtomicUsize::new(0);
+
+pub fn next_run() -> usize {
+    RUN_SEQ.fetch_add(1, Ordering::Relaxed)
+}
+
+/// The real-time priority for policy and priority, 0 for SCHED_OTHER
+pub fn rt_prio(policy: usize, priority: usize) -> Result<u8, &'static str> {
+    match policy {
+        SCHED_OTHER if priority == 0 => Ok(0),
+        SCHED_FIFO if (1..=RT_PRIO_MAX).contains(&priority) => Ok(priority as u8),
+        SCHED_OTHER | SCHED_FIFO => Err("priority out of range"),
+        _ => Err("unknown policy"),
+    }
+}
+
+/// The ticks of a slice of a process of rt_prio with its quantum set to quantum,
+/// 0 for the class default, and 0 back for no limit
+pub fn quantum(rt_prio: u8, quantum: usize) -> usize {
+    match (rt_prio, quantum) {
+        (_, quantum) if quantum > 0 => quantum,
+        (0, _) => DEFAULT_QUANTUM,
+        _ => 0,
+    }
+}
+
+/// Whether a runnable process of (rt_prio, last_run) goes before one of other
+pub fn before(a: (u8, usize), other: (u8, usize)) -> bool {
+    a.0 > other.0 || (a.0 == other.0 && a.1 < other.1)
+}
+
+#[cfg(feature = "unit_test")]
+pub mod tests {
+    use super::*;
+
+    pub fn rt_order() {
+        assert_eq!(rt_prio(SCHED_OTHER, 0), Ok(0));
+        assert_eq!(rt_prio(SCHED_FIFO, RT_PRIO_MAX), Ok(RT_PRIO_MAX as u8));
+        assert!(rt_prio(SCHED_OTHER, 1).is_err());
+        assert!(rt_prio(SCHED_FIFO, 0).is_err());
+        assert!(rt_prio(SCHED_FIFO, RT_PRIO_MAX + 1).is_err());
+        assert!(rt_prio(2, 1).is_err());
+
+        assert!(before((2, 10), (1, 0)));
+        assert!(before((1, 0), (1, 10)));
+        assert!(!before((1, 10), (1, 10)));
+        assert!(!before((1, 0), (2, 10)));
+    }
+    crate::kernel_test!(rt_order);
+
+    pub fn quanta() {
+        assert_eq!(quantum(0, 0), DEFAULT_QUANTUM);
+        assert_eq!(quantum(0, 5), 5);
+        assert_eq!(quantum(1, 0), 0);
+        assert_eq!(quantum(RT_PRIO_MAX as u8, 3), 3);
+    }
+    crate::kernel_test!(quanta);
+}
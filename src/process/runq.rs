@@ -0,0 +1,163 @@
+//! Per-hart run queues
+//!
+//! A RUNNABLE process is in exactly one hart's queue, by its index in the process table,
+//! put there under its p->lock by whoever made it runnable, see make_runnable() in mod.rs:
+//! one giving up its hart goes back to the queue of that hart,
+//! one woken up to the queue of an idle hart, which is kicked, or else of the waker's hart.
+//! A hart's scheduler takes from its own queue first, and once that is empty
+//! steals from the longest other queue, see ProcManager::alloc_runnable().
+//! A hart going offline hands its queue to the boot hart, see park() in cpu.rs.
+//!
+//! Only the queue lock is held to take an entry, p->lock is acquired after it is released,
+//! so the order stays p->lock, then a queue lock.
+//!
+//! Real-time processes are taken first from a queue, see rt.rs,
+//! with the priority they had when queued.
+//! LTODO - balancing them across harts, a higher priority one queued on a busy hart
+//!     waits for that hart's next tick even if another hart runs a lower one.
+
+use core::sync::atomic::{AtomicUsize, Ordering};
+
+use crate::consts::{NCPU, NPROC};
+use crate::spinlock::SpinLock;
+
+use super::rt;
+
+#[derive(Clone, Copy)]
+pub struct Entry {
+    /// index in the process table
+    pub index: usize,
+    pub rt_prio: u8,
+    pub last_run: usize,
+}
+
+impl Entry {
+    const fn empty() -> Self {
+        Self { index: 0, rt_prio: 0, last_run: 0 }
+    }
+}
+
+/// Oldest first, a process is only ever in one queue, so NPROC entries are enough
+pub struct RunQueue {
+    entries: [Entry; NPROC],
+    len: usize,
+}
+
+impl RunQueue {
+    pub const fn new() -> Self {
+        Self {
+            entries: [Entry::empty(); NPROC],
+            len: 0,
+        }
+    }
+
+    pub fn push(&mut self, entry: Entry) {
+        assert!(self.len < NPROC, "runq: full");
+        self.entries[self.len] = entry;
+        self.len += 1;
+    }
+
+    /// The real-time entry to run first, or else the oldest one
+    pub fn pop(&mut self) -> Option<Entry> {
+        let entries = &self.entries[..self.len];
+        let mut pos = entries.first().map(|_| 0)?;
+        for (i, e) in entries.iter().enumerate().skip(1) {
+            let best = &entries[pos];
+            if e.rt_prio > 0 && rt::before((e.rt_prio, e.last_run), (best.rt_prio, best.last_run)) {
+                pos = i;
+            }
+        }
+        let entry = self.entries[pos];
+        self.entries.copy_within(pos + 1..self.len, pos);
+        self.len -= 1;
+        Some(entry)
+    }
+
+    /// Whether a real-time entry has a priority above prio
+    pub fn has_above(&self, prio: u8) -> bool {
+        self.entries[..self.len].iter().any(|e| e.rt_prio > prio)
+    }
+
+    pub fn len(&self) -> usize {
+        self.len
+    }
+}
+
+static RUNQS: [SpinLock<RunQueue>; NCPU] = [const { SpinLock::new(RunQueue::new(), "runq") }; NCPU];
+
+/// queue lengths, read without the locks, by stealers and the monitor
+static LENS: [AtomicUsize; NCPU] = [const { AtomicUsize::new(0) }; NCPU];
+
+pub fn push(hart: usize, entry: Entry) {
+    let mut q = RUNQS[hart].lock();
+    q.push(entry);
+    LENS[hart].store(q.len(), Ordering::Relaxed);
+}
+
+pub fn pop(hart: usize) -> Option<Entry> {
+    let mut q = RUNQS[hart].lock();
+    let entry = q.pop();
+    LENS[hart].store(q.len(), Ordering::Relaxed);
+    entry
+}
+
+/// Take an entry from the longest queue of another hart
+pub fn steal(thief: usize) -> Option<Entry> {
+    let victim = (0..NCPU)
+        .filter(|hart| *hart != thief)
+        .max_by_key(|hart| LENS[*hart].load(Ordering::Relaxed))?;
+    if LENS[victim].load(Ordering::Relaxed) == 0 {
+        return None;
+    }
+    pop(victim)
+}
+
+/// Move every entry of hart's queue to to's
+pub fn hand_over(hart: usize, to: usize) {
+    while let Some(entry) = pop(hart) {
+        push(to, entry);
+    }
+}
+
+pub fn len(hart: usize) -> usize {
+    LENS[hart].load(Ordering::Relaxed)
+}
+
+/// Whether any queue has an entry
+pub fn any() -> bool {
+    LENS.iter().any(|len| len.load(Ordering::Relaxed) != 0)
+}
+
+pub fn has_above(hart: usize, prio: u8) -> bool {
+    RUNQS[hart].lock().has_above(prio)
+}
+
+#[cfg(feature = "unit_test")]
+pub mod tests {
+    use super::*;
+
+    fn entry(index: usize, rt_prio: u8, last_run: usize) -> Entry {
+        Entry { index, rt_prio, last_run }
+    }
+
+    /// Oldest first, but the real-time entries before, the highest priority first
+    pub fn runq_order() {
+        let mut q = RunQueue::new();
+        assert!(q.pop().is_none());
+        q.push(entry(0, 0, 5));
+        q.push(entry(1, 0, 1));
+        q.push(entry(2, 3, 9));
+        q.push(entry(3, 7, 9));
+        q.push(entry(4, 7, 2));
+        assert!(q.has_above(6));
+        assert!(!q.has_above(7));
+
+        let order: [usize; 5] = [4, 3, 2, 0, 1];
+        for index in order.iter() {
+            assert_eq!(q.pop().map(|e| e.index), Some(*index));
+        }
+        assert_eq!(q.len(), 0);
+        assert!(q.pop().is_none());
+    }
+    crate::kernel_test!(runq_order);
+}
@@ -0,0 +1,73 @@
+//! Boot self-test checks of the process module, see selftest.rs
+
+use core::mem;
+
+use crate::consts::PGSIZE;
+use crate::mm::{kalloc, kfree};
+
+use super::{context, trapframe, Context, TrapFrame};
+
+/// Compare actual field offsets with what the assembly expects
+fn check_layout(actual: &[(&'static str, usize)], expected: &[usize]) -> Result<(), &'static str> {
+    for ((name, off), want) in actual.iter().zip(expected.iter()) {
+        if off != want {
+            println!("  {} at {}, expected {}", name, off, want);
+            return Err("field offset mismatch");
+        }
+    }
+    Ok(())
+}
+
+/// TrapFrame as trampoline.S uses it
+pub fn trapframe_layout() -> Result<(), &'static str> {
+    check_layout(&trapframe::layout(), &[0, 8, 16, 24, 32, 40, 112, 280])?;
+    if mem::size_of::<TrapFrame>() != 288 {
+        return Err("TrapFrame size is not 288");
+    }
+    Ok(())
+}
+
+/// Context as swtch.S uses it
+pub fn context_layout() -> Result<(), &'static str> {
+    check_layout(&context::layout(), &[0, 8, 16, 104])?;
+    if mem::size_of::<Context>() != 112 {
+        return Err("Context size is not 112");
+    }
+    Ok(())
+}
+
+extern "C" {
+    fn swtch(old: *mut Context, new: *mut Context);
+}
+
+static mut MAIN: Context = Context::new();
+static mut SIDE: Context = Context::new();
+static mut VISITS: usize = 0;
+
+/// Runs on its own stack, switching back every time it is switched to
+unsafe fn side() -> ! {
+    loop {
+        VISITS += 1;
+        swtch(&mut SIDE, &mut MAIN);
+    }
+}
+
+/// Switch to a context on another stack and back a few times
+pub fn swtch_smoke() -> Result<(), &'static str> {
+    let stack = unsafe { kalloc() }.ok_or("no page for the stack")?;
+    unsafe {
+        VISITS = 0;
+        SIDE.clear();
+        SIDE.set_ra(side as *const () as usize);
+        SIDE.set_sp(stack as usize + PGSIZE);
+        for i in 1..=3 {
+            swtch(&mut MAIN, &mut SIDE);
+            if VISITS != i {
+                kfree(stack);
+                return Err("side context did not run");
+            }
+        }
+        kfree(stack);
+    }
+    Ok(())
+}
@@ -0,0 +1,146 @@
+//! Timed sleeps, a min-heap of (deadline, process) popped by the clock interrupt
+//!
+//! A process sleeping until a tick, see Proc::sleep_until(), puts itself in the heap
+//! and sleeps on its own channel, so the clock interrupt on the boot hart, see clock_intr() in trap.rs,
+//! wakes exactly the ones due, instead of all of them re-checking the time at every tick.
+//! The heap lock is held from pushing until the process is SLEEPING, and while popping and waking,
+//! so no wakeup is lost.
+//! A process is in the heap at most once, it takes itself out if woken up by kill first,
+//! so NPROC entries are enough.
+
+use crate::consts::NPROC;
+use crate::spinlock::SpinLock;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord)]
+pub struct Sleeper {
+    pub deadline: usize,
+    /// index in the process table
+    pub index: usize,
+}
+
+pub struct SleepQueue {
+    heap: [Sleeper; NPROC],
+    len: usize,
+}
+
+impl SleepQueue {
+    pub const fn new() -> Self {
+        Self {
+            heap: [Sleeper { deadline: 0, index: 0 }; NPROC],
+            len: 0,
+        }
+    }
+
+    pub fn push(&mut self, sleeper: Sleeper) {
+        assert!(self.len < NPROC, "sleepq: full");
+        self.heap[self.len] = sleeper;
+        self.len += 1;
+        self.sift_up(self.len - 1);
+    }
+
+    pub fn peek(&self) -> Option<Sleeper> {
+        self.heap[..self.len].first().copied()
+    }
+
+    /// Take the earliest sleeper, if it is due at now
+    pub fn pop_due(&mut self, now: usize) -> Option<Sleeper> {
+        match self.peek() {
+            Some(first) if first.deadline <= now => {
+                self.remove_at(0);
+                Some(first)
+            }
+            _ => None,
+        }
+    }
+
+    /// Take out the process at index, return whether it was in
+    pub fn remove(&mut self, index: usize) -> bool {
+        match self.heap[..self.len].iter().position(|s| s.index == index) {
+            Some(pos) => {
+                self.remove_at(pos);
+                true
+            }
+            None => false,
+        }
+    }
+
+    pub fn contains(&self, index: usize) -> bool {
+        self.heap[..self.len].iter().any(|s| s.index == index)
+    }
+
+    fn remove_at(&mut self, pos: usize) {
+        self.len -= 1;
+        if pos == self.len {
+            return;
+        }
+        self.heap[pos] = self.heap[self.len];
+        self.sift_down(pos);
+        self.sift_up(pos);
+    }
+
+    fn sift_up(&mut self, mut pos: usize) {
+        while pos > 0 {
+            let parent = (pos - 1) / 2;
+            if self.heap[parent] <= self.heap[pos] {
+                break;
+            }
+            self.heap.swap(parent, pos);
+            pos = parent;
+        }
+    }
+
+    fn sift_down(&mut self, mut pos: usize) {
+        loop {
+            let mut least = pos;
+            for child in [2 * pos + 1, 2 * pos + 2].iter() {
+                if *child < self.len && self.heap[*child] < self.heap[least] {
+                    least = *child;
+                }
+            }
+            if least == pos {
+                break;
+            }
+            self.heap.swap(least, pos);
+            pos = least;
+        }
+    }
+}
+
+pub static SLEEPQ: SpinLock<SleepQueue> = SpinLock::new(SleepQueue::new(), "sleepq");
+
+/// The earliest deadline, for an idle boot hart, see idle() in cpu.rs
+pub fn next_deadline() -> Option<usize> {
+    SLEEPQ.lock().peek().map(|s| s.deadline)
+}
+
+#[cfg(feature = "unit_test")]
+pub mod tests {
+    use super::*;
+
+    fn sleeper(deadline: usize, index: usize) -> Sleeper {
+        Sleeper { deadline, index }
+    }
+
+    /// Popped in deadline order, and only once due
+    pub fn sleepq_order() {
+        let mut q = SleepQueue::new();
+        for (deadline, index) in [(30, 0), (10, 1), (50, 2), (20, 3), (40, 4), (10, 5)].iter() {
+            q.push(sleeper(*deadline, *index));
+        }
+        assert_eq!(q.peek(), Some(sleeper(10, 1)));
+        assert!(q.remove(3));
+        assert!(!q.remove(3));
+        assert!(q.contains(4));
+
+        assert_eq!(q.pop_due(9), None);
+        assert_eq!(q.pop_due(10), Some(sleeper(10, 1)));
+        assert_eq!(q.pop_due(10), Some(sleeper(10, 5)));
+        assert_eq!(q.pop_due(29), None);
+        let order: [usize; 3] = [0, 4, 2];
+        for index in order.iter() {
+            assert_eq!(q.pop_due(usize::MAX).map(|s| s.index), Some(*index));
+        }
+        assert_eq!(q.peek(), None);
+    }
+    crate::kernel_test!(sleepq_order);
+}
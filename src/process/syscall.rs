@@ -1,49 +1,1479 @@
-use core::{mem, ptr, str};
+use core::cmp::min;
+use core::mem;
 
-use crate::consts::{MAXPATH, MAXARG};
+use crate::consts::{MAXPATH, MAXARG, MQ_MSGSIZE, NCPU, NPROF_SITE, PGSIZE};
+use crate::cpustat::{self, CpuStat};
+use crate::dtb;
+use crate::eventfd;
+use crate::fs::{self, File, Stat, O_NONBLOCK, O_RDONLY, O_RDWR, O_WRONLY};
+use crate::mm::{Box, PageAligned};
+use crate::mqueue;
+use crate::printf;
+use crate::proclog;
+use crate::profile::{self, ProfEntry};
+use crate::random;
+use crate::schedtrace::{self, SchedEvent};
+use crate::socket::{self, SOCK_NONBLOCK};
+use crate::suspend;
+use crate::timerfd;
+use crate::trap;
 
+use super::coredump::Checkpoint;
+use super::{elf, my_cpu, rt, PROC_MANAGER};
 use super::proc::Proc;
 
+/// A page holding exec's argument strings
+struct ArgPage([u8; PGSIZE]);
+
+impl PageAligned for ArgPage {}
+
+/// The aggregated profile, too big for the kernel stack,
+/// NPROF_SITE entries must fit in a page
+struct ProfPage([ProfEntry; NPROF_SITE]);
+
+impl PageAligned for ProfPage {}
+
+const PROF_START: usize = 0;
+const PROF_STOP: usize = 1;
+const PROF_DUMP: usize = 2;
+
+/// A chunk of scheduler events on their way to user space
+struct SchedPage([SchedEvent; PGSIZE / mem::size_of::<SchedEvent>()]);
+
+impl PageAligned for SchedPage {}
+
+const SCHEDTRACE_START: usize = 0;
+const SCHEDTRACE_STOP: usize = 1;
+const SCHEDTRACE_DUMP: usize = 2;
+
+const FTRACE_OFF: usize = 0;
+const FTRACE_ON: usize = 1;
+const FTRACE_DUMP: usize = 2;
+
 pub trait Syscall {
+    fn sys_fork(&mut self) -> usize;
+    fn sys_exit(&mut self) -> usize;
+    fn sys_wait(&mut self) -> usize;
+    fn sys_pipe(&mut self) -> usize;
+    fn sys_read(&mut self) -> usize;
+    fn sys_kill(&mut self) -> usize;
     fn sys_exec(&mut self) -> usize;
+    fn sys_fstat(&mut self) -> usize;
+    fn sys_chdir(&mut self) -> usize;
+    fn sys_dup(&mut self) -> usize;
+    fn sys_getpid(&mut self) -> usize;
+    fn sys_sbrk(&mut self) -> usize;
+    fn sys_sleep(&mut self) -> usize;
+    fn sys_uptime(&mut self) -> usize;
+    fn sys_open(&mut self) -> usize;
+    fn sys_write(&mut self) -> usize;
+    fn sys_mknod(&mut self) -> usize;
+    fn sys_unlink(&mut self) -> usize;
+    fn sys_link(&mut self) -> usize;
+    fn sys_mkdir(&mut self) -> usize;
+    fn sys_close(&mut self) -> usize;
+    fn sys_dmesg(&mut self) -> usize;
+    fn sys_kfault(&mut self) -> usize;
+    fn sys_prof(&mut self) -> usize;
+    fn sys_ftrace(&mut self) -> usize;
+    fn sys_schedtrace(&mut self) -> usize;
+    fn sys_hvguest(&mut self) -> usize;
+    fn sys_setitimer(&mut self) -> usize;
+    fn sys_sigreturn(&mut self) -> usize;
+    fn sys_getuid(&mut self) -> usize;
+    fn sys_geteuid(&mut self) -> usize;
+    fn sys_setuid(&mut self) -> usize;
+    fn sys_getgid(&mut self) -> usize;
+    fn sys_getegid(&mut self) -> usize;
+    fn sys_setgid(&mut self) -> usize;
+    fn sys_chroot(&mut self) -> usize;
+    fn sys_ptrace(&mut self) -> usize;
+    fn sys_proclog(&mut self) -> usize;
+    fn sys_madvise(&mut self) -> usize;
+    fn sys_getrandom(&mut self) -> usize;
+    fn sys_hartctl(&mut self) -> usize;
+    fn sys_suspend(&mut self) -> usize;
+    fn sys_sched_setscheduler(&mut self) -> usize;
+    fn sys_sched_yield(&mut self) -> usize;
+    fn sys_cpustat(&mut self) -> usize;
+    fn sys_sched_setquantum(&mut self) -> usize;
+    fn sys_mmap(&mut self) -> usize;
+    fn sys_munmap(&mut self) -> usize;
+    fn sys_socket(&mut self) -> usize;
+    fn sys_bind(&mut self) -> usize;
+    fn sys_listen(&mut self) -> usize;
+    fn sys_connect(&mut self) -> usize;
+    fn sys_accept(&mut self) -> usize;
+    fn sys_mq_open(&mut self) -> usize;
+    fn sys_mq_send(&mut self) -> usize;
+    fn sys_mq_receive(&mut self) -> usize;
+    fn sys_mq_unlink(&mut self) -> usize;
+    fn sys_eventfd(&mut self) -> usize;
+    fn sys_timerfd_create(&mut self) -> usize;
+    fn sys_timerfd_settime(&mut self) -> usize;
+    fn sys_msync(&mut self) -> usize;
+    fn sys_fcntl(&mut self) -> usize;
+    fn sys_getrlimit(&mut self) -> usize;
+    fn sys_setrlimit(&mut self) -> usize;
+    fn sys_ioctl(&mut self) -> usize;
+    fn sys_copy_file_range(&mut self) -> usize;
+    fn sys_checkpoint(&mut self) -> usize;
+    fn sys_restore(&mut self) -> usize;
 }
 
+/// madvise advice
+pub const MADV_NORMAL: usize = 0;
+pub const MADV_DONTNEED: usize = 4;
+
+/// fcntl commands, and the descriptor flag F_GETFD and F_SETFD take,
+/// F_GETFL and F_SETFL take the O_* of the file instead
+pub const F_DUPFD: usize = 0;
+pub const F_GETFD: usize = 1;
+pub const F_SETFD: usize = 2;
+pub const F_GETFL: usize = 3;
+pub const F_SETFL: usize = 4;
+pub const FD_CLOEXEC: usize = 1;
+
+/// getrlimit and setrlimit resources
+pub const RLIMIT_NOFILE: usize = 7;
+
+/// The largest argument of an ioctl request, see sys_ioctl
+const IOCTL_ARG: usize = 64;
+
 impl Syscall for Proc {
+    /// Create a child, a copy of the process, see ProcManager::fork,
+    /// return its pid, 0 in the child
+    fn sys_fork(&mut self) -> usize {
+        match unsafe { PROC_MANAGER.fork(self) } {
+            Ok(pid) => pid,
+            Err(str) => {
+                println!("sys_fork: {}", str);
+                usize::MAX
+            }
+        }
+    }
+
+    /// Exit with the status in a0, e.g., main's return value
+    fn sys_exit(&mut self) -> usize {
+        let status = self.arg_int(0);
+        self.exit(status as isize)
+    }
+
+    /// Wait for a child to exit, copy its exit status to a0 unless it is 0,
+    /// and return its pid
+    fn sys_wait(&mut self) -> usize {
+        let addr = match self.arg_raw(0) {
+            0 => 0,
+            _ => match self.arg_addr(0, mem::size_of::<i32>()) {
+                Ok(addr) => addr,
+                Err(str) => {
+                    println!("sys_wait: {}", str);
+                    return usize::MAX;
+                }
+            },
+        };
+        let (pid, status) = match unsafe { PROC_MANAGER.wait(self) } {
+            Some(child) => child,
+            None => return usize::MAX,
+        };
+        if addr != 0 {
+            if let Err(str) = self.copy_out(addr, &status.to_ne_bytes()) {
+                println!("sys_wait: {}", str);
+                return usize::MAX;
+            }
+        }
+        pid
+    }
+
+    /// Make a pipe, and copy the descriptors of its read and write ends to the two ints at a0
+    fn sys_pipe(&mut self) -> usize {
+        let addr = match self.arg_addr(0, 2 * mem::size_of::<i32>()) {
+            Ok(addr) => addr,
+            Err(str) => {
+                println!("sys_pipe: {}", str);
+                return usize::MAX;
+            }
+        };
+        let (rf, wf) = match fs::pipealloc() {
+            Ok(ends) => ends,
+            Err(str) => {
+                println!("sys_pipe: {}", str);
+                return usize::MAX;
+            }
+        };
+        let fd0 = self.fdalloc(rf);
+        let fd1 = self.fdalloc(wf);
+        let copied = fd0.and_then(|fd0| {
+            let fd1 = fd1?;
+            let mut fds = [0u8; 8];
+            fds[..4].copy_from_slice(&(fd0 as i32).to_ne_bytes());
+            fds[4..].copy_from_slice(&(fd1 as i32).to_ne_bytes());
+            self.copy_out(addr, &fds)
+        });
+        match copied {
+            Ok(()) => 0,
+            Err(str) => {
+                println!("sys_pipe: {}", str);
+                for (fd, f) in [(fd0, rf), (fd1, wf)].iter() {
+                    if let Ok(fd) = fd {
+                        self.ofile[*fd] = None;
+                    }
+                    fs::fileclose(f);
+                }
+                usize::MAX
+            }
+        }
+    }
+
+    /// Read at most a2 bytes from descriptor a0 into user buf a1,
+    /// return the bytes read, fewer once there is no more for now, 0 at the end of the file
+    fn sys_read(&mut self) -> usize {
+        let n = self.arg_raw(2);
+        let (f, addr) = match self.arg_fd(0).and_then(|(_, f)| Ok((f, self.arg_addr(1, n)?))) {
+            Ok(args) => args,
+            Err(str) => {
+                println!("sys_read: {}", str);
+                return usize::MAX;
+            }
+        };
+
+        let mut chunk: [u8; 512] = [0; 512];
+        let mut copied: usize = 0;
+        while copied < n {
+            let want = min(chunk.len(), n - copied);
+            let count = match fs::fileread(f, &mut chunk[..want]) {
+                Ok(count) => count,
+                Err(_) if copied > 0 => break,
+                Err(str) => {
+                    println!("sys_read: {}", str);
+                    return usize::MAX;
+                }
+            };
+            if let Err(str) = self.copy_out(addr + copied, &chunk[..count]) {
+                println!("sys_read: {}", str);
+                return usize::MAX;
+            }
+            copied += count;
+            if count < want || !f.is_inode() {
+                break;
+            }
+        }
+        copied
+    }
+
+    /// Kill process a0, it exits with -1 the next time it is in the kernel,
+    /// see Cred::check_kill for whom it may kill
+    fn sys_kill(&mut self) -> usize {
+        let pid = self.arg_raw(0);
+        let cred = self.cred;
+        match unsafe { PROC_MANAGER.kill(pid, self.pid, Some(&cred)) } {
+            Ok(()) => 0,
+            Err(str) => {
+                println!("sys_kill: {}", str);
+                usize::MAX
+            }
+        }
+    }
+
+    /// Replace the process's program with the one at path.
+    /// The argument strings are fetched into a kernel page first,
+    /// in case the old user memory is gone when the new stack is built.
     fn sys_exec(&mut self) -> usize {
-        let mut path: [u8; MAXPATH] = unsafe {
-            mem::MaybeUninit::uninit().assume_init()
-        };
-        match self.arg_str(0, &mut path) {
-            Ok(_) => {
-                // debug
-                print!("sys_exec: ");
-                for c in path.iter() {
-                    if *c == 0 {
-                        break
+        let mut path: [u8; MAXPATH] = [0; MAXPATH];
+        if let Err(str) = self.arg_str(0, &mut path) {
+            println!("sys_exec: {}", str);
+            return usize::MAX;
+        }
+
+        let mut page = match Box::<ArgPage>::new() {
+            Some(page) => page,
+            None => {
+                println!("sys_exec: out of memory");
+                return usize::MAX;
+            }
+        };
+        let mut ranges: [(usize, usize); MAXARG] = [(0, 0); MAXARG];
+        let argc = match self.fetch_args(self.arg_raw(1), &mut page.0, &mut ranges) {
+            Ok(argc) => argc,
+            Err(str) => {
+                println!("sys_exec: {}", str);
+                return usize::MAX;
+            }
+        };
+        let mut argv: [&[u8]; MAXARG] = [&[]; MAXARG];
+        for i in 0..argc {
+            argv[i] = &page.0[ranges[i].0..ranges[i].1];
+        }
+
+        match elf::exec(self, &path, &argv[..argc]) {
+            Ok(argc) => {
+                proclog::exec(self.pid, self.ppid(), self.name());
+                argc
+            }
+            Err(str) => {
+                println!("sys_exec: {}", str);
+                usize::MAX
+            }
+        }
+    }
+
+    /// Copy the Stat of descriptor a0's file to user a1
+    fn sys_fstat(&mut self) -> usize {
+        let args = self.arg_fd(0).and_then(|(_, f)| Ok((f, self.arg_addr(1, mem::size_of::<Stat>())?)));
+        let stat = args.and_then(|(f, addr)| Ok((fs::filestat(f)?, addr)));
+        let copied = stat.and_then(|(stat, addr)| {
+            let bytes = unsafe {
+                core::slice::from_raw_parts(&stat as *const Stat as *const u8, mem::size_of::<Stat>())
+            };
+            self.copy_out(addr, bytes)
+        });
+        match copied {
+            Ok(()) => 0,
+            Err(str) => {
+                println!("sys_fstat: {}", str);
+                usize::MAX
+            }
+        }
+    }
+
+    /// Make the directory at path a0 the working directory, where relative paths start
+    fn sys_chdir(&mut self) -> usize {
+        let mut path: [u8; MAXPATH] = [0; MAXPATH];
+        if let Err(str) = self.arg_str(0, &mut path) {
+            println!("sys_chdir: {}", str);
+            return usize::MAX;
+        }
+        let op = fs::begin_op();
+        let ip = match fs::namei(&op, &path) {
+            Ok(ip) => ip,
+            Err(str) => {
+                println!("sys_chdir: {}", str);
+                return usize::MAX;
+            }
+        };
+        if !fs::ilock(ip).is_dir() {
+            println!("sys_chdir: not a directory");
+            fs::iput(&op, ip);
+            return usize::MAX;
+        }
+        if let Some(old) = self.cwd.replace(ip) {
+            fs::iput(&op, old);
+        }
+        0
+    }
+
+    /// A new descriptor for the file of descriptor a0, the lowest free one
+    fn sys_dup(&mut self) -> usize {
+        let f = match self.arg_fd(0) {
+            Ok((_, f)) => fs::filedup(f),
+            Err(str) => {
+                println!("sys_dup: {}", str);
+                return usize::MAX;
+            }
+        };
+        match self.fdalloc(f) {
+            Ok(fd) => fd,
+            Err(str) => {
+                println!("sys_dup: {}", str);
+                fs::fileclose(f);
+                usize::MAX
+            }
+        }
+    }
+
+    fn sys_getpid(&mut self) -> usize {
+        self.pid
+    }
+
+    /// Grow the process's memory by a0 bytes, or shrink it if a0 is negative,
+    /// and return where it ended before, the start of what was added
+    fn sys_sbrk(&mut self) -> usize {
+        let n = self.arg_int(0);
+        match self.grow(n as isize) {
+            Ok(oldsz) => oldsz,
+            Err(str) => {
+                println!("sys_sbrk: {}", str);
+                usize::MAX
+            }
+        }
+    }
+
+    /// Sleep for a0 clock ticks, see sleep_until(), fail if killed meanwhile
+    fn sys_sleep(&mut self) -> usize {
+        let n = self.arg_int(0);
+        let deadline = trap::ticks() + n.max(0) as usize;
+        match self.sleep_until(deadline) {
+            Ok(()) => 0,
+            Err(_) => usize::MAX,
+        }
+    }
+
+    /// Clock ticks since boot
+    fn sys_uptime(&mut self) -> usize {
+        trap::ticks()
+    }
+
+    /// Open the file at path a0 in mode a1, see fs/file.rs, and return its descriptor
+    fn sys_open(&mut self) -> usize {
+        let mut path: [u8; MAXPATH] = [0; MAXPATH];
+        if let Err(str) = self.arg_str(0, &mut path) {
+            println!("sys_open: {}", str);
+            return usize::MAX;
+        }
+        let f = match fs::fileopen(&path, self.arg_raw(1)) {
+            Ok(f) => f,
+            Err(str) => {
+                println!("sys_open: {}", str);
+                return usize::MAX;
+            }
+        };
+        match self.fdalloc(f) {
+            Ok(fd) => fd,
+            Err(str) => {
+                println!("sys_open: {}", str);
+                fs::fileclose(f);
+                usize::MAX
+            }
+        }
+    }
+
+    /// Write a2 bytes from user buf a1 to descriptor a0, return the bytes written
+    fn sys_write(&mut self) -> usize {
+        let n = self.arg_raw(2);
+        let (f, addr) = match self.arg_fd(0).and_then(|(_, f)| Ok((f, self.arg_addr(1, n)?))) {
+            Ok(args) => args,
+            Err(str) => {
+                println!("sys_write: {}", str);
+                return usize::MAX;
+            }
+        };
+
+        let mut chunk: [u8; 512] = [0; 512];
+        let mut written: usize = 0;
+        while written < n {
+            let want = min(chunk.len(), n - written);
+            if let Err(str) = self.copy_in(addr + written, &mut chunk[..want]) {
+                println!("sys_write: {}", str);
+                return usize::MAX;
+            }
+            let count = match fs::filewrite(f, &chunk[..want]) {
+                Ok(count) => count,
+                Err(_) if written > 0 => break,
+                Err(str) => {
+                    println!("sys_write: {}", str);
+                    return usize::MAX;
+                }
+            };
+            written += count;
+            if count < want {
+                break;
+            }
+        }
+        written
+    }
+
+    /// Make a device file at path a0, with major number a1 and minor number a2,
+    /// or a named pipe, for the inode type a3, T_DEVICE or T_FIFO
+    fn sys_mknod(&mut self) -> usize {
+        let mut path: [u8; MAXPATH] = [0; MAXPATH];
+        if let Err(str) = self.arg_str(0, &mut path) {
+            println!("sys_mknod: {}", str);
+            return usize::MAX;
+        }
+        let (major, minor) = (self.arg_int(1), self.arg_int(2));
+        if !(0..=u16::MAX as i32).contains(&major) || !(0..=u16::MAX as i32).contains(&minor) {
+            println!("sys_mknod: bad device number");
+            return usize::MAX;
+        }
+        let made = match self.arg_raw(3) as u16 {
+            fs::T_DEVICE => fs::mknod(&path, major as u16, minor as u16),
+            fs::T_FIFO => fs::mkfifo(&path),
+            _ => Err("neither a device nor a named pipe"),
+        };
+        match made {
+            Ok(()) => 0,
+            Err(str) => {
+                println!("sys_mknod: {}", str);
+                usize::MAX
+            }
+        }
+    }
+
+    /// Take the name at path a0 off its directory, see fs/dir.rs
+    fn sys_unlink(&mut self) -> usize {
+        let mut path: [u8; MAXPATH] = [0; MAXPATH];
+        match self.arg_str(0, &mut path).and_then(|()| fs::unlink(&path)) {
+            Ok(()) => 0,
+            Err(str) => {
+                println!("sys_unlink: {}", str);
+                usize::MAX
+            }
+        }
+    }
+
+    /// Make path a1 another name of the file at path a0
+    fn sys_link(&mut self) -> usize {
+        let mut old: [u8; MAXPATH] = [0; MAXPATH];
+        let mut new: [u8; MAXPATH] = [0; MAXPATH];
+        let linked = self.arg_str(0, &mut old)
+            .and_then(|()| self.arg_str(1, &mut new))
+            .and_then(|()| fs::link(&old, &new));
+        match linked {
+            Ok(()) => 0,
+            Err(str) => {
+                println!("sys_link: {}", str);
+                usize::MAX
+            }
+        }
+    }
+
+    /// Make an empty directory at path a0
+    fn sys_mkdir(&mut self) -> usize {
+        let mut path: [u8; MAXPATH] = [0; MAXPATH];
+        match self.arg_str(0, &mut path).and_then(|()| fs::mkdir(&path)) {
+            Ok(()) => 0,
+            Err(str) => {
+                println!("sys_mkdir: {}", str);
+                usize::MAX
+            }
+        }
+    }
+
+    /// Close descriptor a0
+    fn sys_close(&mut self) -> usize {
+        match self.arg_fd(0) {
+            Ok((fd, f)) => {
+                self.ofile[fd] = None;
+                fs::fileclose(f);
+                0
+            }
+            Err(str) => {
+                println!("sys_close: {}", str);
+                usize::MAX
+            }
+        }
+    }
+
+    /// Copy at most n bytes of buffered kernel messages to user buf,
+    /// oldest first. Return the number of bytes copied.
+    fn sys_dmesg(&mut self) -> usize {
+        let addr = self.arg_raw(0);
+        let n = self.arg_raw(1);
+
+        let mut chunk: [u8; 128] = [0; 128];
+        let mut seq: usize = 0;
+        let mut copied: usize = 0;
+        while copied < n {
+            let want = min(chunk.len(), n - copied);
+            let (count, next) = printf::kmsg_read(seq, &mut chunk[..want]);
+            if count == 0 {
+                break;
+            }
+            if let Err(str) = self.copy_out(addr + copied, &chunk[..count]) {
+                println!("sys_dmesg: {}", str);
+                return usize::MAX;
+            }
+            seq = next;
+            copied += count;
+        }
+        copied
+    }
+
+    /// Switch allocation fault injection, see mm/fault.rs, only for root.
+    /// Return the number of faults injected under the previous mode,
+    /// fail if the kernel was built without the fault_inject feature.
+    fn sys_kfault(&mut self) -> usize {
+        if let Err(str) = self.cred.check_root() {
+            println!("sys_kfault: {}", str);
+            return usize::MAX;
+        }
+        #[cfg(feature = "fault_inject")]
+        {
+            let mode = self.arg_raw(0);
+            let arg = self.arg_raw(1);
+            match crate::mm::fault::set(mode, arg) {
+                Ok(injected) => injected,
+                Err(str) => {
+                    println!("sys_kfault: {}", str);
+                    usize::MAX
+                }
+            }
+        }
+
+        #[cfg(not(feature = "fault_inject"))]
+        usize::MAX
+    }
+
+    /// Control the sampling profiler, see profile.rs, only for root.
+    /// a0 is PROF_START, PROF_STOP, or PROF_DUMP,
+    /// which copies up to a2 ProfEntry to a1,
+    /// and returns the number copied.
+    fn sys_prof(&mut self) -> usize {
+        if let Err(str) = self.cred.check_root() {
+            println!("sys_prof: {}", str);
+            return usize::MAX;
+        }
+        match self.arg_raw(0) {
+            PROF_START => {
+                profile::start();
+                0
+            }
+            PROF_STOP => {
+                profile::stop();
+                0
+            }
+            PROF_DUMP => {
+                let addr = self.arg_raw(1);
+                let max = self.arg_raw(2);
+                let mut entries = match Box::<ProfPage>::new() {
+                    Some(page) => page,
+                    None => {
+                        println!("sys_prof: out of memory");
+                        return usize::MAX;
+                    }
+                };
+                let n = min(profile::dump(&mut entries.0), max);
+                let bytes = unsafe {
+                    core::slice::from_raw_parts(
+                        entries.0.as_ptr() as *const u8,
+                        n * mem::size_of::<ProfEntry>(),
+                    )
+                };
+                if let Err(str) = self.copy_out(addr, bytes) {
+                    println!("sys_prof: {}", str);
+                    return usize::MAX;
+                }
+                n
+            }
+            _ => usize::MAX,
+        }
+    }
+
+    /// Control function-entry tracing, see ftrace.rs, only for root.
+    /// a0 is FTRACE_OFF, FTRACE_ON, or FTRACE_DUMP,
+    /// which prints the trace on the console.
+    /// Fail if the kernel was built without the ftrace feature.
+    fn sys_ftrace(&mut self) -> usize {
+        if let Err(str) = self.cred.check_root() {
+            println!("sys_ftrace: {}", str);
+            return usize::MAX;
+        }
+        #[cfg(feature = "ftrace")]
+        match self.arg_raw(0) {
+            FTRACE_OFF => {
+                crate::ftrace::set_enabled(false);
+                0
+            }
+            FTRACE_ON => {
+                crate::ftrace::set_enabled(true);
+                0
+            }
+            FTRACE_DUMP => {
+                crate::ftrace::dump();
+                0
+            }
+            _ => usize::MAX,
+        }
+
+        #[cfg(not(feature = "ftrace"))]
+        usize::MAX
+    }
+
+    /// Control scheduler event tracing, see schedtrace.rs, only for root.
+    /// a0 is SCHEDTRACE_START, SCHEDTRACE_STOP, or SCHEDTRACE_DUMP,
+    /// which copies up to a2 SchedEvent to a1, oldest first,
+    /// and returns the number copied.
+    fn sys_schedtrace(&mut self) -> usize {
+        if let Err(str) = self.cred.check_root() {
+            println!("sys_schedtrace: {}", str);
+            return usize::MAX;
+        }
+        match self.arg_raw(0) {
+            SCHEDTRACE_START => {
+                schedtrace::start();
+                0
+            }
+            SCHEDTRACE_STOP => {
+                schedtrace::stop();
+                0
+            }
+            SCHEDTRACE_DUMP => {
+                let addr = self.arg_raw(1);
+                let max = self.arg_raw(2);
+                let mut chunk = match Box::<SchedPage>::new() {
+                    Some(page) => page,
+                    None => {
+                        println!("sys_schedtrace: out of memory");
+                        return usize::MAX;
+                    }
+                };
+                let mut cursor = schedtrace::Cursor::new();
+                let mut copied = 0;
+                while copied < max {
+                    let want = min(chunk.0.len(), max - copied);
+                    let n = cursor.fill(&mut chunk.0[..want]);
+                    if n == 0 {
+                        break;
                     }
-                    print!("{}", *c as char);
+                    let bytes = unsafe {
+                        core::slice::from_raw_parts(
+                            chunk.0.as_ptr() as *const u8,
+                            n * mem::size_of::<SchedEvent>(),
+                        )
+                    };
+                    let dst = addr + copied * mem::size_of::<SchedEvent>();
+                    if let Err(str) = self.copy_out(dst, bytes) {
+                        println!("sys_schedtrace: {}", str);
+                        return usize::MAX;
+                    }
+                    copied += n;
+                }
+                copied
+            }
+            _ => usize::MAX,
+        }
+    }
+
+    /// Run the kernel's built-in guest in virtual supervisor mode,
+    /// see hyp.rs, and return the reason it shut down with, only for root.
+    /// Fail if the kernel was built without the hypervisor feature,
+    /// or the harts lack the H extension.
+    fn sys_hvguest(&mut self) -> usize {
+        if let Err(str) = self.cred.check_root() {
+            println!("sys_hvguest: {}", str);
+            return usize::MAX;
+        }
+        #[cfg(feature = "hypervisor")]
+        match crate::hyp::run() {
+            Ok(reason) => reason,
+            Err(str) => {
+                println!("sys_hvguest: {}", str);
+                usize::MAX
+            }
+        }
+
+        #[cfg(not(feature = "hypervisor"))]
+        usize::MAX
+    }
+
+    /// Arm interval timer a0, ITIMER_REAL or ITIMER_VIRTUAL, see itimer.rs,
+    /// to call the user handler a3 in a1 ticks, and every a2 ticks after that,
+    /// or disarm it if a1 is 0.
+    fn sys_setitimer(&mut self) -> usize {
+        let which = self.arg_raw(0);
+        let value = self.arg_raw(1);
+        let interval = self.arg_raw(2);
+        let handler = self.arg_raw(3);
+        match self.set_itimer(which, value, interval, handler) {
+            Ok(()) => 0,
+            Err(str) => {
+                println!("sys_setitimer: {}", str);
+                usize::MAX
+            }
+        }
+    }
+
+    /// Return from an interval timer's handler to the code it interrupted
+    fn sys_sigreturn(&mut self) -> usize {
+        match self.itimer_return() {
+            Ok(a0) => a0,
+            Err(str) => {
+                println!("sys_sigreturn: {}", str);
+                usize::MAX
+            }
+        }
+    }
+
+    /// The real user id, see cred.rs
+    fn sys_getuid(&mut self) -> usize {
+        self.cred.uid as usize
+    }
+
+    fn sys_geteuid(&mut self) -> usize {
+        self.cred.euid as usize
+    }
+
+    /// Set the user id to a0, only the effective one unless root
+    fn sys_setuid(&mut self) -> usize {
+        let uid = self.arg_raw(0) as u32;
+        match self.cred.set_uid(uid) {
+            Ok(()) => 0,
+            Err(str) => {
+                println!("sys_setuid: {}", str);
+                usize::MAX
+            }
+        }
+    }
+
+    fn sys_getgid(&mut self) -> usize {
+        self.cred.gid as usize
+    }
+
+    fn sys_getegid(&mut self) -> usize {
+        self.cred.egid as usize
+    }
+
+    /// Set the group id to a0, only the effective one unless root
+    fn sys_setgid(&mut self) -> usize {
+        let gid = self.arg_raw(0) as u32;
+        match self.cred.set_gid(gid) {
+            Ok(()) => 0,
+            Err(str) => {
+                println!("sys_setgid: {}", str);
+                usize::MAX
+            }
+        }
+    }
+
+    /// Make the directory at path a0 the root of absolute paths, only for root.
+    /// The path must be absolute itself, the old root is put.
+    fn sys_chroot(&mut self) -> usize {
+        if let Err(str) = self.cred.check_root() {
+            println!("sys_chroot: {}", str);
+            return usize::MAX;
+        }
+        let mut path: [u8; MAXPATH] = [0; MAXPATH];
+        if let Err(str) = self.arg_str(0, &mut path) {
+            println!("sys_chroot: {}", str);
+            return usize::MAX;
+        }
+        if path[0] != b'/' {
+            println!("sys_chroot: not an absolute path");
+            return usize::MAX;
+        }
+        let op = fs::begin_op();
+        let ip = match fs::namei(&op, &path) {
+            Ok(ip) => ip,
+            Err(str) => {
+                println!("sys_chroot: {}", str);
+                return usize::MAX;
+            }
+        };
+        if !fs::ilock(ip).is_dir() {
+            println!("sys_chroot: not a directory");
+            fs::iput(&op, ip);
+            return usize::MAX;
+        }
+        if let Some(old) = self.root.replace(ip) {
+            fs::iput(&op, old);
+        }
+        0
+    }
+
+    /// Trace process a1 with request a0, see ptrace.rs,
+    /// a2 is an address in the tracee, a3 a word or an address in the caller.
+    fn sys_ptrace(&mut self) -> usize {
+        let req = self.arg_raw(0);
+        let pid = self.arg_raw(1);
+        let addr = self.arg_raw(2);
+        let data = self.arg_raw(3);
+        match self.ptrace(req, pid, addr, data) {
+            Ok(ret) => ret,
+            Err(str) => {
+                println!("sys_ptrace: {}", str);
+                usize::MAX
+            }
+        }
+    }
+
+    /// Turn the process event log on if a0 is 1, off if it is 0,
+    /// see proclog.rs, only for root.
+    fn sys_proclog(&mut self) -> usize {
+        if let Err(str) = self.cred.check_root() {
+            println!("sys_proclog: {}", str);
+            return usize::MAX;
+        }
+        match self.arg_raw(0) {
+            0 => proclog::set_enabled(false),
+            1 => proclog::set_enabled(true),
+            _ => return usize::MAX,
+        }
+        0
+    }
+
+    /// Advise on the use of a1 bytes at a0, with a2 as MADV_*.
+    /// MADV_DONTNEED frees the writable pages in the range,
+    /// they come back zeroed on the next touch, sz is not changed.
+    /// Return how many pages were freed.
+    /// LTODO - a tracer's PTRACE_PEEK still fails on a freed page,
+    ///     as on a page of the heap not touched yet, only the process's own syscalls fault them in.
+    fn sys_madvise(&mut self) -> usize {
+        let addr = self.arg_raw(0);
+        let len = self.arg_raw(1);
+        match self.arg_raw(2) {
+            MADV_NORMAL => 0,
+            MADV_DONTNEED => match self.dontneed(addr, len) {
+                Ok(n) => n,
+                Err(str) => {
+                    println!("sys_madvise: {}", str);
+                    usize::MAX
                 }
-                println!();
+            },
+            _ => {
+                println!("sys_madvise: unknown advice");
+                usize::MAX
             }
+        }
+    }
+
+    /// Fill a1 bytes at a0 with random bytes, a2 is GRND_*, see random.rs.
+    /// Wait until the generator is seeded, unless a2 says not to.
+    /// Return how many bytes were filled.
+    fn sys_getrandom(&mut self) -> usize {
+        let addr = self.arg_raw(0);
+        let n = self.arg_raw(1);
+        let flags = self.arg_raw(2);
+
+        let mut chunk: [u8; 256] = [0; 256];
+        let mut copied: usize = 0;
+        while copied < n {
+            let want = min(chunk.len(), n - copied);
+            let result = random::getrandom(&mut chunk[..want], flags).and_then(|count| {
+                self.copy_out(addr + copied, &chunk[..count])
+                    .map(|()| count)
+            });
+            match result {
+                Ok(count) => copied += count,
+                Err(str) => {
+                    println!("sys_getrandom: {}", str);
+                    return usize::MAX;
+                }
+            }
+        }
+        copied
+    }
+
+    /// Take hart a0 off scheduling if a1 is 0, or bring it back if it is 1,
+    /// see park() in cpu.rs, only for root.
+    /// Going offline takes effect at the hart's next tick.
+    fn sys_hartctl(&mut self) -> usize {
+        if let Err(str) = self.cred.check_root() {
+            println!("sys_hartctl: {}", str);
+            return usize::MAX;
+        }
+        let hart = self.arg_raw(0);
+        let online = match self.arg_raw(1) {
+            0 => false,
+            1 => true,
+            _ => return usize::MAX,
+        };
+        match super::set_online(hart, online) {
+            Ok(()) => 0,
             Err(str) => {
-                println!("sys_exec1: {}", str);
+                println!("sys_hartctl: {}", str);
+                usize::MAX
+            }
+        }
+    }
+
+    /// Suspend the system until console input, or until a0 ticks from now if it is not 0,
+    /// see suspend.rs, only for root.
+    /// Return WAKE_CONSOLE or WAKE_ALARM.
+    fn sys_suspend(&mut self) -> usize {
+        if let Err(str) = self.cred.check_root() {
+            println!("sys_suspend: {}", str);
+            return usize::MAX;
+        }
+        match suspend::suspend(self.arg_raw(0)) {
+            Ok(wake) => wake,
+            Err(str) => {
+                println!("sys_suspend: {}", str);
+                usize::MAX
+            }
+        }
+    }
+
+    /// Set the scheduling policy of process a0, 0 for itself,
+    /// to a1, SCHED_OTHER or SCHED_FIFO, with priority a2, see rt.rs.
+    /// Only root makes a process real-time, or changes another one.
+    fn sys_sched_setscheduler(&mut self) -> usize {
+        let pid = match self.arg_raw(0) {
+            0 => self.pid,
+            pid => pid,
+        };
+        let prio = match rt::rt_prio(self.arg_raw(1), self.arg_raw(2)) {
+            Ok(prio) => prio,
+            Err(str) => {
+                println!("sys_sched_setscheduler: {}", str);
+                return usize::MAX;
+            }
+        };
+        if prio > 0 || pid != self.pid {
+            if let Err(str) = self.cred.check_root() {
+                println!("sys_sched_setscheduler: {}", str);
+                return usize::MAX;
+            }
+        }
+        match unsafe { PROC_MANAGER.set_rt_prio(pid, prio) } {
+            true => 0,
+            false => usize::MAX,
+        }
+    }
+
+    /// Give up the hart, a real-time process to the others of its priority
+    fn sys_sched_yield(&mut self) -> usize {
+        unsafe { my_cpu() }.yielding();
+        0
+    }
+
+    /// Copy the times of up to a1 harts into the CpuStat array at a0, see cpustat.rs,
+    /// return how many were copied
+    fn sys_cpustat(&mut self) -> usize {
+        let addr = self.arg_raw(0);
+        let max = self.arg_raw(1);
+        let harts = (0..NCPU).filter(|hart| dtb::harts() & (1 << hart) != 0);
+        for (i, hart) in harts.take(max).enumerate() {
+            let stat = cpustat::read(hart);
+            let bytes = unsafe {
+                core::slice::from_raw_parts(&stat as *const CpuStat as *const u8, mem::size_of::<CpuStat>())
+            };
+            let dst = addr + i * mem::size_of::<CpuStat>();
+            if let Err(str) = self.copy_out(dst, bytes) {
+                println!("sys_cpustat: {}", str);
+                return usize::MAX;
+            }
+        }
+        min(max, dtb::nharts())
+    }
+
+    /// Set the quantum of process a0, 0 for itself, to a1 ticks, 0 for its class's default, see rt.rs.
+    /// Only root lengthens it past DEFAULT_QUANTUM, or changes another process's.
+    fn sys_sched_setquantum(&mut self) -> usize {
+        let pid = match self.arg_raw(0) {
+            0 => self.pid,
+            pid => pid,
+        };
+        let quantum = self.arg_raw(1);
+        if quantum > rt::QUANTUM_MAX {
+            println!("sys_sched_setquantum: quantum out of range");
+            return usize::MAX;
+        }
+        if quantum > rt::DEFAULT_QUANTUM || pid != self.pid {
+            if let Err(str) = self.cred.check_root() {
+                println!("sys_sched_setquantum: {}", str);
                 return usize::MAX;
             }
         }
+        match unsafe { PROC_MANAGER.set_quantum(pid, quantum) } {
+            true => 0,
+            false => usize::MAX,
+        }
+    }
+
+    /// Map a1 bytes of the file of descriptor a4, from offset a5, into the process's memory,
+    /// a2 is PROT_*, a3 one of MAP_SHARED and MAP_PRIVATE, see vma.rs,
+    /// a0 would be where, it is ignored.
+    /// Return where it is mapped.
+    fn sys_mmap(&mut self) -> usize {
+        let (len, prot, flags, off) = (self.arg_raw(1), self.arg_raw(2), self.arg_raw(3), self.arg_raw(5));
+        match self.arg_fd(4).and_then(|(_, f)| self.mmap(len, prot, flags, f, off)) {
+            Ok(addr) => addr,
+            Err(str) => {
+                println!("sys_mmap: {}", str);
+                usize::MAX
+            }
+        }
+    }
+
+    /// Unmap the a1 bytes at a0 of a mapping, off either of its ends, writing back what is shared
+    fn sys_munmap(&mut self) -> usize {
+        let (addr, len) = (self.arg_raw(0), self.arg_raw(1));
+        match self.munmap(addr, len) {
+            Ok(()) => 0,
+            Err(str) => {
+                println!("sys_munmap: {}", str);
+                usize::MAX
+            }
+        }
+    }
+
+    /// A new local socket, a0 is SOCK_NONBLOCK or 0, see socket.rs, return its descriptor
+    fn sys_socket(&mut self) -> usize {
+        let nonblock = self.arg_raw(0) & SOCK_NONBLOCK != 0;
+        let installed = socket::socket().and_then(|id| {
+            fs::sockalloc(id).inspect_err(|_| socket::close(id).unwrap())
+        }).and_then(|f| {
+            f.set_nonblock(nonblock);
+            self.fdinstall(f)
+        });
+        match installed {
+            Ok(fd) => fd,
+            Err(str) => {
+                println!("sys_socket: {}", str);
+                usize::MAX
+            }
+        }
+    }
+
+    /// Name the socket of descriptor a0 by path a1
+    fn sys_bind(&mut self) -> usize {
+        let mut path: [u8; MAXPATH] = [0; MAXPATH];
+        let bound = self.arg_fd(0).and_then(|(_, f)| f.socket())
+            .and_then(|id| Ok((id, self.arg_path(1, &mut path)?)))
+            .and_then(|(id, path)| socket::bind(id, path));
+        match bound {
+            Ok(()) => 0,
+            Err(str) => {
+                println!("sys_bind: {}", str);
+                usize::MAX
+            }
+        }
+    }
+
+    /// Take connections to the path the socket of descriptor a0 is bound to
+    fn sys_listen(&mut self) -> usize {
+        match self.arg_fd(0).and_then(|(_, f)| f.socket()).and_then(socket::listen) {
+            Ok(()) => 0,
+            Err(str) => {
+                println!("sys_listen: {}", str);
+                usize::MAX
+            }
+        }
+    }
+
+    /// Connect the socket of descriptor a0 to the one listening on path a1
+    fn sys_connect(&mut self) -> usize {
+        let mut path: [u8; MAXPATH] = [0; MAXPATH];
+        let connected = self.arg_fd(0).and_then(|(_, f)| f.socket())
+            .and_then(|id| Ok((id, self.arg_path(1, &mut path)?)))
+            .and_then(|(id, path)| socket::connect(id, path));
+        match connected {
+            Ok(()) => 0,
+            Err(str) => {
+                println!("sys_connect: {}", str);
+                usize::MAX
+            }
+        }
+    }
+
+    /// Wait for a connection to the listening socket of descriptor a0, unless it is O_NONBLOCK,
+    /// return the descriptor of the server's socket of it, which blocks
+    fn sys_accept(&mut self) -> usize {
+        let installed = self.arg_fd(0).and_then(|(_, f)| Ok((f.socket()?, f.nonblock())))
+            .and_then(|(id, nonblock)| socket::accept(id, nonblock))
+            .and_then(|id| {
+                fs::sockalloc(id).inspect_err(|_| socket::close(id).unwrap())
+            }).and_then(|f| self.fdinstall(f));
+        match installed {
+            Ok(fd) => fd,
+            Err(str) => {
+                println!("sys_accept: {}", str);
+                usize::MAX
+            }
+        }
+    }
+
+    /// Open the message queue called a0 with flags a1, see mqueue.rs, return its descriptor
+    fn sys_mq_open(&mut self) -> usize {
+        let mut name: [u8; MAXPATH] = [0; MAXPATH];
+        let flags = self.arg_raw(1);
+        let installed = self.arg_path(0, &mut name)
+            .and_then(|name| mqueue::mq_open(name, flags))
+            .and_then(|mqd| {
+                fs::mqalloc(mqd).inspect_err(|_| mqueue::mq_close(mqd).unwrap())
+            }).and_then(|f| self.fdinstall(f));
+        match installed {
+            Ok(fd) => fd,
+            Err(str) => {
+                println!("sys_mq_open: {}", str);
+                usize::MAX
+            }
+        }
+    }
 
-        // tmp ignore argv, i.e., only have path as first argument
-        // also not copy any content from user to kernel
-        // let mut argv: [*mut u8; MAXARG] = [ptr::null_mut(); MAXARG];
-        // let argv_addr: *const usize = self.arg_addr(1);
-        // argv[0] = unsafe {*argv_addr} as *mut u8;
-        // argv[1] = unsafe {*argv_addr.offset(1)} as *mut u8;
-        // if argv[1] as usize != 0 {
-        //     panic!("argv[1] is not 0, is {}...", unsafe {*argv[1]});
-        // }
-        // argv[1] = ptr::null_mut();
+    /// Send the a2 bytes at a1 to the queue of descriptor a0, with priority a3
+    fn sys_mq_send(&mut self) -> usize {
+        let (len, prio) = (self.arg_raw(2), self.arg_raw(3));
+        let mut msg = [0u8; MQ_MSGSIZE];
+        let sent = self.arg_fd(0).and_then(|(_, f)| f.mqueue())
+            .and_then(|mqd| match len <= MQ_MSGSIZE {
+                true => Ok((mqd, self.arg_addr(1, len)?)),
+                false => Err("message too long"),
+            })
+            .and_then(|(mqd, addr)| {
+                self.copy_in(addr, &mut msg[..len])?;
+                mqueue::mq_send(mqd, &msg[..len], prio)
+            });
+        match sent {
+            Ok(()) => 0,
+            Err(str) => {
+                println!("sys_mq_send: {}", str);
+                usize::MAX
+            }
+        }
+    }
 
-        // TODO - ELF load
-        panic!("sys_exec: end");
+    /// Receive the next message of the queue of descriptor a0 into user buf a1 of a2 bytes,
+    /// at least MQ_MSGSIZE, its priority to a3 unless it is 0, return its length
+    fn sys_mq_receive(&mut self) -> usize {
+        let (len, paddr) = (self.arg_raw(2), self.arg_raw(3));
+        let mut msg = [0u8; MQ_MSGSIZE];
+        let received = self.arg_fd(0).and_then(|(_, f)| f.mqueue())
+            .and_then(|mqd| Ok((mqd, self.arg_addr(1, len)?)))
+            .and_then(|(mqd, addr)| match paddr {
+                0 => Ok((mqd, addr)),
+                _ => self.arg_addr(3, mem::size_of::<usize>()).map(|_| (mqd, addr)),
+            })
+            .and_then(|(mqd, addr)| {
+                // a buffer shorter than MQ_MSGSIZE is refused before a message is taken
+                let (n, prio) = mqueue::mq_receive(mqd, &mut msg[..len.min(MQ_MSGSIZE)])?;
+                self.copy_out(addr, &msg[..n])?;
+                if paddr != 0 {
+                    self.copy_out(paddr, &prio.to_ne_bytes())?;
+                }
+                Ok(n)
+            });
+        match received {
+            Ok(n) => n,
+            Err(str) => {
+                println!("sys_mq_receive: {}", str);
+                usize::MAX
+            }
+        }
+    }
+
+    /// Remove the name a0 of a message queue, its openers can go on using it
+    fn sys_mq_unlink(&mut self) -> usize {
+        let mut name: [u8; MAXPATH] = [0; MAXPATH];
+        match self.arg_path(0, &mut name).and_then(mqueue::mq_unlink) {
+            Ok(()) => 0,
+            Err(str) => {
+                println!("sys_mq_unlink: {}", str);
+                usize::MAX
+            }
+        }
+    }
+
+    /// A new event counter starting at a0, a1 is EFD_NONBLOCK or 0, see eventfd.rs,
+    /// return its descriptor
+    fn sys_eventfd(&mut self) -> usize {
+        let installed = eventfd::eventfd(self.arg_raw(0) as u64, self.arg_raw(1)).and_then(|id| {
+            fs::eventalloc(id).inspect_err(|_| eventfd::close(id).unwrap())
+        }).and_then(|f| self.fdinstall(f));
+        match installed {
+            Ok(fd) => fd,
+            Err(str) => {
+                println!("sys_eventfd: {}", str);
+                usize::MAX
+            }
+        }
+    }
+
+    /// A new disarmed timer descriptor, a0 is TFD_NONBLOCK or 0, see timerfd.rs,
+    /// return its descriptor
+    fn sys_timerfd_create(&mut self) -> usize {
+        let installed = timerfd::timerfd_create(self.arg_raw(0)).and_then(|id| {
+            fs::timeralloc(id).inspect_err(|_| timerfd::close(id).unwrap())
+        }).and_then(|f| self.fdinstall(f));
+        match installed {
+            Ok(fd) => fd,
+            Err(str) => {
+                println!("sys_timerfd_create: {}", str);
+                usize::MAX
+            }
+        }
+    }
+
+    /// Arm the timer descriptor a0 to expire in a1 ticks, then every a2 ticks,
+    /// or disarm it if a1 is 0
+    fn sys_timerfd_settime(&mut self) -> usize {
+        let (value, interval) = (self.arg_raw(1), self.arg_raw(2));
+        let set = self.arg_fd(0).and_then(|(_, f)| f.timerfd())
+            .and_then(|id| timerfd::timerfd_settime(id, value, interval));
+        match set {
+            Ok(()) => 0,
+            Err(str) => {
+                println!("sys_timerfd_settime: {}", str);
+                usize::MAX
+            }
+        }
+    }
+
+    /// Write back the dirty shared pages of the a1 bytes at a0, a2 is MS_SYNC or MS_ASYNC
+    fn sys_msync(&mut self) -> usize {
+        let (addr, len, flags) = (self.arg_raw(0), self.arg_raw(1), self.arg_raw(2));
+        match self.msync(addr, len, flags) {
+            Ok(()) => 0,
+            Err(str) => {
+                println!("sys_msync: {}", str);
+                usize::MAX
+            }
+        }
+    }
+
+    /// Control the descriptor a0, a1 is the command, a2 its argument:
+    /// F_DUPFD, a new descriptor for the file, the lowest not below a2, return it,
+    /// F_GETFD, return its flags, FD_CLOEXEC or 0,
+    /// F_SETFD, set them to a2, exec closes it if FD_CLOEXEC is among them,
+    /// F_GETFL, return the mode and flags of its file, O_RDONLY, O_WRONLY or O_RDWR, and O_NONBLOCK,
+    /// F_SETFL, set O_NONBLOCK of its file as in a2, shared by every descriptor of it
+    fn sys_fcntl(&mut self) -> usize {
+        let (cmd, arg) = (self.arg_raw(1), self.arg_raw(2));
+        let done = self.arg_fd(0).and_then(|(fd, f)| match cmd {
+            F_DUPFD => {
+                let f = fs::filedup(f);
+                self.fdalloc_from(f, arg).inspect_err(|_| fs::fileclose(f))
+            }
+            F_GETFD => Ok(if self.cloexec[fd] { FD_CLOEXEC } else { 0 }),
+            F_SETFD => {
+                self.cloexec[fd] = arg & FD_CLOEXEC != 0;
+                Ok(0)
+            }
+            F_GETFL => {
+                let mode = match (f.readable(), f.writable()) {
+                    (true, true) => O_RDWR,
+                    (false, true) => O_WRONLY,
+                    _ => O_RDONLY,
+                };
+                Ok(mode | if f.nonblock() { O_NONBLOCK } else { 0 })
+            }
+            F_SETFL => {
+                f.set_nonblock(arg & O_NONBLOCK != 0);
+                Ok(0)
+            }
+            _ => Err("bad command"),
+        });
+        match done {
+            Ok(ret) => ret,
+            Err(str) => {
+                println!("sys_fcntl: {}", str);
+                usize::MAX
+            }
+        }
+    }
+
+    /// Copy the soft and hard limits on resource a0, RLIMIT_NOFILE, out to user a1, two usizes
+    fn sys_getrlimit(&mut self) -> usize {
+        let copied = match self.arg_raw(0) {
+            RLIMIT_NOFILE => self.arg_addr(1, 2 * mem::size_of::<usize>()).and_then(|addr| {
+                let (cur, max) = self.nofile;
+                let mut buf = [0u8; 2 * mem::size_of::<usize>()];
+                buf[..mem::size_of::<usize>()].copy_from_slice(&cur.to_ne_bytes());
+                buf[mem::size_of::<usize>()..].copy_from_slice(&max.to_ne_bytes());
+                self.copy_out(addr, &buf)
+            }),
+            _ => Err("bad resource"),
+        };
+        match copied {
+            Ok(()) => 0,
+            Err(str) => {
+                println!("sys_getrlimit: {}", str);
+                usize::MAX
+            }
+        }
+    }
+
+    /// Set the soft limit on resource a0, RLIMIT_NOFILE, to a1, and the hard one to a2,
+    /// see Proc::set_nofile
+    fn sys_setrlimit(&mut self) -> usize {
+        let (cur, max) = (self.arg_raw(1), self.arg_raw(2));
+        let set = match self.arg_raw(0) {
+            RLIMIT_NOFILE => self.set_nofile(cur, max),
+            _ => Err("bad resource"),
+        };
+        match set {
+            Ok(()) => 0,
+            Err(str) => {
+                println!("sys_setrlimit: {}", str);
+                usize::MAX
+            }
+        }
+    }
+
+    /// Carry out ioctl request a1 on the device of descriptor a0, see DEVSW in fs/file.rs,
+    /// its argument at user a2 is copied in, and back out once the request is done
+    fn sys_ioctl(&mut self) -> usize {
+        let req = self.arg_raw(1);
+        let mut arg = [0u8; IOCTL_ARG];
+        let done = self.arg_fd(0).and_then(|(_, f)| {
+            let size = fs::fileioctl_size(f, req)?;
+            let arg = arg.get_mut(..size).ok_or("argument too big")?;
+            let addr = self.arg_addr(2, size)?;
+            self.copy_in(addr, arg)?;
+            fs::fileioctl(f, req, arg)?;
+            self.copy_out(addr, arg)
+        });
+        match done {
+            Ok(()) => 0,
+            Err(str) => {
+                println!("sys_ioctl: {}", str);
+                usize::MAX
+            }
+        }
+    }
+
+    /// Copy up to a4 bytes from descriptor a0 to descriptor a2, see fs::filecopy,
+    /// at the offsets at user a1 and a3, usizes moved along, or at the files' own where 0,
+    /// the flags a5 must be 0. Return the bytes copied.
+    fn sys_copy_file_range(&mut self) -> usize {
+        let len = self.arg_raw(4);
+        let copied = self.arg_fd(0).and_then(|(_, fin)| {
+            let (_, fout) = self.arg_fd(2)?;
+            if self.arg_raw(5) != 0 {
+                return Err("bad flags");
+            }
+            let mut offs = [None, None];
+            for (off, n) in offs.iter_mut().zip([1, 3]) {
+                if self.arg_raw(n) != 0 {
+                    let addr = self.arg_addr(n, mem::size_of::<usize>())?;
+                    let mut buf = [0u8; mem::size_of::<usize>()];
+                    self.copy_in(addr, &mut buf)?;
+                    let val = usize::from_ne_bytes(buf);
+                    if val > u32::MAX as usize {
+                        return Err("offset too big");
+                    }
+                    *off = Some((addr, val as u32));
+                }
+            }
+            let [mut off_in, mut off_out] = offs;
+            let n = fs::filecopy(
+                fin,
+                off_in.as_mut().map(|(_, off)| off),
+                fout,
+                off_out.as_mut().map(|(_, off)| off),
+                len,
+            )?;
+            for (addr, off) in off_in.into_iter().chain(off_out) {
+                self.copy_out(addr, &(off as usize).to_ne_bytes())?;
+            }
+            Ok(n)
+        });
+        match copied {
+            Ok(n) => n,
+            Err(str) => {
+                println!("sys_copy_file_range: {}", str);
+                usize::MAX
+            }
+        }
+    }
+
+    /// Save the process to a new file at path a0, see Proc::checkpoint,
+    /// return 0, and 1 in the one restored from it
+    fn sys_checkpoint(&mut self) -> usize {
+        let mut path: [u8; MAXPATH] = [0; MAXPATH];
+        match self.arg_str(0, &mut path).and_then(|()| self.checkpoint(&path)) {
+            Ok(()) => 0,
+            Err(str) => {
+                println!("sys_checkpoint: {}", str);
+                usize::MAX
+            }
+        }
+    }
+
+    /// Start the process saved in the checkpoint at path a0 as a child, see ProcManager::restore,
+    /// return its pid
+    fn sys_restore(&mut self) -> usize {
+        let mut path: [u8; MAXPATH] = [0; MAXPATH];
+        let restored = self.arg_str(0, &mut path)
+            .and_then(|()| Checkpoint::read(&path))
+            .and_then(|mut ckpt| unsafe { PROC_MANAGER.restore(self, &mut ckpt) });
+        match restored {
+            Ok(pid) => pid,
+            Err(str) => {
+                println!("sys_restore: {}", str);
+                usize::MAX
+            }
+        }
     }
 }
 
@@ -63,13 +1493,69 @@ impl Proc {
         }
     }
 
-    fn arg_addr(&self, n: usize) -> *const usize {
-        self.arg_raw(n) as *const usize
+    /// The n-th argument as a C int
+    fn arg_int(&self, n: usize) -> i32 {
+        self.arg_raw(n) as i32
+    }
+
+    /// The n-th argument as an open file descriptor, and its file
+    fn arg_fd(&self, n: usize) -> Result<(usize, &'static File), &'static str> {
+        let fd = self.arg_raw(n);
+        match self.ofile.get(fd) {
+            Some(Some(f)) => Ok((fd, *f)),
+            _ => Err("bad file descriptor"),
+        }
+    }
+
+    /// The n-th argument as a path, copied into buf, up to its nul
+    fn arg_path<'a>(&mut self, n: usize, buf: &'a mut [u8]) -> Result<&'a [u8], &'static str> {
+        self.arg_str(n, buf)?;
+        let len = buf.iter().position(|c| *c == 0).unwrap_or(buf.len());
+        Ok(&buf[..len])
+    }
+
+    /// A descriptor for f, closing it if there is none free
+    fn fdinstall(&mut self, f: &'static File) -> Result<usize, &'static str> {
+        self.fdalloc(f).inspect_err(|_| fs::fileclose(f))
+    }
+
+    /// The n-th argument as a user address of len bytes, all of them below sz, or in a mapping.
+    /// The pages may still be unmapped, copy_in and copy_out map them in, or fail.
+    fn arg_addr(&self, n: usize, len: usize) -> Result<usize, &'static str> {
+        let addr = self.arg_raw(n);
+        match addr.checked_add(len) {
+            Some(end) if end <= self.sz() || self.in_mmap(addr, end) => Ok(addr),
+            _ => Err("address out of range"),
+        }
+    }
+
+    /// Fetch the nul-terminated strings of the user argv array at uargv into buf.
+    /// The range of the i-th string in buf, including its nul, goes to ranges[i].
+    /// Return the number of strings.
+    fn fetch_args(
+        &mut self,
+        uargv: usize,
+        buf: &mut [u8],
+        ranges: &mut [(usize, usize); MAXARG],
+    ) -> Result<usize, &'static str> {
+        let mut used: usize = 0;
+        for (i, range) in ranges.iter_mut().enumerate() {
+            let mut uarg: [u8; mem::size_of::<usize>()] = [0; mem::size_of::<usize>()];
+            self.copy_in(uargv + i * mem::size_of::<usize>(), &mut uarg)?;
+            let uarg = usize::from_ne_bytes(uarg);
+            if uarg == 0 {
+                return Ok(i);
+            }
+            self.copy_in_str(uarg, &mut buf[used..])?;
+            let len = buf[used..].iter().position(|c| *c == 0).unwrap() + 1;
+            *range = (used, used + len);
+            used += len;
+        }
+        Err("too many arguments")
     }
 
-    fn arg_str(&self, n: usize, buf: &mut [u8]) -> Result<(), &'static str> {
+    fn arg_str(&mut self, n: usize, buf: &mut [u8]) -> Result<(), &'static str> {
         let addr: usize = self.arg_raw(n);
-        self.pagetable.as_ref().unwrap().copy_in_str(addr, buf)?;
-        Ok(())
+        self.copy_in_str(addr, buf)
     }
 }
@@ -1,4 +1,5 @@
 #[repr(C)]
+#[derive(Clone, Copy)]
 pub struct TrapFrame {
     /*   0 */ pub kernel_satp: usize,   // kernel page table
     /*   8 */ pub kernel_sp: usize,     // top of process's kernel stack
@@ -49,11 +50,21 @@ impl TrapFrame {
         self.sp = sp;
     }
 
+    #[inline]
+    pub fn get_sp(&self) -> usize {
+        self.sp
+    }
+
     #[inline]
     pub fn set_a0(&mut self, a0: usize){
         self.a0 = a0;
     }
 
+    #[inline]
+    pub fn set_a1(&mut self, a1: usize){
+        self.a1 = a1;
+    }
+
     #[inline]
     pub fn get_a0(&self) -> usize {
         self.a0
@@ -88,4 +99,47 @@ impl TrapFrame {
     pub fn get_a7(&self) -> usize {
         self.a7
     }
+
+    /// The user registers as a debugger sees them, pc then x1-x31
+    pub fn user_regs(&self) -> [usize; 32] {
+        [
+            self.epc, self.ra, self.sp, self.gp, self.tp, self.t0, self.t1, self.t2,
+            self.s0, self.s1, self.a0, self.a1, self.a2, self.a3, self.a4, self.a5,
+            self.a6, self.a7, self.s2, self.s3, self.s4, self.s5, self.s6, self.s7,
+            self.s8, self.s9, self.s10, self.s11, self.t3, self.t4, self.t5, self.t6,
+        ]
+    }
+
+    /// Set the user registers in the order of user_regs
+    pub fn set_user_regs(&mut self, regs: &[usize; 32]) {
+        let [epc, ra, sp, gp, tp, t0, t1, t2,
+            s0, s1, a0, a1, a2, a3, a4, a5,
+            a6, a7, s2, s3, s4, s5, s6, s7,
+            s8, s9, s10, s11, t3, t4, t5, t6] = *regs;
+        *self = Self {
+            epc, ra, sp, gp, tp, t0, t1, t2,
+            s0, s1, a0, a1, a2, a3, a4, a5,
+            a6, a7, s2, s3, s4, s5, s6, s7,
+            s8, s9, s10, s11, t3, t4, t5, t6,
+            ..*self
+        };
+    }
+}
+
+/// Offsets of the fields trampoline.S relies on, for the boot self-test
+#[cfg(feature = "selftest")]
+pub fn layout() -> [(&'static str, usize); 8] {
+    let tf: TrapFrame = unsafe { core::mem::zeroed() };
+    let base = &tf as *const TrapFrame as usize;
+    let off = |field: &usize| field as *const usize as usize - base;
+    [
+        ("kernel_satp", off(&tf.kernel_satp)),
+        ("kernel_sp", off(&tf.kernel_sp)),
+        ("kernel_trap", off(&tf.kernel_trap)),
+        ("epc", off(&tf.epc)),
+        ("kernel_hartid", off(&tf.kernel_hartid)),
+        ("ra", off(&tf.ra)),
+        ("a0", off(&tf.a0)),
+        ("t6", off(&tf.t6)),
+    ]
 }
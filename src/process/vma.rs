@@ -0,0 +1,439 @@
+//! Mappings of files into a process's memory, mmap() and munmap(), see sys_mmap
+//!
+//! Each Vma is a range of pages above the heap and below the trapframe,
+//! a new one placed right below the lowest one, and holds a reference to its file.
+//! Its pages are read from the file at the first touch, see Proc::mmap_fault(),
+//! those past the end of the file are zeros.
+//! The pages of a MAP_SHARED mapping that were written to, dirty, go back into the file,
+//! up to its end, when they are unmapped, by munmap(), exec or exit, or at msync(),
+//! those of a MAP_PRIVATE one stay the process's own.
+//! munmap() takes pages off either end of a mapping, or all of it, not out of its middle.
+//! A child of fork gets copies of the mappings and of the pages touched, see Proc::fork_vma().
+//! A mapping of the framebuffer device, see driver/fb.rs, has the gpu's pages mapped at once,
+//! it is shared, unmapped all at once, and not inherited, only one process has it.
+
+use core::convert::TryFrom;
+use core::ptr;
+use core::slice;
+
+use crate::consts::{NVMA, PGSIZE, TRAPFRAME};
+use crate::driver::fb;
+use crate::fs::{self, File};
+use crate::mm::{kalloc, kfree, Addr, PageTable, PhysAddr, PteFlag, VirtAddr};
+
+use super::coredump::CoreVma;
+use super::cpu;
+use super::proc::Proc;
+
+/// mmap() protections and flags, as in user/src/lib.rs
+pub const PROT_READ: usize = 0x1;
+pub const PROT_WRITE: usize = 0x2;
+pub const PROT_EXEC: usize = 0x4;
+pub const MAP_SHARED: usize = 0x01;
+pub const MAP_PRIVATE: usize = 0x02;
+pub const MS_ASYNC: usize = 0x1;
+pub const MS_SYNC: usize = 0x4;
+
+/// A mapping of the file from off at [start, end), page-aligned
+#[derive(Clone, Copy)]
+pub struct Vma {
+    start: usize,
+    end: usize,
+    prot: usize,
+    flags: usize,
+    file: &'static File,
+    off: usize,
+    /// of the framebuffer, fb::map() put its pages in
+    fb: bool,
+}
+
+impl Vma {
+    fn contains(&self, va: usize) -> bool {
+        self.start <= va && va < self.end
+    }
+
+    /// Of its pages, always readable, a leaf needs one of R, W and X
+    fn perm(&self) -> PteFlag {
+        let mut perm = PteFlag::R | PteFlag::U;
+        if self.prot & PROT_WRITE != 0 {
+            perm |= PteFlag::W;
+        }
+        if self.prot & PROT_EXEC != 0 {
+            perm |= PteFlag::X;
+        }
+        perm
+    }
+}
+
+/// What is left of the pages [start, end) once [addr, aend) is taken out, None if nothing,
+/// fail if it would leave a hole
+fn cut(start: usize, end: usize, addr: usize, aend: usize) -> Result<Option<(usize, usize)>, &'static str> {
+    if addr < start || aend > end {
+        return Err("range beyond the mapping");
+    }
+    match (addr == start, aend == end) {
+        (true, true) => Ok(None),
+        (true, false) => Ok(Some((aend, end))),
+        (false, true) => Ok(Some((start, addr))),
+        (false, false) => Err("a hole in the middle of a mapping"),
+    }
+}
+
+/// Map a page at page with perm, filled by read, zeros past what it reads,
+/// return whether it was, not if it already is
+fn fault_page(
+    pagetable: &mut PageTable,
+    page: usize,
+    perm: PteFlag,
+    read: &mut dyn FnMut(&mut [u8]) -> Result<usize, &'static str>,
+) -> bool {
+    let va = VirtAddr::try_from(page).unwrap();
+    if pagetable.walk(va).is_some_and(|pte| pte.is_valid()) {
+        return false;
+    }
+    let pa = match unsafe { kalloc() } {
+        Some(pa) => pa,
+        None => return false,
+    };
+    unsafe { ptr::write_bytes(pa, 0, PGSIZE); }
+    let dst = unsafe { slice::from_raw_parts_mut(pa, PGSIZE) };
+    let mapped = read(dst)
+        .and_then(|_| pagetable.map_pages(va, PGSIZE, PhysAddr::try_from(pa as usize).unwrap(), perm));
+    if mapped.is_err() {
+        unsafe { kfree(pa); }
+        return false;
+    }
+    true
+}
+
+/// Where dirty pages are written, given their address and contents, e.g., their file
+type PageWriter<'a> = dyn FnMut(usize, &[u8]) -> Result<usize, &'static str> + 'a;
+
+/// Hand the dirty pages of [addr, aend) to write, with their address,
+/// and mark those it wrote clean, a failed one stays dirty.
+/// Return the first failed write, once all are tried even so.
+fn sync_pages(
+    pagetable: &mut PageTable,
+    addr: usize,
+    aend: usize,
+    write: &mut PageWriter,
+) -> Result<(), &'static str> {
+    let mut written = Ok(());
+    let mut va = VirtAddr::try_from(addr).unwrap();
+    while va.as_usize() < aend {
+        if let Some(pte) = pagetable.walk(va).filter(|pte| pte.is_valid() && pte.is_dirty()) {
+            let src = unsafe {
+                slice::from_raw_parts(pte.as_phys_addr().as_usize() as *const u8, PGSIZE)
+            };
+            match write(va.as_usize(), src) {
+                Ok(_) => pagetable.uvm_clear_dirty(va),
+                Err(str) => written = written.and(Err(str)),
+            }
+        }
+        va.add_page();
+    }
+    written
+}
+
+/// Unmap and free the pages of [addr, aend) that were touched,
+/// after handing the dirty ones to write, with their address, if shared.
+/// Return the first failed write, once all are unmapped even so.
+fn unmap_pages(
+    pagetable: &mut PageTable,
+    addr: usize,
+    aend: usize,
+    shared: bool,
+    write: &mut PageWriter,
+) -> Result<(), &'static str> {
+    let written = if shared { sync_pages(pagetable, addr, aend, write) } else { Ok(()) };
+    let mut va = VirtAddr::try_from(addr).unwrap();
+    while va.as_usize() < aend {
+        if pagetable.walk(va).is_some_and(|pte| pte.is_valid()) {
+            // stale TLB entries go at the sfence.vma on the way back to user space
+            pagetable.unmap_pages(va, 1, true).unwrap();
+        }
+        va.add_page();
+    }
+    written
+}
+
+impl Proc {
+    /// Map len bytes of file from off, page-aligned, prot is PROT_*, PROT_READ among them,
+    /// flags one of MAP_SHARED and MAP_PRIVATE.
+    /// Return where it is mapped.
+    pub fn mmap(&mut self, len: usize, prot: usize, flags: usize, file: &'static File, off: usize)
+        -> Result<usize, &'static str>
+    {
+        if len == 0 || !off.is_multiple_of(PGSIZE) {
+            return Err("bad length or offset");
+        }
+        if prot & !(PROT_READ | PROT_WRITE | PROT_EXEC) != 0 || prot & PROT_READ == 0 {
+            return Err("bad protection, a mapping is readable");
+        }
+        let shared = match flags {
+            MAP_SHARED => true,
+            MAP_PRIVATE => false,
+            _ => return Err("bad flags"),
+        };
+        let fb = file.major() == Some(fs::FB);
+        if !file.is_inode() && !fb {
+            return Err("not a file");
+        }
+        if fb && (!shared || off != 0 || prot != PROT_READ | PROT_WRITE || len > fb::len()?) {
+            return Err("fb: shared, readable and writable, from 0 and within the framebuffer");
+        }
+        if !file.readable() || (shared && prot & PROT_WRITE != 0 && !file.writable()) {
+            return Err("file not open for the protection");
+        }
+        let len = match fb {
+            true => fb::len()?,
+            false => len.checked_add(PGSIZE - 1).ok_or("too long")? / PGSIZE * PGSIZE,
+        };
+        if off.checked_add(len).is_none_or(|end| end > u32::MAX as usize) {
+            return Err("beyond the largest file");
+        }
+        let slot = self.vma.iter().position(|v| v.is_none()).ok_or("too many mappings")?;
+        let heap = self.sz().div_ceil(PGSIZE) * PGSIZE;
+        let start = match self.mmap_base().checked_sub(len) {
+            Some(start) if start >= heap => start,
+            _ => return Err("out of address space"),
+        };
+        if fb {
+            fb::map(self.pagetable.as_mut().unwrap(), start)?;
+        }
+        self.vma[slot] = Some(Vma { start, end: start + len, prot, flags, file: fs::filedup(file), off, fb });
+        Ok(start)
+    }
+
+    /// Where the lowest mapping starts, the heap may grow up to it
+    pub fn mmap_base(&self) -> usize {
+        self.vma.iter().flatten().map(|v| v.start).min().unwrap_or_else(|| TRAPFRAME.into())
+    }
+
+    /// Whether [addr, end) is within a mapping
+    pub fn in_mmap(&self, addr: usize, end: usize) -> bool {
+        self.vma.iter().flatten().any(|v| v.start <= addr && end <= v.end)
+    }
+
+    /// Read in the page of a mapping at va, if it is not mapped yet,
+    /// for an instruction fetch if fetch, only from a PROT_EXEC mapping.
+    /// Return whether it was, then the faulting access is retried,
+    /// a store to a page not mapped writable faults again and fails.
+    pub fn mmap_fault(&mut self, va: usize, fetch: bool) -> bool {
+        let vma = match self.vma.iter().flatten().find(|v| v.contains(va)) {
+            Some(vma) if !vma.fb && (!fetch || vma.prot & PROT_EXEC != 0) => *vma,
+            _ => return false,
+        };
+        let page = va / PGSIZE * PGSIZE;
+        // the file may be read from the disk, with interrupts on as in a syscall
+        cpu::intr_on();
+        let off = (vma.off + page - vma.start) as u32;
+        fault_page(self.pagetable.as_mut().unwrap(), page, vma.perm(),
+            &mut |dst| fs::fileread_at(vma.file, off, dst))
+    }
+
+    /// Mark the pages of mappings in [va, va+len) dirty, copy_out() wrote to them
+    pub fn mmap_written(&mut self, va: usize, len: usize) {
+        let end = va.saturating_add(len);
+        let mut page = va / PGSIZE * PGSIZE;
+        while page < end {
+            if self.vma.iter().flatten().any(|v| v.contains(page)) {
+                self.pagetable.as_mut().unwrap().uvm_set_dirty(VirtAddr::try_from(page).unwrap());
+            }
+            page += PGSIZE;
+        }
+    }
+
+    /// Unmap the pages of [addr, addr+len), addr page-aligned,
+    /// off either end of a mapping or all of it, writing back the dirty ones of a shared mapping.
+    /// A failed write-back is returned once the pages are unmapped even so.
+    pub fn munmap(&mut self, addr: usize, len: usize) -> Result<(), &'static str> {
+        if !addr.is_multiple_of(PGSIZE) || len == 0 {
+            return Err("bad address or length");
+        }
+        let aend = addr.checked_add(len).and_then(|end| end.checked_add(PGSIZE - 1))
+            .ok_or("range out of the address space")? / PGSIZE * PGSIZE;
+        let slot = self.vma.iter().position(|v| v.is_some_and(|v| v.contains(addr)))
+            .ok_or("not mapped")?;
+        let mut vma = self.vma[slot].unwrap();
+        let left = cut(vma.start, vma.end, addr, aend)?;
+        if vma.fb {
+            // the pages are the gpu's, not freed
+            if left.is_some() {
+                return Err("fb: unmapped all at once");
+            }
+            fb::unmap(self.pagetable.as_mut().unwrap(), vma.start)?;
+            self.vma[slot] = None;
+            fs::fileclose(vma.file);
+            return Ok(());
+        }
+        let written = unmap_pages(self.pagetable.as_mut().unwrap(), addr, aend, vma.flags == MAP_SHARED,
+            &mut |va, src| fs::filewrite_at(vma.file, (vma.off + va - vma.start) as u32, src));
+        match left {
+            None => {
+                self.vma[slot] = None;
+                fs::fileclose(vma.file);
+            }
+            Some((start, end)) => {
+                vma.off += start - vma.start;
+                vma.start = start;
+                vma.end = end;
+                self.vma[slot] = Some(vma);
+            }
+        }
+        written
+    }
+
+    /// Write the dirty pages of shared mappings in [addr, addr+len) back into their files,
+    /// addr page-aligned, every page of the range within a mapping, they stay mapped, clean.
+    /// flags is one of MS_SYNC and MS_ASYNC, either way the write is done at the return,
+    /// through the log, committed by the last operation in flight to end, see log.rs.
+    /// A failed write-back is returned once the rest are written even so.
+    pub fn msync(&mut self, addr: usize, len: usize, flags: usize) -> Result<(), &'static str> {
+        if !addr.is_multiple_of(PGSIZE) || (flags != MS_SYNC && flags != MS_ASYNC) {
+            return Err("bad address or flags");
+        }
+        let aend = addr.checked_add(len).and_then(|end| end.checked_add(PGSIZE - 1))
+            .ok_or("range out of the address space")? / PGSIZE * PGSIZE;
+        let mut written = Ok(());
+        let mut va = addr;
+        while va < aend {
+            let vma = *self.vma.iter().flatten().find(|v| v.contains(va)).ok_or("not mapped")?;
+            let end = vma.end.min(aend);
+            if vma.flags == MAP_SHARED && !vma.fb {
+                let synced = sync_pages(self.pagetable.as_mut().unwrap(), va, end,
+                    &mut |va, src| fs::filewrite_at(vma.file, (vma.off + va - vma.start) as u32, src));
+                written = written.and(synced);
+            }
+            va = end;
+        }
+        written
+    }
+
+    /// The layout of the mappings, for a core dump, lowest first in out, return how many
+    pub fn core_vmas(&self, out: &mut [CoreVma; NVMA]) -> usize {
+        let mut n = 0;
+        for v in self.vma.iter().flatten() {
+            out[n] = CoreVma { start: v.start, end: v.end, prot: v.prot, flags: v.flags, off: v.off };
+            n += 1;
+        }
+        out[..n].sort_unstable_by_key(|v| v.start);
+        n
+    }
+
+    /// Copy the mappings of parent, and the pages of them it touched, for fork.
+    /// The copies are the child's own, those of a shared mapping only go back into the file
+    /// if it writes to them itself, the framebuffer's is not copied.
+    /// On failure the child has none of them.
+    pub fn fork_vma(&mut self, parent: &Proc) -> Result<(), &'static str> {
+        let from = parent.pagetable.as_ref().unwrap();
+        let pagetable = self.pagetable.as_mut().unwrap();
+        for vma in parent.vma.iter().flatten().filter(|v| !v.fb) {
+            if let Err(str) = from.uvm_copy(pagetable, vma.start, vma.end) {
+                for vma in parent.vma.iter().flatten().filter(|v| !v.fb) {
+                    pagetable.uvm_dealloc(vma.end, vma.start);
+                }
+                return Err(str);
+            }
+        }
+        for (vma, pvma) in self.vma.iter_mut().zip(parent.vma.iter()) {
+            *vma = pvma.filter(|v| !v.fb).map(|v| Vma { file: fs::filedup(v.file), ..v });
+        }
+        Ok(())
+    }
+
+    /// Unmap all the mappings, at exec and exit
+    pub fn munmap_all(&mut self) {
+        for i in 0..NVMA {
+            if let Some(vma) = self.vma[i] {
+                if let Err(str) = self.munmap(vma.start, vma.end - vma.start) {
+                    println!("munmap of pid {}: {}", self.pid, str);
+                }
+            }
+        }
+    }
+}
+
+#[cfg(feature = "unit_test")]
+pub mod tests {
+    use super::*;
+
+    /// Pages come off either end of a mapping, or all of it, never out of its middle
+    pub fn vma_cut() {
+        let (start, end) = (0x10000, 0x14000);
+        assert_eq!(cut(start, end, start, end), Ok(None));
+        assert_eq!(cut(start, end, start, start + PGSIZE), Ok(Some((start + PGSIZE, end))));
+        assert_eq!(cut(start, end, end - PGSIZE, end), Ok(Some((start, end - PGSIZE))));
+        assert!(cut(start, end, start + PGSIZE, end - PGSIZE).is_err());
+        assert!(cut(start, end, start, end + PGSIZE).is_err());
+        assert!(cut(start, end, start - PGSIZE, end).is_err());
+    }
+    crate::kernel_test!(vma_cut);
+
+    /// Pages read in at the first touch, zeros past the end of the file,
+    /// only the dirty ones written back at a sync, clean after it,
+    /// and those of a shared mapping when unmapped
+    pub fn vma_fault_writeback() {
+        let mut pagetable = PageTable::uvm_create();
+        let start = 0x10000;
+        let file = [b'f'; 100];
+        let read = |page: usize| move |dst: &mut [u8]| {
+            let off = page - start;
+            let n = file.len().saturating_sub(off).min(dst.len());
+            dst[..n].copy_from_slice(&file[off.min(file.len())..][..n]);
+            Ok(n)
+        };
+        let perm = PteFlag::R | PteFlag::W | PteFlag::U;
+        assert!(fault_page(&mut pagetable, start, perm, &mut read(start)));
+        assert!(fault_page(&mut pagetable, start + PGSIZE, perm, &mut read(start + PGSIZE)));
+        assert!(!fault_page(&mut pagetable, start, perm, &mut read(start)));
+        let mut buf = [0u8; 104];
+        pagetable.copy_in(start, &mut buf).unwrap();
+        assert!(buf[..100] == file[..] && buf[100..] == [0; 4]);
+        pagetable.copy_in(start + PGSIZE, &mut buf).unwrap();
+        assert!(buf == [0; 104]);
+
+        // the second page written to, by the hardware or copy_out,
+        // a failed write-back leaves it dirty, a done one clean
+        pagetable.copy_out(start + PGSIZE, b"synced").unwrap();
+        pagetable.uvm_set_dirty(VirtAddr::try_from(start + PGSIZE).unwrap());
+        let mut tries = 0;
+        assert!(sync_pages(&mut pagetable, start, start + 2 * PGSIZE, &mut |_, _| {
+            tries += 1;
+            Err("disk full")
+        }).is_err());
+        sync_pages(&mut pagetable, start, start + 2 * PGSIZE, &mut |va, src| {
+            assert!(va == start + PGSIZE && &src[..6] == b"synced");
+            tries += 1;
+            Ok(src.len())
+        }).unwrap();
+        sync_pages(&mut pagetable, start, start + 2 * PGSIZE, &mut |_, _| {
+            panic!("vma_fault_writeback: a clean page written back")
+        }).unwrap();
+        assert_eq!(tries, 2);
+
+        pagetable.copy_out(start + PGSIZE, b"dirty").unwrap();
+        pagetable.uvm_set_dirty(VirtAddr::try_from(start + PGSIZE).unwrap());
+        let mut written = [0usize; 4];
+        let mut n = 0;
+        unmap_pages(&mut pagetable, start, start + 3 * PGSIZE, true, &mut |va, src| {
+            assert_eq!(&src[..5], b"dirty");
+            written[n] = va;
+            n += 1;
+            Ok(src.len())
+        }).unwrap();
+        assert_eq!(&written[..n], &[start + PGSIZE]);
+        for page in (start..start + 2 * PGSIZE).step_by(PGSIZE) {
+            assert!(pagetable.walk(VirtAddr::try_from(page).unwrap()).is_none_or(|pte| !pte.is_valid()));
+        }
+
+        // those of a private one stay the process's own
+        assert!(fault_page(&mut pagetable, start, perm, &mut read(start)));
+        pagetable.uvm_set_dirty(VirtAddr::try_from(start).unwrap());
+        unmap_pages(&mut pagetable, start, start + PGSIZE, false, &mut |_, _| {
+            panic!("vma_fault_writeback: a private page written back")
+        }).unwrap();
+        pagetable.free_walk();
+    }
+    crate::kernel_test!(vma_fault_writeback);
+}
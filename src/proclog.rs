@@ -0,0 +1,93 @@
+//! Process lifecycle events, logged into the kernel message ring
+//!
+//! When on, every fork, exec, exit, kill and OOM kill becomes one line,
+//! the event and then key=value pairs, for reading back with dmesg, e.g.,
+//! "proc: exit pid=3 ppid=1 status=0 time=1234567".
+//! The ring prefixes each line with the tick and the hart as usual,
+//! time is the CLINT mtime, which orders events within a tick.
+//! The lines only go into the ring, a busy system would flood the console.
+//!
+//! Toggled by the proclog syscall, and on from boot with proclog on the command line.
+//! Forks are those of user processes, and the kernel threads spawned,
+//! nothing kills for memory yet, oom_kill is for whatever will.
+
+use core::fmt;
+use core::sync::atomic::{AtomicBool, Ordering};
+
+use crate::printf;
+use crate::register::clint;
+
+static ON: AtomicBool = AtomicBool::new(false);
+
+pub fn set_enabled(on: bool) {
+    ON.store(on, Ordering::Relaxed);
+}
+
+pub fn enabled() -> bool {
+    ON.load(Ordering::Relaxed)
+}
+
+fn log(event: &str, pid: usize, ppid: usize, rest: fmt::Arguments) {
+    if !enabled() {
+        return;
+    }
+    printf::kmsg_log(format_args!("proc: {} pid={} ppid={} {}time={}\n",
+        event, pid, ppid, rest, unsafe { clint::read_mtime() }));
+}
+
+pub fn fork(pid: usize, ppid: usize, name: &str) {
+    log("fork", pid, ppid, format_args!("name={} ", name));
+}
+
+pub fn exec(pid: usize, ppid: usize, name: &str) {
+    log("exec", pid, ppid, format_args!("name={} ", name));
+}
+
+pub fn exit(pid: usize, ppid: usize, status: i32) {
+    log("exit", pid, ppid, format_args!("status={} ", status));
+}
+
+/// by is the pid of the killer, 0 for the kernel, e.g., the monitor
+pub fn kill(pid: usize, ppid: usize, by: usize) {
+    log("kill", pid, ppid, format_args!("by={} ", by));
+}
+
+pub fn oom_kill(pid: usize, ppid: usize) {
+    log("oom-kill", pid, ppid, format_args!(""));
+}
+
+#[cfg(feature = "unit_test")]
+pub mod tests {
+    use super::*;
+
+    /// An event is one line in the ring, and nothing while off
+    pub fn ring_line() {
+        let mut buf = [0u8; 512];
+        let mut seq = 0;
+        loop {
+            let (n, next) = printf::kmsg_read(seq, &mut buf);
+            seq = next;
+            if n == 0 {
+                break;
+            }
+        }
+
+        let was = enabled();
+        set_enabled(false);
+        exit(42, 7, -3);
+        set_enabled(true);
+        exit(42, 7, -3);
+        set_enabled(was);
+
+        let (n, _) = printf::kmsg_read(seq, &mut buf);
+        let text = core::str::from_utf8(&buf[..n]).unwrap();
+        // other harts may have printed in between
+        let mut events = text.lines().filter(|line| line.contains("proc: exit pid=42 "));
+        let line = events.next().expect("ring_line: no event");
+        assert!(events.next().is_none());
+        // after the "[ticks hartN] " prefix
+        let event = &line[line.find("] ").unwrap() + 2..];
+        assert!(event.starts_with("proc: exit pid=42 ppid=7 status=-3 time="));
+    }
+    crate::kernel_test!(ring_line);
+}
@@ -0,0 +1,122 @@
+//! Sampling profiler driven by the timer interrupt
+//!
+//! When enabled, every timer tick records the interrupted pc,
+//! whether it was in user space, and the current pid,
+//! into a ring of NPROF samples per hart.
+//! Each ring is only written by its own hart with interrupts off,
+//! so there is no lock, dump() is meant to be called after stop().
+//! The prof syscall starts, stops and dumps it, see user/src/bin/prof.rs.
+
+use core::sync::atomic::{AtomicBool, Ordering};
+
+use crate::consts::{NCPU, NPROF, NPROF_SITE};
+
+#[derive(Clone, Copy)]
+struct Sample {
+    pc: usize,
+    pid: u32,
+    user: bool,
+}
+
+struct Ring {
+    samples: [Sample; NPROF],
+    // total samples ever taken, the write index is n % NPROF
+    n: usize,
+}
+
+static mut RINGS: [Ring; NCPU] = [const { Ring {
+    samples: [Sample { pc: 0, pid: 0, user: false }; NPROF],
+    n: 0,
+} }; NCPU];
+
+static ENABLED: AtomicBool = AtomicBool::new(false);
+
+/// One line of the aggregated profile, as copied out to user space
+#[repr(C)]
+#[derive(Clone, Copy)]
+pub struct ProfEntry {
+    pub pc: usize,
+    pub pid: u32,
+    pub user: u32,
+    pub count: usize,
+}
+
+impl ProfEntry {
+    const fn empty() -> Self {
+        Self { pc: 0, pid: 0, user: 0, count: 0 }
+    }
+}
+
+/// Called on every timer tick with the interrupted pc.
+/// Interrupts must be off.
+pub fn sample(hart: usize, pc: usize, user: bool, pid: Option<usize>) {
+    if !ENABLED.load(Ordering::Relaxed) {
+        return;
+    }
+    let ring = unsafe { &mut RINGS[hart] };
+    ring.samples[ring.n % NPROF] = Sample {
+        pc,
+        pid: pid.unwrap_or(0) as u32,
+        user,
+    };
+    ring.n += 1;
+}
+
+/// Clear the rings and start sampling
+pub fn start() {
+    ENABLED.store(false, Ordering::SeqCst);
+    for ring in unsafe { RINGS.iter_mut() } {
+        ring.n = 0;
+    }
+    ENABLED.store(true, Ordering::SeqCst);
+}
+
+pub fn stop() {
+    ENABLED.store(false, Ordering::SeqCst);
+}
+
+/// Aggregate the samples of all harts by (pc, pid, user) into entries,
+/// the most frequent first.
+/// Samples whose key finds no room are counted in one last kernel entry with pc 0.
+/// Return the number of entries filled.
+pub fn dump(entries: &mut [ProfEntry; NPROF_SITE]) -> usize {
+    // not a whole-array assignment, which is a temporary on the small kernel stack
+    for e in entries.iter_mut() {
+        *e = ProfEntry::empty();
+    }
+    let mut used = 0;
+    let mut others = 0;
+
+    for ring in unsafe { RINGS.iter() } {
+        let n = if ring.n < NPROF { ring.n } else { NPROF };
+        for s in ring.samples[..n].iter() {
+            let user = s.user as u32;
+            match entries[..used].iter_mut()
+                .find(|e| e.pc == s.pc && e.pid == s.pid && e.user == user)
+            {
+                Some(e) => e.count += 1,
+                // keep the last slot for the others
+                None if used < NPROF_SITE - 1 => {
+                    entries[used] = ProfEntry { pc: s.pc, pid: s.pid, user, count: 1 };
+                    used += 1;
+                }
+                None => others += 1,
+            }
+        }
+    }
+
+    // insertion sort, most samples first
+    for i in 1..used {
+        let mut j = i;
+        while j > 0 && entries[j - 1].count < entries[j].count {
+            entries.swap(j - 1, j);
+            j -= 1;
+        }
+    }
+
+    if others > 0 {
+        entries[used] = ProfEntry { pc: 0, pid: 0, user: 0, count: others };
+        used += 1;
+    }
+    used
+}
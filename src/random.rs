@@ -0,0 +1,341 @@
+//! Kernel random numbers, an entropy pool feeding a ChaCha20 generator
+//!
+//! Entropy comes from the virtio rng, if there is one, see driver/virtio_rng.rs,
+//! and from the time and cycle counter of each interrupt.
+//! It is mixed into the pool, a sponge over ChaCha's permutation,
+//! and credited with the bits it is thought to hold, for interrupts one bit per INTR_PER_BIT.
+//! Once the pool has SEED_BITS, the generator is seeded from it,
+//! until then getrandom() waits, or fails with GRND_NONBLOCK, unless GRND_INSECURE
+//! takes what the generator gives anyway.
+//! After that it is reseeded every RESEED_TICKS, when the pool has SEED_BITS again,
+//! pulling from the virtio rng first.
+//! Each output is followed by a block taken as the next key,
+//! so the state after it cannot give back what was output.
+//! The random device, see DEVSW in fs/file.rs, reads from the generator,
+//! minor 0, /random, waiting like getrandom(), minor 1, /urandom, not waiting to be seeded,
+//! and mixes what is written to it into the pool, without crediting it.
+//!
+//! LTODO - ASLR and TCP initial sequence numbers are to take u64(), once there are either.
+
+use core::cmp::min;
+
+use crate::consts::RESEED_TICKS;
+use crate::driver::virtio_rng;
+use crate::process::{my_proc, PROC_MANAGER};
+use crate::register::{clint, cycle};
+use crate::spinlock::SpinLock;
+use crate::trap;
+
+/// getrandom flags, as Linux's
+pub const GRND_NONBLOCK: usize = 1;
+pub const GRND_RANDOM: usize = 2;
+pub const GRND_INSECURE: usize = 4;
+
+/// bits the pool must hold to seed the generator, and bytes of them
+pub const SEED_BITS: usize = 256;
+pub const SEED_BYTES: usize = SEED_BITS / 8;
+/// the most bits the pool is credited with
+const POOL_BITS: usize = 512;
+/// interrupts mixed in for each bit credited
+const INTR_PER_BIT: usize = 64;
+
+/// words of the pool input is mixed into, the rest is never output
+const RATE: usize = 8;
+
+const SIGMA: [u32; 4] = [0x61707865, 0x3320646e, 0x79622d32, 0x6b206574];
+
+fn quarter(s: &mut [u32; 16], a: usize, b: usize, c: usize, d: usize) {
+    s[a] = s[a].wrapping_add(s[b]);
+    s[d] = (s[d] ^ s[a]).rotate_left(16);
+    s[c] = s[c].wrapping_add(s[d]);
+    s[b] = (s[b] ^ s[c]).rotate_left(12);
+    s[a] = s[a].wrapping_add(s[b]);
+    s[d] = (s[d] ^ s[a]).rotate_left(8);
+    s[c] = s[c].wrapping_add(s[d]);
+    s[b] = (s[b] ^ s[c]).rotate_left(7);
+}
+
+/// ChaCha's 20 rounds
+fn permute(s: &mut [u32; 16]) {
+    for _ in 0..10 {
+        quarter(s, 0, 4, 8, 12);
+        quarter(s, 1, 5, 9, 13);
+        quarter(s, 2, 6, 10, 14);
+        quarter(s, 3, 7, 11, 15);
+        quarter(s, 0, 5, 10, 15);
+        quarter(s, 1, 6, 11, 12);
+        quarter(s, 2, 7, 8, 13);
+        quarter(s, 3, 4, 9, 14);
+    }
+}
+
+/// The ChaCha20 block function of RFC 7539
+fn block(key: &[u32; 8], counter: u32, nonce: &[u32; 3]) -> [u32; 16] {
+    let mut input = [0u32; 16];
+    input[..4].copy_from_slice(&SIGMA);
+    input[4..12].copy_from_slice(key);
+    input[12] = counter;
+    input[13..].copy_from_slice(nonce);
+    let mut s = input;
+    permute(&mut s);
+    for (word, inword) in s.iter_mut().zip(input.iter()) {
+        *word = word.wrapping_add(*inword);
+    }
+    s
+}
+
+struct Random {
+    pool: [u32; 16],
+    /// the next word of the rate input goes to
+    pos: usize,
+    /// bits the pool is credited with, and interrupts not credited yet
+    bits: usize,
+    intrs: usize,
+    key: [u32; 8],
+    counter: u32,
+    seeded: bool,
+    /// the tick to reseed at, if the pool has the bits
+    reseed_at: usize,
+}
+
+impl Random {
+    const fn new() -> Self {
+        Self {
+            pool: [0; 16],
+            pos: 0,
+            bits: 0,
+            intrs: 0,
+            key: [0; 8],
+            counter: 0,
+            seeded: false,
+            reseed_at: 0,
+        }
+    }
+
+    fn mix(&mut self, word: u32) {
+        self.pool[self.pos] ^= word;
+        self.pos += 1;
+        if self.pos == RATE {
+            permute(&mut self.pool);
+            self.pos = 0;
+        }
+    }
+
+    fn mix_bytes(&mut self, data: &[u8]) {
+        for chunk in data.chunks(4) {
+            let mut word = [0u8; 4];
+            word[..chunk.len()].copy_from_slice(chunk);
+            self.mix(u32::from_le_bytes(word));
+        }
+    }
+
+    /// Credit bits to the pool, seed the generator the first time it has enough,
+    /// return whether this seeded it.
+    fn credit(&mut self, bits: usize, now: usize) -> bool {
+        self.bits = min(self.bits + bits, POOL_BITS);
+        if self.seeded || self.bits < SEED_BITS {
+            return false;
+        }
+        self.reseed(now);
+        true
+    }
+
+    /// Squeeze a key out of the pool, the old key mixed in, and erase what was squeezed
+    fn reseed(&mut self, now: usize) {
+        permute(&mut self.pool);
+        for (key, word) in self.key.iter_mut().zip(self.pool[..RATE].iter_mut()) {
+            *key ^= *word;
+            *word = 0;
+        }
+        self.pos = 0;
+        self.counter = 0;
+        self.bits -= SEED_BITS;
+        self.seeded = true;
+        self.reseed_at = now + RESEED_TICKS;
+    }
+
+    fn reseed_due(&self, now: usize) -> bool {
+        self.seeded && now >= self.reseed_at
+    }
+
+    /// Fill dst from the generator, and take the next block as the key
+    fn extract(&mut self, dst: &mut [u8], now: usize) {
+        if self.reseed_due(now) && self.bits >= SEED_BITS {
+            self.reseed(now);
+        }
+        for chunk in dst.chunks_mut(64) {
+            let out = block(&self.key, self.counter, &[0; 3]);
+            self.counter += 1;
+            for (i, b) in chunk.iter_mut().enumerate() {
+                *b = out[i / 4].to_le_bytes()[i % 4];
+            }
+        }
+        let next = block(&self.key, self.counter, &[0; 3]);
+        self.key.copy_from_slice(&next[..8]);
+        self.counter = 0;
+    }
+}
+
+static RANDOM: SpinLock<Random> = SpinLock::new(Random::new(), "random");
+
+/// Somewhere for getrandom() to sleep until the generator is seeded
+fn chan() -> usize {
+    &RANDOM as *const SpinLock<Random> as usize
+}
+
+/// Mix data into the pool, credited with bits, e.g., from a hardware source
+pub fn add_entropy(data: &[u8], bits: usize) {
+    let now = trap::ticks();
+    let mut r = RANDOM.lock();
+    r.mix_bytes(data);
+    if r.credit(bits, now) {
+        unsafe { PROC_MANAGER.wakeup(chan()); }
+    }
+}
+
+/// Mix in the time of an interrupt, what caused it, and the cycle it came at
+pub fn add_interrupt(cause: usize) {
+    let now = trap::ticks();
+    let mut r = RANDOM.lock();
+    let time = unsafe { clint::read_mtime() };
+    r.mix(time as u32);
+    r.mix(cycle::read() as u32);
+    r.mix(cause as u32);
+    r.intrs += 1;
+    if r.intrs == INTR_PER_BIT {
+        r.intrs = 0;
+        if r.credit(1, now) {
+            unsafe { PROC_MANAGER.wakeup(chan()); }
+        }
+    }
+}
+
+/// Whether the generator was seeded
+pub fn seeded() -> bool {
+    RANDOM.lock().seeded
+}
+
+/// Pull fresh bytes from the virtio rng when a reseed is due
+fn refill(now: usize) {
+    if !virtio_rng::is_present() || !RANDOM.lock().reseed_due(now) {
+        return;
+    }
+    let mut buf = [0u8; SEED_BYTES];
+    let n = virtio_rng::fill(&mut buf);
+    add_entropy(&buf[..n], n * 8);
+}
+
+/// Fill dst with random bytes, for the kernel itself, not waiting to be seeded
+pub fn get_random_bytes(dst: &mut [u8]) {
+    let now = trap::ticks();
+    refill(now);
+    RANDOM.lock().extract(dst, now);
+}
+
+pub fn u64() -> u64 {
+    let mut buf = [0u8; 8];
+    get_random_bytes(&mut buf);
+    u64::from_le_bytes(buf)
+}
+
+/// Fill dst with random bytes for getrandom, return how many.
+/// It waits for the generator to be seeded, unless flags has GRND_NONBLOCK,
+/// failing instead, or GRND_INSECURE. GRND_RANDOM changes nothing.
+pub fn getrandom(dst: &mut [u8], flags: usize) -> Result<usize, &'static str> {
+    if flags & !(GRND_NONBLOCK | GRND_RANDOM | GRND_INSECURE) != 0 {
+        return Err("getrandom: unknown flags");
+    }
+    let now = trap::ticks();
+    refill(now);
+    let mut r = RANDOM.lock();
+    while !r.seeded && flags & GRND_INSECURE == 0 {
+        if flags & GRND_NONBLOCK != 0 {
+            return Err("would block");
+        }
+        let p = unsafe { my_proc() };
+        if p.killed {
+            return Err("killed");
+        }
+        r = p.sleep(chan(), r);
+    }
+    r.extract(dst, now);
+    Ok(dst.len())
+}
+
+/// read() of the random device, minor 0 waits until the generator is seeded, unless nonblock
+pub fn randread(minor: u16, dst: &mut [u8], nonblock: bool) -> Result<usize, &'static str> {
+    let flags = match (minor, nonblock) {
+        (0, false) => 0,
+        (0, true) => GRND_NONBLOCK,
+        _ => GRND_INSECURE,
+    };
+    getrandom(dst, flags)
+}
+
+/// write() of the random device, anyone may write it, so nothing is credited
+pub fn randwrite(_minor: u16, src: &[u8]) -> Result<usize, &'static str> {
+    add_entropy(src, 0);
+    Ok(src.len())
+}
+
+pub fn ioctl_size(_req: usize) -> Result<usize, &'static str> {
+    Err("random: no ioctls")
+}
+
+pub fn randioctl(_minor: u16, _req: usize, _arg: &mut [u8]) -> Result<(), &'static str> {
+    Err("random: no ioctls")
+}
+
+#[cfg(feature = "unit_test")]
+pub mod tests {
+    use super::*;
+
+    /// The block of section 2.3.2 of RFC 7539
+    pub fn chacha20() {
+        let mut key = [0u32; 8];
+        for (i, word) in key.iter_mut().enumerate() {
+            let b = 4 * i as u8;
+            *word = u32::from_le_bytes([b, b + 1, b + 2, b + 3]);
+        }
+        let out = block(&key, 1, &[0x09000000, 0x4a000000, 0]);
+        assert_eq!(out, [
+            0xe4e7f110, 0x15593bd1, 0x1fdd0f50, 0xc47120a3,
+            0xc7f4d1c7, 0x0368c033, 0x9aaa2204, 0x4e6cd4c3,
+            0x466482d2, 0x09aa9f07, 0x05d7c214, 0xa2028bd9,
+            0xd19c12b5, 0xb94e16de, 0xe883d0cb, 0x4e3c50a2,
+        ]);
+    }
+    crate::kernel_test!(chacha20);
+
+    /// A pool of its own, seeded once it has the bits,
+    /// and reseeded only when due and full again
+    pub fn seeding() {
+        let mut r = Random::new();
+        r.mix_bytes(b"not enough");
+        assert!(!r.credit(SEED_BITS - 1, 0));
+        assert!(!r.seeded);
+        assert!(r.credit(1, 0));
+        assert!(r.seeded);
+        assert_eq!(r.bits, 0);
+        assert_eq!(r.pool[..RATE], [0; RATE]);
+
+        let mut a = [0u8; 100];
+        let mut b = [0u8; 100];
+        let key = r.key;
+        r.extract(&mut a, 1);
+        assert_ne!(r.key, key);
+        r.extract(&mut b, 1);
+        assert_ne!(a[..], b[..]);
+        assert_ne!(a[64..], [0; 36]);
+
+        // due, but the pool is empty
+        r.extract(&mut a, RESEED_TICKS);
+        assert!(r.reseed_due(RESEED_TICKS));
+        r.mix_bytes(&b);
+        assert!(!r.credit(SEED_BITS, RESEED_TICKS));
+        r.extract(&mut a, RESEED_TICKS);
+        assert!(!r.reseed_due(RESEED_TICKS));
+        assert_eq!(r.bits, 0);
+    }
+    crate::kernel_test!(seeding);
+}
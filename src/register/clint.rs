@@ -6,15 +6,29 @@
 use core::ptr;
 use core::convert::Into;
 
-use crate::consts::{CLINT_MTIME, CLINT_MTIMECMP};
+use crate::consts::{CLINT_MSIP, CLINT_MTIMECMP};
+#[cfg(not(feature = "sbi"))]
+use crate::consts::CLINT_MTIME;
 
+/// Also readable in supervisor mode, since CLINT is mapped,
+/// e.g., for timing with a finer resolution than the tick.
+#[cfg(not(feature = "sbi"))]
 #[inline]
-unsafe fn read_mtime() -> u64 {
+pub unsafe fn read_mtime() -> u64 {
     ptr::read_volatile(Into::<usize>::into(CLINT_MTIME) as *const u64)
 }
 
+/// OpenSBI keeps the CLINT to itself, the time csr has the same value.
+#[cfg(feature = "sbi")]
 #[inline]
-unsafe fn write_mtimecmp(mhartid: usize, value: u64) {
+pub unsafe fn read_mtime() -> u64 {
+    super::time::read()
+}
+
+/// Also writable in supervisor mode, since CLINT is mapped,
+/// e.g., for an idle hart to skip ticks.
+#[inline]
+pub unsafe fn write_mtimecmp(mhartid: usize, value: u64) {
     let offset = Into::<usize>::into(CLINT_MTIMECMP) + 8 * mhartid;
     ptr::write_volatile(offset as *mut u64, value);
 }
@@ -28,3 +42,12 @@ pub unsafe fn read_mtimecmp(mhartid: usize) -> u64 {
     let offset = Into::<usize>::into(CLINT_MTIMECMP) + 8 * mhartid;
     ptr::read_volatile(offset as *const u64)
 }
+
+/// Raise a machine software interrupt on the given hart, i.e., send an IPI.
+/// timervec in kernelvec.S clears it and forwards it
+/// as a supervisor software interrupt.
+/// It can also be called in supervisor mode, since CLINT is mapped.
+pub unsafe fn send_msip(mhartid: usize) {
+    let offset = Into::<usize>::into(CLINT_MSIP) + 4 * mhartid;
+    ptr::write_volatile(offset as *mut u32, 1);
+}
@@ -0,0 +1,56 @@
+//! Hypervisor extension registers, used by hyp.rs,
+//! accessing any of them traps on harts without the H extension.
+//! While the guest runs, its s* registers are really the vs* ones here.
+
+macro_rules! hcsr {
+    ($name:ident, $csr:literal) => {
+        pub mod $name {
+            #[inline]
+            pub fn read() -> usize {
+                unsafe {csr_read!($csr)}
+            }
+
+            #[inline]
+            pub fn write(x: usize) {
+                unsafe {csr_write!($csr, x);}
+            }
+        }
+    };
+}
+
+hcsr!(hstatus, "hstatus");
+hcsr!(hedeleg, "hedeleg");
+hcsr!(hideleg, "hideleg");
+hcsr!(hvip, "hvip");
+hcsr!(hcounteren, "hcounteren");
+hcsr!(htimedelta, "htimedelta");
+hcsr!(hgatp, "hgatp");
+hcsr!(htval, "htval");
+hcsr!(htinst, "htinst");
+
+hcsr!(vsstatus, "vsstatus");
+hcsr!(vsie, "vsie");
+hcsr!(vstvec, "vstvec");
+hcsr!(vsscratch, "vsscratch");
+hcsr!(vsepc, "vsepc");
+hcsr!(vscause, "vscause");
+hcsr!(vstval, "vstval");
+hcsr!(vsatp, "vsatp");
+
+/// hstatus.SPV, sret goes to virtualized mode
+pub const HSTATUS_SPV: usize = 1 << 7;
+
+/// virtual supervisor interrupts, in hvip, hideleg and the guest's sip
+pub const VSSIP: usize = 1 << 2;
+pub const VSTIP: usize = 1 << 6;
+pub const VSEIP: usize = 1 << 10;
+
+/// hgatp mode for Sv39x4 second-stage translation
+pub const HGATP_SV39X4: usize = 8 << 60;
+
+/// Invalidate all second-stage translations on this hart,
+/// spelled out, since the assembler may not know the H extension
+#[inline]
+pub fn hfence_gvma() {
+    unsafe {core::arch::asm!(".4byte 0x62000073", options(nostack));} // hfence.gvma zero, zero
+}
@@ -4,14 +4,12 @@ use bit_field::BitField;
 
 #[inline]
 unsafe fn read() -> usize {
-    let ret: usize;
-    llvm_asm!("csrr $0, mie":"=r"(ret):::"volatile");
-    ret
+    csr_read!("mie")
 }
 
 #[inline]
 unsafe fn write(x: usize) {
-    llvm_asm!("csrw mie, $0"::"r"(x)::"volatile");
+    csr_write!("mie", x);
 }
 
 /// set MTIE field
@@ -20,3 +18,10 @@ pub unsafe fn set_mtie() {
     mie.set_bit(7, true);
     write(mie);
 }
+
+/// set MSIE field
+pub unsafe fn set_msie() {
+    let mut mie = read();
+    mie.set_bit(3, true);
+    write(mie);
+}
@@ -1,6 +1,28 @@
 //! register modules defined in this file are only used once in start.rs
 
+use core::arch::asm;
+
+/// Read a CSR by name, e.g., `csr_read!("sstatus")`, in an unsafe context
+macro_rules! csr_read {
+    ($csr:literal) => {{
+        let ret: usize;
+        core::arch::asm!(concat!("csrr {}, ", $csr), out(reg) ret, options(nomem, nostack));
+        ret
+    }};
+}
+
+/// Write a CSR by name, e.g., `csr_write!("sstatus", x)`, in an unsafe context.
+/// Not nomem, since writes such as satp or sstatus change how memory
+/// may be accessed, so the compiler must not move loads and stores across.
+macro_rules! csr_write {
+    ($csr:literal, $x:expr) => {
+        core::arch::asm!(concat!("csrw ", $csr, ", {}"), in(reg) $x, options(nostack))
+    };
+}
+
 pub mod clint;
+#[cfg(feature = "hypervisor")]
+pub mod hyp;
 pub mod mie;
 pub mod mstatus;
 pub mod satp;
@@ -12,44 +34,88 @@ pub mod scause;
 /// medeleg
 pub mod medeleg {
     pub unsafe fn write(medeleg: usize) {
-        llvm_asm!("csrw medeleg, $0"::"r"(medeleg)::"volatile");
+        csr_write!("medeleg", medeleg);
+    }
+}
+
+/// mcounteren, which counters lower modes may read
+pub mod mcounteren {
+    pub const CY: usize = 1 << 0;
+    pub const TM: usize = 1 << 1;
+    pub const IR: usize = 1 << 2;
+
+    pub unsafe fn write(mcounteren: usize) {
+        csr_write!("mcounteren", mcounteren);
     }
 }
 
 /// mepc
 pub mod mepc {
     pub unsafe fn write(mepc: usize) {
-        llvm_asm!("csrw mepc, $0"::"r"(mepc)::"volatile");
+        csr_write!("mepc", mepc);
     }
 }
 
 /// mhartid
 pub mod mhartid {
     pub unsafe fn read() -> usize {
-        let ret: usize;
-        llvm_asm!("csrr $0, mhartid":"=r"(ret):::"volatile");
-        ret
+        csr_read!("mhartid")
     }
 }
 
 /// mideleg
 pub mod mideleg {
     pub unsafe fn write(mideleg: usize) {
-        llvm_asm!("csrw mideleg, $0"::"r"(mideleg)::"volatile");
+        csr_write!("mideleg", mideleg);
     }
 }
 
 /// mscratch
 pub mod mscratch {
     pub unsafe fn write(mscratch: usize) {
-        llvm_asm!("csrw mscratch, $0"::"r"(mscratch)::"volatile");
+        csr_write!("mscratch", mscratch);
     }
 }
 
 /// mtvec
 pub mod mtvec {
     pub unsafe fn write(mtvec: usize) {
-        llvm_asm!("csrw mtvec, $0"::"r"(mtvec)::"volatile");
+        csr_write!("mtvec", mtvec);
+    }
+}
+
+/// physical memory protection, 16 entries,
+/// pmpcfg0 and pmpcfg2 hold 8 configuration bytes each on RV64
+pub mod pmp {
+    /// i is 0 or 2, the odd ones do not exist on RV64
+    pub unsafe fn write_cfg(i: usize, cfg: usize) {
+        match i {
+            0 => csr_write!("pmpcfg0", cfg),
+            2 => csr_write!("pmpcfg2", cfg),
+            _ => panic!("pmp: no pmpcfg{}", i),
+        }
+    }
+
+    pub unsafe fn write_addr(i: usize, addr: usize) {
+        match i {
+            0 => csr_write!("pmpaddr0", addr),
+            1 => csr_write!("pmpaddr1", addr),
+            2 => csr_write!("pmpaddr2", addr),
+            3 => csr_write!("pmpaddr3", addr),
+            4 => csr_write!("pmpaddr4", addr),
+            5 => csr_write!("pmpaddr5", addr),
+            6 => csr_write!("pmpaddr6", addr),
+            7 => csr_write!("pmpaddr7", addr),
+            8 => csr_write!("pmpaddr8", addr),
+            9 => csr_write!("pmpaddr9", addr),
+            10 => csr_write!("pmpaddr10", addr),
+            11 => csr_write!("pmpaddr11", addr),
+            12 => csr_write!("pmpaddr12", addr),
+            13 => csr_write!("pmpaddr13", addr),
+            14 => csr_write!("pmpaddr14", addr),
+            15 => csr_write!("pmpaddr15", addr),
+            _ => panic!("pmp: no pmpaddr{}", i),
+        }
     }
 }
 
@@ -57,19 +123,53 @@ pub mod mtvec {
 pub mod tp {
     pub unsafe fn read() -> usize {
         let ret: usize;
-        llvm_asm!("mv $0, tp":"=r"(ret):::"volatile");
+        core::arch::asm!("mv {}, tp", out(reg) ret, options(nomem, nostack));
         ret
     }
 
     pub unsafe fn write(tp: usize) {
-        llvm_asm!("mv tp, $0"::"r"(tp)::"volatile");
+        core::arch::asm!("mv tp, {}", in(reg) tp, options(nomem, nostack));
     }
 }
 
+/// fp, i.e., s0
+/// only meaningful with frame pointers forced on, see .cargo/config
+pub mod fp {
+    #[inline(always)]
+    pub fn read() -> usize {
+        let ret: usize;
+        unsafe {core::arch::asm!("mv {}, s0", out(reg) ret, options(nomem, nostack));}
+        ret
+    }
+}
+
+/// time, the read-only copy of the CLINT's mtime,
+/// readable in supervisor mode if machine mode allows it, e.g., OpenSBI does
+pub mod time {
+    #[inline]
+    pub fn read() -> u64 {
+        unsafe {csr_read!("time") as u64}
+    }
+}
+
+/// cycle, the hart's clock cycles, readable in supervisor mode as time is
+pub mod cycle {
+    #[inline]
+    pub fn read() -> u64 {
+        unsafe {csr_read!("cycle") as u64}
+    }
+}
+
+/// wait for interrupt
+#[inline]
+pub fn wfi() {
+    unsafe {asm!("wfi", options(nomem, nostack));}
+}
+
 /// stvec
 pub mod stvec {
     pub unsafe fn write(stvec: usize) {
-        llvm_asm!("csrw stvec, $0"::"r"(stvec)::"volatile");
+        csr_write!("stvec", stvec);
     }
 }
 
@@ -79,13 +179,11 @@ pub mod stvec {
 /// exception will go.(from xv6-riscv)
 pub mod sepc {
     pub fn read() -> usize {
-        let ret: usize;
-        unsafe {llvm_asm!("csrr $0, sepc":"=r"(ret):::"volatile");}
-        ret
+        unsafe {csr_read!("sepc")}
     }
 
     pub fn write(sepc: usize) {
-        unsafe {llvm_asm!("csrw sepc, $0"::"r"(sepc)::"volatile");}
+        unsafe {csr_write!("sepc", sepc);}
     }
 }
 
@@ -93,8 +191,6 @@ pub mod sepc {
 /// contains supervisor trap value
 pub mod stval {
     pub fn read() -> usize {
-        let ret: usize;
-        unsafe {llvm_asm!("csrr $0, stval":"=r"(ret):::"volatile");}
-        ret
+        unsafe {csr_read!("stval")}
     }
 }
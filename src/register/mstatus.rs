@@ -4,17 +4,16 @@ use bit_field::BitField;
 
 #[inline]
 unsafe fn read() -> usize {
-    let ret: usize;
-    llvm_asm!("csrr $0, mstatus":"=r"(ret):::"volatile");
-    ret
+    csr_read!("mstatus")
 }
 
 #[inline]
 unsafe fn write(x: usize) {
-    llvm_asm!("csrw mstatus, $0"::"r"(x)::"volatile");
+    csr_write!("mstatus", x);
 }
 
 /// Machine Previous Privilege Mode
+#[allow(clippy::upper_case_acronyms)]
 pub enum MPP {
     User = 0,
     Supervisor = 1,
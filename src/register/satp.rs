@@ -2,16 +2,10 @@
 
 #[inline]
 pub fn read() -> usize {
-    let ret;
-    unsafe {
-        llvm_asm!("csrr $0, satp":"=r"(ret):::"volatile");
-    }
-    ret
+    unsafe {csr_read!("satp")}
 }
 
 #[inline]
 pub fn write(satp: usize) {
-    unsafe {
-        llvm_asm!("csrw satp, $0"::"r"(satp)::"volatile");
-    }
+    unsafe {csr_write!("satp", satp);}
 }
@@ -2,30 +2,47 @@
 
 const INTERRUPT: usize = 0x8000000000000000;
 const INTERRUPT_SUPERVISOR_SOFTWARE: usize = INTERRUPT + 1;
+const INTERRUPT_SUPERVISOR_TIMER: usize = INTERRUPT + 5;
 const INTERRUPT_SUPERVISOR_EXTERNAL: usize = INTERRUPT + 9;
 const EXCEPTION: usize = 0;
+const EXCEPTION_BREAKPOINT: usize = EXCEPTION + 3;
 const EXCEPTION_ECALL_USER: usize = EXCEPTION + 8;
+const EXCEPTION_INSTRUCTION_PAGE_FAULT: usize = EXCEPTION + 12;
+const EXCEPTION_LOAD_PAGE_FAULT: usize = EXCEPTION + 13;
+const EXCEPTION_STORE_PAGE_FAULT: usize = EXCEPTION + 15;
 
 pub enum ScauseType {
     Unknown,
     IntSSoft,
+    IntSTimer,
     IntSExt,
+    ExcBreakpoint,
     ExcUEcall,
+    ExcPageFault,
 }
 
 #[inline]
 pub fn read() -> usize {
-    let ret: usize;
-    unsafe {llvm_asm!("csrr $0, scause":"=r"(ret):::"volatile");}
-    ret
+    unsafe {csr_read!("scause")}
 }
 
 pub fn get_scause() -> ScauseType {
     let scause = read();
     match scause {
         INTERRUPT_SUPERVISOR_SOFTWARE => ScauseType::IntSSoft,
+        INTERRUPT_SUPERVISOR_TIMER => ScauseType::IntSTimer,
         INTERRUPT_SUPERVISOR_EXTERNAL => ScauseType::IntSExt,
+        EXCEPTION_BREAKPOINT => ScauseType::ExcBreakpoint,
         EXCEPTION_ECALL_USER => ScauseType::ExcUEcall,
+        EXCEPTION_INSTRUCTION_PAGE_FAULT
+        | EXCEPTION_LOAD_PAGE_FAULT
+        | EXCEPTION_STORE_PAGE_FAULT => ScauseType::ExcPageFault,
         _ => ScauseType::Unknown,
     }
 }
+
+/// Whether the page fault is of a load or a store, not of an instruction fetch
+#[inline]
+pub fn is_load_store_fault() -> bool {
+    matches!(read(), EXCEPTION_LOAD_PAGE_FAULT | EXCEPTION_STORE_PAGE_FAULT)
+}
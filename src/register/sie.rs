@@ -6,14 +6,12 @@ const SEIE: usize = 1 << 9; // external
 
 #[inline]
 unsafe fn read() -> usize {
-    let ret: usize;
-    llvm_asm!("csrr $0, sie":"=r"(ret):::"volatile");
-    ret
+    csr_read!("sie")
 }
 
 #[inline]
 unsafe fn write(x: usize) {
-    llvm_asm!("csrw sie, $0"::"r"(x)::"volatile");
+    csr_write!("sie", x);
 }
 
 /// enable all software interrupts
@@ -4,14 +4,12 @@ const SSIP: usize = 1 << 1;
 
 #[inline]
 unsafe fn read() -> usize {
-    let ret: usize;
-    llvm_asm!("csrr $0, sip":"=r"(ret):::"volatile");
-    ret
+    csr_read!("sip")
 }
 
 #[inline]
 unsafe fn write(x: usize) {
-    llvm_asm!("csrw sip, $0"::"r"(x)::"volatile");
+    csr_write!("sip", x);
 }
 
 pub fn clear_ssip() {
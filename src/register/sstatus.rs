@@ -3,17 +3,16 @@
 const SIE: usize = 1 << 1;  // supervisor interrupt enable
 const SPIE: usize = 1 << 5; // supervisor previous interrupt enable
 const SPP: usize = 1 << 8;  // previous mode, is from supervisor?
+const SUM: usize = 1 << 18; // supervisor may access user memory
 
 #[inline]
 pub fn read() -> usize {
-    let ret: usize;
-    unsafe {llvm_asm!("csrr $0, sstatus":"=r"(ret):::"volatile");}
-    ret
+    unsafe {csr_read!("sstatus")}
 }
 
 #[inline]
 pub fn write(x: usize) {
-    unsafe {llvm_asm!("csrw sstatus, $0"::"r"(x)::"volatile");}
+    unsafe {csr_write!("sstatus", x);}
 }
 
 /// set SIE to enable device interrupts
@@ -36,6 +35,20 @@ pub fn intr_get() -> bool {
     (x & SIE) != 0
 }
 
+/// Keep supervisor mode off pages with PTE_U.
+/// The kernel page table does not map user memory anyway,
+/// copy_in and copy_out in pagetable.rs go through the direct map,
+/// so a kernel access through a user pointer faults either way.
+#[inline]
+pub fn sum_clear() {
+    write(read() & !SUM);
+}
+
+#[inline]
+pub fn sum_get() -> bool {
+    (read() & SUM) != 0
+}
+
 /// check is the previous mode from supervisor
 #[inline]
 pub fn is_from_supervisor() -> bool {
@@ -58,3 +71,11 @@ pub fn user_ret_prepare() {
     x |= SPIE;
     write(x);
 }
+
+/// Prepare to enter the guest in virtual supervisor mode,
+/// the rest is in hyp.rs: set SPP to 1 for supervisor mode
+#[cfg(feature = "hypervisor")]
+#[inline]
+pub fn guest_ret_prepare() {
+    write(read() | SPP);
+}
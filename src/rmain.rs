@@ -1,55 +1,142 @@
-use core::sync::atomic::{AtomicBool, Ordering};
+use core::sync::atomic::{AtomicUsize, Ordering};
 
-use crate::driver::virtio::disk_init;
+use crate::cmdline;
+use crate::console::fbcon;
+use crate::consts::HAS_VIRTIO;
+use crate::driver::{virtio::disk_init, virtio_9p, virtio_balloon, virtio_gpu, virtio_rng};
+use crate::dtb;
 use crate::fs;
 use crate::mm::{kinit, kvm_init, kvm_init_hart};
+use crate::once::Once;
 use crate::plic;
-use crate::process::{cpu_id, my_cpu, PROC_MANAGER};
+use crate::printf;
+use crate::process::{cpu_id, set_preempt_kernel, PROC_MANAGER};
+use crate::proclog;
+use crate::register::wfi;
 use crate::trap::trap_init_hart;
 
-/// Used by hart 0 to communicate with other harts.
-/// When hart 0 finished some initial work,
-/// it sets started to true to tell other harts that they can run
-///
-/// note: actually a simple Bool would be enough,
-///     because it is only written once, but just...
-static STARTED: AtomicBool = AtomicBool::new(false);
-
-/// start() jumps here in supervisor mode on all CPUs.
-pub unsafe fn rust_main() -> ! {
-    if cpu_id() == 0 {
-        crate::console::consoleinit();
-        println!();
-        println!("xv6-riscv-rust is booting");
-        println!();
-        kinit();
-        kvm_init(); // init kernel page table
-        PROC_MANAGER.proc_init(); // process table
-        kvm_init_hart(); // trun on paging
-        trap_init_hart(); // install kernel trap vector
-        plic::init();
-        plic::init_hart();
-        fs::binit(); // buffer cache
-        disk_init(); // emulated hard disk
-        PROC_MANAGER.user_init(); // first user process
+/// The one-time setup, done by whichever hart gets to rust_main() first,
+/// the boot hart. The other harts wait in call_once() until it is done,
+/// e.g., all of them race for it in machine mode, or the boot hart
+/// under OpenSBI may be any of them.
+static INIT: Once = Once::new();
 
-        STARTED.store(true, Ordering::SeqCst);
-    } else {
-        while !STARTED.load(Ordering::SeqCst) {}
+/// The hart that did the setup, it also keeps the time, see trap.rs
+static BOOT_HART: AtomicUsize = AtomicUsize::new(0);
 
-        println!("hart {} starting", cpu_id());
+pub fn boot_hart() -> usize {
+    BOOT_HART.load(Ordering::Relaxed)
+}
+
+/// start() jumps here in supervisor mode on all CPUs,
+/// with the device tree address passed in a0.
+pub unsafe extern "C" fn rust_main(dtb: usize) -> ! {
+    let id = cpu_id();
+    if !INIT.call_once(|| init(id, dtb)) {
+        if dtb::harts() & (1 << id) == 0 {
+            // e.g., nosmp, or disabled in the device tree
+            loop {
+                wfi();
+            }
+        }
+        println!("hart {} starting", id);
         kvm_init_hart(); // turn on paging
         trap_init_hart(); // install kernel trap vector
         plic::init_hart(); // ask PLIC for device interrupts
+        crate::watch::init_hart(false); // no hardware triggers left set
+
+        #[cfg(feature = "unit_test")]
+        crate::test::run();
 
         // LTODO - init other things
-        loop {}
+        #[cfg(not(feature = "unit_test"))]
+        loop {
+            wfi();
+        }
     }
 
     #[cfg(feature = "unit_test")]
-    super::test_main_entry();
+    crate::test::run();
 
     // each cpu's lifetime start here?
-    let c = my_cpu();
-    c.scheduler();
+    #[cfg(not(feature = "unit_test"))]
+    {
+        let c = crate::process::my_cpu();
+        c.scheduler();
+    }
+}
+
+unsafe fn init(id: usize, dtb: usize) {
+    BOOT_HART.store(id, Ordering::Relaxed);
+    // install the kernel trap vector right away,
+    // so that faults during early setup are reported by the early console
+    trap_init_hart();
+    crate::console::consoleinit();
+    println!();
+    println!("xv6-riscv-rust is booting on hart {}", id);
+    println!();
+    dtb::init(dtb); // harts, RAM and the command line, before paging hides the tree
+    options(id);
+    kinit();
+    kvm_init(); // init kernel page table
+    PROC_MANAGER.proc_init(); // process table
+    kvm_init_hart(); // trun on paging
+    trap_init_hart(); // install kernel trap vector
+    #[cfg(feature = "fu740")]
+    crate::driver::l2cache::init(); // all of the L2 as cache
+    #[cfg(feature = "gdbstub")]
+    crate::gdbstub::init(); // wait for gdb on the second uart
+    plic::init();
+    plic::init_hart();
+    crate::watch::init_hart(true); // count the hardware triggers
+    fs::binit(); // buffer cache
+    if HAS_VIRTIO {
+        disk_init(); // emulated hard disk
+        if virtio_gpu::init() { // optional display
+            fbcon::init(); // framebuffer console
+        }
+        virtio_9p::init(); // optional host directory
+        virtio_balloon::init(); // optional memory balloon
+        virtio_rng::init(); // optional entropy source
+    }
+    #[cfg(feature = "selftest")]
+    crate::selftest::run(); // quick invariant checks
+
+    // unit tests run kernel threads instead,
+    // and without a disk there is no init to run
+    #[cfg(not(feature = "unit_test"))]
+    if HAS_VIRTIO {
+        PROC_MANAGER.user_init(); // first user process
+    } else {
+        println!("no disk on this board, no user processes");
+    }
+
+    #[cfg(feature = "sbi")]
+    crate::start::start_harts(dtb); // the others are still stopped
+}
+
+/// Apply the command-line options that matter this early, see cmdline.rs
+fn options(id: usize) {
+    if !cmdline::line().is_empty() {
+        println!("command line: {}", cmdline::line());
+    }
+    match cmdline::get("loglevel") {
+        Some("0") => printf::set_quiet(true),
+        Some(level) if level.parse::<usize>().is_err() => {
+            println!("loglevel={} is not a number, ignored", level);
+        }
+        _ => {}
+    }
+    if cmdline::has("quiet") {
+        printf::set_quiet(true);
+    }
+    if cmdline::has("nopreempt") {
+        set_preempt_kernel(false);
+    }
+    if cmdline::has("proclog") {
+        proclog::set_enabled(true);
+    }
+    if cmdline::has("nosmp") {
+        dtb::set_harts(1 << id);
+    }
 }
@@ -0,0 +1,94 @@
+//! Supervisor Binary Interface calls, only with the sbi feature,
+//! where OpenSBI stays in machine mode and enters the kernel in supervisor mode.
+//! Refer to the RISC-V SBI specification (v0.2 or later) for detail.
+//!
+//! The extension id goes in a7, the function id in a6, arguments in a0-a5,
+//! and the error and value come back in a0 and a1.
+//! Legacy extensions only return a value in a0.
+
+use core::arch::asm;
+
+const EID_CONSOLE_PUTCHAR: usize = 0x01;
+const EID_CONSOLE_GETCHAR: usize = 0x02;
+const EID_TIME: usize = 0x54494d45; // "TIME"
+const EID_IPI: usize = 0x735049; // "sPI"
+const EID_HSM: usize = 0x48534d; // "HSM"
+const EID_SRST: usize = 0x53525354; // "SRST"
+
+const FID_SET_TIMER: usize = 0;
+const FID_SEND_IPI: usize = 0;
+const FID_HART_START: usize = 0;
+const FID_SYSTEM_RESET: usize = 0;
+
+const RESET_COLD_REBOOT: usize = 1;
+const RESET_SYSTEM_FAILURE: usize = 1;
+
+#[inline]
+fn call(eid: usize, fid: usize, a0: usize, a1: usize, a2: usize) -> Result<usize, isize> {
+    let error: isize;
+    let value: usize;
+    unsafe {
+        asm!("ecall",
+            inlateout("a0") a0 => error,
+            inlateout("a1") a1 => value,
+            in("a2") a2,
+            in("a6") fid,
+            in("a7") eid,
+            options(nostack));
+    }
+    if error == 0 {
+        Ok(value)
+    } else {
+        Err(error)
+    }
+}
+
+#[inline]
+fn legacy(eid: usize, a0: usize) -> isize {
+    let ret: isize;
+    unsafe {
+        asm!("ecall",
+            inlateout("a0") a0 => ret,
+            in("a7") eid,
+            options(nostack));
+    }
+    ret
+}
+
+/// Ask for a supervisor timer interrupt when the time csr reaches stime,
+/// this also clears the pending one.
+pub fn set_timer(stime: u64) {
+    let _ = call(EID_TIME, FID_SET_TIMER, stime as usize, 0, 0);
+}
+
+/// Raise a supervisor software interrupt on each hart in hart_mask,
+/// bit i standing for hart i.
+pub fn send_ipi(hart_mask: usize) {
+    let _ = call(EID_IPI, FID_SEND_IPI, hart_mask, 0, 0);
+}
+
+/// Start a stopped hart at start_addr in supervisor mode,
+/// with its hart id in a0 and opaque in a1, paging off.
+/// Fails for harts that do not exist or are already running.
+pub fn hart_start(hartid: usize, start_addr: usize, opaque: usize) -> Result<(), isize> {
+    call(EID_HSM, FID_HART_START, hartid, start_addr, opaque).map(|_| ())
+}
+
+/// Reboot the machine after a system failure, e.g., a panic.
+/// Returns if the firmware has no SRST extension.
+pub fn system_reset() {
+    let _ = call(EID_SRST, FID_SYSTEM_RESET, RESET_COLD_REBOOT, RESET_SYSTEM_FAILURE, 0);
+}
+
+/// Write a byte to the firmware's console
+pub fn console_putchar(c: u8) {
+    legacy(EID_CONSOLE_PUTCHAR, c as usize);
+}
+
+/// Read a byte from the firmware's console, None if there is none
+pub fn console_getchar() -> Option<u8> {
+    match legacy(EID_CONSOLE_GETCHAR, 0) {
+        c if c >= 0 => Some(c as u8),
+        _ => None,
+    }
+}
@@ -0,0 +1,150 @@
+//! Scheduler event tracing
+//!
+//! When enabled, the scheduler records every decision into the ring of its hart:
+//! a process switched in (SCHED_RUN), or switched out by yielding,
+//! blocking in sleep, or exiting, and every wakeup of a sleeping process.
+//! Each event carries the CLINT mtime, so latencies between harts line up,
+//! and a global sequence number that orders events with the same time.
+//! The schedtrace syscall starts, stops and copies out the events in order,
+//! see user/src/bin/schedtrace.rs.
+//!
+//! mtime is read instead of ticks(), which would put events of a tick in one lump.
+
+use core::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+
+use crate::consts::{NCPU, NSCHEDTRACE};
+use crate::process::cpu_id;
+use crate::register::clint;
+use crate::spinlock::{pop_off, push_off};
+
+/// the scheduler switched to process to, from is whoever ran last on this hart
+pub const SCHED_RUN: u32 = 0;
+/// process from gave up the cpu, e.g., at a timer tick
+pub const SCHED_YIELD: u32 = 1;
+/// process from went to sleep
+pub const SCHED_BLOCK: u32 = 2;
+/// process from exited
+pub const SCHED_EXIT: u32 = 3;
+/// process to was woken up by process from, 0 if none was running
+pub const SCHED_WAKEUP: u32 = 4;
+
+/// One event, as copied out to user space
+#[repr(C)]
+#[derive(Clone, Copy)]
+pub struct SchedEvent {
+    pub time: u64,
+    pub hart: u32,
+    pub reason: u32,
+    pub from: u32,
+    pub to: u32,
+}
+
+impl SchedEvent {
+    pub const fn empty() -> Self {
+        Self { time: 0, hart: 0, reason: 0, from: 0, to: 0 }
+    }
+}
+
+struct Ring {
+    events: [(usize, SchedEvent); NSCHEDTRACE],
+    // total events ever recorded, the write index is n % NSCHEDTRACE
+    n: usize,
+    // pid last switched out on this hart
+    last: u32,
+}
+
+static mut RINGS: [Ring; NCPU] = [const { Ring {
+    events: [(0, SchedEvent::empty()); NSCHEDTRACE],
+    n: 0,
+    last: 0,
+} }; NCPU];
+
+static ENABLED: AtomicBool = AtomicBool::new(false);
+static SEQ: AtomicUsize = AtomicUsize::new(0);
+
+/// Record an event on the current hart.
+/// For SCHED_RUN, from is ignored and taken from the last switch out.
+pub fn record(reason: u32, from: usize, to: usize) {
+    if !ENABLED.load(Ordering::Relaxed) {
+        return;
+    }
+    push_off();
+    let hart = unsafe { cpu_id() };
+    let ring = unsafe { &mut RINGS[hart] };
+    let from = match reason {
+        SCHED_RUN => ring.last,
+        SCHED_WAKEUP => from as u32,
+        _ => {
+            ring.last = from as u32;
+            from as u32
+        }
+    };
+    let event = SchedEvent {
+        time: unsafe { clint::read_mtime() },
+        hart: hart as u32,
+        reason,
+        from,
+        to: to as u32,
+    };
+    ring.events[ring.n % NSCHEDTRACE] = (SEQ.fetch_add(1, Ordering::Relaxed), event);
+    ring.n += 1;
+    pop_off();
+}
+
+/// Clear the rings and start recording
+pub fn start() {
+    ENABLED.store(false, Ordering::SeqCst);
+    for ring in unsafe { RINGS.iter_mut() } {
+        ring.n = 0;
+        ring.last = 0;
+    }
+    ENABLED.store(true, Ordering::SeqCst);
+}
+
+pub fn stop() {
+    ENABLED.store(false, Ordering::SeqCst);
+}
+
+/// Position in the merged rings, for copying them out in several chunks
+pub struct Cursor {
+    next: [usize; NCPU],
+}
+
+impl Cursor {
+    /// Start at the oldest event still in each ring
+    pub fn new() -> Self {
+        let mut next = [0usize; NCPU];
+        for (hart, ring) in unsafe { RINGS.iter() }.enumerate() {
+            next[hart] = ring.n.saturating_sub(NSCHEDTRACE);
+        }
+        Self { next }
+    }
+
+    /// Fill out with the following events of all harts, oldest first,
+    /// return the number filled, 0 when all are done.
+    /// Meant to be called after stop(), so the rings hold still.
+    pub fn fill(&mut self, out: &mut [SchedEvent]) -> usize {
+        let rings = unsafe { &RINGS };
+        let mut filled = 0;
+        while filled < out.len() {
+            let mut oldest: Option<(usize, usize)> = None;
+            for (hart, ring) in rings.iter().enumerate() {
+                if self.next[hart] < ring.n {
+                    let seq = ring.events[self.next[hart] % NSCHEDTRACE].0;
+                    if oldest.is_none_or(|(_, o)| seq < o) {
+                        oldest = Some((hart, seq));
+                    }
+                }
+            }
+            match oldest {
+                Some((hart, _)) => {
+                    out[filled] = rings[hart].events[self.next[hart] % NSCHEDTRACE].1;
+                    self.next[hart] += 1;
+                    filled += 1;
+                }
+                None => break,
+            }
+        }
+        filled
+    }
+}
@@ -0,0 +1,117 @@
+//! Boot-time self-test, only built with the selftest feature
+//!
+//! rust_main runs these quick invariant checks on the boot hart,
+//! after the kernel is initialized and before the other harts and init start,
+//! and prints a summary.
+//! They catch toolchain and layout regressions right at boot,
+//! a failing check panics after the summary.
+
+use core::convert::TryFrom;
+use core::ptr;
+
+use crate::consts::PGSIZE;
+use crate::mm::{kalloc, kfree, Addr, Box, PageTable, PhysAddr, PteFlag, VirtAddr};
+use crate::process::selftest;
+
+/// A check, failing with what went wrong
+type Check = fn() -> Result<(), &'static str>;
+
+const CHECKS: &[(&str, Check)] = &[
+    ("kalloc exhaustion", kalloc_exhaustion),
+    ("page table round trip", pagetable_round_trip),
+    ("trapframe layout", selftest::trapframe_layout),
+    ("context layout", selftest::context_layout),
+    ("context switch", selftest::swtch_smoke),
+    // last, so that it also finds pages the others leaked
+    ("kalloc restored", kalloc_restored),
+];
+
+static mut FREE_PAGES: usize = 0;
+
+/// Take every free page, chaining them through their first word,
+/// then give them all back, return how many there were.
+fn drain() -> usize {
+    let mut head: *mut usize = ptr::null_mut();
+    let mut n = 0;
+    while let Some(pa) = unsafe { kalloc() } {
+        let page = pa as *mut usize;
+        unsafe { *page = head as usize; }
+        head = page;
+        n += 1;
+    }
+    while !head.is_null() {
+        let next = unsafe { *head } as *mut usize;
+        unsafe { kfree(head as *mut u8); }
+        head = next;
+    }
+    n
+}
+
+fn kalloc_exhaustion() -> Result<(), &'static str> {
+    let n = drain();
+    if n == 0 {
+        return Err("no free pages");
+    }
+    if unsafe { kalloc() }.map(|pa| unsafe { kfree(pa) }).is_none() {
+        return Err("kalloc fails after restoring");
+    }
+    if drain() != n {
+        return Err("free page count changed");
+    }
+    unsafe { FREE_PAGES = n; }
+    Ok(())
+}
+
+fn kalloc_restored() -> Result<(), &'static str> {
+    let n = drain();
+    if n != unsafe { FREE_PAGES } {
+        println!("  {} free pages, {} at start", n, unsafe { FREE_PAGES });
+        return Err("self-tests leaked pages");
+    }
+    Ok(())
+}
+
+/// Map a page, look it up, unmap it, and free the page-table pages
+fn pagetable_round_trip() -> Result<(), &'static str> {
+    let mut pagetable = Box::<PageTable>::new().ok_or("no page for the page table")?;
+    pagetable.clear();
+    let pa = unsafe { kalloc() }.ok_or("no page to map")?;
+
+    // somewhere needing all three levels
+    let va = VirtAddr::try_from(0x40_0000_0000 - 16 * PGSIZE).unwrap();
+    let ret = (|| {
+        pagetable.map_pages(va, PGSIZE, PhysAddr::try_from(pa as usize).unwrap(), PteFlag::R | PteFlag::W)?;
+        match pagetable.walk(va) {
+            Some(pte) if pte.is_valid() && pte.as_phys_addr().as_usize() == pa as usize => {}
+            _ => return Err("walk does not find the mapping"),
+        }
+        pagetable.unmap_pages(va, 1, false)?;
+        match pagetable.walk(va) {
+            Some(pte) if pte.is_valid() => Err("still mapped after unmap"),
+            _ => Ok(()),
+        }
+    })();
+
+    pagetable.free_walk();
+    unsafe { kfree(pa); }
+    ret
+}
+
+/// Run all checks and print a summary, panic if any failed
+pub fn run() {
+    println!("selftest: running {} checks", CHECKS.len());
+    let mut failed = 0;
+    for (name, check) in CHECKS.iter() {
+        match check() {
+            Ok(()) => println!("selftest {} ... ok", name),
+            Err(why) => {
+                println!("selftest {} ... FAILED: {}", name, why);
+                failed += 1;
+            }
+        }
+    }
+    println!("selftest: {} passed, {} failed", CHECKS.len() - failed, failed);
+    if failed > 0 {
+        panic!("selftest: {} checks failed", failed);
+    }
+}
@@ -0,0 +1,159 @@
+//! Sleep locks, for what is held across disk I/O, e.g., a buffer or an inode
+//!
+//! A process waiting for one sleeps instead of spinning, see Proc::sleep,
+//! and the holder may sleep too, with interrupts on, so only a process takes one,
+//! never an interrupt handler or the scheduler, and never holding a spinlock.
+//! Whether it is held, and by whom, is under a spinlock of its own,
+//! the waiters sleep on the address of the SleepLock.
+
+use core::cell::UnsafeCell;
+use core::ops::{Deref, DerefMut, Drop};
+
+use crate::process::{my_pid, my_proc, PROC_MANAGER};
+use crate::spinlock::SpinLock;
+
+pub struct SleepLock<T: ?Sized> {
+    /// the pid of the holder, 0 if it is free
+    holder: SpinLock<usize>,
+    name: &'static str,
+    data: UnsafeCell<T>,
+}
+
+unsafe impl<T: ?Sized + Send> Sync for SleepLock<T> {}
+
+impl<T> SleepLock<T> {
+    pub const fn new(user_data: T, name: &'static str) -> SleepLock<T> {
+        SleepLock {
+            holder: SpinLock::new(0, "sleeplock"),
+            name,
+            data: UnsafeCell::new(user_data),
+        }
+    }
+}
+
+impl<T: ?Sized> SleepLock<T> {
+    /// Lock it, sleeping until it is free, and return a guard, like SpinLock::lock
+    pub fn lock(&self) -> SleepLockGuard<'_, T> {
+        let p = unsafe { my_proc() };
+        let mut holder = self.holder.lock();
+        while *holder != 0 {
+            holder = p.sleep(self.chan(), holder);
+        }
+        *holder = p.pid;
+        drop(holder);
+        SleepLockGuard {
+            sleep_lock: self,
+            data: unsafe { &mut *self.data.get() },
+        }
+    }
+
+    /// Whether the current process holds it
+    pub fn holding(&self) -> bool {
+        let holder = *self.holder.lock();
+        holder != 0 && Some(holder) == unsafe { my_pid() }
+    }
+
+    /// The data, not locking it, there being no one else to hold it
+    pub fn get_mut(&mut self) -> &mut T {
+        self.data.get_mut()
+    }
+
+    pub fn name(&self) -> &'static str {
+        self.name
+    }
+
+    fn unlock(&self) {
+        let mut holder = self.holder.lock();
+        *holder = 0;
+        unsafe { PROC_MANAGER.wakeup(self.chan()); }
+        drop(holder);
+    }
+
+    fn chan(&self) -> usize {
+        self as *const Self as *const u8 as usize
+    }
+}
+
+pub struct SleepLockGuard<'a, T: ?Sized + 'a> {
+    sleep_lock: &'a SleepLock<T>,
+    data: &'a mut T,
+}
+
+impl<'a, T: ?Sized> Deref for SleepLockGuard<'a, T> {
+    type Target = T;
+    fn deref(&self) -> &T {
+        &*self.data
+    }
+}
+
+impl<'a, T: ?Sized> DerefMut for SleepLockGuard<'a, T> {
+    fn deref_mut(&mut self) -> &mut T {
+        &mut *self.data
+    }
+}
+
+impl<'a, T: ?Sized> Drop for SleepLockGuard<'a, T> {
+    /// Release the lock, and wake up the processes waiting for it
+    fn drop(&mut self) {
+        self.sleep_lock.unlock();
+    }
+}
+
+#[cfg(feature = "unit_test")]
+pub mod tests {
+    use core::sync::atomic::{AtomicBool, Ordering};
+
+    use crate::process::{cpu_id, my_cpu};
+    use crate::rmain::boot_hart;
+    use super::*;
+
+    const ROUNDS: usize = 50;
+
+    static COUNTER: SleepLock<usize> = SleepLock::new(0, "counter");
+    static STARTED: AtomicBool = AtomicBool::new(false);
+    static DONE: AtomicBool = AtomicBool::new(false);
+
+    /// Bump the counter, giving up the hart while holding the lock in between,
+    /// return the last count
+    fn bump(_: usize) -> i32 {
+        let mut last = 0;
+        for _ in 0..ROUNDS {
+            let mut counter = COUNTER.lock();
+            assert!(COUNTER.holding());
+            let seen = *counter;
+            unsafe { my_cpu().yielding(); }
+            *counter = seen + 1;
+            last = *counter;
+        }
+        last as i32
+    }
+
+    /// Two kernel threads each holding the lock across a yield lose no update
+    pub fn sleeplock_exclusion() {
+        if unsafe { cpu_id() } == boot_hart() {
+            let pids = [0, 1].map(|_| unsafe { PROC_MANAGER.spawn_kthread(b"bump", bump, 0) }
+                .expect("sleeplock_exclusion: no free proc"));
+            STARTED.store(true, Ordering::SeqCst);
+            let mut last = 0;
+            for pid in pids.iter() {
+                loop {
+                    if let Some(status) = unsafe { PROC_MANAGER.reap_orphan(*pid) } {
+                        last = last.max(status as usize);
+                        break;
+                    }
+                    unsafe { my_cpu().schedule_once(); }
+                }
+            }
+            DONE.store(true, Ordering::SeqCst);
+            assert_eq!(last, 2 * ROUNDS);
+        } else {
+            while !STARTED.load(Ordering::SeqCst) {
+                core::hint::spin_loop();
+            }
+            while !DONE.load(Ordering::SeqCst) {
+                unsafe { my_cpu().schedule_once(); }
+            }
+        }
+    }
+    crate::kernel_test!(sleeplock_exclusion, smp);
+}
@@ -0,0 +1,383 @@
+//! Local stream sockets, like AF_UNIX SOCK_STREAM
+//!
+//! A server binds a socket to a path and listens on it,
+//! a client connects to the path, which pairs it with a new socket
+//! waiting in the listener's backlog until accept() hands it out.
+//! Each socket of a pair has a ring of SOCKBUF bytes that its peer sends into,
+//! send sleeps while the peer's ring is full, and recv while its own is empty,
+//! recv returns 0 once the peer closed and the ring is drained.
+//! sendmsg and recvmsg gather and scatter over several buffers,
+//! there are no control messages, so no passing of file descriptors.
+//! Whether a socket blocks is the O_NONBLOCK of its File, see fs/file.rs,
+//! passed down to what would sleep, which then fails with "would block" instead,
+//! or returns what was sent when only some could be.
+//!
+//! The sockets are referred to by their index in the table,
+//! each is behind a File, see sockalloc() in fs/file.rs, whose descriptor the socket syscalls take,
+//! read and write on it are recv and send, its last close closes it.
+//! The paths they bind to are names in this table, not inodes of the file system.
+
+use core::cmp::min;
+
+use crate::consts::{MAXPATH, NSOCKET, SOCKBUF};
+use crate::process::{my_proc, PROC_MANAGER};
+use crate::spinlock::{SpinLock, SpinLockGuard};
+
+/// socket flags, SOCK_NONBLOCK makes its File O_NONBLOCK, see sys_socket
+pub const SOCK_NONBLOCK: usize = 1 << 0;
+
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+enum State {
+    Free,
+    /// created, neither bound nor connected
+    Open,
+    Listening,
+    /// the server side of a connection not yet accepted from listener
+    Pending(usize),
+    Connected,
+}
+
+/// Bytes sent by the peer, oldest at r
+struct Ring {
+    buf: [u8; SOCKBUF],
+    r: usize,
+    n: usize,
+}
+
+impl Ring {
+    const fn new() -> Self {
+        Self { buf: [0; SOCKBUF], r: 0, n: 0 }
+    }
+
+    fn put(&mut self, src: &[u8]) -> usize {
+        let n = min(src.len(), SOCKBUF - self.n);
+        for (i, b) in src[..n].iter().enumerate() {
+            self.buf[(self.r + self.n + i) % SOCKBUF] = *b;
+        }
+        self.n += n;
+        n
+    }
+
+    fn get(&mut self, dst: &mut [u8]) -> usize {
+        let n = min(dst.len(), self.n);
+        for (i, b) in dst[..n].iter_mut().enumerate() {
+            *b = self.buf[(self.r + i) % SOCKBUF];
+        }
+        self.r = (self.r + n) % SOCKBUF;
+        self.n -= n;
+        n
+    }
+}
+
+struct Socket {
+    state: State,
+    path: [u8; MAXPATH],
+    path_len: usize,
+    /// the other end, None once it closed
+    peer: Option<usize>,
+    /// order of connections in the backlog
+    seq: usize,
+    rx: Ring,
+}
+
+impl Socket {
+    const fn new() -> Self {
+        Self {
+            state: State::Free,
+            path: [0; MAXPATH],
+            path_len: 0,
+            peer: None,
+            seq: 0,
+            rx: Ring::new(),
+        }
+    }
+
+    fn path(&self) -> &[u8] {
+        &self.path[..self.path_len]
+    }
+}
+
+struct Sockets {
+    table: [Socket; NSOCKET],
+    seq: usize,
+}
+
+static SOCKETS: SpinLock<Sockets> = SpinLock::new(Sockets {
+    table: [const { Socket::new() }; NSOCKET],
+    seq: 0,
+}, "socket");
+
+/// Processes waiting on socket id sleep here
+fn chan(s: &Sockets, id: usize) -> usize {
+    &s.table[id] as *const Socket as usize
+}
+
+fn wakeup(s: &Sockets, id: usize) {
+    unsafe { PROC_MANAGER.wakeup(chan(s, id)); }
+}
+
+/// Sleep until socket id changes, fail if the process was killed,
+/// or at once if nonblock
+fn sleep<'a>(id: usize, guard: SpinLockGuard<'a, Sockets>, nonblock: bool)
+    -> Result<SpinLockGuard<'a, Sockets>, &'static str>
+{
+    if nonblock {
+        return Err("would block");
+    }
+    let p = unsafe { my_proc() };
+    if p.killed {
+        return Err("killed");
+    }
+    let chan = chan(&guard, id);
+    Ok(p.sleep(chan, guard))
+}
+
+fn check(s: &Sockets, id: usize, state: State) -> Result<(), &'static str> {
+    match s.table.get(id) {
+        Some(sock) if sock.state == state => Ok(()),
+        Some(sock) if sock.state != State::Free => Err("socket in the wrong state"),
+        _ => Err("no such socket"),
+    }
+}
+
+fn alloc(s: &mut Sockets, state: State) -> Result<usize, &'static str> {
+    let id = s.table.iter().position(|sock| sock.state == State::Free).ok_or("out of sockets")?;
+    s.table[id] = Socket::new();
+    s.table[id].state = state;
+    Ok(id)
+}
+
+/// Create a socket
+pub fn socket() -> Result<usize, &'static str> {
+    let mut s = SOCKETS.lock();
+    alloc(&mut s, State::Open)
+}
+
+/// Name socket id by path, which no other socket has
+pub fn bind(id: usize, path: &[u8]) -> Result<(), &'static str> {
+    let mut s = SOCKETS.lock();
+    check(&s, id, State::Open)?;
+    if path.is_empty() || path.len() > MAXPATH {
+        return Err("bad path");
+    }
+    if s.table.iter().any(|sock| sock.state != State::Free && sock.path() == path) {
+        return Err("path in use");
+    }
+    let sock = &mut s.table[id];
+    sock.path[..path.len()].copy_from_slice(path);
+    sock.path_len = path.len();
+    Ok(())
+}
+
+/// Take connections to the path socket id is bound to
+pub fn listen(id: usize) -> Result<(), &'static str> {
+    let mut s = SOCKETS.lock();
+    check(&s, id, State::Open)?;
+    if s.table[id].path_len == 0 {
+        return Err("not bound");
+    }
+    s.table[id].state = State::Listening;
+    Ok(())
+}
+
+/// Connect socket id to the one listening on path,
+/// it can send at once, before the server accepts.
+pub fn connect(id: usize, path: &[u8]) -> Result<(), &'static str> {
+    let mut s = SOCKETS.lock();
+    check(&s, id, State::Open)?;
+    let listener = s.table.iter()
+        .position(|sock| sock.state == State::Listening && sock.path() == path)
+        .ok_or("connection refused")?;
+    let server = alloc(&mut s, State::Pending(listener))?;
+    s.seq += 1;
+    let seq = s.seq;
+    s.table[server].seq = seq;
+    s.table[server].peer = Some(id);
+    s.table[id].peer = Some(server);
+    s.table[id].state = State::Connected;
+    wakeup(&s, listener);
+    Ok(())
+}
+
+/// Wait for a connection to listening socket id, unless nonblock,
+/// return the server's socket of the oldest one.
+pub fn accept(id: usize, nonblock: bool) -> Result<usize, &'static str> {
+    let mut s = SOCKETS.lock();
+    loop {
+        check(&s, id, State::Listening)?;
+        let oldest = s.table.iter().enumerate()
+            .filter(|(_, sock)| sock.state == State::Pending(id))
+            .min_by_key(|(_, sock)| sock.seq)
+            .map(|(server, _)| server);
+        if let Some(server) = oldest {
+            s.table[server].state = State::Connected;
+            return Ok(server);
+        }
+        s = sleep(id, s, nonblock)?;
+    }
+}
+
+/// Send all of src to the peer, return its length
+pub fn send(id: usize, src: &[u8], nonblock: bool) -> Result<usize, &'static str> {
+    sendmsg(id, &[src], nonblock)
+}
+
+/// Receive at least a byte into dst, return how many, 0 once the peer closed
+pub fn recv(id: usize, dst: &mut [u8], nonblock: bool) -> Result<usize, &'static str> {
+    recvmsg(id, &mut [dst], nonblock)
+}
+
+/// Send the buffers one after the other, return how many bytes that was
+pub fn sendmsg(id: usize, bufs: &[&[u8]], nonblock: bool) -> Result<usize, &'static str> {
+    let mut s = SOCKETS.lock();
+    check(&s, id, State::Connected)?;
+    let mut sent = 0;
+    for buf in bufs.iter() {
+        let mut done = 0;
+        while done < buf.len() {
+            let peer = s.table[id].peer.ok_or("connection closed")?;
+            let n = s.table[peer].rx.put(&buf[done..]);
+            done += n;
+            if n != 0 {
+                wakeup(&s, peer);
+            } else if nonblock && sent + done != 0 {
+                return Ok(sent + done);
+            } else {
+                s = sleep(id, s, nonblock)?;
+            }
+        }
+        sent += done;
+    }
+    Ok(sent)
+}
+
+/// Fill the buffers one after the other with what has arrived,
+/// waiting only while nothing has, return how many bytes that was
+pub fn recvmsg(id: usize, bufs: &mut [&mut [u8]], nonblock: bool) -> Result<usize, &'static str> {
+    let mut s = SOCKETS.lock();
+    check(&s, id, State::Connected)?;
+    while s.table[id].rx.n == 0 && s.table[id].peer.is_some() && bufs.iter().any(|buf| !buf.is_empty()) {
+        s = sleep(id, s, nonblock)?;
+        check(&s, id, State::Connected)?;
+    }
+    let mut got = 0;
+    for buf in bufs.iter_mut() {
+        got += s.table[id].rx.get(buf);
+    }
+    if let Some(peer) = s.table[id].peer {
+        // the peer may be waiting for room
+        wakeup(&s, peer);
+    }
+    Ok(got)
+}
+
+/// Close socket id, its peer gets the end of its stream,
+/// the connections a listener has not accepted are closed with it.
+pub fn close(id: usize) -> Result<(), &'static str> {
+    let mut s = SOCKETS.lock();
+    if s.table.get(id).is_none_or(|sock| sock.state == State::Free) {
+        return Err("no such socket");
+    }
+    if s.table[id].state == State::Listening {
+        for server in 0..NSOCKET {
+            if s.table[server].state == State::Pending(id) {
+                release(&mut s, server);
+            }
+        }
+    }
+    release(&mut s, id);
+    Ok(())
+}
+
+fn release(s: &mut Sockets, id: usize) {
+    if let Some(peer) = s.table[id].peer.take() {
+        s.table[peer].peer = None;
+        wakeup(s, peer);
+    }
+    s.table[id].state = State::Free;
+    // a process may still sleep in accept, send or recv on it
+    wakeup(s, id);
+}
+
+#[cfg(feature = "unit_test")]
+pub mod tests {
+    use super::*;
+
+    /// A client and a server, without anything having to sleep
+    pub fn stream_pair() {
+        let path = b"/tmp/stream_pair";
+        let listener = socket().unwrap();
+        bind(listener, path).unwrap();
+        listen(listener).unwrap();
+        let other = socket().unwrap();
+        assert!(bind(other, path).is_err());
+        assert!(connect(other, b"/tmp/nobody").is_err());
+
+        let client = socket().unwrap();
+        connect(client, path).unwrap();
+        connect(other, path).unwrap();
+        assert_eq!(send(client, b"hello", false), Ok(5));
+        // in the order they connected
+        let server = accept(listener, false).unwrap();
+        let second = accept(listener, false).unwrap();
+
+        let mut buf = [0u8; 8];
+        let (a, b) = buf.split_at_mut(2);
+        assert_eq!(recvmsg(server, &mut [a, b], false), Ok(5));
+        assert_eq!(&buf[..5], b"hello");
+        assert_eq!(sendmsg(server, &[b"ab", b"", b"cd"], false), Ok(4));
+        assert_eq!(recv(client, &mut buf[..3], false), Ok(3));
+        assert_eq!(recv(client, &mut buf[3..], false), Ok(1));
+        assert_eq!(&buf[..4], b"abcd");
+
+        // the ring wraps around
+        let big = [7u8; SOCKBUF - 3];
+        assert_eq!(send(second, &big, false), Ok(big.len()));
+        let mut back = [0u8; SOCKBUF];
+        assert_eq!(recv(other, &mut back, false), Ok(big.len()));
+        assert_eq!(send(second, &big, false), Ok(big.len()));
+        assert_eq!(recv(other, &mut back, false), Ok(big.len()));
+        assert!(back[..big.len()] == big[..]);
+
+        // the end of the stream, after what was sent before the close
+        send(client, b"bye", false).unwrap();
+        close(client).unwrap();
+        assert_eq!(recv(server, &mut buf, false), Ok(3));
+        assert_eq!(recv(server, &mut buf, false), Ok(0));
+        assert!(send(server, b"x", false).is_err());
+
+        for id in [server, second, other, listener] {
+            close(id).unwrap();
+        }
+        assert!(close(listener).is_err());
+    }
+    crate::kernel_test!(stream_pair);
+
+    /// What would sleep fails instead, a full peer takes part of a send
+    pub fn nonblocking() {
+        let path = b"/tmp/nonblocking";
+        let listener = socket().unwrap();
+        bind(listener, path).unwrap();
+        listen(listener).unwrap();
+        assert_eq!(accept(listener, true), Err("would block"));
+
+        let client = socket().unwrap();
+        connect(client, path).unwrap();
+        let server = accept(listener, true).unwrap();
+        let mut buf = [0u8; 4];
+        assert_eq!(recv(server, &mut buf, true), Err("would block"));
+
+        let big = [1u8; SOCKBUF - 2];
+        assert_eq!(send(client, &big, true), Ok(big.len()));
+        assert_eq!(sendmsg(client, &[b"ab", b"cd"], true), Ok(2));
+        assert_eq!(send(client, b"x", true), Err("would block"));
+        let mut back = [0u8; SOCKBUF];
+        assert_eq!(recv(server, &mut back, true), Ok(SOCKBUF));
+        assert_eq!(&back[SOCKBUF - 2..], b"ab");
+
+        for id in [client, server, listener] {
+            close(id).unwrap();
+        }
+    }
+    crate::kernel_test!(nonblocking);
+}
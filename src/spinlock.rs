@@ -4,11 +4,25 @@
 
 use core::cell::{Cell, UnsafeCell};
 use core::ops::{Deref, DerefMut, Drop};
-use core::sync::atomic::{fence, AtomicBool, Ordering};
+use core::sync::atomic::{fence, AtomicBool, AtomicUsize, Ordering};
 
+use crate::consts::NCPU;
 use crate::register::sstatus;
 use crate::process::{self, cpu_id};
 
+/// Per hart, the acquisitions of any spinlock, and how many of them had to spin,
+/// for the kernel monitor. Only touched by their hart, with interrupts off.
+static LOCK_STATS: [(AtomicUsize, AtomicUsize); NCPU] =
+    [const { (AtomicUsize::new(0), AtomicUsize::new(0)) }; NCPU];
+
+pub fn lock_stats() -> [(usize, usize); NCPU] {
+    let mut stats = [(0, 0); NCPU];
+    for (hart, s) in stats.iter_mut().enumerate() {
+        *s = (LOCK_STATS[hart].0.load(Ordering::Relaxed), LOCK_STATS[hart].1.load(Ordering::Relaxed));
+    }
+    stats
+}
+
 pub struct SpinLock<T: ?Sized> {
     // for debugging
     // None means this spinlock is not held by any cpu
@@ -39,9 +53,8 @@ impl<T> SpinLock<T> {
 
 impl<T: ?Sized> SpinLock<T> {
     pub unsafe fn holding(&self) -> bool {
-        let r: bool;
         push_off();
-        r = self.lock.load(Ordering::Relaxed) && (self.cpu_id.get() == cpu_id() as isize);
+        let r = self.lock.load(Ordering::Relaxed) && (self.cpu_id.get() == cpu_id() as isize);
         pop_off();
         r
     }
@@ -53,9 +66,22 @@ impl<T: ?Sized> SpinLock<T> {
         if self.holding() {
             panic!("acquire");
         }
-        while self.lock.compare_and_swap(false, true, Ordering::Acquire) {}
+        let stats = &LOCK_STATS[cpu_id()];
+        if self.lock.compare_exchange(false, true, Ordering::Acquire, Ordering::Relaxed).is_err() {
+            stats.1.fetch_add(1, Ordering::Relaxed);
+            while self.lock.compare_exchange(false, true, Ordering::Acquire, Ordering::Relaxed).is_err() {}
+        }
+        stats.0.fetch_add(1, Ordering::Relaxed);
         fence(Ordering::SeqCst);
         self.cpu_id.set(cpu_id() as isize);
+        #[cfg(debug_assertions)]
+        process::lock_acquired(self.addr(), self.name);
+    }
+
+    /// Address identifying this lock in the per-hart record of held locks
+    #[cfg(debug_assertions)]
+    pub fn addr(&self) -> usize {
+        &self.lock as *const AtomicBool as usize
     }
 
     /// Locks the spinlock and returns a guard.
@@ -74,10 +100,10 @@ impl<T: ?Sized> SpinLock<T> {
     ///     // i.e. the lock will be released
     /// }
     /// ```
-    pub fn lock(&self) -> SpinLockGuard<T> {
+    pub fn lock(&self) -> SpinLockGuard<'_, T> {
         unsafe {self.acquire_lock();}
         SpinLockGuard {
-            spin_lock: &self,
+            spin_lock: self,
             data: unsafe { &mut *self.data.get() },
         }
     }
@@ -86,6 +112,8 @@ impl<T: ?Sized> SpinLock<T> {
         if !self.holding() {
             panic!("release");
         }
+        #[cfg(debug_assertions)]
+        process::lock_released(self.addr());
         self.cpu_id.set(-1);
         fence(Ordering::SeqCst);
         self.lock.store(false, Ordering::Release);
@@ -96,13 +124,13 @@ impl<T: ?Sized> SpinLock<T> {
 /// push_off/pop_off are like intr_off()/intr_on() except that they are matched:
 /// it takes two pop_off()s to undo two push_off()s.  Also, if interrupts
 /// are initially off, then push_off, pop_off leaves them off.
-fn push_off() {
+pub fn push_off() {
     let old: bool = sstatus::intr_get();
     sstatus::intr_off();
     process::push_off(old);
 }
 
-fn pop_off() {
+pub fn pop_off() {
     if sstatus::intr_get() {
         panic!("spinlock.rs: pop_off - interruptable");
     }
@@ -116,6 +144,14 @@ pub struct SpinLockGuard<'a, T: ?Sized + 'a> {
     data: &'a mut T,
 }
 
+impl<'a, T: ?Sized> SpinLockGuard<'a, T> {
+    /// The lock guard holds, e.g., for sleep() to take it again,
+    /// not a method so that it does not hide one of T's
+    pub fn spin_lock(guard: &Self) -> &'a SpinLock<T> {
+        guard.spin_lock
+    }
+}
+
 impl<'a, T: ?Sized> Deref for SpinLockGuard<'a, T> {
     type Target = T;
     fn deref(&self) -> &T {
@@ -140,6 +176,8 @@ impl<'a, T: ?Sized> Drop for SpinLockGuard<'a, T> {
 /// Copy from crate spin(https://crates.io/crates/spin)
 #[cfg(feature = "unit_test")]
 pub mod tests {
+    use core::ptr;
+
     use super::*;
 
     pub fn smoke() {
@@ -147,4 +185,28 @@ pub mod tests {
         m.lock();
         m.lock();
     }
+    crate::kernel_test!(smoke);
+
+    pub fn guard_lock() {
+        let m = SpinLock::new(1, "guard");
+        let guard = m.lock();
+        assert!(ptr::eq(SpinLockGuard::spin_lock(&guard), &m));
+        assert_eq!(*guard, 1);
+    }
+    crate::kernel_test!(guard_lock);
+
+    /// Locks released out of order are still tracked right
+    #[cfg(debug_assertions)]
+    pub fn held_out_of_order() {
+        let a = SpinLock::new((), "a");
+        let b = SpinLock::new((), "b");
+        let guard_a = a.lock();
+        let guard_b = b.lock();
+        drop(guard_a);
+        process::assert_only_holding(b.addr(), "held_out_of_order");
+        drop(guard_b);
+        process::assert_only_holding(0, "held_out_of_order");
+    }
+    #[cfg(debug_assertions)]
+    crate::kernel_test!(held_out_of_order);
 }
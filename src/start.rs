@@ -1,29 +1,73 @@
+//! Per-hart startup, _entry in entry.S jumps here.
+//!
+//! Normally the kernel is entered in machine mode, e.g., with qemu's -bios none,
+//! sets up the timer and drops to supervisor mode itself.
+//! With the sbi feature, OpenSBI has done that, and enters the kernel
+//! in supervisor mode through entry_sbi.S, see sbi.rs.
+
+#[cfg(not(feature = "sbi"))]
+use core::arch::asm;
+#[cfg(not(feature = "sbi"))]
 use core::convert::Into;
 
-use crate::consts::{CLINT_MTIMECMP, NCPU};
+use crate::consts::NCPU;
+#[cfg(not(feature = "sbi"))]
+use crate::consts::{
+    ConstAddr, CLINT, CLINT_MAP_SIZE, CLINT_MSIP, CLINT_MTIMECMP, KERNBASE, PHYSTOP, PLIC,
+    PLIC_MAP_SIZE, UART0, UART0_MAP_SIZE, VIRTIO0, VIRTIO0_MAP_SIZE, VIRTIO1, VIRTIO1_MAP_SIZE,
+    VIRT_TEST, VIRT_TEST_MAP_SIZE,
+};
+use crate::dtb;
+#[cfg(not(feature = "sbi"))]
 use crate::register::{
-    clint, medeleg, mepc, mhartid, mideleg, mie, mscratch, mstatus, mtvec, satp, tp,
+    clint, mcounteren, medeleg, mepc, mhartid, mideleg, mie, mscratch, mstatus, mtvec, pmp,
 };
+#[cfg(feature = "sbi")]
+use crate::register::time;
+use crate::register::{satp, tp};
 use crate::rmain::rust_main;
+#[cfg(feature = "sbi")]
+use crate::sbi;
+
+/// cycles between timer interrupts; about 1/10th second in qemu.
+pub const INTERVAL: u64 = 1000000;
 
-/// for each cpu, only 6 of 32 usize are used, others are reserved.
+/// for each cpu, only 8 of 32 usize are used, others are reserved.
+/// the timer's 7, and one for the machine call, see kernelvec.S.
+#[cfg(not(feature = "sbi"))]
 static mut MSCRATCH0: [usize; NCPU * 32] = [0; NCPU * 32];
 
+/// medeleg's bit of an ecall from supervisor mode
+#[cfg(not(feature = "sbi"))]
+const ECALL_FROM_S: usize = 1 << 9;
+
+#[cfg(not(feature = "sbi"))]
 #[no_mangle]
-pub unsafe fn start() -> ! {
+pub unsafe fn start(dtb: usize) -> ! {
     // set M Previous Privilege mode to Supervisor, for mret.
     mstatus::set_mpp(mstatus::MPP::Supervisor);
 
     // set M Exception Program Counter to main, for mret.
-    mepc::write(rust_main as usize);
+    mepc::write(rust_main as *const () as usize);
 
     // disable paging for now.
     satp::write(0);
 
-    // delegate all interrupts and exceptions to supervisor mode.
-    medeleg::write(0xffff);
+    // delegate all interrupts and exceptions to supervisor mode,
+    // including the guest's page faults and virtual instruction traps, see hyp.rs,
+    // but ecalls from supervisor mode, which set the hardware triggers, see watch.rs.
+    #[cfg(not(feature = "hypervisor"))]
+    medeleg::write(0xffff & !ECALL_FROM_S);
+    #[cfg(feature = "hypervisor")]
+    medeleg::write((0xffff & !ECALL_FROM_S) | 0xf << 20);
     mideleg::write(0xffff);
 
+    // let supervisor mode reach its memory and devices.
+    pmpinit(dtb);
+
+    // and read the counters, e.g., cycle for the entropy pool, see random.rs.
+    mcounteren::write(mcounteren::CY | mcounteren::TM | mcounteren::IR);
+
     // ask for clock interrupts.
     timerinit();
 
@@ -31,43 +75,177 @@ pub unsafe fn start() -> ! {
     let id = mhartid::read();
     tp::write(id);
 
-    // switch to supervisor mode and jump to main().
-    llvm_asm!("mret"::::"volatile");
+    // switch to supervisor mode and jump to main(dtb).
+    asm!("mret", in("a0") dtb, options(noreturn));
+}
+
+const PMP_R: u8 = 1 << 0;
+const PMP_W: u8 = 1 << 1;
+const PMP_X: u8 = 1 << 2;
+/// naturally aligned power-of-2 region
+const PMP_NAPOT: u8 = 3 << 3;
 
-    // cannot panic or print here
-    loop {}
+/// pmpaddr of a naturally aligned power-of-2 region of at least 8 bytes
+fn napot(base: usize, size: usize) -> usize {
+    debug_assert!(size.is_power_of_two() && size >= 8 && base.is_multiple_of(size));
+    (base + size / 2 - 1) >> 2
+}
+
+/// The smallest naturally aligned power-of-2 region
+/// holding [start, end), as (base, size)
+fn napot_around(start: usize, end: usize) -> (usize, usize) {
+    let mut size = 8;
+    while start & !(size - 1) != (end - 1) & !(size - 1) {
+        size <<= 1;
+    }
+    (start & !(size - 1), size)
+}
+
+/// Grant supervisor mode exactly what the kernel maps in kvm_init(),
+/// i.e., RAM up to PHYSTOP and the device registers,
+/// plus the device tree read-only, which dtb::init() parses before paging.
+/// Without any pmp entry, accesses from supervisor mode fault on real hardware.
+/// Entries are not locked, so machine mode is not restricted.
+#[cfg(not(feature = "sbi"))]
+unsafe fn pmpinit(dtb: usize) {
+    let mut cfg = [0u8; 16];
+    let mut n = 0;
+    let mut grant = |base: ConstAddr, size: usize, perm: u8| {
+        pmp::write_addr(n, napot(base.into(), size));
+        cfg[n] = perm | PMP_NAPOT;
+        n += 1;
+    };
+
+    grant(KERNBASE, Into::<usize>::into(PHYSTOP) - Into::<usize>::into(KERNBASE), PMP_R | PMP_W | PMP_X);
+    grant(VIRT_TEST, VIRT_TEST_MAP_SIZE, PMP_R | PMP_W);
+    grant(CLINT, CLINT_MAP_SIZE, PMP_R | PMP_W);
+    grant(PLIC, PLIC_MAP_SIZE, PMP_R | PMP_W);
+    grant(UART0, UART0_MAP_SIZE, PMP_R | PMP_W);
+    #[cfg(feature = "gdbstub")]
+    grant(crate::consts::UART1, crate::consts::UART1_MAP_SIZE, PMP_R | PMP_W);
+    grant(VIRTIO0, VIRTIO0_MAP_SIZE, PMP_R | PMP_W);
+    grant(VIRTIO1, VIRTIO1_MAP_SIZE, PMP_R | PMP_W);
+
+    if let Ok(size) = dtb::total_size(dtb) {
+        let (base, size) = napot_around(dtb, dtb + size);
+        pmp::write_addr(n, napot(base, size));
+        cfg[n] = PMP_R | PMP_NAPOT;
+    }
+
+    let mut word = [0u8; 8];
+    word.copy_from_slice(&cfg[..8]);
+    pmp::write_cfg(0, usize::from_le_bytes(word));
+    word.copy_from_slice(&cfg[8..]);
+    pmp::write_cfg(2, usize::from_le_bytes(word));
 }
 
 /// set up to receive timer interrupts in machine mode,
 /// which arrive at timervec in kernelvec.S,
 /// which turns them into software interrupts for
 /// devintr() in trap.rs.
+#[cfg(not(feature = "sbi"))]
 unsafe fn timerinit() {
     // each CPU has a separate source of timer interrupts.
     let id = mhartid::read();
 
     // ask the CLINT for a timer interrupt.
-    let interval: u64 = 1000000; // cycles; about 1/10th second in qemu.
-    clint::add_mtimecmp(id, interval);
+    clint::add_mtimecmp(id, INTERVAL);
 
     // prepare information in scratch[] for timervec.
     // scratch[0..3] : space for timervec to save registers.
     // scratch[4] : address of CLINT MTIMECMP register.
     // scratch[5] : desired interval (in cycles) between timer interrupts.
+    // scratch[6] : address of CLINT MSIP register, to clear IPIs.
     let offset = 32 * id;
     MSCRATCH0[offset + 4] = 8 * id + Into::<usize>::into(CLINT_MTIMECMP);
-    MSCRATCH0[offset + 5] = interval as usize;
+    MSCRATCH0[offset + 5] = INTERVAL as usize;
+    MSCRATCH0[offset + 6] = 4 * id + Into::<usize>::into(CLINT_MSIP);
     mscratch::write((MSCRATCH0.as_ptr() as usize) + offset * core::mem::size_of::<usize>());
 
     // set the machine-mode trap handler.
     extern "C" {
         fn timervec();
     }
-    mtvec::write(timervec as usize);
+    mtvec::write(timervec as *const () as usize);
 
     // enable machine-mode interrupts.
     mstatus::set_mie();
 
     // enable machine-mode timer interrupts.
     mie::set_mtie();
+
+    // enable machine-mode software interrupts, i.e., IPIs.
+    mie::set_msie();
+}
+
+/// Have this hart's next timer interrupt come at time, instead of after INTERVAL,
+/// for an idle hart, see idle() in cpu.rs.
+/// Without the sbi feature, timervec keeps adding INTERVAL to it after that.
+pub fn timer_at(time: u64) {
+    #[cfg(not(feature = "sbi"))]
+    unsafe { clint::write_mtimecmp(tp::read(), time); }
+    #[cfg(feature = "sbi")]
+    sbi::set_timer(time);
+}
+
+#[cfg(feature = "sbi")]
+#[no_mangle]
+pub unsafe fn start(hartid: usize, dtb: usize) -> ! {
+    // keep each CPU's hartid in its tp register, for cpuid().
+    tp::write(hartid);
+
+    // disable paging for now.
+    satp::write(0);
+
+    // ask for clock interrupts.
+    timer_next();
+
+    // already in supervisor mode.
+    rust_main(dtb);
+}
+
+/// OpenSBI only enters the kernel on the boot hart,
+/// the others stay stopped until started through the HSM extension,
+/// which rust_main() does once it is done with the setup.
+/// Older firmware enters on all harts, then these calls just fail.
+#[cfg(feature = "sbi")]
+pub unsafe fn start_harts(dtb: usize) {
+    // the hart is running already
+    const ERR_ALREADY_AVAILABLE: isize = -6;
+
+    extern "C" {
+        fn _entry();
+    }
+    let me = tp::read();
+    for hart in (0..NCPU).filter(|hart| *hart != me && dtb::harts() & (1 << hart) != 0) {
+        match sbi::hart_start(hart, _entry as *const () as usize, dtb) {
+            Ok(()) | Err(ERR_ALREADY_AVAILABLE) => {}
+            Err(err) => println!("start_harts: hart {} not started, sbi error {}", hart, err),
+        }
+    }
+}
+
+/// Ask the firmware for the next timer interrupt,
+/// which arrives as a supervisor timer interrupt,
+/// handled by devintr() in trap.rs.
+#[cfg(feature = "sbi")]
+pub fn timer_next() {
+    sbi::set_timer(time::read() + INTERVAL);
+}
+
+#[cfg(feature = "unit_test")]
+pub mod tests {
+    use super::*;
+
+    pub fn pmp_napot() {
+        // 4KB at 0x10000000, 128MB at 0x80000000
+        assert_eq!(napot(0x10000000, 0x1000), 0x040001ff);
+        assert_eq!(napot(0x80000000, 0x8000000), 0x20ffffff);
+        assert_eq!(napot_around(0x1000, 0x1008), (0x1000, 8));
+        assert_eq!(napot_around(0x1000, 0x1009), (0x1000, 16));
+        assert_eq!(napot_around(0xbfe00000, 0xbfe01234), (0xbfe00000, 0x2000));
+        // straddling a boundary doubles until both ends fit
+        assert_eq!(napot_around(0x1ff8, 0x2008), (0, 0x4000));
+    }
+    crate::kernel_test!(pmp_napot);
 }
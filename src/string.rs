@@ -1,12 +1,258 @@
 //! string module containing C-like mem operation, like memset
-//! Is there a better way to rewrite these ops in Rust?
+//!
+//! compiler_builtins already exports the C symbols for -none targets,
+//! byte-at-a-time, and those stay under ptr::copy, ptr::write_bytes and friends.
+//! So these are plain kernel functions, called directly on the hot paths,
+//! e.g., page table clearing and copyin/copyout.
+//! They work a usize at a time once both pointers are aligned,
+//! with byte loops for the unaligned head and tail.
+//! Pointers that can never be aligned together are copied byte by byte,
+//! since misaligned word accesses trap or are slow on riscv.
+//!
+//! The inner loops use volatile accesses, otherwise LLVM recognizes them
+//! as memset/memcpy idioms and turns them back into the byte-wise calls.
+
+use core::mem::size_of;
+use core::ptr::{read_volatile, write_volatile};
+
+const WORD: usize = size_of::<usize>();
+
+#[inline(always)]
+fn aligned(addr: usize) -> bool {
+    addr.is_multiple_of(WORD)
+}
 
 /// memset
-///
-/// only support [u8]
 pub unsafe fn memset(dst: *mut u8, c: u8, n: usize) -> *mut u8 {
-    for _ in 0..n {
-        *dst = c;
+    let mut i = 0;
+    while i < n && !aligned(dst as usize + i) {
+        write_volatile(dst.add(i), c);
+        i += 1;
+    }
+    let word = (c as usize) * (usize::MAX / 0xff);
+    while i + WORD <= n {
+        write_volatile(dst.add(i) as *mut usize, word);
+        i += WORD;
+    }
+    while i < n {
+        write_volatile(dst.add(i), c);
+        i += 1;
+    }
+    dst
+}
+
+/// Copy n bytes forwards, overlap is fine if dst is below src
+unsafe fn copy_forward(dst: *mut u8, src: *const u8, n: usize) {
+    let mut i = 0;
+    if aligned(dst as usize ^ src as usize) {
+        while i < n && !aligned(dst as usize + i) {
+            write_volatile(dst.add(i), read_volatile(src.add(i)));
+            i += 1;
+        }
+        while i + WORD <= n {
+            write_volatile(dst.add(i) as *mut usize, read_volatile(src.add(i) as *const usize));
+            i += WORD;
+        }
+    }
+    while i < n {
+        write_volatile(dst.add(i), read_volatile(src.add(i)));
+        i += 1;
     }
+}
+
+/// Copy n bytes backwards, overlap is fine if dst is above src
+unsafe fn copy_backward(dst: *mut u8, src: *const u8, n: usize) {
+    let mut i = n;
+    if aligned(dst as usize ^ src as usize) {
+        while i > 0 && !aligned(dst as usize + i) {
+            i -= 1;
+            write_volatile(dst.add(i), read_volatile(src.add(i)));
+        }
+        while i >= WORD {
+            i -= WORD;
+            write_volatile(dst.add(i) as *mut usize, read_volatile(src.add(i) as *const usize));
+        }
+    }
+    while i > 0 {
+        i -= 1;
+        write_volatile(dst.add(i), read_volatile(src.add(i)));
+    }
+}
+
+/// memcpy, dst and src must not overlap
+pub unsafe fn memcpy(dst: *mut u8, src: *const u8, n: usize) -> *mut u8 {
+    copy_forward(dst, src, n);
     dst
 }
+
+/// memmove, dst and src may overlap
+pub unsafe fn memmove(dst: *mut u8, src: *const u8, n: usize) -> *mut u8 {
+    if (dst as usize) <= (src as usize) || (dst as usize) >= (src as usize) + n {
+        copy_forward(dst, src, n);
+    } else {
+        copy_backward(dst, src, n);
+    }
+    dst
+}
+
+/// memcmp, the difference of the first differing bytes
+pub unsafe fn memcmp(a: *const u8, b: *const u8, n: usize) -> i32 {
+    let mut i = 0;
+    if aligned(a as usize ^ b as usize) {
+        while i < n && !aligned(a as usize + i) {
+            let (x, y) = (read_volatile(a.add(i)), read_volatile(b.add(i)));
+            if x != y {
+                return x as i32 - y as i32;
+            }
+            i += 1;
+        }
+        // skip equal words, the byte loop below finds the difference
+        while i + WORD <= n
+            && read_volatile(a.add(i) as *const usize) == read_volatile(b.add(i) as *const usize)
+        {
+            i += WORD;
+        }
+    }
+    while i < n {
+        let (x, y) = (read_volatile(a.add(i)), read_volatile(b.add(i)));
+        if x != y {
+            return x as i32 - y as i32;
+        }
+        i += 1;
+    }
+    0
+}
+
+/// Length of the nul-terminated string at s, or max if no nul in its first max bytes.
+/// Scans a word at a time once s is aligned.
+pub unsafe fn strnlen(s: *const u8, max: usize) -> usize {
+    const LOW: usize = usize::MAX / 0xff;
+    const HIGH: usize = LOW << 7;
+    let mut i = 0;
+    while i < max && !aligned(s as usize + i) {
+        if read_volatile(s.add(i)) == 0 {
+            return i;
+        }
+        i += 1;
+    }
+    // a word has a zero byte iff this is nonzero
+    while i + WORD <= max {
+        let w = read_volatile(s.add(i) as *const usize);
+        if w.wrapping_sub(LOW) & !w & HIGH != 0 {
+            break;
+        }
+        i += WORD;
+    }
+    while i < max {
+        if read_volatile(s.add(i)) == 0 {
+            return i;
+        }
+        i += 1;
+    }
+    max
+}
+
+#[cfg(feature = "unit_test")]
+pub mod tests {
+    use super::*;
+    use crate::consts::PGSIZE;
+    use crate::mm::{kalloc, kfree};
+    use crate::register::clint;
+
+    /// Every alignment and length around a word, including overlaps
+    pub fn edge_cases() {
+        let mut buf = [0u8; 64];
+        let mut want = [0u8; 64];
+        for dst in 0..WORD * 2 {
+            for src in 0..WORD * 2 {
+                for n in 0..WORD * 3 {
+                    for (i, (b, w)) in buf.iter_mut().zip(want.iter_mut()).enumerate() {
+                        *b = i as u8;
+                        *w = i as u8;
+                    }
+                    // reference, one byte at a time in the safe direction
+                    if dst < src {
+                        for i in 0..n { want[dst + i] = want[src + i]; }
+                    } else {
+                        for i in (0..n).rev() { want[dst + i] = want[src + i]; }
+                    }
+                    unsafe { memmove(buf.as_mut_ptr().add(dst), buf.as_ptr().add(src), n); }
+                    assert!(buf == want, "memmove dst={} src={} n={}", dst, src, n);
+                }
+            }
+        }
+
+        for off in 0..WORD {
+            for n in 0..WORD * 3 {
+                buf = [0; 64];
+                unsafe { memset(buf.as_mut_ptr().add(off), 0xab, n); }
+                for (i, b) in buf.iter().enumerate() {
+                    let inside = i >= off && i < off + n;
+                    assert_eq!(*b, if inside { 0xab } else { 0 }, "memset off={} n={}", off, n);
+                }
+            }
+        }
+
+        for (i, w) in want.iter_mut().enumerate() {
+            *w = i as u8;
+        }
+        for off in 0..WORD {
+            for diff in off..want.len() {
+                let mut other = want;
+                other[diff] = other[diff].wrapping_add(1);
+                let n = want.len() - off;
+                let ret = unsafe { memcmp(want.as_ptr().add(off), other.as_ptr().add(off), n) };
+                assert!(ret < 0, "memcmp off={} diff={}", off, diff);
+                let ret = unsafe { memcmp(want.as_ptr().add(off), want.as_ptr().add(off), n) };
+                assert_eq!(ret, 0);
+            }
+        }
+    }
+    crate::kernel_test!(edge_cases);
+
+    /// The nul is found at every offset, whatever the alignment
+    pub fn strnlen_offsets() {
+        let mut buf = [b'x'; 40];
+        for off in 0..WORD {
+            for nul in off..buf.len() {
+                buf[nul] = 0;
+                let len = unsafe { strnlen(buf.as_ptr().add(off), buf.len() - off) };
+                assert_eq!(len, nul - off, "strnlen off={} nul={}", off, nul);
+                let len = unsafe { strnlen(buf.as_ptr().add(off), nul - off) };
+                assert_eq!(len, nul - off, "strnlen off={} max={}", off, nul - off);
+                buf[nul] = b'x';
+            }
+        }
+    }
+    crate::kernel_test!(strnlen_offsets);
+
+    /// Print the copy throughput of memcpy against a byte loop
+    pub fn throughput() {
+        const ROUNDS: usize = 256;
+        let src = unsafe { kalloc() }.expect("throughput: out of memory");
+        let dst = unsafe { kalloc() }.expect("throughput: out of memory");
+
+        let start = unsafe { clint::read_mtime() };
+        for _ in 0..ROUNDS {
+            unsafe { memcpy(dst, src, PGSIZE); }
+        }
+        let words = unsafe { clint::read_mtime() } - start;
+
+        let start = unsafe { clint::read_mtime() };
+        for _ in 0..ROUNDS {
+            for i in 0..PGSIZE {
+                unsafe { write_volatile(dst.add(i), read_volatile(src.add(i))); }
+            }
+        }
+        let bytes = unsafe { clint::read_mtime() } - start;
+
+        // mtime runs at 10MHz in qemu
+        let kb = (ROUNDS * PGSIZE / 1024) as u64;
+        println!("memcpy {} KB: {} mtime ticks, byte loop {} ticks", kb, words, bytes);
+        unsafe {
+            kfree(src);
+            kfree(dst);
+        }
+    }
+    crate::kernel_test!(throughput);
+}
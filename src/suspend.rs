@@ -0,0 +1,93 @@
+//! Suspend to idle
+//!
+//! suspend() stops everything but the hart it is called on:
+//! the other harts are parked, the boot hart too, see park() in cpu.rs,
+//! so no process is scheduled, and this hart keeps interrupts off,
+//! with only the uart let through the PLIC, the disk's and balloon's
+//! completions wait in the PLIC until the resume.
+//! It then sits in wfi until a byte comes in on the console, which the console gets as usual,
+//! or the alarm is due, and brings the harts back.
+//! The timer wheel is not run meanwhile, its timers due fire at the next tick after.
+//!
+//! LTODO - suspending the devices themselves, and a real RTC alarm,
+//!     on a board with power states, the time of the CLINT stands in for it.
+
+use core::sync::atomic::{AtomicBool, Ordering};
+
+use crate::console;
+use crate::consts::{HAS_VIRTIO, UART0_IRQ, VIRTIO0_IRQ, VIRTIO3_IRQ};
+use crate::plic;
+use crate::printf;
+use crate::process::{cpu_id, park_others, unpark_others};
+use crate::register::{clint, sip, wfi};
+use crate::spinlock::{pop_off, push_off};
+use crate::start::{timer_at, INTERVAL};
+
+/// What ended a suspend
+pub const WAKE_CONSOLE: usize = 0;
+pub const WAKE_ALARM: usize = 1;
+
+/// interrupts only taken again after the resume
+const MASKED_IRQS: [usize; 2] = [VIRTIO0_IRQ, VIRTIO3_IRQ];
+
+static SUSPENDED: AtomicBool = AtomicBool::new(false);
+
+/// Suspend until console input, or until alarm ticks from now if it is not 0,
+/// return WAKE_CONSOLE or WAKE_ALARM.
+pub fn suspend(alarm: usize) -> Result<usize, &'static str> {
+    if SUSPENDED.swap(true, Ordering::SeqCst) {
+        return Err("suspend: already suspending");
+    }
+    println!("suspending");
+    printf::flush();
+
+    push_off();
+    let id = unsafe { cpu_id() };
+    let parked = park_others();
+    if HAS_VIRTIO {
+        for irq in MASKED_IRQS.iter() {
+            plic::disable(id, *irq);
+        }
+    }
+    let alarm = match alarm {
+        0 => None,
+        ticks => Some(unsafe { clint::read_mtime() } + ticks as u64 * INTERVAL),
+    };
+    timer_at(alarm.unwrap_or(u64::MAX));
+
+    let wake = loop {
+        wfi();
+        // a timer interrupt forwarded by timervec, or an IPI
+        sip::clear_ssip();
+        if let Some(alarm) = alarm {
+            if unsafe { clint::read_mtime() } >= alarm {
+                break WAKE_ALARM;
+            }
+        }
+        let irq = plic::claim();
+        if irq == 0 {
+            continue;
+        }
+        let console = irq as usize == UART0_IRQ;
+        if console {
+            console::uartintr();
+        }
+        plic::complete(irq);
+        if console {
+            break WAKE_CONSOLE;
+        }
+    };
+
+    timer_at(unsafe { clint::read_mtime() } + INTERVAL);
+    if HAS_VIRTIO {
+        for irq in MASKED_IRQS.iter() {
+            plic::enable(id, *irq);
+        }
+    }
+    unpark_others(parked);
+    pop_off();
+
+    SUSPENDED.store(false, Ordering::SeqCst);
+    println!("resumed by the {}", if wake == WAKE_ALARM { "alarm" } else { "console" });
+    Ok(wake)
+}
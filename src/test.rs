@@ -0,0 +1,127 @@
+//! In-kernel test harness, only built with the unit_test feature
+//!
+//! A test is a fn() registered with kernel_test!,
+//! which puts a TestCase into the .kernel_test section, see kernel.ld.
+//! Every hart calls run() after booting,
+//! the boot hart runs the tests in link order while the others wait,
+//! except for smp tests, which all harts run together.
+//! A panic means the test failed, see the panic handler in printf.rs,
+//! so does leaving pages allocated in debug builds, see mm/leak.rs.
+//! Qemu exits with status 0 after all tests pass, through its test device.
+
+use core::mem;
+use core::slice;
+use core::sync::atomic::{AtomicUsize, Ordering};
+
+use crate::driver::qemu;
+use crate::dtb;
+#[cfg(debug_assertions)]
+use crate::mm::leak;
+use crate::printf;
+use crate::process::cpu_id;
+use crate::rmain::boot_hart;
+
+pub struct TestCase {
+    pub name: &'static str,
+    pub func: fn(),
+    // run by all harts at the same time
+    pub smp: bool,
+}
+
+/// Register a test, e.g., `kernel_test!(smoke);`,
+/// or `kernel_test!(alloc_simo, smp);` for a test run by all harts.
+#[macro_export]
+macro_rules! kernel_test {
+    ($func:ident) => {
+        $crate::kernel_test!(@register $func, false);
+    };
+    ($func:ident, smp) => {
+        $crate::kernel_test!(@register $func, true);
+    };
+    (@register $func:ident, $smp:expr) => {
+        const _: () = {
+            #[used]
+            #[link_section = ".kernel_test"]
+            static TEST: $crate::test::TestCase = $crate::test::TestCase {
+                name: concat!(module_path!(), "::", stringify!($func)),
+                func: $func,
+                smp: $smp,
+            };
+        };
+    };
+}
+
+/// Name of the running test, only written by the boot hart
+static mut CURRENT: Option<&'static str> = None;
+
+pub fn current() -> Option<&'static str> {
+    unsafe { CURRENT }
+}
+
+fn tests() -> &'static [TestCase] {
+    extern "C" {
+        static __kernel_test_start: u8;
+        static __kernel_test_end: u8;
+    }
+    unsafe {
+        let start = &__kernel_test_start as *const u8 as usize;
+        let end = &__kernel_test_end as *const u8 as usize;
+        let n = (end - start) / mem::size_of::<TestCase>();
+        slice::from_raw_parts(start as *const TestCase, n)
+    }
+}
+
+/// Wait until all harts get here
+fn barrier() {
+    static COUNT: AtomicUsize = AtomicUsize::new(0);
+    static GENERATION: AtomicUsize = AtomicUsize::new(0);
+
+    let gen = GENERATION.load(Ordering::SeqCst);
+    if COUNT.fetch_add(1, Ordering::SeqCst) + 1 == dtb::nharts() {
+        COUNT.store(0, Ordering::SeqCst);
+        GENERATION.fetch_add(1, Ordering::SeqCst);
+    } else {
+        while GENERATION.load(Ordering::SeqCst) == gen {
+            core::hint::spin_loop();
+        }
+    }
+}
+
+/// Run all registered tests, called by every hart
+pub fn run() -> ! {
+    let boot = unsafe { cpu_id() } == boot_hart();
+    let tests = tests();
+    if boot {
+        println!("running {} tests", tests.len());
+    }
+
+    for test in tests {
+        barrier();
+        if boot {
+            unsafe { CURRENT = Some(test.name); }
+            print!("test {} ... ", test.name);
+            printf::flush();
+        }
+        #[cfg(debug_assertions)]
+        let gen = if boot { leak::mark() } else { 0 };
+        if test.smp || boot {
+            (test.func)();
+        }
+        barrier();
+        if boot {
+            #[cfg(debug_assertions)]
+            if leak::report(gen) > 0 {
+                panic!("{} leaked pages", test.name);
+            }
+            println!("ok");
+        }
+    }
+
+    barrier();
+    if boot {
+        unsafe { CURRENT = None; }
+        println!("test result: ok. {} passed", tests.len());
+        qemu::exit(0);
+    }
+    printf::freeze();
+}
@@ -0,0 +1,423 @@
+//! Kernel timers, as a hierarchical timing wheel
+//!
+//! A timer calls func(arg) once its ticks have passed, or every period ticks,
+//! from the clock interrupt on the boot hart, see clock_intr() in trap.rs.
+//! Callbacks run with interrupts off but without the wheel's lock,
+//! so they may add or cancel timers, and must not sleep.
+//!
+//! There are LEVELS wheels of SLOTS slots, wheel l holding the timers
+//! due within SLOTS^(l+1) ticks, in slot (expires >> (SHIFT * l)) % SLOTS.
+//! Whenever the lowest wheel comes round, the next slot of the wheel above
+//! is cascaded, i.e., its timers are put in again, closer to their turn,
+//! like the timer wheel of Linux before 4.8.
+//! So adding, cancelling and each tick cost O(1), except for the cascades.
+//! An idle boot hart skips the ticks up to next_expiry(), see idle() in cpu.rs,
+//! run() then catches up on them all at once.
+//!
+//! Timers live in a fixed pool of NTIMER entries,
+//! linked into their slots by index.
+
+use core::cmp::min;
+
+use crate::consts::NTIMER;
+use crate::spinlock::SpinLock;
+
+const SHIFT: usize = 6;
+const SLOTS: usize = 1 << SHIFT;
+const MASK: usize = SLOTS - 1;
+const LEVELS: usize = 4;
+/// longest delay, later timers fire after this long instead
+const MAX_DELAY: usize = (1 << (SHIFT * LEVELS)) - 1;
+
+const NIL: u16 = u16::MAX;
+
+/// A callback due, func and arg of its timer
+type Due = (fn(usize), usize);
+
+/// Callbacks run per batch, between taking the lock again
+const BATCH: usize = 16;
+
+/// Handle of an added timer, for cancel()
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct TimerId {
+    index: u16,
+    gen: u32,
+}
+
+#[derive(Clone, Copy)]
+struct Entry {
+    pending: bool,
+    /// bumped on each add, so stale TimerIds miss
+    gen: u32,
+    expires: usize,
+    /// 0 for a one-shot timer
+    period: usize,
+    func: fn(usize),
+    arg: usize,
+    /// level * SLOTS + slot of the list it is in
+    bucket: u16,
+    prev: u16,
+    next: u16,
+}
+
+fn nop(_: usize) {}
+
+impl Entry {
+    const fn new() -> Self {
+        Self {
+            pending: false,
+            gen: 0,
+            expires: 0,
+            period: 0,
+            func: nop,
+            arg: 0,
+            bucket: 0,
+            prev: NIL,
+            next: NIL,
+        }
+    }
+}
+
+pub struct Wheel {
+    /// the next tick to run
+    clk: usize,
+    heads: [[u16; SLOTS]; LEVELS],
+    entries: [Entry; NTIMER],
+    /// free entries, linked through next
+    free: u16,
+}
+
+impl Wheel {
+    const fn new() -> Self {
+        let mut entries = [Entry::new(); NTIMER];
+        let mut i = 0;
+        while i + 1 < NTIMER {
+            entries[i].next = (i + 1) as u16;
+            i += 1;
+        }
+        Self {
+            clk: 0,
+            heads: [[NIL; SLOTS]; LEVELS],
+            entries,
+            free: 0,
+        }
+    }
+
+    /// Add a timer due at tick expires, every period ticks after that, if not 0
+    fn add(&mut self, expires: usize, period: usize, func: fn(usize), arg: usize)
+        -> Result<TimerId, &'static str>
+    {
+        if self.free == NIL {
+            return Err("timer: out of timers");
+        }
+        let index = self.free;
+        let e = &mut self.entries[index as usize];
+        self.free = e.next;
+        e.pending = true;
+        e.gen = e.gen.wrapping_add(1);
+        e.expires = expires;
+        e.period = period;
+        e.func = func;
+        e.arg = arg;
+        let gen = e.gen;
+        self.insert(index);
+        Ok(TimerId { index, gen })
+    }
+
+    /// Remove a pending timer, return false if it has fired or was cancelled already
+    fn cancel(&mut self, id: TimerId) -> bool {
+        match self.entries.get(id.index as usize) {
+            Some(e) if e.pending && e.gen == id.gen => {}
+            _ => return false,
+        }
+        self.unlink(id.index);
+        self.release(id.index);
+        true
+    }
+
+    /// Put entry index in the slot for its expiry, relative to clk
+    fn insert(&mut self, index: u16) {
+        let clk = self.clk;
+        let e = &mut self.entries[index as usize];
+        if e.expires < clk {
+            // late, run it on the next tick
+            e.expires = clk;
+        }
+        let delay = min(e.expires - clk, MAX_DELAY);
+        e.expires = clk + delay;
+        let mut level = 0;
+        while level + 1 < LEVELS && delay >> (SHIFT * (level + 1)) != 0 {
+            level += 1;
+        }
+        let bucket = level * SLOTS + ((e.expires >> (SHIFT * level)) & MASK);
+        e.bucket = bucket as u16;
+        e.prev = NIL;
+        let head = &mut self.heads[level][bucket % SLOTS];
+        e.next = *head;
+        let next = *head;
+        *head = index;
+        if next != NIL {
+            self.entries[next as usize].prev = index;
+        }
+    }
+
+    fn unlink(&mut self, index: u16) {
+        let e = self.entries[index as usize];
+        match e.prev {
+            NIL => {
+                let bucket = e.bucket as usize;
+                self.heads[bucket / SLOTS][bucket % SLOTS] = e.next;
+            }
+            prev => self.entries[prev as usize].next = e.next,
+        }
+        if e.next != NIL {
+            self.entries[e.next as usize].prev = e.prev;
+        }
+    }
+
+    fn release(&mut self, index: u16) {
+        let e = &mut self.entries[index as usize];
+        e.pending = false;
+        e.next = self.free;
+        self.free = index;
+    }
+
+    /// Put the timers of the current slot of level again, and of the levels above
+    /// whenever this one wraps too
+    fn cascade(&mut self, level: usize) {
+        let slot = (self.clk >> (SHIFT * level)) & MASK;
+        if slot == 0 && level + 1 < LEVELS {
+            self.cascade(level + 1);
+        }
+        let mut index = self.heads[level][slot];
+        self.heads[level][slot] = NIL;
+        while index != NIL {
+            let next = self.entries[index as usize].next;
+            self.insert(index);
+            index = next;
+        }
+    }
+
+    /// The earliest tick a timer is due at, the entries are few enough to look at each
+    fn next_expiry(&self) -> Option<usize> {
+        self.entries.iter().filter(|e| e.pending).map(|e| e.expires).min()
+    }
+
+    /// Run the wheel up to tick now, taking the callbacks of the timers due
+    /// into out, and re-arming the periodic ones.
+    /// Return how many were taken, if out is full, call again for the rest.
+    fn expire(&mut self, now: usize, out: &mut [Due]) -> usize {
+        let mut n = 0;
+        while self.clk <= now {
+            let slot = self.clk & MASK;
+            loop {
+                let index = self.heads[0][slot];
+                if index == NIL {
+                    break;
+                }
+                if n == out.len() {
+                    return n;
+                }
+                self.unlink(index);
+                let e = &mut self.entries[index as usize];
+                out[n] = (e.func, e.arg);
+                n += 1;
+                if e.period != 0 {
+                    e.expires = self.clk + e.period;
+                    self.insert(index);
+                } else {
+                    self.release(index);
+                }
+            }
+            self.clk += 1;
+            if self.clk & MASK == 0 {
+                self.cascade(1);
+            }
+        }
+        n
+    }
+}
+
+static WHEEL: SpinLock<Wheel> = SpinLock::new(Wheel::new(), "timer");
+
+/// Call func(arg) once, ticks from now, at least one tick.
+pub fn add_timer(ticks: usize, func: fn(usize), arg: usize) -> Result<TimerId, &'static str> {
+    let mut wheel = WHEEL.lock();
+    // clk is one past the last tick run
+    let expires = wheel.clk + ticks.max(1) - 1;
+    wheel.add(expires, 0, func, arg)
+}
+
+/// Call func(arg) every period ticks, period must not be 0.
+pub fn add_periodic(period: usize, func: fn(usize), arg: usize) -> Result<TimerId, &'static str> {
+    if period == 0 {
+        return Err("timer: period 0");
+    }
+    let mut wheel = WHEEL.lock();
+    let expires = wheel.clk + period - 1;
+    wheel.add(expires, period, func, arg)
+}
+
+/// Stop a timer, return false if it is not pending,
+/// e.g., it was a one-shot timer that has fired.
+/// Its callback may still be running on the boot hart.
+pub fn cancel(id: TimerId) -> bool {
+    WHEEL.lock().cancel(id)
+}
+
+/// The tick the next timer is due at, if there is one
+pub fn next_expiry() -> Option<usize> {
+    WHEEL.lock().next_expiry()
+}
+
+/// Run the timers due up to tick now,
+/// only called by the boot hart on each clock tick.
+pub fn run(now: usize) {
+    let mut batch: [Due; BATCH] = [(nop, 0); BATCH];
+    loop {
+        let n = WHEEL.lock().expire(now, &mut batch);
+        for &(func, arg) in batch[..n].iter() {
+            func(arg);
+        }
+        if n < BATCH {
+            break;
+        }
+    }
+}
+
+#[cfg(feature = "unit_test")]
+pub mod tests {
+    use core::mem;
+    use core::sync::atomic::{AtomicUsize, Ordering};
+
+    use crate::consts::PGSIZE;
+    use crate::mm::{Box, PageAligned};
+    use super::*;
+
+    /// The wheel is too big for the kernel stack
+    #[repr(C, align(4096))]
+    struct Page(Wheel);
+
+    impl PageAligned for Page {}
+
+    const _: () = assert!(mem::size_of::<Page>() <= PGSIZE);
+
+    /// Bit arg is set in it when timer arg fires
+    static FIRED: AtomicUsize = AtomicUsize::new(0);
+
+    fn fire(arg: usize) {
+        FIRED.fetch_or(1 << arg, Ordering::SeqCst);
+    }
+
+    fn new_wheel() -> Box<Page> {
+        // the page is not initialized, but all of its fields are plain data
+        let mut page = Box::<Page>::new().expect("timer test: out of memory");
+        page.0.clk = 0;
+        page.0.heads = [[NIL; SLOTS]; LEVELS];
+        for (i, e) in page.0.entries.iter_mut().enumerate() {
+            *e = Entry::new();
+            e.next = if i + 1 < NTIMER { (i + 1) as u16 } else { NIL };
+        }
+        page.0.free = 0;
+        page
+    }
+
+    /// Run w tick by tick up to now, return the bits of the timers fired
+    fn advance(w: &mut Wheel, now: usize) -> usize {
+        let mut out = [(nop as fn(usize), 0); 4];
+        FIRED.store(0, Ordering::SeqCst);
+        loop {
+            let n = w.expire(now, &mut out);
+            for &(func, arg) in out[..n].iter() {
+                func(arg);
+            }
+            if n < out.len() {
+                break;
+            }
+        }
+        FIRED.load(Ordering::SeqCst)
+    }
+
+    /// Timers on each level fire on their tick, not before
+    pub fn wheel_levels() {
+        let mut page = new_wheel();
+        let w = &mut page.0;
+        let due = [1, 63, 64, 65, 4095, 4096, 262143, 262144, 300000];
+        for (i, &expires) in due.iter().enumerate() {
+            w.add(expires, 0, fire, i).unwrap();
+        }
+        for (i, &expires) in due.iter().enumerate() {
+            assert_eq!(advance(w, expires - 1), 0, "timer {} early", i);
+            assert_eq!(advance(w, expires), 1 << i, "timer {} not on time", i);
+        }
+        assert_eq!(advance(w, 400000), 0);
+        // all entries are free again
+        for e in w.entries.iter() {
+            assert!(!e.pending);
+        }
+
+        // too far out is cut to MAX_DELAY
+        let id = w.add(w.clk + 2 * MAX_DELAY, 0, fire, 0).unwrap();
+        assert_eq!(w.entries[id.index as usize].expires, w.clk + MAX_DELAY);
+        assert!(w.cancel(id));
+    }
+    crate::kernel_test!(wheel_levels);
+
+    pub fn wheel_periodic_cancel() {
+        let mut page = new_wheel();
+        let w = &mut page.0;
+        let periodic = w.add(10, 10, fire, 0).unwrap();
+        let once = w.add(25, 0, fire, 1).unwrap();
+        let cancelled = w.add(30, 0, fire, 2).unwrap();
+        assert!(w.cancel(cancelled));
+        assert!(!w.cancel(cancelled));
+
+        let mut count = 0;
+        for now in 1..=100 {
+            let bits = advance(w, now);
+            assert_eq!(bits & 1 != 0, now % 10 == 0, "periodic at {}", now);
+            assert_eq!(bits & 2 != 0, now == 25);
+            assert_eq!(bits & 4, 0);
+            count += bits & 1;
+        }
+        assert_eq!(count, 10);
+        // fired one-shots cannot be cancelled, even once their entry is reused
+        assert!(!w.cancel(once));
+        let reuse = w.add(200, 0, fire, 3).unwrap();
+        assert!(!w.cancel(once));
+        assert!(w.cancel(reuse));
+        assert!(w.cancel(periodic));
+        assert_eq!(advance(w, 1000), 0);
+    }
+    crate::kernel_test!(wheel_periodic_cancel);
+
+    /// Running out of entries fails, and many timers on one tick take several batches
+    pub fn wheel_full() {
+        let mut page = new_wheel();
+        let w = &mut page.0;
+        for i in 0..NTIMER {
+            w.add(5, 0, fire, i % 8).unwrap();
+        }
+        assert!(w.add(5, 0, fire, 0).is_err());
+        assert_eq!(advance(w, 5), 0xff);
+        assert!(w.add(5, 0, fire, 0).is_ok());
+    }
+    crate::kernel_test!(wheel_full);
+
+    /// An idle hart sleeps until the earliest timer, at whatever level it is
+    pub fn wheel_next_expiry() {
+        let mut page = new_wheel();
+        let w = &mut page.0;
+        assert_eq!(w.next_expiry(), None);
+        w.add(5000, 0, fire, 0).unwrap();
+        let id = w.add(70, 0, fire, 1).unwrap();
+        w.add(300, 50, fire, 2).unwrap();
+        assert_eq!(w.next_expiry(), Some(70));
+        assert!(w.cancel(id));
+        assert_eq!(w.next_expiry(), Some(300));
+        // the periodic one comes back
+        assert_eq!(advance(w, 300), 1 << 2);
+        assert_eq!(w.next_expiry(), Some(350));
+    }
+    crate::kernel_test!(wheel_next_expiry);
+}
@@ -0,0 +1,168 @@
+//! Timer descriptors, like Linux's timerfd
+//!
+//! A timer descriptor is a kernel timer, see timer.rs,
+//! which adds each expiry to an event counter, see eventfd.rs,
+//! so read() waits for the next expiry, and returns how many there were since the last read,
+//! and readable() tells a poll whether one came.
+//! settime() arms it to expire in value ticks, and then every interval ticks,
+//! or once if interval is 0, or disarms it if value is 0.
+//!
+//! A timer descriptor is referred to by its index in the table,
+//! behind a File from the timerfd_create syscall, see timeralloc() in fs/file.rs,
+//! a read of 8 bytes of it is read(), its last close closes it.
+
+use crate::consts::NTIMERFD;
+use crate::eventfd::{self, EFD_NONBLOCK};
+use crate::spinlock::SpinLock;
+use crate::timer::{self, TimerId};
+
+pub const TFD_NONBLOCK: usize = EFD_NONBLOCK;
+
+#[derive(Clone, Copy)]
+struct Timerfd {
+    used: bool,
+    /// the event counter its expiries go to
+    counter: usize,
+    interval: usize,
+    timer: Option<TimerId>,
+    /// timer is the periodic one, after the first expiry
+    periodic: bool,
+    /// bumped whenever it is set, so a stale expiry is ignored
+    seq: usize,
+}
+
+static TIMERFDS: SpinLock<[Timerfd; NTIMERFD]> = SpinLock::new([Timerfd {
+    used: false,
+    counter: 0,
+    interval: 0,
+    timer: None,
+    periodic: false,
+    seq: 0,
+}; NTIMERFD], "timerfd");
+
+/// The argument of the timer's callback, the index and its seq
+fn arg(t: &[Timerfd; NTIMERFD], id: usize) -> usize {
+    id + NTIMERFD * t[id].seq
+}
+
+/// Called from the timing wheel on the boot hart, with interrupts off.
+/// The first expiry of a periodic timer puts in the periodic one.
+fn expire(arg: usize) {
+    let mut t = TIMERFDS.lock();
+    let id = arg % NTIMERFD;
+    if !t[id].used || t[id].seq != arg / NTIMERFD {
+        return;
+    }
+    let interval = t[id].interval;
+    if interval == 0 {
+        t[id].timer = None;
+    } else if !t[id].periodic {
+        t[id].periodic = true;
+        t[id].timer = timer::add_periodic(interval, expire, arg).ok();
+    }
+    // the counter cannot be closed before the timer, it sleeps in nobody
+    let _ = eventfd::add(t[id].counter, 1);
+}
+
+fn check(t: &[Timerfd; NTIMERFD], id: usize) -> Result<(), &'static str> {
+    match t.get(id) {
+        Some(timerfd) if timerfd.used => Ok(()),
+        _ => Err("no such timer descriptor"),
+    }
+}
+
+/// Create a disarmed timer descriptor, flags are TFD_NONBLOCK
+pub fn timerfd_create(flags: usize) -> Result<usize, &'static str> {
+    let mut t = TIMERFDS.lock();
+    let id = t.iter().position(|timerfd| !timerfd.used).ok_or("out of timer descriptors")?;
+    let counter = eventfd::eventfd(0, flags)?;
+    let seq = t[id].seq + 1;
+    t[id] = Timerfd { used: true, counter, interval: 0, timer: None, periodic: false, seq };
+    Ok(id)
+}
+
+/// Expire in value ticks, and every interval ticks after that,
+/// or disarm it if value is 0. Expiries not read yet are kept.
+pub fn timerfd_settime(id: usize, value: usize, interval: usize) -> Result<(), &'static str> {
+    let mut t = TIMERFDS.lock();
+    check(&t, id)?;
+    if let Some(timer) = t[id].timer.take() {
+        timer::cancel(timer);
+    }
+    t[id].seq += 1;
+    t[id].interval = interval;
+    t[id].periodic = false;
+    if value != 0 {
+        let arg = arg(&t, id);
+        t[id].timer = Some(timer::add_timer(value, expire, arg)?);
+    }
+    Ok(())
+}
+
+/// Wait for an expiry, return how many there were since the last read
+pub fn read(id: usize) -> Result<u64, &'static str> {
+    let counter = {
+        let t = TIMERFDS.lock();
+        check(&t, id)?;
+        t[id].counter
+    };
+    eventfd::read(counter)
+}
+
+/// Whether read() would return at once
+pub fn readable(id: usize) -> bool {
+    let t = TIMERFDS.lock();
+    check(&t, id).is_ok() && eventfd::readable(t[id].counter)
+}
+
+/// Disarm and free timer descriptor id
+pub fn close(id: usize) -> Result<(), &'static str> {
+    let mut t = TIMERFDS.lock();
+    check(&t, id)?;
+    if let Some(timer) = t[id].timer.take() {
+        timer::cancel(timer);
+    }
+    t[id].used = false;
+    t[id].seq += 1;
+    eventfd::close(t[id].counter)
+}
+
+#[cfg(feature = "unit_test")]
+pub mod tests {
+    use super::*;
+
+    /// Expiries are counted, and stale ones ignored,
+    /// the wheel's callback is called by hand instead of waiting for ticks
+    pub fn expiries() {
+        let id = timerfd_create(TFD_NONBLOCK).unwrap();
+        assert!(read(id).is_err());
+        timerfd_settime(id, 1000, 0).unwrap();
+        let first = arg(&TIMERFDS.lock(), id);
+        expire(first);
+        assert!(readable(id));
+        assert_eq!(read(id), Ok(1));
+        assert!(TIMERFDS.lock()[id].timer.is_none());
+
+        // periodic, the first expiry puts in the periodic timer
+        timerfd_settime(id, 1000, 500).unwrap();
+        let periodic = arg(&TIMERFDS.lock(), id);
+        expire(first);
+        assert!(!readable(id));
+        expire(periodic);
+        expire(periodic);
+        expire(periodic);
+        assert_eq!(read(id), Ok(3));
+        assert!(TIMERFDS.lock()[id].timer.is_some());
+
+        // a disarmed one no longer expires
+        timerfd_settime(id, 0, 0).unwrap();
+        expire(periodic);
+        assert!(!readable(id));
+        assert!(TIMERFDS.lock()[id].timer.is_none());
+
+        close(id).unwrap();
+        assert!(read(id).is_err());
+        assert!(timerfd_settime(id, 1, 0).is_err());
+    }
+    crate::kernel_test!(expiries);
+}
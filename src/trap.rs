@@ -1,42 +1,69 @@
 //! Trap handler between user/kernel space and kernel space
 //! Mostly adopted from xv6-riscv
 
-use crate::consts::{TRAMPOLINE, TRAPFRAME, UART0_IRQ, VIRTIO0_IRQ};
-use crate::register::{stvec, sstatus, sepc, stval, sip, scause::{self, ScauseType}};
-use crate::process::{cpu_id, my_cpu};
+use core::sync::atomic::{AtomicUsize, Ordering};
+
+use crate::console;
+use crate::cpustat;
+use crate::consts::{HAS_VIRTIO, TRAMPOLINE, TRAPFRAME, UART0_IRQ, VIRTIO0_IRQ, VIRTIO3_IRQ};
+use crate::register::{clint, stvec, sstatus, sepc, stval, sip, scause::{self, ScauseType}};
+use crate::process::{cond_resched, cpu_id, expire_sleepers, my_cpu, my_pid, my_proc};
 use crate::spinlock::SpinLock;
 use crate::plic;
-use crate::driver::virtio;
+use crate::printf;
+use crate::rmain::boot_hart;
+use crate::start::INTERVAL;
+use crate::profile;
+use crate::random;
+use crate::timer;
+use crate::watch;
+use crate::driver::{virtio, virtio_balloon};
 
 pub unsafe fn trap_init_hart() {
     extern "C" {
         fn kernelvec();
     }
 
-    stvec::write(kernelvec as usize);
+    stvec::write(kernelvec as *const () as usize);
+
+    // firmware may have left it set
+    sstatus::sum_clear();
 }
 
 /// uservec in trampoline.S jumps here 
 #[no_mangle]
-pub unsafe extern fn user_trap() {
+pub unsafe extern "C" fn user_trap() {
+    ftrace!();
     if !sstatus::is_from_user() {
         panic!("user_trap: not from user mode, sstatus={:#x}", sstatus::read());
     }
 
     // switch the trap handler to kerneltrap()
     extern "C" {fn kernelvec();}
-    stvec::write(kernelvec as usize);
+    stvec::write(kernelvec as *const () as usize);
+    cpustat::enter(cpustat::CPU_KERNEL);
 
     handle_trap(true);
 
+    // a tick during a system call that did not yield
+    cond_resched();
+
+    // a tracer just attached, see process/ptrace.rs
+    my_proc().trace_trap();
+
+    // killed meanwhile, e.g., during a device interrupt, it is not to run again
+    my_cpu().try_abondon(-1);
+
     user_trap_ret();
 }
 
 /// Return to user space
 pub unsafe fn user_trap_ret() -> ! {
+    ftrace!();
     // disable interrupts and prepare sret to user mode
     sstatus::intr_off();
     sstatus::user_ret_prepare();
+    cpustat::enter(cpustat::CPU_USER);
 
     // send interrupts and exceptions to uservec/trampoline in trampoline.S
     stvec::write(TRAMPOLINE.into());
@@ -50,17 +77,30 @@ pub unsafe fn user_trap_ret() -> ! {
         fn trampoline();
         fn userret();
     }
-    let distance = userret as usize - trampoline as usize;
+    let distance = userret as *const () as usize - trampoline as *const () as usize;
     let userret_virt: extern "C" fn(usize, usize) -> ! =
         core::mem::transmute(Into::<usize>::into(TRAMPOLINE) + distance);
     userret_virt(TRAPFRAME.into(), satp);
 }
 
+/// Registers x1-x31 as saved by kernelvec on the kernel stack,
+/// restored from here when kerneltrap returns.
+#[repr(C)]
+pub struct KernelFrame {
+    pub regs: [usize; 31],
+}
+
+impl KernelFrame {
+    /// room kernelvec makes on the stack, the saved sp is below it
+    pub const SIZE: usize = 256;
+}
+
 /// Used to handle kernel space's trap
 /// Being called from kernelvec
 #[no_mangle]
-pub fn kerneltrap() {
-    let local_sepc = sepc::read();
+pub extern "C" fn kerneltrap(frame: &mut KernelFrame) {
+    #[allow(unused_mut)]
+    let mut local_sepc = sepc::read();
     let local_sstatus = sstatus::read();
 
     if !sstatus::is_from_supervisor() {
@@ -69,8 +109,20 @@ pub fn kerneltrap() {
     if sstatus::intr_get() {
         panic!("kerneltrap: interrupts enabled");
     }
+    if sstatus::sum_get() {
+        panic!("kerneltrap: sstatus.SUM set");
+    }
 
-    handle_trap(false);
+    match scause::get_scause() {
+        #[cfg(feature = "gdbstub")]
+        ScauseType::ExcBreakpoint if !watch::is_hit(local_sepc) => {
+            local_sepc = crate::gdbstub::trap(frame, local_sepc)
+        }
+        _ => {
+            let _ = frame;
+            handle_trap(false);
+        }
+    }
 
     // the yield() may have caused some traps to occur,
     // so restore trap registers for use by kernelvec.S's sepc instruction.
@@ -82,51 +134,127 @@ pub fn kerneltrap() {
 /// under the supervisor mode
 /// it is from xv6-riscv's devintr()
 fn handle_trap(is_user: bool) {
+    ftrace!();
     match scause::get_scause() {
         ScauseType::IntSExt => {
             // this is a supervisor external interrupt, via PLIC.
 
+            count(TRAP_DEVICE);
+            let was = cpustat::enter(cpustat::CPU_IRQ);
             let irq = plic::claim();
+            random::add_interrupt(irq as usize);
             if irq as usize == UART0_IRQ {
-                // uart intr
-            } else if irq as usize == VIRTIO0_IRQ {
+                console::uartintr();
+            } else if HAS_VIRTIO && irq as usize == VIRTIO0_IRQ {
                 virtio::disk_intr();
+            } else if HAS_VIRTIO && irq as usize == VIRTIO3_IRQ {
+                virtio_balloon::intr();
             }
 
             plic::complete(irq);
+            cpustat::enter(was);
         }
-        ScauseType::IntSSoft => {
+        cause @ (ScauseType::IntSSoft | ScauseType::IntSTimer) => {
             // software interrupt from a machine-mode timer interrupt,
-            // forwarded by timervec in kernelvec.S.
+            // or from an IPI, forwarded by timervec in kernelvec.S.
+            // under OpenSBI, timer interrupts come in directly instead.
+
+            count(TRAP_TIMER);
+            let was = cpustat::enter(cpustat::CPU_IRQ);
+            random::add_interrupt(0);
+
+            // another hart has panicked
+            if printf::panicked() {
+                printf::freeze();
+            }
 
             let cid = unsafe {cpu_id()};
+            watch::sync();
 
-            if cid == 0 {
+            if cid == boot_hart() {
                 clock_intr();
             }
 
-            // acknowledge the software interrupt
-            sip::clear_ssip();
+            // charge the tick to the process, for its virtual interval timer
+            if is_user {
+                unsafe {my_proc()}.user_tick();
+            }
+
+            profile::sample(cid, sepc::read(), is_user, unsafe {my_pid()});
+
+            // acknowledge the interrupt
+            match cause {
+                #[cfg(feature = "sbi")]
+                ScauseType::IntSTimer => crate::start::timer_next(),
+                _ => sip::clear_ssip(),
+            }
+
+            cpustat::enter(was);
 
             // give up the cpu
+            // in kernel mode only outside preempt_disable(), otherwise later, see cond_resched()
             let c = unsafe {my_cpu()};
             if is_user {
                 c.try_abondon(-1);
             }
-            c.yielding();
+            if !c.tick_yields() {
+                // the rest of its slice, or a real-time process keeps running
+            } else if is_user || c.preemptible() {
+                c.yielding();
+            } else {
+                c.set_need_resched();
+            }
         }
         ScauseType::ExcUEcall => {
             if !is_user {
                 panic!("handler_trap: ecall from supervisor mode");
             }
 
+            count(TRAP_SYSCALL);
             let c = unsafe {my_cpu()};
             c.syscall();
         }
-        ScauseType::Unknown => {
+        ScauseType::ExcPageFault if !is_user => {
+            count(TRAP_PAGE_FAULT);
+            panic!("handle_trap: kernel page fault, sepc={:#x} stval={:#x}, \
+                user memory is only reachable through copy_in and copy_out",
+                sepc::read(), stval::read());
+        }
+        ScauseType::ExcPageFault if is_user && scause::is_load_store_fault()
+            && unsafe {my_proc()}.zero_fault(stval::read()) => {
+            // a page of its heap not touched yet, or one it gave back with madvise,
+            // a fault beyond sz kills it below
+            count(TRAP_PAGE_FAULT);
+        }
+        ScauseType::ExcPageFault if is_user
+            && unsafe {my_proc()}.mmap_fault(stval::read(), !scause::is_load_store_fault()) => {
+            // a page of a mapped file not read in yet, see process/vma.rs,
+            // one of instructions of a PROT_EXEC mapping too
+            count(TRAP_PAGE_FAULT);
+        }
+        ScauseType::ExcBreakpoint if !is_user && watch::is_hit(sepc::read()) => {
+            count(TRAP_OTHER);
+            watch::kernel_hit(sepc::read(), stval::read());
+        }
+        ScauseType::ExcBreakpoint if is_user => {
+            count(TRAP_OTHER);
+            // a breakpoint of its tracer, see process/ptrace.rs
+            if !unsafe {my_proc()}.trace_breakpoint() {
+                println!("scause {:#x}", scause::read());
+                println!("sepc={:#x} stval={:#x}", sepc::read(), stval::read());
+                unsafe {my_proc()}.core_dump(scause::read(), sepc::read(), stval::read());
+                unsafe {my_cpu()}.abondon(-1);
+            }
+        }
+        ScauseType::ExcBreakpoint | ScauseType::ExcPageFault | ScauseType::Unknown => {
+            match scause::get_scause() {
+                ScauseType::ExcPageFault => count(TRAP_PAGE_FAULT),
+                _ => count(TRAP_OTHER),
+            }
             println!("scause {:#x}", scause::read());
             println!("sepc={:#x} stval={:#x}", sepc::read(), stval::read());
             if is_user {
+                unsafe {my_proc()}.core_dump(scause::read(), sepc::read(), stval::read());
                 let c = unsafe {my_cpu()};
                 c.abondon(-1);
             } else {
@@ -136,10 +264,49 @@ fn handle_trap(is_user: bool) {
     }
 }
 
+const TRAP_SYSCALL: usize = 0;
+const TRAP_TIMER: usize = 1;
+const TRAP_DEVICE: usize = 2;
+const TRAP_PAGE_FAULT: usize = 3;
+const TRAP_OTHER: usize = 4;
+const NTRAP: usize = 5;
+
+const TRAP_NAMES: [&str; NTRAP] = ["syscall", "timer/ipi", "device", "page fault", "other"];
+
+/// traps taken by handle_trap, by cause, for the kernel monitor
+static TRAP_COUNTS: [AtomicUsize; NTRAP] = [const { AtomicUsize::new(0) }; NTRAP];
+
+#[inline]
+fn count(cause: usize) {
+    TRAP_COUNTS[cause].fetch_add(1, Ordering::Relaxed);
+}
+
+pub fn trap_counts() -> [(&'static str, usize); NTRAP] {
+    let mut counts = [("", 0); NTRAP];
+    for (i, count) in counts.iter_mut().enumerate() {
+        *count = (TRAP_NAMES[i], TRAP_COUNTS[i].load(Ordering::Relaxed));
+    }
+    counts
+}
+
+/// the last tick the timers were run for
 static TICKS: SpinLock<usize> = SpinLock::new(0usize, "time");
 
+/// Read the number of clock ticks since the machine started.
+/// Counted from the time, since an idle boot hart skips ticks, see idle() in cpu.rs.
+pub fn ticks() -> usize {
+    (unsafe { clint::read_mtime() } / INTERVAL) as usize
+}
+
 fn clock_intr() {
+    let now = ticks();
     let mut _ticks = TICKS.lock();
-    *_ticks += 1;
+    // e.g., an IPI in the same tick
+    if now <= *_ticks {
+        return;
+    }
+    *_ticks = now;
     drop(_ticks);
+    timer::run(now);
+    expire_sleepers(now);
 }
@@ -0,0 +1,246 @@
+//! Hardware watchpoints, with the load and store address triggers of the Sdtrig extension
+//!
+//! The trigger registers, tselect and tdata1-2, are machine-mode CSRs,
+//! the kernel sets them with its one machine call, see mcall in kernelvec.S.
+//! LTODO - under OpenSBI, its debug triggers extension, SBI DBTR, until then there are none.
+//!
+//! qemu has two triggers a hart, each an mcontrol matching one address exactly:
+//! - trigger 0 is the kernel's watchpoint, set with the monitor's watch command,
+//!   or set() to catch whoever writes something, e.g., a Proc field or a PTE,
+//!   it fires in supervisor mode only, on every hart, each picks a new one up at its next tick,
+//! - trigger 1 is the watchpoint of the process running on the hart,
+//!   set by its tracer with PTRACE_WATCH, see process/ptrace.rs,
+//!   it fires in user mode only, and is switched on the way to user space.
+//!
+//! Either raises a breakpoint exception before the access is done. A watchpoint is one-shot,
+//! the kernel one prints the hart, the pc and a backtrace, and is cleared,
+//! a traced process stops with STOP_WATCH, the tracer steps it past the access to set it again.
+
+use core::sync::atomic::{AtomicUsize, Ordering};
+
+use crate::consts::NCPU;
+use crate::insn;
+use crate::printf;
+use crate::process::{cpu_id, my_pid};
+use crate::spinlock::{pop_off, push_off, SpinLock};
+
+/// What a watchpoint fires on, bits
+pub const WATCH_STORE: usize = 1;
+pub const WATCH_LOAD: usize = 2;
+
+const KERNEL_TRIGGER: usize = 0;
+const USER_TRIGGER: usize = 1;
+
+// mcontrol, tdata1 of a type 2 trigger
+const MCONTROL: usize = 2 << 60;
+const MCONTROL_S: usize = 1 << 4;
+const MCONTROL_U: usize = 1 << 3;
+const MCONTROL_STORE: usize = 1 << 1;
+const MCONTROL_LOAD: usize = 1 << 0;
+/// the bits the trigger has to keep as they were set
+const MCONTROL_KEPT: usize = 0xf << 60 | MCONTROL_S | MCONTROL_U | MCONTROL_STORE | MCONTROL_LOAD;
+
+/// tdata1 of a watchpoint of kind, firing in the modes of mode
+fn mcontrol(kind: usize, mode: usize) -> usize {
+    let mut tdata1 = MCONTROL | mode;
+    if kind & WATCH_STORE != 0 {
+        tdata1 |= MCONTROL_STORE;
+    }
+    if kind & WATCH_LOAD != 0 {
+        tdata1 |= MCONTROL_LOAD;
+    }
+    tdata1
+}
+
+/// Set trigger index of this hart, tdata1 0 turns it off
+#[cfg(not(feature = "sbi"))]
+fn set_trigger(index: usize, tdata1: usize, tdata2: usize) -> Result<(), &'static str> {
+    let took: isize;
+    unsafe {
+        core::arch::asm!("ecall",
+            inlateout("a0") index as isize => took,
+            in("a1") tdata1,
+            in("a2") tdata2,
+            options(nostack));
+    }
+    if took == -1 {
+        Err("no such trigger")
+    } else if took as usize & MCONTROL_KEPT != tdata1 & MCONTROL_KEPT {
+        Err("the trigger cannot watch that")
+    } else {
+        Ok(())
+    }
+}
+
+#[cfg(feature = "sbi")]
+fn set_trigger(_index: usize, _tdata1: usize, _tdata2: usize) -> Result<(), &'static str> {
+    Err("no triggers under OpenSBI")
+}
+
+/// Triggers of each hart, found by the boot hart
+static TRIGGERS: AtomicUsize = AtomicUsize::new(0);
+
+pub fn triggers() -> usize {
+    TRIGGERS.load(Ordering::Relaxed)
+}
+
+/// Turn off this hart's triggers, they may be set from before a reboot,
+/// and count them on the boot hart
+pub fn init_hart(boot: bool) {
+    let n = (0..=USER_TRIGGER).take_while(|i| set_trigger(*i, 0, 0).is_ok()).count();
+    if boot {
+        TRIGGERS.store(n, Ordering::Relaxed);
+    }
+}
+
+#[derive(Clone, Copy, PartialEq)]
+struct Watch {
+    addr: usize,
+    kind: usize,
+}
+
+const NONE: Watch = Watch { addr: 0, kind: 0 };
+
+/// The kernel watchpoint
+static KERNEL: SpinLock<Watch> = SpinLock::new(NONE, "watch");
+/// Bumped on every change of it, under its lock, for the harts to catch up
+static GEN: AtomicUsize = AtomicUsize::new(0);
+/// The generation each hart has set trigger 0 to
+static SEEN: [AtomicUsize; NCPU] = [const { AtomicUsize::new(0) }; NCPU];
+/// What each hart's trigger 1 is set to, only touched by it with interrupts off
+static mut USER: [Watch; NCPU] = [NONE; NCPU];
+
+/// Set the kernel watchpoint, on this hart right away, on the others at their next tick
+pub fn set(addr: usize, kind: usize) -> Result<(), &'static str> {
+    if addr == 0 || kind == 0 || kind & !(WATCH_STORE | WATCH_LOAD) != 0 {
+        return Err("bad watchpoint");
+    }
+    change(Watch { addr, kind })
+}
+
+pub fn clear() -> Result<(), &'static str> {
+    change(NONE)
+}
+
+pub fn get() -> Option<(usize, usize)> {
+    let watch = *KERNEL.lock();
+    match watch {
+        NONE => None,
+        watch => Some((watch.addr, watch.kind)),
+    }
+}
+
+fn change(watch: Watch) -> Result<(), &'static str> {
+    if triggers() == 0 {
+        return Err("no hardware triggers");
+    }
+    let mut kernel = KERNEL.lock();
+    // the generation is only bumped once this hart has it
+    program(watch)?;
+    *kernel = watch;
+    let gen = GEN.fetch_add(1, Ordering::SeqCst) + 1;
+    SEEN[unsafe { cpu_id() }].store(gen, Ordering::Relaxed);
+    drop(kernel);
+    Ok(())
+}
+
+fn program(watch: Watch) -> Result<(), &'static str> {
+    match watch {
+        NONE => set_trigger(KERNEL_TRIGGER, 0, 0),
+        watch => set_trigger(KERNEL_TRIGGER, mcontrol(watch.kind, MCONTROL_S), watch.addr),
+    }
+}
+
+/// Catch up with the kernel watchpoint, at each tick
+pub fn sync() {
+    if triggers() == 0 {
+        return;
+    }
+    let id = unsafe { cpu_id() };
+    if SEEN[id].load(Ordering::Relaxed) == GEN.load(Ordering::SeqCst) {
+        return;
+    }
+    let kernel = KERNEL.lock();
+    // it worked on the hart that set it, the others are the same
+    let _ = program(*kernel);
+    SEEN[id].store(GEN.load(Ordering::SeqCst), Ordering::Relaxed);
+    drop(kernel);
+}
+
+/// Whether inst, the one at a breakpoint exception, is a software breakpoint
+fn is_ebreak(inst: u32) -> bool {
+    match insn::inst_len(inst) {
+        2 => inst as u16 == insn::C_EBREAK,
+        _ => inst == insn::EBREAK,
+    }
+}
+
+/// The instruction at pc in kernel text, only as many bytes as it has
+fn kernel_inst(pc: usize) -> u32 {
+    let low = unsafe { (pc as *const u16).read() } as u32;
+    match insn::inst_len(low) {
+        2 => low,
+        _ => low | (unsafe { ((pc + 2) as *const u16).read() } as u32) << 16,
+    }
+}
+
+/// Whether a breakpoint exception in supervisor mode at pc is the kernel watchpoint's
+pub fn is_hit(pc: usize) -> bool {
+    triggers() > KERNEL_TRIGGER && !is_ebreak(kernel_inst(pc))
+}
+
+/// Report a hit of the kernel watchpoint at pc, and clear it
+pub fn kernel_hit(pc: usize, stval: usize) {
+    let watch = *KERNEL.lock();
+    println!("watchpoint {:#x} hit on hart {} at pc {:#x}, stval {:#x}, pid {}",
+        watch.addr, unsafe { cpu_id() }, pc, stval, unsafe { my_pid() }.unwrap_or(0));
+    printf::backtrace();
+    // already cleared by a hit on another hart, this one has not caught up yet
+    let cleared = match watch {
+        NONE => program(NONE),
+        _ => clear(),
+    };
+    if let Err(err) = cleared {
+        println!("watchpoint: {}", err);
+    }
+}
+
+/// Whether a breakpoint exception in user mode, at the instruction inst, is the watchpoint's
+pub fn is_user_hit(inst: u32) -> bool {
+    triggers() > USER_TRIGGER && !is_ebreak(inst)
+}
+
+/// Set this hart's trigger 1 to the watchpoint of the process going to user space,
+/// addr 0 for none, with interrupts off
+pub fn switch_user(addr: usize, kind: usize) {
+    if triggers() <= USER_TRIGGER {
+        return;
+    }
+    let watch = if addr == 0 { NONE } else { Watch { addr, kind } };
+    push_off();
+    let current = unsafe { &mut USER[cpu_id()] };
+    if *current != watch {
+        let tdata1 = if addr == 0 { 0 } else { mcontrol(kind, MCONTROL_U) };
+        match set_trigger(USER_TRIGGER, tdata1, addr) {
+            Ok(()) => *current = watch,
+            Err(err) => println!("watchpoint: {}", err),
+        }
+    }
+    pop_off();
+}
+
+#[cfg(feature = "unit_test")]
+pub mod tests {
+    use super::*;
+
+    pub fn watch_bits() {
+        assert_eq!(mcontrol(WATCH_STORE, MCONTROL_S), 2 << 60 | 1 << 4 | 1 << 1);
+        assert_eq!(mcontrol(WATCH_STORE | WATCH_LOAD, MCONTROL_U), 2 << 60 | 1 << 3 | 3);
+        assert!(is_ebreak(insn::EBREAK));
+        assert!(is_ebreak(insn::C_EBREAK as u32));
+        // sd a0, 0(a1) and c.sd a0, 0(a1)
+        assert!(!is_ebreak(0x00a5b023));
+        assert!(!is_ebreak(0xe188));
+    }
+    crate::kernel_test!(watch_bits);
+}
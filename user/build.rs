@@ -0,0 +1,29 @@
+//! Generate the syscall numbers from the kernel's syscall table,
+//! so that user space and the kernel never disagree.
+
+use std::env;
+use std::fmt::Write;
+use std::fs;
+use std::path::Path;
+
+fn main() {
+    let table = Path::new(env!("CARGO_MANIFEST_DIR")).join("../src/asm/syscall.h");
+    println!("cargo:rerun-if-changed={}", table.display());
+
+    let text = fs::read_to_string(&table).expect("cannot read syscall.h");
+    let mut out = String::new();
+    for line in text.lines() {
+        let mut words = line.split_whitespace();
+        if words.next() != Some("#define") {
+            continue;
+        }
+        let (name, nr) = match (words.next(), words.next()) {
+            (Some(name), Some(nr)) if name.starts_with("SYS_") => (name, nr),
+            _ => continue,
+        };
+        writeln!(out, "pub const {}: usize = {};", name.to_uppercase(), nr).unwrap();
+    }
+
+    let dst = Path::new(&env::var("OUT_DIR").unwrap()).join("syscall_nr.rs");
+    fs::write(dst, out).unwrap();
+}
@@ -0,0 +1,61 @@
+#![no_std]
+#![no_main]
+
+use core::sync::atomic::{AtomicUsize, Ordering};
+
+use user::{eprintln, println, setitimer, uptime, Args, ITIMER_REAL, ITIMER_VIRTUAL};
+
+user::entry!(main);
+
+const FIRINGS: usize = 5;
+const INTERVAL: usize = 2;
+
+static COUNT: [AtomicUsize; 2] = [AtomicUsize::new(0), AtomicUsize::new(0)];
+
+fn handler(which: usize) {
+    COUNT[which].fetch_add(1, Ordering::SeqCst);
+}
+
+/// Spin until timer which fired FIRINGS times, or give up after limit ticks
+fn spin(which: usize, limit: isize) -> bool {
+    let start = uptime();
+    while COUNT[which].load(Ordering::SeqCst) < FIRINGS {
+        if uptime() - start > limit {
+            return false;
+        }
+    }
+    true
+}
+
+fn test(which: usize, name: &str) -> bool {
+    println!("alarm: {} every {} ticks", name, INTERVAL);
+    if setitimer(which, INTERVAL, INTERVAL, handler) < 0 {
+        eprintln!("alarm: setitimer {} failed", name);
+        return false;
+    }
+    let ok = spin(which, 100 * (FIRINGS * INTERVAL) as isize);
+    setitimer(which, 0, 0, handler);
+    if !ok {
+        eprintln!("alarm: {} fired {} times", name, COUNT[which].load(Ordering::SeqCst));
+        return false;
+    }
+
+    // disarmed, nothing more comes
+    let count = COUNT[which].load(Ordering::SeqCst);
+    let start = uptime();
+    while uptime() - start < (4 * INTERVAL) as isize {}
+    if COUNT[which].load(Ordering::SeqCst) != count {
+        eprintln!("alarm: {} fired after it was disarmed", name);
+        return false;
+    }
+    true
+}
+
+fn main(_args: Args) -> i32 {
+    let ok = test(ITIMER_REAL, "real") && test(ITIMER_VIRTUAL, "virtual");
+    if !ok {
+        return 1;
+    }
+    println!("alarm: ok");
+    0
+}
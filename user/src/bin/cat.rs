@@ -0,0 +1,50 @@
+#![no_std]
+#![no_main]
+
+use user::fcntl::O_RDONLY;
+use user::{close, eprintln, open, read, write, Args, STDOUT};
+
+user::entry!(main);
+
+fn cat(fd: i32) -> Result<(), &'static str> {
+    let mut buf: [u8; 512] = [0; 512];
+    loop {
+        let n = read(fd, &mut buf);
+        if n < 0 {
+            return Err("read error");
+        }
+        if n == 0 {
+            return Ok(());
+        }
+        if write(STDOUT, &buf[..n as usize]) != n {
+            return Err("write error");
+        }
+    }
+}
+
+fn main(args: Args) -> i32 {
+    if args.len() <= 1 {
+        return match cat(0) {
+            Ok(()) => 0,
+            Err(err) => {
+                eprintln!("cat: {}", err);
+                1
+            }
+        };
+    }
+
+    for path in args.iter().skip(1) {
+        let fd = open(path, O_RDONLY);
+        if fd < 0 {
+            eprintln!("cat: cannot open {}", path);
+            return 1;
+        }
+        let ret = cat(fd as i32);
+        close(fd as i32);
+        if let Err(err) = ret {
+            eprintln!("cat: {}", err);
+            return 1;
+        }
+    }
+    0
+}
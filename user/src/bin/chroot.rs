@@ -0,0 +1,28 @@
+#![no_std]
+#![no_main]
+
+use user::{chroot, eprintln, exec, Args, MAXARG};
+
+user::entry!(main);
+
+/// Run command with dir as the root, command is looked up inside it
+fn main(args: Args) -> i32 {
+    if args.len() < 3 || args.len() - 2 > MAXARG {
+        eprintln!("Usage: chroot dir command [args...]");
+        return 1;
+    }
+    let dir = args.get(1).unwrap();
+    if chroot(dir) < 0 {
+        eprintln!("chroot: cannot chroot to {}", dir);
+        return 1;
+    }
+
+    let mut argv: [&str; MAXARG] = [""; MAXARG];
+    let n = args.len() - 2;
+    for (i, arg) in args.iter().skip(2).enumerate() {
+        argv[i] = arg;
+    }
+    exec(argv[0], &argv[..n]);
+    eprintln!("chroot: exec {} failed", argv[0]);
+    1
+}
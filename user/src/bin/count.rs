@@ -0,0 +1,33 @@
+#![no_std]
+#![no_main]
+
+use user::{checkpoint, eprintln, println, sleep, Args};
+
+user::entry!(main);
+
+/// Count up to n, ten ticks a number, saving a checkpoint to file before each,
+/// which restore carries on from
+fn main(args: Args) -> i32 {
+    let n = match (args.len(), args.get(1).map(str::parse::<usize>)) {
+        (3, Some(Ok(n))) => n,
+        _ => {
+            eprintln!("Usage: count n file");
+            return 1;
+        }
+    };
+    let path = args.get(2).unwrap();
+
+    for i in 0..n {
+        match checkpoint(path) {
+            0 => {}
+            1 => println!("count: restored at {}", i),
+            _ => {
+                eprintln!("count: cannot save {}", path);
+                return 1;
+            }
+        }
+        println!("{}", i);
+        sleep(10);
+    }
+    0
+}
@@ -0,0 +1,46 @@
+#![no_std]
+#![no_main]
+
+use user::fcntl::{O_CREATE, O_RDONLY, O_TRUNC, O_WRONLY};
+use user::{close, copy_file_range, eprintln, open, Args};
+
+user::entry!(main);
+
+/// Copy the whole of fd_in to fd_out, in the kernel, see copy_file_range
+fn cp(fd_in: i32, fd_out: i32) -> Result<(), &'static str> {
+    loop {
+        match copy_file_range(fd_in, None, fd_out, None, 64 * 1024) {
+            n if n < 0 => return Err("copy error"),
+            0 => return Ok(()),
+            _ => {}
+        }
+    }
+}
+
+fn main(args: Args) -> i32 {
+    if args.len() != 3 {
+        eprintln!("Usage: cp src dst");
+        return 1;
+    }
+    let (src, dst) = (args.get(1).unwrap(), args.get(2).unwrap());
+
+    let fd_in = open(src, O_RDONLY);
+    if fd_in < 0 {
+        eprintln!("cp: cannot open {}", src);
+        return 1;
+    }
+    let fd_out = open(dst, O_WRONLY | O_CREATE | O_TRUNC);
+    if fd_out < 0 {
+        eprintln!("cp: cannot create {}", dst);
+        close(fd_in as i32);
+        return 1;
+    }
+    let ret = cp(fd_in as i32, fd_out as i32);
+    close(fd_out as i32);
+    close(fd_in as i32);
+    if let Err(err) = ret {
+        eprintln!("cp: {}", err);
+        return 1;
+    }
+    0
+}
@@ -0,0 +1,14 @@
+#![no_std]
+#![no_main]
+
+use user::{write, Args, STDOUT};
+
+user::entry!(main);
+
+fn main(args: Args) -> i32 {
+    for (i, arg) in args.iter().enumerate().skip(1) {
+        write(STDOUT, arg.as_bytes());
+        write(STDOUT, if i + 1 < args.len() { b" " } else { b"\n" });
+    }
+    0
+}
@@ -0,0 +1,23 @@
+#![no_std]
+#![no_main]
+
+use user::{eprintln, ftrace, Args, FTRACE_DUMP, FTRACE_OFF, FTRACE_ON};
+
+user::entry!(main);
+
+fn main(args: Args) -> i32 {
+    let cmd = match args.get(1) {
+        Some("on") => FTRACE_ON,
+        Some("off") => FTRACE_OFF,
+        Some("dump") => FTRACE_DUMP,
+        _ => {
+            eprintln!("Usage: ftrace on|off|dump");
+            return 1;
+        }
+    };
+    if ftrace(cmd) < 0 {
+        eprintln!("ftrace: not supported, build the kernel with --features ftrace");
+        return 1;
+    }
+    0
+}
@@ -0,0 +1,105 @@
+//! Simple grep. Only supports ^ . * $ operators.
+
+#![no_std]
+#![no_main]
+
+use user::fcntl::O_RDONLY;
+use user::{close, eprintln, open, println, read, write, Args, STDOUT};
+
+user::entry!(main);
+
+fn grep(pattern: &[u8], fd: i32) {
+    let mut buf: [u8; 1024] = [0; 1024];
+    let mut m = 0;
+    loop {
+        let n = read(fd, &mut buf[m..]);
+        if n <= 0 {
+            break;
+        }
+        m += n as usize;
+
+        // match each complete line
+        let mut start = 0;
+        while let Some(len) = buf[start..m].iter().position(|c| *c == b'\n') {
+            let line = &buf[start..start + len];
+            if matches(pattern, line) {
+                write(STDOUT, &buf[start..start + len + 1]);
+            }
+            start += len + 1;
+        }
+
+        // keep the incomplete line, drop it if it fills the buffer
+        if start == 0 && m == buf.len() {
+            m = 0;
+        } else if start > 0 {
+            buf.copy_within(start..m, 0);
+            m -= start;
+        }
+    }
+}
+
+fn matches(re: &[u8], text: &[u8]) -> bool {
+    if re.first() == Some(&b'^') {
+        return match_here(&re[1..], text);
+    }
+    // must look at empty string
+    for i in 0..=text.len() {
+        if match_here(re, &text[i..]) {
+            return true;
+        }
+    }
+    false
+}
+
+/// Search for re at beginning of text
+fn match_here(re: &[u8], text: &[u8]) -> bool {
+    match re {
+        [] => true,
+        [c, b'*', rest @ ..] => match_star(*c, rest, text),
+        [b'$'] => text.is_empty(),
+        [c, rest @ ..] => match text.first() {
+            Some(t) if *c == b'.' || c == t => match_here(rest, &text[1..]),
+            _ => false,
+        },
+    }
+}
+
+/// Search for c*re at beginning of text
+fn match_star(c: u8, re: &[u8], mut text: &[u8]) -> bool {
+    loop {
+        // a * matches zero or more instances
+        if match_here(re, text) {
+            return true;
+        }
+        match text.first() {
+            Some(t) if *t == c || c == b'.' => text = &text[1..],
+            _ => return false,
+        }
+    }
+}
+
+fn main(args: Args) -> i32 {
+    let pattern = match args.get(1) {
+        Some(pattern) => pattern.as_bytes(),
+        None => {
+            eprintln!("usage: grep pattern [file ...]");
+            return 1;
+        }
+    };
+
+    if args.len() <= 2 {
+        grep(pattern, 0);
+        return 0;
+    }
+
+    for path in args.iter().skip(2) {
+        let fd = open(path, O_RDONLY);
+        if fd < 0 {
+            println!("grep: cannot open {}", path);
+            return 1;
+        }
+        grep(pattern, fd as i32);
+        close(fd as i32);
+    }
+    0
+}
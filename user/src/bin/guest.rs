@@ -0,0 +1,17 @@
+#![no_std]
+#![no_main]
+
+use user::{eprintln, hvguest, println, Args};
+
+user::entry!(main);
+
+fn main(_args: Args) -> i32 {
+    let reason = hvguest();
+    if reason < 0 {
+        eprintln!("guest: not supported, build the kernel with --features hypervisor");
+        eprintln!("       and run it on harts with the H extension");
+        return 1;
+    }
+    println!("guest: shut down, reason {}", reason);
+    0
+}
@@ -0,0 +1,64 @@
+#![no_std]
+#![no_main]
+
+use user::{eprintln, exec, getegid, geteuid, getgid, getuid, println, setgid, setuid, Args};
+
+user::entry!(main);
+
+fn usage() -> i32 {
+    eprintln!("Usage: id");
+    eprintln!("       id -u uid [-g gid] command [args...]");
+    1
+}
+
+/// Print the ids, or run command as uid and gid
+fn main(args: Args) -> i32 {
+    if args.len() == 1 {
+        println!("uid={} euid={} gid={} egid={}", getuid(), geteuid(), getgid(), getegid());
+        return 0;
+    }
+
+    let mut i = 1;
+    let mut uid = None;
+    let mut gid = None;
+    while i + 1 < args.len() {
+        let id = match args.get(i + 1).unwrap().parse::<u32>() {
+            Ok(id) => id,
+            Err(_) => return usage(),
+        };
+        match args.get(i).unwrap() {
+            "-u" => uid = Some(id),
+            "-g" => gid = Some(id),
+            _ => break,
+        }
+        i += 2;
+    }
+    if uid.is_none() || i >= args.len() {
+        return usage();
+    }
+
+    // the group first, it is no longer allowed once the uid is not root
+    if let Some(gid) = gid {
+        if setgid(gid) < 0 {
+            eprintln!("id: setgid {} failed", gid);
+            return 1;
+        }
+    }
+    let uid = uid.unwrap();
+    if setuid(uid) < 0 {
+        eprintln!("id: setuid {} failed", uid);
+        return 1;
+    }
+
+    let mut argv: [&str; user::MAXARG] = [""; user::MAXARG];
+    let n = args.len() - i;
+    if n > user::MAXARG {
+        return usage();
+    }
+    for (j, arg) in args.iter().skip(i).enumerate() {
+        argv[j] = arg;
+    }
+    exec(argv[0], &argv[..n]);
+    eprintln!("id: exec {} failed", argv[0]);
+    1
+}
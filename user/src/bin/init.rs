@@ -0,0 +1,63 @@
+//! init: The initial user-level program
+//!
+//! Loaded by the kernel as the first process.
+//! It opens the console as fd 0/1/2, makes the framebuffer's and the random device files,
+//! keeps a shell running,
+//! and reaps orphans that get reparented to it.
+
+#![no_std]
+#![no_main]
+
+use user::fcntl::O_RDWR;
+use user::fb::FB;
+use user::{dup, exec, exit, fork, mknod, open, println, stat, wait, RANDOM};
+
+user::entry!(main);
+
+/// major device number of the console
+const CONSOLE: i16 = 1;
+
+fn main(_args: user::Args) -> i32 {
+    if open("console", O_RDWR) < 0 {
+        mknod("console", CONSOLE, 1);
+        open("console", O_RDWR);
+    }
+    dup(0); // stdout
+    dup(0); // stderr
+    if stat("fb").is_none() {
+        mknod("fb", FB, 0);
+    }
+    if stat("random").is_none() {
+        mknod("random", RANDOM, 0);
+    }
+    if stat("urandom").is_none() {
+        mknod("urandom", RANDOM, 1);
+    }
+
+    loop {
+        println!("init: starting sh");
+        let pid = fork();
+        if pid < 0 {
+            println!("init: fork failed");
+            exit(1);
+        }
+        if pid == 0 {
+            exec("sh", &["sh"]);
+            println!("init: exec sh failed");
+            exit(1);
+        }
+
+        // reap children until the shell exits,
+        // these are orphans whose parent exited before them
+        loop {
+            match wait() {
+                Some((wpid, _)) if wpid == pid => break,
+                Some(_) => continue,
+                None => {
+                    println!("init: wait returned an error");
+                    exit(1);
+                }
+            }
+        }
+    }
+}
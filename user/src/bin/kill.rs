@@ -0,0 +1,28 @@
+#![no_std]
+#![no_main]
+
+use user::{eprintln, kill, Args};
+
+user::entry!(main);
+
+fn main(args: Args) -> i32 {
+    if args.len() < 2 {
+        eprintln!("usage: kill pid...");
+        return 1;
+    }
+
+    for arg in args.iter().skip(1) {
+        match arg.parse::<i32>() {
+            Ok(pid) if kill(pid) == 0 => {}
+            Ok(pid) => {
+                eprintln!("kill: {} failed to kill", pid);
+                return 1;
+            }
+            Err(_) => {
+                eprintln!("kill: bad pid {}", arg);
+                return 1;
+            }
+        }
+    }
+    0
+}
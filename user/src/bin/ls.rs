@@ -0,0 +1,96 @@
+#![no_std]
+#![no_main]
+
+use core::{mem, slice, str};
+
+use user::fcntl::O_RDONLY;
+use user::fs::{Dirent, DIRSIZ};
+use user::stat::{T_DEVICE, T_DIR, T_FIFO, T_FILE};
+use user::{close, eprintln, fstat, open, println, read, stat, Args, MAXPATH};
+
+user::entry!(main);
+
+/// Last path element, padded to DIRSIZ
+struct Name<'a>(&'a str);
+
+impl<'a> core::fmt::Display for Name<'a> {
+    fn fmt(&self, f: &mut core::fmt::Formatter) -> core::fmt::Result {
+        let name = self.0.rsplit('/').next().unwrap_or(self.0);
+        write!(f, "{:<width$}", name, width = DIRSIZ)
+    }
+}
+
+fn ls(path: &str) {
+    let fd = open(path, O_RDONLY);
+    if fd < 0 {
+        eprintln!("ls: cannot open {}", path);
+        return;
+    }
+    let fd = fd as i32;
+
+    let st = match fstat(fd) {
+        Some(st) => st,
+        None => {
+            eprintln!("ls: cannot stat {}", path);
+            close(fd);
+            return;
+        }
+    };
+
+    match st.typ {
+        T_FILE | T_DEVICE | T_FIFO => {
+            println!("{} {} {} {}", Name(path), st.typ, st.ino, st.size);
+        }
+        T_DIR => {
+            if path.len() + 1 + DIRSIZ + 1 > MAXPATH {
+                println!("ls: path too long");
+                close(fd);
+                return;
+            }
+            let mut buf: [u8; MAXPATH] = [0; MAXPATH];
+            buf[..path.len()].copy_from_slice(path.as_bytes());
+            buf[path.len()] = b'/';
+            let prefix = path.len() + 1;
+
+            let mut de = Dirent::default();
+            loop {
+                let dst = unsafe {
+                    slice::from_raw_parts_mut(&mut de as *mut Dirent as *mut u8, mem::size_of::<Dirent>())
+                };
+                if read(fd, dst) != Dirent::SIZE as isize {
+                    break;
+                }
+                if de.inum == 0 {
+                    continue;
+                }
+                let name = de.name();
+                buf[prefix..prefix + name.len()].copy_from_slice(name);
+                let full = match str::from_utf8(&buf[..prefix + name.len()]) {
+                    Ok(full) => full,
+                    Err(_) => continue,
+                };
+                match stat(full) {
+                    Some(st) => {
+                        println!("{} {} {} {}", Name(full), st.typ, st.ino, st.size);
+                    }
+                    None => {
+                        println!("ls: cannot stat {}", full);
+                    }
+                }
+            }
+        }
+        _ => {}
+    }
+    close(fd);
+}
+
+fn main(args: Args) -> i32 {
+    if args.len() < 2 {
+        ls(".");
+        return 0;
+    }
+    for path in args.iter().skip(1) {
+        ls(path);
+    }
+    0
+}
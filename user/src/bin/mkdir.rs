@@ -0,0 +1,21 @@
+#![no_std]
+#![no_main]
+
+use user::{eprintln, mkdir, Args};
+
+user::entry!(main);
+
+fn main(args: Args) -> i32 {
+    if args.len() < 2 {
+        eprintln!("Usage: mkdir files...");
+        return 1;
+    }
+
+    for path in args.iter().skip(1) {
+        if mkdir(path) < 0 {
+            eprintln!("mkdir: {} failed to create", path);
+            return 1;
+        }
+    }
+    0
+}
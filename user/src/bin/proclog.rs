@@ -0,0 +1,22 @@
+#![no_std]
+#![no_main]
+
+use user::{eprintln, proclog, Args};
+
+user::entry!(main);
+
+fn main(args: Args) -> i32 {
+    let on = match args.get(1) {
+        Some("on") => true,
+        Some("off") => false,
+        _ => {
+            eprintln!("Usage: proclog on|off");
+            return 1;
+        }
+    };
+    if proclog(on) < 0 {
+        eprintln!("proclog: permission denied");
+        return 1;
+    }
+    0
+}
@@ -0,0 +1,77 @@
+#![no_std]
+#![no_main]
+
+use user::{eprintln, exec, exit, fork, println, prof_dump, prof_start, prof_stop, wait, Args, ProfEntry};
+
+user::entry!(main);
+
+/// entries printed at most, the kernel aggregates into 128
+const NENTRY: usize = 128;
+
+fn usage() -> i32 {
+    eprintln!("Usage: prof start|stop|dump");
+    eprintln!("       prof run command [args...]");
+    1
+}
+
+fn dump() -> i32 {
+    let mut entries = [ProfEntry::default(); NENTRY];
+    let n = prof_dump(&mut entries);
+    if n < 0 {
+        eprintln!("prof: dump failed");
+        return 1;
+    }
+    let entries = &entries[..n as usize];
+    let total: usize = entries.iter().map(|e| e.count).sum();
+    println!("{} samples", total);
+    println!("   count      pc             pid");
+    for e in entries {
+        // a kernel pc of 0 marks the samples that did not fit
+        if e.pc == 0 && e.user == 0 {
+            println!("{:>8}   (others)", e.count);
+        } else {
+            println!("{:>8}   {:#014x} {} {}", e.count, e.pc, if e.user != 0 { 'u' } else { 'k' }, e.pid);
+        }
+    }
+    0
+}
+
+fn main(args: Args) -> i32 {
+    match args.get(1) {
+        Some("start") => {
+            prof_start();
+            0
+        }
+        Some("stop") => {
+            prof_stop();
+            0
+        }
+        Some("dump") => dump(),
+        Some("run") if args.len() > 2 => {
+            let mut argv: [&str; user::MAXARG] = [""; user::MAXARG];
+            let argc = (args.len() - 2).min(argv.len());
+            for (slot, arg) in argv.iter_mut().zip(args.iter().skip(2)) {
+                *slot = arg;
+            }
+            prof_start();
+            match fork() {
+                0 => {
+                    exec(argv[0], &argv[..argc]);
+                    eprintln!("prof: exec {} failed", argv[0]);
+                    exit(1);
+                }
+                pid if pid < 0 => {
+                    prof_stop();
+                    eprintln!("prof: fork failed");
+                    return 1;
+                }
+                _ => {
+                    wait();
+                }
+            }
+            prof_stop();
+            dump()
+        }
+        _ => usage(),
+    }
+}
@@ -0,0 +1,24 @@
+#![no_std]
+#![no_main]
+
+use user::{eprintln, restore, wait, Args};
+
+user::entry!(main);
+
+/// Carry on as the process saved in the checkpoint file, see count, in a child,
+/// and exit as it does
+fn main(args: Args) -> i32 {
+    if args.len() != 2 {
+        eprintln!("Usage: restore file");
+        return 1;
+    }
+    let path = args.get(1).unwrap();
+    if restore(path) < 0 {
+        eprintln!("restore: cannot restore {}", path);
+        return 1;
+    }
+    match wait() {
+        Some((_, status)) => status,
+        None => 1,
+    }
+}
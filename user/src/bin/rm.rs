@@ -0,0 +1,21 @@
+#![no_std]
+#![no_main]
+
+use user::{eprintln, unlink, Args};
+
+user::entry!(main);
+
+fn main(args: Args) -> i32 {
+    if args.len() < 2 {
+        eprintln!("Usage: rm files...");
+        return 1;
+    }
+
+    for path in args.iter().skip(1) {
+        if unlink(path) < 0 {
+            eprintln!("rm: {} failed to delete", path);
+            return 1;
+        }
+    }
+    0
+}
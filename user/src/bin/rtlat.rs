@@ -0,0 +1,54 @@
+#![no_std]
+#![no_main]
+
+use user::{eprintln, println, sched_setscheduler, sleep, uptime, Args, SCHED_FIFO};
+
+user::entry!(main);
+
+/// ticks between wakeups
+const PERIOD: isize = 2;
+
+fn usage() -> i32 {
+    eprintln!("Usage: rtlat [prio [periods]]");
+    eprintln!("       prio 0 stays SCHED_OTHER, to compare with");
+    1
+}
+
+/// Wake up every PERIOD ticks, like an audio task filling its buffer,
+/// and print how many ticks late it woke up, at most and on average
+fn main(args: Args) -> i32 {
+    let mut nums = [50, 100];
+    for (i, num) in nums.iter_mut().enumerate() {
+        if let Some(arg) = args.get(i + 1) {
+            match arg.parse::<usize>() {
+                Ok(n) => *num = n,
+                Err(_) => return usage(),
+            }
+        }
+    }
+    let [prio, periods] = nums;
+    if periods == 0 {
+        return usage();
+    }
+    if prio > 0 && sched_setscheduler(0, SCHED_FIFO, prio) < 0 {
+        eprintln!("rtlat: sched_setscheduler failed");
+        return 1;
+    }
+
+    let start = uptime();
+    let mut max = 0;
+    let mut total = 0;
+    for k in 1..=periods as isize {
+        let due = start + k * PERIOD;
+        let left = due - uptime();
+        if left > 0 {
+            sleep(left as i32);
+        }
+        let late = uptime() - due;
+        max = max.max(late);
+        total += late;
+    }
+    println!("rtlat: prio {}, {} periods of {} ticks, late by {} at most, {}/{} on average",
+        prio, periods, PERIOD, max, total, periods);
+    0
+}
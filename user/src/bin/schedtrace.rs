@@ -0,0 +1,89 @@
+#![no_std]
+#![no_main]
+
+use user::{eprintln, exec, exit, fork, println, wait, Args, SchedEvent};
+use user::{schedtrace_dump, schedtrace_start, schedtrace_stop};
+use user::{SCHED_BLOCK, SCHED_EXIT, SCHED_RUN, SCHED_WAKEUP, SCHED_YIELD};
+
+user::entry!(main);
+
+/// events printed at most, the kernel keeps 512 per hart
+const NEVENT: usize = 1024;
+
+fn usage() -> i32 {
+    eprintln!("Usage: schedtrace start|stop|dump");
+    eprintln!("       schedtrace run command [args...]");
+    1
+}
+
+fn reason(r: u32) -> &'static str {
+    match r {
+        SCHED_RUN => "run",
+        SCHED_YIELD => "yield",
+        SCHED_BLOCK => "block",
+        SCHED_EXIT => "exit",
+        SCHED_WAKEUP => "wakeup",
+        _ => "?",
+    }
+}
+
+fn dump() -> i32 {
+    // too big for the user stack
+    static mut EVENTS: [SchedEvent; NEVENT] = [SchedEvent {
+        time: 0, hart: 0, reason: 0, from: 0, to: 0,
+    }; NEVENT];
+    let events = unsafe { &mut *core::ptr::addr_of_mut!(EVENTS) };
+    let n = schedtrace_dump(events);
+    if n < 0 {
+        eprintln!("schedtrace: dump failed");
+        return 1;
+    }
+    let events = &events[..n as usize];
+    let start = events.first().map_or(0, |e| e.time);
+    println!("{} events", events.len());
+    println!("        time hart event   from   to");
+    for e in events {
+        println!("{:>12} {:>4} {:<7} {:>4} {:>4}", e.time - start, e.hart, reason(e.reason), e.from, e.to);
+    }
+    0
+}
+
+fn main(args: Args) -> i32 {
+    match args.get(1) {
+        Some("start") => {
+            schedtrace_start();
+            0
+        }
+        Some("stop") => {
+            schedtrace_stop();
+            0
+        }
+        Some("dump") => dump(),
+        Some("run") if args.len() > 2 => {
+            let mut argv: [&str; user::MAXARG] = [""; user::MAXARG];
+            let argc = (args.len() - 2).min(argv.len());
+            for (slot, arg) in argv.iter_mut().zip(args.iter().skip(2)) {
+                *slot = arg;
+            }
+            schedtrace_start();
+            match fork() {
+                0 => {
+                    exec(argv[0], &argv[..argc]);
+                    eprintln!("schedtrace: exec {} failed", argv[0]);
+                    exit(1);
+                }
+                pid if pid < 0 => {
+                    schedtrace_stop();
+                    eprintln!("schedtrace: fork failed");
+                    return 1;
+                }
+                _ => {
+                    wait();
+                }
+            }
+            schedtrace_stop();
+            dump()
+        }
+        _ => usage(),
+    }
+}
@@ -0,0 +1,345 @@
+//! Shell
+//!
+//! Supports commands with arguments, redirection with <, > and >>,
+//! pipelines with |, background jobs with &, sequencing with ;,
+//! and grouping with ( ).
+//! The parser is the one of xv6's sh.c,
+//! with commands kept in a fixed array instead of malloc'ed.
+
+#![no_std]
+#![no_main]
+
+use core::str;
+
+use user::fcntl::{O_CREATE, O_RDONLY, O_RDWR, O_TRUNC, O_WRONLY};
+use user::{chdir, close, dup, eprint, eprintln, exec, exit, fork, open, pipe, read, wait};
+
+user::entry!(main);
+
+const MAXARGS: usize = 16;
+const MAXCMD: usize = 32;
+const LINE: usize = 128;
+
+const WHITESPACE: &[u8] = b" \t\r\n\x0b";
+const SYMBOLS: &[u8] = b"<|>&;()";
+
+// the commands live in the parser's fixed table, Copy, not boxed
+#[derive(Copy, Clone)]
+#[allow(clippy::large_enum_variant)]
+enum Cmd<'a> {
+    Exec { argv: [&'a str; MAXARGS], argc: usize },
+    Redir { cmd: usize, file: &'a str, mode: i32, fd: i32 },
+    Pipe { left: usize, right: usize },
+    List { left: usize, right: usize },
+    Back { cmd: usize },
+}
+
+type Result<T> = core::result::Result<T, &'static str>;
+
+/// Parsed commands refer to each other by their index in cmds
+struct Parser<'a> {
+    s: &'a str,
+    pos: usize,
+    cmds: [Option<Cmd<'a>>; MAXCMD],
+    ncmd: usize,
+}
+
+impl<'a> Parser<'a> {
+    /// Parse a whole line, return the parser and the index of the top command
+    fn parse(s: &'a str) -> Result<(Self, usize)> {
+        let mut p = Self {
+            s,
+            pos: 0,
+            cmds: [None; MAXCMD],
+            ncmd: 0,
+        };
+        let cmd = p.parse_line()?;
+        p.peek(b"");
+        if p.pos != p.s.len() {
+            return Err("syntax error");
+        }
+        Ok((p, cmd))
+    }
+
+    fn alloc(&mut self, cmd: Cmd<'a>) -> Result<usize> {
+        if self.ncmd == MAXCMD {
+            return Err("too many commands");
+        }
+        self.cmds[self.ncmd] = Some(cmd);
+        self.ncmd += 1;
+        Ok(self.ncmd - 1)
+    }
+
+    fn cmd(&self, i: usize) -> Cmd<'a> {
+        self.cmds[i].unwrap()
+    }
+
+    /// Skip whitespace, and tell if the next byte is one of toks
+    fn peek(&mut self, toks: &[u8]) -> bool {
+        let b = self.s.as_bytes();
+        while self.pos < b.len() && WHITESPACE.contains(&b[self.pos]) {
+            self.pos += 1;
+        }
+        self.pos < b.len() && toks.contains(&b[self.pos])
+    }
+
+    /// Return the next token, 0 at the end of line,
+    /// b'a' with the word for a word, '+' for >>,
+    /// and the symbol itself otherwise.
+    fn get_token(&mut self) -> (u8, &'a str) {
+        self.peek(b"");
+        let b = self.s.as_bytes();
+        if self.pos == b.len() {
+            return (0, "");
+        }
+
+        let c = b[self.pos];
+        match c {
+            b'|' | b'(' | b')' | b';' | b'&' | b'<' => {
+                self.pos += 1;
+                (c, "")
+            }
+            b'>' => {
+                self.pos += 1;
+                if self.pos < b.len() && b[self.pos] == b'>' {
+                    self.pos += 1;
+                    (b'+', "")
+                } else {
+                    (b'>', "")
+                }
+            }
+            _ => {
+                let start = self.pos;
+                while self.pos < b.len()
+                    && !WHITESPACE.contains(&b[self.pos])
+                    && !SYMBOLS.contains(&b[self.pos])
+                {
+                    self.pos += 1;
+                }
+                (b'a', &self.s[start..self.pos])
+            }
+        }
+    }
+
+    fn parse_line(&mut self) -> Result<usize> {
+        let mut cmd = self.parse_pipe()?;
+        while self.peek(b"&") {
+            self.get_token();
+            cmd = self.alloc(Cmd::Back { cmd })?;
+        }
+        if self.peek(b";") {
+            self.get_token();
+            let right = self.parse_line()?;
+            cmd = self.alloc(Cmd::List { left: cmd, right })?;
+        }
+        Ok(cmd)
+    }
+
+    fn parse_pipe(&mut self) -> Result<usize> {
+        let mut cmd = self.parse_exec()?;
+        if self.peek(b"|") {
+            self.get_token();
+            let right = self.parse_pipe()?;
+            cmd = self.alloc(Cmd::Pipe { left: cmd, right })?;
+        }
+        Ok(cmd)
+    }
+
+    fn parse_redirs(&mut self, mut cmd: usize) -> Result<usize> {
+        while self.peek(b"<>") {
+            let (tok, _) = self.get_token();
+            let file = match self.get_token() {
+                (b'a', file) => file,
+                _ => return Err("missing file for redirection"),
+            };
+            let (mode, fd) = match tok {
+                b'<' => (O_RDONLY, 0),
+                b'>' => (O_WRONLY | O_CREATE | O_TRUNC, 1),
+                _ => (O_WRONLY | O_CREATE, 1), // >>
+            };
+            cmd = self.alloc(Cmd::Redir { cmd, file, mode, fd })?;
+        }
+        Ok(cmd)
+    }
+
+    fn parse_block(&mut self) -> Result<usize> {
+        self.get_token(); // (
+        let cmd = self.parse_line()?;
+        if !self.peek(b")") {
+            return Err("syntax - missing )");
+        }
+        self.get_token();
+        self.parse_redirs(cmd)
+    }
+
+    fn parse_exec(&mut self) -> Result<usize> {
+        if self.peek(b"(") {
+            return self.parse_block();
+        }
+
+        let exec = self.alloc(Cmd::Exec {
+            argv: [""; MAXARGS],
+            argc: 0,
+        })?;
+        let mut cmd = self.parse_redirs(exec)?;
+        while !self.peek(b"|)&;") {
+            let word = match self.get_token() {
+                (0, _) => break,
+                (b'a', word) => word,
+                _ => return Err("syntax error"),
+            };
+            if let Some(Cmd::Exec { argv, argc }) = self.cmds[exec].as_mut() {
+                if *argc == MAXARGS {
+                    return Err("too many args");
+                }
+                argv[*argc] = word;
+                *argc += 1;
+            }
+            cmd = self.parse_redirs(cmd)?;
+        }
+        Ok(cmd)
+    }
+
+    /// Execute cmd in the current process, never returns
+    fn run(&self, cmd: usize) -> ! {
+        match self.cmd(cmd) {
+            Cmd::Exec { argv, argc } => {
+                if argc == 0 {
+                    exit(1);
+                }
+                exec(argv[0], &argv[..argc]);
+                eprintln!("exec {} failed", argv[0]);
+                exit(1);
+            }
+            Cmd::Redir { cmd, file, mode, fd } => {
+                close(fd);
+                if open(file, mode) < 0 {
+                    eprintln!("open {} failed", file);
+                    exit(1);
+                }
+                self.run(cmd);
+            }
+            Cmd::List { left, right } => {
+                if fork1() == 0 {
+                    self.run(left);
+                }
+                wait();
+                self.run(right);
+            }
+            Cmd::Pipe { left, right } => {
+                let (r, w) = match pipe() {
+                    Some(fds) => fds,
+                    None => fatal("pipe"),
+                };
+                if fork1() == 0 {
+                    close(1);
+                    dup(w);
+                    close(r);
+                    close(w);
+                    self.run(left);
+                }
+                if fork1() == 0 {
+                    close(0);
+                    dup(r);
+                    close(r);
+                    close(w);
+                    self.run(right);
+                }
+                close(r);
+                close(w);
+                wait();
+                wait();
+            }
+            Cmd::Back { cmd } => {
+                if fork1() == 0 {
+                    self.run(cmd);
+                }
+            }
+        }
+        exit(0)
+    }
+}
+
+fn fatal(msg: &str) -> ! {
+    eprintln!("sh: {}", msg);
+    exit(1)
+}
+
+fn fork1() -> isize {
+    let pid = fork();
+    if pid < 0 {
+        fatal("fork");
+    }
+    pid
+}
+
+/// Prompt and read a line, return its length,
+/// or None at end of input
+fn get_cmd(buf: &mut [u8]) -> Option<usize> {
+    eprint!("$ ");
+
+    let mut n = 0;
+    let mut c: [u8; 1] = [0];
+    while n < buf.len() {
+        if read(0, &mut c) < 1 {
+            break;
+        }
+        buf[n] = c[0];
+        n += 1;
+        if c[0] == b'\n' || c[0] == b'\r' {
+            break;
+        }
+    }
+    if n == 0 {
+        None
+    } else {
+        Some(n)
+    }
+}
+
+fn main(_args: user::Args) -> i32 {
+    // ensure that three file descriptors are open
+    loop {
+        let fd = open("console", O_RDWR);
+        if fd < 0 {
+            break;
+        }
+        if fd >= 3 {
+            close(fd as i32);
+            break;
+        }
+    }
+
+    let mut buf: [u8; LINE] = [0; LINE];
+    while let Some(n) = get_cmd(&mut buf) {
+        let line = match str::from_utf8(&buf[..n]) {
+            Ok(line) => line.trim(),
+            Err(_) => {
+                eprintln!("sh: invalid input");
+                continue;
+            }
+        };
+
+        // chdir must be called by the parent, not the child
+        if line == "cd" || line.starts_with("cd ") {
+            let dir = line[2..].trim();
+            if chdir(dir) < 0 {
+                eprintln!("cannot cd {}", dir);
+            }
+            continue;
+        }
+
+        let (parser, cmd) = match Parser::parse(line) {
+            Ok(parsed) => parsed,
+            Err(msg) => {
+                eprintln!("sh: {}", msg);
+                continue;
+            }
+        };
+        if fork1() == 0 {
+            parser.run(cmd);
+        }
+        wait();
+    }
+    0
+}
@@ -0,0 +1,53 @@
+#![no_std]
+#![no_main]
+
+use user::{eprintln, println, ptrace_attach, ptrace_detach, ptrace_getregs, ptrace_resume,
+    ptrace_wait, Args, Regs, PTRACE_SYSCALL, STOP_SYSCALL_ENTER, STOP_SYSCALL_EXIT};
+
+user::entry!(main);
+
+const REG_A0: usize = 10;
+const REG_A7: usize = 17;
+
+/// Print the syscalls of process pid, with their first arguments and results,
+/// until it dies or count of them were seen
+fn main(args: Args) -> i32 {
+    let pid = match args.get(1).and_then(|arg| arg.parse::<i32>().ok()) {
+        Some(pid) if args.len() <= 3 => pid,
+        _ => {
+            eprintln!("Usage: strace pid [count]");
+            return 1;
+        }
+    };
+    let count = args.get(2).and_then(|arg| arg.parse::<usize>().ok()).unwrap_or(usize::MAX);
+
+    if ptrace_attach(pid) < 0 || ptrace_wait(pid) < 0 {
+        eprintln!("strace: cannot attach to {}", pid);
+        return 1;
+    }
+    let mut regs: Regs = [0; 32];
+    let mut seen = 0;
+    while seen < count {
+        if ptrace_resume(pid, PTRACE_SYSCALL) < 0 {
+            break;
+        }
+        let stop = ptrace_wait(pid);
+        if stop < 0 || ptrace_getregs(pid, &mut regs) < 0 {
+            println!("strace: {} is gone", pid);
+            return 0;
+        }
+        let a = &regs[REG_A0..];
+        match stop as usize {
+            STOP_SYSCALL_ENTER => {
+                println!("{:#x}: syscall {}({:#x}, {:#x}, {:#x})", regs[0], regs[REG_A7], a[0], a[1], a[2])
+            }
+            STOP_SYSCALL_EXIT => {
+                println!("  = {}", a[0] as isize);
+                seen += 1;
+            }
+            _ => println!("{:#x}: stopped, {}", regs[0], stop),
+        }
+    }
+    ptrace_detach(pid);
+    0
+}
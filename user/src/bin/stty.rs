@@ -0,0 +1,80 @@
+#![no_std]
+#![no_main]
+
+use user::termios::{CRTSCTS, CS5, CS6, CS7, CS8, CSIZE, CSTOPB, ECHO, ICANON};
+use user::{eprintln, get_winsize, println, tcgetattr, tcsetattr, Args, STDIN};
+
+user::entry!(main);
+
+fn usage() -> i32 {
+    eprintln!("Usage: stty");
+    eprintln!("       stty [baud] [cs5|cs6|cs7|cs8] [[-]cstopb] [[-]crtscts] [[-]echo] [[-]icanon]");
+    1
+}
+
+/// The setting word names: whether it is of lflag rather than cflag,
+/// the mask of its bits, and what they become
+fn setting(word: &str) -> Option<(bool, u32, u32)> {
+    let (lflag, mask, bits) = match word.trim_start_matches('-') {
+        "cs5" => (false, CSIZE, CS5),
+        "cs6" => (false, CSIZE, CS6),
+        "cs7" => (false, CSIZE, CS7),
+        "cs8" => (false, CSIZE, CS8),
+        "cstopb" => (false, CSTOPB, CSTOPB),
+        "crtscts" => (false, CRTSCTS, CRTSCTS),
+        "echo" => (true, ECHO, ECHO),
+        "icanon" => (true, ICANON, ICANON),
+        _ => return None,
+    };
+    // a leading - turns a flag off, word sizes have none
+    match (word.starts_with('-'), mask == CSIZE) {
+        (false, _) => Some((lflag, mask, bits)),
+        (true, false) => Some((lflag, mask, 0)),
+        (true, true) => None,
+    }
+}
+
+fn sign(on: bool) -> &'static str {
+    if on { "" } else { "-" }
+}
+
+/// Print the console's settings, or change them, the uart's through cflag
+fn main(args: Args) -> i32 {
+    let mut t = match tcgetattr(STDIN) {
+        Some(t) => t,
+        None => {
+            eprintln!("stty: stdin is not a terminal");
+            return 1;
+        }
+    };
+    if args.len() == 1 {
+        let bits = 5 + (t.cflag & CSIZE) / CS6;
+        println!("speed {} cs{} {}cstopb {}crtscts {}echo {}icanon",
+            t.speed().unwrap_or(0), bits, sign(t.cflag & CSTOPB != 0), sign(t.cflag & CRTSCTS != 0),
+            sign(t.lflag & ECHO != 0), sign(t.lflag & ICANON != 0));
+        if let Some(w) = get_winsize(STDIN) {
+            println!("rows {} columns {}", w.row, w.col);
+        }
+        return 0;
+    }
+
+    for word in args.iter().skip(1) {
+        if let Ok(baud) = word.parse::<u32>() {
+            if !t.set_speed(baud) {
+                eprintln!("stty: unsupported speed {}", baud);
+                return 1;
+            }
+            continue;
+        }
+        match setting(word) {
+            Some((true, mask, bits)) => t.lflag = (t.lflag & !mask) | bits,
+            Some((false, mask, bits)) => t.cflag = (t.cflag & !mask) | bits,
+            None => return usage(),
+        }
+    }
+    if tcsetattr(STDIN, &t) < 0 {
+        eprintln!("stty: setting the terminal failed");
+        return 1;
+    }
+    0
+}
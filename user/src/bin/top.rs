@@ -0,0 +1,57 @@
+#![no_std]
+#![no_main]
+
+use user::{cpustat, eprintln, println, sleep, Args, CpuStat, CPU_IDLE, CPU_IRQ, CPU_KERNEL, CPU_USER};
+
+user::entry!(main);
+
+const NHART: usize = 8;
+/// ticks between readings
+const DELAY: i32 = 10;
+
+fn usage() -> i32 {
+    eprintln!("Usage: top [rounds]");
+    1
+}
+
+fn read(stats: &mut [CpuStat; NHART]) -> usize {
+    let n = cpustat(stats);
+    if n < 0 {
+        eprintln!("top: cpustat failed");
+        return 0;
+    }
+    n as usize
+}
+
+/// Print how each hart spent the time between two readings, rounds times
+fn main(args: Args) -> i32 {
+    let rounds = match args.get(1).map(|arg| arg.parse::<usize>()) {
+        None => 5,
+        Some(Ok(n)) => n,
+        Some(Err(_)) => return usage(),
+    };
+
+    let mut before = [CpuStat::default(); NHART];
+    let mut after = [CpuStat::default(); NHART];
+    let mut n = read(&mut before);
+    if n == 0 {
+        return 1;
+    }
+    for _ in 0..rounds {
+        sleep(DELAY);
+        n = n.min(read(&mut after));
+        println!("hart  user%  kernel%  idle%  irq%");
+        for (b, a) in before[..n].iter().zip(after[..n].iter()) {
+            let mut delta = [0u64; 4];
+            for (d, (x, y)) in delta.iter_mut().zip(b.times.iter().zip(a.times.iter())) {
+                *d = y.saturating_sub(*x);
+            }
+            let total = delta.iter().sum::<u64>().max(1);
+            let pct = |state: usize| delta[state] * 100 / total;
+            println!("{:<5} {:<6} {:<8} {:<6} {}",
+                a.hart, pct(CPU_USER), pct(CPU_KERNEL), pct(CPU_IDLE), pct(CPU_IRQ));
+        }
+        before = after;
+    }
+    0
+}
@@ -0,0 +1,65 @@
+#![no_std]
+#![no_main]
+
+use user::fcntl::O_RDONLY;
+use user::{close, eprintln, open, println, read, Args};
+
+user::entry!(main);
+
+fn wc(fd: i32, name: &str) -> Result<(), &'static str> {
+    let mut buf: [u8; 512] = [0; 512];
+    let (mut l, mut w, mut c) = (0, 0, 0);
+    let mut inword = false;
+    loop {
+        let n = read(fd, &mut buf);
+        if n < 0 {
+            return Err("read error");
+        }
+        if n == 0 {
+            break;
+        }
+        for byte in buf[..n as usize].iter() {
+            c += 1;
+            if *byte == b'\n' {
+                l += 1;
+            }
+            if b" \r\t\n\x0b".contains(byte) {
+                inword = false;
+            } else if !inword {
+                w += 1;
+                inword = true;
+            }
+        }
+    }
+    println!("{} {} {} {}", l, w, c, name);
+    Ok(())
+}
+
+fn main(args: Args) -> i32 {
+    let ret = if args.len() <= 1 {
+        wc(0, "")
+    } else {
+        let mut ret = Ok(());
+        for path in args.iter().skip(1) {
+            let fd = open(path, O_RDONLY);
+            if fd < 0 {
+                eprintln!("wc: cannot open {}", path);
+                return 1;
+            }
+            ret = wc(fd as i32, path);
+            close(fd as i32);
+            if ret.is_err() {
+                break;
+            }
+        }
+        ret
+    };
+
+    match ret {
+        Ok(()) => 0,
+        Err(err) => {
+            eprintln!("wc: {}", err);
+            1
+        }
+    }
+}
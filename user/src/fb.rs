@@ -0,0 +1,32 @@
+//! The framebuffer device, same as the kernel's driver/fb.rs
+//!
+//! Open /fb read and write, mmap it MAP_SHARED, PROT_READ | PROT_WRITE, from 0,
+//! and draw pixels 0x00RRGGBB, a row after the other, stride bytes apart,
+//! then FBIO_FLUSH a rectangle of it to the screen.
+
+/// major device number of the framebuffer
+pub const FB: i16 = 2;
+
+/// ioctl requests
+pub const FBIOGET_INFO: usize = 0x4600;
+pub const FBIO_FLUSH: usize = 0x4680;
+
+#[repr(C)]
+#[derive(Copy, Clone, Default)]
+pub struct FbInfo {
+    pub width: u32,
+    pub height: u32,
+    /// bytes from a row to the next
+    pub stride: u32,
+    pub bits_per_pixel: u32,
+}
+
+/// A rectangle to present, in pixels
+#[repr(C)]
+#[derive(Copy, Clone, Default)]
+pub struct FbRect {
+    pub x: u32,
+    pub y: u32,
+    pub width: u32,
+    pub height: u32,
+}
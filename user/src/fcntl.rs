@@ -0,0 +1,8 @@
+//! Flags for open, same as the kernel's
+
+pub const O_RDONLY: i32 = 0x000;
+pub const O_WRONLY: i32 = 0x001;
+pub const O_RDWR: i32 = 0x002;
+pub const O_CREATE: i32 = 0x200;
+pub const O_TRUNC: i32 = 0x400;
+pub const O_NONBLOCK: i32 = 0x800;
@@ -0,0 +1,20 @@
+//! On-disk directory entries, as read from a directory
+
+pub const DIRSIZ: usize = 14;
+
+#[repr(C)]
+#[derive(Copy, Clone, Default)]
+pub struct Dirent {
+    pub inum: u16,
+    pub name: [u8; DIRSIZ],
+}
+
+impl Dirent {
+    pub const SIZE: usize = core::mem::size_of::<Self>();
+
+    /// Name up to the first nul byte
+    pub fn name(&self) -> &[u8] {
+        let len = self.name.iter().position(|c| *c == 0).unwrap_or(DIRSIZ);
+        &self.name[..len]
+    }
+}
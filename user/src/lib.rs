@@ -0,0 +1,854 @@
+//! User-space support library
+//!
+//! Syscall wrappers and the program entry for user programs written in Rust.
+//! A program is a file in src/bin, which looks like
+//!
+//! ```ignore
+//! #![no_std]
+//! #![no_main]
+//!
+//! user::entry!(main);
+//!
+//! use user::println;
+//!
+//! fn main(args: user::Args) -> i32 {
+//!     for arg in args.iter() {
+//!         println!("{}", arg);
+//!     }
+//!     0
+//! }
+//! ```
+//!
+//! Programs can also use the heap, with `extern crate alloc;`,
+//! e.g., `alloc::vec::Vec` and `alloc::string::String`.
+
+#![no_std]
+
+extern crate alloc;
+
+use core::panic::PanicInfo;
+
+#[macro_use]
+pub mod printf;
+
+pub mod fb;
+pub mod fcntl;
+pub mod fs;
+pub mod stat;
+pub mod string;
+pub mod sys;
+pub mod termios;
+pub mod umalloc;
+
+use fb::{FbInfo, FbRect};
+use stat::Stat;
+use string::{from_cstr, with_cstr};
+use termios::{Termios, Winsize};
+
+pub const STDIN: i32 = 0;
+pub const STDOUT: i32 = 1;
+pub const STDERR: i32 = 2;
+
+/// same as the kernel's
+pub const MAXPATH: usize = 128;
+pub const MAXARG: usize = 32;
+
+core::arch::global_asm!(include_str!("crt0.S"));
+
+/// Exit status of a program that panics
+pub const EXIT_PANIC: i32 = 101;
+
+/// Declare the main function of a program.
+///
+/// The startup contract:
+/// exec starts the program at _start in crt0.S, with argc in a0 and argv in a1,
+/// the strings and the null-terminated argv array being on the user stack,
+/// at most MAXARG of them.
+/// _start calls __start_rust, which calls main with the arguments,
+/// and main's return value is the exit status.
+/// A panic prints its message to stderr and exits with EXIT_PANIC.
+#[macro_export]
+macro_rules! entry {
+    ($main:path) => {
+        #[no_mangle]
+        pub fn __user_main(args: $crate::Args) -> i32 {
+            let f: fn($crate::Args) -> i32 = $main;
+            f(args)
+        }
+    };
+}
+
+#[no_mangle]
+extern "C" fn __start_rust(argc: usize, argv: *const *const u8) -> ! {
+    extern "Rust" {
+        fn __user_main(args: Args) -> i32;
+    }
+    exit(unsafe { __user_main(Args::new(argc, argv)) })
+}
+
+/// Command line arguments, argv[0] is the program name
+#[derive(Copy, Clone)]
+pub struct Args {
+    argc: usize,
+    argv: *const *const u8,
+}
+
+impl Args {
+    /// # Safety
+    ///
+    /// argv must point to argc nul-terminated strings, which stay as they are.
+    pub unsafe fn new(argc: usize, argv: *const *const u8) -> Self {
+        Self { argc, argv }
+    }
+
+    pub fn len(&self) -> usize {
+        self.argc
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.argc == 0
+    }
+
+    /// The i-th argument, an argument that is not utf-8 is empty
+    pub fn get(&self, i: usize) -> Option<&'static str> {
+        if i >= self.argc {
+            return None;
+        }
+        let arg = unsafe { from_cstr(*self.argv.add(i)) };
+        Some(core::str::from_utf8(arg).unwrap_or(""))
+    }
+
+    pub fn iter(&self) -> ArgsIter {
+        ArgsIter { args: *self, i: 0 }
+    }
+}
+
+pub struct ArgsIter {
+    args: Args,
+    i: usize,
+}
+
+impl Iterator for ArgsIter {
+    type Item = &'static str;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        let arg = self.args.get(self.i)?;
+        self.i += 1;
+        Some(arg)
+    }
+}
+
+pub fn fork() -> isize {
+    unsafe { sys::fork() }
+}
+
+pub fn exit(status: i32) -> ! {
+    unsafe {
+        sys::exit(status);
+    }
+    // exit never returns
+    loop {
+        core::hint::spin_loop();
+    }
+}
+
+/// Wait for a child to exit, return its pid and exit status
+pub fn wait() -> Option<(isize, i32)> {
+    let mut status: i32 = 0;
+    match unsafe { sys::wait(&mut status) } {
+        pid if pid < 0 => None,
+        pid => Some((pid, status)),
+    }
+}
+
+/// Create a pipe, return (read end, write end)
+pub fn pipe() -> Option<(i32, i32)> {
+    let mut fds: [i32; 2] = [0; 2];
+    match unsafe { sys::pipe(fds.as_mut_ptr()) } {
+        0 => Some((fds[0], fds[1])),
+        _ => None,
+    }
+}
+
+pub fn read(fd: i32, buf: &mut [u8]) -> isize {
+    unsafe { sys::read(fd, buf.as_mut_ptr(), buf.len()) }
+}
+
+pub fn write(fd: i32, buf: &[u8]) -> isize {
+    unsafe { sys::write(fd, buf.as_ptr(), buf.len()) }
+}
+
+pub fn kill(pid: i32) -> isize {
+    unsafe { sys::kill(pid) }
+}
+
+/// Replace the current program with path.
+/// Returns only if it fails.
+pub fn exec(path: &str, argv: &[&str]) -> isize {
+    // the strings are copied into one buffer, each followed by a nul
+    const ARGBUF: usize = 1024;
+    let mut buf: [u8; ARGBUF] = [0; ARGBUF];
+    let mut ptrs: [*const u8; MAXARG + 1] = [core::ptr::null(); MAXARG + 1];
+    if argv.len() > MAXARG {
+        return -1;
+    }
+
+    let mut used = 0;
+    for (i, arg) in argv.iter().enumerate() {
+        match string::to_cstr(arg, &mut buf[used..]) {
+            Some(s) => {
+                ptrs[i] = s.as_ptr();
+                used += s.len();
+            }
+            None => return -1,
+        }
+    }
+    with_cstr(path, |path| unsafe { sys::exec(path, ptrs.as_ptr()) })
+}
+
+pub fn fstat(fd: i32) -> Option<Stat> {
+    let mut st = Stat::default();
+    match unsafe { sys::fstat(fd, &mut st as *mut Stat as *mut u8) } {
+        0 => Some(st),
+        _ => None,
+    }
+}
+
+/// fstat by path
+pub fn stat(path: &str) -> Option<Stat> {
+    let fd = open(path, fcntl::O_RDONLY);
+    if fd < 0 {
+        return None;
+    }
+    let st = fstat(fd as i32);
+    close(fd as i32);
+    st
+}
+
+pub fn chdir(path: &str) -> isize {
+    with_cstr(path, |path| unsafe { sys::chdir(path) })
+}
+
+pub fn dup(fd: i32) -> isize {
+    unsafe { sys::dup(fd) }
+}
+
+pub fn getpid() -> isize {
+    unsafe { sys::getpid() }
+}
+
+/// Grow the memory by n bytes, return the start of the new memory
+pub fn sbrk(n: isize) -> isize {
+    unsafe { sys::sbrk(n) }
+}
+
+pub fn sleep(ticks: i32) -> isize {
+    unsafe { sys::sleep(ticks) }
+}
+
+pub fn uptime() -> isize {
+    unsafe { sys::uptime() }
+}
+
+pub fn open(path: &str, flags: i32) -> isize {
+    with_cstr(path, |path| unsafe { sys::open(path, flags) })
+}
+
+pub fn mknod(path: &str, major: i16, minor: i16) -> isize {
+    with_cstr(path, |path| unsafe { sys::mknod(path, major, minor, stat::T_DEVICE) })
+}
+
+/// Make a named pipe at path, opening one end waits for the other to be opened
+pub fn mkfifo(path: &str) -> isize {
+    with_cstr(path, |path| unsafe { sys::mknod(path, 0, 0, stat::T_FIFO) })
+}
+
+pub fn unlink(path: &str) -> isize {
+    with_cstr(path, |path| unsafe { sys::unlink(path) })
+}
+
+pub fn link(old: &str, new: &str) -> isize {
+    with_cstr(old, |old| with_cstr(new, |new| unsafe { sys::link(old, new) }))
+}
+
+pub fn mkdir(path: &str) -> isize {
+    with_cstr(path, |path| unsafe { sys::mkdir(path) })
+}
+
+pub fn close(fd: i32) -> isize {
+    unsafe { sys::close(fd) }
+}
+
+pub fn dmesg(buf: &mut [u8]) -> isize {
+    unsafe { sys::dmesg(buf.as_mut_ptr(), buf.len()) }
+}
+
+/// Modes of kfault, mirroring the kernel's mm/fault.rs
+pub const FAULT_OFF: usize = 0;
+pub const FAULT_NTH: usize = 1;
+pub const FAULT_RANDOM: usize = 2;
+
+/// Make the kernel's page allocator fail every arg-th allocation,
+/// or with a chance of arg percent, a zero arg turns it off.
+/// Return the faults injected under the previous mode,
+/// -1 if the kernel was built without fault_inject.
+pub fn kfault(mode: usize, arg: usize) -> isize {
+    unsafe { sys::kfault(mode, arg) }
+}
+
+/// One line of the kernel's aggregated profile, same as its profile::ProfEntry
+#[repr(C)]
+#[derive(Clone, Copy, Default)]
+pub struct ProfEntry {
+    pub pc: usize,
+    pub pid: u32,
+    pub user: u32,
+    pub count: usize,
+}
+
+/// Clear the samples and start the kernel's sampling profiler
+pub fn prof_start() -> isize {
+    unsafe { sys::prof(0, core::ptr::null_mut(), 0) }
+}
+
+pub fn prof_stop() -> isize {
+    unsafe { sys::prof(1, core::ptr::null_mut(), 0) }
+}
+
+/// Fill entries with the samples aggregated by pc and pid,
+/// the most frequent first, return the number filled.
+/// A last kernel entry with pc 0 counts the samples that did not fit.
+pub fn prof_dump(entries: &mut [ProfEntry]) -> isize {
+    unsafe { sys::prof(2, entries.as_mut_ptr() as *mut u8, entries.len()) }
+}
+
+/// Commands of ftrace, mirroring the kernel's syscall.rs
+pub const FTRACE_OFF: usize = 0;
+pub const FTRACE_ON: usize = 1;
+pub const FTRACE_DUMP: usize = 2;
+
+/// Switch the kernel's function-entry tracing, or print it on the console.
+/// Return -1 if the kernel was built without ftrace.
+pub fn ftrace(cmd: usize) -> isize {
+    unsafe { sys::ftrace(cmd) }
+}
+
+/// Reasons of a SchedEvent, mirroring the kernel's schedtrace.rs
+pub const SCHED_RUN: u32 = 0;
+pub const SCHED_YIELD: u32 = 1;
+pub const SCHED_BLOCK: u32 = 2;
+pub const SCHED_EXIT: u32 = 3;
+pub const SCHED_WAKEUP: u32 = 4;
+
+/// One scheduler event, same as the kernel's schedtrace::SchedEvent.
+/// time is the CLINT mtime.
+#[repr(C)]
+#[derive(Clone, Copy, Default)]
+pub struct SchedEvent {
+    pub time: u64,
+    pub hart: u32,
+    pub reason: u32,
+    pub from: u32,
+    pub to: u32,
+}
+
+/// Clear the events and start the kernel's scheduler tracing
+pub fn schedtrace_start() -> isize {
+    unsafe { sys::schedtrace(0, core::ptr::null_mut(), 0) }
+}
+
+pub fn schedtrace_stop() -> isize {
+    unsafe { sys::schedtrace(1, core::ptr::null_mut(), 0) }
+}
+
+/// Fill events with the recorded events of all harts, oldest first,
+/// return the number filled.
+pub fn schedtrace_dump(events: &mut [SchedEvent]) -> isize {
+    unsafe { sys::schedtrace(2, events.as_mut_ptr() as *mut u8, events.len()) }
+}
+
+/// Run the kernel's built-in guest, see its hyp.rs, return its exit code
+pub fn hvguest() -> isize {
+    unsafe { sys::hvguest() }
+}
+
+/// Interval timers, mirroring the kernel's process/itimer.rs
+pub const ITIMER_REAL: usize = 0;
+pub const ITIMER_VIRTUAL: usize = 1;
+
+static mut ITIMER_HANDLERS: [Option<fn(usize)>; 2] = [None; 2];
+
+/// The kernel enters here when a timer fires, with it in a0
+extern "C" fn itimer_entry(which: usize) -> ! {
+    if let Some(handler) = unsafe { ITIMER_HANDLERS[which] } {
+        handler(which);
+    }
+    unsafe {
+        sys::sigreturn();
+    }
+    // sigreturn never returns here
+    loop {
+        core::hint::spin_loop();
+    }
+}
+
+/// Call handler in value ticks, and every interval ticks after that,
+/// or never again if value is 0.
+/// ITIMER_REAL counts clock ticks, ITIMER_VIRTUAL the ticks this process ran.
+/// handler interrupts the program wherever it is.
+pub fn setitimer(which: usize, value: usize, interval: usize, handler: fn(usize)) -> isize {
+    if which >= 2 {
+        return -1;
+    }
+    unsafe {
+        ITIMER_HANDLERS[which] = Some(handler);
+        sys::setitimer(which, value, interval, itimer_entry as *const () as usize)
+    }
+}
+
+/// The real user id, 0 is root
+pub fn getuid() -> u32 {
+    unsafe { sys::getuid() as u32 }
+}
+
+/// The user id permissions are checked against
+pub fn geteuid() -> u32 {
+    unsafe { sys::geteuid() as u32 }
+}
+
+/// Set all user ids as root, otherwise only the effective one,
+/// to the real or the saved one
+pub fn setuid(uid: u32) -> isize {
+    unsafe { sys::setuid(uid) }
+}
+
+pub fn getgid() -> u32 {
+    unsafe { sys::getgid() as u32 }
+}
+
+pub fn getegid() -> u32 {
+    unsafe { sys::getegid() as u32 }
+}
+
+pub fn setgid(gid: u32) -> isize {
+    unsafe { sys::setgid(gid) }
+}
+
+/// Make the directory at path the root of absolute paths, only for root
+pub fn chroot(path: &str) -> isize {
+    with_cstr(path, |path| unsafe { sys::chroot(path) })
+}
+
+/// ptrace requests and stop reasons, mirroring the kernel's process/ptrace.rs
+pub const PTRACE_ATTACH: usize = 0;
+pub const PTRACE_DETACH: usize = 1;
+pub const PTRACE_PEEK: usize = 2;
+pub const PTRACE_POKE: usize = 3;
+pub const PTRACE_GETREGS: usize = 4;
+pub const PTRACE_SETREGS: usize = 5;
+pub const PTRACE_CONT: usize = 6;
+pub const PTRACE_SYSCALL: usize = 7;
+pub const PTRACE_SINGLESTEP: usize = 8;
+pub const PTRACE_WAIT: usize = 9;
+pub const PTRACE_WATCH: usize = 10;
+
+pub const STOP_ATTACH: usize = 1;
+pub const STOP_BREAKPOINT: usize = 2;
+pub const STOP_STEP: usize = 3;
+pub const STOP_SYSCALL_ENTER: usize = 4;
+pub const STOP_SYSCALL_EXIT: usize = 5;
+pub const STOP_WATCH: usize = 6;
+
+/// What a watchpoint fires on, bits, mirroring the kernel's watch.rs
+pub const WATCH_STORE: usize = 1;
+pub const WATCH_LOAD: usize = 2;
+
+/// Registers of a tracee, pc then x1-x31
+pub type Regs = [usize; 32];
+
+/// Trace process pid, it stops at its next trap into the kernel
+pub fn ptrace_attach(pid: i32) -> isize {
+    unsafe { sys::ptrace(PTRACE_ATTACH, pid as usize, 0, 0) }
+}
+
+/// Let the tracee go on untraced
+pub fn ptrace_detach(pid: i32) -> isize {
+    unsafe { sys::ptrace(PTRACE_DETACH, pid as usize, 0, 0) }
+}
+
+/// Read the word at addr in the stopped tracee
+pub fn ptrace_peek(pid: i32, addr: usize) -> Option<usize> {
+    let mut word: usize = 0;
+    let ret = unsafe { sys::ptrace(PTRACE_PEEK, pid as usize, addr, &mut word as *mut usize as usize) };
+    if ret < 0 { None } else { Some(word) }
+}
+
+/// Write the word at addr in the stopped tracee, also into its text
+pub fn ptrace_poke(pid: i32, addr: usize, word: usize) -> isize {
+    unsafe { sys::ptrace(PTRACE_POKE, pid as usize, addr, word) }
+}
+
+pub fn ptrace_getregs(pid: i32, regs: &mut Regs) -> isize {
+    unsafe { sys::ptrace(PTRACE_GETREGS, pid as usize, 0, regs.as_mut_ptr() as usize) }
+}
+
+pub fn ptrace_setregs(pid: i32, regs: &Regs) -> isize {
+    unsafe { sys::ptrace(PTRACE_SETREGS, pid as usize, 0, regs.as_ptr() as usize) }
+}
+
+/// Resume the stopped tracee with PTRACE_CONT, PTRACE_SYSCALL or PTRACE_SINGLESTEP
+pub fn ptrace_resume(pid: i32, req: usize) -> isize {
+    unsafe { sys::ptrace(req, pid as usize, 0, 0) }
+}
+
+/// Stop the tracee with STOP_WATCH before it accesses addr as kind says,
+/// once, addr 0 for no watchpoint. It needs hardware triggers.
+pub fn ptrace_watch(pid: i32, addr: usize, kind: usize) -> isize {
+    unsafe { sys::ptrace(PTRACE_WATCH, pid as usize, addr, kind) }
+}
+
+/// Wait until the tracee stops, return one of the STOP_ reasons
+pub fn ptrace_wait(pid: i32) -> isize {
+    unsafe { sys::ptrace(PTRACE_WAIT, pid as usize, 0, 0) }
+}
+
+/// Turn logging fork, exec, exit and kill into the kernel message ring on or off,
+/// only for root, read the events with dmesg
+pub fn proclog(on: bool) -> isize {
+    unsafe { sys::proclog(on as usize) }
+}
+
+/// madvise advice, mirroring the kernel's process/syscall.rs
+pub const MADV_NORMAL: usize = 0;
+pub const MADV_DONTNEED: usize = 4;
+
+/// Advise the kernel on the use of len bytes at page-aligned addr,
+/// MADV_DONTNEED gives their memory back, they read as zeros afterwards.
+/// Return how many pages were given back.
+pub fn madvise(addr: usize, len: usize, advice: usize) -> isize {
+    unsafe { sys::madvise(addr, len, advice) }
+}
+
+/// getrandom flags, mirroring the kernel's random.rs
+pub const GRND_NONBLOCK: usize = 1;
+pub const GRND_RANDOM: usize = 2;
+pub const GRND_INSECURE: usize = 4;
+
+/// The random device's major number, mirroring the kernel's fs/file.rs,
+/// minor 0 is /random, waiting like getrandom, minor 1 /urandom, as with GRND_INSECURE
+pub const RANDOM: i16 = 3;
+
+/// Fill buf with random bytes, waiting until the kernel's generator is seeded,
+/// unless flags has GRND_NONBLOCK or GRND_INSECURE.
+/// Return how many bytes were filled.
+pub fn getrandom(buf: &mut [u8], flags: usize) -> isize {
+    unsafe { sys::getrandom(buf.as_mut_ptr(), buf.len(), flags) }
+}
+
+/// Take a hart off scheduling, or bring it back, only for root,
+/// the boot hart stays online
+pub fn hartctl(hart: usize, online: bool) -> isize {
+    unsafe { sys::hartctl(hart, online as usize) }
+}
+
+/// What ended a suspend, mirroring the kernel's suspend.rs
+pub const WAKE_CONSOLE: isize = 0;
+pub const WAKE_ALARM: isize = 1;
+
+/// Suspend the system until console input, or until alarm ticks from now if it is not 0,
+/// only for root, return WAKE_CONSOLE or WAKE_ALARM
+pub fn suspend(alarm: usize) -> isize {
+    unsafe { sys::suspend(alarm) }
+}
+
+/// Scheduling policies, mirroring the kernel's process/rt.rs
+pub const SCHED_OTHER: usize = 0;
+pub const SCHED_FIFO: usize = 1;
+
+/// Set the policy of process pid, 0 for the caller, with priority 1 to 99 for SCHED_FIFO,
+/// 0 for SCHED_OTHER. Only root makes a process real-time, or changes another one.
+pub fn sched_setscheduler(pid: usize, policy: usize, priority: usize) -> isize {
+    unsafe { sys::sched_setscheduler(pid, policy, priority) }
+}
+
+/// Give up the cpu, to the real-time processes of the same priority too
+pub fn sched_yield() -> isize {
+    unsafe { sys::sched_yield() }
+}
+
+/// Set the ticks process pid, 0 for the caller, runs before a tick takes its cpu,
+/// up to 100, 0 for the default of its policy, 1 for SCHED_OTHER and none for SCHED_FIFO.
+/// Only root lengthens it past 1, or changes another process's.
+pub fn sched_setquantum(pid: usize, ticks: usize) -> isize {
+    unsafe { sys::sched_setquantum(pid, ticks) }
+}
+
+/// mmap protections and flags, mirroring the kernel's process/vma.rs
+pub const PROT_READ: usize = 0x1;
+pub const PROT_WRITE: usize = 0x2;
+pub const PROT_EXEC: usize = 0x4;
+pub const MAP_SHARED: usize = 0x01;
+pub const MAP_PRIVATE: usize = 0x02;
+pub const MS_ASYNC: usize = 0x1;
+pub const MS_SYNC: usize = 0x4;
+
+/// Map len bytes of the file open at fd, from page-aligned off, readable, with prot,
+/// MAP_SHARED writes go back into the file, up to its end, MAP_PRIVATE ones stay in memory.
+/// Return where it is mapped, -1 on failure.
+pub fn mmap(len: usize, prot: usize, flags: usize, fd: i32, off: usize) -> isize {
+    unsafe { sys::mmap(0, len, prot, flags, fd, off) }
+}
+
+/// Unmap len bytes at page-aligned addr, off either end of a mapping or all of it
+pub fn munmap(addr: usize, len: usize) -> isize {
+    unsafe { sys::munmap(addr, len) }
+}
+
+/// Write the pages of MAP_SHARED mappings written to in len bytes at page-aligned addr
+/// back into their files, flags is one of MS_SYNC and MS_ASYNC, they stay mapped
+pub fn msync(addr: usize, len: usize, flags: usize) -> isize {
+    unsafe { sys::msync(addr, len, flags) }
+}
+
+/// socket flags, mirroring the kernel's socket.rs
+pub const SOCK_NONBLOCK: usize = 1 << 0;
+
+/// A new local stream socket, read and written like a pipe once connected,
+/// with SOCK_NONBLOCK what would wait fails instead. Return its descriptor.
+pub fn socket(flags: usize) -> isize {
+    unsafe { sys::socket(flags) }
+}
+
+/// Name the socket fd by path, no other socket's
+pub fn bind(fd: i32, path: &str) -> isize {
+    with_cstr(path, |path| unsafe { sys::bind(fd, path) })
+}
+
+/// Take connections to the path the socket fd is bound to
+pub fn listen(fd: i32) -> isize {
+    unsafe { sys::listen(fd) }
+}
+
+/// Connect the socket fd to the one listening on path
+pub fn connect(fd: i32, path: &str) -> isize {
+    with_cstr(path, |path| unsafe { sys::connect(fd, path) })
+}
+
+/// Wait for a connection to the listening socket fd, return the descriptor of its server end
+pub fn accept(fd: i32) -> isize {
+    unsafe { sys::accept(fd) }
+}
+
+/// mq_open flags, mirroring the kernel's mqueue.rs
+pub const MQ_CREATE: usize = 1 << 0;
+pub const MQ_NONBLOCK: usize = 1 << 1;
+/// The longest message, a buffer to receive into holds at least that
+pub const MQ_MSGSIZE: usize = 64;
+
+/// Open the message queue called name, creating it with MQ_CREATE,
+/// with MQ_NONBLOCK sending to a full one and receiving from an empty one fail instead of waiting.
+/// Return its descriptor, which close() closes.
+pub fn mq_open(name: &str, flags: usize) -> isize {
+    with_cstr(name, |name| unsafe { sys::mq_open(name, flags) })
+}
+
+/// Send msg to the queue fd with priority prio, the highest ones are received first
+pub fn mq_send(fd: i32, msg: &[u8], prio: usize) -> isize {
+    unsafe { sys::mq_send(fd, msg.as_ptr(), msg.len(), prio) }
+}
+
+/// Receive the next message of the queue fd into buf, return its length and priority
+pub fn mq_receive(fd: i32, buf: &mut [u8]) -> Option<(usize, usize)> {
+    let mut prio = 0;
+    match unsafe { sys::mq_receive(fd, buf.as_mut_ptr(), buf.len(), &mut prio) } {
+        n if n < 0 => None,
+        n => Some((n as usize, prio)),
+    }
+}
+
+/// Remove the name of a queue, its openers go on using it
+pub fn mq_unlink(name: &str) -> isize {
+    with_cstr(name, |name| unsafe { sys::mq_unlink(name) })
+}
+
+/// eventfd flags, mirroring the kernel's eventfd.rs
+pub const EFD_NONBLOCK: usize = 1 << 0;
+
+/// A new event counter starting at initial, return its descriptor,
+/// with EFD_NONBLOCK reading it while it is 0 fails instead of waiting
+pub fn eventfd(initial: u64, flags: usize) -> isize {
+    unsafe { sys::eventfd(initial, flags) }
+}
+
+/// Wait until the event counter fd is nonzero, return it and reset it
+pub fn eventfd_read(fd: i32) -> Option<u64> {
+    let mut n = [0u8; 8];
+    match read(fd, &mut n) {
+        8 => Some(u64::from_ne_bytes(n)),
+        _ => None,
+    }
+}
+
+/// Add n to the event counter fd
+pub fn eventfd_write(fd: i32, n: u64) -> isize {
+    match write(fd, &n.to_ne_bytes()) {
+        8 => 0,
+        _ => -1,
+    }
+}
+
+/// timerfd_create flags, mirroring the kernel's timerfd.rs
+pub const TFD_NONBLOCK: usize = EFD_NONBLOCK;
+
+/// A new disarmed timer descriptor, return it,
+/// with TFD_NONBLOCK reading it before an expiry fails instead of waiting
+pub fn timerfd_create(flags: usize) -> isize {
+    unsafe { sys::timerfd_create(flags) }
+}
+
+/// Arm the timer descriptor fd to expire in value ticks, then every interval ticks,
+/// or once if interval is 0, or disarm it if value is 0
+pub fn timerfd_settime(fd: i32, value: usize, interval: usize) -> isize {
+    unsafe { sys::timerfd_settime(fd, value, interval) }
+}
+
+/// Wait for an expiry of the timer descriptor fd, return how many there were since the last read
+pub fn timerfd_read(fd: i32) -> Option<u64> {
+    eventfd_read(fd)
+}
+
+/// fcntl commands and descriptor flags, mirroring the kernel's process/syscall.rs
+pub const F_DUPFD: usize = 0;
+pub const F_GETFD: usize = 1;
+pub const F_SETFD: usize = 2;
+pub const F_GETFL: usize = 3;
+pub const F_SETFL: usize = 4;
+pub const FD_CLOEXEC: usize = 1;
+
+/// Control descriptor fd: F_DUPFD returns a new one for its file, the lowest not below arg,
+/// F_GETFD returns its flags, F_SETFD sets them to arg, with FD_CLOEXEC exec closes it,
+/// F_GETFL returns the open mode and O_NONBLOCK of its file, F_SETFL sets O_NONBLOCK as in arg
+pub fn fcntl(fd: i32, cmd: usize, arg: usize) -> isize {
+    unsafe { sys::fcntl(fd, cmd, arg) }
+}
+
+/// getrlimit and setrlimit resources
+pub const RLIMIT_NOFILE: usize = 7;
+
+/// The soft and hard limits on resource
+pub fn getrlimit(resource: usize) -> Option<(usize, usize)> {
+    let mut limits = [0usize; 2];
+    match unsafe { sys::getrlimit(resource, limits.as_mut_ptr()) } {
+        0 => Some((limits[0], limits[1])),
+        _ => None,
+    }
+}
+
+/// Set the soft limit on resource to cur and the hard one to max, only root raises the hard one
+pub fn setrlimit(resource: usize, cur: usize, max: usize) -> isize {
+    unsafe { sys::setrlimit(resource, cur, max) }
+}
+
+/// Carry out ioctl request req on the device open at fd, on the argument at arg,
+/// which the request reads or fills in
+///
+/// # Safety
+///
+/// arg must point to as many bytes as the argument of req has, e.g., a Termios for TCGETS.
+pub unsafe fn ioctl(fd: i32, req: usize, arg: *mut u8) -> isize {
+    sys::ioctl(fd, req, arg)
+}
+
+/// The attributes of the terminal open at fd
+pub fn tcgetattr(fd: i32) -> Option<Termios> {
+    let mut t = Termios::default();
+    match unsafe { ioctl(fd, termios::TCGETS, &mut t as *mut Termios as *mut u8) } {
+        0 => Some(t),
+        _ => None,
+    }
+}
+
+/// Set the attributes of the terminal open at fd, e.g., ICANON and ECHO off for raw input
+pub fn tcsetattr(fd: i32, t: &Termios) -> isize {
+    let mut t = *t;
+    unsafe { ioctl(fd, termios::TCSETS, &mut t as *mut Termios as *mut u8) }
+}
+
+/// The window size of the terminal open at fd
+pub fn get_winsize(fd: i32) -> Option<Winsize> {
+    let mut w = Winsize::default();
+    match unsafe { ioctl(fd, termios::TIOCGWINSZ, &mut w as *mut Winsize as *mut u8) } {
+        0 => Some(w),
+        _ => None,
+    }
+}
+
+pub fn set_winsize(fd: i32, w: &Winsize) -> isize {
+    let mut w = *w;
+    unsafe { ioctl(fd, termios::TIOCSWINSZ, &mut w as *mut Winsize as *mut u8) }
+}
+
+/// The size and layout of the framebuffer open at fd
+pub fn fb_info(fd: i32) -> Option<FbInfo> {
+    let mut info = FbInfo::default();
+    match unsafe { ioctl(fd, fb::FBIOGET_INFO, &mut info as *mut FbInfo as *mut u8) } {
+        0 => Some(info),
+        _ => None,
+    }
+}
+
+/// Present rectangle r of the framebuffer open at fd, once drawn into its mapping
+pub fn fb_flush(fd: i32, r: &FbRect) -> isize {
+    let mut r = *r;
+    unsafe { ioctl(fd, fb::FBIO_FLUSH, &mut r as *mut FbRect as *mut u8) }
+}
+
+/// Copy up to len bytes from fd_in to fd_out in the kernel, at the given offsets, moved along,
+/// or at the descriptors' own, return the bytes copied, fewer at the end of fd_in
+pub fn copy_file_range(
+    fd_in: i32,
+    off_in: Option<&mut usize>,
+    fd_out: i32,
+    off_out: Option<&mut usize>,
+    len: usize,
+) -> isize {
+    let off_in = off_in.map_or(core::ptr::null_mut(), |off| off as *mut usize);
+    let off_out = off_out.map_or(core::ptr::null_mut(), |off| off as *mut usize);
+    unsafe { sys::copy_file_range(fd_in, off_in, fd_out, off_out, len, 0) }
+}
+
+/// Save the process to a new file at path, return 0, or 1 in the process restored from it
+pub fn checkpoint(path: &str) -> isize {
+    with_cstr(path, |path| unsafe { sys::checkpoint(path) })
+}
+
+/// Start the process saved in the checkpoint at path as a child, return its pid
+pub fn restore(path: &str) -> isize {
+    with_cstr(path, |path| unsafe { sys::restore(path) })
+}
+
+/// Indices of CpuStat's times, mirroring the kernel's cpustat.rs
+pub const CPU_USER: usize = 0;
+pub const CPU_KERNEL: usize = 1;
+pub const CPU_IDLE: usize = 2;
+pub const CPU_IRQ: usize = 3;
+
+/// The time a hart spent in user mode, the kernel, idle and interrupts,
+/// same as the kernel's cpustat::CpuStat, in CLINT mtime units
+#[repr(C)]
+#[derive(Clone, Copy, Default)]
+pub struct CpuStat {
+    pub hart: u64,
+    pub times: [u64; 4],
+}
+
+/// Fill stats with the times of the harts, return how many
+pub fn cpustat(stats: &mut [CpuStat]) -> isize {
+    unsafe { sys::cpustat(stats.as_mut_ptr() as *mut u8, stats.len()) }
+}
+
+#[panic_handler]
+fn panic(info: &PanicInfo) -> ! {
+    eprintln!("{}", info);
+    exit(EXIT_PANIC)
+}
@@ -0,0 +1,88 @@
+//! Formatted printing for user programs
+//!
+//! Output of one print! call is collected in a buffer on the stack,
+//! and written with as few write syscalls as possible:
+//! when the buffer is full, and at the end of the call.
+
+use core::fmt::{self, Write};
+
+use crate::write;
+
+const BUF: usize = 256;
+
+struct Writer {
+    fd: i32,
+    buf: [u8; BUF],
+    len: usize,
+}
+
+impl Writer {
+    fn flush(&mut self) {
+        if self.len > 0 {
+            write(self.fd, &self.buf[..self.len]);
+            self.len = 0;
+        }
+    }
+}
+
+impl Write for Writer {
+    fn write_str(&mut self, s: &str) -> fmt::Result {
+        for byte in s.bytes() {
+            if self.len == BUF {
+                self.flush();
+            }
+            self.buf[self.len] = byte;
+            self.len += 1;
+        }
+        Ok(())
+    }
+}
+
+/// used only in the print macros
+pub fn _print(fd: i32, args: fmt::Arguments) {
+    let mut w = Writer {
+        fd,
+        buf: [0; BUF],
+        len: 0,
+    };
+    let _ = w.write_fmt(args);
+    w.flush();
+}
+
+/// Print to a file descriptor
+#[macro_export]
+macro_rules! fprint {
+    ($fd:expr, $($arg:tt)*) => {
+        $crate::printf::_print($fd, format_args!($($arg)*))
+    };
+}
+
+#[macro_export]
+macro_rules! fprintln {
+    ($fd:expr) => {$crate::fprint!($fd, "\n")};
+    ($fd:expr, $fmt:expr) => {$crate::fprint!($fd, concat!($fmt, "\n"))};
+    ($fd:expr, $fmt:expr, $($arg:tt)*) => {
+        $crate::fprint!($fd, concat!($fmt, "\n"), $($arg)*)
+    };
+}
+
+#[macro_export]
+macro_rules! print {
+    ($($arg:tt)*) => {$crate::fprint!($crate::STDOUT, $($arg)*)};
+}
+
+#[macro_export]
+macro_rules! println {
+    ($($arg:tt)*) => {$crate::fprintln!($crate::STDOUT, $($arg)*)};
+}
+
+/// Print to stderr
+#[macro_export]
+macro_rules! eprint {
+    ($($arg:tt)*) => {$crate::fprint!($crate::STDERR, $($arg)*)};
+}
+
+#[macro_export]
+macro_rules! eprintln {
+    ($($arg:tt)*) => {$crate::fprintln!($crate::STDERR, $($arg)*)};
+}
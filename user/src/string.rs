@@ -0,0 +1,65 @@
+//! Helpers between Rust strings and the nul-terminated strings syscalls take
+
+use core::slice;
+
+use crate::MAXPATH;
+
+/// Length of a nul-terminated string
+///
+/// # Safety
+///
+/// s must point to a nul-terminated string.
+pub unsafe fn strlen(s: *const u8) -> usize {
+    let mut n = 0;
+    while *s.add(n) != 0 {
+        n += 1;
+    }
+    n
+}
+
+/// View a nul-terminated string as bytes, without the nul
+///
+/// # Safety
+///
+/// s must point to a nul-terminated string, which stays as it is for 'a.
+pub unsafe fn from_cstr<'a>(s: *const u8) -> &'a [u8] {
+    slice::from_raw_parts(s, strlen(s))
+}
+
+/// Copy s into buf followed by a nul byte.
+/// Return None if it does not fit or s itself contains a nul.
+pub fn to_cstr<'a>(s: &str, buf: &'a mut [u8]) -> Option<&'a [u8]> {
+    let bytes = s.as_bytes();
+    if bytes.len() >= buf.len() || bytes.contains(&0) {
+        return None;
+    }
+    buf[..bytes.len()].copy_from_slice(bytes);
+    buf[bytes.len()] = 0;
+    Some(&buf[..bytes.len() + 1])
+}
+
+/// Call f with a nul-terminated copy of path,
+/// or return -1 if path is too long.
+pub fn with_cstr<F: FnOnce(*const u8) -> isize>(path: &str, f: F) -> isize {
+    let mut buf: [u8; MAXPATH] = [0; MAXPATH];
+    match to_cstr(path, &mut buf) {
+        Some(s) => f(s.as_ptr()),
+        None => -1,
+    }
+}
+
+/// Parse a decimal number, with an optional leading '-'.
+/// Parsing stops at the first non-digit, like C's atoi.
+pub fn atoi(s: &str) -> i32 {
+    let bytes = s.as_bytes();
+    let (neg, digits) = match bytes.first() {
+        Some(b'-') => (true, &bytes[1..]),
+        _ => (false, bytes),
+    };
+    let mut n: i32 = 0;
+    for c in digits.iter().take_while(|c| c.is_ascii_digit()) {
+        n = n.wrapping_mul(10).wrapping_add((c - b'0') as i32);
+    }
+    if neg { -n } else { n }
+}
+
@@ -0,0 +1,113 @@
+//! Raw system call stubs
+//!
+//! The numbers come from the kernel's src/asm/syscall.h, see build.rs.
+//! Arguments go in a0-a5, the number in a7, and the result comes back in a0.
+//! Most programs should use the wrappers in the crate root instead.
+
+use core::arch::asm;
+
+include!(concat!(env!("OUT_DIR"), "/syscall_nr.rs"));
+
+#[inline(always)]
+unsafe fn ecall(nr: usize, args: &[usize]) -> isize {
+    let mut a: [usize; 6] = [0; 6];
+    a[..args.len()].copy_from_slice(args);
+
+    let ret: usize;
+    asm!("ecall",
+        inlateout("a0") a[0] => ret,
+        in("a1") a[1],
+        in("a2") a[2],
+        in("a3") a[3],
+        in("a4") a[4],
+        in("a5") a[5],
+        in("a7") nr,
+        options(nostack));
+    ret as isize
+}
+
+/// Generate one ecall wrapper per line of the table
+macro_rules! syscalls {
+    ($( fn $name:ident($($arg:ident: $ty:ty),*) = $nr:ident; )*) => {
+        $(
+            /// # Safety
+            ///
+            /// The pointers among the arguments must be valid for what the syscall does with them.
+            #[inline]
+            pub unsafe fn $name($($arg: $ty),*) -> isize {
+                ecall($nr, &[$($arg as usize),*])
+            }
+        )*
+    };
+}
+
+syscalls! {
+    fn fork() = SYS_FORK;
+    fn exit(status: i32) = SYS_EXIT;
+    fn wait(status: *mut i32) = SYS_WAIT;
+    fn pipe(fds: *mut i32) = SYS_PIPE;
+    fn read(fd: i32, buf: *mut u8, n: usize) = SYS_READ;
+    fn kill(pid: i32) = SYS_KILL;
+    fn exec(path: *const u8, argv: *const *const u8) = SYS_EXEC;
+    fn fstat(fd: i32, st: *mut u8) = SYS_FSTAT;
+    fn chdir(path: *const u8) = SYS_CHDIR;
+    fn dup(fd: i32) = SYS_DUP;
+    fn getpid() = SYS_GETPID;
+    fn sbrk(n: isize) = SYS_SBRK;
+    fn sleep(ticks: i32) = SYS_SLEEP;
+    fn uptime() = SYS_UPTIME;
+    fn open(path: *const u8, flags: i32) = SYS_OPEN;
+    fn write(fd: i32, buf: *const u8, n: usize) = SYS_WRITE;
+    fn mknod(path: *const u8, major: i16, minor: i16, typ: i16) = SYS_MKNOD;
+    fn unlink(path: *const u8) = SYS_UNLINK;
+    fn link(old: *const u8, new: *const u8) = SYS_LINK;
+    fn mkdir(path: *const u8) = SYS_MKDIR;
+    fn close(fd: i32) = SYS_CLOSE;
+    fn dmesg(buf: *mut u8, n: usize) = SYS_DMESG;
+    fn kfault(mode: usize, arg: usize) = SYS_KFAULT;
+    fn prof(cmd: usize, entries: *mut u8, n: usize) = SYS_PROF;
+    fn ftrace(cmd: usize) = SYS_FTRACE;
+    fn schedtrace(cmd: usize, events: *mut u8, n: usize) = SYS_SCHEDTRACE;
+    fn hvguest() = SYS_HVGUEST;
+    fn setitimer(which: usize, value: usize, interval: usize, handler: usize) = SYS_SETITIMER;
+    fn sigreturn() = SYS_SIGRETURN;
+    fn getuid() = SYS_GETUID;
+    fn geteuid() = SYS_GETEUID;
+    fn setuid(uid: u32) = SYS_SETUID;
+    fn getgid() = SYS_GETGID;
+    fn getegid() = SYS_GETEGID;
+    fn setgid(gid: u32) = SYS_SETGID;
+    fn chroot(path: *const u8) = SYS_CHROOT;
+    fn ptrace(req: usize, pid: usize, addr: usize, data: usize) = SYS_PTRACE;
+    fn proclog(on: usize) = SYS_PROCLOG;
+    fn madvise(addr: usize, len: usize, advice: usize) = SYS_MADVISE;
+    fn getrandom(buf: *mut u8, n: usize, flags: usize) = SYS_GETRANDOM;
+    fn hartctl(hart: usize, online: usize) = SYS_HARTCTL;
+    fn suspend(alarm: usize) = SYS_SUSPEND;
+    fn sched_setscheduler(pid: usize, policy: usize, priority: usize) = SYS_SCHED_SETSCHEDULER;
+    fn sched_yield() = SYS_SCHED_YIELD;
+    fn cpustat(stats: *mut u8, n: usize) = SYS_CPUSTAT;
+    fn sched_setquantum(pid: usize, ticks: usize) = SYS_SCHED_SETQUANTUM;
+    fn mmap(addr: usize, len: usize, prot: usize, flags: usize, fd: i32, off: usize) = SYS_MMAP;
+    fn munmap(addr: usize, len: usize) = SYS_MUNMAP;
+    fn socket(flags: usize) = SYS_SOCKET;
+    fn bind(fd: i32, path: *const u8) = SYS_BIND;
+    fn listen(fd: i32) = SYS_LISTEN;
+    fn connect(fd: i32, path: *const u8) = SYS_CONNECT;
+    fn accept(fd: i32) = SYS_ACCEPT;
+    fn mq_open(name: *const u8, flags: usize) = SYS_MQ_OPEN;
+    fn mq_send(fd: i32, msg: *const u8, len: usize, prio: usize) = SYS_MQ_SEND;
+    fn mq_receive(fd: i32, buf: *mut u8, len: usize, prio: *mut usize) = SYS_MQ_RECEIVE;
+    fn mq_unlink(name: *const u8) = SYS_MQ_UNLINK;
+    fn eventfd(initial: u64, flags: usize) = SYS_EVENTFD;
+    fn timerfd_create(flags: usize) = SYS_TIMERFD_CREATE;
+    fn timerfd_settime(fd: i32, value: usize, interval: usize) = SYS_TIMERFD_SETTIME;
+    fn msync(addr: usize, len: usize, flags: usize) = SYS_MSYNC;
+    fn fcntl(fd: i32, cmd: usize, arg: usize) = SYS_FCNTL;
+    fn getrlimit(resource: usize, limits: *mut usize) = SYS_GETRLIMIT;
+    fn setrlimit(resource: usize, cur: usize, max: usize) = SYS_SETRLIMIT;
+    fn ioctl(fd: i32, req: usize, arg: *mut u8) = SYS_IOCTL;
+    fn copy_file_range(fd_in: i32, off_in: *mut usize, fd_out: i32, off_out: *mut usize, len: usize, flags: usize) = SYS_COPY_FILE_RANGE;
+    fn checkpoint(path: *const u8) = SYS_CHECKPOINT;
+    fn restore(path: *const u8) = SYS_RESTORE;
+}
@@ -0,0 +1,70 @@
+//! Terminal attributes and window size of the console, same as the kernel's console/termios.rs
+
+/// ioctl requests
+pub const TCGETS: usize = 0x5401;
+pub const TCSETS: usize = 0x5402;
+pub const TIOCGWINSZ: usize = 0x5413;
+pub const TIOCSWINSZ: usize = 0x5414;
+
+/// Termios lflag
+pub const ICANON: u32 = 0o2;
+pub const ECHO: u32 = 0o10;
+
+/// Termios cflag, the kernel sets the uart up by them at TCSETS
+pub const CBAUD: u32 = 0o10017;
+pub const CSIZE: u32 = 0o60;
+pub const CS5: u32 = 0o0;
+pub const CS6: u32 = 0o20;
+pub const CS7: u32 = 0o40;
+pub const CS8: u32 = 0o60;
+pub const CSTOPB: u32 = 0o100;
+pub const CRTSCTS: u32 = 0o20000000000;
+
+/// The CBAUD codes of the baud rates the uart takes
+const BAUDS: [(u32, u32); 11] = [
+    (0o11, 1200), (0o13, 2400), (0o14, 4800), (0o15, 9600), (0o16, 19200), (0o17, 38400),
+    (0o10001, 57600), (0o10002, 115200), (0o10003, 230400), (0o10004, 460800), (0o10007, 921600),
+];
+
+/// Indices into Termios cc
+pub const VTIME: usize = 5;
+pub const VMIN: usize = 6;
+pub const NCCS: usize = 19;
+
+#[repr(C)]
+#[derive(Copy, Clone, Default)]
+pub struct Termios {
+    pub iflag: u32,
+    pub oflag: u32,
+    pub cflag: u32,
+    pub lflag: u32,
+    pub line: u8,
+    pub cc: [u8; NCCS],
+}
+
+#[repr(C)]
+#[derive(Copy, Clone, Default)]
+pub struct Winsize {
+    pub row: u16,
+    pub col: u16,
+    pub xpixel: u16,
+    pub ypixel: u16,
+}
+
+impl Termios {
+    /// The baud rate of cflag
+    pub fn speed(&self) -> Option<u32> {
+        BAUDS.iter().find(|(code, _)| *code == self.cflag & CBAUD).map(|(_, baud)| *baud)
+    }
+
+    /// Set the baud rate of cflag, fail if the uart does not take it
+    pub fn set_speed(&mut self, baud: u32) -> bool {
+        match BAUDS.iter().find(|(_, b)| *b == baud) {
+            Some((code, _)) => {
+                self.cflag = (self.cflag & !CBAUD) | code;
+                true
+            }
+            None => false,
+        }
+    }
+}
@@ -0,0 +1,152 @@
+//! Memory allocator for user programs
+//!
+//! The free list allocator of xv6's umalloc.c (K&R, chapter 8.7),
+//! growing the heap with sbrk,
+//! wired up as the global allocator, so that alloc's Box, Vec and String work.
+//! User programs are single-threaded, so there is no lock.
+
+use core::alloc::{GlobalAlloc, Layout};
+use core::cell::UnsafeCell;
+use core::{cmp, mem, ptr};
+
+use crate::sbrk;
+
+/// Header of a free or allocated block,
+/// also the allocation unit, so every block is 16-byte aligned.
+#[repr(C, align(16))]
+struct Header {
+    next: *mut Header,
+    size: usize, // in units of Header, including the header itself
+}
+
+const UNIT: usize = mem::size_of::<Header>();
+
+/// Least number of units to get from sbrk each time
+const NALLOC: usize = 4096;
+
+struct Heap {
+    base: Header,
+    freep: *mut Header,
+}
+
+impl Heap {
+    const fn new() -> Self {
+        Self {
+            base: Header {
+                next: ptr::null_mut(),
+                size: 0,
+            },
+            freep: ptr::null_mut(),
+        }
+    }
+
+    unsafe fn free(&mut self, ap: *mut u8) {
+        let bp = (ap as *mut Header).sub(1);
+        let mut p = self.freep;
+        while !(bp > p && bp < (*p).next) {
+            if p >= (*p).next && (bp > p || bp < (*p).next) {
+                break;
+            }
+            p = (*p).next;
+        }
+        if bp.add((*bp).size) == (*p).next {
+            (*bp).size += (*(*p).next).size;
+            (*bp).next = (*(*p).next).next;
+        } else {
+            (*bp).next = (*p).next;
+        }
+        if p.add((*p).size) == bp {
+            (*p).size += (*bp).size;
+            (*p).next = (*bp).next;
+        } else {
+            (*p).next = bp;
+        }
+        self.freep = p;
+    }
+
+    unsafe fn morecore(&mut self, nu: usize) -> *mut Header {
+        let nu = cmp::max(nu, NALLOC);
+        // one more unit in case the break is not aligned
+        let p = sbrk(((nu + 1) * UNIT) as isize);
+        if p == -1 {
+            return ptr::null_mut();
+        }
+        let hp = ((p as usize + UNIT - 1) & !(UNIT - 1)) as *mut Header;
+        (*hp).size = nu;
+        self.free(hp.add(1) as *mut u8);
+        self.freep
+    }
+
+    unsafe fn malloc(&mut self, nbytes: usize) -> *mut u8 {
+        let nunits = nbytes.div_ceil(UNIT) + 1;
+        if self.freep.is_null() {
+            self.base.next = &mut self.base;
+            self.base.size = 0;
+            self.freep = &mut self.base;
+        }
+
+        let mut prevp = self.freep;
+        let mut p = (*prevp).next;
+        loop {
+            if (*p).size >= nunits {
+                if (*p).size == nunits {
+                    (*prevp).next = (*p).next;
+                } else {
+                    // allocate the tail end
+                    (*p).size -= nunits;
+                    p = p.add((*p).size);
+                    (*p).size = nunits;
+                }
+                self.freep = prevp;
+                return p.add(1) as *mut u8;
+            }
+            if p == self.freep {
+                p = self.morecore(nunits);
+                if p.is_null() {
+                    return ptr::null_mut();
+                }
+            }
+            prevp = p;
+            p = (*p).next;
+        }
+    }
+}
+
+pub struct UserAlloc(UnsafeCell<Heap>);
+
+unsafe impl Sync for UserAlloc {}
+
+/// Blocks are UNIT aligned.
+/// For a larger alignment, the block is over-allocated,
+/// and the start of the block is kept right below the returned pointer.
+unsafe impl GlobalAlloc for UserAlloc {
+    unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
+        let heap = &mut *self.0.get();
+        if layout.align() <= UNIT {
+            return heap.malloc(layout.size());
+        }
+
+        let word = mem::size_of::<usize>();
+        let raw = heap.malloc(layout.size() + layout.align() + word);
+        if raw.is_null() {
+            return raw;
+        }
+        let aligned = (raw as usize + word + layout.align() - 1) & !(layout.align() - 1);
+        *((aligned - word) as *mut usize) = raw as usize;
+        aligned as *mut u8
+    }
+
+    unsafe fn dealloc(&self, ptr: *mut u8, layout: Layout) {
+        let heap = &mut *self.0.get();
+        if layout.align() <= UNIT {
+            heap.free(ptr);
+        } else {
+            let raw = *((ptr as usize - mem::size_of::<usize>()) as *const usize);
+            heap.free(raw as *mut u8);
+        }
+    }
+}
+
+#[global_allocator]
+static ALLOC: UserAlloc = UserAlloc(UnsafeCell::new(Heap::new()));
+